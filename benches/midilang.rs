@@ -0,0 +1,63 @@
+use std::fs;
+use std::path::Path;
+
+use criterion::{criterion_group, criterion_main, BatchSize, Criterion};
+use midilang::compose::{compose, ComposeOptions};
+use midilang::interpreter::{Interpreter, RunOptions};
+use midilang::parser::{self, CellWidth};
+use midilang::{optimizer, vm, MidiProgram};
+use midly::Smf;
+
+// the brainf programs in benches/programs, by name
+fn programs() -> Vec<(String, MidiProgram)> {
+    let dir = Path::new(env!("CARGO_MANIFEST_DIR")).join("benches/programs");
+    let mut programs: Vec<(String, MidiProgram)> = fs::read_dir(dir)
+        .unwrap()
+        .map(|entry| entry.unwrap().path())
+        .filter(|path| path.extension().is_some_and(|ext| ext == "b"))
+        .map(|path| {
+            let name = path.file_stem().unwrap().to_string_lossy().into_owned();
+            let program = MidiProgram::from_bf_str(&fs::read_to_string(&path).unwrap()).unwrap();
+            (name, program)
+        })
+        .collect();
+    programs.sort_by(|a, b| a.0.cmp(&b.0));
+    programs
+}
+
+// a program far longer than anyone would play, that ends
+fn big_program() -> MidiProgram {
+    let options = ComposeOptions { seed: 1, length: 20_000, terminating: true, ..ComposeOptions::default() };
+    let mut bytes = vec![];
+    midilang::ast_to_smf(&compose(&options)).write_std(&mut bytes).unwrap();
+    MidiProgram::from_bytes(&bytes).unwrap()
+}
+
+fn parsing(c: &mut Criterion) {
+    let mut bytes = vec![];
+    big_program().to_smf().write_std(&mut bytes).unwrap();
+    c.bench_function("parse/20000 chords", |b| b.iter(|| parser::parse(Smf::parse(&bytes).unwrap()).unwrap()));
+}
+
+fn optimizing(c: &mut Criterion) {
+    let program = big_program();
+    c.bench_function("optimize/20000 instructions", |b| {
+        b.iter_batched(|| program.ast().clone(), |ast| optimizer::optimize(ast, CellWidth::default()), BatchSize::SmallInput)
+    });
+}
+
+fn running(c: &mut Criterion) {
+    for (name, program) in programs() {
+        let optimized = program.optimize(CellWidth::default());
+        c.bench_function(&format!("interpret/{}", name), |b| {
+            b.iter(|| Interpreter::new().run(optimized.ast(), &mut &b""[..], &mut std::io::sink()).unwrap())
+        });
+        let code = vm::Bytecode::new(optimized.ast());
+        c.bench_function(&format!("vm/{}", name), |b| {
+            b.iter(|| vm::Vm::with_options(RunOptions::default()).run(&code, &mut &b""[..], &mut std::io::sink()).unwrap())
+        });
+    }
+}
+
+criterion_group!(benches, parsing, optimizing, running);
+criterion_main!(benches);
@@ -0,0 +1,13 @@
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+use midilang::parser::decode_chord;
+
+// notes in any order, and sorted the way the parser hands them over
+fuzz_target!(|notes: Vec<u8>| {
+    decode_chord(&notes);
+    let mut notes: Vec<u8> = notes.into_iter().map(|note| note & 0x7f).collect();
+    notes.sort_unstable();
+    let decoded = decode_chord(&notes);
+    assert!(decoded.amount >= 1);
+});
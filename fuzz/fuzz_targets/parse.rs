@@ -0,0 +1,14 @@
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+use midilang::check::{self, LintLevels};
+use midilang::{explain, stats};
+
+// any bytes, parsed as far as they go and handed to everything that looks at
+// parsed songs
+fuzz_target!(|bytes: &[u8]| {
+    let parsed = midilang::parse_bytes_lossy(bytes);
+    explain::explain(&parsed);
+    stats::Stats::new(&parsed);
+    check::lint_parsed(&parsed, &LintLevels::default());
+});
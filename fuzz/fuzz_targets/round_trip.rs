@@ -0,0 +1,13 @@
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+use midilang::generate::ArbitraryAst;
+use midilang::MidiProgram;
+
+// every valid program comes back from its song the same
+fuzz_target!(|ast: ArbitraryAst| {
+    let mut bytes = vec![];
+    midilang::ast_to_smf(&ast.0).write_std(&mut bytes).unwrap();
+    let program = MidiProgram::from_bytes(&bytes).unwrap();
+    assert_eq!(program.ast(), &ast.0);
+});
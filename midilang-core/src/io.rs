@@ -0,0 +1,215 @@
+use alloc::vec::Vec;
+use core::convert::Infallible;
+
+/// Where `,` reads bytes from, like a serial port or the notes coming in on a
+/// DIN MIDI input.
+pub trait Input {
+    type Error;
+
+    /// The next byte, `None` once there are no more, which programs read as 0.
+    fn read_byte(&mut self) -> Result<Option<u8>, Self::Error>;
+}
+
+/// What `.` and `,` write and read.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum Encoding {
+    /// A byte a cell
+    #[default]
+    Bytes,
+    /// `.` writes only whole UTF-8 sequences, and `,` reads a code point into
+    /// as many cells as it takes
+    Utf8,
+    /// `.` writes the cell as a decimal number on a line of its own, and `,`
+    /// reads a number, see `read_number`
+    Numeric,
+}
+
+/// U+FFFD, written for UTF-8 that isn't valid.
+pub const REPLACEMENT: [u8; 3] = [0xef, 0xbf, 0xbd];
+
+/// Where `.` writes bytes to.
+pub trait Output {
+    type Error;
+
+    fn write_byte(&mut self, byte: u8) -> Result<(), Self::Error>;
+
+    /// Called before reading input, so prompts get out in time.
+    fn flush(&mut self) -> Result<(), Self::Error> {
+        Ok(())
+    }
+}
+
+/// Input fixed up front, read from the start.
+impl Input for &[u8] {
+    type Error = Infallible;
+
+    fn read_byte(&mut self) -> Result<Option<u8>, Self::Error> {
+        let Some((&byte, rest)) = self.split_first() else {
+            return Ok(None);
+        };
+        *self = rest;
+        Ok(Some(byte))
+    }
+}
+
+/// Output kept in memory.
+impl Output for Vec<u8> {
+    type Error = Infallible;
+
+    fn write_byte(&mut self, byte: u8) -> Result<(), Self::Error> {
+        self.push(byte);
+        Ok(())
+    }
+}
+
+/// Bytes written one at a time, held back until they make up a whole UTF-8
+/// sequence. Sequences that turn out not to be valid are replaced by
+/// `REPLACEMENT`.
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct Utf8Output {
+    pending: [u8; 4],
+    len: usize,
+    /// Continuation bytes the pending sequence is still missing
+    need: usize,
+    /// Range the next continuation byte has to be in, narrower after some
+    /// leading bytes to rule out overlong encodings, surrogates and code points
+    /// past U+10FFFF
+    next: (u8, u8),
+}
+
+impl Utf8Output {
+    pub fn new() -> Self {
+        Utf8Output::default()
+    }
+
+    /// Adds `byte` to the pending sequence, writing it to `output` once it's
+    /// whole.
+    pub fn write_byte<O: Output + ?Sized>(&mut self, byte: u8, output: &mut O) -> Result<(), O::Error> {
+        if self.need > 0 {
+            if (self.next.0..=self.next.1).contains(&byte) {
+                self.pending[self.len] = byte;
+                self.len += 1;
+                self.need -= 1;
+                self.next = (0x80, 0xbf);
+                return match self.need {
+                    0 => self.write_pending(output),
+                    _ => Ok(()),
+                };
+            }
+            // cut short, the byte might start the next one
+            self.finish(output)?;
+        }
+        match lead(byte) {
+            Some((0, _)) => output.write_byte(byte),
+            Some((need, next)) => {
+                self.pending[0] = byte;
+                self.len = 1;
+                self.need = need;
+                self.next = next;
+                Ok(())
+            }
+            None => write_all(output, &REPLACEMENT),
+        }
+    }
+
+    /// Replaces a sequence that never got whole, when nothing more is written.
+    pub fn finish<O: Output + ?Sized>(&mut self, output: &mut O) -> Result<(), O::Error> {
+        if self.need == 0 {
+            return Ok(());
+        }
+        self.need = 0;
+        self.len = 0;
+        write_all(output, &REPLACEMENT)
+    }
+
+    fn write_pending<O: Output + ?Sized>(&mut self, output: &mut O) -> Result<(), O::Error> {
+        let len = core::mem::take(&mut self.len);
+        write_all(output, &self.pending[..len])
+    }
+}
+
+/// Reads a UTF-8 sequence from `input` into `bytes`, returning how many it
+/// took, 0 at the end of the input. Sequences that aren't valid read as
+/// `REPLACEMENT`, along with the byte that gave them away.
+pub fn read_utf8<I: Input + ?Sized>(input: &mut I, bytes: &mut [u8; 4]) -> Result<usize, I::Error> {
+    let Some(byte) = input.read_byte()? else {
+        return Ok(0);
+    };
+    let Some((need, mut next)) = lead(byte) else {
+        return Ok(replace(bytes));
+    };
+    bytes[0] = byte;
+    for len in 1..=need {
+        match input.read_byte()? {
+            Some(byte) if (next.0..=next.1).contains(&byte) => bytes[len] = byte,
+            _ => return Ok(replace(bytes)),
+        }
+        next = (0x80, 0xbf);
+    }
+    Ok(need + 1)
+}
+
+/// Writes `value` in decimal and a newline.
+pub fn write_number<O: Output + ?Sized>(output: &mut O, value: u32) -> Result<(), O::Error> {
+    let mut digits = [0; 10];
+    let mut start = digits.len();
+    let mut rest = value;
+    loop {
+        start -= 1;
+        digits[start] = b'0' + (rest % 10) as u8;
+        rest /= 10;
+        if rest == 0 {
+            break;
+        }
+    }
+    write_all(output, &digits[start..])?;
+    output.write_byte(b'\n')
+}
+
+/// Reads a decimal number from `input`, skipping whatever comes before it and
+/// the byte right after it. A `-` right before the digits negates it, and
+/// numbers too big for a `u32` wrap around. The end of the input reads as 0.
+pub fn read_number<I: Input + ?Sized>(input: &mut I) -> Result<u32, I::Error> {
+    let mut negative = false;
+    let mut byte = loop {
+        match input.read_byte()? {
+            None => return Ok(0),
+            Some(digit @ b'0'..=b'9') => break digit,
+            Some(other) => negative = other == b'-',
+        }
+    };
+    let mut value = 0_u32;
+    loop {
+        value = value.wrapping_mul(10).wrapping_add(u32::from(byte - b'0'));
+        match input.read_byte()? {
+            Some(digit @ b'0'..=b'9') => byte = digit,
+            _ => break,
+        }
+    }
+    Ok(if negative { value.wrapping_neg() } else { value })
+}
+
+// continuation bytes following `byte` and the range the first has to be in,
+// `None` when no sequence starts with it
+fn lead(byte: u8) -> Option<(usize, (u8, u8))> {
+    Some(match byte {
+        0x00..=0x7f => (0, (0, 0)),
+        0xc2..=0xdf => (1, (0x80, 0xbf)),
+        0xe0 => (2, (0xa0, 0xbf)),
+        0xed => (2, (0x80, 0x9f)),
+        0xe1..=0xef => (2, (0x80, 0xbf)),
+        0xf0 => (3, (0x90, 0xbf)),
+        0xf4 => (3, (0x80, 0x8f)),
+        0xf1..=0xf3 => (3, (0x80, 0xbf)),
+        _ => return None,
+    })
+}
+
+fn replace(bytes: &mut [u8; 4]) -> usize {
+    bytes[..3].copy_from_slice(&REPLACEMENT);
+    3
+}
+
+fn write_all<O: Output + ?Sized>(output: &mut O, bytes: &[u8]) -> Result<(), O::Error> {
+    bytes.iter().try_for_each(|&byte| output.write_byte(byte))
+}
@@ -0,0 +1,506 @@
+//! The part of midilang programs run on: a tape of cells and a dispatch loop
+//! over flattened bytecode, with no more than `core` and `alloc` underneath.
+//!
+//! midilang's `vm::Vm` runs programs on it, reading and writing through
+//! `std::io` and stopping them by the clock. Embedded hosts bring their own
+//! `Input` and `Output`, a serial port or DIN MIDI, and a `Host` when they
+//! have a clock to stop programs by:
+//!
+//! ```
+//! use midilang_core::{Machine, Op};
+//! use std::num::Wrapping;
+//!
+//! // ,[-.]
+//! let ops = [Op::Input, Op::JumpIfZero(5), Op::Add(Wrapping(-1)), Op::Output, Op::JumpUnlessZero(2)];
+//! let mut output = vec![];
+//! Machine::new().run(&ops, &mut &[3][..], &mut output, &mut ()).unwrap();
+//! assert_eq!(output, [2, 1, 0]);
+//! ```
+#![no_std]
+
+extern crate alloc;
+
+use core::num::Wrapping;
+
+pub mod io;
+mod rng;
+mod tape;
+
+pub use io::{Encoding, Input, Output, Utf8Output};
+pub use rng::{Rng, GOLDEN_GAMMA, MIX_MULTIPLIERS};
+pub use tape::{CellOverflow, Tape, TapeDump, DUMP_RADIUS};
+
+/// Cell values and increments, wide enough for every cell width
+pub type Cell = Wrapping<i32>;
+
+/// Steps between asking the `Host` whether to stop.
+pub const INTERRUPT_INTERVAL: usize = 1 << 16;
+
+/// A single bytecode instruction, jumps hold the index of the op they go to.
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub enum Op {
+    Add(Cell),
+    Move(isize),
+    Output,
+    Input,
+    /// Start of a loop, jumping past the end of the loop when the cell is 0
+    JumpIfZero(usize),
+    /// End of a loop, jumping back to the start of its body unless the cell is 0
+    JumpUnlessZero(usize),
+    /// Kept so the AST can be had back, the machine never stops for the debugger
+    Breakpoint,
+    /// Sets the cell to what the `Host` returns calling its function with this
+    /// index on it
+    Call(usize),
+    /// Sets the cell to a random byte from the machine's `Rng`
+    Random,
+    /// Shows the cells around the pointer through `Host::dump_tape`
+    DumpTape,
+}
+
+/// Limits on running a program that don't need a clock. Nothing is limited by
+/// default.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct Limits {
+    /// Instructions executed before giving up
+    pub max_steps: Option<usize>,
+    /// Bytes written to the output before giving up
+    pub max_output_bytes: Option<usize>,
+}
+
+/// Why a program stopped before its end.
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub enum Stop<E> {
+    StepLimit(usize),
+    OutputLimit(usize),
+    /// The `Host` asked to stop
+    Interrupted,
+    /// Moving the pointer at this op would have taken it left of the first cell
+    PointerUnderflow(usize),
+    /// Moving the pointer at this op would have taken it past the end of a
+    /// tape with a size
+    PointerOverflow(usize),
+    /// Adding at this op would have overflowed a cell that traps
+    CellOverflow(usize),
+    /// The `Host` couldn't call the function of the `Call` at this op
+    NoFunction(usize),
+    Io(E),
+}
+
+/// What the machine asks of whoever runs it besides IO. `()` never stops a
+/// program, runs every loop op by op and has no functions to call.
+pub trait Host {
+    /// Whether to stop the program after `steps`, asked every
+    /// `INTERRUPT_INTERVAL` steps starting with the first.
+    fn interrupted(&mut self, _steps: usize) -> bool {
+        false
+    }
+
+    /// Offered the loop from op `start`, its `JumpIfZero`, to `end`, right
+    /// after its `JumpUnlessZero`, whenever the body is about to run. Running
+    /// it until its cell is 0 some other way, like natively, returns the steps
+    /// that took and carries on at `end`.
+    fn run_loop(&mut self, _ops: &[Op], _tape: &mut Tape, _start: usize, _end: usize) -> Option<usize> {
+        None
+    }
+
+    /// Calls the external function with the index `function` on `cell` and
+    /// returns what it returns, `None` when there's no such function.
+    fn call(&mut self, _function: usize, _cell: Cell) -> Option<Cell> {
+        None
+    }
+
+    /// Shows `tape` to whoever's debugging the program, with `Tape::dump`
+    /// usually. Nothing is shown by default.
+    fn dump_tape(&mut self, _tape: &Tape) {}
+}
+
+impl Host for () {}
+
+/// Runs bytecode against its tape, counting steps and keeping to its `Limits`.
+#[derive(Debug, Clone, Default)]
+pub struct Machine {
+    tape: Tape,
+    steps: usize,
+    output_bytes: usize,
+    limits: Limits,
+    rng: Rng,
+    encoding: Encoding,
+    utf8: Utf8Output,
+}
+
+impl Machine {
+    pub fn new() -> Self {
+        Machine::default()
+    }
+
+    /// Creates a machine that gives up once it runs into any of `limits`.
+    pub fn with_limits(limits: Limits) -> Self {
+        Machine { limits, ..Machine::default() }
+    }
+
+    pub fn tape(&self) -> &Tape {
+        &self.tape
+    }
+
+    pub fn tape_mut(&mut self) -> &mut Tape {
+        &mut self.tape
+    }
+
+    pub fn steps(&self) -> usize {
+        self.steps
+    }
+
+    pub fn limits(&self) -> Limits {
+        self.limits
+    }
+
+    /// Has `Op::Output` and `Op::Input` write and read UTF-8 or numbers instead
+    /// of bytes.
+    pub fn set_encoding(&mut self, encoding: Encoding) {
+        self.encoding = encoding;
+    }
+
+    /// Starts the random bytes of `Op::Random` over from `seed`, 0 unless set.
+    pub fn set_seed(&mut self, seed: u64) {
+        self.rng = Rng::new(seed);
+    }
+
+    /// Executes `ops` against the current tape. Breakpoints aren't steps.
+    ///
+    /// `output` is flushed before reading input, and the end of the input
+    /// reads as 0. UTF-8 that isn't valid reads and writes as U+FFFD.
+    pub fn run<I, O, H>(&mut self, ops: &[Op], input: &mut I, output: &mut O, host: &mut H) -> Result<(), Stop<I::Error>>
+    where
+        I: Input + ?Sized,
+        O: Output<Error = I::Error> + ?Sized,
+        H: Host + ?Sized,
+    {
+        let mut pc = 0;
+        while let Some(&op) = ops.get(pc) {
+            pc += 1;
+            if op == Op::Breakpoint {
+                continue;
+            }
+            if let Some(max) = self.limits.max_steps {
+                if self.steps >= max {
+                    return Err(Stop::StepLimit(max));
+                }
+            }
+            if self.steps.is_multiple_of(INTERRUPT_INTERVAL) && host.interrupted(self.steps) {
+                return Err(Stop::Interrupted);
+            }
+            self.steps += 1;
+            match op {
+                Op::Add(amount) => {
+                    if !self.tape.add(amount) {
+                        return Err(Stop::CellOverflow(pc - 1));
+                    }
+                }
+                Op::Move(amount) => {
+                    if !self.tape.move_by(amount) {
+                        return Err(match amount < 0 {
+                            true => Stop::PointerUnderflow(pc - 1),
+                            false => Stop::PointerOverflow(pc - 1),
+                        });
+                    }
+                }
+                Op::Output => {
+                    if let Some(max) = self.limits.max_output_bytes {
+                        if self.output_bytes >= max {
+                            return Err(Stop::OutputLimit(max));
+                        }
+                    }
+                    self.output_bytes += 1;
+                    let byte = self.tape.current().0 as u8;
+                    match self.encoding {
+                        Encoding::Bytes => output.write_byte(byte),
+                        Encoding::Utf8 => self.utf8.write_byte(byte, output),
+                        Encoding::Numeric => io::write_number(output, self.tape.current().0 as u32),
+                    }
+                    .map_err(Stop::Io)?;
+                }
+                Op::Input => {
+                    output.flush().map_err(Stop::Io)?;
+                    match self.encoding {
+                        Encoding::Bytes => {
+                            let byte = input.read_byte().map_err(Stop::Io)?.unwrap_or(0);
+                            self.tape.set(Wrapping(i32::from(byte)));
+                        }
+                        Encoding::Utf8 => {
+                            let mut bytes = [0; 4];
+                            let len = io::read_utf8(input, &mut bytes).map_err(Stop::Io)?;
+                            // the end of the input is a 0 either way
+                            self.tape.set_from(&bytes[..len.max(1)]);
+                        }
+                        Encoding::Numeric => {
+                            let value = io::read_number(input).map_err(Stop::Io)?;
+                            self.tape.set(Wrapping(value as i32));
+                        }
+                    }
+                }
+                Op::JumpIfZero(target) => {
+                    if self.tape.current().0 == 0 || self.run_loop(ops, host, pc - 1, target) {
+                        pc = target;
+                    }
+                }
+                Op::JumpUnlessZero(target) => {
+                    if self.tape.current().0 != 0 && !self.run_loop(ops, host, target - 1, pc) {
+                        pc = target;
+                    }
+                }
+                Op::Call(function) => {
+                    output.flush().map_err(Stop::Io)?;
+                    let value = host.call(function, self.tape.current()).ok_or(Stop::NoFunction(pc - 1))?;
+                    self.tape.set(value);
+                }
+                Op::Random => self.tape.set(Wrapping(i32::from(self.rng.byte()))),
+                Op::DumpTape => {
+                    // after what the program printed so far
+                    output.flush().map_err(Stop::Io)?;
+                    host.dump_tape(&self.tape);
+                }
+                Op::Breakpoint => {}
+            }
+        }
+        // a sequence the program never finished comes out replaced
+        self.utf8.finish(output).map_err(Stop::Io)
+    }
+
+    // whether `host` ran the loop from `start` to `end`
+    fn run_loop<H: Host + ?Sized>(&mut self, ops: &[Op], host: &mut H, start: usize, end: usize) -> bool {
+        match host.run_loop(ops, &mut self.tape, start, end) {
+            Some(steps) => {
+                self.steps += steps;
+                true
+            }
+            None => false,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+    use alloc::string::{String, ToString};
+    use alloc::vec;
+    use alloc::vec::Vec;
+    use core::convert::Infallible;
+
+    // +++[>++<-]>.
+    const DOUBLE: [Op; 9] = [
+        Op::Add(Wrapping(3)),
+        Op::JumpIfZero(7),
+        Op::Move(1),
+        Op::Add(Wrapping(2)),
+        Op::Move(-1),
+        Op::Add(Wrapping(-1)),
+        Op::JumpUnlessZero(2),
+        Op::Move(1),
+        Op::Output,
+    ];
+
+    #[test]
+    fn runs_loops_and_limits() {
+        let mut output = vec![];
+        let mut machine = Machine::new();
+        machine.run(&DOUBLE, &mut &[][..], &mut output, &mut ()).unwrap();
+        assert_eq!(output, [6]);
+        assert_eq!((machine.tape().cells(), machine.steps()), (&[Wrapping(0), Wrapping(6)][..], 19));
+
+        let mut machine = Machine::with_limits(Limits { max_steps: Some(5), ..Limits::default() });
+        assert_eq!(machine.run(&DOUBLE, &mut &[][..], &mut Vec::new(), &mut ()), Err(Stop::StepLimit(5)));
+        let mut machine = Machine::with_limits(Limits { max_output_bytes: Some(0), ..Limits::default() });
+        assert_eq!(machine.run(&DOUBLE, &mut &[][..], &mut Vec::new(), &mut ()), Err(Stop::OutputLimit(0)));
+        let underflow = [Op::Output, Op::Move(-1)];
+        assert_eq!(Machine::new().run(&underflow, &mut &[][..], &mut Vec::new(), &mut ()), Err(Stop::PointerUnderflow(1)));
+    }
+
+    #[test]
+    fn cells_wrap_at_their_width() {
+        let mut machine = Machine::new();
+        machine.run(&[Op::Add(Wrapping(-1))], &mut &[][..], &mut Vec::new(), &mut ()).unwrap();
+        assert_eq!(machine.tape().current(), Wrapping(255));
+        machine.tape_mut().set_cell_bits(16);
+        machine.run(&[Op::Add(Wrapping(-256))], &mut &[][..], &mut Vec::new(), &mut ()).unwrap();
+        assert_eq!(machine.tape().current(), Wrapping(0xffff));
+    }
+
+    // stops on the first question and runs loops in one go
+    struct Gadget {
+        asked: usize,
+    }
+
+    impl Host for Gadget {
+        fn interrupted(&mut self, _steps: usize) -> bool {
+            self.asked += 1;
+            self.asked > 1
+        }
+
+        fn run_loop(&mut self, _ops: &[Op], tape: &mut Tape, start: usize, end: usize) -> Option<usize> {
+            assert_eq!((start, end), (1, 7));
+            let times = tape.current();
+            tape.set(Wrapping(0));
+            assert!(tape.move_by(1));
+            assert!(tape.add(times * Wrapping(2)));
+            assert!(tape.move_by(-1));
+            Some(5 * times.0 as usize)
+        }
+    }
+
+    #[test]
+    fn hosts_run_loops_and_stop_programs() {
+        let mut output: Vec<u8> = vec![];
+        let mut machine = Machine::new();
+        machine.run(&DOUBLE, &mut &[][..], &mut output, &mut Gadget { asked: 0 }).unwrap();
+        assert_eq!((output, machine.steps()), (vec![6], 19));
+
+        let mut machine = Machine::new();
+        let stopped: Result<(), Stop<Infallible>> = machine.run(&DOUBLE, &mut &[][..], &mut Vec::new(), &mut Gadget { asked: 1 });
+        assert_eq!(stopped, Err(Stop::Interrupted));
+    }
+
+    // doubles the cell with function 0, the only one it has
+    struct Doubler;
+
+    impl Host for Doubler {
+        fn call(&mut self, function: usize, cell: Cell) -> Option<Cell> {
+            (function == 0).then_some(cell * Wrapping(2))
+        }
+    }
+
+    #[test]
+    fn hosts_call_functions() {
+        let ops = [Op::Add(Wrapping(200)), Op::Call(0), Op::Output, Op::Call(1)];
+        let mut output: Vec<u8> = vec![];
+        let mut machine = Machine::new();
+        let stopped: Result<(), Stop<Infallible>> = machine.run(&ops, &mut &[][..], &mut output, &mut Doubler);
+        assert_eq!((stopped, output), (Err(Stop::NoFunction(3)), vec![144]));
+        let stopped: Result<(), Stop<Infallible>> = Machine::new().run(&ops, &mut &[][..], &mut Vec::new(), &mut ());
+        assert_eq!(stopped, Err(Stop::NoFunction(1)));
+    }
+
+    #[test]
+    fn seeds_pick_the_same_bytes() {
+        let ops = [Op::Random, Op::Output, Op::Random, Op::Output];
+        let run = |seed| {
+            let mut output: Vec<u8> = vec![];
+            let mut machine = Machine::new();
+            machine.set_seed(seed);
+            machine.run(&ops, &mut &[][..], &mut output, &mut ()).unwrap();
+            output
+        };
+        let mut rng = Rng::new(7);
+        assert_eq!(run(7), [rng.byte(), rng.byte()]);
+        assert_ne!(run(7), run(8));
+    }
+
+    // keeps what it's shown
+    #[derive(Default)]
+    struct Dumps(Vec<String>);
+
+    impl Host for Dumps {
+        fn dump_tape(&mut self, tape: &Tape) {
+            self.0.push(tape.dump().to_string());
+        }
+    }
+
+    #[test]
+    fn dumps_the_cells_around_the_pointer() {
+        let ops = [Op::Add(Wrapping(7)), Op::DumpTape, Op::Move(5), Op::Add(Wrapping(-1)), Op::DumpTape];
+        let mut dumps = Dumps::default();
+        let stopped: Result<(), Stop<Infallible>> = Machine::new().run(&ops, &mut &[][..], &mut Vec::new(), &mut dumps);
+        assert_eq!(stopped, Ok(()));
+        assert_eq!(dumps.0, ["tape at 0: [7] 0 0 0 0", "tape at 5: 0 0 0 0 [255] 0 0 0 0"]);
+    }
+
+    #[test]
+    fn two_sided_tapes_grow_to_the_left() {
+        let ops = [Op::Add(Wrapping(7)), Op::Move(-3), Op::Add(Wrapping(1)), Op::DumpTape, Op::Move(3), Op::DumpTape];
+        let mut machine = Machine::new();
+        machine.tape_mut().set_two_sided(true);
+        let mut dumps = Dumps::default();
+        let stopped: Result<(), Stop<Infallible>> = machine.run(&ops, &mut &[][..], &mut Vec::new(), &mut dumps);
+        assert_eq!(stopped, Ok(()));
+        assert_eq!(dumps.0, ["tape at -3: 0 0 0 0 [1] 0 0 7 0", "tape at 0: 0 1 0 0 [7] 0 0 0 0"]);
+        assert_eq!((machine.tape().origin(), machine.tape().position()), (3, 0));
+    }
+
+    #[test]
+    fn cells_saturate_or_trap_instead_of_wrapping() {
+        let ops = [Op::Add(Wrapping(250)), Op::Add(Wrapping(10)), Op::Output, Op::Add(Wrapping(-300)), Op::Output];
+        let run = |overflow| {
+            let mut machine = Machine::new();
+            machine.tape_mut().set_overflow(overflow);
+            let mut output: Vec<u8> = vec![];
+            let stopped = machine.run(&ops, &mut &[][..], &mut output, &mut ());
+            (stopped, output)
+        };
+        assert_eq!(run(CellOverflow::Wrap), (Ok(()), vec![4, 216]));
+        assert_eq!(run(CellOverflow::Saturate), (Ok(()), vec![255, 0]));
+        assert_eq!(run(CellOverflow::Trap), (Err(Stop::CellOverflow(1)), vec![]));
+    }
+
+    #[test]
+    fn pointers_wrap_or_stop_at_the_size_of_the_tape() {
+        let mut tape = Tape::new();
+        tape.set_size(3, true);
+        assert!(tape.move_by(-1));
+        assert_eq!(tape.pointer(), 2);
+        assert!(tape.move_by(4));
+        assert_eq!((tape.pointer(), tape.cells().len()), (0, 3));
+
+        let ops = [Op::Move(2), Op::Move(1)];
+        let mut machine = Machine::new();
+        machine.tape_mut().set_size(3, false);
+        let stopped: Result<(), Stop<Infallible>> = machine.run(&ops, &mut &[][..], &mut Vec::new(), &mut ());
+        assert_eq!(stopped, Err(Stop::PointerOverflow(1)));
+        assert_eq!(machine.tape().pointer(), 2);
+    }
+
+    #[test]
+    fn reads_and_writes_whole_utf8_sequences() {
+        let mut machine = Machine::new();
+        machine.set_encoding(Encoding::Utf8);
+        // ,.>. then a stray continuation byte and a sequence cut short
+        let ops = [
+            Op::Input,
+            Op::Output,
+            Op::Move(1),
+            Op::Output,
+            Op::Move(1),
+            Op::Add(Wrapping(0x80)),
+            Op::Output,
+            Op::Add(Wrapping(0x62)),
+            Op::Output,
+        ];
+        let mut output = vec![];
+        machine.run(&ops, &mut "é!".as_bytes(), &mut output, &mut ()).unwrap();
+        let cells: Vec<i32> = machine.tape().cells().iter().map(|cell| cell.0).collect();
+        assert_eq!(cells, [0xc3, 0xa9, 0xe2]);
+        assert_eq!(core::str::from_utf8(&output), Ok("é\u{fffd}\u{fffd}"));
+
+        let mut bytes = [0; 4];
+        let mut input = &b"\xe2\x82\xac\xed\xa0\x80"[..];
+        assert_eq!(io::read_utf8(&mut input, &mut bytes), Ok(3));
+        assert_eq!(core::str::from_utf8(&bytes[..3]), Ok("€"));
+        // surrogates aren't valid
+        assert_eq!(io::read_utf8(&mut input, &mut bytes), Ok(3));
+        assert_eq!(bytes[..3], io::REPLACEMENT);
+    }
+
+    #[test]
+    fn reads_and_writes_numbers() {
+        let mut machine = Machine::new();
+        machine.set_encoding(Encoding::Numeric);
+        machine.tape_mut().set_cell_bits(16);
+        let ops = [Op::Input, Op::Add(Wrapping(1)), Op::Output, Op::Input, Op::Output, Op::Input, Op::Output];
+        let mut output = vec![];
+        machine.run(&ops, &mut &b"  41 x-1,"[..], &mut output, &mut ()).unwrap();
+        assert_eq!(output, b"42\n65535\n0\n");
+
+        let mut input = &b"4294967297"[..];
+        assert_eq!(io::read_number(&mut input), Ok(1));
+    }
+}
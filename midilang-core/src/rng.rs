@@ -0,0 +1,34 @@
+/// SplitMix64, plenty random for games and picking instructions, and the same
+/// everywhere for a seed, compiled programs included.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Rng(u64);
+
+/// What SplitMix64 adds to its state for every number.
+pub const GOLDEN_GAMMA: u64 = 0x9e37_79b9_7f4a_7c15;
+
+/// The multipliers of SplitMix64's mix, in the order it applies them.
+pub const MIX_MULTIPLIERS: [u64; 2] = [0xbf58_476d_1ce4_e5b9, 0x94d0_49bb_1331_11eb];
+
+impl Rng {
+    pub fn new(seed: u64) -> Self {
+        Rng(seed)
+    }
+
+    pub fn next_u64(&mut self) -> u64 {
+        self.0 = self.0.wrapping_add(GOLDEN_GAMMA);
+        let mut z = self.0;
+        z = (z ^ (z >> 30)).wrapping_mul(MIX_MULTIPLIERS[0]);
+        z = (z ^ (z >> 27)).wrapping_mul(MIX_MULTIPLIERS[1]);
+        z ^ (z >> 31)
+    }
+
+    /// A number below `n`, which can't be 0.
+    pub fn below(&mut self, n: u64) -> u64 {
+        self.next_u64() % n
+    }
+
+    /// A random byte, the top one of the next number.
+    pub fn byte(&mut self) -> u8 {
+        (self.next_u64() >> 56) as u8
+    }
+}
@@ -0,0 +1,272 @@
+use alloc::vec;
+use alloc::vec::Vec;
+use core::fmt;
+use core::num::Wrapping;
+
+use crate::Cell;
+
+/// Cells on either side of the pointer a tape dump shows.
+pub const DUMP_RADIUS: usize = 4;
+
+/// What `Tape::add` does when a cell would go past its maximum or below 0.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum CellOverflow {
+    /// Wrap around to the other end, like classic BF
+    #[default]
+    Wrap,
+    /// Stop at the maximum or at 0
+    Saturate,
+    /// Leave the cell alone and fail
+    Trap,
+}
+
+/// The cells a program works on and the pointer into them.
+///
+/// The tape starts out as a single zeroed cell and grows to the right on
+/// demand, and to the left too when it's two sided, or up to its size when it
+/// has one. Cells wrap around at their width, 8 bits unless set otherwise.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Tape {
+    cells: Vec<Cell>,
+    pointer: usize,
+    /// Index of the cell the pointer started on, past 0 once a two sided
+    /// tape has grown to the left
+    origin: usize,
+    two_sided: bool,
+    /// Cells the pointer can't move past, unlimited when `None`
+    size: Option<usize>,
+    /// Whether the pointer moving off either end of `size` cells comes back
+    /// in at the other
+    wrap: bool,
+    /// Bits of a cell that are kept, all of them for 32 bit cells
+    mask: i32,
+    overflow: CellOverflow,
+}
+
+impl Tape {
+    pub fn new() -> Self {
+        Tape {
+            cells: vec![Wrapping(0)],
+            pointer: 0,
+            origin: 0,
+            two_sided: false,
+            size: None,
+            wrap: false,
+            mask: 0xff,
+            overflow: CellOverflow::Wrap,
+        }
+    }
+
+    /// Cells wrap around at `bits`, 8, 16 or 32.
+    pub fn set_cell_bits(&mut self, bits: u32) {
+        self.mask = if bits >= 32 { -1 } else { (1 << bits) - 1 };
+    }
+
+    /// What adding to a cell does when it goes past its width.
+    pub fn set_overflow(&mut self, overflow: CellOverflow) {
+        self.overflow = overflow;
+    }
+
+    pub fn overflow(&self) -> CellOverflow {
+        self.overflow
+    }
+
+    /// Keeps the pointer on the first `size` cells, moving it off either end
+    /// comes back in at the other with `wrap` and fails without. Wins over
+    /// being two sided.
+    pub fn set_size(&mut self, size: usize, wrap: bool) {
+        self.size = Some(size);
+        self.wrap = wrap;
+    }
+
+    /// Lets the pointer move left of the first cell, growing the tape to the
+    /// left like it does to the right.
+    pub fn set_two_sided(&mut self, two_sided: bool) {
+        self.two_sided = two_sided;
+    }
+
+    pub fn cells(&self) -> &[Cell] {
+        &self.cells
+    }
+
+    /// The cells to hand to native code, which can't grow the tape.
+    pub fn cells_mut(&mut self) -> &mut [Cell] {
+        &mut self.cells
+    }
+
+    pub fn pointer(&self) -> usize {
+        self.pointer
+    }
+
+    /// Index of the cell the pointer started on.
+    pub fn origin(&self) -> usize {
+        self.origin
+    }
+
+    /// Where the pointer is counted from the cell it started on, negative left
+    /// of it.
+    pub fn position(&self) -> isize {
+        self.pointer as isize - self.origin as isize
+    }
+
+    /// The cell under the pointer.
+    #[inline]
+    pub fn current(&self) -> Cell {
+        self.cells[self.pointer]
+    }
+
+    /// Stores `value` under the pointer, wrapped around to the width of a cell.
+    #[inline]
+    pub fn set(&mut self, value: Cell) {
+        self.cells[self.pointer] = Wrapping(value.0 & self.mask);
+    }
+
+    /// Sets the cell under the pointer and the ones after it to `bytes`, as
+    /// many as fit on a tape with a size. The pointer stays where it is.
+    pub fn set_from(&mut self, bytes: &[u8]) {
+        let end = self.pointer + bytes.len();
+        let end = self.size.map_or(end, |size| end.min(size));
+        if end > self.cells.len() {
+            self.cells.resize(end, Wrapping(0));
+        }
+        for (cell, &byte) in self.cells[self.pointer..end].iter_mut().zip(bytes) {
+            *cell = Wrapping(i32::from(byte));
+        }
+    }
+
+    /// Adds `amount` to the cell under the pointer. Returns whether it did,
+    /// it doesn't when the cell overflows and overflowing traps.
+    #[inline]
+    #[must_use]
+    pub fn add(&mut self, amount: Cell) -> bool {
+        if self.overflow == CellOverflow::Wrap {
+            self.set(self.current() + amount);
+            return true;
+        }
+        // cells are unsigned, so overflowing means crossing 0 or the maximum
+        let max = i64::from(self.mask as u32);
+        let sum = i64::from(self.current().0 as u32) + i64::from(amount.0);
+        let value = match self.overflow {
+            _ if (0..=max).contains(&sum) => sum,
+            CellOverflow::Saturate => sum.clamp(0, max),
+            _ => return false,
+        };
+        self.set(Wrapping(value as i32));
+        true
+    }
+
+    /// Moves the pointer by `amount`, growing the tape when it goes past the
+    /// end. Returns whether it moved, it doesn't when it would go left of the
+    /// first cell of a tape that isn't two sided, or off a tape with a size
+    /// that doesn't wrap.
+    #[inline]
+    #[must_use]
+    pub fn move_by(&mut self, amount: isize) -> bool {
+        if let Some(size) = self.size {
+            return self.move_within(size, amount);
+        }
+        let pointer = match self.pointer.checked_add_signed(amount) {
+            Some(pointer) => pointer,
+            None if self.two_sided => {
+                self.grow_left(amount.unsigned_abs() - self.pointer);
+                self.pointer - amount.unsigned_abs()
+            }
+            None => return false,
+        };
+        if pointer >= self.cells.len() {
+            self.cells.resize(pointer + 1, Wrapping(0));
+        }
+        self.pointer = pointer;
+        true
+    }
+
+    // moves the pointer on a tape of `size` cells
+    fn move_within(&mut self, size: usize, amount: isize) -> bool {
+        let Some(target) = (self.pointer as isize).checked_add(amount) else {
+            return false;
+        };
+        let pointer = match self.wrap {
+            true => target.rem_euclid(size as isize) as usize,
+            false if (0..size as isize).contains(&target) => target as usize,
+            false => return false,
+        };
+        if pointer >= self.cells.len() {
+            self.cells.resize(pointer + 1, Wrapping(0));
+        }
+        self.pointer = pointer;
+        true
+    }
+
+    // Adds at least `missing` zeroed cells in front, doubling the tape so
+    // walking left takes as few copies as walking right.
+    #[cold]
+    fn grow_left(&mut self, missing: usize) {
+        let grown = missing.max(self.cells.len());
+        self.cells.splice(0..0, core::iter::repeat_n(Wrapping(0), grown));
+        self.pointer += grown;
+        self.origin += grown;
+    }
+
+    /// Starts the tape out with a cell for every byte of `data`, or a single
+    /// zeroed one when there's none, and the pointer on the first.
+    pub fn load(&mut self, data: &[u8]) {
+        self.cells.clear();
+        self.cells.extend(data.iter().map(|&byte| Wrapping(i32::from(byte))));
+        if self.cells.is_empty() {
+            self.cells.push(Wrapping(0));
+        }
+        self.pointer = 0;
+        self.origin = 0;
+    }
+
+    /// The cells around the pointer, for `Op::DumpTape` to print.
+    pub fn dump(&self) -> TapeDump<'_> {
+        TapeDump(self)
+    }
+
+    /// Puts back cells, a pointer and an origin taken from another tape.
+    pub fn restore(&mut self, cells: &[Cell], pointer: usize, origin: usize) {
+        self.cells.clear();
+        self.cells.extend_from_slice(cells);
+        self.pointer = pointer;
+        self.origin = origin;
+    }
+}
+
+/// Shows the `DUMP_RADIUS` cells on either side of the pointer, like
+/// `tape at 5: 0 0 72 105 [33] 0 0 0 0`, counting from the cell the pointer
+/// started on. Cells past the end of the tape are 0, so compiled programs,
+/// which have all of theirs from the start, show the same. A two sided tape
+/// shows as many cells left of its first as it would have grown there.
+pub struct TapeDump<'a>(&'a Tape);
+
+impl fmt::Display for TapeDump<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let tape = self.0;
+        write!(f, "tape at {}:", tape.position())?;
+        let pointer = tape.pointer as isize;
+        let first = match tape.two_sided {
+            true => pointer - DUMP_RADIUS as isize,
+            false => pointer.saturating_sub(DUMP_RADIUS as isize).max(0),
+        };
+        for index in first..=pointer + DUMP_RADIUS as isize {
+            // cells are never more than 32 bits, and unsigned
+            let cell = usize::try_from(index)
+                .ok()
+                .and_then(|index| tape.cells.get(index))
+                .map_or(0, |cell| cell.0 as u32);
+            if index == pointer {
+                write!(f, " [{}]", cell)?;
+            } else {
+                write!(f, " {}", cell)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+impl Default for Tape {
+    fn default() -> Self {
+        Self::new()
+    }
+}
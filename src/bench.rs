@@ -0,0 +1,101 @@
+use std::io::{self, Write};
+use std::time::{Duration, Instant};
+
+use crate::compiler::{self, CompileOptions, Emit};
+use crate::error::MidilangResult;
+use crate::interpreter::RunOptions;
+use crate::parser::ParseOptions;
+use crate::{optimizer, parse_source, run_error, vm, IN_MEMORY};
+
+/// Phases of getting a program running that get timed, in order.
+pub const PHASES: [&str; 4] = ["parse", "optimize", "compile", "run"];
+
+/// How long every phase took each time a program went through them.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Timings {
+    /// Times of each of `PHASES`, one for every run
+    pub phases: [Vec<Duration>; 4],
+}
+
+impl Timings {
+    /// Parses the MIDI file in `bytes` with `parse`, optimizes it, compiles it
+    /// to an object file with the backend in `options` and runs it on the
+    /// bytecode VM with `input`, `runs` times over, timing each phase.
+    pub fn measure(
+        bytes: &[u8],
+        parse: &ParseOptions,
+        input: &[u8],
+        options: CompileOptions,
+        limits: RunOptions,
+        runs: usize,
+    ) -> MidilangResult<Self> {
+        let mut timings = Timings::default();
+        // the VM runs the program like it runs compiled
+        let limits = options.run_options(limits);
+        for _ in 0..runs {
+            let started = Instant::now();
+            let (program, source_map, data) = parse_source(IN_MEMORY, bytes, parse)?;
+            timings.phases[0].push(started.elapsed());
+
+            let started = Instant::now();
+            let program = optimizer::optimize_with_data(program, options.cell_width, &limits, &data);
+            timings.phases[1].push(started.elapsed());
+
+            let started = Instant::now();
+            let compile_options = CompileOptions { data: data.clone(), ..options.clone() };
+            compiler::compile_program(program.clone(), Some(source_map.clone()), compile_options)?.emit_bytes(Emit::Obj)?;
+            timings.phases[2].push(started.elapsed());
+
+            let started = Instant::now();
+            let code = vm::Bytecode::new(&program);
+            let mut machine = crate::new_vm(options.cell_width, limits);
+            machine.load_data(&data);
+            let result = machine.run(&code, &mut &input[..], &mut io::sink());
+            timings.phases[3].push(started.elapsed());
+            result.map_err(|err| run_error(err, &source_map))?;
+        }
+        Ok(timings)
+    }
+
+    /// Writes the median, fastest and slowest time of every phase.
+    ///
+    /// ```text
+    /// phase         median     fastest     slowest
+    /// parse        301.2µs     295.0µs     350.9µs
+    /// ```
+    pub fn write_report(&self, out: &mut impl Write) -> io::Result<()> {
+        writeln!(out, "{:<8} {:>11} {:>11} {:>11}", "phase", "median", "fastest", "slowest")?;
+        for (phase, times) in PHASES.iter().zip(&self.phases) {
+            let mut times = times.clone();
+            times.sort_unstable();
+            let Some((&fastest, &slowest)) = times.first().zip(times.last()) else {
+                continue;
+            };
+            let median = times[times.len() / 2];
+            let [median, fastest, slowest] = [median, fastest, slowest].map(|time| format!("{:.1?}", time));
+            writeln!(out, "{:<8} {:>11} {:>11} {:>11}", phase, median, fastest, slowest)?;
+        }
+        Ok(())
+    }
+}
+
+// timing compiles, which takes a backend
+#[cfg(all(test, any(feature = "llvm", feature = "cranelift")))]
+mod tests {
+
+    use super::*;
+
+    #[test]
+    fn times_every_phase() {
+        let mut bytes = vec![];
+        crate::brainf_to_smf("++[>+++<-]>.").write_std(&mut bytes).unwrap();
+        let timings = Timings::measure(&bytes, &ParseOptions::default(), b"", CompileOptions::default(), RunOptions::default(), 3).unwrap();
+        assert!(timings.phases.iter().all(|times| times.len() == 3));
+
+        let mut report = vec![];
+        timings.write_report(&mut report).unwrap();
+        let report = String::from_utf8(report).unwrap();
+        let phases: Vec<&str> = report.lines().skip(1).map(|line| line.split(' ').next().unwrap()).collect();
+        assert_eq!(phases, PHASES);
+    }
+}
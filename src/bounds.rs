@@ -0,0 +1,221 @@
+use std::fmt::Display;
+
+use crate::parser::{MidiAST, MidiInstructionKind::*, Position};
+use crate::timing::SourceMap;
+
+/// A move that takes the pointer off the tape wherever it was before it,
+/// every time the program gets to it.
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub struct OutOfBounds {
+    /// Position of the move
+    pub position: Option<Position>,
+    /// Lowest and highest cell the pointer can be on after the move, `None`
+    /// when it can be any number of cells further
+    pub cells: (Option<i64>, Option<i64>),
+    /// Cells on the tape, `None` when it's left of the first cell
+    pub past_tape: Option<u64>,
+    /// Positions of the moves that took the pointer there, the last one
+    /// being the move itself
+    pub chain: Vec<Position>,
+}
+
+impl OutOfBounds {
+    /// What went wrong, with where the moves are in the song.
+    pub fn describe(&self, source_map: &SourceMap) -> String {
+        let cells = match self.cells {
+            (Some(low), Some(high)) if low == high => format!("cell {}", low),
+            (Some(low), Some(high)) => format!("cells {} to {}", low, high),
+            (None, Some(high)) => format!("cell {} or further left", high),
+            (Some(low), None) => format!("cell {} or further right", low),
+            (None, None) => "anywhere".to_owned(),
+        };
+        let side = match self.past_tape {
+            Some(tape_size) => format!("past the end of the {} cell tape", tape_size),
+            None => "left of the first cell".to_owned(),
+        };
+        let at = self.position.map_or_else(|| "somewhere".to_owned(), |pos| source_map.describe(pos));
+        let chain: Vec<String> = self.chain.iter().map(|pos| source_map.describe(*pos)).collect();
+        format!("pointer always moves to {}, {}, at {} (moved there by {})", cells, side, at, chain.join(", "))
+    }
+}
+
+impl Display for OutOfBounds {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(&self.describe(&SourceMap::default()))
+    }
+}
+
+/// Finds the first move in `program` that definitely takes the pointer left of
+/// the first cell, or past the end of a tape of `tape_size` cells when given.
+///
+/// Goes through the program keeping track of the lowest and highest cell the
+/// pointer can be on, along with the moves that put it there. Loops that move
+/// the pointer are assumed to go around any number of times, so only moves
+/// the pointer leaves the tape on wherever it was are found.
+pub fn check(program: &MidiAST, tape_size: Option<u64>) -> Option<OutOfBounds> {
+    let mut found = None;
+    Analysis { tape_size, found: &mut found, report: true }.body(program, Range::start());
+    found
+}
+
+/// Cells the pointer can be on, `None` for no bound.
+#[derive(Debug, PartialEq, Eq, Clone)]
+struct Range {
+    low: Option<i64>,
+    high: Option<i64>,
+    /// Moves that took `low` where it is
+    low_chain: Vec<Position>,
+    /// Moves that took `high` where it is
+    high_chain: Vec<Position>,
+}
+
+impl Range {
+    fn start() -> Self {
+        Range {
+            low: Some(0),
+            high: Some(0),
+            low_chain: vec![],
+            high_chain: vec![],
+        }
+    }
+
+    fn contains(&self, other: &Range) -> bool {
+        let low = match (self.low, other.low) {
+            (None, _) => true,
+            (Some(_), None) => false,
+            (Some(this), Some(that)) => this <= that,
+        };
+        let high = match (self.high, other.high) {
+            (None, _) => true,
+            (Some(_), None) => false,
+            (Some(this), Some(that)) => this >= that,
+        };
+        low && high
+    }
+
+    /// Gives up on the bounds that `other` goes past.
+    fn widen(&mut self, other: &Range) {
+        if other.low.is_none() || other.low < self.low {
+            self.low = None;
+            self.low_chain.clear();
+        }
+        if other.high.is_none() || self.high.zip(other.high).is_some_and(|(this, that)| that > this) {
+            self.high = None;
+            self.high_chain.clear();
+        }
+    }
+}
+
+struct Analysis<'a> {
+    tape_size: Option<u64>,
+    found: &'a mut Option<OutOfBounds>,
+    /// Whether to report what's found, loops are gone through quietly until
+    /// it's known where they can take the pointer
+    report: bool,
+}
+
+impl Analysis<'_> {
+    /// Where the pointer can be after `body`, starting in `range`.
+    fn body(&mut self, body: &MidiAST, mut range: Range) -> Range {
+        for inst in body {
+            if self.found.is_some() {
+                break;
+            }
+            match &inst.instruction {
+                MovePointer { amount } => {
+                    let amount = *amount as i64;
+                    range.low = range.low.map(|low| low.saturating_add(amount));
+                    range.high = range.high.map(|high| high.saturating_add(amount));
+                    if let Some(position) = inst.position {
+                        range.low_chain.push(position);
+                        range.high_chain.push(position);
+                    }
+                    self.check(inst.position, &range);
+                }
+                Loop { body } => {
+                    // loops go around until the pointer can't go anywhere new
+                    let report = std::mem::replace(&mut self.report, false);
+                    let mut head = range.clone();
+                    loop {
+                        let after = self.body(body, head.clone());
+                        if head.contains(&after) {
+                            break;
+                        }
+                        head.widen(&after);
+                    }
+                    self.report = report;
+                    if self.report {
+                        self.body(body, head.clone());
+                    }
+                    range = head;
+                }
+                _ => {}
+            }
+        }
+        range
+    }
+
+    fn check(&mut self, position: Option<Position>, range: &Range) {
+        if !self.report {
+            return;
+        }
+        let past_tape = self.tape_size.filter(|&size| range.low.is_some_and(|low| low >= size as i64));
+        let below = range.high.is_some_and(|high| high < 0);
+        if !below && past_tape.is_none() {
+            return;
+        }
+        *self.found = Some(OutOfBounds {
+            position,
+            cells: (range.low, range.high),
+            past_tape,
+            chain: if below { range.high_chain.clone() } else { range.low_chain.clone() },
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+    use crate::MidiProgram;
+
+    type Found = (Option<i64>, Option<i64>, Option<u64>, Vec<usize>);
+
+    fn found(bf: &str, tape_size: Option<u64>) -> Option<Found> {
+        let program = MidiProgram::from_bf_str(bf).unwrap();
+        check(program.ast(), tape_size)
+            .map(|oob| (oob.cells.0, oob.cells.1, oob.past_tape, oob.chain.iter().map(Position::start).collect()))
+    }
+
+    #[test]
+    fn finds_moves_off_either_end() {
+        assert_eq!(found(">+<<", None), Some((Some(-1), Some(-1), None, vec![0, 2, 3])));
+        assert_eq!(found(">>>>", Some(3)), Some((Some(3), Some(3), Some(3), vec![0, 1, 2])));
+        assert_eq!(found(">>>>", None), None);
+        assert_eq!(found("><", Some(2)), None);
+    }
+
+    #[test]
+    fn follows_the_pointer_through_loops() {
+        // the loop can take the pointer anywhere to the right, but not left
+        assert_eq!(found(">[>]<<", None), None);
+        assert_eq!(found("[>]<", None), None);
+        // loops that go back to where they started
+        assert_eq!(found("+[>+<-]<", None), Some((Some(-1), Some(-1), None, vec![7])));
+        assert_eq!(found("+[-<<>>]", None), Some((Some(-1), Some(-1), None, vec![3])));
+        // moving left any number of times is only wrong once it has to be
+        assert_eq!(found("+[<]", None), Some((None, Some(-1), None, vec![2])));
+        assert_eq!(found(">>+[<]", None), None);
+    }
+
+    #[test]
+    fn describes_where_the_moves_are() {
+        let program = MidiProgram::from_bf_str("><<").unwrap();
+        let oob = check(program.ast(), None).unwrap();
+        assert_eq!(
+            oob.to_string(),
+            "pointer always moves to cell -1, left of the first cell, at instruction 2 \
+             (moved there by instruction 0, instruction 1, instruction 2)"
+        );
+    }
+}
@@ -0,0 +1,200 @@
+use std::fmt::Write;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::process;
+
+use log::{debug, warn};
+use sha2::{Digest, Sha256};
+
+use crate::parser::{self, ParseOptions, Parsed, TrackReading};
+use crate::progress::Progress;
+use crate::sysex::{self, Includes};
+
+/// Name of the directory the cache is kept in, next to the files it's for.
+pub const CACHE_DIR: &str = ".midilang-cache";
+
+/// Tracks read and programs compiled by earlier builds, so building a file
+/// again only reads the tracks that changed, and compiles nothing if none did.
+///
+/// Tracks are kept by a hash of their bytes, outputs by the hashes of every
+/// track of the file along with how it was compiled. Nothing is ever taken
+/// out, removing the directory empties it.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Cache {
+    dir: PathBuf,
+}
+
+impl Cache {
+    pub fn new(dir: PathBuf) -> Self {
+        Cache { dir }
+    }
+
+    /// The cache in the directory `source` is in.
+    pub fn beside(source: &Path) -> Self {
+        let dir = match source.parent() {
+            Some(dir) if !dir.as_os_str().is_empty() => dir,
+            _ => Path::new("."),
+        };
+        Cache::new(dir.join(CACHE_DIR))
+    }
+
+    /// Parses the MIDI file in `bytes` like `parser::parse_all_bytes_in`,
+    /// reading only the tracks that aren't in the cache yet and keeping those.
+    /// Included files are read every time.
+    pub fn parse(&self, bytes: &[u8], includes: &Includes, options: &ParseOptions) -> midly::Result<Parsed> {
+        let (header, tracks) = midly::parse(bytes)?;
+        let tracks = tracks.collect::<midly::Result<Vec<_>>>()?;
+        let total = tracks.iter().map(|events| events.unread().len() as u64).sum();
+        let tracks = tracks.into_iter().map(|events| {
+            move |progress: &Progress| {
+                let path = self.entry("tracks", &hash(&[events.unread()]), "json");
+                if let Some(reading) = fs::read(&path).ok().and_then(|json| serde_json::from_slice(&json).ok()) {
+                    progress.inc(events.unread().len() as u64);
+                    return reading;
+                }
+                let reading: TrackReading = parser::read_track(parser::raw_events(events), progress);
+                self.keep(&path, &serde_json::to_vec(&reading).unwrap_or_default());
+                reading
+            }
+        });
+        Ok(parser::collect_parsed(header, tracks.collect(), total, includes, options))
+    }
+
+    /// Key of what compiling the MIDI file in `bytes` the way `settings` says
+    /// makes, `None` when it isn't a MIDI file or includes other files, which
+    /// can change without it.
+    pub fn output_key(bytes: &[u8], settings: &str) -> Option<String> {
+        let (header, tracks) = midly::parse(bytes).ok()?;
+        let tracks = tracks.collect::<midly::Result<Vec<_>>>().ok()?;
+        let mut events = tracks.iter().flat_map(|events| events.clone().filter_map(Result::ok));
+        if events.any(|event| sysex::is_include(&event.kind)) {
+            return None;
+        }
+        let header = format!("{:?}", header);
+        let mut parts = vec![header.as_bytes(), settings.as_bytes()];
+        parts.extend(tracks.iter().map(|events| events.unread()));
+        Some(hash(&parts))
+    }
+
+    /// Where the output with `key` is kept, if it is.
+    pub fn output(&self, key: &str) -> Option<PathBuf> {
+        Some(self.entry("outputs", key, "out")).filter(|path| path.is_file())
+    }
+
+    /// Keeps a copy of the output file at `path` as the output with `key`.
+    pub fn keep_output(&self, key: &str, path: &Path) {
+        // copying keeps an executable executable
+        let entry = self.entry("outputs", key, "out");
+        if let Err(err) = self.make_dirs(&entry).and_then(|()| fs::copy(path, temp(&entry))).and_then(|_| fs::rename(temp(&entry), &entry)) {
+            warn!("Could not keep {} in {}: {}", path.display(), self.dir.display(), err);
+        }
+    }
+
+    /// Keeps `bytes` as the output with `key`.
+    pub fn keep_output_bytes(&self, key: &str, bytes: &[u8]) {
+        self.keep(&self.entry("outputs", key, "out"), bytes);
+    }
+
+    fn entry(&self, kind: &str, key: &str, extension: &str) -> PathBuf {
+        self.dir.join(kind).join(key).with_extension(extension)
+    }
+
+    // writes an entry whole or not at all, so builds running at once never
+    // read half of one. Failing only costs the next build time
+    fn keep(&self, entry: &Path, bytes: &[u8]) {
+        let written = self.make_dirs(entry).and_then(|()| fs::write(temp(entry), bytes)).and_then(|()| fs::rename(temp(entry), entry));
+        match written {
+            Ok(()) => debug!("Kept {}", entry.display()),
+            Err(err) => warn!("Could not write {}: {}", entry.display(), err),
+        }
+    }
+
+    fn make_dirs(&self, entry: &Path) -> io::Result<()> {
+        if let Some(dir) = entry.parent() {
+            fs::create_dir_all(dir)?;
+        }
+        // keep the cache out of version control
+        let ignore = self.dir.join(".gitignore");
+        if !ignore.exists() {
+            fs::write(ignore, "*\n")?;
+        }
+        Ok(())
+    }
+}
+
+// the version goes in every hash, a new midilang might read or compile differently
+fn hash(parts: &[&[u8]]) -> String {
+    let mut hasher = Sha256::new();
+    hasher.update(env!("CARGO_PKG_VERSION"));
+    for part in parts {
+        // lengths keep parts from running into each other
+        hasher.update((part.len() as u64).to_le_bytes());
+        hasher.update(part);
+    }
+    let mut hex = String::with_capacity(64);
+    for byte in hasher.finalize() {
+        write!(hex, "{:02x}", byte).unwrap();
+    }
+    hex
+}
+
+fn temp(entry: &Path) -> PathBuf {
+    entry.with_extension(format!("{}.tmp", process::id()))
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+    use crate::compiler;
+
+    fn song(bf: &str) -> Vec<u8> {
+        let mut bytes = vec![];
+        crate::brainf_to_smf(bf).write_std(&mut bytes).unwrap();
+        bytes
+    }
+
+    #[test]
+    fn reads_tracks_once() {
+        let cache = Cache::new(compiler::temp_path("cache"));
+        let bytes = song("+[>,.<]");
+        let read = parser::parse_all_bytes(&bytes).unwrap();
+        let first = cache.parse(&bytes, &Includes::default(), &ParseOptions::default()).unwrap();
+        assert_eq!((&first.ast, &first.chords), (&read.ast, &read.chords));
+
+        // the next parse takes the track from the cache, whatever it says
+        let (_, tracks) = midly::parse(&bytes).unwrap();
+        let program = tracks.last().unwrap().unwrap();
+        let entry = cache.entry("tracks", &hash(&[program.unread()]), "json");
+        let json = fs::read_to_string(&entry).unwrap();
+        fs::write(&entry, json.replacen("[9]", "[5]", 1)).unwrap();
+        let ast = cache.parse(&bytes, &Includes::default(), &ParseOptions::default()).unwrap().ast.unwrap();
+        assert_eq!(ast[0].instruction, parser::MidiInstruction::new_inc(std::num::Wrapping(-1)).instruction);
+        assert_eq!(fs::read_to_string(cache.dir.join(".gitignore")).unwrap(), "*\n");
+        fs::remove_dir_all(&cache.dir).unwrap();
+    }
+
+    #[test]
+    fn keys_outputs_by_tracks_and_settings() {
+        let bytes = song("+.");
+        let key = Cache::output_key(&bytes, "-O0").unwrap();
+        assert_eq!(Cache::output_key(&bytes, "-O0").unwrap(), key);
+        assert_ne!(Cache::output_key(&bytes, "-O2").unwrap(), key);
+        assert_ne!(Cache::output_key(&song("-."), "-O0").unwrap(), key);
+        assert_eq!(Cache::output_key(b"[]", "-O0"), None);
+        // what an include compiles to can change without the song changing
+        let mut smf = crate::brainf_to_smf("+.");
+        let data = sysex::Directive::Include("riff.mid".to_owned()).to_sysex();
+        smf.tracks[1].insert(0, midly::TrackEvent { delta: 0.into(), kind: midly::TrackEventKind::SysEx(&data) });
+        let mut bytes = vec![];
+        smf.write_std(&mut bytes).unwrap();
+        assert_eq!(Cache::output_key(&bytes, "-O0"), None);
+
+        let cache = Cache::new(compiler::temp_path("cache"));
+        assert_eq!(cache.output(&key), None);
+        cache.keep_output_bytes(&key, b"compiled");
+        assert_eq!(fs::read(cache.output(&key).unwrap()).unwrap(), b"compiled");
+        fs::remove_dir_all(&cache.dir).unwrap();
+    }
+}
@@ -0,0 +1,661 @@
+use std::collections::{HashMap, HashSet};
+use std::fmt::Display;
+
+use midly::Smf;
+
+use crate::parser::{parse_all, MidiAST, MidiInstruction, MidiInstructionKind::*, Parsed, Position};
+use crate::timing::BarBeat;
+
+#[derive(Debug, PartialEq, Eq, Clone, Copy, PartialOrd, Ord)]
+pub enum Severity {
+    Warning,
+    Error,
+}
+
+impl Display for Severity {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Warning => f.pad("warning"),
+            Self::Error => f.pad("error"),
+        }
+    }
+}
+
+/// Something wrong with a program, at the chord it's about if it has one.
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub struct Diagnostic {
+    /// Short name of the kind of problem, for tools to match on
+    pub code: &'static str,
+    pub severity: Severity,
+    pub tick: Option<u64>,
+    /// `None` for timecode based files too
+    pub bar_beat: Option<BarBeat>,
+    /// Index of the chord in `Parsed::chords`
+    pub chord: Option<usize>,
+    /// Keys of the chord's notes, lowest first
+    pub notes: Vec<u8>,
+    pub message: String,
+}
+
+impl Diagnostic {
+    /// A problem with the file as a whole.
+    pub fn file(code: &'static str, severity: Severity, message: impl Into<String>) -> Self {
+        Diagnostic {
+            code,
+            severity,
+            tick: None,
+            bar_beat: None,
+            chord: None,
+            notes: vec![],
+            message: message.into(),
+        }
+    }
+}
+
+/// Result of checking a file, diagnostics are sorted by where they are.
+#[derive(Debug, Clone)]
+pub struct Report {
+    pub diagnostics: Vec<Diagnostic>,
+}
+
+impl Report {
+    /// Worst severity found, `None` for a clean file.
+    pub fn severity(&self) -> Option<Severity> {
+        self.diagnostics.iter().map(|diag| diag.severity).max()
+    }
+}
+
+/// Parses `midi`, reporting every chord that doesn't parse instead of stopping
+/// at the first. With `lint`, programs that do parse are also looked over for
+/// code that is almost certainly a mistake.
+pub fn check(midi: Smf, lint: bool) -> Report {
+    check_parsed(&parse_all(midi), lint)
+}
+
+/// Like `check`, for a file that's already parsed.
+pub fn check_parsed(parsed: &Parsed, lint: bool) -> Report {
+    report(parsed, lint.then(LintLevels::default).as_ref())
+}
+
+/// Like `check` with lints, at the severities in `levels`.
+pub fn lint_parsed(parsed: &Parsed, levels: &LintLevels) -> Report {
+    report(parsed, Some(levels))
+}
+
+fn report(parsed: &Parsed, levels: Option<&LintLevels>) -> Report {
+    let at_chord = |code, severity, chord: Option<usize>, message| {
+        let tick = chord.and_then(|chord| parsed.chords.get(chord)).map(|chord| chord.tick);
+        Diagnostic {
+            code,
+            severity,
+            tick,
+            bar_beat: tick.and_then(|tick| parsed.source_map.bar_beat_at(tick)),
+            chord,
+            notes: chord.and_then(|chord| parsed.chords.get(chord)).map_or_else(Vec::new, |chord| chord.notes.clone()),
+            message,
+        }
+    };
+    let mut diagnostics: Vec<Diagnostic> = parsed
+        .errors
+        .iter()
+        .map(|err| match err.chord {
+            Some(chord) => at_chord(err.error.code(), Severity::Error, Some(chord), err.error.describe()),
+            None => Diagnostic::file(err.error.code(), Severity::Error, err.error.describe()),
+        })
+        .collect();
+    if let (Ok(program), Some(levels)) = (&parsed.ast, levels) {
+        for lint in LINTS {
+            let Some(severity) = levels.severity(*lint) else {
+                continue;
+            };
+            let mut found = vec![];
+            lint.check(program, &mut found);
+            diagnostics.extend(found.into_iter().map(|(pos, message)| {
+                let chord = pos.and_then(|pos| parsed.instructions.get(pos.start()).copied());
+                at_chord(lint.name(), severity, chord, message)
+            }));
+        }
+    }
+    diagnostics.sort_by_key(|diag| diag.chord);
+    Report { diagnostics }
+}
+
+/// Where a lint found something, and what.
+pub type Finding = (Option<Position>, String);
+
+/// A look over programs that parsed for code that is almost certainly a
+/// mistake. Every lint is in `LINTS`.
+pub trait Lint {
+    /// Name to allow or deny the lint by, and the code of what it finds
+    fn name(&self) -> &'static str;
+
+    /// What the lint looks for, in a few words
+    fn description(&self) -> &'static str;
+
+    /// Severity of what it finds unless it's allowed or denied
+    fn default_severity(&self) -> Severity {
+        Severity::Warning
+    }
+
+    fn check(&self, program: &MidiAST, found: &mut Vec<Finding>);
+}
+
+/// Every lint, in the order they're listed.
+pub const LINTS: &[&dyn Lint] = &[
+    &DeadLoop,
+    &EmptyLoop,
+    &InfiniteLoop,
+    &UnreachableCode,
+    &CancelingPair,
+    &HugeMove,
+    &LeftoverBreakpoint,
+    &PointerUnderflow,
+];
+
+/// Severity of every lint, changed from their defaults by name.
+#[derive(Debug, Clone, Default)]
+pub struct LintLevels {
+    changed: Vec<(&'static str, Option<Severity>)>,
+}
+
+impl LintLevels {
+    /// Sets the severity of the lint called `name`, `None` allows it. Later
+    /// changes win over earlier ones. Fails on names that aren't lints.
+    pub fn set(&mut self, name: &str, severity: Option<Severity>) -> Result<(), String> {
+        let lint = LINTS.iter().find(|lint| lint.name() == name).ok_or_else(|| format!("no lint called `{}`", name))?;
+        self.changed.push((lint.name(), severity));
+        Ok(())
+    }
+
+    /// Severity of what `lint` finds, `None` when it's allowed.
+    pub fn severity(&self, lint: &dyn Lint) -> Option<Severity> {
+        match self.changed.iter().rev().find(|(name, _)| *name == lint.name()) {
+            Some((_, severity)) => *severity,
+            None => Some(lint.default_severity()),
+        }
+    }
+}
+
+/// Loops where the cell is always zero, after the program starts or another loop.
+pub struct DeadLoop;
+
+impl Lint for DeadLoop {
+    fn name(&self) -> &'static str {
+        "dead-loop"
+    }
+
+    fn description(&self) -> &'static str {
+        "loops that never run"
+    }
+
+    fn check(&self, program: &MidiAST, found: &mut Vec<Finding>) {
+        visit_loops(program, true, &mut |position, _, dead| {
+            if let Some(reason) = dead {
+                found.push((position, format!("loop never runs, the cell is always zero after {}", reason)));
+            }
+        });
+    }
+}
+
+/// Empty loops that might run, and never end when they do.
+pub struct EmptyLoop;
+
+impl Lint for EmptyLoop {
+    fn name(&self) -> &'static str {
+        "empty-loop"
+    }
+
+    fn description(&self) -> &'static str {
+        "empty loops that never end once entered"
+    }
+
+    fn check(&self, program: &MidiAST, found: &mut Vec<Finding>) {
+        visit_loops(program, true, &mut |position, body, dead| {
+            if dead.is_none() && body.is_empty() {
+                found.push((position, "empty loop never ends once entered".to_owned()));
+            }
+        });
+    }
+}
+
+/// Calls `visit` with the position and body of every loop in `body` and the
+/// loops in it, and why the loop never runs if it can't. `top` is whether
+/// `body` is the whole program, which starts with every cell at zero.
+fn visit_loops<F: FnMut(Option<Position>, &MidiAST, Option<&str>)>(body: &MidiAST, top: bool, visit: &mut F) {
+    let mut after_loop = top;
+    for MidiInstruction { position, instruction } in body {
+        if let Loop { body } = instruction {
+            let dead = after_loop.then(|| match top && position.is_some_and(|pos| pos.start() == 0) {
+                true => "the program starts",
+                false => "a loop",
+            });
+            visit(*position, body, dead);
+            visit_loops(body, false, visit);
+        }
+        after_loop = matches!(instruction, Loop { .. });
+    }
+}
+
+/// Loops that never end once they're entered, because nothing in them changes
+/// the cell they check, like `[>+<]`. Empty loops are left to `EmptyLoop`.
+pub struct InfiniteLoop;
+
+impl Lint for InfiniteLoop {
+    fn name(&self) -> &'static str {
+        "infinite-loop"
+    }
+
+    fn description(&self) -> &'static str {
+        "loops that never change the cell they check"
+    }
+
+    fn check(&self, program: &MidiAST, found: &mut Vec<Finding>) {
+        visit_loops(program, true, &mut |position, body, dead| {
+            if dead.is_none() && !body.is_empty() && never_ends(body) {
+                found.push((position, "loop never ends once entered, nothing in it changes the cell it checks".to_owned()));
+            }
+        });
+    }
+}
+
+/// Whether a loop with `body` goes around forever once it's entered: it ends
+/// up back on the cell it checks and never writes to it.
+fn never_ends(body: &MidiAST) -> bool {
+    written_cells(body).is_some_and(|(written, end)| end == 0 && !written.contains(&0))
+}
+
+/// Cells `body` can write to and where the pointer ends up, both from where it
+/// starts, or `None` when a loop in it moves the pointer somewhere unknown.
+fn written_cells(body: &MidiAST) -> Option<(HashSet<isize>, isize)> {
+    let mut written = HashSet::new();
+    let mut pointer: isize = 0;
+    for inst in body {
+        match &inst.instruction {
+            IncrementCell { .. } | InputCell | Call { .. } | Random => {
+                written.insert(pointer);
+            }
+            MovePointer { amount } => pointer = pointer.checked_add(*amount)?,
+            Loop { body } => match written_cells(body)? {
+                (inner, 0) => written.extend(inner.into_iter().map(|cell| cell + pointer)),
+                _ => return None,
+            },
+            OutputCell | Breakpoint | DumpTape => {}
+        }
+    }
+    Some((written, pointer))
+}
+
+/// Code after a loop that's certain to be entered and can never end, see
+/// `InfiniteLoop`.
+pub struct UnreachableCode;
+
+impl Lint for UnreachableCode {
+    fn name(&self) -> &'static str {
+        "unreachable-code"
+    }
+
+    fn description(&self) -> &'static str {
+        "code after a loop that never ends"
+    }
+
+    fn check(&self, program: &MidiAST, found: &mut Vec<Finding>) {
+        unreachable_after(program, KnownCells::start(), found);
+    }
+}
+
+fn unreachable_after(body: &MidiAST, mut cells: KnownCells, found: &mut Vec<Finding>) {
+    for (idx, inst) in body.iter().enumerate() {
+        match &inst.instruction {
+            IncrementCell { amount } => cells.add(amount.0),
+            MovePointer { amount } => cells.pointer += amount,
+            InputCell | Call { .. } | Random => cells.forget(),
+            OutputCell | Breakpoint | DumpTape => {}
+            Loop { body: loop_body } => {
+                if never_ends(loop_body) && cells.nonzero() {
+                    if let Some(next) = body.get(idx + 1) {
+                        found.push((next.position, "unreachable, the loop before it never ends".to_owned()));
+                    }
+                    return;
+                }
+                unreachable_after(loop_body, KnownCells::entering_loop(), found);
+                cells = KnownCells::after_loop();
+            }
+        }
+    }
+}
+
+/// What is certain about a cell while going through a program without running it.
+#[derive(Debug, Clone, Copy)]
+enum Known {
+    Value(i32),
+    NonZero,
+    Unknown,
+}
+
+/// What is certain about the cells around the pointer.
+struct KnownCells {
+    /// By where they are from the pointer the cells were first known from
+    cells: HashMap<isize, Known>,
+    pointer: isize,
+    /// What is known about every cell not in `cells`
+    rest: Known,
+}
+
+impl KnownCells {
+    /// Programs start with every cell at zero
+    fn start() -> Self {
+        KnownCells {
+            cells: HashMap::new(),
+            pointer: 0,
+            rest: Known::Value(0),
+        }
+    }
+
+    /// Loop bodies start on a cell that isn't zero, and nothing else is known
+    fn entering_loop() -> Self {
+        KnownCells {
+            cells: HashMap::from([(0, Known::NonZero)]),
+            pointer: 0,
+            rest: Known::Unknown,
+        }
+    }
+
+    /// Loops end on a zero cell, and nothing else is known
+    fn after_loop() -> Self {
+        KnownCells {
+            cells: HashMap::from([(0, Known::Value(0))]),
+            pointer: 0,
+            rest: Known::Unknown,
+        }
+    }
+
+    fn current(&self) -> Known {
+        self.cells.get(&self.pointer).copied().unwrap_or(self.rest)
+    }
+
+    fn add(&mut self, amount: i32) {
+        let added = match self.current() {
+            Known::Value(value) => Known::Value(value.wrapping_add(amount)),
+            _ => Known::Unknown,
+        };
+        self.cells.insert(self.pointer, added);
+    }
+
+    fn forget(&mut self) {
+        self.cells.insert(self.pointer, Known::Unknown);
+    }
+
+    /// Whether the cell can't be zero, whatever the width of the cells. Values
+    /// that aren't zero in the lowest byte aren't zero in a wider cell either
+    fn nonzero(&self) -> bool {
+        match self.current() {
+            Known::Value(value) => value & 0xff != 0,
+            Known::NonZero => true,
+            Known::Unknown => false,
+        }
+    }
+}
+
+/// Neighbouring instructions that undo each other, like `+` right before `-`.
+pub struct CancelingPair;
+
+impl Lint for CancelingPair {
+    fn name(&self) -> &'static str {
+        "canceling-pair"
+    }
+
+    fn description(&self) -> &'static str {
+        "instructions that undo the one before them"
+    }
+
+    fn check(&self, program: &MidiAST, found: &mut Vec<Finding>) {
+        for pair in program.windows(2) {
+            let cancels = match (&pair[0].instruction, &pair[1].instruction) {
+                (IncrementCell { amount: first }, IncrementCell { amount: second }) => (first + second).0 == 0,
+                (MovePointer { amount: first }, MovePointer { amount: second }) => first + second == 0,
+                _ => false,
+            };
+            if cancels {
+                found.push((
+                    pair[0].position,
+                    format!("{} and {} cancel out", pair[0].instruction.describe(), pair[1].instruction.describe()),
+                ));
+            }
+        }
+        for inst in program {
+            if let Loop { body } = &inst.instruction {
+                self.check(body, found);
+            }
+        }
+    }
+}
+
+/// Moves that take the note for the highest bit of the argument, which is
+/// more often a wrong note than a program that jumps that far.
+pub struct HugeMove;
+
+/// Moves further than this are suspicious.
+const HUGE_MOVE: usize = 255;
+
+impl Lint for HugeMove {
+    fn name(&self) -> &'static str {
+        "huge-move"
+    }
+
+    fn description(&self) -> &'static str {
+        "pointer moves of hundreds of cells at once"
+    }
+
+    fn check(&self, program: &MidiAST, found: &mut Vec<Finding>) {
+        for inst in program {
+            match &inst.instruction {
+                MovePointer { amount } if amount.unsigned_abs() > HUGE_MOVE => found.push((
+                    inst.position,
+                    format!("pointer moves {} cells at once, is a note of the chord wrong?", amount.unsigned_abs()),
+                )),
+                Loop { body } => self.check(body, found),
+                _ => {}
+            }
+        }
+    }
+}
+
+pub struct LeftoverBreakpoint;
+
+impl Lint for LeftoverBreakpoint {
+    fn name(&self) -> &'static str {
+        "breakpoint"
+    }
+
+    fn description(&self) -> &'static str {
+        "breakpoints left in the program"
+    }
+
+    fn check(&self, program: &MidiAST, found: &mut Vec<Finding>) {
+        for inst in program {
+            match &inst.instruction {
+                Breakpoint => found.push((inst.position, "breakpoint left in".to_owned())),
+                Loop { body } => self.check(body, found),
+                _ => {}
+            }
+        }
+    }
+}
+
+/// The pointer moving left of the first cell before the program's first
+/// loop, where it is known for certain.
+pub struct PointerUnderflow;
+
+impl Lint for PointerUnderflow {
+    fn name(&self) -> &'static str {
+        "pointer-underflow"
+    }
+
+    fn description(&self) -> &'static str {
+        "the pointer moving left of the first cell"
+    }
+
+    fn default_severity(&self) -> Severity {
+        Severity::Error
+    }
+
+    fn check(&self, program: &MidiAST, found: &mut Vec<Finding>) {
+        let mut pointer: isize = 0;
+        for MidiInstruction { position, instruction } in program {
+            match instruction {
+                MovePointer { amount } => {
+                    pointer += amount;
+                    if pointer < 0 {
+                        found.push((*position, format!("pointer moves to cell {}, left of the first cell", pointer)));
+                        return;
+                    }
+                }
+                Loop { .. } => return,
+                _ => {}
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+    use midly::num::{u15, u28, u4, u7};
+    use midly::{Format, Header, MidiMessage, Timing, TrackEvent, TrackEventKind};
+
+    fn smf(chords: &[&[u8]]) -> Smf<'static> {
+        let mut track = vec![];
+        for keys in chords {
+            for (on, delta) in [(true, 0), (false, 96)] {
+                for (idx, &key) in keys.iter().enumerate() {
+                    let message = match on {
+                        true => MidiMessage::NoteOn { key: u7::from(key), vel: u7::from(100) },
+                        false => MidiMessage::NoteOff { key: u7::from(key), vel: u7::from(0) },
+                    };
+                    track.push(TrackEvent {
+                        delta: u28::from(if idx == 0 { delta } else { 0 }),
+                        kind: TrackEventKind::Midi { channel: u4::from(0), message },
+                    });
+                }
+            }
+        }
+        let mut midi = Smf::new(Header::new(Format::SingleTrack, Timing::Metrical(u15::from(96))));
+        midi.tracks.push(track);
+        midi
+    }
+
+    fn found(report: &Report) -> Vec<(Severity, Option<u64>, &str)> {
+        report.diagnostics.iter().map(|diag| (diag.severity, diag.tick, diag.message.as_str())).collect()
+    }
+
+    #[test]
+    fn clean_programs_have_no_diagnostics() {
+        // +[-]
+        let report = check(smf(&[&[9], &[7], &[5], &[0]]), true);
+        assert_eq!(found(&report), []);
+        assert_eq!(report.severity(), None);
+    }
+
+    #[test]
+    fn reports_every_parse_error() {
+        // C#, [, ], ], [
+        let report = check(smf(&[&[1], &[7], &[0], &[0], &[7]]), true);
+        assert_eq!(
+            found(&report),
+            [
+                (Severity::Error, Some(0), "chord's root isn't in C major"),
+                (Severity::Error, Some(288), "closing chord without a loop to close"),
+                (Severity::Error, Some(384), "loop is never closed"),
+            ]
+        );
+        assert_eq!(report.severity(), Some(Severity::Error));
+    }
+
+    #[test]
+    fn lints_parsed_programs() {
+        // [-] + [] [-] #
+        let report = check(smf(&[&[7], &[5], &[0], &[9], &[7], &[0], &[7], &[5], &[0], &[12, 15, 18]]), true);
+        assert_eq!(
+            found(&report),
+            [
+                (Severity::Warning, Some(0), "loop never runs, the cell is always zero after the program starts"),
+                (Severity::Warning, Some(384), "empty loop never ends once entered"),
+                (Severity::Warning, Some(576), "loop never runs, the cell is always zero after a loop"),
+                (Severity::Warning, Some(576), "unreachable, the loop before it never ends"),
+                (Severity::Warning, Some(864), "breakpoint left in"),
+            ]
+        );
+        let codes: Vec<_> = report.diagnostics.iter().map(|diag| diag.code).collect();
+        assert_eq!(codes, ["dead-loop", "empty-loop", "dead-loop", "unreachable-code", "breakpoint"]);
+        // the chord and notes of the breakpoint
+        assert_eq!((report.diagnostics[4].chord, report.diagnostics[4].notes.as_slice()), (Some(9), &[12, 15, 18][..]));
+        assert_eq!(found(&check(smf(&[&[7], &[0]]), false)), []);
+    }
+
+    #[test]
+    fn pointer_left_of_the_first_cell_is_an_error() {
+        // > < < [<]
+        let report = check(smf(&[&[4], &[2], &[2], &[7], &[2], &[0]]), true);
+        assert_eq!(
+            found(&report),
+            [
+                (Severity::Warning, Some(0), "move 1 and move -1 cancel out"),
+                (Severity::Error, Some(192), "pointer moves to cell -1, left of the first cell"),
+            ]
+        );
+        // only certain before the first loop
+        assert_eq!(found(&check(smf(&[&[9], &[7], &[2], &[0]]), true)), []);
+    }
+
+    #[test]
+    fn finds_canceling_pairs_unreachable_code_and_huge_moves() {
+        // ,[+ -] + + . [.] > >(300)
+        let report = check(smf(&[&[11], &[7], &[9], &[5], &[0], &[9], &[9], &[11, 15, 18], &[7], &[11, 15, 18], &[0], &[4], &[4, 5, 8, 9, 11, 14]]), true);
+        assert_eq!(
+            found(&report),
+            [
+                (Severity::Warning, Some(192), "add 1 and add -1 cancel out"),
+                (Severity::Warning, Some(768), "loop never ends once entered, nothing in it changes the cell it checks"),
+                (Severity::Warning, Some(1056), "unreachable, the loop before it never ends"),
+                (Severity::Warning, Some(1152), "pointer moves 300 cells at once, is a note of the chord wrong?"),
+            ]
+        );
+        // +256 [] >, where the cell is zero with 8 bit cells
+        let report = check(smf(&[&[9, 13, 22], &[7], &[0], &[4]]), true);
+        assert!(report.diagnostics.iter().all(|diag| diag.code != "unreachable-code"));
+    }
+
+    #[test]
+    fn lints_can_be_allowed_and_denied() {
+        // + - #
+        let parsed = parse_all(smf(&[&[9], &[5], &[12, 15, 18]]));
+        let mut levels = LintLevels::default();
+        levels.set("breakpoint", None).unwrap();
+        levels.set("canceling-pair", Some(Severity::Warning)).unwrap();
+        levels.set("canceling-pair", Some(Severity::Error)).unwrap();
+        let report = lint_parsed(&parsed, &levels);
+        assert_eq!(found(&report), [(Severity::Error, Some(0), "add 1 and add -1 cancel out")]);
+        assert_eq!(levels.set("no-such-lint", None), Err("no lint called `no-such-lint`".to_owned()));
+    }
+
+    #[test]
+    fn finds_loops_that_never_change_their_cell() {
+        let infinite = |bf: &str| {
+            let program = crate::MidiProgram::from_bf_str(bf).unwrap();
+            let mut found = vec![];
+            InfiniteLoop.check(program.ast(), &mut found);
+            found.iter().map(|(pos, _)| pos.unwrap().start()).collect::<Vec<_>>()
+        };
+        assert_eq!(infinite("+[>+<]"), [1]);
+        // the inner loop clears the cell next to it, not the one checked
+        assert_eq!(infinite("+[>[-]<.]"), [1]);
+        assert_eq!(infinite(",[>+<-]"), [] as [usize; 0]);
+        assert_eq!(infinite("+[[-]]"), [] as [usize; 0]);
+        // moves off the cell, or might
+        assert_eq!(infinite("+[>]"), [] as [usize; 0]);
+        assert_eq!(infinite("+[>[>]<]"), [] as [usize; 0]);
+        // left to the empty loop lint
+        assert_eq!(infinite("+[]"), [] as [usize; 0]);
+    }
+}
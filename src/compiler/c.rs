@@ -0,0 +1,639 @@
+use midilang_core::{DUMP_RADIUS, GOLDEN_GAMMA, MIX_MULTIPLIERS};
+
+use super::{CompileOptions, IoMode, Overflow, PointerOverflow, TapeMode};
+use crate::parser::{MidiAST, MidiInstruction, MidiInstructionKind::*, Position};
+use crate::timing::SourceMap;
+
+/// Translates `midi_program` into a C program that behaves like the compiled one,
+/// using nothing but the C standard library, and the functions it calls, which
+/// it declares as `int name(int)` for whatever defines them to link in.
+///
+/// Every statement is commented with the bar and beat it came from when there's
+/// a `source_map`.
+pub fn transpile(
+    midi_program: &MidiAST,
+    source_map: Option<&SourceMap>,
+    mut options: CompileOptions,
+) -> String {
+    options.fit_tape(midi_program);
+    let mut writer = CWriter {
+        options: &options,
+        source_map,
+        body: String::new(),
+        depth: 1,
+        uses_input: false,
+        uses_output: false,
+        uses_fail: false,
+        uses_random: false,
+        uses_dump: false,
+        functions: vec![],
+    };
+    writer.write_instructions(midi_program);
+    writer.finish()
+}
+
+/// Writes the body of `main`, keeping track of which helpers it calls.
+struct CWriter<'a> {
+    options: &'a CompileOptions,
+    source_map: Option<&'a SourceMap>,
+    body: String,
+    /// Indentation level of the next line
+    depth: usize,
+    uses_input: bool,
+    uses_output: bool,
+    uses_fail: bool,
+    uses_random: bool,
+    uses_dump: bool,
+    /// Functions called, in the order they're first called
+    functions: Vec<&'a str>,
+}
+
+impl<'a> CWriter<'a> {
+    /// Adds `code` as a line of the body, commented with the location of `position`.
+    fn line(&mut self, code: &str, position: Option<Position>) {
+        self.body.push_str(&"    ".repeat(self.depth));
+        self.body.push_str(code);
+        if let (Some(source_map), Some(position)) = (self.source_map, position) {
+            self.body.push_str(" // ");
+            self.body.push_str(&source_map.describe(position));
+        }
+        self.body.push('\n');
+    }
+
+    /// Adds a line stopping the program with `reason` when `condition` holds,
+    /// exiting with 1 like a run stopped by the VM does.
+    fn check(&mut self, condition: &str, reason: &str, position: Option<Position>) {
+        self.uses_fail = true;
+        let location = match (self.source_map, position) {
+            (Some(source_map), Some(position)) => format!(" at {}", source_map.describe(position)),
+            (None, Some(position)) => format!(" at instruction {}", position.start()),
+            (_, None) => String::new(),
+        };
+        self.line(&format!("if ({}) fail(\"{}{}\");", condition, reason, location), None);
+    }
+
+    /// Adds a line adding `step` to the current cell, stopping at 0 and `CELL_MAX`.
+    fn saturating_add(&mut self, step: i64, position: Option<Position>) {
+        let max = u64::MAX >> (64 - self.options.cell_width.bits());
+        let line = if step.unsigned_abs() > max {
+            format!("tape[ptr] = {};", if step < 0 { "0" } else { "CELL_MAX" })
+        } else if step < 0 {
+            format!("tape[ptr] = tape[ptr] < {0} ? 0 : tape[ptr] - {0};", step.unsigned_abs())
+        } else {
+            format!("tape[ptr] = tape[ptr] > CELL_MAX - {0} ? CELL_MAX : tape[ptr] + {0};", step)
+        };
+        self.line(&line, position);
+    }
+
+    fn write_instructions(&mut self, program: &'a [MidiInstruction]) {
+        for inst in program {
+            self.write_instruction(inst);
+        }
+    }
+
+    fn write_instruction(&mut self, inst: &'a MidiInstruction) {
+        let position = inst.position;
+        match &inst.instruction {
+            IncrementCell { amount } => {
+                let step = i64::from(amount.0);
+                if self.options.overflow == Overflow::Trap {
+                    // cells are unsigned, so overflow means crossing 0
+                    let max = u64::MAX >> (64 - self.options.cell_width.bits());
+                    if step.unsigned_abs() > max {
+                        self.check("1", "cell overflow", position);
+                    } else if step > 0 {
+                        self.check(&format!("tape[ptr] > CELL_MAX - {}", step), "cell overflow", position);
+                    } else {
+                        let limit = format!("tape[ptr] < {}", step.unsigned_abs());
+                        self.check(&limit, "cell overflow", position);
+                    }
+                }
+                if self.options.overflow == Overflow::Saturate {
+                    self.saturating_add(step, position);
+                } else if step < 0 {
+                    self.line(&format!("tape[ptr] -= {};", step.unsigned_abs()), position);
+                } else {
+                    self.line(&format!("tape[ptr] += {};", step), position);
+                }
+            }
+            MovePointer { amount } if self.options.pointer_overflow == Some(PointerOverflow::Wrap) => {
+                // only fixed tapes wrap, so the pointer comes back in within one lap
+                let distance = amount.unsigned_abs() as u64 % self.options.tape_size;
+                if *amount < 0 {
+                    let wrapped = format!("ptr = ptr >= {0} ? ptr - {0} : ptr + capacity - {0};", distance);
+                    self.line(&wrapped, position);
+                } else {
+                    self.line(&format!("ptr += {};", distance), position);
+                    self.line("if (ptr >= capacity) ptr -= capacity;", None);
+                }
+            }
+            MovePointer { amount } => {
+                let distance = amount.unsigned_abs();
+                let growing = self.options.tape_mode != TapeMode::Fixed;
+                if *amount < 0 {
+                    if self.options.tape_mode == TapeMode::Infinite {
+                        let condition = format!("ptr < {}", distance);
+                        self.line(&format!("if ({}) grow_left({} - ptr);", condition, distance), None);
+                    } else if self.options.bounds_check {
+                        self.check(&format!("ptr < {}", distance), "pointer out of bounds", position);
+                    }
+                    self.line(&format!("ptr -= {};", distance), position);
+                } else {
+                    if growing {
+                        let needed = format!("ptr + {}", distance);
+                        self.line(&format!("if ({} >= capacity) grow({});", needed, needed), None);
+                    } else if self.options.bounds_check {
+                        let condition = format!("capacity - ptr <= {}", distance);
+                        self.check(&condition, "pointer out of bounds", position);
+                    }
+                    self.line(&format!("ptr += {};", distance), position);
+                }
+            }
+            OutputCell if self.options.io == IoMode::Utf8 => {
+                self.uses_output = true;
+                self.line("put_utf8(tape[ptr]);", position);
+            }
+            OutputCell if self.options.io == IoMode::Numeric => {
+                self.line("printf(\"%lu\\n\", (unsigned long)tape[ptr]);", position)
+            }
+            OutputCell => self.line("putchar((unsigned char)tape[ptr]);", position),
+            InputCell if self.options.io == IoMode::Utf8 => {
+                self.uses_input = true;
+                if self.options.tape_mode != TapeMode::Fixed {
+                    // room for the longest sequence
+                    self.line("if (ptr + 3 >= capacity) grow(ptr + 3);", None);
+                }
+                self.line("read_utf8();", position);
+            }
+            InputCell if self.options.io == IoMode::Numeric => {
+                self.uses_input = true;
+                self.line("tape[ptr] = read_number();", position);
+            }
+            InputCell => {
+                self.uses_input = true;
+                self.line("tape[ptr] = read_cell();", position);
+            }
+            Loop { body } => {
+                self.line("while (tape[ptr]) {", position);
+                self.depth += 1;
+                self.write_instructions(body);
+                self.depth -= 1;
+                self.line("}", None);
+            }
+            Call { name } => {
+                if !self.functions.contains(&name.as_str()) {
+                    self.functions.push(name);
+                }
+                self.line(&format!("tape[ptr] = (cell){}(tape[ptr]);", name), position);
+            }
+            Random => {
+                self.uses_random = true;
+                self.line("tape[ptr] = random_cell();", position);
+            }
+            DumpTape => {
+                self.uses_dump = true;
+                self.line("dump_tape();", position);
+            }
+            // compiled programs never stop for the debugger
+            Breakpoint => {}
+        }
+    }
+
+    /// The whole C file, with the helpers the body uses in front of `main`.
+    fn finish(self) -> String {
+        let growing = self.options.tape_mode != TapeMode::Fixed;
+        let infinite = self.options.tape_mode == TapeMode::Infinite;
+        let data = &self.options.data;
+        let bits = self.options.cell_width.bits();
+        let mut c = format!("// Generated by midilang {}\n", env!("CARGO_PKG_VERSION"));
+        c.push_str("#include <stdint.h>\n#include <stdio.h>\n#include <stdlib.h>\n");
+        if growing || !data.is_empty() {
+            c.push_str("#include <string.h>\n");
+        }
+        let seeded_by_clock = self.uses_random && self.options.seed.is_none();
+        if seeded_by_clock {
+            c.push_str("#include <time.h>\n");
+        }
+        c.push_str(&format!(
+            "\ntypedef uint{bits}_t cell;\n#define CELL_MAX UINT{bits}_MAX\n\n\
+             static cell *tape;\nstatic size_t capacity = {};\nstatic size_t ptr;\n",
+            self.options.tape_size
+        ));
+        if infinite {
+            c.push_str("// Index of the cell the pointer started on\nstatic size_t origin;\n");
+        }
+        if !self.functions.is_empty() {
+            c.push_str("\n// Called by the program, from whatever is linked in\n");
+            for name in &self.functions {
+                c.push_str(&format!("int {}(int);\n", name));
+            }
+        }
+        if !data.is_empty() {
+            // 16 cells to a line
+            let lines: Vec<String> = data
+                .chunks(16)
+                .map(|cells| cells.iter().map(u8::to_string).collect::<Vec<_>>().join(", "))
+                .collect();
+            c.push_str(&format!("\n// The data track\nstatic const cell data[] = {{\n    {},\n}};\n", lines.join(",\n    ")));
+        }
+        if self.uses_fail {
+            c.push_str(
+                "\nstatic void fail(const char *message) {\n\
+                 \x20   fflush(stdout);\n\
+                 \x20   fprintf(stderr, \"midilang: %s\\n\", message);\n\
+                 \x20   exit(1);\n\
+                 }\n",
+            );
+        }
+        if growing {
+            c.push_str(
+                "\n// Reallocates the tape to fit cell `index`, zeroing the new cells\n\
+                 static void grow(size_t index) {\n\
+                 \x20   size_t new_capacity = capacity * 2 > index ? capacity * 2 : index + 1;\n\
+                 \x20   cell *grown = realloc(tape, new_capacity * sizeof(cell));\n\
+                 \x20   if (!grown) abort();\n\
+                 \x20   memset(grown + capacity, 0, (new_capacity - capacity) * sizeof(cell));\n\
+                 \x20   tape = grown;\n\
+                 \x20   capacity = new_capacity;\n\
+                 }\n",
+            );
+        }
+        if infinite {
+            c.push_str(
+                "\n// Reallocates the tape with at least `missing` zeroed cells in front of it\n\
+                 static void grow_left(size_t missing) {\n\
+                 \x20   size_t added = missing > capacity ? missing : capacity;\n\
+                 \x20   cell *grown = realloc(tape, (capacity + added) * sizeof(cell));\n\
+                 \x20   if (!grown) abort();\n\
+                 \x20   memmove(grown + added, grown, capacity * sizeof(cell));\n\
+                 \x20   memset(grown, 0, added * sizeof(cell));\n\
+                 \x20   tape = grown;\n\
+                 \x20   capacity += added;\n\
+                 \x20   ptr += added;\n\
+                 \x20   origin += added;\n\
+                 }\n",
+            );
+        }
+        if self.uses_random {
+            let seed = self.options.seed.map(|seed| format!(" = UINT64_C({:#x})", seed)).unwrap_or_default();
+            c.push_str(&format!(
+                "\n// SplitMix64, picking the bytes midilang does for the same seed\n\
+                 static uint64_t rng_state{seed};\n\n\
+                 static cell random_cell(void) {{\n\
+                 \x20   uint64_t z = rng_state += UINT64_C({:#x});\n\
+                 \x20   z = (z ^ (z >> 30)) * UINT64_C({:#x});\n\
+                 \x20   z = (z ^ (z >> 27)) * UINT64_C({:#x});\n\
+                 \x20   return (cell)((z ^ (z >> 31)) >> 56);\n\
+                 }}\n",
+                GOLDEN_GAMMA, MIX_MULTIPLIERS[0], MIX_MULTIPLIERS[1],
+            ));
+        }
+        if self.uses_dump && infinite {
+            c.push_str(&format!(
+                "\n// Prints the cells around the pointer like midilang does, off the tape as 0\n\
+                 static void dump_tape(void) {{\n\
+                 \x20   long long at = (long long)ptr;\n\
+                 \x20   fflush(stdout);\n\
+                 \x20   fprintf(stderr, \"tape at %lld:\", at - (long long)origin);\n\
+                 \x20   for (long long i = at - {radius}; i <= at + {radius}; i++) {{\n\
+                 \x20       unsigned long value = i >= 0 && (size_t)i < capacity ? (unsigned long)tape[i] : 0;\n\
+                 \x20       fprintf(stderr, i == at ? \" [%lu]\" : \" %lu\", value);\n\
+                 \x20   }}\n\
+                 \x20   fputc('\\n', stderr);\n\
+                 }}\n",
+                radius = DUMP_RADIUS,
+            ));
+        } else if self.uses_dump {
+            c.push_str(&format!(
+                "\n// Prints the cells around the pointer like midilang does, past the end of the tape as 0\n\
+                 static void dump_tape(void) {{\n\
+                 \x20   size_t first = ptr > {radius} ? ptr - {radius} : 0;\n\
+                 \x20   fflush(stdout);\n\
+                 \x20   fprintf(stderr, \"tape at %zu:\", ptr);\n\
+                 \x20   for (size_t i = first; i <= ptr + {radius}; i++) {{\n\
+                 \x20       unsigned long value = i < capacity ? (unsigned long)tape[i] : 0;\n\
+                 \x20       fprintf(stderr, i == ptr ? \" [%lu]\" : \" %lu\", value);\n\
+                 \x20   }}\n\
+                 \x20   fputc('\\n', stderr);\n\
+                 }}\n",
+                radius = DUMP_RADIUS,
+            ));
+        }
+        let utf8 = self.options.io == IoMode::Utf8;
+        if utf8 && (self.uses_input || self.uses_output) {
+            c.push_str(
+                "\n// Continuation bytes following `byte` and the range the first has to be in,\n\
+                 // -1 when no UTF-8 sequence starts with it\n\
+                 static int utf8_lead(unsigned char byte, unsigned char *lo, unsigned char *hi) {\n\
+                 \x20   *lo = byte == 0xe0 ? 0xa0 : byte == 0xf0 ? 0x90 : 0x80;\n\
+                 \x20   *hi = byte == 0xed ? 0x9f : byte == 0xf4 ? 0x8f : 0xbf;\n\
+                 \x20   if (byte < 0x80) return 0;\n\
+                 \x20   if (byte < 0xc2 || byte > 0xf4) return -1;\n\
+                 \x20   return byte >= 0xf0 ? 3 : byte >= 0xe0 ? 2 : 1;\n\
+                 }\n",
+            );
+        }
+        if utf8 && self.uses_output {
+            c.push_str(
+                "\n// Bytes held back until they make up a whole UTF-8 sequence\n\
+                 static unsigned char pending[4];\n\
+                 static int pending_len, pending_need;\n\
+                 static unsigned char next_lo, next_hi;\n\
+                 \n\
+                 // Writes U+FFFD, for UTF-8 that isn't valid\n\
+                 static void put_replacement(void) {\n\
+                 \x20   fputs(\"\\xef\\xbf\\xbd\", stdout);\n\
+                 }\n\
+                 \n\
+                 static void put_utf8(cell value) {\n\
+                 \x20   unsigned char byte = (unsigned char)value;\n\
+                 \x20   if (pending_need) {\n\
+                 \x20       if (byte >= next_lo && byte <= next_hi) {\n\
+                 \x20           pending[pending_len++] = byte;\n\
+                 \x20           next_lo = 0x80;\n\
+                 \x20           next_hi = 0xbf;\n\
+                 \x20           if (--pending_need == 0) fwrite(pending, 1, pending_len, stdout);\n\
+                 \x20           return;\n\
+                 \x20       }\n\
+                 \x20       // cut short, the byte might start the next one\n\
+                 \x20       pending_need = 0;\n\
+                 \x20       put_replacement();\n\
+                 \x20   }\n\
+                 \x20   int need = utf8_lead(byte, &next_lo, &next_hi);\n\
+                 \x20   if (need == 0) {\n\
+                 \x20       putchar(byte);\n\
+                 \x20   } else if (need < 0) {\n\
+                 \x20       put_replacement();\n\
+                 \x20   } else {\n\
+                 \x20       pending[0] = byte;\n\
+                 \x20       pending_len = 1;\n\
+                 \x20       pending_need = need;\n\
+                 \x20   }\n\
+                 }\n",
+            );
+        }
+        if utf8 && self.uses_input {
+            c.push_str(
+                "\n// Reads a code point into the cell under the pointer and the ones after it,\n\
+                 // a cell for every byte of its UTF-8, or of U+FFFD when that isn't valid.\n\
+                 // EOF reads as 0\n\
+                 static void read_utf8(void) {\n\
+                 \x20   unsigned char bytes[4], lo, hi;\n\
+                 \x20   int c = getchar();\n\
+                 \x20   int len = 1, need = c == EOF ? 0 : utf8_lead((unsigned char)c, &lo, &hi);\n\
+                 \x20   bytes[0] = c == EOF ? 0 : (unsigned char)c;\n\
+                 \x20   for (; need > 0; need--, len++, lo = 0x80, hi = 0xbf) {\n\
+                 \x20       c = getchar();\n\
+                 \x20       if (c == EOF || c < lo || c > hi) {\n\
+                 \x20           need = -1;\n\
+                 \x20           break;\n\
+                 \x20       }\n\
+                 \x20       bytes[len] = (unsigned char)c;\n\
+                 \x20   }\n\
+                 \x20   if (need < 0) {\n\
+                 \x20       bytes[0] = 0xef;\n\
+                 \x20       bytes[1] = 0xbf;\n\
+                 \x20       bytes[2] = 0xbd;\n\
+                 \x20       len = 3;\n\
+                 \x20   }\n\
+                 \x20   for (int i = 0; i < len && ptr + i < capacity; i++) tape[ptr + i] = bytes[i];\n\
+                 }\n",
+            );
+        } else if self.uses_input && self.options.io == IoMode::Numeric {
+            c.push_str(
+                "\n// Reads the next decimal number, negative after a '-', skipping whatever isn't one.\n\
+                 // EOF reads as 0\n\
+                 static cell read_number(void) {\n\
+                 \x20   int c, negative = 0;\n\
+                 \x20   while ((c = getchar()) != EOF && (c < '0' || c > '9')) negative = c == '-';\n\
+                 \x20   unsigned long value = 0;\n\
+                 \x20   for (; c >= '0' && c <= '9'; c = getchar()) value = value * 10 + (unsigned long)(c - '0');\n\
+                 \x20   return (cell)(negative ? 0 - value : value);\n\
+                 }\n",
+            );
+        } else if self.uses_input {
+            c.push_str(
+                "\n// EOF reads as 0\n\
+                 static cell read_cell(void) {\n\
+                 \x20   int c = getchar();\n\
+                 \x20   return c == EOF ? 0 : (cell)c;\n\
+                 }\n",
+            );
+        }
+        c.push_str("\nint main(void) {\n");
+        let redirects = [(&self.options.stdin, "rb", "stdin"), (&self.options.stdout, "wb", "stdout")];
+        for (path, mode, stream) in redirects {
+            if let Some(path) = path {
+                let path = string_literal(&path.to_string_lossy());
+                c.push_str(&format!(
+                    "    if (!freopen({path}, \"{mode}\", {stream})) {{\n        perror({path});\n        return 1;\n    }}\n"
+                ));
+            }
+        }
+        c.push_str("    tape = calloc(capacity, sizeof(cell));\n    if (!tape) return 1;\n");
+        if !data.is_empty() {
+            c.push_str("    memcpy(tape, data, sizeof data);\n");
+        }
+        if seeded_by_clock {
+            c.push_str("    rng_state = (uint64_t)time(NULL);\n");
+        }
+        c.push_str(&self.body);
+        if utf8 && self.uses_output {
+            // a sequence the program never finished comes out replaced
+            c.push_str("    if (pending_need) put_replacement();\n");
+        }
+        c.push_str("    free(tape);\n    return 0;\n}\n");
+        c
+    }
+}
+
+// `text` as a C string literal, with everything but printable ASCII escaped
+fn string_literal(text: &str) -> String {
+    let mut literal = String::from("\"");
+    for byte in text.bytes() {
+        match byte {
+            b'"' | b'\\' => literal.push_str(&format!("\\{}", byte as char)),
+            b' '..=b'~' => literal.push(byte as char),
+            _ => literal.push_str(&format!("\\{:03o}", byte)),
+        }
+    }
+    literal.push('"');
+    literal
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+    use crate::compiler::CellWidth;
+    use crate::parser::MidiASTBuilder;
+    use std::num::Wrapping;
+
+    fn build(insts: Vec<MidiInstruction>) -> MidiAST {
+        let mut builder = MidiASTBuilder::new();
+        for inst in insts {
+            builder.push(inst).unwrap();
+        }
+        builder.into_mast().unwrap()
+    }
+
+    #[test]
+    fn transpiles_loops_and_io() {
+        let prog = build(vec![
+            MidiInstruction::new_input(),
+            MidiInstruction::new_open_loop(),
+            MidiInstruction::new_output(),
+            MidiInstruction::new_inc(Wrapping(-1)),
+            MidiInstruction::new_close_loop(),
+        ]);
+        let c = transpile(&prog, None, CompileOptions::default());
+        assert!(c.contains("typedef uint8_t cell;"));
+        assert!(c.contains("static size_t capacity = 30000;"));
+        assert!(c.contains("static cell read_cell(void) {"));
+        assert!(!c.contains("fail("));
+        assert!(c.contains(
+            "    tape[ptr] = read_cell();\n    while (tape[ptr]) {\n        \
+             putchar((unsigned char)tape[ptr]);\n        tape[ptr] -= 1;\n    }\n"
+        ));
+    }
+
+    #[test]
+    fn transpiles_checks_and_growing_tape() {
+        let prog = build(vec![
+            MidiInstruction::new_move(3),
+            MidiInstruction::new_inc(Wrapping(2)),
+            MidiInstruction::new_move(-5),
+        ]);
+        let options = CompileOptions::builder()
+            .tape_mode(TapeMode::Grow)
+            .cell_width(CellWidth::I16)
+            .bounds_check(true)
+            .overflow(Overflow::Trap)
+            .build()
+            .unwrap();
+        let c = transpile(&prog, None, options);
+        assert!(c.contains("typedef uint16_t cell;"));
+        assert!(c.contains("#include <string.h>"));
+        assert!(c.contains("if (ptr + 3 >= capacity) grow(ptr + 3);\n    ptr += 3;"));
+        assert!(c.contains("if (tape[ptr] > CELL_MAX - 2) fail(\"cell overflow at instruction 1\");"));
+        assert!(c.contains("if (ptr < 5) fail(\"pointer out of bounds at instruction 2\");\n    ptr -= 5;"));
+        assert!(!c.contains("read_cell"));
+    }
+
+    #[test]
+    fn saturates_cells_and_wraps_the_pointer() {
+        let prog = build(vec![
+            MidiInstruction::new_inc(Wrapping(-3)),
+            MidiInstruction::new_move(-12),
+            MidiInstruction::new_inc(Wrapping(300)),
+            MidiInstruction::new_move(7),
+        ]);
+        let options = CompileOptions::builder()
+            .tape_size(10)
+            .overflow(Overflow::Saturate)
+            .pointer_overflow(Some(PointerOverflow::Wrap))
+            .build()
+            .unwrap();
+        let c = transpile(&prog, None, options);
+        assert!(c.contains("    tape[ptr] = tape[ptr] < 3 ? 0 : tape[ptr] - 3;\n"));
+        assert!(c.contains("    ptr = ptr >= 2 ? ptr - 2 : ptr + capacity - 2;\n    tape[ptr] = CELL_MAX;\n"));
+        assert!(c.contains("    ptr += 7;\n    if (ptr >= capacity) ptr -= capacity;\n"));
+        assert!(!c.contains("fail("));
+    }
+
+    #[test]
+    fn copies_data_onto_the_tape() {
+        assert!(!transpile(&vec![], None, CompileOptions::default()).contains("data"));
+        let options = CompileOptions::builder().tape_size(1).data((0..20).collect()).build().unwrap();
+        let c = transpile(&vec![], None, options);
+        assert!(c.contains("#include <string.h>"));
+        assert!(c.contains("static size_t capacity = 20;"));
+        assert!(c.contains(
+            "static const cell data[] = {\n    0, 1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11, 12, 13, 14, 15,\n    16, 17, 18, 19,\n};"
+        ));
+        assert!(c.contains("    if (!tape) return 1;\n    memcpy(tape, data, sizeof data);\n"));
+    }
+
+    #[test]
+    fn declares_the_functions_it_calls() {
+        let prog = build(vec![
+            MidiInstruction::new_call("toupper".to_owned()),
+            MidiInstruction::new_output(),
+            MidiInstruction::new_call("toupper".to_owned()),
+            MidiInstruction::new_call("abs".to_owned()),
+        ]);
+        let c = transpile(&prog, None, CompileOptions::default());
+        assert!(c.contains("// Called by the program, from whatever is linked in\nint toupper(int);\nint abs(int);\n"));
+        assert!(c.contains("    tape[ptr] = (cell)toupper(tape[ptr]);\n    putchar((unsigned char)tape[ptr]);\n"));
+    }
+
+    #[test]
+    fn seeds_random_bytes() {
+        let prog = build(vec![MidiInstruction::new_random(), MidiInstruction::new_output()]);
+        let c = transpile(&prog, None, CompileOptions::default());
+        assert!(c.contains("#include <time.h>"));
+        assert!(c.contains("static uint64_t rng_state;\n"));
+        assert!(c.contains("    rng_state = (uint64_t)time(NULL);\n"));
+        assert!(c.contains("    tape[ptr] = random_cell();\n"));
+
+        let seeded = transpile(&prog, None, CompileOptions::builder().seed(Some(42)).build().unwrap());
+        assert!(!seeded.contains("time("));
+        assert!(seeded.contains("static uint64_t rng_state = UINT64_C(0x2a);\n"));
+        assert!(!transpile(&vec![], None, CompileOptions::default()).contains("rng_state"));
+    }
+
+    #[test]
+    fn dumps_the_tape_to_stderr() {
+        let prog = build(vec![MidiInstruction::new_dump_tape(), MidiInstruction::new_output()]);
+        let c = transpile(&prog, None, CompileOptions::default());
+        assert!(c.contains("static void dump_tape(void) {\n    size_t first = ptr > 4 ? ptr - 4 : 0;\n"));
+        assert!(c.contains("    dump_tape();\n    putchar((unsigned char)tape[ptr]);\n"));
+        assert!(!transpile(&vec![], None, CompileOptions::default()).contains("dump_tape"));
+    }
+
+    #[test]
+    fn grows_infinite_tapes_to_the_left() {
+        let prog = build(vec![MidiInstruction::new_move(-3), MidiInstruction::new_dump_tape(), MidiInstruction::new_move(4)]);
+        let options = CompileOptions::builder().tape_mode(TapeMode::Infinite).bounds_check(true).build().unwrap();
+        let c = transpile(&prog, None, options);
+        assert!(c.contains("    if (ptr < 3) grow_left(3 - ptr);\n    ptr -= 3;\n"));
+        assert!(c.contains("    if (ptr + 4 >= capacity) grow(ptr + 4);\n    ptr += 4;\n"));
+        assert!(c.contains("static size_t origin;"));
+        assert!(c.contains("fprintf(stderr, \"tape at %lld:\", at - (long long)origin);"));
+        assert!(!c.contains("fail("));
+    }
+
+    #[test]
+    fn writes_and_reads_utf8() {
+        let prog = build(vec![MidiInstruction::new_input(), MidiInstruction::new_output()]);
+        let options = CompileOptions::builder().tape_mode(TapeMode::Grow).io(IoMode::Utf8).build().unwrap();
+        let c = transpile(&prog, None, options);
+        assert!(c.contains("static int utf8_lead(unsigned char byte, unsigned char *lo, unsigned char *hi) {"));
+        assert!(c.contains("    if (ptr + 3 >= capacity) grow(ptr + 3);\n    read_utf8();\n    put_utf8(tape[ptr]);\n"));
+        assert!(c.contains("    if (pending_need) put_replacement();\n    free(tape);"));
+        assert!(!c.contains("read_cell"));
+    }
+
+    #[test]
+    fn prints_and_reads_numbers() {
+        let prog = build(vec![MidiInstruction::new_input(), MidiInstruction::new_output()]);
+        let options = CompileOptions::builder().io(IoMode::Numeric).build().unwrap();
+        let c = transpile(&prog, None, options);
+        assert!(c.contains("static cell read_number(void) {"));
+        assert!(c.contains("    tape[ptr] = read_number();\n    printf(\"%lu\\n\", (unsigned long)tape[ptr]);\n"));
+        assert!(!c.contains("read_cell"));
+    }
+
+    #[test]
+    fn reopens_stdin_and_stdout() {
+        let options = CompileOptions::builder()
+            .stdin(Some("in put.txt".into()))
+            .stdout(Some("out\\\"1\".txt".into()))
+            .build()
+            .unwrap();
+        let c = transpile(&vec![], None, options);
+        assert!(c.contains("int main(void) {\n    if (!freopen(\"in put.txt\", \"rb\", stdin)) {\n        perror(\"in put.txt\");\n"));
+        assert!(c.contains("    if (!freopen(\"out\\\\\\\"1\\\".txt\", \"wb\", stdout)) {"));
+        assert!(!transpile(&vec![], None, CompileOptions::default()).contains("freopen"));
+    }
+}
@@ -0,0 +1,1420 @@
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::fs;
+use std::io::{self, Read, Write};
+use std::mem;
+use std::num::Wrapping;
+use std::path::Path;
+
+use cranelift_codegen::ir::condcodes::IntCC;
+use cranelift_codegen::ir::{
+    types, AbiParam, Endianness, FuncRef, GlobalValue, InstBuilder, MemFlags, StackSlot, StackSlotData, StackSlotKind,
+    TrapCode, Type, Value,
+};
+use cranelift_codegen::isa::OwnedTargetIsa;
+use cranelift_codegen::settings::{self, Configurable};
+use cranelift_frontend::{FunctionBuilder, FunctionBuilderContext, Variable};
+use cranelift_jit::{JITBuilder, JITModule};
+use cranelift_module::{default_libcall_names, DataDescription, DataId, FuncId, Linkage, Module};
+use cranelift_object::{ObjectBuilder, ObjectModule};
+use log::debug;
+use midilang_core::{DUMP_RADIUS, GOLDEN_GAMMA, MIX_MULTIPLIERS};
+
+use super::{
+    link, link_to_memory, object_section, plays, Backend, CellWidth, CompileOptions, Emit, IoMode,
+    MCompileError, MCompileResult, OptLevel, Overflow, PointerOverflow, TapeMode,
+};
+use crate::ffi::Functions;
+use crate::parser::{Cell, MidiAST, MidiInstruction, MidiInstructionKind::*};
+use crate::progress::{instruction_count, Progress};
+use crate::provenance::embedded_source;
+
+/// A program compiled to an object file for the host by Cranelift.
+pub struct CraneliftCompiler {
+    options: CompileOptions,
+    object: Vec<u8>,
+    /// Cranelift IR of `main`, before Cranelift's own optimizations
+    clif: String,
+}
+
+impl Backend for CraneliftCompiler {
+    fn print_ir(&self) -> String {
+        self.clif.clone()
+    }
+
+    fn emit(&self, emit: Emit, path: &Path) -> MCompileResult<()> {
+        match emit {
+            Emit::Obj => fs::write(path, &self.object).map_err(|e| {
+                MCompileError::CraneliftError(format!("Could not write {}: {}", path.display(), e))
+            }),
+            Emit::Exe => link(self, &self.options, path),
+            _ => Err(MCompileError::Unsupported(format!(
+                "--emit {:?} needs the LLVM backend",
+                emit
+            ))),
+        }
+    }
+
+    fn emit_bytes(&self, emit: Emit) -> MCompileResult<Vec<u8>> {
+        match emit {
+            Emit::Obj => Ok(self.object.clone()),
+            Emit::Exe => link_to_memory(self, &self.options),
+            _ => Err(MCompileError::Unsupported(format!(
+                "--emit {:?} needs the LLVM backend",
+                emit
+            ))),
+        }
+    }
+}
+
+fn cranelift_error(err: impl std::fmt::Display) -> MCompileError {
+    MCompileError::CraneliftError(err.to_string())
+}
+
+/// Fails for options only the LLVM backend implements.
+fn check_supported(options: &CompileOptions) -> MCompileResult<()> {
+    let unsupported = if options.target_triple.is_some() {
+        Some("cross compiling")
+    } else if options.opt_level == OptLevel::O3 {
+        Some("-O3")
+    } else if options.bounds_check {
+        Some("bounds checks")
+    } else if options.overflow == Overflow::Trap {
+        Some("overflow traps")
+    } else if options.io == IoMode::Utf8 {
+        Some("UTF-8 IO")
+    } else if options.io == IoMode::Numeric {
+        Some("numeric IO")
+    } else if options.stdin.is_some() || options.stdout.is_some() {
+        Some("opening files for stdin and stdout")
+    } else if options.debug_info.is_some() {
+        Some("debug info")
+    } else if options.perform.is_some() {
+        Some("performance mode")
+    } else {
+        None
+    };
+    match unsupported {
+        Some(what) => Err(MCompileError::Unsupported(format!(
+            "{} needs the LLVM backend",
+            what
+        ))),
+        None => Ok(()),
+    }
+}
+
+/// ISA for the machine we're running on.
+fn host_isa(options: &CompileOptions, pic: bool) -> MCompileResult<OwnedTargetIsa> {
+    let mut flags = settings::builder();
+    let opt_level = if options.opt_level == OptLevel::O0 {
+        "none"
+    } else {
+        "speed"
+    };
+    flags.set("opt_level", opt_level).map_err(cranelift_error)?;
+    flags
+        .set("is_pic", if pic { "true" } else { "false" })
+        .map_err(cranelift_error)?;
+    let isa = cranelift_native::builder().map_err(cranelift_error)?;
+    isa.finish(settings::Flags::new(flags))
+        .map_err(cranelift_error)
+}
+
+/// Compiles `midi_program` into an object file for the host.
+pub fn compile_program(
+    midi_program: &MidiAST,
+    mut options: CompileOptions,
+) -> MCompileResult<CraneliftCompiler> {
+    check_supported(&options)?;
+    options.fit_tape(midi_program);
+    let builder = ObjectBuilder::new(
+        host_isa(&options, true)?,
+        "midilang",
+        default_libcall_names(),
+    )
+    .map_err(cranelift_error)?;
+    let mut module = ObjectModule::new(builder);
+    let (_, _, clif) = define_main(&mut module, midi_program, &options)?;
+    define_provenance(&mut module, &options)?;
+    let object = module.finish().emit().map_err(cranelift_error)?;
+    Ok(CraneliftCompiler {
+        options,
+        object,
+        clif,
+    })
+}
+
+/// Adds the provenance of the program as `midilang_provenance` in its own section,
+/// and the source MIDI file as `midilang_source` when it's embedded.
+fn define_provenance(module: &mut ObjectModule, options: &CompileOptions) -> MCompileResult<()> {
+    if let Some(provenance) = &options.provenance {
+        define_section_data(module, "midilang_provenance", "midilang", provenance.to_bytes())?;
+    }
+    if let Some(smf) = &options.embedded_source {
+        define_section_data(module, "midilang_source", "midilang.src", embedded_source(smf))?;
+    }
+    Ok(())
+}
+
+/// Defines the read-only data object `name` holding `bytes` in the section `section`.
+fn define_section_data(
+    module: &mut ObjectModule,
+    name: &str,
+    section: &str,
+    bytes: Vec<u8>,
+) -> MCompileResult<()> {
+    let id = module
+        .declare_data(name, Linkage::Export, false, false)
+        .map_err(cranelift_error)?;
+    let mut data = DataDescription::new();
+    data.define(bytes.into_boxed_slice());
+    let section = object_section(&module.isa().triple().to_string(), section);
+    let (segment, section) = section.split_once(',').unwrap_or(("", &section));
+    data.set_segment_section(segment, section);
+    module.define_data(id, &data).map_err(cranelift_error)
+}
+
+/// Compiles `midi_program` in memory and runs it right away, returning its exit code.
+///
+/// IO goes through Rust's stdin and stdout rather than libc's.
+pub fn run_jit(midi_program: &MidiAST, options: CompileOptions) -> MCompileResult<i32> {
+    let stdio = [
+        ("putchar", jit_putchar as *const u8),
+        ("getchar", jit_getchar as *const u8),
+        ("fflush", jit_fflush as *const u8),
+    ];
+    run_with_runtime(midi_program, options, &stdio).map(|(exit_code, _)| exit_code)
+}
+
+/// Like `run_jit`, but reading `input` instead of stdin and writing `output`
+/// instead of stdout when they're given.
+pub fn run_jit_redirected(
+    midi_program: &MidiAST,
+    options: CompileOptions,
+    input: Option<fs::File>,
+    output: Option<fs::File>,
+) -> MCompileResult<i32> {
+    REDIRECT.with(|redirect| {
+        *redirect.borrow_mut() = Redirect {
+            input: input.map(io::BufReader::new),
+            output: output.map(io::BufWriter::new),
+        }
+    });
+    let result = run_jit(midi_program, options);
+    // the program flushed its output before returning
+    REDIRECT.with(RefCell::take);
+    result
+}
+
+/// What a program left behind after `run_jit_captured`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct JitRun {
+    pub exit_code: i32,
+    pub output: Vec<u8>,
+    /// What the program wrote to stderr, its tape dumps
+    pub errors: Vec<u8>,
+    /// The whole tape, cells past the ones the program used included
+    pub tape: Vec<Cell>,
+    /// Index in `tape` of the cell the pointer started on, past 0 once an
+    /// infinite tape grew to the left
+    pub origin: usize,
+}
+
+/// Like `run_jit`, but reading `input` instead of stdin and collecting the
+/// output, tape dumps and the final tape instead of letting them go.
+pub fn run_jit_captured(midi_program: &MidiAST, options: CompileOptions, input: &[u8]) -> MCompileResult<JitRun> {
+    let cell_bytes = options.cell_width.bits() as usize / 8;
+    CAPTURE.with(|capture| {
+        *capture.borrow_mut() = Some(Capture {
+            input: input.to_vec(),
+            ..Capture::default()
+        })
+    });
+    let captured = [
+        ("putchar", capture_putchar as *const u8),
+        ("getchar", capture_getchar as *const u8),
+        ("fflush", capture_fflush as *const u8),
+        ("calloc", capture_calloc as *const u8),
+        ("realloc", capture_realloc as *const u8),
+        ("free", capture_free as *const u8),
+        ("write", capture_write as *const u8),
+    ];
+    let result = run_with_runtime(midi_program, options, &captured);
+    let capture = CAPTURE.with(|capture| capture.borrow_mut().take()).unwrap_or_default();
+    let tape = capture
+        .tape
+        .chunks_exact(cell_bytes)
+        .map(|cell| match *cell {
+            [byte] => Wrapping(i32::from(byte)),
+            [low, high] => Wrapping(i32::from(u16::from_ne_bytes([low, high]))),
+            _ => Wrapping(i32::from_ne_bytes(cell.try_into().unwrap_or_default())),
+        })
+        .collect();
+    let (exit_code, origin) = result?;
+    Ok(JitRun {
+        exit_code,
+        output: capture.output,
+        errors: capture.errors,
+        tape,
+        origin,
+    })
+}
+
+/// Compiles `midi_program` with the runtime functions in `symbols` standing in
+/// for libc's, and runs it. Returns its exit code and where the cell the
+/// pointer started on ended up.
+fn run_with_runtime(
+    midi_program: &MidiAST,
+    mut options: CompileOptions,
+    symbols: &[(&str, *const u8)],
+) -> MCompileResult<(i32, usize)> {
+    check_supported(&options)?;
+    options.fit_tape(midi_program);
+    let mut builder = JITBuilder::with_isa(host_isa(&options, false)?, default_libcall_names());
+    for (name, function) in symbols {
+        builder.symbol(*name, *function);
+    }
+    // calls find what the interpreter would, the JIT would look in the whole
+    // process otherwise. The libraries stay loaded until the program returns
+    let mut functions = Functions::linked(&options.link).map_err(MCompileError::Unsupported)?;
+    for name in called_functions(midi_program) {
+        let function = functions.find(name).map_err(|_| MCompileError::Unsupported(format!("no function called {} to call", name)))?;
+        builder.symbol(name, function as *const u8);
+    }
+    let mut module = JITModule::new(builder);
+    let (main_id, origin_id, _) = define_main(&mut module, midi_program, &options)?;
+    module.finalize_definitions().map_err(cranelift_error)?;
+
+    let code = module.get_finalized_function(main_id);
+    // SAFETY: `main` was defined above with the signature `() -> i32`
+    let main = unsafe { mem::transmute::<*const u8, extern "C" fn() -> i32>(code) };
+    let exit_code = main();
+    let origin = origin_id.map_or(0, |id| {
+        let (origin, _) = module.get_finalized_data(id);
+        // SAFETY: `midilang_origin` is a pointer sized number main stored before returning
+        unsafe { origin.cast::<usize>().read_unaligned() }
+    });
+    // SAFETY: nothing from the module is used after this
+    unsafe { module.free_memory() };
+    Ok((exit_code, origin))
+}
+
+/// Files the program running in `run_jit_redirected` reads and writes instead
+/// of stdin and stdout.
+#[derive(Default)]
+struct Redirect {
+    input: Option<io::BufReader<fs::File>>,
+    output: Option<io::BufWriter<fs::File>>,
+}
+
+thread_local! {
+    static REDIRECT: RefCell<Redirect> = RefCell::default();
+}
+
+extern "C" fn jit_putchar(c: i32) -> i32 {
+    let written = REDIRECT.with(|redirect| match &mut redirect.borrow_mut().output {
+        Some(file) => file.write_all(&[c as u8]),
+        None => io::stdout().write_all(&[c as u8]),
+    });
+    match written {
+        Ok(()) => c,
+        Err(_) => -1,
+    }
+}
+
+extern "C" fn jit_getchar() -> i32 {
+    let mut buf = [0_u8];
+    let read = REDIRECT.with(|redirect| match &mut redirect.borrow_mut().input {
+        Some(file) => file.read(&mut buf),
+        None => io::stdin().read(&mut buf),
+    });
+    match read {
+        Ok(1) => i32::from(buf[0]),
+        _ => -1,
+    }
+}
+
+extern "C" fn jit_fflush(_stream: *const u8) -> i32 {
+    let flushed = REDIRECT.with(|redirect| match &mut redirect.borrow_mut().output {
+        Some(file) => file.flush(),
+        None => io::stdout().flush(),
+    });
+    match flushed {
+        Ok(()) => 0,
+        Err(_) => -1,
+    }
+}
+
+/// IO and the tape of the program running in `run_jit_captured`.
+#[derive(Default)]
+struct Capture {
+    input: Vec<u8>,
+    /// Bytes of `input` read so far
+    read: usize,
+    output: Vec<u8>,
+    errors: Vec<u8>,
+    /// Size of the tape as last allocated
+    tape_bytes: usize,
+    /// Copy of the tape taken when the program frees it
+    tape: Vec<u8>,
+}
+
+thread_local! {
+    // the runtime functions can't take any state, so it lives here
+    static CAPTURE: RefCell<Option<Capture>> = const { RefCell::new(None) };
+}
+
+/// Runs `f` on the capture of the current run, which `run_jit_captured` always sets.
+fn with_capture<T: Default>(f: impl FnOnce(&mut Capture) -> T) -> T {
+    CAPTURE.with(|capture| capture.borrow_mut().as_mut().map(f).unwrap_or_default())
+}
+
+extern "C" {
+    fn calloc(count: usize, size: usize) -> *mut u8;
+    fn realloc(ptr: *mut u8, size: usize) -> *mut u8;
+    fn free(ptr: *mut u8);
+}
+
+extern "C" fn capture_putchar(c: i32) -> i32 {
+    with_capture(|capture| capture.output.push(c as u8));
+    c
+}
+
+extern "C" fn capture_getchar() -> i32 {
+    with_capture(|capture| match capture.input.get(capture.read) {
+        Some(&byte) => {
+            capture.read += 1;
+            i32::from(byte)
+        }
+        None => -1,
+    })
+}
+
+// only tape dumps write, and only to stderr
+extern "C" fn capture_write(_fd: i32, buf: *const u8, len: usize) -> isize {
+    // SAFETY: the runtime passes a buffer `len` bytes long
+    let bytes = unsafe { std::slice::from_raw_parts(buf, len) };
+    with_capture(|capture| capture.errors.extend_from_slice(bytes));
+    len as isize
+}
+
+extern "C" fn capture_fflush(_stream: *const u8) -> i32 {
+    0
+}
+
+// the tape is the only thing programs allocate
+extern "C" fn capture_calloc(count: usize, size: usize) -> *mut u8 {
+    with_capture(|capture| capture.tape_bytes = count * size);
+    unsafe { calloc(count, size) }
+}
+
+extern "C" fn capture_realloc(ptr: *mut u8, size: usize) -> *mut u8 {
+    with_capture(|capture| capture.tape_bytes = size);
+    unsafe { realloc(ptr, size) }
+}
+
+extern "C" fn capture_free(ptr: *mut u8) {
+    with_capture(|capture| {
+        // SAFETY: `ptr` is the tape, which is `tape_bytes` long
+        capture.tape = unsafe { std::slice::from_raw_parts(ptr, capture.tape_bytes) }.to_vec();
+    });
+    unsafe { free(ptr) }
+}
+
+/// libc functions the generated code calls into, mapped to Rust ones when JITing.
+struct Runtime {
+    putchar: FuncId,
+    getchar: FuncId,
+    fflush: FuncId,
+    calloc: FuncId,
+    realloc: FuncId,
+    memset: FuncId,
+    memcpy: FuncId,
+    memmove: FuncId,
+    free: FuncId,
+    abort: FuncId,
+    time: FuncId,
+    write: FuncId,
+}
+
+fn declare_runtime<M: Module>(module: &mut M) -> MCompileResult<Runtime> {
+    let ptr = module.target_config().pointer_type();
+    let mut declare = |name: &str, params: &[Type], returns: &[Type]| {
+        let mut sig = module.make_signature();
+        sig.params
+            .extend(params.iter().map(|ty| AbiParam::new(*ty)));
+        sig.returns
+            .extend(returns.iter().map(|ty| AbiParam::new(*ty)));
+        module
+            .declare_function(name, Linkage::Import, &sig)
+            .map_err(cranelift_error)
+    };
+    Ok(Runtime {
+        putchar: declare("putchar", &[types::I32], &[types::I32])?,
+        getchar: declare("getchar", &[], &[types::I32])?,
+        fflush: declare("fflush", &[ptr], &[types::I32])?,
+        calloc: declare("calloc", &[ptr, ptr], &[ptr])?,
+        realloc: declare("realloc", &[ptr, ptr], &[ptr])?,
+        memset: declare("memset", &[ptr, types::I32, ptr], &[ptr])?,
+        memcpy: declare("memcpy", &[ptr, ptr, ptr], &[ptr])?,
+        memmove: declare("memmove", &[ptr, ptr, ptr], &[ptr])?,
+        free: declare("free", &[ptr], &[])?,
+        abort: declare("abort", &[], &[])?,
+        time: declare("time", &[ptr], &[types::I64])?,
+        write: declare("write", &[types::I32, ptr, ptr], &[ptr])?,
+    })
+}
+
+/// Names of the external functions `program` calls, in the order they're
+/// first called.
+fn called_functions(program: &[MidiInstruction]) -> Vec<&str> {
+    fn push<'a>(program: &'a [MidiInstruction], names: &mut Vec<&'a str>) {
+        for inst in program {
+            match &inst.instruction {
+                Call { name } if !names.contains(&name.as_str()) => names.push(name),
+                Loop { body } => push(body, names),
+                _ => {}
+            }
+        }
+    }
+    let mut names = vec![];
+    push(program, &mut names);
+    names
+}
+
+/// Declares every function `midi_program` calls as `int name(int)`, for the
+/// linker or the JIT to find.
+fn declare_functions<'p, M: Module>(module: &mut M, midi_program: &'p MidiAST) -> MCompileResult<Vec<(&'p str, FuncId)>> {
+    let mut sig = module.make_signature();
+    sig.params.push(AbiParam::new(types::I32));
+    sig.returns.push(AbiParam::new(types::I32));
+    called_functions(midi_program)
+        .into_iter()
+        .map(|name| Ok((name, module.declare_function(name, Linkage::Import, &sig).map_err(cranelift_error)?)))
+        .collect()
+}
+
+/// Defines `main` for `midi_program` in `module`, returning it along with
+/// `midilang_origin` and its IR.
+fn define_main<M: Module>(
+    module: &mut M,
+    midi_program: &MidiAST,
+    options: &CompileOptions,
+) -> MCompileResult<(FuncId, Option<DataId>, String)> {
+    let runtime = declare_runtime(module)?;
+    let functions = declare_functions(module, midi_program)?;
+    let objects = MainObjects {
+        data: define_data(module, options)?,
+        origin: define_origin(module, options)?,
+        dump_tape: match plays(midi_program, &DumpTape) {
+            true => Some(define_dump_tape(module, &runtime, options)?),
+            false => None,
+        },
+    };
+    let mut sig = module.make_signature();
+    sig.returns.push(AbiParam::new(types::I32));
+    let main_id = module
+        .declare_function("main", Linkage::Export, &sig)
+        .map_err(cranelift_error)?;
+
+    let mut ctx = module.make_context();
+    ctx.func.signature = sig;
+    let mut fn_ctx = FunctionBuilderContext::new();
+    let builder = FunctionBuilder::new(&mut ctx.func, &mut fn_ctx);
+    let translator = Translator::new(builder, module, &runtime, &functions, &objects, options);
+    translator.translate(midi_program);
+
+    let clif = ctx.func.display().to_string();
+    debug!("Cranelift IR:\n{}", clif);
+    module
+        .define_function(main_id, &mut ctx)
+        .map_err(cranelift_error)?;
+    module.clear_context(&mut ctx);
+    Ok((main_id, objects.origin, clif))
+}
+
+/// What `main` uses that's defined before it, besides the runtime.
+struct MainObjects {
+    /// `midilang_data`, when there's a data track
+    data: Option<DataId>,
+    /// `midilang_origin`, when the tape is infinite
+    origin: Option<DataId>,
+    /// `midilang_dump_tape`, when the program dumps the tape
+    dump_tape: Option<FuncId>,
+}
+
+/// Defines the read-only data object `midilang_data` holding the cells of the
+/// data track, `None` when there aren't any.
+fn define_data<M: Module>(module: &mut M, options: &CompileOptions) -> MCompileResult<Option<DataId>> {
+    if options.data.is_empty() {
+        return Ok(None);
+    }
+    let id = module
+        .declare_data("midilang_data", Linkage::Local, false, false)
+        .map_err(cranelift_error)?;
+    // data is 7 bit, so every byte but the low one of a cell is 0
+    let cell_bytes = options.cell_width.bits() as usize / 8;
+    let low = match module.isa().endianness() {
+        Endianness::Little => 0,
+        Endianness::Big => cell_bytes - 1,
+    };
+    let mut bytes = vec![0; options.data.len() * cell_bytes];
+    for (idx, &byte) in options.data.iter().enumerate() {
+        bytes[idx * cell_bytes + low] = byte;
+    }
+    let mut data = DataDescription::new();
+    data.define(bytes.into_boxed_slice());
+    module.define_data(id, &data).map_err(cranelift_error)?;
+    Ok(Some(id))
+}
+
+/// Defines the writable data object `midilang_origin`, where `main` leaves
+/// the index of the cell the pointer started on for the JIT to read back,
+/// `None` unless the tape is infinite.
+fn define_origin<M: Module>(module: &mut M, options: &CompileOptions) -> MCompileResult<Option<DataId>> {
+    if options.tape_mode != TapeMode::Infinite {
+        return Ok(None);
+    }
+    let id = module
+        .declare_data("midilang_origin", Linkage::Local, true, false)
+        .map_err(cranelift_error)?;
+    let mut data = DataDescription::new();
+    data.define_zeroinit(module.target_config().pointer_bytes() as usize);
+    module.define_data(id, &data).map_err(cranelift_error)?;
+    Ok(Some(id))
+}
+
+/// Defines the tape dump runtime like the LLVM backend's, writing straight to
+/// stderr a piece at a time, and returns `midilang_dump_tape`.
+///
+/// - `midilang_write_decimal(i64)` writes a number that isn't negative
+/// - `midilang_dump_tape(cells, capacity, index, origin)` writes the cells
+///   around `index` like `midilang_core::TapeDump`, those off the tape as 0
+fn define_dump_tape<M: Module>(module: &mut M, runtime: &Runtime, options: &CompileOptions) -> MCompileResult<FuncId> {
+    let ptr = module.target_config().pointer_type();
+    let cell_type = cell_type(options.cell_width);
+    let mut ctx = module.make_context();
+    let mut fn_ctx = FunctionBuilderContext::new();
+
+    // midilang_write_decimal, filling in digits from the end of a buffer big
+    // enough for any u64
+    let mut sig = module.make_signature();
+    sig.params.push(AbiParam::new(types::I64));
+    let decimal_id = module
+        .declare_function("midilang_write_decimal", Linkage::Local, &sig)
+        .map_err(cranelift_error)?;
+    ctx.func.signature = sig;
+    let mut builder = FunctionBuilder::new(&mut ctx.func, &mut fn_ctx);
+    let write = module.declare_func_in_func(runtime.write, builder.func);
+    let digits = builder.create_sized_stack_slot(StackSlotData::new(StackSlotKind::ExplicitSlot, 20, 0));
+    let entry = builder.create_block();
+    let digit = builder.create_block();
+    let done = builder.create_block();
+    builder.append_block_params_for_function_params(entry);
+    builder.append_block_param(digit, types::I64);
+    builder.append_block_param(digit, ptr);
+    builder.switch_to_block(entry);
+    let value = builder.block_params(entry)[0];
+    let end = builder.ins().iconst(ptr, 20);
+    builder.ins().jump(digit, &[value, end]);
+
+    builder.switch_to_block(digit);
+    let (value, end) = (builder.block_params(digit)[0], builder.block_params(digit)[1]);
+    let start = builder.ins().iadd_imm(end, -1);
+    let last = builder.ins().urem_imm(value, 10);
+    let ascii = builder.ins().iadd_imm(last, i64::from(b'0'));
+    let ascii = builder.ins().ireduce(types::I8, ascii);
+    let buffer = builder.ins().stack_addr(ptr, digits, 0);
+    let first = builder.ins().iadd(buffer, start);
+    builder.ins().store(MemFlags::trusted(), ascii, first, 0);
+    let rest = builder.ins().udiv_imm(value, 10);
+    builder.ins().brif(rest, digit, &[rest, start], done, &[]);
+
+    builder.switch_to_block(done);
+    let stderr = builder.ins().iconst(types::I32, 2);
+    let len = builder.ins().irsub_imm(start, 20);
+    builder.ins().call(write, &[stderr, first, len]);
+    builder.ins().return_(&[]);
+    builder.seal_all_blocks();
+    builder.finalize();
+    module
+        .define_function(decimal_id, &mut ctx)
+        .map_err(cranelift_error)?;
+    module.clear_context(&mut ctx);
+
+    // midilang_dump_tape
+    let mut sig = module.make_signature();
+    sig.params.extend([AbiParam::new(ptr); 4]);
+    let dump_id = module
+        .declare_function("midilang_dump_tape", Linkage::Local, &sig)
+        .map_err(cranelift_error)?;
+    ctx.func.signature = sig;
+    let mut builder = FunctionBuilder::new(&mut ctx.func, &mut fn_ctx);
+    let write = module.declare_func_in_func(runtime.write, builder.func);
+    let decimal = module.declare_func_in_func(decimal_id, builder.func);
+    let text = builder.create_sized_stack_slot(StackSlotData::new(StackSlotKind::ExplicitSlot, 8, 0));
+    let entry = builder.create_block();
+    let cell = builder.create_block();
+    let end = builder.create_block();
+    builder.append_block_params_for_function_params(entry);
+    builder.append_block_param(cell, ptr);
+    builder.switch_to_block(entry);
+    let (cells, capacity, index, origin) = match *builder.block_params(entry) {
+        [cells, capacity, index, origin] => (cells, capacity, index, origin),
+        _ => unreachable!("midilang_dump_tape takes 4 parameters"),
+    };
+    let radius = DUMP_RADIUS as i64;
+    let zero = builder.ins().iconst(ptr, 0);
+    let left = builder.ins().iadd_imm(index, -radius);
+    // an infinite tape shows the cells left of its first too, wrapping around
+    let first = match options.tape_mode {
+        TapeMode::Infinite => left,
+        _ => {
+            let near_start = builder.ins().icmp_imm(IntCC::UnsignedLessThan, index, radius);
+            builder.ins().select(near_start, zero, left)
+        }
+    };
+    let past_last = builder.ins().iadd_imm(index, radius + 1);
+    write_text(&mut builder, ptr, write, text, b"tape at ", None);
+    let position = builder.ins().isub(index, origin);
+    let position = if ptr == types::I64 {
+        position
+    } else {
+        builder.ins().sextend(types::I64, position)
+    };
+    let negative = builder.ins().icmp_imm(IntCC::SignedLessThan, position, 0);
+    let (one, two) = (builder.ins().iconst(ptr, 1), builder.ins().iconst(ptr, 2));
+    let sign_len = builder.ins().select(negative, one, zero);
+    write_text(&mut builder, ptr, write, text, b"-", Some(sign_len));
+    let negated = builder.ins().ineg(position);
+    let magnitude = builder.ins().select(negative, negated, position);
+    builder.ins().call(decimal, &[magnitude]);
+    write_text(&mut builder, ptr, write, text, b":", None);
+    builder.ins().jump(cell, &[first]);
+
+    builder.switch_to_block(cell);
+    let at = builder.block_params(cell)[0];
+    let in_tape = builder.ins().icmp(IntCC::UnsignedLessThan, at, capacity);
+    // cells past the end read the first one instead, and show as 0
+    let slot = builder.ins().select(in_tape, at, zero);
+    let offset = builder.ins().imul_imm(slot, i64::from(options.cell_width.bits() / 8));
+    let addr = builder.ins().iadd(cells, offset);
+    let loaded = builder.ins().load(cell_type, MemFlags::trusted(), addr, 0);
+    let loaded = builder.ins().uextend(types::I64, loaded);
+    let zero_cell = builder.ins().iconst(types::I64, 0);
+    let value = builder.ins().select(in_tape, loaded, zero_cell);
+    let is_pointer = builder.ins().icmp(IntCC::Equal, at, index);
+    let before_len = builder.ins().select(is_pointer, two, one);
+    write_text(&mut builder, ptr, write, text, b" [", Some(before_len));
+    builder.ins().call(decimal, &[value]);
+    let after_len = builder.ins().select(is_pointer, one, zero);
+    write_text(&mut builder, ptr, write, text, b"]", Some(after_len));
+    let next = builder.ins().iadd_imm(at, 1);
+    let more = builder.ins().icmp(IntCC::NotEqual, next, past_last);
+    builder.ins().brif(more, cell, &[next], end, &[]);
+
+    builder.switch_to_block(end);
+    write_text(&mut builder, ptr, write, text, b"\n", None);
+    builder.ins().return_(&[]);
+    builder.seal_all_blocks();
+    builder.finalize();
+    module
+        .define_function(dump_id, &mut ctx)
+        .map_err(cranelift_error)?;
+    module.clear_context(&mut ctx);
+    Ok(dump_id)
+}
+
+// writes `text`, no more than the 8 bytes `slot` holds, to stderr, or its first
+// `len` bytes
+fn write_text(builder: &mut FunctionBuilder, ptr: Type, write: FuncRef, slot: StackSlot, text: &[u8], len: Option<Value>) {
+    for (offset, &byte) in text.iter().enumerate() {
+        let byte = builder.ins().iconst(types::I8, i64::from(byte));
+        builder.ins().stack_store(byte, slot, offset as i32);
+    }
+    let stderr = builder.ins().iconst(types::I32, 2);
+    let buffer = builder.ins().stack_addr(ptr, slot, 0);
+    let len = len.unwrap_or_else(|| builder.ins().iconst(ptr, text.len() as i64));
+    builder.ins().call(write, &[stderr, buffer, len]);
+}
+
+fn cell_type(cell_width: CellWidth) -> Type {
+    match cell_width {
+        CellWidth::I8 => types::I8,
+        CellWidth::I16 => types::I16,
+        CellWidth::I32 => types::I32,
+    }
+}
+
+/// Emits the body of `main` one instruction at a time.
+struct Translator<'a> {
+    builder: FunctionBuilder<'a>,
+    options: &'a CompileOptions,
+    cell_type: Type,
+    cell_bytes: i64,
+    ptr_type: Type,
+    tape: Variable,
+    index: Variable,
+    capacity: Variable,
+    /// Index of the cell the pointer started on, for infinite tapes
+    origin: Variable,
+    /// State of the SplitMix64 random bytes come from
+    rng: Variable,
+    putchar: FuncRef,
+    getchar: FuncRef,
+    fflush: FuncRef,
+    calloc: FuncRef,
+    realloc: FuncRef,
+    memset: FuncRef,
+    memcpy: FuncRef,
+    memmove: FuncRef,
+    free: FuncRef,
+    abort: FuncRef,
+    time: FuncRef,
+    /// `midilang_dump_tape`, when the program dumps the tape
+    dump_tape: Option<FuncRef>,
+    /// Functions the program calls, by name
+    functions: HashMap<String, FuncRef>,
+    /// `midilang_data`, when there's a data track
+    data: Option<GlobalValue>,
+    /// `midilang_origin`, when the tape is infinite
+    origin_data: Option<GlobalValue>,
+    /// Counts translated instructions while `translate` runs
+    progress: Progress,
+}
+
+impl<'a> Translator<'a> {
+    fn new<M: Module>(
+        mut builder: FunctionBuilder<'a>,
+        module: &mut M,
+        runtime: &Runtime,
+        functions: &[(&str, FuncId)],
+        objects: &MainObjects,
+        options: &'a CompileOptions,
+    ) -> Self {
+        let mut import = |id| module.declare_func_in_func(id, builder.func);
+        let (putchar, getchar, fflush) = (
+            import(runtime.putchar),
+            import(runtime.getchar),
+            import(runtime.fflush),
+        );
+        let (calloc, realloc, memset) = (
+            import(runtime.calloc),
+            import(runtime.realloc),
+            import(runtime.memset),
+        );
+        let (memcpy, memmove) = (import(runtime.memcpy), import(runtime.memmove));
+        let (free, abort) = (import(runtime.free), import(runtime.abort));
+        let time = import(runtime.time);
+        let dump_tape = objects.dump_tape.map(&mut import);
+        let functions = functions.iter().map(|&(name, id)| (name.to_owned(), import(id))).collect();
+        let data = objects.data.map(|id| module.declare_data_in_func(id, builder.func));
+        let origin_data = objects.origin.map(|id| module.declare_data_in_func(id, builder.func));
+
+        let ptr_type = module.target_config().pointer_type();
+        let (tape, index, capacity, origin) = (
+            Variable::from_u32(0),
+            Variable::from_u32(1),
+            Variable::from_u32(2),
+            Variable::from_u32(4),
+        );
+        for var in [tape, index, capacity, origin] {
+            builder.declare_var(var, ptr_type);
+        }
+        let rng = Variable::from_u32(3);
+        builder.declare_var(rng, types::I64);
+        let cell_type = cell_type(options.cell_width);
+        Translator {
+            builder,
+            options,
+            cell_type,
+            cell_bytes: i64::from(options.cell_width.bits() / 8),
+            ptr_type,
+            tape,
+            index,
+            capacity,
+            origin,
+            rng,
+            putchar,
+            getchar,
+            fflush,
+            calloc,
+            realloc,
+            memset,
+            memcpy,
+            memmove,
+            free,
+            abort,
+            time,
+            dump_tape,
+            functions,
+            data,
+            origin_data,
+            progress: Progress::default(),
+        }
+    }
+
+    fn call(&mut self, func: FuncRef, args: &[Value]) -> Option<Value> {
+        let call = self.builder.ins().call(func, args);
+        self.builder.inst_results(call).first().copied()
+    }
+
+    fn translate(mut self, midi_program: &MidiAST) {
+        let entry = self.builder.create_block();
+        self.builder.switch_to_block(entry);
+        self.builder.seal_block(entry);
+
+        let num_cells = self
+            .builder
+            .ins()
+            .iconst(self.ptr_type, self.options.tape_size as i64);
+        let cell_bytes = self.builder.ins().iconst(self.ptr_type, self.cell_bytes);
+        let cells = self.call(self.calloc, &[num_cells, cell_bytes]).unwrap();
+        self.builder.def_var(self.tape, cells);
+        self.builder.def_var(self.capacity, num_cells);
+        let zero = self.builder.ins().iconst(self.ptr_type, 0);
+        self.builder.def_var(self.index, zero);
+        self.builder.def_var(self.origin, zero);
+        if let Some(data) = self.data {
+            let from = self.builder.ins().global_value(self.ptr_type, data);
+            let size = self.options.data.len() as i64 * self.cell_bytes;
+            let size = self.builder.ins().iconst(self.ptr_type, size);
+            self.call(self.memcpy, &[cells, from, size]);
+        }
+        let seed = match self.options.seed {
+            Some(seed) => self.builder.ins().iconst(types::I64, seed as i64),
+            None if plays(midi_program, &Random) => {
+                let null = self.builder.ins().iconst(self.ptr_type, 0);
+                self.call(self.time, &[null]).unwrap()
+            }
+            None => self.builder.ins().iconst(types::I64, 0),
+        };
+        self.builder.def_var(self.rng, seed);
+
+        self.progress = Progress::new("Compiling", instruction_count(midi_program));
+        self.translate_instructions(midi_program);
+        self.progress = Progress::default();
+
+        let null = self.builder.ins().iconst(self.ptr_type, 0);
+        self.call(self.fflush, &[null]);
+        if let Some(origin_data) = self.origin_data {
+            let at = self.builder.ins().global_value(self.ptr_type, origin_data);
+            let origin = self.builder.use_var(self.origin);
+            self.builder.ins().store(MemFlags::trusted(), origin, at, 0);
+        }
+        let cells = self.builder.use_var(self.tape);
+        self.call(self.free, &[cells]);
+        let exit_code = self.builder.ins().iconst(types::I32, 0);
+        self.builder.ins().return_(&[exit_code]);
+        self.builder.finalize();
+    }
+
+    fn translate_instructions(&mut self, program: &[MidiInstruction]) {
+        for inst in program {
+            self.progress.inc(1);
+            self.translate_instruction(inst);
+        }
+    }
+
+    /// Returns the address of the current cell.
+    fn cell_address(&mut self) -> Value {
+        let cells = self.builder.use_var(self.tape);
+        let index = self.builder.use_var(self.index);
+        let offset = self.builder.ins().imul_imm(index, self.cell_bytes);
+        self.builder.ins().iadd(cells, offset)
+    }
+
+    /// Returns `sum`, or 0 or the maximum when adding `step` to `cell` went past either.
+    fn saturate(&mut self, cell: Value, sum: Value, step: i64) -> Value {
+        // cells are treated as unsigned, so overflow means crossing 0
+        let max = u64::MAX >> (64 - self.options.cell_width.bits());
+        let bound = self.builder.ins().iconst(self.cell_type, if step > 0 { max as i64 } else { 0 });
+        if step.unsigned_abs() > max {
+            // no cell value can take a step this big
+            return bound;
+        }
+        let (condition, limit) = match step > 0 {
+            true => (IntCC::UnsignedGreaterThan, max - step as u64),
+            false => (IntCC::UnsignedLessThan, step.unsigned_abs()),
+        };
+        let limit = self.builder.ins().iconst(self.cell_type, limit as i64);
+        let crossed = self.builder.ins().icmp(condition, cell, limit);
+        self.builder.ins().select(crossed, bound, sum)
+    }
+
+    fn translate_instruction(&mut self, inst: &MidiInstruction) {
+        match &inst.instruction {
+            IncrementCell { amount } => {
+                let addr = self.cell_address();
+                let cell = self
+                    .builder
+                    .ins()
+                    .load(self.cell_type, MemFlags::trusted(), addr, 0);
+                let mut sum = self.builder.ins().iadd_imm(cell, i64::from(amount.0));
+                if self.options.overflow == Overflow::Saturate {
+                    sum = self.saturate(cell, sum, i64::from(amount.0));
+                }
+                self.builder.ins().store(MemFlags::trusted(), sum, addr, 0);
+            }
+            MovePointer { amount } if self.options.pointer_overflow == Some(PointerOverflow::Wrap) => {
+                // only fixed tapes wrap, so the pointer comes back in within one lap
+                let size = self.options.tape_size as i64;
+                let distance = (amount.unsigned_abs() as u64 % self.options.tape_size) as i64;
+                let index = self.builder.use_var(self.index);
+                let wrapped = if *amount < 0 {
+                    let moved = self.builder.ins().iadd_imm(index, -distance);
+                    let short = self.builder.ins().icmp_imm(IntCC::UnsignedLessThan, index, distance);
+                    let around = self.builder.ins().iadd_imm(index, size - distance);
+                    self.builder.ins().select(short, around, moved)
+                } else {
+                    let moved = self.builder.ins().iadd_imm(index, distance);
+                    let past = self.builder.ins().icmp_imm(IntCC::UnsignedGreaterThanOrEqual, moved, size);
+                    let around = self.builder.ins().iadd_imm(moved, -size);
+                    self.builder.ins().select(past, around, moved)
+                };
+                self.builder.def_var(self.index, wrapped);
+            }
+            MovePointer { amount } => {
+                if self.options.tape_mode == TapeMode::Infinite && *amount < 0 {
+                    self.grow_tape_left(amount.unsigned_abs() as i64);
+                }
+                let index = self.builder.use_var(self.index);
+                let moved = self.builder.ins().iadd_imm(index, *amount as i64);
+                if self.options.tape_mode != TapeMode::Fixed && *amount > 0 {
+                    self.grow_tape(moved);
+                }
+                self.builder.def_var(self.index, moved);
+            }
+            OutputCell => {
+                let addr = self.cell_address();
+                let cell = self
+                    .builder
+                    .ins()
+                    .load(self.cell_type, MemFlags::trusted(), addr, 0);
+                let out = if self.cell_type == types::I32 {
+                    cell
+                } else {
+                    self.builder.ins().uextend(types::I32, cell)
+                };
+                self.call(self.putchar, &[out]);
+            }
+            InputCell => {
+                // flush first so prompts show up before we block on input
+                let null = self.builder.ins().iconst(self.ptr_type, 0);
+                self.call(self.fflush, &[null]);
+                // EOF reads as 0
+                let read = self.call(self.getchar, &[]).unwrap();
+                let eof = self.builder.ins().icmp_imm(IntCC::Equal, read, -1);
+                let byte = if self.cell_type == types::I32 {
+                    read
+                } else {
+                    self.builder.ins().ireduce(self.cell_type, read)
+                };
+                let zero = self.builder.ins().iconst(self.cell_type, 0);
+                let new_cell = self.builder.ins().select(eof, zero, byte);
+                let addr = self.cell_address();
+                self.builder
+                    .ins()
+                    .store(MemFlags::trusted(), new_cell, addr, 0);
+            }
+            Call { name } => {
+                // whatever the function prints goes after what came before
+                let null = self.builder.ins().iconst(self.ptr_type, 0);
+                self.call(self.fflush, &[null]);
+                let addr = self.cell_address();
+                let cell = self
+                    .builder
+                    .ins()
+                    .load(self.cell_type, MemFlags::trusted(), addr, 0);
+                let arg = if self.cell_type == types::I32 {
+                    cell
+                } else {
+                    self.builder.ins().uextend(types::I32, cell)
+                };
+                let result = self.call(self.functions[name], &[arg]).unwrap();
+                let new_cell = if self.cell_type == types::I32 {
+                    result
+                } else {
+                    self.builder.ins().ireduce(self.cell_type, result)
+                };
+                self.builder
+                    .ins()
+                    .store(MemFlags::trusted(), new_cell, addr, 0);
+            }
+            Random => {
+                // SplitMix64, like `midilang_core::Rng`
+                let state = self.builder.use_var(self.rng);
+                let state = self.builder.ins().iadd_imm(state, GOLDEN_GAMMA as i64);
+                self.builder.def_var(self.rng, state);
+                let mut z = state;
+                for (shift, multiplier) in [(30, MIX_MULTIPLIERS[0]), (27, MIX_MULTIPLIERS[1])] {
+                    let shifted = self.builder.ins().ushr_imm(z, shift);
+                    let mixed = self.builder.ins().bxor(z, shifted);
+                    z = self.builder.ins().imul_imm(mixed, multiplier as i64);
+                }
+                let shifted = self.builder.ins().ushr_imm(z, 31);
+                let mixed = self.builder.ins().bxor(z, shifted);
+                let byte = self.builder.ins().ushr_imm(mixed, 56);
+                let new_cell = self.builder.ins().ireduce(self.cell_type, byte);
+                let addr = self.cell_address();
+                self.builder
+                    .ins()
+                    .store(MemFlags::trusted(), new_cell, addr, 0);
+            }
+            DumpTape => {
+                // after what the program printed so far
+                let null = self.builder.ins().iconst(self.ptr_type, 0);
+                self.call(self.fflush, &[null]);
+                let dump_tape = self.dump_tape.expect("the tape is dumped without midilang_dump_tape");
+                let args = [
+                    self.builder.use_var(self.tape),
+                    self.builder.use_var(self.capacity),
+                    self.builder.use_var(self.index),
+                    self.builder.use_var(self.origin),
+                ];
+                self.call(dump_tape, &args);
+            }
+            Loop { body } => {
+                let cond_block = self.builder.create_block();
+                let body_block = self.builder.create_block();
+                let end_block = self.builder.create_block();
+                self.builder.ins().jump(cond_block, &[]);
+
+                self.builder.switch_to_block(cond_block);
+                let addr = self.cell_address();
+                let cell = self
+                    .builder
+                    .ins()
+                    .load(self.cell_type, MemFlags::trusted(), addr, 0);
+                self.builder
+                    .ins()
+                    .brif(cell, body_block, &[], end_block, &[]);
+
+                self.builder.switch_to_block(body_block);
+                self.builder.seal_block(body_block);
+                self.translate_instructions(body);
+                self.builder.ins().jump(cond_block, &[]);
+                self.builder.seal_block(cond_block);
+
+                self.builder.switch_to_block(end_block);
+                self.builder.seal_block(end_block);
+            }
+            // compiled programs never stop for the debugger
+            Breakpoint => {}
+        }
+    }
+
+    /// Reallocates the tape when `moved` is past its end, at least doubling it
+    /// and zeroing the new cells.
+    fn grow_tape(&mut self, moved: Value) {
+        let grow_block = self.builder.create_block();
+        let failed_block = self.builder.create_block();
+        let grown_block = self.builder.create_block();
+        let done_block = self.builder.create_block();
+
+        let old_cap = self.builder.use_var(self.capacity);
+        let full = self
+            .builder
+            .ins()
+            .icmp(IntCC::UnsignedGreaterThanOrEqual, moved, old_cap);
+        self.builder
+            .ins()
+            .brif(full, grow_block, &[], done_block, &[]);
+
+        self.builder.switch_to_block(grow_block);
+        self.builder.seal_block(grow_block);
+        let doubled = self.builder.ins().ishl_imm(old_cap, 1);
+        let needed = self.builder.ins().iadd_imm(moved, 1);
+        let too_small = self
+            .builder
+            .ins()
+            .icmp(IntCC::UnsignedLessThan, doubled, needed);
+        let new_cap = self.builder.ins().select(too_small, needed, doubled);
+        let old_cells = self.builder.use_var(self.tape);
+        let bytes = self.builder.ins().imul_imm(new_cap, self.cell_bytes);
+        let cells = self.call(self.realloc, &[old_cells, bytes]).unwrap();
+        self.builder
+            .ins()
+            .brif(cells, grown_block, &[], failed_block, &[]);
+
+        self.builder.switch_to_block(failed_block);
+        self.builder.seal_block(failed_block);
+        self.call(self.abort, &[]);
+        self.builder.ins().trap(TrapCode::unwrap_user(1));
+
+        self.builder.switch_to_block(grown_block);
+        self.builder.seal_block(grown_block);
+        let old_bytes = self.builder.ins().imul_imm(old_cap, self.cell_bytes);
+        let fresh = self.builder.ins().iadd(cells, old_bytes);
+        let fresh_bytes = self.builder.ins().isub(bytes, old_bytes);
+        let zero = self.builder.ins().iconst(types::I32, 0);
+        self.call(self.memset, &[fresh, zero, fresh_bytes]);
+        self.builder.def_var(self.tape, cells);
+        self.builder.def_var(self.capacity, new_cap);
+        self.builder.ins().jump(done_block, &[]);
+
+        self.builder.switch_to_block(done_block);
+        self.builder.seal_block(done_block);
+    }
+
+    /// Reallocates the tape when the pointer is less than `distance` cells
+    /// from its start, adding at least as many zeroed cells in front as it had
+    /// and moving the pointer and origin along with them.
+    fn grow_tape_left(&mut self, distance: i64) {
+        let grow_block = self.builder.create_block();
+        let failed_block = self.builder.create_block();
+        let grown_block = self.builder.create_block();
+        let done_block = self.builder.create_block();
+
+        let index = self.builder.use_var(self.index);
+        let short = self
+            .builder
+            .ins()
+            .icmp_imm(IntCC::UnsignedLessThan, index, distance);
+        self.builder
+            .ins()
+            .brif(short, grow_block, &[], done_block, &[]);
+
+        self.builder.switch_to_block(grow_block);
+        self.builder.seal_block(grow_block);
+        let old_cap = self.builder.use_var(self.capacity);
+        let missing = self.builder.ins().irsub_imm(index, distance);
+        let too_few = self
+            .builder
+            .ins()
+            .icmp(IntCC::UnsignedLessThan, old_cap, missing);
+        let added = self.builder.ins().select(too_few, missing, old_cap);
+        let new_cap = self.builder.ins().iadd(old_cap, added);
+        let old_cells = self.builder.use_var(self.tape);
+        let bytes = self.builder.ins().imul_imm(new_cap, self.cell_bytes);
+        let cells = self.call(self.realloc, &[old_cells, bytes]).unwrap();
+        self.builder
+            .ins()
+            .brif(cells, grown_block, &[], failed_block, &[]);
+
+        self.builder.switch_to_block(failed_block);
+        self.builder.seal_block(failed_block);
+        self.call(self.abort, &[]);
+        self.builder.ins().trap(TrapCode::unwrap_user(1));
+
+        self.builder.switch_to_block(grown_block);
+        self.builder.seal_block(grown_block);
+        let old_bytes = self.builder.ins().imul_imm(old_cap, self.cell_bytes);
+        let added_bytes = self.builder.ins().imul_imm(added, self.cell_bytes);
+        let moved = self.builder.ins().iadd(cells, added_bytes);
+        self.call(self.memmove, &[moved, cells, old_bytes]);
+        let zero = self.builder.ins().iconst(types::I32, 0);
+        self.call(self.memset, &[cells, zero, added_bytes]);
+        self.builder.def_var(self.tape, cells);
+        self.builder.def_var(self.capacity, new_cap);
+        let index = self.builder.ins().iadd(index, added);
+        self.builder.def_var(self.index, index);
+        let origin = self.builder.use_var(self.origin);
+        let origin = self.builder.ins().iadd(origin, added);
+        self.builder.def_var(self.origin, origin);
+        self.builder.ins().jump(done_block, &[]);
+
+        self.builder.switch_to_block(done_block);
+        self.builder.seal_block(done_block);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+    use crate::parser::MidiASTBuilder;
+    use crate::provenance::{extract_source, Provenance};
+    use std::num::Wrapping;
+
+    fn build(insts: Vec<MidiInstruction>) -> MidiAST {
+        let mut builder = MidiASTBuilder::new();
+        for inst in insts {
+            builder.push(inst).unwrap();
+        }
+        builder.into_mast().unwrap()
+    }
+
+    #[test]
+    fn compiles_object_and_clif() {
+        let prog = build(vec![
+            MidiInstruction::new_inc(Wrapping(3)),
+            MidiInstruction::new_open_loop(),
+            MidiInstruction::new_move(1),
+            MidiInstruction::new_close_loop(),
+            MidiInstruction::new_output(),
+        ]);
+        let provenance = Provenance::new(Path::new("song.mid"), b"");
+        let options = CompileOptions::builder()
+            .tape_size(4)
+            .provenance(Some(provenance))
+            .embed_source(Some(b"MThd and the rest".to_vec()))
+            .build()
+            .unwrap();
+        let compiled = compile_program(&prog, options).unwrap();
+        assert!(compiled.print_ir().contains("call fn"));
+        let embedded = b"midilang provenance\0source=song.mid\0";
+        assert!(compiled.object.windows(embedded.len()).any(|bytes| bytes == embedded));
+        assert_eq!(extract_source(&compiled.object), Some(&b"MThd and the rest"[..]));
+        // the loop can't be bounded, so the tape grows
+        assert_eq!(compiled.options.tape_mode, TapeMode::Grow);
+        assert!(compiled.object.starts_with(b"\x7fELF") || !cfg!(target_os = "linux"));
+    }
+
+    #[test]
+    fn runs_in_the_jit() {
+        let prog = build(vec![
+            MidiInstruction::new_inc(Wrapping(2)),
+            MidiInstruction::new_open_loop(),
+            MidiInstruction::new_inc(Wrapping(-1)),
+            MidiInstruction::new_move(1),
+            MidiInstruction::new_inc(Wrapping(1)),
+            MidiInstruction::new_move(-1),
+            MidiInstruction::new_close_loop(),
+        ]);
+        assert_eq!(run_jit(&prog, CompileOptions::default()), Ok(0));
+    }
+
+    #[test]
+    fn runs_on_files_instead_of_stdio() {
+        let prog = build(vec![
+            MidiInstruction::new_input(),
+            MidiInstruction::new_inc(Wrapping(1)),
+            MidiInstruction::new_output(),
+        ]);
+        let (input, output) = (crate::compiler::temp_path("in"), crate::compiler::temp_path("out"));
+        fs::write(&input, b"A").unwrap();
+        let files = (fs::File::open(&input).ok(), fs::File::create(&output).ok());
+        assert_eq!(run_jit_redirected(&prog, CompileOptions::default(), files.0, files.1), Ok(0));
+        assert_eq!(fs::read(&output).unwrap(), b"B");
+        let _ = (fs::remove_file(input), fs::remove_file(output));
+        // compiled programs don't get to open them
+        let options = CompileOptions::builder().stdin(Some("in.txt".into())).build().unwrap();
+        assert!(matches!(compile_program(&prog, options), Err(MCompileError::Unsupported(_))));
+    }
+
+    #[test]
+    fn captures_io_and_tape() {
+        // echoes its input until EOF, then sets cell 2 to 300
+        let prog = build(vec![
+            MidiInstruction::new_input(),
+            MidiInstruction::new_open_loop(),
+            MidiInstruction::new_output(),
+            MidiInstruction::new_input(),
+            MidiInstruction::new_close_loop(),
+            MidiInstruction::new_move(2),
+            MidiInstruction::new_inc(Wrapping(300)),
+        ]);
+        let options = CompileOptions::builder()
+            .tape_size(3)
+            .cell_width(CellWidth::I16)
+            .build()
+            .unwrap();
+        let run = run_jit_captured(&prog, options, b"hi").unwrap();
+        assert_eq!(run.exit_code, 0);
+        assert_eq!(run.output, b"hi");
+        assert_eq!(run.tape, [Wrapping(0), Wrapping(0), Wrapping(300)]);
+    }
+
+    #[test]
+    fn picks_the_random_bytes_of_its_seed() {
+        let prog = build(vec![
+            MidiInstruction::new_random(),
+            MidiInstruction::new_move(1),
+            MidiInstruction::new_random(),
+        ]);
+        let options = CompileOptions::builder().tape_size(2).seed(Some(7)).build().unwrap();
+        let run = run_jit_captured(&prog, options, b"").unwrap();
+        let mut rng = midilang_core::Rng::new(7);
+        assert_eq!(run.tape, [Wrapping(i32::from(rng.byte())), Wrapping(i32::from(rng.byte()))]);
+    }
+
+    #[test]
+    fn starts_the_tape_with_data() {
+        let prog = build(vec![
+            MidiInstruction::new_output(),
+            MidiInstruction::new_move(1),
+            MidiInstruction::new_inc(Wrapping(1)),
+            MidiInstruction::new_output(),
+        ]);
+        let options = CompileOptions::builder()
+            .tape_size(1)
+            .cell_width(CellWidth::I16)
+            .data(b"hi".to_vec())
+            .build()
+            .unwrap();
+        let run = run_jit_captured(&prog, options, b"").unwrap();
+        assert_eq!(run.output, b"hj");
+        assert_eq!(run.tape, [Wrapping(104), Wrapping(106)]);
+    }
+
+    #[test]
+    fn leaves_checks_and_cross_compiling_to_llvm() {
+        let checked = CompileOptions::builder()
+            .bounds_check(true)
+            .build()
+            .unwrap();
+        assert!(matches!(
+            compile_program(&vec![], checked),
+            Err(MCompileError::Unsupported(_))
+        ));
+        let cross = CompileOptions::builder()
+            .target_triple(Some("aarch64-unknown-linux-gnu".to_owned()))
+            .build()
+            .unwrap();
+        assert!(matches!(
+            run_jit(&vec![], cross),
+            Err(MCompileError::Unsupported(_))
+        ));
+        let debug = CompileOptions::builder()
+            .debug_info(Some("prog.midimap".into()))
+            .build()
+            .unwrap();
+        assert!(matches!(
+            compile_program(&vec![], debug),
+            Err(MCompileError::Unsupported(_))
+        ));
+        let perform = CompileOptions::builder().perform(Some(100)).build().unwrap();
+        assert!(matches!(
+            compile_program(&vec![], perform),
+            Err(MCompileError::Unsupported(_))
+        ));
+    }
+
+    #[test]
+    fn dumps_the_tape_without_touching_it() {
+        let prog = build(vec![
+            MidiInstruction::new_inc(Wrapping(72)),
+            MidiInstruction::new_dump_tape(),
+            MidiInstruction::new_output(),
+            MidiInstruction::new_move(1),
+            MidiInstruction::new_dump_tape(),
+        ]);
+        let options = CompileOptions::builder().tape_size(2).build().unwrap();
+        let run = run_jit_captured(&prog, options, b"").unwrap();
+        assert_eq!((run.exit_code, run.output), (0, b"H".to_vec()));
+        assert_eq!(run.tape, [Wrapping(72), Wrapping(0)]);
+        let dumps = String::from_utf8(run.errors).unwrap();
+        assert_eq!(dumps, "tape at 0: [72] 0 0 0 0\ntape at 1: 72 [0] 0 0 0 0\n");
+    }
+
+    #[test]
+    fn grows_infinite_tapes_to_the_left() {
+        let prog = build(vec![
+            MidiInstruction::new_inc(Wrapping(7)),
+            MidiInstruction::new_move(-3),
+            MidiInstruction::new_inc(Wrapping(1)),
+            MidiInstruction::new_dump_tape(),
+            MidiInstruction::new_move(3),
+            MidiInstruction::new_output(),
+        ]);
+        let options = CompileOptions::builder().tape_size(2).tape_mode(TapeMode::Infinite).build().unwrap();
+        let run = run_jit_captured(&prog, options, b"").unwrap();
+        assert_eq!((run.exit_code, run.output), (0, b"\x07".to_vec()));
+        // three cells missing from a tape of two grew it by three
+        assert_eq!(run.origin, 3);
+        assert_eq!(run.tape, [Wrapping(1), Wrapping(0), Wrapping(0), Wrapping(7), Wrapping(0)]);
+        let dumps = String::from_utf8(run.errors).unwrap();
+        assert_eq!(dumps, "tape at -3: 0 0 0 0 [1] 0 0 7 0\n");
+    }
+}
@@ -0,0 +1,2131 @@
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::path::Path;
+
+use log::debug;
+use midilang_core::{DUMP_RADIUS, GOLDEN_GAMMA, MIX_MULTIPLIERS};
+
+use super::{
+    link, link_to_memory, object_section, plays, Backend, CompileOptions, Emit, MCompileError, MCompileResult,
+    IoMode, OptLevel, Overflow, PointerOverflow, TapeMode,
+};
+use crate::parser::{c_major_root, MidiAST, MidiInstruction, MidiInstructionKind::*, Position};
+use crate::progress::{instruction_count, Progress};
+use crate::provenance::embedded_source;
+use crate::timing::SourceMap;
+pub use wrapper::host_triple;
+use wrapper::{
+    BasicBlock, Builder, CodeGenOptLevel, DebugInfoBuilder, FileType, Function, IntPredicate, Linkage, Metadata, Module,
+    TargetMachine, Type, Value,
+};
+
+pub mod jit;
+mod wrapper;
+
+/// Metadata kind attached to every instruction emitted for a MIDI instruction,
+/// holding the musical location of that instruction.
+const POSITION_METADATA: &str = "midilang.position";
+
+/// Named metadata holding the provenance of the program as `!{!"field", !"value"}` pairs.
+const PROVENANCE_METADATA: &str = "midilang.provenance";
+
+/// Size of the stdin and stdout buffers in the runtime of compiled programs.
+const IO_BUFFER_SIZE: u64 = 4096;
+
+/// Environment variable naming the raw MIDI device performances are played on.
+const PERFORM_DEVICE_VAR: &str = "MIDILANG_MIDI_OUT";
+
+/// Device performances are played on when `PERFORM_DEVICE_VAR` isn't set, the
+/// first port of the first ALSA sound card.
+const PERFORM_DEVICE: &str = "/dev/snd/midiC0D0";
+
+/// Performances play chord roots in the octave starting at middle C.
+const MIDDLE_C: u8 = 60;
+
+impl From<OptLevel> for CodeGenOptLevel {
+    fn from(level: OptLevel) -> Self {
+        match level {
+            OptLevel::O0 => CodeGenOptLevel::LLVMCodeGenLevelNone,
+            OptLevel::O1 => CodeGenOptLevel::LLVMCodeGenLevelLess,
+            OptLevel::O2 => CodeGenOptLevel::LLVMCodeGenLevelDefault,
+            OptLevel::O3 => CodeGenOptLevel::LLVMCodeGenLevelAggressive,
+        }
+    }
+}
+
+/// Owns the LLVM module and target machine used to compile a single program.
+pub struct MidiCompiler {
+    module: Module,
+    target_machine: TargetMachine,
+    /// Normalized triple of `target_machine`
+    triple: String,
+    options: CompileOptions,
+    /// Used to name the MIDI location responsible for runtime errors
+    source_map: Option<SourceMap>,
+}
+
+impl MidiCompiler {
+    /// Fails when the target triple in `options` isn't supported.
+    pub fn new(options: CompileOptions) -> MCompileResult<Self> {
+        let target_machine = TargetMachine::new(options.target_triple.as_deref(), options.opt_level.into())
+            .map_err(MCompileError::LLVMError)?;
+        let module = Module::new("midilang");
+        module.set_target(&target_machine);
+        Ok(MidiCompiler {
+            module,
+            triple: target_machine.triple(),
+            target_machine,
+            options,
+            source_map: None,
+        })
+    }
+
+    /// Lets runtime errors name the bar and beat of the instruction that caused them.
+    pub fn set_source_map(&mut self, source_map: SourceMap) {
+        self.source_map = Some(source_map);
+    }
+
+    /// Emits `main` for `midi_program` into the module, then runs the LLVM verifier over it.
+    ///
+    /// Verifier errors name the broken functions and, when the verifier points at an
+    /// instruction we know the origin of, the MIDI location responsible for it.
+    pub fn compile(&mut self, midi_program: &MidiAST) -> MCompileResult<()> {
+        if self.options.perform.is_some() && self.triple.starts_with("wasm") {
+            return Err(MCompileError::Unsupported(
+                "performance mode needs a target with device files".to_owned(),
+            ));
+        }
+        self.options.fit_tape(midi_program);
+        let mut codegen = CodeGen::new(self);
+        codegen.compile(midi_program);
+        codegen.verify()
+    }
+
+    /// Runs LLVM's default pipeline for the configured `OptLevel`.
+    pub fn run_passes(&mut self) -> MCompileResult<()> {
+        let level = self.options.opt_level;
+        if level == OptLevel::O0 {
+            return Ok(());
+        }
+        debug!("Running LLVM passes at {:?}", level);
+        self.module
+            .run_passes(&format!("default<{:?}>", level), &self.target_machine)
+            .map_err(MCompileError::LLVMError)
+    }
+
+    /// Returns the textual IR of the module.
+    pub fn print_ir(&self) -> String {
+        self.module.print_to_string()
+    }
+
+    /// Writes the compiled program to `path` in the form asked for by `emit`.
+    pub fn emit(&self, emit: Emit, path: &Path) -> MCompileResult<()> {
+        debug!("Emitting {:?} to {}", emit, path.display());
+        let result = match emit {
+            Emit::LlvmIr => self.module.print_to_file(path),
+            Emit::LlvmBc => self.module.write_bitcode_to_file(path),
+            Emit::Asm => self.target_machine.emit_to_file(&self.module, path, FileType::LLVMAssemblyFile),
+            Emit::Obj => self.target_machine.emit_to_file(&self.module, path, FileType::LLVMObjectFile),
+            Emit::Exe => return link(self, &self.options, path),
+            Emit::C => {
+                return Err(MCompileError::Unsupported(
+                    "C source is translated from the program, see compiler::c::transpile".to_owned(),
+                ))
+            }
+        };
+        result.map_err(MCompileError::LLVMError)
+    }
+
+    /// Returns the compiled program in the form asked for by `emit`, like `emit`
+    /// would write it.
+    pub fn emit_bytes(&self, emit: Emit) -> MCompileResult<Vec<u8>> {
+        debug!("Emitting {:?} to memory", emit);
+        let result = match emit {
+            Emit::LlvmIr => Ok(self.module.print_to_string().into_bytes()),
+            Emit::LlvmBc => Ok(self.module.write_bitcode_to_memory()),
+            Emit::Asm => self.target_machine.emit_to_memory(&self.module, FileType::LLVMAssemblyFile),
+            Emit::Obj => self.target_machine.emit_to_memory(&self.module, FileType::LLVMObjectFile),
+            Emit::Exe => return link_to_memory(self, &self.options),
+            Emit::C => {
+                return Err(MCompileError::Unsupported(
+                    "C source is translated from the program, see compiler::c::transpile".to_owned(),
+                ))
+            }
+        };
+        result.map_err(MCompileError::LLVMError)
+    }
+}
+
+impl Backend for MidiCompiler {
+    fn print_ir(&self) -> String {
+        MidiCompiler::print_ir(self)
+    }
+
+    fn emit(&self, emit: Emit, path: &Path) -> MCompileResult<()> {
+        MidiCompiler::emit(self, emit, path)
+    }
+
+    fn emit_bytes(&self, emit: Emit) -> MCompileResult<Vec<u8>> {
+        MidiCompiler::emit_bytes(self, emit)
+    }
+}
+
+/// Emits a program into the module of a `MidiCompiler`, which it borrows for as
+/// long as it holds on to anything in there.
+struct CodeGen<'m> {
+    module: &'m Module,
+    builder: Builder<'m>,
+    options: &'m CompileOptions,
+    source_map: Option<&'m SourceMap>,
+    triple: &'m str,
+    is_wasi: bool,
+    cell_type: Type<'m>,
+    i32_type: Type<'m>,
+    /// Integer type as wide as a pointer, used for `size_t` and tape indexes
+    size_type: Type<'m>,
+    main_fn: Function<'m>,
+    /// Stack slot holding the start of the tape
+    tape: Value<'m>,
+    /// Stack slot holding the number of allocated cells
+    capacity: Value<'m>,
+    /// Stack slot holding the index of the current cell
+    index: Value<'m>,
+    /// Stack slot holding the index of the cell the pointer started on, which
+    /// moves when an infinite tape grows to the left
+    origin: Value<'m>,
+    /// Global holding the state of the SplitMix64 random bytes come from, if
+    /// the program picks any
+    rng: Option<Value<'m>>,
+    /// LLVM instructions emitted for each MIDI instruction, innermost first
+    positions: Vec<(Value<'m>, Position)>,
+    /// Id of the `POSITION_METADATA` kind
+    position_kind: u32,
+    /// How many values and blocks got each name with a position in it, so repeats
+    /// are numbered after the label instead of by LLVM's running counter
+    label_counts: RefCell<HashMap<String, usize>>,
+    /// Label of the MIDI instruction being compiled, for the blocks it adds
+    current_label: Option<String>,
+    /// Set while emitting debug info, dropping it finalizes the debug info
+    debug_info: Option<DebugInfoBuilder<'m>>,
+    /// Debug info scope of `main`, whose lines are instruction indexes plus one
+    debug_scope: Option<Metadata<'m>>,
+    /// Counts compiled instructions while `compile` runs
+    progress: Progress,
+}
+
+impl<'m> CodeGen<'m> {
+    /// Adds `main` and the stack slots for the tape, leaving the builder at its entry block.
+    fn new(compiler: &'m MidiCompiler) -> Self {
+        let module = &compiler.module;
+        let is_wasi = compiler.triple.starts_with("wasm") && compiler.triple.contains("-wasi");
+        let cell_type = module.int_type(compiler.options.cell_width.bits());
+        let i32_type = module.int_type(32);
+        let size_type = module.int_ptr_type();
+
+        // wasi-libc's start code calls `__main_void` for a `main` without arguments
+        let main_name = if is_wasi { "__main_void" } else { "main" };
+        let main_fn = module.add_function(main_name, i32_type.fn_type(&[]));
+        let builder = module.create_builder();
+        builder.position_at_end(module.append_block(main_fn, "entry"));
+        let tape = builder.alloca(module.ptr_type(cell_type), "tape");
+        let capacity = builder.alloca(size_type, "capacity");
+        let index = builder.alloca(size_type, "index");
+        let origin = builder.alloca(size_type, "origin");
+
+        let optimized = compiler.options.opt_level != OptLevel::O0;
+        let (debug_info, debug_scope) = match &compiler.options.debug_info {
+            Some(listing) => {
+                let debug_info = DebugInfoBuilder::new(module);
+                let file = debug_info.create_compile_unit(listing, "midilang", optimized);
+                let scope = debug_info.create_function(file, main_fn, 1, optimized);
+                (Some(debug_info), Some(scope))
+            }
+            None => (None, None),
+        };
+
+        CodeGen {
+            module,
+            builder,
+            options: &compiler.options,
+            source_map: compiler.source_map.as_ref(),
+            triple: &compiler.triple,
+            is_wasi,
+            cell_type,
+            i32_type,
+            size_type,
+            main_fn,
+            tape,
+            capacity,
+            index,
+            origin,
+            rng: None,
+            positions: vec![],
+            position_kind: module.metadata_kind(POSITION_METADATA),
+            label_counts: RefCell::new(HashMap::new()),
+            current_label: None,
+            debug_info,
+            debug_scope,
+            progress: Progress::default(),
+        }
+    }
+
+    fn cell_ptr_type(&self) -> Type<'m> {
+        self.module.ptr_type(self.cell_type)
+    }
+
+    fn cell_bytes(&self) -> u64 {
+        u64::from(self.options.cell_width.bits() / 8)
+    }
+
+    fn size_const(&self, value: u64) -> Value<'m> {
+        self.size_type.const_int(value, false)
+    }
+
+    fn cell_const(&self, value: u64) -> Value<'m> {
+        self.cell_type.const_int(value, false)
+    }
+
+    /// A function added by one of the `add_*` methods.
+    fn function(&self, name: &str) -> Function<'m> {
+        match self.module.function(name) {
+            Some(func) => func,
+            None => panic!("{} is used before it's declared", name),
+        }
+    }
+
+    /// Declares the libc functions the generated code calls into.
+    ///
+    /// On WASI `read` and `write` are defined on top of the WASI imports instead.
+    fn add_c_declarations(&self) {
+        if self.is_wasi {
+            self.add_wasi_io();
+        }
+        let module = self.module;
+        let (i32_type, size_type) = (self.i32_type, self.size_type);
+        let void_type = module.void_type();
+        let cell_ptr_type = self.cell_ptr_type();
+
+        if !self.is_wasi {
+            let byte_ptr_type = module.ptr_type(module.int_type(8));
+            let io_type = size_type.fn_type(&[i32_type, byte_ptr_type, size_type]);
+            module.add_function("read", io_type);
+            module.add_function("write", io_type);
+        }
+        module.add_function("calloc", cell_ptr_type.fn_type(&[size_type, size_type]));
+        module.add_function("free", void_type.fn_type(&[cell_ptr_type]));
+        module.add_function("abort", void_type.fn_type(&[]));
+        module.add_function("exit", void_type.fn_type(&[i32_type]));
+        module.add_function("realloc", cell_ptr_type.fn_type(&[cell_ptr_type, size_type]));
+        module.add_function("memset", cell_ptr_type.fn_type(&[cell_ptr_type, i32_type, size_type]));
+        if !self.options.data.is_empty() {
+            module.add_function("memcpy", cell_ptr_type.fn_type(&[cell_ptr_type, cell_ptr_type, size_type]));
+        }
+        if self.options.tape_mode == TapeMode::Infinite {
+            module.add_function("memmove", cell_ptr_type.fn_type(&[cell_ptr_type, cell_ptr_type, size_type]));
+        }
+        if self.options.stdin.is_some() || self.options.stdout.is_some() {
+            // FILE * as a byte pointer
+            let byte_ptr_type = module.ptr_type(module.int_type(8));
+            module.add_function("fopen", byte_ptr_type.fn_type(&[byte_ptr_type, byte_ptr_type]));
+            module.add_function("fileno", i32_type.fn_type(&[byte_ptr_type]));
+            module.add_function("dup2", i32_type.fn_type(&[i32_type, i32_type]));
+            module.add_function("perror", void_type.fn_type(&[byte_ptr_type]));
+        }
+        if self.options.perform.is_some() {
+            let byte_ptr_type = module.ptr_type(module.int_type(8));
+            module.add_function("getenv", byte_ptr_type.fn_type(&[byte_ptr_type]));
+            module.add_function("open", i32_type.var_arg_fn_type(&[byte_ptr_type, i32_type]));
+            module.add_function("usleep", i32_type.fn_type(&[i32_type]));
+        }
+    }
+
+    /// Defines internal `read` and `write` functions with the usual POSIX signatures
+    /// on top of the `fd_read` and `fd_write` WASI imports, so the rest of the
+    /// runtime doesn't care which one it's running on.
+    fn add_wasi_io(&self) {
+        let module = self.module;
+        let (i32_type, size_type) = (self.i32_type, self.size_type);
+        let byte_ptr_type = module.ptr_type(module.int_type(8));
+        let iovec_type = module.struct_type(&[byte_ptr_type, size_type]);
+
+        // both imports are (fd, iovs, iovs_len, out count) -> errno
+        let import_type =
+            i32_type.fn_type(&[i32_type, module.ptr_type(iovec_type), size_type, module.ptr_type(size_type)]);
+        let posix_type = size_type.fn_type(&[i32_type, byte_ptr_type, size_type]);
+
+        let builder = module.create_builder();
+        for (import_name, posix_name, symbol) in [
+            ("fd_read", "read", "midilang_wasi_fd_read"),
+            ("fd_write", "write", "midilang_wasi_fd_write"),
+        ] {
+            let import = module.add_function(symbol, import_type);
+            module.add_function_attribute(import, "wasm-import-module", "wasi_snapshot_preview1");
+            module.add_function_attribute(import, "wasm-import-name", import_name);
+
+            let func = module.add_function(posix_name, posix_type);
+            func.set_linkage(Linkage::LLVMInternalLinkage);
+            builder.position_at_end(module.append_block(func, "entry"));
+            let iovec = builder.alloca(iovec_type, "iovec");
+            let count = builder.alloca(size_type, "count");
+            builder.store(func.param(1), builder.struct_gep(iovec_type, iovec, 0, "buf"));
+            builder.store(func.param(2), builder.struct_gep(iovec_type, iovec, 1, "len"));
+            let args = [func.param(0), iovec, self.size_const(1), count];
+            let errno = builder.call(import, &args, "errno");
+            let failed = builder.icmp(IntPredicate::LLVMIntNE, errno, i32_type.const_int(0, false), "failed");
+            let done = builder.load(size_type, count, "done");
+            let result = builder.select(failed, size_type.const_all_ones(), done, "result");
+            builder.ret(result);
+        }
+    }
+
+    /// Records where the program came from as module metadata, and as the constant
+    /// `midilang_provenance` in its own section so it survives into the binary.
+    /// The source MIDI file goes in a section too when it's embedded.
+    fn add_provenance(&self) {
+        if let Some(provenance) = &self.options.provenance {
+            self.module.add_named_string_metadata(PROVENANCE_METADATA, &provenance.fields());
+            self.add_section_data("midilang_provenance", "midilang", &provenance.to_bytes());
+        }
+        if let Some(smf) = &self.options.embedded_source {
+            self.add_section_data("midilang_source", "midilang.src", &embedded_source(smf));
+        }
+    }
+
+    /// Adds a constant global `name` holding `bytes` in the object file section `section`.
+    fn add_section_data(&self, name: &str, section: &str, bytes: &[u8]) {
+        let module = self.module;
+        let global = module.add_global(module.int_type(8).array_type(bytes.len() as u32), name);
+        global.set_initializer(module.const_bytes(bytes));
+        global.set_constant(true);
+        global.set_section(&object_section(self.triple, section));
+    }
+
+    /// Adds `midilang_perform(i8 note)`, which plays `note` for `note_ms` milliseconds
+    /// on the MIDI device, opening it on first use, or beeps when there's no device.
+    ///
+    /// Stdout is flushed first so output shows up along with the note for it.
+    fn add_perform_runtime(&self, note_ms: u32) {
+        let module = self.module;
+        let i8_type = module.int_type(8);
+        let i32_type = self.i32_type;
+        let message_type = i8_type.array_type(3);
+        // -2 until the device is opened, -1 if that failed
+        let unopened = i32_type.const_int(-2i64 as u64, true);
+        let fd_global = module.add_global(i32_type, "midilang_midi_fd");
+        fd_global.set_linkage(Linkage::LLVMInternalLinkage);
+        fd_global.set_initializer(unopened);
+        let message = module.add_global(message_type, "midilang_midi_message");
+        message.set_linkage(Linkage::LLVMInternalLinkage);
+
+        let perform_fn = module.add_function("midilang_perform", module.void_type().fn_type(&[i8_type]));
+        perform_fn.set_linkage(Linkage::LLVMInternalLinkage);
+        let builder = module.create_builder();
+        let entry = module.append_block(perform_fn, "entry");
+        let open = module.append_block(perform_fn, "open");
+        let play = module.append_block(perform_fn, "play");
+        let midi = module.append_block(perform_fn, "midi");
+        let beep = module.append_block(perform_fn, "beep");
+        let wait = |builder: &Builder<'m>| {
+            let micros = i32_type.const_int(u64::from(note_ms.saturating_mul(1000)), false);
+            builder.call(self.function("usleep"), &[micros], "");
+        };
+
+        builder.position_at_end(entry);
+        builder.call(self.function("midilang_flush"), &[], "");
+        let fd = builder.load(i32_type, fd_global, "fd");
+        let is_unopened = builder.icmp(IntPredicate::LLVMIntEQ, fd, unopened, "unopened");
+        builder.cond_br(is_unopened, open, play);
+
+        builder.position_at_end(open);
+        let var = builder.global_string_ptr(PERFORM_DEVICE_VAR, "device_var");
+        let env = builder.call(self.function("getenv"), &[var], "env");
+        let unset = builder.is_null(env, "unset");
+        let default_device = builder.global_string_ptr(PERFORM_DEVICE, "default_device");
+        let path = builder.select(unset, default_device, env, "path");
+        // O_WRONLY
+        let opened = builder.call(self.function("open"), &[path, i32_type.const_int(1, false)], "opened");
+        builder.store(opened, fd_global);
+        builder.br(play);
+
+        builder.position_at_end(play);
+        let device = builder.phi(i32_type, "device");
+        device.add_incoming(&[(fd, entry), (opened, open)]);
+        let has_device = builder.icmp(IntPredicate::LLVMIntSGE, device, i32_type.const_int(0, false), "has_device");
+        builder.cond_br(has_device, midi, beep);
+
+        builder.position_at_end(midi);
+        let byte_at = |idx: u64, name: &str| builder.gep(message_type, message, &[self.size_const(0), self.size_const(idx)], name);
+        let send = |status: u64, velocity: u64| {
+            builder.store(i8_type.const_int(status, false), byte_at(0, "status"));
+            builder.store(perform_fn.param(0), byte_at(1, "key"));
+            builder.store(i8_type.const_int(velocity, false), byte_at(2, "velocity"));
+            builder.call(self.function("write"), &[device, byte_at(0, "start"), self.size_const(3)], "");
+        };
+        // note on, then note off, on channel 1
+        send(0x90, 100);
+        wait(&builder);
+        send(0x80, 0);
+        builder.ret_void();
+
+        builder.position_at_end(beep);
+        let bell = builder.global_string_ptr("\x07", "bell");
+        builder.call(self.function("write"), &[i32_type.const_int(2, false), bell, self.size_const(1)], "");
+        wait(&builder);
+        builder.ret_void();
+    }
+
+    /// Plays the note `degree` semitones above middle C in performance mode.
+    fn perform(&self, degree: u8) {
+        if self.options.perform.is_some() {
+            let note = self.module.int_type(8).const_int(u64::from(MIDDLE_C + degree), false);
+            self.builder.call(self.function("midilang_perform"), &[note], "");
+        }
+    }
+
+    /// Allocates a zeroed tape of `num_cells` cells with the pointer on the first one.
+    fn allocate_cells(&self, num_cells: u64) {
+        let args = [self.size_const(num_cells), self.size_const(self.cell_bytes())];
+        let cells = self.builder.call(self.function("calloc"), &args, "cells");
+        self.builder.store(cells, self.tape);
+        self.builder.store(self.size_const(num_cells), self.capacity);
+        self.builder.store(self.size_const(0), self.index);
+        self.builder.store(self.size_const(0), self.origin);
+    }
+
+    /// Points the descriptors `read` and `write` use for stdin and stdout at the
+    /// files in the options, returning 1 from `main` when one can't be opened.
+    fn redirect_stdio(&self) {
+        let builder = &self.builder;
+        let redirects = [(&self.options.stdin, "rb", 0), (&self.options.stdout, "wb", 1)];
+        for (path, mode, fd) in redirects {
+            let Some(path) = path else {
+                continue;
+            };
+            let path = builder.global_string_ptr(&path.to_string_lossy(), "redirect_path");
+            let mode = builder.global_string_ptr(mode, "redirect_mode");
+            let file = builder.call(self.function("fopen"), &[path, mode], "redirect_file");
+            let failed_block = self.append_block("unopened");
+            let opened_block = self.append_block("opened");
+            builder.cond_br(builder.is_null(file, "failed"), failed_block, opened_block);
+
+            builder.position_at_end(failed_block);
+            builder.call(self.function("perror"), &[path], "");
+            builder.ret(self.i32_type.const_int(1, false));
+
+            builder.position_at_end(opened_block);
+            let opened = builder.call(self.function("fileno"), &[file], "opened_fd");
+            builder.call(self.function("dup2"), &[opened, self.i32_type.const_int(fd, false)], "");
+        }
+    }
+
+    /// Copies the cells of the data track to the start of the tape, out of the
+    /// constant `midilang_data`.
+    fn load_data(&self) {
+        let data = &self.options.data;
+        if data.is_empty() {
+            return;
+        }
+        let values: Vec<_> = data.iter().map(|&byte| self.cell_const(u64::from(byte))).collect();
+        let data_type = self.cell_type.array_type(data.len() as u32);
+        let global = self.module.add_global(data_type, "midilang_data");
+        global.set_initializer(self.cell_type.const_array(&values));
+        global.set_constant(true);
+        global.set_linkage(Linkage::LLVMPrivateLinkage);
+        let zero = self.size_const(0);
+        let from = self.builder.gep(data_type, global, &[zero, zero], "data");
+        let cells = self.builder.load(self.cell_ptr_type(), self.tape, "cells");
+        let size = self.size_const(data.len() as u64 * self.cell_bytes());
+        self.builder.call(self.function("memcpy"), &[cells, from, size], "");
+    }
+
+    /// Adds `midilang_rng`, seeded with the seed in the options or with the
+    /// clock at startup, when `midi_program` picks random bytes.
+    fn seed_random(&mut self, midi_program: &MidiAST) {
+        if !plays(midi_program, &Random) {
+            return;
+        }
+        let i64_type = self.module.int_type(64);
+        let global = self.module.add_global(i64_type, "midilang_rng");
+        global.set_initializer(i64_type.const_int(self.options.seed.unwrap_or(0), false));
+        global.set_linkage(Linkage::LLVMPrivateLinkage);
+        if self.options.seed.is_none() {
+            let byte_ptr_type = self.module.ptr_type(self.module.int_type(8));
+            let time = self.module.add_function("time", i64_type.fn_type(&[byte_ptr_type]));
+            let now = self.builder.call(time, &[byte_ptr_type.const_null()], "now");
+            self.builder.store(now, global);
+        }
+        self.rng = Some(global);
+    }
+
+    /// Adds the buffered IO runtime used instead of calling `putchar`/`getchar`
+    /// for every byte.
+    ///
+    /// - `midilang_flush()` writes out everything buffered for stdout
+    /// - `midilang_putc(i8)` buffers a byte for stdout, flushing when the buffer is full
+    /// - `midilang_getc() -> i32` flushes stdout, then returns the next byte of
+    ///   stdin, refilling its buffer as needed, or -1 at EOF
+    fn add_io_runtime(&self) {
+        let module = self.module;
+        let i8_type = module.int_type(8);
+        let size_type = self.size_type;
+        let void_type = module.void_type();
+        let buf_type = i8_type.array_type(IO_BUFFER_SIZE as u32);
+
+        let add_global = |ty: Type<'m>, name: &str| {
+            let global = module.add_global(ty, name);
+            global.set_linkage(Linkage::LLVMInternalLinkage);
+            global
+        };
+        let out_buf = add_global(buf_type, "midilang_out_buf");
+        let out_len = add_global(size_type, "midilang_out_len");
+        let in_buf = add_global(buf_type, "midilang_in_buf");
+        let in_len = add_global(size_type, "midilang_in_len");
+        let in_pos = add_global(size_type, "midilang_in_pos");
+
+        let add_function = |name: &str, fn_type: Type<'m>| {
+            let func = module.add_function(name, fn_type);
+            func.set_linkage(Linkage::LLVMInternalLinkage);
+            func
+        };
+        let flush_fn = add_function("midilang_flush", void_type.fn_type(&[]));
+        let putc_fn = add_function("midilang_putc", void_type.fn_type(&[i8_type]));
+        let getc_fn = add_function("midilang_getc", self.i32_type.fn_type(&[]));
+
+        let builder = module.create_builder();
+        let buf_at = |buf: Value<'m>, idx: Value<'m>, name: &str| builder.gep(buf_type, buf, &[self.size_const(0), idx], name);
+
+        // midilang_flush, retrying short writes until everything is out or write fails
+        let entry = module.append_block(flush_fn, "entry");
+        let check = module.append_block(flush_fn, "check");
+        let write = module.append_block(flush_fn, "write");
+        let done = module.append_block(flush_fn, "done");
+        builder.position_at_end(entry);
+        let len = builder.load(size_type, out_len, "len");
+        builder.br(check);
+
+        builder.position_at_end(check);
+        let written = builder.phi(size_type, "written");
+        let finished = builder.icmp(IntPredicate::LLVMIntSGE, written, len, "finished");
+        builder.cond_br(finished, done, write);
+
+        builder.position_at_end(write);
+        let write_args = [
+            self.i32_type.const_int(1, false),
+            buf_at(out_buf, written, "start"),
+            builder.sub(len, written, "remaining"),
+        ];
+        let count = builder.call(self.function("write"), &write_args, "count");
+        let failed = builder.icmp(IntPredicate::LLVMIntSLE, count, self.size_const(0), "failed");
+        let next = builder.add(written, count, "next");
+        builder.cond_br(failed, done, check);
+
+        written.add_incoming(&[(self.size_const(0), entry), (next, write)]);
+
+        builder.position_at_end(done);
+        builder.store(self.size_const(0), out_len);
+        builder.ret_void();
+
+        // midilang_putc
+        let entry = module.append_block(putc_fn, "entry");
+        let flush = module.append_block(putc_fn, "flush");
+        let store = module.append_block(putc_fn, "store");
+        builder.position_at_end(entry);
+        let len = builder.load(size_type, out_len, "len");
+        let full = builder.icmp(IntPredicate::LLVMIntUGE, len, self.size_const(IO_BUFFER_SIZE), "full");
+        builder.cond_br(full, flush, store);
+
+        builder.position_at_end(flush);
+        builder.call(flush_fn, &[], "");
+        builder.br(store);
+
+        builder.position_at_end(store);
+        let len = builder.load(size_type, out_len, "len_after_flush");
+        builder.store(putc_fn.param(0), buf_at(out_buf, len, "slot"));
+        builder.store(builder.add(len, self.size_const(1), "new_len"), out_len);
+        builder.ret_void();
+
+        // midilang_getc, flushing first so prompts show up before we block on input
+        let entry = module.append_block(getc_fn, "entry");
+        let refill = module.append_block(getc_fn, "refill");
+        let eof = module.append_block(getc_fn, "eof");
+        let refilled = module.append_block(getc_fn, "refilled");
+        let take = module.append_block(getc_fn, "take");
+        builder.position_at_end(entry);
+        builder.call(flush_fn, &[], "");
+        let pos = builder.load(size_type, in_pos, "pos");
+        let len = builder.load(size_type, in_len, "len");
+        let empty = builder.icmp(IntPredicate::LLVMIntUGE, pos, len, "empty");
+        builder.cond_br(empty, refill, take);
+
+        builder.position_at_end(refill);
+        let read_args = [
+            self.i32_type.const_int(0, false),
+            buf_at(in_buf, self.size_const(0), "start"),
+            self.size_const(IO_BUFFER_SIZE),
+        ];
+        let count = builder.call(self.function("read"), &read_args, "count");
+        let at_end = builder.icmp(IntPredicate::LLVMIntSLE, count, self.size_const(0), "at_end");
+        builder.cond_br(at_end, eof, refilled);
+
+        builder.position_at_end(eof);
+        builder.ret(self.i32_type.const_int(u64::MAX, true));
+
+        builder.position_at_end(refilled);
+        builder.store(count, in_len);
+        builder.store(self.size_const(0), in_pos);
+        builder.br(take);
+
+        builder.position_at_end(take);
+        let pos = builder.load(size_type, in_pos, "pos_after_refill");
+        let byte = builder.load(i8_type, buf_at(in_buf, pos, "slot"), "byte");
+        builder.store(builder.add(pos, self.size_const(1), "new_pos"), in_pos);
+        builder.ret(builder.zext(byte, self.i32_type, "result"));
+    }
+
+    /// Adds the decimal runtime of `IoMode::Numeric`, on top of the buffered IO one.
+    ///
+    /// - `midilang_put_number(i64)` buffers a number in decimal and a newline
+    /// - `midilang_read_number() -> i64` reads the next decimal number, negative
+    ///   after a '-', skipping whatever isn't one, 0 at EOF
+    fn add_numeric_runtime(&self) {
+        let module = self.module;
+        let i8_type = module.int_type(8);
+        let i64_type = module.int_type(64);
+        let void_type = module.void_type();
+        let digits_type = i8_type.array_type(20);
+        let number = |value: u64| i64_type.const_int(value, false);
+
+        let add_function = |name: &str, fn_type: Type<'m>| {
+            let func = module.add_function(name, fn_type);
+            func.set_linkage(Linkage::LLVMInternalLinkage);
+            func
+        };
+        let put_fn = add_function("midilang_put_number", void_type.fn_type(&[i64_type]));
+        let read_fn = add_function("midilang_read_number", i64_type.fn_type(&[]));
+        let putc_fn = self.function("midilang_putc");
+        let getc_fn = self.function("midilang_getc");
+
+        let builder = module.create_builder();
+
+        // midilang_put_number, gathering the digits backwards first
+        let entry = module.append_block(put_fn, "entry");
+        let digit = module.append_block(put_fn, "digit");
+        let emit = module.append_block(put_fn, "emit");
+        let emit_digit = module.append_block(put_fn, "emit_digit");
+        let done = module.append_block(put_fn, "done");
+        builder.position_at_end(entry);
+        let digits = builder.alloca(digits_type, "digits");
+        let digits_at = |idx: Value<'m>| builder.gep(digits_type, digits, &[self.size_const(0), idx], "slot");
+        builder.br(digit);
+
+        builder.position_at_end(digit);
+        let value = builder.phi(i64_type, "value");
+        let count = builder.phi(self.size_type, "count");
+        let low = builder.int_cast(builder.urem(value, number(10), "low"), i8_type, "low");
+        builder.store(builder.add(low, i8_type.const_int(u64::from(b'0'), false), "ascii"), digits_at(count));
+        let rest = builder.udiv(value, number(10), "rest");
+        let more = builder.add(count, self.size_const(1), "more");
+        let finished = builder.icmp(IntPredicate::LLVMIntEQ, rest, number(0), "finished");
+        builder.cond_br(finished, emit, digit);
+        value.add_incoming(&[(put_fn.param(0), entry), (rest, digit)]);
+        count.add_incoming(&[(self.size_const(0), entry), (more, digit)]);
+
+        builder.position_at_end(emit);
+        let left = builder.phi(self.size_type, "left");
+        let empty = builder.icmp(IntPredicate::LLVMIntEQ, left, self.size_const(0), "empty");
+        builder.cond_br(empty, done, emit_digit);
+
+        builder.position_at_end(emit_digit);
+        let fewer = builder.sub(left, self.size_const(1), "fewer");
+        builder.call(putc_fn, &[builder.load(i8_type, digits_at(fewer), "ascii")], "");
+        builder.br(emit);
+        left.add_incoming(&[(more, digit), (fewer, emit_digit)]);
+
+        builder.position_at_end(done);
+        builder.call(putc_fn, &[i8_type.const_int(u64::from(b'\n'), false)], "");
+        builder.ret_void();
+
+        // midilang_read_number
+        let entry = module.append_block(read_fn, "entry");
+        let skip = module.append_block(read_fn, "skip");
+        let skip_more = module.append_block(read_fn, "skip_more");
+        let digit = module.append_block(read_fn, "digit");
+        let finish = module.append_block(read_fn, "finish");
+        let is_digit = |read: Value<'m>| {
+            let offset = builder.sub(read, self.i32_type.const_int(u64::from(b'0'), false), "offset");
+            builder.icmp(IntPredicate::LLVMIntULT, offset, self.i32_type.const_int(10, false), "is_digit")
+        };
+        builder.position_at_end(entry);
+        builder.br(skip);
+
+        builder.position_at_end(skip);
+        let negative = builder.phi(module.bool_type(), "negative");
+        let read = builder.call(getc_fn, &[], "read");
+        builder.cond_br(is_digit(read), digit, skip_more);
+
+        builder.position_at_end(skip_more);
+        let eof = builder.icmp(IntPredicate::LLVMIntEQ, read, self.i32_type.const_int(u64::MAX, true), "eof");
+        let minus = builder.icmp(IntPredicate::LLVMIntEQ, read, self.i32_type.const_int(u64::from(b'-'), false), "minus");
+        builder.cond_br(eof, finish, skip);
+        negative.add_incoming(&[(module.bool_type().const_int(0, false), entry), (minus, skip_more)]);
+
+        builder.position_at_end(digit);
+        let value = builder.phi(i64_type, "value");
+        let current = builder.phi(self.i32_type, "current");
+        let offset = builder.sub(current, self.i32_type.const_int(u64::from(b'0'), false), "offset");
+        let shifted = builder.mul(value, number(10), "shifted");
+        let next_value = builder.add(shifted, builder.zext(offset, i64_type, "digit_value"), "next_value");
+        let next = builder.call(getc_fn, &[], "next");
+        builder.cond_br(is_digit(next), digit, finish);
+        value.add_incoming(&[(number(0), skip), (next_value, digit)]);
+        current.add_incoming(&[(read, skip), (next, digit)]);
+
+        builder.position_at_end(finish);
+        let result = builder.phi(i64_type, "result");
+        result.add_incoming(&[(number(0), skip_more), (next_value, digit)]);
+        let negated = builder.sub(number(0), result, "negated");
+        builder.ret(builder.select(negative, negated, result, "signed"));
+    }
+
+    /// Adds the UTF-8 runtime of `IoMode::Utf8`, on top of the buffered IO one.
+    ///
+    /// - `midilang_utf8_lead(i8, i8*, i8*) -> i64` returns how many continuation
+    ///   bytes follow a byte and stores the range the first has to be in, -1 when
+    ///   no sequence starts with it
+    /// - `midilang_put_replacement()` buffers U+FFFD
+    /// - `midilang_put_utf8(i8)` holds bytes back until they make up a whole
+    ///   sequence, buffering U+FFFD for the ones that aren't valid
+    /// - `midilang_finish_utf8()` buffers U+FFFD for a sequence never finished
+    /// - `midilang_read_utf8(cells, capacity, index)` reads a code point into the
+    ///   cell at `index` and the ones after it on the tape, a cell a byte, U+FFFD
+    ///   when it isn't valid and 0 at EOF
+    fn add_utf8_runtime(&self) {
+        let module = self.module;
+        let i8_type = module.int_type(8);
+        let i8_ptr_type = module.ptr_type(i8_type);
+        let size_type = self.size_type;
+        let void_type = module.void_type();
+        let pending_type = i8_type.array_type(4);
+        let byte = |value: u64| i8_type.const_int(value, false);
+
+        let add_global = |ty: Type<'m>, name: &str| {
+            let global = module.add_global(ty, name);
+            global.set_linkage(Linkage::LLVMInternalLinkage);
+            global
+        };
+        let pending = add_global(pending_type, "midilang_utf8_pending");
+        let pending_len = add_global(size_type, "midilang_utf8_len");
+        let pending_need = add_global(size_type, "midilang_utf8_need");
+        let next_lo = add_global(i8_type, "midilang_utf8_lo");
+        let next_hi = add_global(i8_type, "midilang_utf8_hi");
+
+        let add_function = |name: &str, fn_type: Type<'m>| {
+            let func = module.add_function(name, fn_type);
+            func.set_linkage(Linkage::LLVMInternalLinkage);
+            func
+        };
+        let lead_fn = add_function("midilang_utf8_lead", size_type.fn_type(&[i8_type, i8_ptr_type, i8_ptr_type]));
+        let replacement_fn = add_function("midilang_put_replacement", void_type.fn_type(&[]));
+        let put_fn = add_function("midilang_put_utf8", void_type.fn_type(&[i8_type]));
+        let finish_fn = add_function("midilang_finish_utf8", void_type.fn_type(&[]));
+        let read_params = [self.cell_ptr_type(), size_type, size_type];
+        let read_fn = add_function("midilang_read_utf8", void_type.fn_type(&read_params));
+        let putc_fn = self.function("midilang_putc");
+        let getc_fn = self.function("midilang_getc");
+
+        let builder = module.create_builder();
+        let is = |value: Value<'m>, expected: u64| builder.icmp(IntPredicate::LLVMIntEQ, value, byte(expected), "is");
+        let in_range = |value: Value<'m>, lo: Value<'m>, hi: Value<'m>| {
+            let above = builder.icmp(IntPredicate::LLVMIntUGE, value, lo, "above");
+            let below = builder.icmp(IntPredicate::LLVMIntULE, value, hi, "below");
+            builder.and(above, below, "in_range")
+        };
+        let pending_at = |idx: Value<'m>| builder.gep(pending_type, pending, &[self.size_const(0), idx], "slot");
+
+        // midilang_utf8_lead, all selects
+        let entry = module.append_block(lead_fn, "entry");
+        builder.position_at_end(entry);
+        let lead = lead_fn.param(0);
+        let lo = builder.select(is(lead, 0xe0), byte(0xa0), builder.select(is(lead, 0xf0), byte(0x90), byte(0x80), "lo"), "lo");
+        let hi = builder.select(is(lead, 0xed), byte(0x9f), builder.select(is(lead, 0xf4), byte(0x8f), byte(0xbf), "hi"), "hi");
+        builder.store(lo, lead_fn.param(1));
+        builder.store(hi, lead_fn.param(2));
+        let at_least = |min: u64| builder.icmp(IntPredicate::LLVMIntUGE, lead, byte(min), "at_least");
+        let longer = builder.select(at_least(0xe0), self.size_const(2), self.size_const(1), "longer");
+        let need = builder.select(at_least(0xf0), self.size_const(3), longer, "need");
+        let valid = in_range(lead, byte(0xc2), byte(0xf4));
+        let need = builder.select(valid, need, self.size_type.const_int(u64::MAX, true), "need");
+        let need = builder.select(at_least(0x80), need, self.size_const(0), "need");
+        builder.ret(need);
+
+        // midilang_put_replacement
+        let entry = module.append_block(replacement_fn, "entry");
+        builder.position_at_end(entry);
+        for value in midilang_core::io::REPLACEMENT {
+            builder.call(putc_fn, &[byte(u64::from(value))], "");
+        }
+        builder.ret_void();
+
+        // midilang_put_utf8
+        let entry = module.append_block(put_fn, "entry");
+        let waiting = module.append_block(put_fn, "waiting");
+        let append = module.append_block(put_fn, "append");
+        let emit = module.append_block(put_fn, "emit");
+        let emit_byte = module.append_block(put_fn, "emit_byte");
+        let broken = module.append_block(put_fn, "broken");
+        let start = module.append_block(put_fn, "start");
+        let single = module.append_block(put_fn, "single");
+        let multi = module.append_block(put_fn, "multi");
+        let invalid = module.append_block(put_fn, "invalid");
+        let first = module.append_block(put_fn, "first");
+        let done = module.append_block(put_fn, "done");
+        let value = put_fn.param(0);
+        builder.position_at_end(entry);
+        let need = builder.load(size_type, pending_need, "need");
+        let is_waiting = builder.icmp(IntPredicate::LLVMIntNE, need, self.size_const(0), "is_waiting");
+        builder.cond_br(is_waiting, waiting, start);
+
+        builder.position_at_end(waiting);
+        let lo = builder.load(i8_type, next_lo, "lo");
+        let hi = builder.load(i8_type, next_hi, "hi");
+        builder.cond_br(in_range(value, lo, hi), append, broken);
+
+        builder.position_at_end(append);
+        let len = builder.load(size_type, pending_len, "len");
+        builder.store(value, pending_at(len));
+        let len = builder.add(len, self.size_const(1), "new_len");
+        builder.store(len, pending_len);
+        builder.store(byte(0x80), next_lo);
+        builder.store(byte(0xbf), next_hi);
+        let need = builder.sub(need, self.size_const(1), "new_need");
+        builder.store(need, pending_need);
+        let whole = builder.icmp(IntPredicate::LLVMIntEQ, need, self.size_const(0), "whole");
+        builder.cond_br(whole, emit, done);
+
+        builder.position_at_end(emit);
+        let written = builder.phi(size_type, "written");
+        let finished = builder.icmp(IntPredicate::LLVMIntUGE, written, len, "finished");
+        builder.cond_br(finished, done, emit_byte);
+
+        builder.position_at_end(emit_byte);
+        builder.call(putc_fn, &[builder.load(i8_type, pending_at(written), "byte")], "");
+        let next = builder.add(written, self.size_const(1), "next");
+        builder.br(emit);
+        written.add_incoming(&[(self.size_const(0), append), (next, emit_byte)]);
+
+        // cut short, the byte might start the next one
+        builder.position_at_end(broken);
+        builder.store(self.size_const(0), pending_need);
+        builder.call(replacement_fn, &[], "");
+        builder.br(start);
+
+        builder.position_at_end(start);
+        let need = builder.call(lead_fn, &[value, next_lo, next_hi], "need");
+        let is_single = builder.icmp(IntPredicate::LLVMIntEQ, need, self.size_const(0), "is_single");
+        builder.cond_br(is_single, single, multi);
+
+        builder.position_at_end(single);
+        builder.call(putc_fn, &[value], "");
+        builder.br(done);
+
+        builder.position_at_end(multi);
+        let is_invalid = builder.icmp(IntPredicate::LLVMIntSLT, need, self.size_const(0), "is_invalid");
+        builder.cond_br(is_invalid, invalid, first);
+
+        builder.position_at_end(invalid);
+        builder.call(replacement_fn, &[], "");
+        builder.br(done);
+
+        builder.position_at_end(first);
+        builder.store(value, pending_at(self.size_const(0)));
+        builder.store(self.size_const(1), pending_len);
+        builder.store(need, pending_need);
+        builder.br(done);
+
+        builder.position_at_end(done);
+        builder.ret_void();
+
+        // midilang_finish_utf8
+        let entry = module.append_block(finish_fn, "entry");
+        let unfinished = module.append_block(finish_fn, "unfinished");
+        let done = module.append_block(finish_fn, "done");
+        builder.position_at_end(entry);
+        let need = builder.load(size_type, pending_need, "need");
+        let is_waiting = builder.icmp(IntPredicate::LLVMIntNE, need, self.size_const(0), "is_waiting");
+        builder.cond_br(is_waiting, unfinished, done);
+
+        builder.position_at_end(unfinished);
+        builder.store(self.size_const(0), pending_need);
+        builder.call(replacement_fn, &[], "");
+        builder.br(done);
+
+        builder.position_at_end(done);
+        builder.ret_void();
+
+        // midilang_read_utf8, gathering the sequence in `bytes` first
+        let entry = module.append_block(read_fn, "entry");
+        let check = module.append_block(read_fn, "check");
+        let more = module.append_block(read_fn, "more");
+        let next = module.append_block(read_fn, "next");
+        let replace = module.append_block(read_fn, "replace");
+        let copy = module.append_block(read_fn, "copy");
+        let copy_byte = module.append_block(read_fn, "copy_byte");
+        let done = module.append_block(read_fn, "done");
+        let (cells, capacity, index) = (read_fn.param(0), read_fn.param(1), read_fn.param(2));
+        builder.position_at_end(entry);
+        let bytes = builder.alloca(pending_type, "bytes");
+        let (lo, hi) = (builder.alloca(i8_type, "lo"), builder.alloca(i8_type, "hi"));
+        let len = builder.alloca(size_type, "len");
+        let bytes_at = |idx: Value<'m>| builder.gep(pending_type, bytes, &[self.size_const(0), idx], "slot");
+        let eof_code = self.i32_type.const_int(u64::MAX, true);
+        let read = builder.call(getc_fn, &[], "read");
+        let eof = builder.icmp(IntPredicate::LLVMIntEQ, read, eof_code, "eof");
+        let lead = builder.int_cast(read, i8_type, "lead");
+        // EOF reads as a single 0
+        builder.store(builder.select(eof, byte(0), lead, "first"), bytes_at(self.size_const(0)));
+        builder.store(self.size_const(1), len);
+        let need = builder.call(lead_fn, &[lead, lo, hi], "need");
+        let need = builder.select(eof, self.size_const(0), need, "need");
+        let is_invalid = builder.icmp(IntPredicate::LLVMIntSLT, need, self.size_const(0), "is_invalid");
+        builder.cond_br(is_invalid, replace, check);
+
+        builder.position_at_end(check);
+        let left = builder.phi(size_type, "left");
+        let finished = builder.icmp(IntPredicate::LLVMIntEQ, left, self.size_const(0), "finished");
+        builder.cond_br(finished, copy, more);
+
+        builder.position_at_end(more);
+        let read = builder.call(getc_fn, &[], "read");
+        let continuation = builder.int_cast(read, i8_type, "continuation");
+        let fits = in_range(continuation, builder.load(i8_type, lo, "lo"), builder.load(i8_type, hi, "hi"));
+        let eof = builder.icmp(IntPredicate::LLVMIntEQ, read, eof_code, "eof");
+        let not_eof = builder.xor(eof, self.module.bool_type().const_int(1, false), "not_eof");
+        builder.cond_br(builder.and(fits, not_eof, "fits"), next, replace);
+
+        builder.position_at_end(next);
+        let taken = builder.load(size_type, len, "taken");
+        builder.store(continuation, bytes_at(taken));
+        builder.store(builder.add(taken, self.size_const(1), "new_len"), len);
+        builder.store(byte(0x80), lo);
+        builder.store(byte(0xbf), hi);
+        let fewer = builder.sub(left, self.size_const(1), "fewer");
+        builder.br(check);
+        left.add_incoming(&[(need, entry), (fewer, next)]);
+
+        builder.position_at_end(replace);
+        for (idx, value) in midilang_core::io::REPLACEMENT.into_iter().enumerate() {
+            builder.store(byte(u64::from(value)), bytes_at(self.size_const(idx as u64)));
+        }
+        builder.store(self.size_const(3), len);
+        builder.br(copy);
+
+        // as many bytes as fit on the tape
+        builder.position_at_end(copy);
+        let copied = builder.phi(size_type, "copied");
+        let count = builder.load(size_type, len, "count");
+        let cell_index = builder.add(index, copied, "cell_index");
+        let all = builder.icmp(IntPredicate::LLVMIntUGE, copied, count, "all");
+        let off_tape = builder.icmp(IntPredicate::LLVMIntUGE, cell_index, capacity, "off_tape");
+        builder.cond_br(builder.or(all, off_tape, "stop"), done, copy_byte);
+
+        builder.position_at_end(copy_byte);
+        let value = builder.load(i8_type, bytes_at(copied), "byte");
+        let cell = builder.int_cast(value, self.cell_type, "cell");
+        builder.store(cell, builder.gep(self.cell_type, cells, &[cell_index], "cell_addr"));
+        let next_copied = builder.add(copied, self.size_const(1), "next_copied");
+        builder.br(copy);
+        copied.add_incoming(&[(self.size_const(0), check), (self.size_const(0), replace), (next_copied, copy_byte)]);
+
+        builder.position_at_end(done);
+        builder.ret_void();
+    }
+
+    /// Adds `grow_tape`, which reallocates the tape so that it holds the cell at
+    /// `index`, at least doubling it and zeroing the new cells.
+    fn add_grow_tape(&self) {
+        let module = self.module;
+        let size_type = self.size_type;
+        let params = [module.ptr_type(self.cell_ptr_type()), module.ptr_type(size_type), size_type];
+        let grow_fn = module.add_function("grow_tape", module.void_type().fn_type(&params));
+        grow_fn.set_linkage(Linkage::LLVMInternalLinkage);
+        let (tape, capacity, index) = (grow_fn.param(0), grow_fn.param(1), grow_fn.param(2));
+
+        let builder = module.create_builder();
+        let entry = module.append_block(grow_fn, "entry");
+        let failed = module.append_block(grow_fn, "failed");
+        let done = module.append_block(grow_fn, "done");
+        builder.position_at_end(entry);
+
+        let old_cap = builder.load(size_type, capacity, "old_cap");
+        let doubled = builder.shl(old_cap, self.size_const(1), "doubled");
+        let needed = builder.add(index, self.size_const(1), "needed");
+        let too_small = builder.icmp(IntPredicate::LLVMIntULT, doubled, needed, "too_small");
+        let new_cap = builder.select(too_small, needed, doubled, "new_cap");
+
+        let cell_bytes = self.size_const(self.cell_bytes());
+        let realloc_args = [
+            builder.load(self.cell_ptr_type(), tape, "old"),
+            builder.mul(new_cap, cell_bytes, "bytes"),
+        ];
+        let cells = builder.call(self.function("realloc"), &realloc_args, "cells");
+        let is_null = builder.is_null(cells, "is_null");
+        builder.cond_br(is_null, failed, done);
+
+        builder.position_at_end(failed);
+        builder.call(self.function("abort"), &[], "");
+        builder.unreachable();
+
+        builder.position_at_end(done);
+        let fresh = builder.gep(self.cell_type, cells, &[old_cap], "fresh");
+        let fresh_cells = builder.sub(new_cap, old_cap, "fresh_cells");
+        let memset_args = [
+            fresh,
+            self.i32_type.const_int(0, false),
+            builder.mul(fresh_cells, cell_bytes, "fresh_bytes"),
+        ];
+        builder.call(self.function("memset"), &memset_args, "");
+        builder.store(cells, tape);
+        builder.store(new_cap, capacity);
+        builder.ret_void();
+    }
+
+    /// Adds `grow_tape_left`, which reallocates the tape with at least
+    /// `missing` zeroed cells in front, and at least as many as it had, moving
+    /// the index and the origin along with the cells.
+    fn add_grow_tape_left(&self) {
+        let module = self.module;
+        let size_type = self.size_type;
+        let size_ptr_type = module.ptr_type(size_type);
+        let params = [module.ptr_type(self.cell_ptr_type()), size_ptr_type, size_ptr_type, size_ptr_type, size_type];
+        let grow_fn = module.add_function("grow_tape_left", module.void_type().fn_type(&params));
+        grow_fn.set_linkage(Linkage::LLVMInternalLinkage);
+        let (tape, capacity, index, origin) = (grow_fn.param(0), grow_fn.param(1), grow_fn.param(2), grow_fn.param(3));
+        let missing = grow_fn.param(4);
+
+        let builder = module.create_builder();
+        let entry = module.append_block(grow_fn, "entry");
+        let failed = module.append_block(grow_fn, "failed");
+        let done = module.append_block(grow_fn, "done");
+        builder.position_at_end(entry);
+
+        let old_cap = builder.load(size_type, capacity, "old_cap");
+        let too_few = builder.icmp(IntPredicate::LLVMIntULT, old_cap, missing, "too_few");
+        let added = builder.select(too_few, missing, old_cap, "added");
+        let new_cap = builder.add(old_cap, added, "new_cap");
+
+        let cell_bytes = self.size_const(self.cell_bytes());
+        let realloc_args = [
+            builder.load(self.cell_ptr_type(), tape, "old"),
+            builder.mul(new_cap, cell_bytes, "bytes"),
+        ];
+        let cells = builder.call(self.function("realloc"), &realloc_args, "cells");
+        let is_null = builder.is_null(cells, "is_null");
+        builder.cond_br(is_null, failed, done);
+
+        builder.position_at_end(failed);
+        builder.call(self.function("abort"), &[], "");
+        builder.unreachable();
+
+        builder.position_at_end(done);
+        let moved = builder.gep(self.cell_type, cells, &[added], "moved");
+        let memmove_args = [moved, cells, builder.mul(old_cap, cell_bytes, "old_bytes")];
+        builder.call(self.function("memmove"), &memmove_args, "");
+        let memset_args = [
+            cells,
+            self.i32_type.const_int(0, false),
+            builder.mul(added, cell_bytes, "fresh_bytes"),
+        ];
+        builder.call(self.function("memset"), &memset_args, "");
+        builder.store(cells, tape);
+        builder.store(new_cap, capacity);
+        for slot in [index, origin] {
+            let old = builder.load(size_type, slot, "old");
+            builder.store(builder.add(old, added, "shifted"), slot);
+        }
+        builder.ret_void();
+    }
+
+    /// Adds the tape dump runtime, which writes straight to stderr, a piece at
+    /// a time.
+    ///
+    /// - `midilang_write_decimal(i64)` writes a number that isn't negative
+    /// - `midilang_dump_tape(cells, capacity, index, origin)` writes the cells
+    ///   around `index` like `midilang_core::TapeDump`, those off the tape as 0
+    fn add_dump_tape(&self) {
+        let module = self.module;
+        let (i8_type, size_type) = (module.int_type(8), self.size_type);
+        let i64_type = module.int_type(64);
+        let void_type = module.void_type();
+        let stderr = self.i32_type.const_int(2, false);
+        let builder = module.create_builder();
+
+        let decimal_fn = module.add_function("midilang_write_decimal", void_type.fn_type(&[i64_type]));
+        decimal_fn.set_linkage(Linkage::LLVMInternalLinkage);
+        let dump_fn = module.add_function("midilang_dump_tape", void_type.fn_type(&[self.cell_ptr_type(), size_type, size_type, size_type]));
+        dump_fn.set_linkage(Linkage::LLVMInternalLinkage);
+
+        // midilang_write_decimal, filling in digits from the end of a buffer
+        // big enough for any u64
+        let digits_type = i8_type.array_type(20);
+        let entry = module.append_block(decimal_fn, "entry");
+        let digit = module.append_block(decimal_fn, "digit");
+        let write = module.append_block(decimal_fn, "write");
+        builder.position_at_end(entry);
+        let digits = builder.alloca(digits_type, "digits");
+        builder.br(digit);
+
+        builder.position_at_end(digit);
+        let value = builder.phi(i64_type, "value");
+        let end = builder.phi(size_type, "end");
+        let start = builder.sub(end, self.size_const(1), "start");
+        let ten = i64_type.const_int(10, false);
+        let last = builder.int_cast(builder.urem(value, ten, "last"), i8_type, "last_digit");
+        let ascii = builder.add(last, i8_type.const_int(u64::from(b'0'), false), "ascii");
+        builder.store(ascii, builder.gep(digits_type, digits, &[self.size_const(0), start], "slot"));
+        let rest = builder.udiv(value, ten, "rest");
+        let done = builder.icmp(IntPredicate::LLVMIntEQ, rest, i64_type.const_int(0, false), "done");
+        builder.cond_br(done, write, digit);
+        value.add_incoming(&[(decimal_fn.param(0), entry), (rest, digit)]);
+        end.add_incoming(&[(self.size_const(20), entry), (start, digit)]);
+
+        builder.position_at_end(write);
+        let write_args = [
+            stderr,
+            builder.gep(digits_type, digits, &[self.size_const(0), start], "first"),
+            builder.sub(self.size_const(20), start, "len"),
+        ];
+        builder.call(self.function("write"), &write_args, "");
+        builder.ret_void();
+
+        // midilang_dump_tape
+        let (cells, capacity, index, origin) = (dump_fn.param(0), dump_fn.param(1), dump_fn.param(2), dump_fn.param(3));
+        let entry = module.append_block(dump_fn, "entry");
+        let cell = module.append_block(dump_fn, "cell");
+        let end = module.append_block(dump_fn, "end");
+        builder.position_at_end(entry);
+        let write_str = |string: &str| {
+            let args = [stderr, builder.global_string_ptr(string, "dump_text"), self.size_const(string.len() as u64)];
+            builder.call(self.function("write"), &args, "");
+        };
+        let radius = self.size_const(DUMP_RADIUS as u64);
+        let left = builder.sub(index, radius, "left");
+        // an infinite tape shows the cells left of its first too, wrapping around
+        let first = match self.options.tape_mode {
+            TapeMode::Infinite => left,
+            _ => {
+                let near_start = builder.icmp(IntPredicate::LLVMIntULT, index, radius, "near_start");
+                builder.select(near_start, self.size_const(0), left, "first")
+            }
+        };
+        let past_last = builder.add(index, self.size_const(DUMP_RADIUS as u64 + 1), "past_last");
+        write_str("tape at ");
+        let position = builder.int_cast(builder.sub(index, origin, "position"), i64_type, "position");
+        let zero = i64_type.const_int(0, false);
+        let negative = builder.icmp(IntPredicate::LLVMIntSLT, position, zero, "negative");
+        let sign_args = [
+            stderr,
+            builder.global_string_ptr("-", "minus"),
+            builder.select(negative, self.size_const(1), self.size_const(0), "sign_len"),
+        ];
+        builder.call(self.function("write"), &sign_args, "");
+        let magnitude = builder.select(negative, builder.sub(zero, position, "negated"), position, "magnitude");
+        builder.call(decimal_fn, &[magnitude], "");
+        write_str(":");
+        builder.br(cell);
+
+        builder.position_at_end(cell);
+        let at = builder.phi(size_type, "at");
+        let in_tape = builder.icmp(IntPredicate::LLVMIntULT, at, capacity, "in_tape");
+        // cells past the end read the first one instead, and show as 0
+        let slot = builder.select(in_tape, at, self.size_const(0), "slot");
+        let loaded = builder.load(self.cell_type, builder.gep(self.cell_type, cells, &[slot], "cell_addr"), "cell");
+        let loaded = builder.int_cast(loaded, i64_type, "wide");
+        let value = builder.select(in_tape, loaded, i64_type.const_int(0, false), "value");
+        let is_pointer = builder.icmp(IntPredicate::LLVMIntEQ, at, index, "is_pointer");
+        let open_args = [
+            stderr,
+            builder.select(is_pointer, builder.global_string_ptr(" [", "open"), builder.global_string_ptr(" ", "space"), "before"),
+            builder.select(is_pointer, self.size_const(2), self.size_const(1), "before_len"),
+        ];
+        builder.call(self.function("write"), &open_args, "");
+        builder.call(decimal_fn, &[value], "");
+        let close_args = [
+            stderr,
+            builder.global_string_ptr("]", "close"),
+            builder.select(is_pointer, self.size_const(1), self.size_const(0), "after_len"),
+        ];
+        builder.call(self.function("write"), &close_args, "");
+        let next = builder.add(at, self.size_const(1), "next");
+        let more = builder.icmp(IntPredicate::LLVMIntNE, next, past_last, "more");
+        builder.cond_br(more, cell, end);
+        at.add_incoming(&[(first, entry), (next, cell)]);
+
+        builder.position_at_end(end);
+        write_str("\n");
+        builder.ret_void();
+    }
+
+    /// Returns the address of the current cell.
+    fn cell_address(&self) -> Value<'m> {
+        let cells = self.builder.load(self.cell_ptr_type(), self.tape, "cells");
+        let index = self.builder.load(self.size_type, self.index, "idx");
+        self.builder.gep(self.cell_type, cells, &[index], "cell_addr")
+    }
+
+    /// Musical location of `position` when there's a source map, its index otherwise.
+    fn describe(&self, position: Position) -> String {
+        match self.source_map {
+            Some(map) => map.describe(position),
+            None => format!("instruction {}", position.start()),
+        }
+    }
+
+    /// Suffix for the names of values emitted for `position`, like `bar12_beat3`.
+    fn label(&self, position: Position) -> String {
+        match self.source_map {
+            Some(map) => map.label(position),
+            None => format!("inst{}", position.start()),
+        }
+    }
+
+    /// `name`, or `name.1`, `name.2` and so on when it's been used before.
+    ///
+    /// Names in `main` only depend on where in the MIDI they come from, not on how
+    /// many values LLVM has seen, so the IR is the same every time and small
+    /// changes to a program don't rename everything after them.
+    fn unique_name(&self, name: String) -> String {
+        let mut counts = self.label_counts.borrow_mut();
+        let count = counts.entry(name.clone()).or_default();
+        *count += 1;
+        match *count {
+            1 => name,
+            n => format!("{}.{}", name, n - 1),
+        }
+    }
+
+    /// Appends a block to `main`, labelled with the MIDI instruction being compiled.
+    fn append_block(&self, name: &str) -> BasicBlock<'m> {
+        let name = match &self.current_label {
+            Some(label) => self.unique_name(format!("{}_{}", name, label)),
+            None => name.to_owned(),
+        };
+        self.module.append_block(self.main_fn, &name)
+    }
+
+    /// Continues in a new block if `failed` is false. Otherwise prints `reason` and
+    /// the location of `position` to stderr, then exits with status 1.
+    fn guard(&self, failed: Value<'m>, reason: &str, position: Option<Position>) {
+        let location = match position {
+            Some(pos) => format!(" at {}", self.describe(pos)),
+            None => String::new(),
+        };
+        let message = format!("midilang: {}{}\n", reason, location);
+        let builder = &self.builder;
+        let trap_block = self.append_block("trap");
+        let ok_block = self.append_block("ok");
+        builder.cond_br(failed, trap_block, ok_block);
+
+        builder.position_at_end(trap_block);
+        builder.call(self.function("midilang_flush"), &[], "");
+        let args = [
+            self.i32_type.const_int(2, false),
+            builder.global_string_ptr(&message, "trap_message"),
+            self.size_const(message.len() as u64),
+        ];
+        builder.call(self.function("write"), &args, "");
+        // the same status a run the VM stops has
+        builder.call(self.function("exit"), &[self.i32_type.const_int(1, false)], "");
+        builder.unreachable();
+
+        builder.position_at_end(ok_block);
+    }
+
+    fn compile_instructions(&mut self, program: &[MidiInstruction]) {
+        for inst in program {
+            self.progress.inc(1);
+            let block = self.builder.insert_block();
+            let before = block.and_then(BasicBlock::last_instruction);
+            let last_block = self.main_fn.last_block();
+            let outer_label = match inst.position {
+                Some(position) => self.current_label.replace(self.label(position)),
+                None => self.current_label.clone(),
+            };
+            self.perform(c_major_root(&inst.instruction));
+            self.compile_instruction(inst);
+            self.current_label = outer_label;
+            if let (Some(block), Some(last_block), Some(position)) = (block, last_block, inst.position) {
+                self.record_position(block, before, last_block, position);
+            }
+        }
+    }
+
+    /// Maps everything emitted since `before` in `block`, plus every block added
+    /// after `last_block`, to `position`.
+    ///
+    /// Each of those instructions gets the location of `position` attached as
+    /// metadata and added to its name, so `%cell` becomes `%cell_bar12_beat3`.
+    /// Instructions of nested loops already belong to their own position.
+    /// With debug info they also get the line of `position` in the source map listing.
+    fn record_position(&mut self, block: BasicBlock<'m>, before: Option<Value<'m>>, last_block: BasicBlock<'m>, position: Position) {
+        let mut value = match before {
+            Some(before) => before.next_instruction(),
+            None => block.first_instruction(),
+        };
+        let mut next_block = last_block.next();
+        let description = self.describe(position);
+        let label = self.label(position);
+        let location = self
+            .debug_scope
+            .map(|scope| self.module.debug_location(position.start() as u32 + 1, 0, scope));
+        loop {
+            while let Some(inst) = value {
+                if !inst.has_metadata(self.position_kind) {
+                    self.positions.push((inst, position));
+                    self.module.set_string_metadata(inst, self.position_kind, &description);
+                    if let Some(location) = location {
+                        inst.set_debug_location(location);
+                    }
+                    let name = inst.name();
+                    if !name.is_empty() {
+                        // drop the number LLVM added to tell apart values with the same name
+                        let name = format!("{}_{}", name.trim_end_matches(|c: char| c.is_ascii_digit()), label);
+                        inst.set_name(&self.unique_name(name));
+                    }
+                }
+                value = inst.next_instruction();
+            }
+            let Some(block) = next_block else {
+                break;
+            };
+            value = block.first_instruction();
+            next_block = block.next();
+        }
+    }
+
+    fn compile_instruction(&mut self, inst: &MidiInstruction) {
+        let builder = &self.builder;
+        match &inst.instruction {
+            IncrementCell { amount } => {
+                let addr = self.cell_address();
+                let cell = builder.load(self.cell_type, addr, "cell");
+                let step = i64::from(amount.0);
+                let amount = self.cell_type.const_int(amount.0 as u64, true);
+                let mut sum = builder.add(cell, amount, "inc");
+                if self.options.overflow != Overflow::Wrap {
+                    // cells are treated as unsigned, so overflow means crossing 0
+                    let max = u64::MAX >> (64 - self.options.cell_width.bits());
+                    let failed = if step.unsigned_abs() > max {
+                        // no cell value can take a step this big
+                        self.module.bool_type().const_int(1, false)
+                    } else if step > 0 {
+                        let limit = self.cell_const(max - step as u64);
+                        builder.icmp(IntPredicate::LLVMIntUGT, cell, limit, "overflow")
+                    } else {
+                        let limit = self.cell_const(step.unsigned_abs());
+                        builder.icmp(IntPredicate::LLVMIntULT, cell, limit, "underflow")
+                    };
+                    if self.options.overflow == Overflow::Trap {
+                        self.guard(failed, "cell overflow", inst.position);
+                    } else {
+                        let bound = self.cell_const(if step > 0 { max } else { 0 });
+                        sum = builder.select(failed, bound, sum, "saturated");
+                    }
+                }
+                builder.store(sum, addr);
+            }
+            MovePointer { amount } if self.options.pointer_overflow == Some(PointerOverflow::Wrap) => {
+                // only fixed tapes wrap, so the pointer comes back in within one lap
+                let size = self.options.tape_size;
+                let distance = amount.unsigned_abs() as u64 % size;
+                let index = builder.load(self.size_type, self.index, "idx");
+                let wrapped = if *amount < 0 {
+                    let moved = builder.sub(index, self.size_const(distance), "move");
+                    let short = builder.icmp(IntPredicate::LLVMIntULT, index, self.size_const(distance), "short");
+                    let around = builder.add(index, self.size_const(size - distance), "around");
+                    builder.select(short, around, moved, "wrapped")
+                } else {
+                    let moved = builder.add(index, self.size_const(distance), "move");
+                    let past = builder.icmp(IntPredicate::LLVMIntUGE, moved, self.size_const(size), "past");
+                    let around = builder.sub(moved, self.size_const(size), "around");
+                    builder.select(past, around, moved, "wrapped")
+                };
+                builder.store(wrapped, self.index);
+            }
+            MovePointer { amount } => {
+                let infinite = self.options.tape_mode == TapeMode::Infinite;
+                if infinite && *amount < 0 {
+                    let index = builder.load(self.size_type, self.index, "idx");
+                    let distance = self.size_const(amount.unsigned_abs() as u64);
+                    let short = builder.icmp(IntPredicate::LLVMIntULT, index, distance, "short");
+                    let grow_block = self.append_block("grow_left");
+                    let room_block = self.append_block("room");
+                    builder.cond_br(short, grow_block, room_block);
+
+                    builder.position_at_end(grow_block);
+                    let missing = builder.sub(distance, index, "missing");
+                    let args = [self.tape, self.capacity, self.index, self.origin, missing];
+                    builder.call(self.function("grow_tape_left"), &args, "");
+                    builder.br(room_block);
+
+                    builder.position_at_end(room_block);
+                }
+                let index = builder.load(self.size_type, self.index, "idx");
+                let offset = self.size_type.const_int(*amount as u64, true);
+                let moved = builder.add(index, offset, "move");
+                let growing = self.options.tape_mode != TapeMode::Fixed;
+                // infinite tapes have room for every move by now
+                if self.options.bounds_check && !(growing && *amount > 0) && !infinite {
+                    // negative indexes wrap around to huge unsigned ones
+                    let failed = if growing {
+                        builder.icmp(IntPredicate::LLVMIntSLT, moved, self.size_const(0), "out_of_bounds")
+                    } else {
+                        let size = self.size_const(self.options.tape_size);
+                        builder.icmp(IntPredicate::LLVMIntUGE, moved, size, "out_of_bounds")
+                    };
+                    self.guard(failed, "pointer out of bounds", inst.position);
+                }
+                if growing && *amount > 0 {
+                    let capacity = builder.load(self.size_type, self.capacity, "capacity");
+                    let full = builder.icmp(IntPredicate::LLVMIntUGE, moved, capacity, "full");
+                    let grow_block = self.append_block("grow");
+                    let moved_block = self.append_block("moved");
+                    builder.cond_br(full, grow_block, moved_block);
+
+                    builder.position_at_end(grow_block);
+                    builder.call(self.function("grow_tape"), &[self.tape, self.capacity, moved], "");
+                    builder.br(moved_block);
+
+                    builder.position_at_end(moved_block);
+                }
+                builder.store(moved, self.index);
+            }
+            OutputCell if self.options.io == IoMode::Numeric => {
+                let cell = builder.load(self.cell_type, self.cell_address(), "cell");
+                let number = builder.int_cast(cell, self.module.int_type(64), "out");
+                builder.call(self.function("midilang_put_number"), &[number], "");
+            }
+            OutputCell => {
+                let cell = builder.load(self.cell_type, self.cell_address(), "cell");
+                let byte = builder.int_cast(cell, self.module.int_type(8), "out");
+                let put = if self.options.io == IoMode::Utf8 { "midilang_put_utf8" } else { "midilang_putc" };
+                builder.call(self.function(put), &[byte], "");
+            }
+            InputCell if self.options.io == IoMode::Utf8 => {
+                let index = builder.load(self.size_type, self.index, "idx");
+                if self.options.tape_mode != TapeMode::Fixed {
+                    // room for the longest sequence
+                    let last = builder.add(index, self.size_const(3), "last");
+                    let capacity = builder.load(self.size_type, self.capacity, "capacity");
+                    let full = builder.icmp(IntPredicate::LLVMIntUGE, last, capacity, "full");
+                    let grow_block = self.append_block("grow");
+                    let room_block = self.append_block("room");
+                    builder.cond_br(full, grow_block, room_block);
+
+                    builder.position_at_end(grow_block);
+                    builder.call(self.function("grow_tape"), &[self.tape, self.capacity, last], "");
+                    builder.br(room_block);
+
+                    builder.position_at_end(room_block);
+                }
+                let cells = builder.load(self.cell_ptr_type(), self.tape, "cells");
+                let capacity = builder.load(self.size_type, self.capacity, "capacity");
+                builder.call(self.function("midilang_read_utf8"), &[cells, capacity, index], "");
+            }
+            InputCell if self.options.io == IoMode::Numeric => {
+                let read = builder.call(self.function("midilang_read_number"), &[], "read");
+                let new_cell = builder.int_cast(read, self.cell_type, "in");
+                builder.store(new_cell, self.cell_address());
+            }
+            InputCell => {
+                // EOF reads as 0
+                let read = builder.call(self.function("midilang_getc"), &[], "read");
+                let eof = builder.icmp(IntPredicate::LLVMIntEQ, read, self.i32_type.const_int(u64::MAX, true), "eof");
+                let byte = builder.int_cast(read, self.cell_type, "byte");
+                let new_cell = builder.select(eof, self.cell_const(0), byte, "in");
+                builder.store(new_cell, self.cell_address());
+            }
+            Call { name } => {
+                // whatever the function prints goes after what's buffered
+                builder.call(self.function("midilang_flush"), &[], "");
+                let function = match self.module.function(name) {
+                    Some(function) => function,
+                    None => self.module.add_function(name, self.i32_type.fn_type(&[self.i32_type])),
+                };
+                let cell = builder.load(self.cell_type, self.cell_address(), "cell");
+                let arg = builder.int_cast(cell, self.i32_type, "arg");
+                let result = builder.call(function, &[arg], "result");
+                let new_cell = builder.int_cast(result, self.cell_type, "called");
+                builder.store(new_cell, self.cell_address());
+            }
+            Random => {
+                // SplitMix64, like `midilang_core::Rng`
+                let rng = self.rng.expect("random bytes are picked without a seed");
+                let i64_type = self.module.int_type(64);
+                let constant = |value: u64| i64_type.const_int(value, false);
+                let state = builder.load(i64_type, rng, "rng");
+                let state = builder.add(state, constant(GOLDEN_GAMMA), "rng_next");
+                builder.store(state, rng);
+                let mut z = state;
+                for (shift, multiplier) in [(30, MIX_MULTIPLIERS[0]), (27, MIX_MULTIPLIERS[1])] {
+                    let mixed = builder.xor(z, builder.lshr(z, constant(shift), "shifted"), "mixed");
+                    z = builder.mul(mixed, constant(multiplier), "mix");
+                }
+                let mixed = builder.xor(z, builder.lshr(z, constant(31), "shifted"), "mixed");
+                let byte = builder.lshr(mixed, constant(56), "random");
+                let new_cell = builder.int_cast(byte, self.cell_type, "random_cell");
+                builder.store(new_cell, self.cell_address());
+            }
+            DumpTape => {
+                // after what the program printed so far
+                builder.call(self.function("midilang_flush"), &[], "");
+                let args = [
+                    builder.load(self.cell_ptr_type(), self.tape, "cells"),
+                    builder.load(self.size_type, self.capacity, "capacity"),
+                    builder.load(self.size_type, self.index, "idx"),
+                    builder.load(self.size_type, self.origin, "origin"),
+                ];
+                builder.call(self.function("midilang_dump_tape"), &args, "");
+            }
+            Loop { body } => {
+                let cond_block = self.append_block("loop_cond");
+                let body_block = self.append_block("loop_body");
+                let end_block = self.append_block("loop_end");
+                builder.br(cond_block);
+
+                builder.position_at_end(cond_block);
+                let cell = builder.load(self.cell_type, self.cell_address(), "cell");
+                let is_zero = builder.icmp(IntPredicate::LLVMIntEQ, cell, self.cell_const(0), "is_zero");
+                builder.cond_br(is_zero, end_block, body_block);
+
+                builder.position_at_end(body_block);
+                self.compile_instructions(body);
+                // the closing chord plays every time around the loop
+                self.perform(0);
+                self.builder.br(cond_block);
+
+                self.builder.position_at_end(end_block);
+            }
+            // compiled programs never stop for the debugger
+            Breakpoint => {}
+        }
+    }
+
+    /// Emits the runtime and the body of `main` for `midi_program`.
+    fn compile(&mut self, midi_program: &MidiAST) {
+        self.add_c_declarations();
+        self.add_io_runtime();
+        match self.options.io {
+            IoMode::Bytes => {}
+            IoMode::Utf8 => self.add_utf8_runtime(),
+            IoMode::Numeric => self.add_numeric_runtime(),
+        }
+        self.add_provenance();
+        if let Some(note_ms) = self.options.perform {
+            self.add_perform_runtime(note_ms);
+        }
+        if self.options.tape_mode != TapeMode::Fixed {
+            self.add_grow_tape();
+        }
+        if self.options.tape_mode == TapeMode::Infinite {
+            self.add_grow_tape_left();
+        }
+        if plays(midi_program, &DumpTape) {
+            self.add_dump_tape();
+        }
+        self.redirect_stdio();
+        self.allocate_cells(self.options.tape_size);
+        self.load_data();
+        self.seed_random(midi_program);
+        self.progress = Progress::new("Compiling", instruction_count(midi_program));
+        self.compile_instructions(midi_program);
+        self.progress = Progress::default();
+        if self.options.io == IoMode::Utf8 {
+            self.builder.call(self.function("midilang_finish_utf8"), &[], "");
+        }
+        self.builder.call(self.function("midilang_flush"), &[], "");
+        let cells = self.builder.load(self.cell_ptr_type(), self.tape, "final_cells");
+        self.builder.call(self.function("free"), &[cells], "");
+        self.builder.ret(self.i32_type.const_int(0, false));
+        self.finish_debug_info();
+    }
+
+    /// Puts the setup and teardown code of `main` on line 0, which debuggers
+    /// treat as belonging to no line, and finalizes the debug info.
+    fn finish_debug_info(&mut self) {
+        let Some(scope) = self.debug_scope else {
+            return;
+        };
+        let no_line = self.module.debug_location(0, 0, scope);
+        let mut block = self.main_fn.first_block();
+        while let Some(current) = block {
+            let mut value = current.first_instruction();
+            while let Some(inst) = value {
+                if !inst.has_debug_location() {
+                    inst.set_debug_location(no_line);
+                }
+                value = inst.next_instruction();
+            }
+            block = current.next();
+        }
+        self.debug_info = None;
+    }
+
+    /// Runs the LLVM verifier over the module, pointing at the MIDI location
+    /// responsible for the first instruction it complains about.
+    fn verify(&self) -> MCompileResult<()> {
+        let Err(message) = self.module.verify() else {
+            return Ok(());
+        };
+        let functions: Vec<_> = self.module.functions().filter(|func| !func.is_valid()).map(Function::name).collect();
+        let location = self
+            .positions
+            .iter()
+            .find(|(value, _)| message.contains(value.print_to_string().trim()))
+            .map(|(_, position)| format!(" from {}", self.describe(*position)))
+            .unwrap_or_default();
+        Err(MCompileError::LLVMError(format!(
+            "Invalid IR in {}{}: {}",
+            functions.join(", "),
+            location,
+            message.trim()
+        )))
+    }
+}
+
+/// Compiles the given `MidiAST`, running LLVM passes on it.
+///
+/// The returned compiler owns the finished module, get at it with
+/// `MidiCompiler::print_ir` or write it out with `MidiCompiler::emit`.
+pub fn compile_program(
+    midi_program: MidiAST,
+    source_map: Option<SourceMap>,
+    options: CompileOptions,
+) -> MCompileResult<MidiCompiler> {
+    let mut compiler = MidiCompiler::new(options)?;
+    if let Some(source_map) = source_map {
+        compiler.set_source_map(source_map);
+    }
+    compiler.compile(&midi_program)?;
+    compiler.run_passes()?;
+    Ok(compiler)
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+    use crate::compiler::CellWidth;
+    use crate::parser::MidiASTBuilder;
+    use crate::provenance::Provenance;
+    use std::{env, fs, process};
+    use std::num::Wrapping;
+
+    fn compile_ir(insts: Vec<MidiInstruction>, options: CompileOptions) -> String {
+        let mut builder = MidiASTBuilder::new();
+        for inst in insts {
+            builder.push(inst).unwrap();
+        }
+        compile_program(builder.into_mast().unwrap(), None, options).unwrap().print_ir()
+    }
+
+    #[test]
+    fn compiles_cell_width_and_checks() {
+        let insts = vec![
+            MidiInstruction::new_input(),
+            MidiInstruction::new_inc(Wrapping(1)),
+            MidiInstruction::new_move(1),
+            MidiInstruction::new_output(),
+        ];
+        let plain = compile_ir(insts.clone(), CompileOptions::default());
+        assert!(plain.contains("define i32 @main()"));
+        assert!(!plain.contains("trap_inst"));
+
+        let options = CompileOptions::builder()
+            .cell_width(CellWidth::I32)
+            .overflow(Overflow::Trap)
+            .bounds_check(true)
+            .build()
+            .unwrap();
+        let checked = compile_ir(insts, options);
+        assert!(checked.contains("calloc(i64 30000, i64 4)"));
+        assert!(checked.contains("trap_inst1:"));
+        assert!(checked.contains("call void @exit(i32 1)"));
+        assert!(checked.contains("midilang: pointer out of bounds at instruction 2"));
+    }
+
+    #[test]
+    fn checked_errors_name_bar_and_beat() {
+        let mut builder = MidiASTBuilder::new();
+        builder.push(MidiInstruction::new_inc(Wrapping(1))).unwrap();
+        builder.push(MidiInstruction::new_move(-1)).unwrap();
+        let mut source_map = SourceMap::new(midly::Timing::Metrical(midly::num::u15::from(96)));
+        source_map.push_instruction(0);
+        source_map.push_instruction(96 * 5);
+
+        let mut compiler = MidiCompiler::new(CompileOptions::builder().bounds_check(true).build().unwrap()).unwrap();
+        compiler.set_source_map(source_map);
+        compiler.compile(&builder.into_mast().unwrap()).unwrap();
+        assert!(compiler.print_ir().contains("midilang: pointer out of bounds at 2:2 (instruction 1)"));
+    }
+
+    #[test]
+    fn names_values_after_positions() {
+        let mut builder = MidiASTBuilder::new();
+        builder.push(MidiInstruction::new_inc(Wrapping(1))).unwrap();
+        builder.push(MidiInstruction::new_move(-1)).unwrap();
+        let mut source_map = SourceMap::new(midly::Timing::Metrical(midly::num::u15::from(96)));
+        source_map.push_instruction(0);
+        source_map.push_instruction(96 * 5);
+        let ir = compile_program(builder.into_mast().unwrap(), Some(source_map), CompileOptions::default())
+            .unwrap()
+            .print_ir();
+        assert!(ir.contains("%inc_bar1_beat1 = add i8"), "{}", ir);
+        assert!(ir.contains("%move_bar2_beat2 = add i64"), "{}", ir);
+        assert!(ir.contains("!midilang.position !"));
+        assert!(ir.contains("!{!\"2:2 (instruction 1)\"}"));
+
+        // loop bodies keep the position of their own instructions
+        let insts = vec![
+            MidiInstruction::new_open_loop(),
+            MidiInstruction::new_inc(Wrapping(1)),
+            MidiInstruction::new_close_loop(),
+        ];
+        let ir = compile_ir(insts, CompileOptions::default());
+        assert!(ir.contains("%inc_inst1 = add i8"), "{}", ir);
+        assert!(ir.contains("%is_zero_inst0 = icmp eq i8"), "{}", ir);
+        assert!(!ir.contains("_inst1_inst0"));
+    }
+
+    #[test]
+    fn emits_debug_info_keyed_to_listing_lines() {
+        let insts = vec![MidiInstruction::new_input(), MidiInstruction::new_output()];
+        for opt_level in [OptLevel::O0, OptLevel::O2] {
+            let options = CompileOptions::builder()
+                .debug_info(Some("/music/song.midimap".into()))
+                .opt_level(opt_level)
+                .build()
+                .unwrap();
+            let ir = compile_ir(insts.clone(), options);
+            assert!(ir.contains("!DIFile(filename: \"song.midimap\", directory: \"/music\")"), "{}", ir);
+            assert!(ir.contains("distinct !DISubprogram(name: \"main\""));
+            assert!(ir.contains("!\"Debug Info Version\""));
+            // the output is instruction 1, so line 2 of the listing
+            assert!(ir.contains("!DILocation(line: 2, scope: "), "{}", ir);
+        }
+        assert!(!compile_ir(insts, CompileOptions::default()).contains("!dbg"));
+    }
+
+    #[test]
+    fn output_is_reproducible() {
+        let insts = vec![
+            MidiInstruction::new_input(),
+            MidiInstruction::new_open_loop(),
+            MidiInstruction::new_output(),
+            MidiInstruction::new_open_loop(),
+            MidiInstruction::new_move(1),
+            MidiInstruction::new_close_loop(),
+            MidiInstruction::new_inc(Wrapping(-1)),
+            MidiInstruction::new_close_loop(),
+        ];
+        let options = CompileOptions::builder().bounds_check(true).build().unwrap();
+        let compile = || {
+            let mut builder = MidiASTBuilder::new();
+            for inst in insts.clone() {
+                builder.push(inst).unwrap();
+            }
+            compile_program(builder.into_mast().unwrap(), None, options.clone()).unwrap()
+        };
+        let (first, second) = (compile(), compile());
+        let ir = first.print_ir();
+        assert_eq!(ir, second.print_ir());
+        // blocks and values are named after the instruction they're for
+        assert!(ir.contains("loop_cond_inst1:"), "{}", ir);
+        assert!(ir.contains("loop_cond_inst3:"));
+        assert!(ir.contains("grow_inst4:"));
+        assert!(ir.contains("%cells_inst2 = load"));
+        assert!(ir.contains("%final_cells = load"));
+        assert!(!ir.contains("%cells1 ="));
+
+        let dir = env::temp_dir();
+        let objects = [1, 2].map(|n| dir.join(format!("midilang-repro-{}-{}.o", process::id(), n)));
+        first.emit(Emit::Obj, &objects[0]).unwrap();
+        second.emit(Emit::Obj, &objects[1]).unwrap();
+        assert_eq!(fs::read(&objects[0]).unwrap(), fs::read(&objects[1]).unwrap());
+        for object in objects {
+            fs::remove_file(object).unwrap();
+        }
+    }
+
+    #[test]
+    fn sets_triple_and_data_layout() {
+        let host = compile_ir(vec![], CompileOptions::default());
+        assert!(host.contains(&format!("target triple = \"{}\"", host_triple())));
+        assert!(host.contains("target datalayout = "));
+
+        let options = CompileOptions::builder()
+            .target_triple(Some("aarch64-unknown-linux-gnu".to_owned()))
+            .build()
+            .unwrap();
+        let cross = compile_ir(vec![], options);
+        assert!(cross.contains("target triple = \"aarch64-unknown-linux-gnu\""));
+
+        let bogus = CompileOptions::builder()
+            .target_triple(Some("banana-unknown-none".to_owned()))
+            .build()
+            .unwrap();
+        assert!(MidiCompiler::new(bogus).is_err());
+    }
+
+    #[test]
+    fn embeds_provenance() {
+        assert!(!compile_ir(vec![], CompileOptions::default()).contains("provenance"));
+
+        let provenance = Provenance::new(Path::new("/songs/cat.mid"), b"abc");
+        let options = CompileOptions::builder().provenance(Some(provenance)).build().unwrap();
+        let ir = compile_ir(vec![], options);
+        assert!(ir.contains("!midilang.provenance = !{"), "{}", ir);
+        assert!(ir.contains(r#"!{!"source", !"cat.mid"}"#));
+        assert!(ir.contains(r#"!{!"key", !"C major"}"#));
+        assert!(ir.contains("@midilang_provenance = constant [155 x i8] c\"midilang provenance\\00source=cat.mid\\00"));
+        assert!(ir.contains("\\00\", section \".midilang\""));
+        assert!(!ir.contains("midilang_source"));
+
+        let options = CompileOptions::builder().embed_source(Some(b"MThd".to_vec())).build().unwrap();
+        let ir = compile_ir(vec![], options);
+        assert!(ir.contains("@midilang_source = constant [28 x i8] c\"midilang source\\00"), "{}", ir);
+        assert!(ir.contains("MThd\", section \".midilang.src\""));
+    }
+
+    #[test]
+    fn performs_every_instruction() {
+        let insts = vec![
+            MidiInstruction::new_inc(Wrapping(2)),
+            MidiInstruction::new_open_loop(),
+            MidiInstruction::new_inc(Wrapping(-1)),
+            MidiInstruction::new_close_loop(),
+        ];
+        assert!(!compile_ir(insts.clone(), CompileOptions::default()).contains("midilang_perform"));
+
+        let options = CompileOptions::builder().perform(Some(250)).build().unwrap();
+        let ir = compile_ir(insts.clone(), options);
+        assert!(ir.contains("define internal void @midilang_perform(i8 %0)"), "{}", ir);
+        assert!(ir.contains("call i32 @usleep(i32 250000)"));
+        assert!(ir.contains("c\"/dev/snd/midiC0D0\\00\""));
+        // A for +, G for [, F for - and C for ]
+        let notes: Vec<_> = ir
+            .lines()
+            .filter_map(|line| line.trim().strip_prefix("call void @midilang_perform(i8 "))
+            .filter_map(|args| args.split(')').next())
+            .collect();
+        assert_eq!(notes, ["69", "67", "65", "60"]);
+
+        let wasi = CompileOptions::builder()
+            .target_triple(Some("wasm32-wasi".to_owned()))
+            .perform(Some(250))
+            .build()
+            .unwrap();
+        let mut builder = MidiASTBuilder::new();
+        builder.push(MidiInstruction::new_output()).unwrap();
+        assert!(matches!(
+            compile_program(builder.into_mast().unwrap(), None, wasi),
+            Err(MCompileError::Unsupported(_))
+        ));
+    }
+
+    #[test]
+    fn wasi_uses_wasi_imports() {
+        let options = CompileOptions::builder()
+            .target_triple(Some("wasm32-wasi".to_owned()))
+            .build()
+            .unwrap();
+        assert!(options.targets_wasm());
+        let ir = compile_ir(vec![MidiInstruction::new_input(), MidiInstruction::new_output()], options);
+        assert!(ir.contains("define i32 @__main_void()"));
+        assert!(ir.contains("\"wasm-import-module\"=\"wasi_snapshot_preview1\" \"wasm-import-name\"=\"fd_write\""));
+        assert!(ir.contains("define internal i32 @read(i32"));
+        // size_t is 32 bits wide on wasm32
+        assert!(ir.contains("calloc(i32 30000, i32 1)"));
+    }
+
+    #[test]
+    fn emits_ir_and_bitcode() {
+        let mut compiler = MidiCompiler::new(CompileOptions::default()).unwrap();
+        compiler.compile(&MidiASTBuilder::new().into_mast().unwrap()).unwrap();
+        let dir = env::temp_dir();
+        let ll = dir.join(format!("midilang-test-{}.ll", process::id()));
+        let bc = dir.join(format!("midilang-test-{}.bc", process::id()));
+        compiler.emit(Emit::LlvmIr, &ll).unwrap();
+        compiler.emit(Emit::LlvmBc, &bc).unwrap();
+        assert_eq!(fs::read_to_string(&ll).unwrap(), compiler.print_ir());
+        assert!(fs::read(&bc).unwrap().starts_with(b"BC"));
+        fs::remove_file(ll).unwrap();
+        fs::remove_file(bc).unwrap();
+
+        assert_eq!(Emit::Obj.extension(false), "o");
+        assert_eq!(Emit::Exe.extension(true), "wasm");
+    }
+
+    #[test]
+    fn verifier_errors_name_function_and_position() {
+        let mut builder = MidiASTBuilder::new();
+        builder.push(MidiInstruction::new_inc(Wrapping(1))).unwrap();
+        builder.push(MidiInstruction::new_output()).unwrap();
+        let options = CompileOptions::builder().cell_width(CellWidth::I16).build().unwrap();
+        let compiler = MidiCompiler::new(options).unwrap();
+        let mut codegen = CodeGen::new(&compiler);
+        codegen.compile(&builder.into_mast().unwrap());
+        assert_eq!(codegen.verify(), Ok(()));
+
+        // pull the load feeding `putc` out of the function, leaving its users dangling
+        let (load, _) = codegen
+            .positions
+            .iter()
+            .find(|(value, position)| *position == Position::new(1, 1) && value.is_load())
+            .copied()
+            .unwrap();
+        load.remove_from_parent();
+        let Err(MCompileError::LLVMError(message)) = codegen.verify() else {
+            panic!("verifier should fail");
+        };
+        assert!(message.starts_with("Invalid IR in main from instruction 1: "), "{}", message);
+    }
+
+    #[test]
+    fn compiles_growing_tape() {
+        let insts = vec![MidiInstruction::new_move(3), MidiInstruction::new_move(-1)];
+        let options = CompileOptions::builder()
+            .tape_size(2)
+            .tape_mode(TapeMode::Grow)
+            .build()
+            .unwrap();
+        let ir = compile_ir(insts, options);
+        assert!(ir.contains("define internal void @grow_tape("));
+        // only moves to the right can run out of tape
+        assert_eq!(ir.matches("call void @grow_tape(").count(), 1);
+    }
+
+    #[test]
+    fn grows_infinite_tapes_both_ways() {
+        let insts = vec![MidiInstruction::new_move(3), MidiInstruction::new_move(-5)];
+        let options = CompileOptions::builder()
+            .tape_mode(TapeMode::Infinite)
+            .bounds_check(true)
+            .build()
+            .unwrap();
+        let ir = compile_ir(insts, options);
+        assert!(ir.contains("define internal void @grow_tape_left("), "{}", ir);
+        assert_eq!(ir.matches("call void @grow_tape(").count(), 1);
+        assert_eq!(ir.matches("call void @grow_tape_left(").count(), 1);
+        // neither way runs off the tape
+        assert!(!ir.contains("pointer out of bounds"));
+    }
+
+    #[test]
+    fn io_goes_through_buffered_runtime() {
+        let insts = vec![MidiInstruction::new_input(), MidiInstruction::new_output()];
+        let ir = compile_ir(insts, CompileOptions::default());
+        assert!(ir.contains("call void @midilang_putc("));
+        assert!(ir.contains("call i32 @midilang_getc()"));
+        assert!(ir.contains("call void @midilang_flush()"));
+        assert!(!ir.contains("@putchar"));
+        assert!(!ir.contains("@getchar"));
+    }
+
+    #[test]
+    fn sizes_tape_from_analysis() {
+        let bounded = vec![MidiInstruction::new_move(40), MidiInstruction::new_output()];
+        let ir = compile_ir(bounded, CompileOptions::builder().tape_size(8).build().unwrap());
+        assert!(ir.contains("calloc(i64 41, i64 1)"));
+        assert!(!ir.contains("@grow_tape("));
+
+        let unbounded = vec![
+            MidiInstruction::new_open_loop(),
+            MidiInstruction::new_move(1),
+            MidiInstruction::new_close_loop(),
+        ];
+        let ir = compile_ir(unbounded, CompileOptions::default());
+        assert!(ir.contains("call void @grow_tape("));
+    }
+
+    #[test]
+    fn copies_data_onto_the_tape() {
+        assert!(!compile_ir(vec![], CompileOptions::default()).contains("midilang_data"));
+        let options = CompileOptions::builder().tape_size(2).cell_width(CellWidth::I16).data(b"hi!".to_vec()).build().unwrap();
+        let ir = compile_ir(vec![MidiInstruction::new_output()], options);
+        assert!(ir.contains("@midilang_data = private constant [3 x i16] [i16 104, i16 105, i16 33]"), "{}", ir);
+        assert!(ir.contains("calloc(i64 3, i64 2)"));
+        assert!(ir.contains("i64 6)"));
+    }
+
+    #[test]
+    fn seeds_random_bytes() {
+        assert!(!compile_ir(vec![], CompileOptions::default()).contains("midilang_rng"));
+        let ir = compile_ir(vec![MidiInstruction::new_random()], CompileOptions::default());
+        assert!(ir.contains("@midilang_rng = private global i64 0"), "{}", ir);
+        assert!(ir.contains("call i64 @time(i8* null)"), "{}", ir);
+        let seeded = compile_ir(vec![MidiInstruction::new_random()], CompileOptions::builder().seed(Some(42)).build().unwrap());
+        assert!(seeded.contains("@midilang_rng = private global i64 42"), "{}", seeded);
+        assert!(!seeded.contains("@time"));
+    }
+
+    #[test]
+    fn dumps_the_tape_to_stderr() {
+        assert!(!compile_ir(vec![], CompileOptions::default()).contains("midilang_dump_tape"));
+        let ir = compile_ir(vec![MidiInstruction::new_dump_tape()], CompileOptions::default());
+        assert!(ir.contains("define internal void @midilang_dump_tape(i8* %0, i64 %1, i64 %2, i64 %3)"), "{}", ir);
+        assert!(ir.contains("define internal void @midilang_write_decimal(i64 %0)"), "{}", ir);
+        assert!(ir.contains("call void @midilang_dump_tape("), "{}", ir);
+    }
+
+    #[test]
+    fn saturates_cells_and_wraps_the_pointer() {
+        let insts = vec![
+            MidiInstruction::new_input(),
+            MidiInstruction::new_inc(Wrapping(-3)),
+            MidiInstruction::new_move(-12),
+            MidiInstruction::new_output(),
+        ];
+        let options = CompileOptions::builder()
+            .tape_size(10)
+            .overflow(Overflow::Saturate)
+            .pointer_overflow(Some(PointerOverflow::Wrap))
+            .build()
+            .unwrap();
+        let ir = compile_ir(insts, options);
+        assert!(ir.contains("select i1 %underflow_inst1, i8 0, i8 %inc_inst1"), "{}", ir);
+        assert!(ir.contains("%around_inst2 = add i64 %idx_inst2, 8"), "{}", ir);
+        assert!(ir.contains("select i1 %short_inst2, i64 %around_inst2, i64 %move_inst2"), "{}", ir);
+        assert!(!ir.contains("trap_inst"));
+    }
+
+    #[test]
+    fn writes_and_reads_utf8() {
+        let insts = vec![MidiInstruction::new_input(), MidiInstruction::new_output()];
+        assert!(!compile_ir(insts.clone(), CompileOptions::default()).contains("midilang_put_utf8"));
+        let ir = compile_ir(insts, CompileOptions::builder().io(IoMode::Utf8).build().unwrap());
+        assert!(ir.contains("call void @midilang_read_utf8(i8* %cells_inst0, i64 %capacity_inst0, i64 %idx_inst0)"), "{}", ir);
+        assert!(ir.contains("call void @midilang_put_utf8(i8 %cell_inst1)"), "{}", ir);
+        assert!(ir.contains("call void @midilang_finish_utf8()"), "{}", ir);
+        assert!(!ir.contains("call i32 @midilang_getc(), !midilang.position"), "{}", ir);
+    }
+
+    #[test]
+    fn prints_and_reads_numbers() {
+        let insts = vec![MidiInstruction::new_input(), MidiInstruction::new_output()];
+        assert!(!compile_ir(insts.clone(), CompileOptions::default()).contains("midilang_put_number"));
+        let ir = compile_ir(insts, CompileOptions::builder().io(IoMode::Numeric).build().unwrap());
+        assert!(ir.contains("%read_inst0 = call i64 @midilang_read_number()"), "{}", ir);
+        assert!(ir.contains("%out_inst1 = zext i8 %cell_inst1 to i64"), "{}", ir);
+        assert!(ir.contains("call void @midilang_put_number(i64 %out_inst1)"), "{}", ir);
+    }
+
+    #[test]
+    fn reopens_stdin_and_stdout() {
+        let options = CompileOptions::builder().stdout(Some("out.txt".into())).build().unwrap();
+        let ir = compile_ir(vec![], options);
+        assert!(ir.contains("c\"out.txt\\00\""), "{}", ir);
+        assert!(ir.contains("c\"wb\\00\""), "{}", ir);
+        assert!(ir.contains("call i32 @dup2(i32 %opened_fd, i32 1)"), "{}", ir);
+        assert!(!compile_ir(vec![], CompileOptions::default()).contains("@fopen"));
+    }
+}
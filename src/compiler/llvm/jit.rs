@@ -0,0 +1,261 @@
+use std::fmt;
+
+use log::{debug, warn};
+
+use super::wrapper::{BasicBlock, Builder, Function, IntPredicate, Jit, Module, Type, Value};
+use crate::compiler::{MCompileError, MCompileResult};
+use crate::parser::CellWidth;
+use crate::vm::{LoopCompiler, NativeLoop, Op};
+
+/// Compiles the hot loops of a `Vm` to native code with LLVM's ORC JIT.
+pub struct LoopJit {
+    jit: Jit,
+    /// Loops compiled so far
+    compiled: usize,
+}
+
+impl LoopJit {
+    pub fn new() -> MCompileResult<Self> {
+        let jit = Jit::new().map_err(MCompileError::LLVMError)?;
+        Ok(LoopJit { jit, compiled: 0 })
+    }
+}
+
+impl fmt::Debug for LoopJit {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "LoopJit {{ compiled: {} }}", self.compiled)
+    }
+}
+
+impl LoopCompiler for LoopJit {
+    fn compile_loop(&mut self, body: &[Op], start: usize, cell_width: CellWidth) -> Option<NativeLoop> {
+        // every loop gets its own module, so names have to be unique in the JIT
+        let name = format!("midilang_loop_{}_{}", self.compiled, start);
+        let result = self.jit.compile(&name, |module| {
+            LoopCodeGen::new(module, &name, cell_width).compile(body, start);
+        });
+        match result {
+            Ok(address) => {
+                debug!("Compiled the loop at op {} to {:#x}", start - 1, address);
+                self.compiled += 1;
+                // the function was built with the signature of `NativeLoop`
+                Some(unsafe { std::mem::transmute::<usize, NativeLoop>(address as usize) })
+            }
+            Err(msg) => {
+                warn!("Could not compile the loop at op {}: {}", start - 1, msg);
+                None
+            }
+        }
+    }
+}
+
+/// Builds a `NativeLoop` out of the body of a loop.
+struct LoopCodeGen<'m> {
+    module: &'m Module,
+    builder: Builder<'m>,
+    function: Function<'m>,
+    cell_width: CellWidth,
+    /// Cells on the tape, which are `Cell`s no matter the width
+    tape_cell_type: Type<'m>,
+    cell_type: Type<'m>,
+    size_type: Type<'m>,
+    steps_type: Type<'m>,
+    /// Pointer into the tape, kept in an alloca that gets promoted to a register
+    pointer: Value<'m>,
+    steps: Value<'m>,
+}
+
+impl<'m> LoopCodeGen<'m> {
+    fn new(module: &'m Module, name: &str, cell_width: CellWidth) -> Self {
+        let tape_cell_type = module.int_type(32);
+        let size_type = module.int_ptr_type();
+        let steps_type = module.int_type(64);
+        let fn_type = steps_type.fn_type(&[module.ptr_type(tape_cell_type), size_type]);
+        let function = module.add_function(name, fn_type);
+        let builder = module.create_builder();
+        builder.position_at_end(module.append_block(function, "entry"));
+        let pointer = builder.alloca(size_type, "pointer");
+        builder.store(function.param(1), pointer);
+        let steps = builder.alloca(steps_type, "steps");
+        builder.store(steps_type.const_int(0, false), steps);
+        LoopCodeGen {
+            module,
+            builder,
+            function,
+            cell_width,
+            tape_cell_type,
+            cell_type: module.int_type(cell_width.bits()),
+            size_type,
+            steps_type,
+            pointer,
+            steps,
+        }
+    }
+
+    /// Emits the loop as a do-while, since `NativeLoop`s are only called on nonzero cells.
+    fn compile(self, body: &[Op], start: usize) {
+        let exit = self.module.append_block(self.function, "exit");
+        self.compile_body(body, start, exit);
+        self.builder.position_at_end(exit);
+        let steps = self.builder.load(self.steps_type, self.steps, "steps");
+        self.builder.ret(steps);
+    }
+
+    /// Emits `body` followed by the jump back to its start, carrying on at `exit`.
+    fn compile_body(&self, body: &[Op], start: usize, exit: BasicBlock<'m>) {
+        let block = self.module.append_block(self.function, "body");
+        self.builder.br(block);
+        self.builder.position_at_end(block);
+
+        let mut index = 0;
+        while let Some(op) = body.get(index) {
+            self.count_step();
+            index += 1;
+            match *op {
+                Op::Add(amount) => {
+                    let cell = self.builder.int_cast(self.load_cell(), self.cell_type, "cell");
+                    let amount = self.cell_type.const_int(amount.0 as u64, true);
+                    let sum = self.builder.add(cell, amount, "sum");
+                    self.store_cell(sum);
+                }
+                Op::Move(amount) => {
+                    let pointer = self.builder.load(self.size_type, self.pointer, "pointer");
+                    let amount = self.size_type.const_int(amount as u64, true);
+                    let moved = self.builder.add(pointer, amount, "moved");
+                    self.builder.store(moved, self.pointer);
+                }
+                Op::JumpIfZero(end) => {
+                    // `end` is right after the inner loop's `JumpUnlessZero`
+                    let inner_exit = self.module.append_block(self.function, "inner_exit");
+                    let enter = self.module.append_block(self.function, "enter");
+                    self.branch_on_cell(enter, inner_exit);
+                    self.builder.position_at_end(enter);
+                    let inner_start = start + index;
+                    let inner_end = end - start;
+                    self.compile_body(&body[index..inner_end - 1], inner_start, inner_exit);
+                    self.builder.position_at_end(inner_exit);
+                    index = inner_end;
+                }
+                // `LoopCompiler`s never get loops with IO, calls, random bytes, tape
+                // dumps or breakpoints, and the ends of inner loops are skipped over above
+                Op::Output | Op::Input | Op::Breakpoint | Op::Call(_) | Op::Random | Op::DumpTape | Op::JumpUnlessZero(_) => {
+                    unreachable!("{:?} in a compiled loop", op)
+                }
+            }
+        }
+
+        self.count_step();
+        self.branch_on_cell(block, exit);
+    }
+
+    fn count_step(&self) {
+        let steps = self.builder.load(self.steps_type, self.steps, "steps");
+        let one = self.steps_type.const_int(1, false);
+        let steps = self.builder.add(steps, one, "steps");
+        self.builder.store(steps, self.steps);
+    }
+
+    fn cell_ptr(&self) -> Value<'m> {
+        let pointer = self.builder.load(self.size_type, self.pointer, "pointer");
+        let tape = self.function.param(0);
+        self.builder.gep(self.tape_cell_type, tape, &[pointer], "cell_ptr")
+    }
+
+    fn load_cell(&self) -> Value<'m> {
+        self.builder.load(self.tape_cell_type, self.cell_ptr(), "cell")
+    }
+
+    /// Stores a `cell_type` value, zero extended like `CellWidth::truncate` does.
+    fn store_cell(&self, value: Value<'m>) {
+        let value = match self.cell_width {
+            CellWidth::I32 => value,
+            _ => self.builder.zext(value, self.tape_cell_type, "cell"),
+        };
+        self.builder.store(value, self.cell_ptr());
+    }
+
+    fn branch_on_cell(&self, nonzero: BasicBlock<'m>, zero: BasicBlock<'m>) {
+        let zero_cell = self.tape_cell_type.const_int(0, false);
+        let is_nonzero = self.builder.icmp(IntPredicate::LLVMIntNE, self.load_cell(), zero_cell, "nonzero");
+        self.builder.cond_br(is_nonzero, nonzero, zero);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+    use crate::interpreter::Interpreter;
+    use crate::parser::{MidiAST, MidiASTBuilder, MidiInstruction};
+    use crate::vm::{Bytecode, Vm, TIER_UP_THRESHOLD};
+    use std::io;
+    use std::num::Wrapping;
+
+    fn build(insts: Vec<MidiInstruction>) -> MidiAST {
+        let mut builder = MidiASTBuilder::new();
+        for inst in insts {
+            builder.push(inst).unwrap();
+        }
+        builder.into_mast().unwrap()
+    }
+
+    #[test]
+    fn compiles_a_loop() {
+        // moves cell 0 into cell 1 times 3: [->+++<]
+        let body = [Op::Add(Wrapping(-1)), Op::Move(1), Op::Add(Wrapping(3)), Op::Move(-1)];
+        // the code goes away with the JIT
+        let mut jit = LoopJit::new().unwrap();
+        let native = jit.compile_loop(&body, 1, CellWidth::I8).unwrap();
+        let mut tape = vec![Wrapping(100), Wrapping(0)];
+        let steps = unsafe { native(tape.as_mut_ptr(), 0) };
+        assert_eq!(tape, [Wrapping(0), Wrapping(44)]);
+        assert_eq!(steps, 500);
+    }
+
+    #[test]
+    fn hot_loops_match_the_interpreter() {
+        // runs a loop with an inner loop 2000 times, so both get hot
+        let prog = build(vec![
+            MidiInstruction::new_inc(Wrapping(10)),
+            MidiInstruction::new_open_loop(),
+            MidiInstruction::new_move(1),
+            MidiInstruction::new_inc(Wrapping(200)),
+            MidiInstruction::new_open_loop(),
+            MidiInstruction::new_move(1),
+            MidiInstruction::new_inc(Wrapping(7)),
+            MidiInstruction::new_open_loop(),
+            MidiInstruction::new_inc(Wrapping(-1)),
+            MidiInstruction::new_move(1),
+            MidiInstruction::new_inc(Wrapping(3)),
+            MidiInstruction::new_move(1),
+            MidiInstruction::new_inc(Wrapping(-1)),
+            MidiInstruction::new_move(-2),
+            MidiInstruction::new_close_loop(),
+            MidiInstruction::new_move(-1),
+            MidiInstruction::new_inc(Wrapping(-1)),
+            MidiInstruction::new_close_loop(),
+            MidiInstruction::new_move(-1),
+            MidiInstruction::new_inc(Wrapping(-1)),
+            MidiInstruction::new_close_loop(),
+            MidiInstruction::new_move(3),
+            MidiInstruction::new_output(),
+        ]);
+        for width in [CellWidth::I8, CellWidth::I16, CellWidth::I32] {
+            let mut interp = Interpreter::new();
+            interp.set_cell_width(width);
+            let mut interp_out = vec![];
+            interp.run(&prog, &mut io::empty(), &mut interp_out).unwrap();
+
+            let mut vm = Vm::new();
+            vm.set_cell_width(width);
+            vm.set_loop_compiler(Box::new(LoopJit::new().unwrap()));
+            let mut vm_out = vec![];
+            vm.run(&Bytecode::new(&prog), &mut io::empty(), &mut vm_out).unwrap();
+            assert!(interp.steps() > TIER_UP_THRESHOLD as usize * 10);
+            assert_eq!(vm_out, interp_out);
+            assert_eq!(vm.tape(), interp.tape());
+            assert_eq!(vm.pointer(), interp.pointer());
+            assert_eq!(vm.steps(), interp.steps());
+        }
+    }
+}
@@ -0,0 +1,1219 @@
+//! Safe wrappers around the parts of the LLVM C API the compiler uses.
+//!
+//! A `Module` owns the context it lives in, and every type, value, block and
+//! builder borrows the module it came from, so the borrow checker keeps them
+//! from outliving it. Names are taken as `&str` and only turned into C strings
+//! for the duration of each call.
+
+use std::ffi::{CStr, CString};
+use std::marker::PhantomData;
+use std::os::raw::c_char;
+use std::path::Path;
+use std::ptr;
+use std::sync::Once;
+
+use llvm_sys::analysis::*;
+use llvm_sys::bit_writer::{LLVMWriteBitcodeToFile, LLVMWriteBitcodeToMemoryBuffer};
+use llvm_sys::core::*;
+use llvm_sys::debuginfo::*;
+use llvm_sys::error::*;
+use llvm_sys::orc2::lljit::*;
+use llvm_sys::orc2::*;
+use llvm_sys::prelude::*;
+use llvm_sys::target::*;
+use llvm_sys::target_machine::*;
+use llvm_sys::transforms::pass_builder::*;
+use llvm_sys::LLVMAttributeFunctionIndex;
+use log::debug;
+
+pub use llvm_sys::target_machine::{
+    LLVMCodeGenFileType as FileType, LLVMCodeGenOptLevel as CodeGenOptLevel,
+};
+pub use llvm_sys::{LLVMIntPredicate as IntPredicate, LLVMLinkage as Linkage};
+
+/// Backends compiled programs can target, as the `TargetInfo`, `Target`,
+/// `TargetMC` and `AsmPrinter` initializers of each.
+const TARGET_INITIALIZERS: [[unsafe extern "C" fn(); 4]; 5] = [
+    [
+        LLVMInitializeX86TargetInfo,
+        LLVMInitializeX86Target,
+        LLVMInitializeX86TargetMC,
+        LLVMInitializeX86AsmPrinter,
+    ],
+    [
+        LLVMInitializeAArch64TargetInfo,
+        LLVMInitializeAArch64Target,
+        LLVMInitializeAArch64TargetMC,
+        LLVMInitializeAArch64AsmPrinter,
+    ],
+    [
+        LLVMInitializeARMTargetInfo,
+        LLVMInitializeARMTarget,
+        LLVMInitializeARMTargetMC,
+        LLVMInitializeARMAsmPrinter,
+    ],
+    [
+        LLVMInitializeRISCVTargetInfo,
+        LLVMInitializeRISCVTarget,
+        LLVMInitializeRISCVTargetMC,
+        LLVMInitializeRISCVAsmPrinter,
+    ],
+    [
+        LLVMInitializeWebAssemblyTargetInfo,
+        LLVMInitializeWebAssemblyTarget,
+        LLVMInitializeWebAssemblyTargetMC,
+        LLVMInitializeWebAssemblyAsmPrinter,
+    ],
+];
+
+static INIT_TARGETS: Once = Once::new();
+
+/// Copies `s` into a C string, cutting it off at the first nul byte.
+fn c_string(s: &str) -> CString {
+    let end = s.find('\0').unwrap_or(s.len());
+    CString::new(&s[..end]).unwrap_or_default()
+}
+
+/// Output paths can't be cut short like names, so they fail instead.
+fn c_path(path: &Path) -> Result<CString, String> {
+    CString::new(path.to_string_lossy().as_bytes())
+        .map_err(|_| format!("Invalid output path {}", path.display()))
+}
+
+/// Takes ownership of a message allocated by LLVM.
+unsafe fn take_message(message: *mut c_char) -> String {
+    if message.is_null() {
+        return String::new();
+    }
+    let string = CStr::from_ptr(message).to_string_lossy().into_owned();
+    LLVMDisposeMessage(message);
+    string
+}
+
+/// Copies out the contents of a memory buffer allocated by LLVM and frees it.
+unsafe fn take_buffer(buffer: LLVMMemoryBufferRef) -> Vec<u8> {
+    let start = LLVMGetBufferStart(buffer) as *const u8;
+    let bytes = std::slice::from_raw_parts(start, LLVMGetBufferSize(buffer)).to_vec();
+    LLVMDisposeMemoryBuffer(buffer);
+    bytes
+}
+
+/// Takes ownership of an error returned by LLVM, `None` when there wasn't one.
+unsafe fn take_error(error: LLVMErrorRef) -> Option<String> {
+    if error.is_null() {
+        return None;
+    }
+    let message = LLVMGetErrorMessage(error);
+    let string = CStr::from_ptr(message).to_string_lossy().into_owned();
+    LLVMDisposeErrorMessage(message);
+    Some(string)
+}
+
+fn init_targets() {
+    INIT_TARGETS.call_once(|| {
+        for initializers in TARGET_INITIALIZERS {
+            for init in initializers {
+                unsafe { init() };
+            }
+        }
+    });
+}
+
+unsafe fn value_name(value: LLVMValueRef) -> String {
+    let mut len = 0;
+    let name = LLVMGetValueName2(value, &mut len);
+    String::from_utf8_lossy(std::slice::from_raw_parts(name as *const u8, len)).into_owned()
+}
+
+/// Triple of the machine midilang is running on.
+pub fn host_triple() -> String {
+    unsafe { take_message(LLVMGetDefaultTargetTriple()) }
+}
+
+/// Code generator for a single target.
+pub struct TargetMachine {
+    raw: LLVMTargetMachineRef,
+}
+
+impl TargetMachine {
+    /// Creates a target machine for `triple`, or for the host when it's `None`.
+    ///
+    /// Host builds are tuned for the host CPU, cross builds use the backend's default CPU.
+    pub fn new(triple: Option<&str>, opt_level: CodeGenOptLevel) -> Result<Self, String> {
+        init_targets();
+        let (triple, cpu, features) = match triple {
+            Some(triple) => {
+                if triple.contains('\0') {
+                    return Err(format!("Invalid target triple {:?}", triple));
+                }
+                let triple = c_string(triple);
+                let normalized =
+                    unsafe { take_message(LLVMNormalizeTargetTriple(triple.as_ptr())) };
+                // an empty CPU lets each backend pick its own baseline
+                (normalized, String::new(), String::new())
+            }
+            None => unsafe {
+                (
+                    host_triple(),
+                    take_message(LLVMGetHostCPUName()),
+                    take_message(LLVMGetHostCPUFeatures()),
+                )
+            },
+        };
+        debug!("Targeting {} ({})", triple, cpu);
+        let triple_c = c_string(&triple);
+        let cpu = c_string(&cpu);
+        let features = c_string(&features);
+
+        unsafe {
+            let mut target = ptr::null_mut();
+            let mut error = ptr::null_mut();
+            if LLVMGetTargetFromTriple(triple_c.as_ptr(), &mut target, &mut error) != 0 {
+                return Err(format!(
+                    "Unsupported target {}: {}",
+                    triple,
+                    take_message(error)
+                ));
+            }
+            let raw = LLVMCreateTargetMachine(
+                target,
+                triple_c.as_ptr(),
+                cpu.as_ptr(),
+                features.as_ptr(),
+                opt_level,
+                LLVMRelocMode::LLVMRelocPIC,
+                LLVMCodeModel::LLVMCodeModelDefault,
+            );
+            if raw.is_null() {
+                return Err(format!("Could not create a target machine for {}", triple));
+            }
+            Ok(TargetMachine { raw })
+        }
+    }
+
+    /// Normalized triple this machine generates code for.
+    pub fn triple(&self) -> String {
+        unsafe { take_message(LLVMGetTargetMachineTriple(self.raw)) }
+    }
+
+    /// Writes `module` out as an assembly or object file.
+    pub fn emit_to_file(
+        &self,
+        module: &Module,
+        path: &Path,
+        file_type: FileType,
+    ) -> Result<(), String> {
+        let path = c_path(path)?;
+        unsafe {
+            let mut error = ptr::null_mut();
+            // LLVM doesn't actually write through the filename
+            if LLVMTargetMachineEmitToFile(
+                self.raw,
+                module.raw,
+                path.as_ptr() as *mut c_char,
+                file_type,
+                &mut error,
+            ) != 0
+            {
+                return Err(take_message(error));
+            }
+        }
+        Ok(())
+    }
+
+    /// Like `emit_to_file`, returning the file's contents instead.
+    pub fn emit_to_memory(&self, module: &Module, file_type: FileType) -> Result<Vec<u8>, String> {
+        unsafe {
+            let mut error = ptr::null_mut();
+            let mut buffer = ptr::null_mut();
+            if LLVMTargetMachineEmitToMemoryBuffer(self.raw, module.raw, file_type, &mut error, &mut buffer) != 0 {
+                return Err(take_message(error));
+            }
+            Ok(take_buffer(buffer))
+        }
+    }
+}
+
+impl Drop for TargetMachine {
+    fn drop(&mut self) {
+        unsafe { LLVMDisposeTargetMachine(self.raw) }
+    }
+}
+
+/// A module along with the context that owns everything in it.
+pub struct Module {
+    context: LLVMContextRef,
+    raw: LLVMModuleRef,
+    /// Modules built for a `Jit` live in its context, which they mustn't dispose
+    owns_context: bool,
+}
+
+impl Module {
+    /// Creates an empty module in a context of its own.
+    pub fn new(name: &str) -> Self {
+        let name = c_string(name);
+        unsafe {
+            let context = LLVMContextCreate();
+            let raw = LLVMModuleCreateWithNameInContext(name.as_ptr(), context);
+            Module {
+                context,
+                raw,
+                owns_context: true,
+            }
+        }
+    }
+
+    /// Sets the triple and data layout to the ones of `machine`.
+    pub fn set_target(&self, machine: &TargetMachine) {
+        let triple = c_string(&machine.triple());
+        unsafe {
+            LLVMSetTarget(self.raw, triple.as_ptr());
+            // the module keeps its own copy of the layout
+            let target_data = LLVMCreateTargetDataLayout(machine.raw);
+            LLVMSetModuleDataLayout(self.raw, target_data);
+            LLVMDisposeTargetData(target_data);
+        }
+    }
+
+    pub fn int_type(&self, bits: u32) -> Type<'_> {
+        unsafe { Type::new(LLVMIntTypeInContext(self.context, bits)) }
+    }
+
+    pub fn bool_type(&self) -> Type<'_> {
+        self.int_type(1)
+    }
+
+    pub fn void_type(&self) -> Type<'_> {
+        unsafe { Type::new(LLVMVoidTypeInContext(self.context)) }
+    }
+
+    /// Integer type as wide as a pointer in the module's data layout.
+    pub fn int_ptr_type(&self) -> Type<'_> {
+        unsafe {
+            Type::new(LLVMIntPtrTypeInContext(
+                self.context,
+                LLVMGetModuleDataLayout(self.raw),
+            ))
+        }
+    }
+
+    pub fn struct_type<'m>(&'m self, fields: &[Type<'m>]) -> Type<'m> {
+        let mut fields: Vec<_> = fields.iter().map(|field| field.raw).collect();
+        unsafe {
+            Type::new(LLVMStructTypeInContext(
+                self.context,
+                fields.as_mut_ptr(),
+                fields.len() as u32,
+                0,
+            ))
+        }
+    }
+
+    /// Pointer to `pointee`, which only matters for LLVM versions with typed pointers.
+    #[cfg(feature = "llvm14")]
+    pub fn ptr_type<'m>(&'m self, pointee: Type<'m>) -> Type<'m> {
+        unsafe { Type::new(LLVMPointerType(pointee.raw, 0)) }
+    }
+
+    /// Pointer to `pointee`, which only matters for LLVM versions with typed pointers.
+    #[cfg(not(feature = "llvm14"))]
+    pub fn ptr_type<'m>(&'m self, _pointee: Type<'m>) -> Type<'m> {
+        unsafe { Type::new(LLVMPointerTypeInContext(self.context, 0)) }
+    }
+
+    pub fn add_function<'m>(&'m self, name: &str, fn_type: Type<'m>) -> Function<'m> {
+        let name = c_string(name);
+        unsafe { Function::new(LLVMAddFunction(self.raw, name.as_ptr(), fn_type.raw)) }
+    }
+
+    pub fn function(&self, name: &str) -> Option<Function<'_>> {
+        let name = c_string(name);
+        let raw = unsafe { LLVMGetNamedFunction(self.raw, name.as_ptr()) };
+        (!raw.is_null()).then(|| Function::new(raw))
+    }
+
+    pub fn functions(&self) -> Functions<'_> {
+        Functions {
+            next: unsafe { LLVMGetFirstFunction(self.raw) },
+            module: PhantomData,
+        }
+    }
+
+    /// Adds a global of type `ty`, zeroed unless given another initializer.
+    pub fn add_global<'m>(&'m self, ty: Type<'m>, name: &str) -> Value<'m> {
+        let name = c_string(name);
+        unsafe {
+            let global = Value::new(LLVMAddGlobal(self.raw, ty.raw, name.as_ptr()));
+            global.set_initializer(ty.const_null());
+            global
+        }
+    }
+
+    pub fn append_block<'m>(&'m self, function: Function<'m>, name: &str) -> BasicBlock<'m> {
+        let name = c_string(name);
+        unsafe {
+            BasicBlock::new(LLVMAppendBasicBlockInContext(
+                self.context,
+                function.raw,
+                name.as_ptr(),
+            ))
+        }
+    }
+
+    pub fn add_function_attribute(&self, function: Function<'_>, key: &str, value: &str) {
+        unsafe {
+            let attr = LLVMCreateStringAttribute(
+                self.context,
+                key.as_ptr() as *const c_char,
+                key.len() as u32,
+                value.as_ptr() as *const c_char,
+                value.len() as u32,
+            );
+            LLVMAddAttributeAtIndex(function.raw, LLVMAttributeFunctionIndex, attr);
+        }
+    }
+
+    /// Id of the metadata kind called `name`, registering it if it's new.
+    pub fn metadata_kind(&self, name: &str) -> u32 {
+        unsafe {
+            LLVMGetMDKindIDInContext(
+                self.context,
+                name.as_ptr() as *const c_char,
+                name.len() as u32,
+            )
+        }
+    }
+
+    /// Attaches `!{!"text"}` to the instruction `value` as metadata of kind `kind`.
+    pub fn set_string_metadata(&self, value: Value<'_>, kind: u32, text: &str) {
+        unsafe {
+            let mut string =
+                LLVMMDStringInContext2(self.context, text.as_ptr() as *const c_char, text.len());
+            let node = LLVMMDNodeInContext2(self.context, &mut string, 1);
+            LLVMSetMetadata(value.raw, kind, LLVMMetadataAsValue(self.context, node));
+        }
+    }
+
+    /// Adds a `!{!"key", !"value"}` node for every pair to the module level named
+    /// metadata `name`.
+    pub fn add_named_string_metadata(&self, name: &str, pairs: &[(&str, &str)]) {
+        let name = c_string(name);
+        let md_string = |text: &str| unsafe {
+            LLVMMDStringInContext2(self.context, text.as_ptr() as *const c_char, text.len())
+        };
+        for (key, value) in pairs {
+            let mut strings = [md_string(key), md_string(value)];
+            unsafe {
+                let node = LLVMMDNodeInContext2(self.context, strings.as_mut_ptr(), strings.len());
+                LLVMAddNamedMetadataOperand(
+                    self.raw,
+                    name.as_ptr(),
+                    LLVMMetadataAsValue(self.context, node),
+                );
+            }
+        }
+    }
+
+    /// Constant `[N x i8]` holding `bytes` as they are, without adding a NUL.
+    pub fn const_bytes(&self, bytes: &[u8]) -> Value<'_> {
+        unsafe {
+            Value::new(LLVMConstStringInContext(
+                self.context,
+                bytes.as_ptr() as *const c_char,
+                bytes.len() as u32,
+                1,
+            ))
+        }
+    }
+
+    /// Location of line `line` in `scope`, line 0 meaning code that belongs to no line.
+    pub fn debug_location<'m>(
+        &'m self,
+        line: u32,
+        column: u32,
+        scope: Metadata<'m>,
+    ) -> Metadata<'m> {
+        unsafe {
+            Metadata::new(LLVMDIBuilderCreateDebugLocation(
+                self.context,
+                line,
+                column,
+                scope.raw,
+                ptr::null_mut(),
+            ))
+        }
+    }
+
+    pub fn create_builder(&self) -> Builder<'_> {
+        Builder {
+            raw: unsafe { LLVMCreateBuilderInContext(self.context) },
+            module: PhantomData,
+        }
+    }
+
+    /// Runs the LLVM verifier, returning its complaints when the module is broken.
+    pub fn verify(&self) -> Result<(), String> {
+        unsafe {
+            let mut message = ptr::null_mut();
+            let broken = LLVMVerifyModule(
+                self.raw,
+                LLVMVerifierFailureAction::LLVMReturnStatusAction,
+                &mut message,
+            );
+            let message = take_message(message);
+            if broken == 0 {
+                Ok(())
+            } else {
+                Err(message)
+            }
+        }
+    }
+
+    /// Runs a new pass manager pipeline such as `default<O2>`.
+    pub fn run_passes(&self, pipeline: &str, machine: &TargetMachine) -> Result<(), String> {
+        let pipeline = c_string(pipeline);
+        unsafe {
+            let options = LLVMCreatePassBuilderOptions();
+            let error = LLVMRunPasses(self.raw, pipeline.as_ptr(), machine.raw, options);
+            LLVMDisposePassBuilderOptions(options);
+            if let Some(message) = take_error(error) {
+                return Err(message);
+            }
+        }
+        Ok(())
+    }
+
+    pub fn print_to_string(&self) -> String {
+        unsafe { take_message(LLVMPrintModuleToString(self.raw)) }
+    }
+
+    pub fn print_to_file(&self, path: &Path) -> Result<(), String> {
+        let path = c_path(path)?;
+        unsafe {
+            let mut error = ptr::null_mut();
+            if LLVMPrintModuleToFile(self.raw, path.as_ptr(), &mut error) != 0 {
+                return Err(take_message(error));
+            }
+        }
+        Ok(())
+    }
+
+    pub fn write_bitcode_to_file(&self, path: &Path) -> Result<(), String> {
+        let path_c = c_path(path)?;
+        if unsafe { LLVMWriteBitcodeToFile(self.raw, path_c.as_ptr()) } != 0 {
+            return Err(format!("Could not write {}", path.display()));
+        }
+        Ok(())
+    }
+
+    pub fn write_bitcode_to_memory(&self) -> Vec<u8> {
+        unsafe { take_buffer(LLVMWriteBitcodeToMemoryBuffer(self.raw)) }
+    }
+}
+
+impl Drop for Module {
+    fn drop(&mut self) {
+        unsafe {
+            LLVMDisposeModule(self.raw);
+            if self.owns_context {
+                LLVMContextDispose(self.context);
+            }
+        }
+    }
+}
+
+/// Compiles modules to native code in memory with ORC's LLJIT, keeping the code
+/// alive for as long as the JIT is.
+pub struct Jit {
+    raw: LLVMOrcLLJITRef,
+    /// Context every module added to the JIT is built in
+    context: LLVMOrcThreadSafeContextRef,
+    /// Optimizes modules before the JIT compiles them, which LLJIT doesn't do itself
+    machine: TargetMachine,
+}
+
+impl Jit {
+    pub fn new() -> Result<Self, String> {
+        init_targets();
+        let machine = TargetMachine::new(None, CodeGenOptLevel::LLVMCodeGenLevelDefault)?;
+        unsafe {
+            let mut raw = ptr::null_mut();
+            // a null builder makes a JIT for the host
+            if let Some(message) = take_error(LLVMOrcCreateLLJIT(&mut raw, ptr::null_mut())) {
+                return Err(message);
+            }
+            Ok(Jit {
+                raw,
+                context: LLVMOrcCreateNewThreadSafeContext(),
+                machine,
+            })
+        }
+    }
+
+    /// Builds a module with `build`, optimizes it and compiles it, returning the
+    /// address of the function called `name` in it.
+    pub fn compile(&self, name: &str, build: impl FnOnce(&Module)) -> Result<u64, String> {
+        let module_name = c_string(name);
+        let module = unsafe {
+            let context = LLVMOrcThreadSafeContextGetContext(self.context);
+            Module {
+                context,
+                raw: LLVMModuleCreateWithNameInContext(module_name.as_ptr(), context),
+                owns_context: false,
+            }
+        };
+        module.set_target(&self.machine);
+        build(&module);
+        module.verify()?;
+        module.run_passes("default<O2>", &self.machine)?;
+        let name = c_string(name);
+        unsafe {
+            // the JIT takes the module over, even when adding it fails
+            let raw_module = module.raw;
+            std::mem::forget(module);
+            let thread_safe = LLVMOrcCreateNewThreadSafeModule(raw_module, self.context);
+            let dylib = LLVMOrcLLJITGetMainJITDylib(self.raw);
+            if let Some(message) = take_error(LLVMOrcLLJITAddLLVMIRModule(self.raw, dylib, thread_safe)) {
+                return Err(message);
+            }
+            let mut address = 0;
+            if let Some(message) = take_error(LLVMOrcLLJITLookup(self.raw, &mut address, name.as_ptr())) {
+                return Err(message);
+            }
+            Ok(address)
+        }
+    }
+}
+
+impl Drop for Jit {
+    fn drop(&mut self) {
+        unsafe {
+            take_error(LLVMOrcDisposeLLJIT(self.raw));
+            LLVMOrcDisposeThreadSafeContext(self.context);
+        }
+    }
+}
+
+/// A type in the context of the module `'m`.
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub struct Type<'m> {
+    raw: LLVMTypeRef,
+    module: PhantomData<&'m Module>,
+}
+
+impl<'m> Type<'m> {
+    unsafe fn new(raw: LLVMTypeRef) -> Self {
+        Type {
+            raw,
+            module: PhantomData,
+        }
+    }
+
+    /// Function type returning `self`.
+    pub fn fn_type(self, params: &[Type<'m>]) -> Type<'m> {
+        let mut params: Vec<_> = params.iter().map(|param| param.raw).collect();
+        unsafe {
+            Type::new(LLVMFunctionType(
+                self.raw,
+                params.as_mut_ptr(),
+                params.len() as u32,
+                0,
+            ))
+        }
+    }
+
+    /// Function type returning `self` that takes extra arguments after `params`, like `open`.
+    pub fn var_arg_fn_type(self, params: &[Type<'m>]) -> Type<'m> {
+        let mut params: Vec<_> = params.iter().map(|param| param.raw).collect();
+        unsafe {
+            Type::new(LLVMFunctionType(
+                self.raw,
+                params.as_mut_ptr(),
+                params.len() as u32,
+                1,
+            ))
+        }
+    }
+
+    /// Array of `len` elements of this type.
+    pub fn array_type(self, len: u32) -> Type<'m> {
+        unsafe { Type::new(LLVMArrayType(self.raw, len)) }
+    }
+
+    /// Integer constant, `value` is truncated to the width of the type.
+    pub fn const_int(self, value: u64, sign_extend: bool) -> Value<'m> {
+        unsafe { Value::new(LLVMConstInt(self.raw, value, sign_extend as LLVMBool)) }
+    }
+
+    /// Constant array of `values`, each of this type.
+    pub fn const_array(self, values: &[Value<'m>]) -> Value<'m> {
+        let mut values: Vec<_> = values.iter().map(|value| value.raw).collect();
+        unsafe { Value::new(LLVMConstArray(self.raw, values.as_mut_ptr(), values.len() as u32)) }
+    }
+
+    pub fn const_null(self) -> Value<'m> {
+        unsafe { Value::new(LLVMConstNull(self.raw)) }
+    }
+
+    pub fn const_all_ones(self) -> Value<'m> {
+        unsafe { Value::new(LLVMConstAllOnes(self.raw)) }
+    }
+}
+
+/// An instruction, constant, global or argument in the module `'m`.
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub struct Value<'m> {
+    raw: LLVMValueRef,
+    module: PhantomData<&'m Module>,
+}
+
+impl<'m> Value<'m> {
+    unsafe fn new(raw: LLVMValueRef) -> Self {
+        Value {
+            raw,
+            module: PhantomData,
+        }
+    }
+
+    /// `None` for null pointers coming back from LLVM.
+    fn from_raw(raw: LLVMValueRef) -> Option<Self> {
+        (!raw.is_null()).then_some(Value {
+            raw,
+            module: PhantomData,
+        })
+    }
+
+    pub fn set_linkage(self, linkage: Linkage) {
+        unsafe { LLVMSetLinkage(self.raw, linkage) }
+    }
+
+    pub fn set_initializer(self, value: Value<'m>) {
+        unsafe { LLVMSetInitializer(self.raw, value.raw) }
+    }
+
+    /// Marks a global as never written to.
+    pub fn set_constant(self, constant: bool) {
+        unsafe { LLVMSetGlobalConstant(self.raw, constant as i32) }
+    }
+
+    /// Puts a global in the object file section `section`.
+    pub fn set_section(self, section: &str) {
+        let section = c_string(section);
+        unsafe { LLVMSetSection(self.raw, section.as_ptr()) }
+    }
+
+    /// Adds the values a phi takes when coming from each block.
+    pub fn add_incoming(self, incoming: &[(Value<'m>, BasicBlock<'m>)]) {
+        let mut values: Vec<_> = incoming.iter().map(|(value, _)| value.raw).collect();
+        let mut blocks: Vec<_> = incoming.iter().map(|(_, block)| block.raw).collect();
+        unsafe {
+            LLVMAddIncoming(
+                self.raw,
+                values.as_mut_ptr(),
+                blocks.as_mut_ptr(),
+                incoming.len() as u32,
+            )
+        }
+    }
+
+    /// Instruction after this one in its block.
+    pub fn next_instruction(self) -> Option<Value<'m>> {
+        Value::from_raw(unsafe { LLVMGetNextInstruction(self.raw) })
+    }
+
+    /// Empty for unnamed values such as stores and calls to void functions.
+    pub fn name(self) -> String {
+        unsafe { value_name(self.raw) }
+    }
+
+    /// Names the value, LLVM adds a number when the name is already taken.
+    pub fn set_name(self, name: &str) {
+        unsafe { LLVMSetValueName2(self.raw, name.as_ptr() as *const c_char, name.len()) }
+    }
+
+    /// Whether the instruction `self` has metadata of kind `kind` attached.
+    pub fn has_metadata(self, kind: u32) -> bool {
+        unsafe { !LLVMGetMetadata(self.raw, kind).is_null() }
+    }
+
+    /// Whether the instruction `self` has a debug location.
+    pub fn has_debug_location(self) -> bool {
+        unsafe { !LLVMInstructionGetDebugLoc(self.raw).is_null() }
+    }
+
+    pub fn set_debug_location(self, location: Metadata<'m>) {
+        unsafe { LLVMInstructionSetDebugLoc(self.raw, location.raw) }
+    }
+
+    pub fn print_to_string(self) -> String {
+        unsafe { take_message(LLVMPrintValueToString(self.raw)) }
+    }
+
+    #[cfg(test)]
+    pub fn is_load(self) -> bool {
+        unsafe { !LLVMIsALoadInst(self.raw).is_null() }
+    }
+
+    /// Unlinks the instruction from its block without deleting it, for breaking
+    /// modules on purpose.
+    #[cfg(test)]
+    pub fn remove_from_parent(self) {
+        unsafe { LLVMInstructionRemoveFromParent(self.raw) }
+    }
+}
+
+/// A function in the module `'m`.
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub struct Function<'m> {
+    raw: LLVMValueRef,
+    module: PhantomData<&'m Module>,
+}
+
+impl<'m> Function<'m> {
+    fn new(raw: LLVMValueRef) -> Self {
+        Function {
+            raw,
+            module: PhantomData,
+        }
+    }
+
+    pub fn param(self, index: u32) -> Value<'m> {
+        unsafe { Value::new(LLVMGetParam(self.raw, index)) }
+    }
+
+    pub fn set_linkage(self, linkage: Linkage) {
+        unsafe { LLVMSetLinkage(self.raw, linkage) }
+    }
+
+    pub fn name(self) -> String {
+        unsafe { value_name(self.raw) }
+    }
+
+    pub fn first_block(self) -> Option<BasicBlock<'m>> {
+        BasicBlock::from_raw(unsafe { LLVMGetFirstBasicBlock(self.raw) })
+    }
+
+    pub fn last_block(self) -> Option<BasicBlock<'m>> {
+        BasicBlock::from_raw(unsafe { LLVMGetLastBasicBlock(self.raw) })
+    }
+
+    /// Whether the verifier is happy with this function alone.
+    pub fn is_valid(self) -> bool {
+        unsafe {
+            LLVMVerifyFunction(self.raw, LLVMVerifierFailureAction::LLVMReturnStatusAction) == 0
+        }
+    }
+}
+
+/// Iterator over the functions of a module.
+pub struct Functions<'m> {
+    next: LLVMValueRef,
+    module: PhantomData<&'m Module>,
+}
+
+impl<'m> Iterator for Functions<'m> {
+    type Item = Function<'m>;
+
+    fn next(&mut self) -> Option<Function<'m>> {
+        if self.next.is_null() {
+            return None;
+        }
+        let function = Function::new(self.next);
+        self.next = unsafe { LLVMGetNextFunction(self.next) };
+        Some(function)
+    }
+}
+
+/// A basic block in the module `'m`.
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub struct BasicBlock<'m> {
+    raw: LLVMBasicBlockRef,
+    module: PhantomData<&'m Module>,
+}
+
+impl<'m> BasicBlock<'m> {
+    unsafe fn new(raw: LLVMBasicBlockRef) -> Self {
+        BasicBlock {
+            raw,
+            module: PhantomData,
+        }
+    }
+
+    fn from_raw(raw: LLVMBasicBlockRef) -> Option<Self> {
+        (!raw.is_null()).then_some(BasicBlock {
+            raw,
+            module: PhantomData,
+        })
+    }
+
+    pub fn first_instruction(self) -> Option<Value<'m>> {
+        Value::from_raw(unsafe { LLVMGetFirstInstruction(self.raw) })
+    }
+
+    pub fn last_instruction(self) -> Option<Value<'m>> {
+        Value::from_raw(unsafe { LLVMGetLastInstruction(self.raw) })
+    }
+
+    /// Block after this one in its function.
+    pub fn next(self) -> Option<BasicBlock<'m>> {
+        BasicBlock::from_raw(unsafe { LLVMGetNextBasicBlock(self.raw) })
+    }
+}
+
+/// Emits instructions into blocks of the module `'m`.
+pub struct Builder<'m> {
+    raw: LLVMBuilderRef,
+    module: PhantomData<&'m Module>,
+}
+
+impl<'m> Builder<'m> {
+    pub fn position_at_end(&self, block: BasicBlock<'m>) {
+        unsafe { LLVMPositionBuilderAtEnd(self.raw, block.raw) }
+    }
+
+    pub fn insert_block(&self) -> Option<BasicBlock<'m>> {
+        BasicBlock::from_raw(unsafe { LLVMGetInsertBlock(self.raw) })
+    }
+
+    fn value(&self, raw: LLVMValueRef) -> Value<'m> {
+        // everything built here belongs to the module the builder came from
+        unsafe { Value::new(raw) }
+    }
+
+    pub fn alloca(&self, ty: Type<'m>, name: &str) -> Value<'m> {
+        let name = c_string(name);
+        self.value(unsafe { LLVMBuildAlloca(self.raw, ty.raw, name.as_ptr()) })
+    }
+
+    pub fn load(&self, ty: Type<'m>, ptr: Value<'m>, name: &str) -> Value<'m> {
+        let name = c_string(name);
+        self.value(unsafe { LLVMBuildLoad2(self.raw, ty.raw, ptr.raw, name.as_ptr()) })
+    }
+
+    pub fn store(&self, value: Value<'m>, ptr: Value<'m>) -> Value<'m> {
+        self.value(unsafe { LLVMBuildStore(self.raw, value.raw, ptr.raw) })
+    }
+
+    /// Address of `indices` into `ptr`, which points to a `ty`.
+    pub fn gep(
+        &self,
+        ty: Type<'m>,
+        ptr: Value<'m>,
+        indices: &[Value<'m>],
+        name: &str,
+    ) -> Value<'m> {
+        let name = c_string(name);
+        let mut indices: Vec<_> = indices.iter().map(|index| index.raw).collect();
+        self.value(unsafe {
+            LLVMBuildGEP2(
+                self.raw,
+                ty.raw,
+                ptr.raw,
+                indices.as_mut_ptr(),
+                indices.len() as u32,
+                name.as_ptr(),
+            )
+        })
+    }
+
+    /// Address of field `field` of the struct `ty` at `ptr`.
+    pub fn struct_gep(&self, ty: Type<'m>, ptr: Value<'m>, field: u32, name: &str) -> Value<'m> {
+        let name = c_string(name);
+        self.value(unsafe { LLVMBuildStructGEP2(self.raw, ty.raw, ptr.raw, field, name.as_ptr()) })
+    }
+
+    /// Calls `function` using its declared type.
+    pub fn call(&self, function: Function<'m>, args: &[Value<'m>], name: &str) -> Value<'m> {
+        let name = c_string(name);
+        let mut args: Vec<_> = args.iter().map(|arg| arg.raw).collect();
+        self.value(unsafe {
+            let fn_type = LLVMGlobalGetValueType(function.raw);
+            LLVMBuildCall2(
+                self.raw,
+                fn_type,
+                function.raw,
+                args.as_mut_ptr(),
+                args.len() as u32,
+                name.as_ptr(),
+            )
+        })
+    }
+
+    pub fn icmp(
+        &self,
+        predicate: IntPredicate,
+        lhs: Value<'m>,
+        rhs: Value<'m>,
+        name: &str,
+    ) -> Value<'m> {
+        let name = c_string(name);
+        self.value(unsafe { LLVMBuildICmp(self.raw, predicate, lhs.raw, rhs.raw, name.as_ptr()) })
+    }
+
+    pub fn is_null(&self, value: Value<'m>, name: &str) -> Value<'m> {
+        let name = c_string(name);
+        self.value(unsafe { LLVMBuildIsNull(self.raw, value.raw, name.as_ptr()) })
+    }
+
+    pub fn add(&self, lhs: Value<'m>, rhs: Value<'m>, name: &str) -> Value<'m> {
+        let name = c_string(name);
+        self.value(unsafe { LLVMBuildAdd(self.raw, lhs.raw, rhs.raw, name.as_ptr()) })
+    }
+
+    pub fn sub(&self, lhs: Value<'m>, rhs: Value<'m>, name: &str) -> Value<'m> {
+        let name = c_string(name);
+        self.value(unsafe { LLVMBuildSub(self.raw, lhs.raw, rhs.raw, name.as_ptr()) })
+    }
+
+    pub fn mul(&self, lhs: Value<'m>, rhs: Value<'m>, name: &str) -> Value<'m> {
+        let name = c_string(name);
+        self.value(unsafe { LLVMBuildMul(self.raw, lhs.raw, rhs.raw, name.as_ptr()) })
+    }
+
+    pub fn udiv(&self, lhs: Value<'m>, rhs: Value<'m>, name: &str) -> Value<'m> {
+        let name = c_string(name);
+        self.value(unsafe { LLVMBuildUDiv(self.raw, lhs.raw, rhs.raw, name.as_ptr()) })
+    }
+
+    pub fn urem(&self, lhs: Value<'m>, rhs: Value<'m>, name: &str) -> Value<'m> {
+        let name = c_string(name);
+        self.value(unsafe { LLVMBuildURem(self.raw, lhs.raw, rhs.raw, name.as_ptr()) })
+    }
+
+    pub fn shl(&self, lhs: Value<'m>, rhs: Value<'m>, name: &str) -> Value<'m> {
+        let name = c_string(name);
+        self.value(unsafe { LLVMBuildShl(self.raw, lhs.raw, rhs.raw, name.as_ptr()) })
+    }
+
+    pub fn lshr(&self, lhs: Value<'m>, rhs: Value<'m>, name: &str) -> Value<'m> {
+        let name = c_string(name);
+        self.value(unsafe { LLVMBuildLShr(self.raw, lhs.raw, rhs.raw, name.as_ptr()) })
+    }
+
+    pub fn and(&self, lhs: Value<'m>, rhs: Value<'m>, name: &str) -> Value<'m> {
+        let name = c_string(name);
+        self.value(unsafe { LLVMBuildAnd(self.raw, lhs.raw, rhs.raw, name.as_ptr()) })
+    }
+
+    pub fn or(&self, lhs: Value<'m>, rhs: Value<'m>, name: &str) -> Value<'m> {
+        let name = c_string(name);
+        self.value(unsafe { LLVMBuildOr(self.raw, lhs.raw, rhs.raw, name.as_ptr()) })
+    }
+
+    pub fn xor(&self, lhs: Value<'m>, rhs: Value<'m>, name: &str) -> Value<'m> {
+        let name = c_string(name);
+        self.value(unsafe { LLVMBuildXor(self.raw, lhs.raw, rhs.raw, name.as_ptr()) })
+    }
+
+    pub fn zext(&self, value: Value<'m>, ty: Type<'m>, name: &str) -> Value<'m> {
+        let name = c_string(name);
+        self.value(unsafe { LLVMBuildZExt(self.raw, value.raw, ty.raw, name.as_ptr()) })
+    }
+
+    /// Truncates or zero extends `value` to `ty`.
+    pub fn int_cast(&self, value: Value<'m>, ty: Type<'m>, name: &str) -> Value<'m> {
+        let name = c_string(name);
+        self.value(unsafe { LLVMBuildIntCast2(self.raw, value.raw, ty.raw, 0, name.as_ptr()) })
+    }
+
+    pub fn select(
+        &self,
+        cond: Value<'m>,
+        then: Value<'m>,
+        otherwise: Value<'m>,
+        name: &str,
+    ) -> Value<'m> {
+        let name = c_string(name);
+        self.value(unsafe {
+            LLVMBuildSelect(self.raw, cond.raw, then.raw, otherwise.raw, name.as_ptr())
+        })
+    }
+
+    /// Phi without any incoming values yet, see `Value::add_incoming`.
+    pub fn phi(&self, ty: Type<'m>, name: &str) -> Value<'m> {
+        let name = c_string(name);
+        self.value(unsafe { LLVMBuildPhi(self.raw, ty.raw, name.as_ptr()) })
+    }
+
+    /// Pointer to a private global holding `string` and a nul terminator.
+    pub fn global_string_ptr(&self, string: &str, name: &str) -> Value<'m> {
+        let string = c_string(string);
+        let name = c_string(name);
+        self.value(unsafe { LLVMBuildGlobalStringPtr(self.raw, string.as_ptr(), name.as_ptr()) })
+    }
+
+    pub fn br(&self, dest: BasicBlock<'m>) -> Value<'m> {
+        self.value(unsafe { LLVMBuildBr(self.raw, dest.raw) })
+    }
+
+    pub fn cond_br(
+        &self,
+        cond: Value<'m>,
+        then: BasicBlock<'m>,
+        otherwise: BasicBlock<'m>,
+    ) -> Value<'m> {
+        self.value(unsafe { LLVMBuildCondBr(self.raw, cond.raw, then.raw, otherwise.raw) })
+    }
+
+    pub fn ret(&self, value: Value<'m>) -> Value<'m> {
+        self.value(unsafe { LLVMBuildRet(self.raw, value.raw) })
+    }
+
+    pub fn ret_void(&self) -> Value<'m> {
+        self.value(unsafe { LLVMBuildRetVoid(self.raw) })
+    }
+
+    pub fn unreachable(&self) -> Value<'m> {
+        self.value(unsafe { LLVMBuildUnreachable(self.raw) })
+    }
+}
+
+impl Drop for Builder<'_> {
+    fn drop(&mut self) {
+        unsafe { LLVMDisposeBuilder(self.raw) }
+    }
+}
+
+/// A metadata node in the module `'m`, such as a debug info scope or location.
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub struct Metadata<'m> {
+    raw: LLVMMetadataRef,
+    module: PhantomData<&'m Module>,
+}
+
+impl Metadata<'_> {
+    unsafe fn new(raw: LLVMMetadataRef) -> Self {
+        Metadata {
+            raw,
+            module: PhantomData,
+        }
+    }
+}
+
+/// Builds DWARF debug info for the module `'m`, which gets finalized when this is dropped.
+pub struct DebugInfoBuilder<'m> {
+    raw: LLVMDIBuilderRef,
+    module: PhantomData<&'m Module>,
+}
+
+impl<'m> DebugInfoBuilder<'m> {
+    /// Also flags the module as carrying DWARF 4 debug info.
+    pub fn new(module: &'m Module) -> Self {
+        unsafe {
+            let i32_type = LLVMInt32TypeInContext(module.context);
+            for (key, value) in [
+                ("Debug Info Version", LLVMDebugMetadataVersion()),
+                ("Dwarf Version", 4),
+            ] {
+                let value = LLVMValueAsMetadata(LLVMConstInt(i32_type, u64::from(value), 0));
+                LLVMAddModuleFlag(
+                    module.raw,
+                    llvm_sys::LLVMModuleFlagBehavior::LLVMModuleFlagBehaviorWarning,
+                    key.as_ptr() as *const c_char,
+                    key.len(),
+                    value,
+                );
+            }
+            DebugInfoBuilder {
+                raw: LLVMCreateDIBuilder(module.raw),
+                module: PhantomData,
+            }
+        }
+    }
+
+    /// Adds a compile unit for the source file at `path`, returning the file.
+    pub fn create_compile_unit(
+        &self,
+        path: &Path,
+        producer: &str,
+        optimized: bool,
+    ) -> Metadata<'m> {
+        let name = path
+            .file_name()
+            .map(|name| name.to_string_lossy())
+            .unwrap_or_default();
+        let dir = path
+            .parent()
+            .map(|dir| dir.to_string_lossy())
+            .unwrap_or_default();
+        unsafe {
+            let file = LLVMDIBuilderCreateFile(
+                self.raw,
+                name.as_ptr() as *const c_char,
+                name.len(),
+                dir.as_ptr() as *const c_char,
+                dir.len(),
+            );
+            LLVMDIBuilderCreateCompileUnit(
+                self.raw,
+                // there's no language code for MIDI, C keeps debuggers happy
+                LLVMDWARFSourceLanguage::LLVMDWARFSourceLanguageC,
+                file,
+                producer.as_ptr() as *const c_char,
+                producer.len(),
+                optimized as LLVMBool,
+                ptr::null(),
+                0,
+                0,
+                ptr::null(),
+                0,
+                LLVMDWARFEmissionKind::LLVMDWARFEmissionKindFull,
+                0,
+                0,
+                0,
+                ptr::null(),
+                0,
+                ptr::null(),
+                0,
+            );
+            Metadata::new(file)
+        }
+    }
+
+    /// Describes `function` as defined at `line` of `file`, returning its scope.
+    pub fn create_function(
+        &self,
+        file: Metadata<'m>,
+        function: Function<'m>,
+        line: u32,
+        optimized: bool,
+    ) -> Metadata<'m> {
+        let name = function.name();
+        unsafe {
+            let fn_type = LLVMDIBuilderCreateSubroutineType(
+                self.raw,
+                file.raw,
+                ptr::null_mut(),
+                0,
+                LLVMDIFlagZero,
+            );
+            let subprogram = LLVMDIBuilderCreateFunction(
+                self.raw,
+                file.raw,
+                name.as_ptr() as *const c_char,
+                name.len(),
+                name.as_ptr() as *const c_char,
+                name.len(),
+                file.raw,
+                line,
+                fn_type,
+                0,
+                1,
+                line,
+                LLVMDIFlagZero,
+                optimized as LLVMBool,
+            );
+            LLVMSetSubprogram(function.raw, subprogram);
+            Metadata::new(subprogram)
+        }
+    }
+}
+
+impl Drop for DebugInfoBuilder<'_> {
+    fn drop(&mut self) {
+        unsafe {
+            LLVMDIBuilderFinalize(self.raw);
+            LLVMDisposeDIBuilder(self.raw);
+        }
+    }
+}
@@ -0,0 +1,616 @@
+use std::error::Error;
+use std::fmt::{Debug, Display};
+use std::path::{Path, PathBuf};
+use std::process;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+use log::{debug, info};
+use midilang_core::{CellOverflow, Encoding};
+
+use crate::interpreter::RunOptions;
+use crate::optimizer::TapeUsage;
+pub use crate::parser::CellWidth;
+use crate::parser::MidiAST;
+#[cfg(any(feature = "llvm", feature = "cranelift"))]
+use crate::parser::MidiInstructionKind::{self, Loop};
+use crate::provenance::Provenance;
+use crate::timing::SourceMap;
+
+pub mod c;
+#[cfg(feature = "cranelift")]
+pub mod cranelift;
+#[cfg(feature = "llvm")]
+pub mod llvm;
+
+#[cfg(feature = "llvm")]
+pub use llvm::{host_triple, MidiCompiler};
+
+/// Number of cells allocated for the tape of compiled programs.
+pub const DEFAULT_TAPE_SIZE: u64 = 30_000;
+
+pub type MCompileResult<T> = Result<T, MCompileError>;
+
+#[derive(PartialEq, Eq)]
+pub enum MCompileError {
+    LLVMError(String),
+    CraneliftError(String),
+    /// The selected backend can't compile with the given options
+    Unsupported(String),
+}
+
+impl Display for MCompileError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::LLVMError(msg) => write!(f, "LLVM error: {}", msg),
+            Self::CraneliftError(msg) => write!(f, "Cranelift error: {}", msg),
+            Self::Unsupported(msg) => write!(f, "Unsupported: {}", msg),
+        }
+    }
+}
+
+impl Debug for MCompileError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        Display::fmt(self, f)
+    }
+}
+
+impl Error for MCompileError {}
+
+/// How hard the backend should try to optimize the generated module.
+#[derive(Debug, Default, PartialEq, Eq, PartialOrd, Ord, Clone, Copy)]
+pub enum OptLevel {
+    #[default]
+    O0,
+    O1,
+    O2,
+    O3,
+}
+
+impl TryFrom<u8> for OptLevel {
+    type Error = MCompileError;
+
+    fn try_from(level: u8) -> MCompileResult<Self> {
+        match level {
+            0 => Ok(OptLevel::O0),
+            1 => Ok(OptLevel::O1),
+            2 => Ok(OptLevel::O2),
+            3 => Ok(OptLevel::O3),
+            _ => Err(MCompileError::LLVMError(format!("Invalid optimization level {}", level))),
+        }
+    }
+}
+
+impl From<OptLevel> for u32 {
+    fn from(level: OptLevel) -> Self {
+        level as u32
+    }
+}
+
+/// What compiled code does when a cell is incremented past its maximum or below 0.
+#[derive(Debug, Default, PartialEq, Eq, Clone, Copy, clap::ValueEnum)]
+pub enum Overflow {
+    /// Classic BF semantics, cells wrap around
+    #[default]
+    Wrap,
+    /// The cell stays at its maximum or at 0
+    Saturate,
+    /// Stop the program with exit status 1
+    Trap,
+}
+
+impl From<Overflow> for CellOverflow {
+    fn from(overflow: Overflow) -> Self {
+        match overflow {
+            Overflow::Wrap => CellOverflow::Wrap,
+            Overflow::Saturate => CellOverflow::Saturate,
+            Overflow::Trap => CellOverflow::Trap,
+        }
+    }
+}
+
+/// What compiled code does when the pointer moves off a tape that can't grow
+/// there.
+#[derive(Debug, PartialEq, Eq, Clone, Copy, clap::ValueEnum)]
+pub enum PointerOverflow {
+    /// The pointer comes back in at the other end of a fixed tape
+    Wrap,
+    /// Stop the program with exit status 1, like bounds checks do
+    Trap,
+}
+
+/// What output and input instructions write and read.
+#[derive(Debug, Default, PartialEq, Eq, Clone, Copy, clap::ValueEnum)]
+pub enum IoMode {
+    /// A byte a cell
+    #[default]
+    Bytes,
+    /// Only whole UTF-8 sequences are written, invalid ones as U+FFFD, and
+    /// input reads a code point into as many cells as its UTF-8 takes
+    Utf8,
+    /// Output prints the cell as a decimal number on a line of its own, and
+    /// input reads the next number, skipping what comes before it
+    Numeric,
+}
+
+impl From<IoMode> for Encoding {
+    fn from(mode: IoMode) -> Self {
+        match mode {
+            IoMode::Bytes => Encoding::Bytes,
+            IoMode::Utf8 => Encoding::Utf8,
+            IoMode::Numeric => Encoding::Numeric,
+        }
+    }
+}
+
+/// What `compile_program` writes out.
+#[derive(Debug, Default, PartialEq, Eq, Clone, Copy, clap::ValueEnum)]
+pub enum Emit {
+    /// Textual LLVM IR
+    LlvmIr,
+    /// LLVM bitcode
+    LlvmBc,
+    /// Assembly for the target
+    Asm,
+    /// Object file for the target
+    Obj,
+    /// Linked executable
+    #[default]
+    Exe,
+    /// Portable C source, translated from the program rather than compiled
+    C,
+}
+
+impl Emit {
+    /// Extension of the output file when none is given, without the dot.
+    pub fn extension(self, wasm: bool) -> &'static str {
+        match self {
+            Emit::LlvmIr => "ll",
+            Emit::LlvmBc => "bc",
+            Emit::Asm => "s",
+            Emit::Obj if wasm => "wasm",
+            Emit::Obj => "o",
+            Emit::Exe if wasm => "wasm",
+            Emit::Exe => "",
+            Emit::C => "c",
+        }
+    }
+}
+
+/// Code generator used to compile programs.
+#[derive(Debug, PartialEq, Eq, Clone, Copy, clap::ValueEnum)]
+pub enum BackendKind {
+    /// Supports every option and target
+    #[cfg(feature = "llvm")]
+    Llvm,
+    /// Compiles quickly and can JIT, but only for the host and without runtime checks
+    #[cfg(feature = "cranelift")]
+    Cranelift,
+    /// Built without a backend, for the web, where only the VM and
+    /// translating to C are around
+    #[cfg(not(any(feature = "llvm", feature = "cranelift")))]
+    None,
+}
+
+impl Default for BackendKind {
+    #[cfg(feature = "llvm")]
+    fn default() -> Self {
+        BackendKind::Llvm
+    }
+
+    #[cfg(all(feature = "cranelift", not(feature = "llvm")))]
+    fn default() -> Self {
+        BackendKind::Cranelift
+    }
+
+    #[cfg(not(any(feature = "llvm", feature = "cranelift")))]
+    fn default() -> Self {
+        BackendKind::None
+    }
+}
+
+/// What compiled code does when the pointer moves past the end of the tape.
+#[derive(Debug, Default, PartialEq, Eq, Clone, Copy, clap::ValueEnum)]
+pub enum TapeMode {
+    /// The tape never changes size
+    #[default]
+    Fixed,
+    /// The tape is reallocated to fit the pointer
+    Grow,
+    /// The tape is reallocated to fit the pointer on either side, so it can
+    /// move left of where it started
+    Infinite,
+}
+
+/// Options controlling code generation, constructed with `CompileOptionsBuilder`.
+#[derive(Debug, Clone)]
+pub struct CompileOptions {
+    pub(crate) tape_size: u64,
+    pub(crate) tape_mode: TapeMode,
+    pub(crate) cell_width: CellWidth,
+    pub(crate) opt_level: OptLevel,
+    pub(crate) target_triple: Option<String>,
+    pub(crate) overflow: Overflow,
+    /// What the pointer does off the tape, running off it unchecked when `None`
+    pub(crate) pointer_overflow: Option<PointerOverflow>,
+    pub(crate) bounds_check: bool,
+    pub(crate) io: IoMode,
+    /// File the program reads instead of stdin
+    pub(crate) stdin: Option<PathBuf>,
+    /// File the program writes instead of stdout
+    pub(crate) stdout: Option<PathBuf>,
+    pub(crate) linker: String,
+    /// Libraries the program's calls find functions in besides the builtins,
+    /// linked into executables and loaded by the JIT
+    pub(crate) link: Vec<PathBuf>,
+    pub(crate) backend: BackendKind,
+    pub(crate) debug_info: Option<PathBuf>,
+    pub(crate) provenance: Option<Provenance>,
+    pub(crate) embedded_source: Option<Vec<u8>>,
+    pub(crate) perform: Option<u32>,
+    /// Cells the tape starts out with, from the data track
+    pub(crate) data: Vec<u8>,
+    /// Seed of the random bytes, from the clock when the program starts when `None`
+    pub(crate) seed: Option<u64>,
+}
+
+impl CompileOptions {
+    pub fn builder() -> CompileOptionsBuilder {
+        CompileOptionsBuilder::new()
+    }
+
+    /// Whether the target is WebAssembly, whose objects and executables are `.wasm` modules.
+    pub fn targets_wasm(&self) -> bool {
+        self.target_triple.as_deref().is_some_and(|triple| triple.starts_with("wasm"))
+    }
+
+    /// `limits` with the tape, overflow and IO settings of these options, for
+    /// running the program in the interpreter or VM like it runs compiled.
+    pub(crate) fn run_options(&self, limits: RunOptions) -> RunOptions {
+        RunOptions {
+            seed: self.seed.unwrap_or(limits.seed),
+            tape_mode: self.tape_mode,
+            cell_overflow: self.overflow,
+            pointer_overflow: self.pointer_overflow.map(|overflow| (overflow, self.tape_size)),
+            io: self.io,
+            ..limits
+        }
+    }
+
+    /// Makes sure a fixed tape is large enough for `midi_program`.
+    ///
+    /// Tapes are enlarged when the program provably needs more cells, and
+    /// programs whose pointer movement can't be bounded get a growing tape.
+    /// Tapes the pointer wraps around or traps at keep their size.
+    pub(crate) fn fit_tape(&mut self, midi_program: &MidiAST) {
+        if self.data.len() as u64 > self.tape_size {
+            info!("Data takes {} cells, enlarging the tape", self.data.len());
+            self.tape_size = self.data.len() as u64;
+        }
+        if self.tape_mode != TapeMode::Fixed || self.pointer_overflow.is_some() {
+            return;
+        }
+        match midi_program.highest_cell() {
+            Some(highest) if highest as u64 >= self.tape_size => {
+                info!("Program uses {} cells, enlarging the tape", highest + 1);
+                self.tape_size = highest as u64 + 1;
+            }
+            Some(_) => {}
+            None => {
+                info!("Pointer movement is unbounded, falling back to a growing tape");
+                self.tape_mode = TapeMode::Grow;
+            }
+        }
+    }
+}
+
+impl Default for CompileOptions {
+    fn default() -> Self {
+        CompileOptions {
+            tape_size: DEFAULT_TAPE_SIZE,
+            tape_mode: TapeMode::default(),
+            cell_width: CellWidth::default(),
+            opt_level: OptLevel::default(),
+            target_triple: None,
+            overflow: Overflow::default(),
+            pointer_overflow: None,
+            bounds_check: false,
+            io: IoMode::default(),
+            stdin: None,
+            stdout: None,
+            linker: "cc".to_owned(),
+            link: vec![],
+            backend: BackendKind::default(),
+            debug_info: None,
+            provenance: None,
+            embedded_source: None,
+            perform: None,
+            data: vec![],
+            seed: None,
+        }
+    }
+}
+
+pub struct CompileOptionsBuilder {
+    options: CompileOptions,
+}
+
+impl CompileOptionsBuilder {
+    pub fn new() -> Self {
+        CompileOptionsBuilder {
+            options: CompileOptions::default(),
+        }
+    }
+
+    /// Number of cells allocated for the tape, or its initial size when it can grow
+    pub fn tape_size(mut self, num_cells: u64) -> Self {
+        self.options.tape_size = num_cells;
+        self
+    }
+
+    pub fn tape_mode(mut self, mode: TapeMode) -> Self {
+        self.options.tape_mode = mode;
+        self
+    }
+
+    pub fn cell_width(mut self, width: CellWidth) -> Self {
+        self.options.cell_width = width;
+        self
+    }
+
+    pub fn opt_level(mut self, level: OptLevel) -> Self {
+        self.options.opt_level = level;
+        self
+    }
+
+    /// LLVM target triple of the module, e.g. `x86_64-unknown-linux-gnu`, or the host when `None`
+    pub fn target_triple(mut self, triple: Option<String>) -> Self {
+        self.options.target_triple = triple;
+        self
+    }
+
+    pub fn overflow(mut self, overflow: Overflow) -> Self {
+        self.options.overflow = overflow;
+        self
+    }
+
+    /// Wrap the pointer around a fixed tape or stop when it leaves the tape,
+    /// instead of touching memory past it. Trapping checks bounds.
+    pub fn pointer_overflow(mut self, overflow: Option<PointerOverflow>) -> Self {
+        self.options.pointer_overflow = overflow;
+        self
+    }
+
+    /// Stop when the pointer leaves the tape instead of touching memory past it
+    pub fn bounds_check(mut self, check: bool) -> Self {
+        self.options.bounds_check = check;
+        self
+    }
+
+    /// Write and read UTF-8 or decimal numbers instead of a byte a cell
+    pub fn io(mut self, mode: IoMode) -> Self {
+        self.options.io = mode;
+        self
+    }
+
+    /// Have the program read this file instead of stdin. Compiled programs
+    /// open it when they start, at the path as given
+    pub fn stdin(mut self, path: Option<PathBuf>) -> Self {
+        self.options.stdin = path;
+        self
+    }
+
+    /// Have the program write this file instead of stdout, creating or
+    /// truncating it. Compiled programs open it when they start
+    pub fn stdout(mut self, path: Option<PathBuf>) -> Self {
+        self.options.stdout = path;
+        self
+    }
+
+    pub fn backend(mut self, backend: BackendKind) -> Self {
+        self.options.backend = backend;
+        self
+    }
+
+    /// Program used to link executables, `cc` by default
+    pub fn linker(mut self, linker: String) -> Self {
+        self.options.linker = linker;
+        self
+    }
+
+    /// Libraries calls can call the functions of, on top of `ffi::BUILTINS`
+    pub fn link(mut self, libraries: Vec<PathBuf>) -> Self {
+        self.options.link = libraries;
+        self
+    }
+
+    /// Emit DWARF debug info whose line numbers are lines of this source map
+    /// listing, see `SourceMap::write_listing`
+    pub fn debug_info(mut self, listing: Option<PathBuf>) -> Self {
+        self.options.debug_info = listing;
+        self
+    }
+
+    /// Where the program came from, embedded in the output as module metadata and
+    /// in a `.midilang` section of object files
+    pub fn provenance(mut self, provenance: Option<Provenance>) -> Self {
+        self.options.provenance = provenance;
+        self
+    }
+
+    /// Source MIDI file to carry along in a `.midilang.src` section of object files,
+    /// see `provenance::extract_source`
+    pub fn embed_source(mut self, smf: Option<Vec<u8>>) -> Self {
+        self.options.embedded_source = smf;
+        self
+    }
+
+    /// Have compiled programs play the note of every instruction as it runs, each
+    /// lasting this many milliseconds
+    ///
+    /// Notes go to the raw MIDI device in `$MIDILANG_MIDI_OUT`, `/dev/snd/midiC0D0`
+    /// by default, or come out as terminal beeps when it can't be opened.
+    pub fn perform(mut self, note_ms: Option<u32>) -> Self {
+        self.options.perform = note_ms;
+        self
+    }
+
+    /// Cells the tape starts out with, a byte each, like the ones of a data
+    /// track, see `ParseOptions::data_track`. Compiled programs copy them out
+    /// of a constant
+    pub fn data(mut self, data: Vec<u8>) -> Self {
+        self.options.data = data;
+        self
+    }
+
+    /// Seed of the random bytes compiled programs pick, the same one the
+    /// interpreter picks them from for the same seed. Programs seed themselves
+    /// from the clock when they start without one
+    pub fn seed(mut self, seed: Option<u64>) -> Self {
+        self.options.seed = seed;
+        self
+    }
+
+    pub fn build(mut self) -> MCompileResult<CompileOptions> {
+        if self.options.tape_size == 0 {
+            return Err(MCompileError::LLVMError("Tape must have at least one cell".to_owned()));
+        }
+        match self.options.pointer_overflow {
+            Some(PointerOverflow::Wrap) if self.options.tape_mode != TapeMode::Fixed => {
+                return Err(MCompileError::Unsupported("wrapping the pointer needs a fixed tape".to_owned()));
+            }
+            Some(PointerOverflow::Trap) => self.options.bounds_check = true,
+            _ => {}
+        }
+        let redirected = self.options.stdin.is_some() || self.options.stdout.is_some();
+        if redirected && self.options.targets_wasm() {
+            return Err(MCompileError::Unsupported("WebAssembly programs can't open files for stdin and stdout".to_owned()));
+        }
+        Ok(self.options)
+    }
+}
+
+impl Default for CompileOptionsBuilder {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Object file section called `name` for `triple`, like `.midilang.src`, or
+/// `__DATA,__midilang_src` on Mach-O where it's given as `segment,section`.
+#[cfg(any(feature = "llvm", feature = "cranelift"))]
+pub(crate) fn object_section(triple: &str, name: &str) -> String {
+    if triple.contains("-apple-") {
+        format!("__DATA,__{}", name.replace('.', "_"))
+    } else {
+        format!(".{}", name)
+    }
+}
+
+/// Whether `kind` is anywhere in `program`, for runtime functions only some
+/// programs need, like the seed of random bytes.
+#[cfg(any(feature = "llvm", feature = "cranelift"))]
+pub(crate) fn plays(program: &MidiAST, kind: &MidiInstructionKind) -> bool {
+    program.iter().any(|inst| match &inst.instruction {
+        Loop { body } => plays(body, kind),
+        other => other == kind,
+    })
+}
+
+/// A compiled program, ready to be written out.
+pub trait Backend {
+    /// Textual IR of the compiled program, LLVM IR or Cranelift IR depending on the backend
+    fn print_ir(&self) -> String;
+
+    /// Writes the compiled program to `path` in the form asked for by `emit`
+    fn emit(&self, emit: Emit, path: &Path) -> MCompileResult<()>;
+
+    /// Like `emit`, returning what would be written instead. Executables still
+    /// go through a temporary file, the linker needs one.
+    fn emit_bytes(&self, emit: Emit) -> MCompileResult<Vec<u8>>;
+}
+
+/// A fresh path in the temporary directory with extension `ext`, unique even
+/// when several programs compile at once.
+pub(crate) fn temp_path(ext: &str) -> PathBuf {
+    static COUNT: AtomicUsize = AtomicUsize::new(0);
+    let count = COUNT.fetch_add(1, Ordering::Relaxed);
+    std::env::temp_dir().join(format!("midilang-{}-{}", process::id(), count)).with_extension(ext)
+}
+
+/// Writes an object file for `backend` to a temporary location and links it into
+/// an executable at `path` with the configured linker.
+///
+/// Cross builds pass `--target` along, so they need a clang-like linker.
+#[cfg(any(feature = "llvm", feature = "cranelift"))]
+pub(crate) fn link(backend: &dyn Backend, options: &CompileOptions, path: &Path) -> MCompileResult<()> {
+    let object = temp_path("o");
+    backend.emit(Emit::Obj, &object)?;
+
+    let mut command = process::Command::new(&options.linker);
+    if let Some(triple) = &options.target_triple {
+        command.arg(format!("--target={}", triple));
+    }
+    command.arg(&object).args(&options.link).arg("-o").arg(path);
+    debug!("Linking with {:?}", command);
+    let status = command.status();
+    let _ = std::fs::remove_file(&object);
+    match status {
+        Ok(status) if status.success() => Ok(()),
+        Ok(status) => Err(MCompileError::LLVMError(format!("Linker {} failed with {}", options.linker, status))),
+        Err(e) => Err(MCompileError::LLVMError(format!("Could not run linker {}: {}", options.linker, e))),
+    }
+}
+
+/// Links `backend` into an executable in the temporary directory like `link`,
+/// returning its contents.
+#[cfg(any(feature = "llvm", feature = "cranelift"))]
+pub(crate) fn link_to_memory(backend: &dyn Backend, options: &CompileOptions) -> MCompileResult<Vec<u8>> {
+    let exe = temp_path(Emit::Exe.extension(options.targets_wasm()));
+    link(backend, options, &exe)?;
+    let bytes = std::fs::read(&exe);
+    let _ = std::fs::remove_file(&exe);
+    bytes.map_err(|e| MCompileError::LLVMError(format!("Could not read the linked program: {}", e)))
+}
+
+/// Compiles the given `MidiAST` with the backend picked in `options`.
+///
+/// Get at the result with `Backend::print_ir` or write it out with `Backend::emit`.
+pub fn compile_program(
+    midi_program: MidiAST,
+    // only the LLVM backend reports positions
+    #[cfg_attr(not(feature = "llvm"), allow(unused_variables))]
+    source_map: Option<SourceMap>,
+    options: CompileOptions,
+) -> MCompileResult<Box<dyn Backend>> {
+    debug!("Compiling with {:?} ...", options.backend);
+    debug!("{midi_program:?}");
+    match options.backend {
+        #[cfg(feature = "llvm")]
+        BackendKind::Llvm => Ok(Box::new(llvm::compile_program(midi_program, source_map, options)?)),
+        #[cfg(feature = "cranelift")]
+        BackendKind::Cranelift => Ok(Box::new(cranelift::compile_program(&midi_program, options)?)),
+        #[cfg(not(any(feature = "llvm", feature = "cranelift")))]
+        BackendKind::None => Err(MCompileError::Unsupported("midilang was built without a backend".to_owned())),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+    #[test]
+    fn options_builder() {
+        let options = CompileOptions::builder()
+            .tape_size(10)
+            .cell_width(CellWidth::I16)
+            .bounds_check(true)
+            .build()
+            .unwrap();
+        assert_eq!(options.tape_size, 10);
+        assert_eq!(options.cell_width, CellWidth::I16);
+        assert_eq!(options.opt_level, OptLevel::O0);
+        assert!(options.bounds_check);
+        assert!(CompileOptions::builder().tape_size(0).build().is_err());
+    }
+}
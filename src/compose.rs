@@ -0,0 +1,255 @@
+use std::fmt::Display;
+use std::num::Wrapping;
+use std::str::FromStr;
+
+use midilang_core::Rng;
+
+use crate::parser::{MidiAST, MidiInstruction, MidiInstructionKind, MidiInstructionKind::*};
+
+/// Kinds of instructions weights can be given to, by name.
+const KINDS: [&str; 7] = ["add", "subtract", "right", "left", "output", "input", "loop"];
+
+/// Largest amount to add or subtract, small amounts are chords of few notes.
+const MAX_AMOUNT: u64 = 8;
+
+/// Largest move, so the pointer stays around where the loops are.
+const MAX_MOVE: u64 = 3;
+
+/// Most times loops of terminating programs go around, each.
+const MAX_COUNT: u64 = 8;
+
+/// How often each kind of instruction gets picked, relative to the others.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Weights(pub [u32; 7]);
+
+impl Default for Weights {
+    /// No input, a song can't wait for someone to type.
+    fn default() -> Self {
+        Weights([4, 2, 2, 2, 2, 0, 1])
+    }
+}
+
+impl FromStr for Weights {
+    type Err = String;
+
+    /// Reads weights like `add=3,loop=0`, the kinds not given keep their
+    /// default weight.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut weights = Weights::default();
+        for part in s.split(',').filter(|part| !part.is_empty()) {
+            let (name, weight) = part.split_once('=').ok_or_else(|| format!("`{}` isn't `kind=weight`", part))?;
+            let kind = KINDS
+                .iter()
+                .position(|kind| *kind == name.trim())
+                .ok_or_else(|| format!("no instruction called `{}`, there's {}", name, KINDS.join(", ")))?;
+            weights.0[kind] = weight.trim().parse().map_err(|err| format!("weight of {}: {}", name, err))?;
+        }
+        if weights.0.iter().all(|&weight| weight == 0) {
+            return Err("every weight is 0".to_owned());
+        }
+        Ok(weights)
+    }
+}
+
+impl Display for Weights {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let parts: Vec<String> = KINDS.iter().zip(self.0).map(|(kind, weight)| format!("{}={}", kind, weight)).collect();
+        f.write_str(&parts.join(","))
+    }
+}
+
+/// What `compose` makes.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ComposeOptions {
+    /// The same seed makes the same program
+    pub seed: u64,
+    /// Instructions to pick, loops still open at the end get closed after
+    pub length: usize,
+    /// Only make programs that end, see `compose`
+    pub terminating: bool,
+    pub weights: Weights,
+    /// How deep loops nest
+    pub max_depth: usize,
+}
+
+impl Default for ComposeOptions {
+    fn default() -> Self {
+        ComposeOptions {
+            seed: 0,
+            length: 64,
+            terminating: false,
+            weights: Weights::default(),
+            max_depth: 3,
+        }
+    }
+}
+
+/// Makes up a program, picking instructions at random by their weights.
+/// Loops close about as often as they open.
+///
+/// Terminating programs count their loops down: every loop clears a counter
+/// cell, sets it to a few and takes one off it at the end of every time
+/// around, with a body that comes back to the counter and leaves it alone,
+/// like `[-]+++[>+.<-]`. Their pointer never goes left of the first cell
+/// either, so they run to the end, quickly with 8 bit cells. Clearing a cell
+/// that's gone negative takes a while with wider ones.
+pub fn compose(options: &ComposeOptions) -> MidiAST {
+    let mut composer = Composer {
+        options,
+        rng: Rng::new(options.seed),
+        bodies: vec![vec![]],
+        counters: vec![],
+        at: 0,
+    };
+    for _ in 0..options.length {
+        composer.step();
+    }
+    while composer.bodies.len() > 1 {
+        composer.close_loop();
+    }
+    composer.bodies.pop().unwrap_or_default()
+}
+
+struct Composer<'a> {
+    options: &'a ComposeOptions,
+    rng: Rng,
+    /// Bodies of the loops being made, the program itself at the bottom
+    bodies: Vec<MidiAST>,
+    /// Cell every open loop counts down in, only kept for terminating programs
+    counters: Vec<u64>,
+    /// Cell the pointer is on, only kept for terminating programs
+    at: u64,
+}
+
+impl Composer<'_> {
+    fn step(&mut self) {
+        let depth = self.bodies.len() - 1;
+        let mut weights = self.options.weights.0;
+        if depth >= self.options.max_depth {
+            weights[6] = 0;
+        }
+        if self.options.terminating {
+            // leave the counters alone, and don't go past the first cell
+            if self.counters.contains(&self.at) {
+                weights[0] = 0;
+                weights[1] = 0;
+                weights[5] = 0;
+                weights[6] = 0;
+            }
+            if self.at == 0 {
+                weights[3] = 0;
+            }
+        }
+        // closing loops is as likely as opening them
+        let close = if depth > 0 { self.options.weights.0[6] } else { 0 };
+        let total: u64 = weights.iter().map(|&weight| u64::from(weight)).sum::<u64>() + u64::from(close);
+        if total == 0 {
+            return;
+        }
+        let mut pick = self.rng.below(total);
+        let kind = weights.iter().position(|&weight| match pick.checked_sub(u64::from(weight)) {
+            Some(rest) => {
+                pick = rest;
+                false
+            }
+            None => true,
+        });
+        match kind {
+            Some(0) => {
+                let amount = self.rng.below(MAX_AMOUNT) + 1;
+                self.push(IncrementCell { amount: Wrapping(amount as i32) });
+            }
+            Some(1) => {
+                let amount = self.rng.below(MAX_AMOUNT) + 1;
+                self.push(IncrementCell { amount: Wrapping(-(amount as i32)) });
+            }
+            Some(2) => {
+                let amount = self.rng.below(MAX_MOVE) + 1;
+                self.move_to(self.at + amount);
+            }
+            Some(3) => {
+                let amount = (self.rng.below(MAX_MOVE) + 1).min(if self.options.terminating { self.at } else { MAX_MOVE });
+                self.at = self.at.saturating_sub(amount);
+                self.push(MovePointer { amount: -(amount as isize) });
+            }
+            Some(4) => self.push(OutputCell),
+            Some(5) => self.push(InputCell),
+            Some(_) => self.open_loop(),
+            None => self.close_loop(),
+        }
+    }
+
+    fn open_loop(&mut self) {
+        if self.options.terminating {
+            let count = self.rng.below(MAX_COUNT) + 1;
+            self.push(Loop { body: vec![inst(IncrementCell { amount: Wrapping(-1) })] });
+            self.push(IncrementCell { amount: Wrapping(count as i32) });
+            self.counters.push(self.at);
+        }
+        self.bodies.push(vec![]);
+    }
+
+    fn close_loop(&mut self) {
+        if self.options.terminating {
+            let counter = self.counters.pop().unwrap_or_default();
+            self.move_to(counter);
+            self.push(IncrementCell { amount: Wrapping(-1) });
+        }
+        if let Some(body) = self.bodies.pop() {
+            self.push(Loop { body });
+        }
+    }
+
+    fn move_to(&mut self, cell: u64) {
+        let amount = cell as isize - self.at as isize;
+        self.at = cell;
+        if amount != 0 {
+            self.push(MovePointer { amount });
+        }
+    }
+
+    fn push(&mut self, instruction: MidiInstructionKind) {
+        if let Some(body) = self.bodies.last_mut() {
+            body.push(inst(instruction));
+        }
+    }
+}
+
+fn inst(instruction: MidiInstructionKind) -> MidiInstruction {
+    MidiInstruction { position: None, instruction }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+    use crate::interpreter::{Interpreter, RunOptions};
+
+    #[test]
+    fn reads_weights() {
+        let weights: Weights = "loop=0, add = 7".parse().unwrap();
+        assert_eq!(weights.to_string(), "add=7,subtract=2,right=2,left=2,output=2,input=0,loop=0");
+        assert_eq!("jump=1".parse::<Weights>().unwrap_err(), "no instruction called `jump`, there's add, subtract, right, left, output, input, loop");
+        assert!("add=x".parse::<Weights>().is_err());
+        assert_eq!("add=0,subtract=0,right=0,left=0,output=0,loop=0".parse::<Weights>().unwrap_err(), "every weight is 0");
+    }
+
+    #[test]
+    fn seeds_make_the_same_program() {
+        let options = ComposeOptions { seed: 7, ..ComposeOptions::default() };
+        assert_eq!(compose(&options), compose(&options));
+        assert_ne!(compose(&options), compose(&ComposeOptions { seed: 8, ..options }));
+    }
+
+    #[test]
+    fn terminating_programs_end() {
+        let weights = "input=1,loop=3".parse().unwrap();
+        for seed in 0..50 {
+            let options = ComposeOptions { seed, length: 100, terminating: true, weights, ..ComposeOptions::default() };
+            let program = compose(&options);
+            let limits = RunOptions { max_steps: Some(10_000_000), ..RunOptions::default() };
+            let result = Interpreter::with_options(limits).run(&program, &mut &b"input"[..], &mut vec![]);
+            assert!(result.is_ok(), "seed {}: {:?}", seed, result);
+        }
+    }
+}
@@ -0,0 +1,362 @@
+use std::collections::HashMap;
+use std::fs::{self, Permissions};
+use std::io::{self, BufRead, BufReader, Write};
+use std::os::unix::fs::PermissionsExt;
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant, SystemTime};
+
+use clap::ValueEnum;
+use log::{debug, info, warn};
+use serde::{Deserialize, Serialize};
+
+use crate::compiler::{self, CellWidth, CompileOptions, Emit, OptLevel};
+use crate::error::{MidilangError, MidilangResult};
+use crate::interpreter::RunOptions;
+use crate::logging::millis_since;
+use crate::parser::{MidiAST, ParseOptions};
+use crate::timing::SourceMap;
+use crate::{compile_file, load_program, new_vm, run_error, vm, OutputOptions};
+
+/// A request to the daemon, one JSON object on a line of its own:
+///
+/// ```text
+/// {"id":1,"command":"compile","file":"song.mid","emit":"obj","opt_level":2}
+/// {"id":2,"command":"run","file":"song.mid","input":"hi","max_steps":100000}
+/// {"id":3,"command":"ping"}
+/// {"id":4,"command":"shutdown"}
+/// ```
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+pub struct Request {
+    /// Handed back in the response
+    #[serde(default)]
+    pub id: Option<u64>,
+    #[serde(flatten)]
+    pub command: Command,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+#[serde(tag = "command", rename_all = "lowercase")]
+pub enum Command {
+    Compile(CompileRequest),
+    Run(RunRequest),
+    /// Answers right away, to see if the daemon's up
+    Ping,
+    /// Answers, then stops taking connections
+    Shutdown,
+}
+
+/// Compiles a file like `midilang -m`, with options named after its flags.
+/// Built tracks and programs are kept in `.midilang-cache` like with `--cache`.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Deserialize)]
+#[serde(default)]
+pub struct CompileRequest {
+    pub file: String,
+    /// `--emit`, `exe` when not given
+    pub emit: Option<String>,
+    pub output: Option<PathBuf>,
+    pub opt_level: u8,
+    pub cell_size: Option<String>,
+    pub tape_size: Option<u64>,
+    pub tape: Option<String>,
+    pub target: Option<String>,
+    pub backend: Option<String>,
+    pub checked: bool,
+    pub lenient: bool,
+}
+
+/// Runs a file on the VM, with `input` as its input.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Deserialize)]
+#[serde(default)]
+pub struct RunRequest {
+    pub file: String,
+    pub input: String,
+    pub cell_size: Option<String>,
+    pub max_steps: Option<usize>,
+    /// Milliseconds, 10 seconds when not given so a stuck program can't hold
+    /// the daemon up for good
+    pub time_limit: Option<u64>,
+}
+
+/// What the daemon answers every request with, on a line of its own.
+#[derive(Debug, Clone, Default, PartialEq, Serialize)]
+pub struct Response {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub id: Option<u64>,
+    /// What midilang would have exited with doing the same on the command line
+    pub exit_code: i32,
+    /// Everything a run wrote, as UTF-8 with invalid bytes replaced
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub output: Option<String>,
+    /// Why the request failed, like `--error-format json` prints it for files
+    /// that failed to compile and as just a `message` otherwise
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<serde_json::Value>,
+    pub duration_ms: f64,
+}
+
+/// Where the daemon listens unless told otherwise, in `$XDG_RUNTIME_DIR` or
+/// else the temporary directory.
+pub fn default_socket() -> PathBuf {
+    match std::env::var_os("XDG_RUNTIME_DIR") {
+        Some(dir) => PathBuf::from(dir).join("midilang.sock"),
+        // SAFETY: getuid can't fail and touches no memory of ours
+        None => std::env::temp_dir().join(format!("midilang-{}.sock", unsafe { libc::getuid() })),
+    }
+}
+
+/// Listens on `socket` until asked to shut down, answering requests on every
+/// connection in a thread of its own. The backend is warmed up before the
+/// first request, and programs that get run are kept parsed and optimized
+/// until their file changes, so editors and build tools that talk to the
+/// daemon skip the startup a new midilang pays for. Songs are read with `parse`.
+pub fn serve(socket: &Path, parse: &ParseOptions) -> MidilangResult<i32> {
+    if socket.exists() {
+        if UnixStream::connect(socket).is_ok() {
+            return Err(MidilangError::Other(format!("A daemon is already listening on {}", socket.display())));
+        }
+        // left behind by a daemon that didn't get to clean up
+        fs::remove_file(socket)?;
+    }
+    let listener = UnixListener::bind(socket)?;
+    // whoever can connect can have the daemon read and write their files as us
+    fs::set_permissions(socket, Permissions::from_mode(0o600))?;
+    let daemon = Arc::new(Daemon::new(socket, parse));
+    daemon.warm_up();
+    info!("Listening on {}", socket.display());
+
+    for stream in listener.incoming() {
+        if daemon.stopping.load(Ordering::SeqCst) {
+            break;
+        }
+        match stream {
+            Ok(stream) => {
+                let daemon = Arc::clone(&daemon);
+                thread::spawn(move || {
+                    if let Err(err) = daemon.handle(stream) {
+                        warn!("Connection ended: {}", err);
+                    }
+                });
+            }
+            Err(err) => warn!("Could not take a connection: {}", err),
+        }
+    }
+    info!("Shutting down");
+    fs::remove_file(socket)?;
+    Ok(0)
+}
+
+// a program parsed and optimized, with where its instructions came from and
+// the cells its tape starts out with
+type Program = Arc<(MidiAST, SourceMap, Vec<u8>)>;
+
+struct Daemon {
+    socket: PathBuf,
+    stopping: AtomicBool,
+    /// Programs run before, by file and bits in a cell, with when their file
+    /// was last changed
+    programs: Mutex<HashMap<(PathBuf, u32), (SystemTime, Program)>>,
+    parse: ParseOptions,
+}
+
+impl Daemon {
+    fn new(socket: &Path, parse: &ParseOptions) -> Self {
+        Daemon {
+            socket: socket.to_owned(),
+            stopping: AtomicBool::new(false),
+            programs: Mutex::new(HashMap::new()),
+            parse: parse.clone(),
+        }
+    }
+
+    // compiles an empty program, which sets up everything the backend only
+    // sets up once
+    fn warm_up(&self) {
+        let started = Instant::now();
+        match compiler::compile_program(vec![], None, CompileOptions::default()).and_then(|backend| backend.emit_bytes(Emit::Obj)) {
+            Ok(_) => debug!("Warmed up the backend in {:.1}ms", millis_since(started)),
+            Err(err) => warn!("Could not warm up the backend: {:?}", err),
+        }
+    }
+
+    fn handle(&self, stream: UnixStream) -> io::Result<()> {
+        let mut writer = stream.try_clone()?;
+        for line in BufReader::new(stream).lines() {
+            let line = line?;
+            if line.trim().is_empty() {
+                continue;
+            }
+            let response = match serde_json::from_str::<Request>(&line) {
+                Ok(request) => self.respond(request),
+                Err(err) => failed(None, &MidilangError::Other(format!("Not a request: {}", err))),
+            };
+            let mut json = serde_json::to_vec(&response)?;
+            json.push(b'\n');
+            writer.write_all(&json)?;
+            if self.stopping.load(Ordering::SeqCst) {
+                break;
+            }
+        }
+        Ok(())
+    }
+
+    fn respond(&self, request: Request) -> Response {
+        let started = Instant::now();
+        debug!("Request: {:?}", request);
+        let mut output = None;
+        let result = match request.command {
+            Command::Compile(compile) => self.compile(&compile),
+            Command::Run(run) => self.run(&run, &mut output),
+            Command::Ping => Ok(0),
+            Command::Shutdown => {
+                self.stopping.store(true, Ordering::SeqCst);
+                // wakes the listener up to see it's stopping
+                let _ = UnixStream::connect(&self.socket);
+                Ok(0)
+            }
+        };
+        let response = match result {
+            Ok(exit_code) => Response { id: request.id, exit_code, ..Response::default() },
+            Err(err) => failed(request.id, &err),
+        };
+        Response { output, duration_ms: millis_since(started), ..response }
+    }
+
+    fn compile(&self, request: &CompileRequest) -> MidilangResult<i32> {
+        let options = CompileOptions::builder()
+            .opt_level(OptLevel::try_from(request.opt_level)?)
+            .tape_size(request.tape_size.unwrap_or(compiler::DEFAULT_TAPE_SIZE))
+            .tape_mode(value("tape", &request.tape)?.unwrap_or_default())
+            .bounds_check(request.checked)
+            .cell_width(value("cell_size", &request.cell_size)?.unwrap_or_default())
+            .target_triple(request.target.clone())
+            .backend(value("backend", &request.backend)?.unwrap_or_default())
+            .build()?;
+        let output = OutputOptions {
+            emit: value("emit", &request.emit)?.unwrap_or_default(),
+            output: request.output.clone(),
+            lenient: request.lenient,
+            cache: true,
+            ..OutputOptions::default()
+        };
+        compile_file(&request.file, &self.parse, options, &output)
+    }
+
+    fn run(&self, request: &RunRequest, output: &mut Option<String>) -> MidilangResult<i32> {
+        let cell_width: CellWidth = value("cell_size", &request.cell_size)?.unwrap_or_default();
+        let program = self.load(&request.file, cell_width)?;
+        let limits = RunOptions {
+            max_steps: request.max_steps,
+            wall_clock_limit: Some(Duration::from_millis(request.time_limit.unwrap_or(10_000))),
+            ..RunOptions::default()
+        };
+        let mut written = vec![];
+        let mut machine = new_vm(cell_width, limits);
+        machine.load_data(&program.2);
+        let result = machine.run(&vm::Bytecode::new(&program.0), &mut request.input.as_bytes(), &mut written);
+        *output = Some(String::from_utf8_lossy(&written).into_owned());
+        result.map_err(|err| run_error(err, &program.1))?;
+        Ok(0)
+    }
+
+    // the program in `file_path` parsed and optimized, again only when the
+    // file changed since it last was
+    fn load(&self, file_path: &str, cell_width: CellWidth) -> MidilangResult<Program> {
+        let path = fs::canonicalize(file_path)?;
+        let modified = fs::metadata(&path)?.modified()?;
+        let key = (path, cell_width.bits());
+        if let Some((when, program)) = self.programs.lock().unwrap().get(&key) {
+            if *when == modified {
+                debug!("{} didn't change, running it as it was", file_path);
+                return Ok(Arc::clone(program));
+            }
+        }
+        let (ast, source_map, _, data) = load_program(file_path, &self.parse, cell_width, &RunOptions::default())?;
+        let program = Arc::new((ast, source_map, data));
+        self.programs.lock().unwrap().insert(key, (modified, Arc::clone(&program)));
+        Ok(program)
+    }
+}
+
+// the value of an option the command line takes as one of a few names
+fn value<T: ValueEnum>(option: &str, name: &Option<String>) -> MidilangResult<Option<T>> {
+    name.as_deref()
+        .map(|name| T::from_str(name, true).map_err(|_| MidilangError::Other(format!("`{}` isn't a value of {}", name, option))))
+        .transpose()
+}
+
+fn failed(id: Option<u64>, err: &MidilangError) -> Response {
+    Response { id, exit_code: err.exit_code(), error: Some(err.to_json_value()), ..Response::default() }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+    fn ask(stream: &mut UnixStream, request: &str) -> serde_json::Value {
+        stream.write_all(format!("{}\n", request).as_bytes()).unwrap();
+        let mut line = String::new();
+        BufReader::new(stream).read_line(&mut line).unwrap();
+        serde_json::from_str(&line).unwrap()
+    }
+
+    #[test]
+    fn reads_requests() {
+        let request: Request = serde_json::from_str(r#"{"id":7,"command":"run","file":"a.mid","max_steps":10}"#).unwrap();
+        let run = RunRequest { file: "a.mid".to_owned(), max_steps: Some(10), ..RunRequest::default() };
+        assert_eq!(request, Request { id: Some(7), command: Command::Run(run) });
+        assert_eq!(serde_json::from_str::<Request>(r#"{"command":"ping"}"#).unwrap().command, Command::Ping);
+        assert!(serde_json::from_str::<Request>(r#"{"command":"dance"}"#).is_err());
+    }
+
+    #[test]
+    fn answers_over_the_socket() {
+        let dir = compiler::temp_path("dir");
+        fs::create_dir(&dir).unwrap();
+        let song = dir.join("echo.mid");
+        crate::brainf_to_smf(",[.,]").save(&song).unwrap();
+        let socket = dir.join("daemon.sock");
+        let server = {
+            let socket = socket.clone();
+            thread::spawn(move || serve(&socket, &ParseOptions::default()).unwrap())
+        };
+        let mut stream = loop {
+            match UnixStream::connect(&socket) {
+                Ok(stream) => break stream,
+                Err(_) => thread::sleep(Duration::from_millis(10)),
+            }
+        };
+
+        assert_eq!(ask(&mut stream, r#"{"id":1,"command":"ping"}"#)["id"], 1);
+        let run = format!(r#"{{"command":"run","file":{:?},"input":"hi"}}"#, song);
+        for _ in 0..2 {
+            let response = ask(&mut stream, &run);
+            assert_eq!((&response["exit_code"], &response["output"]), (&0.into(), &"hi".into()));
+        }
+        let compile = format!(r#"{{"command":"compile","file":{:?},"emit":"c"}}"#, song);
+        assert_eq!(ask(&mut stream, &compile)["exit_code"], 0);
+        assert!(dir.join("echo.c").exists());
+        let response = ask(&mut stream, r#"{"command":"compile","file":"nowhere.mid","emit":"sound"}"#);
+        assert_eq!(response["error"]["message"], "`sound` isn't a value of emit");
+        let response = ask(&mut stream, r#"{"command":"compile","file":"nowhere.mid"}"#);
+        assert_eq!((&response["exit_code"], &response["error"]["kind"]), (&4.into(), &"io".into()));
+        assert_eq!(ask(&mut stream, "not json")["exit_code"], 1);
+        // files next to a song can be included
+        let mut including = crate::brainf_to_smf("+");
+        let directive = crate::sysex::Directive::Include("echo.mid".to_owned()).to_sysex();
+        including.tracks[1].insert(0, midly::TrackEvent { delta: 0.into(), kind: midly::TrackEventKind::SysEx(&directive) });
+        including.save(dir.join("including.mid")).unwrap();
+        let run = format!(r#"{{"command":"run","file":{:?},"input":"hi"}}"#, dir.join("including.mid"));
+        let response = ask(&mut stream, &run);
+        assert_eq!((&response["exit_code"], &response["output"]), (&0.into(), &"hi".into()));
+
+        assert_eq!(ask(&mut stream, r#"{"command":"shutdown"}"#)["exit_code"], 0);
+        assert_eq!(server.join().unwrap(), 0);
+        assert!(!socket.exists());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,391 @@
+use std::collections::BTreeSet;
+use std::io::{self, Write};
+use std::sync::mpsc::{self, Receiver, Sender};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Duration;
+
+use ratatui::crossterm::event::{self, Event, KeyCode, KeyEventKind};
+use ratatui::layout::{Constraint, Layout, Rect};
+use ratatui::style::{Modifier, Style};
+use ratatui::text::{Line, Span};
+use ratatui::widgets::{Block, Paragraph, Wrap};
+use ratatui::{DefaultTerminal, Frame};
+
+use crate::interpreter::{InterpResult, Interpreter, RunOptions, TapeView, Tracer};
+use crate::parser::{Cell, CellWidth, MidiAST, MidiInstructionKind, MidiInstructionKind::*, Position};
+use crate::timing::SourceMap;
+
+/// Columns each cell takes up in the tape window.
+const CELL_COLUMNS: u16 = 6;
+
+/// What the debugger can tell a paused program to do.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DebugCommand {
+    /// Run a single step and pause again
+    Step,
+    /// Run until the program reaches a bar with a breakpoint or a breakpoint chord
+    Continue,
+    /// Add or remove the breakpoint on a bar, staying paused
+    ToggleBreakpoint(u64),
+}
+
+/// The state of a program paused before a step.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Pause {
+    pub position: Option<Position>,
+    /// Short description of the instruction about to run
+    pub instruction: String,
+    /// Bar the instruction is in, `None` for timecode based files
+    pub bar: Option<u64>,
+    pub cells: Vec<Cell>,
+    pub pointer: usize,
+    pub steps: usize,
+    pub breakpoints: BTreeSet<u64>,
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum DebugEvent {
+    Paused(Pause),
+    Finished(InterpResult<()>),
+}
+
+/// Pauses the interpreter it traces whenever it's stepping or reaches a breakpoint,
+/// and waits for a `DebugCommand` from the debugger.
+struct DebugTracer {
+    /// Bar of every instruction, indexed like `Position`s
+    bars: Vec<Option<u64>>,
+    breakpoints: BTreeSet<u64>,
+    stepping: bool,
+    /// Bar of the last step, so that continuing doesn't stop again in the same bar
+    last_bar: Option<u64>,
+    events: Sender<DebugEvent>,
+    commands: Receiver<DebugCommand>,
+}
+
+impl DebugTracer {
+    fn should_pause(&self, bar: Option<u64>) -> bool {
+        self.stepping || (bar != self.last_bar && bar.is_some_and(|bar| self.breakpoints.contains(&bar)))
+    }
+}
+
+impl Tracer for DebugTracer {
+    fn on_instruction(&mut self, pos: Option<Position>, kind: &MidiInstructionKind, tape: &TapeView) {
+        let bar = pos.and_then(|pos| self.bars.get(pos.start()).copied().flatten());
+        if self.should_pause(bar) || *kind == Breakpoint {
+            let pause = Pause {
+                position: pos,
+                instruction: kind.describe(),
+                bar,
+                cells: tape.cells().to_vec(),
+                pointer: tape.pointer(),
+                steps: tape.steps(),
+                breakpoints: self.breakpoints.clone(),
+            };
+            if self.events.send(DebugEvent::Paused(pause)).is_err() {
+                // nobody is debugging anymore, so just finish the run
+                self.stepping = false;
+                self.breakpoints.clear();
+            }
+            // a closed channel means the debugger went away, which lets the program run on
+            while let Ok(command) = self.commands.recv() {
+                match command {
+                    DebugCommand::Step => break,
+                    DebugCommand::Continue => {
+                        self.stepping = false;
+                        break;
+                    }
+                    DebugCommand::ToggleBreakpoint(bar) => {
+                        if !self.breakpoints.remove(&bar) {
+                            self.breakpoints.insert(bar);
+                        }
+                    }
+                }
+            }
+        }
+        self.last_bar = bar;
+    }
+}
+
+/// Output of a program being debugged, shared with the debugger.
+#[derive(Debug, Default, Clone)]
+pub struct SharedOutput(Arc<Mutex<Vec<u8>>>);
+
+impl SharedOutput {
+    pub fn contents(&self) -> Vec<u8> {
+        self.0.lock().map(|out| out.clone()).unwrap_or_default()
+    }
+}
+
+impl Write for SharedOutput {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        if let Ok(mut out) = self.0.lock() {
+            out.extend_from_slice(buf);
+        }
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+/// A program running in the interpreter on a thread of its own, paused before
+/// its first step.
+pub struct Session {
+    pub commands: Sender<DebugCommand>,
+    pub events: Receiver<DebugEvent>,
+    pub output: SharedOutput,
+}
+
+impl Session {
+    pub fn start(
+        program: MidiAST,
+        source_map: &SourceMap,
+        cell_width: CellWidth,
+        options: RunOptions,
+        data: Vec<u8>,
+        input: Vec<u8>,
+        breakpoints: BTreeSet<u64>,
+    ) -> Self {
+        let (commands, command_receiver) = mpsc::channel();
+        let (event_sender, events) = mpsc::channel();
+        let bars = (0..source_map.len())
+            .map(|index| source_map.bar_beat(index).map(|bar_beat| bar_beat.bar))
+            .collect();
+        let mut tracer = DebugTracer {
+            bars,
+            breakpoints,
+            stepping: true,
+            last_bar: None,
+            events: event_sender.clone(),
+            commands: command_receiver,
+        };
+        let output = SharedOutput::default();
+        let mut program_output = output.clone();
+        thread::spawn(move || {
+            let mut interp = Interpreter::with_options(options);
+            interp.set_cell_width(cell_width);
+            interp.load_data(&data);
+            let result = interp.run_traced(&program, &mut &input[..], &mut program_output, &mut tracer);
+            let _ = event_sender.send(DebugEvent::Finished(result));
+        });
+        Session {
+            commands,
+            events,
+            output,
+        }
+    }
+}
+
+/// What the debugger shows.
+struct DebugView {
+    title: String,
+    pause: Option<Pause>,
+    finished: Option<InterpResult<()>>,
+}
+
+/// Runs `session` in a terminal UI until the user quits.
+pub fn run_tui(session: Session, title: &str, source_map: &SourceMap) -> io::Result<()> {
+    let mut terminal = ratatui::init();
+    let result = debug_loop(&mut terminal, session, title, source_map);
+    ratatui::restore();
+    result
+}
+
+fn debug_loop(terminal: &mut DefaultTerminal, session: Session, title: &str, source_map: &SourceMap) -> io::Result<()> {
+    let mut view = DebugView {
+        title: title.to_owned(),
+        pause: None,
+        finished: None,
+    };
+    loop {
+        while let Ok(event) = session.events.try_recv() {
+            match event {
+                DebugEvent::Paused(pause) => view.pause = Some(pause),
+                DebugEvent::Finished(result) => view.finished = Some(result),
+            }
+        }
+        let output = session.output.contents();
+        terminal.draw(|frame| draw(frame, &view, &output, source_map))?;
+
+        if !event::poll(Duration::from_millis(50))? {
+            continue;
+        }
+        let Event::Key(key) = event::read()? else {
+            continue;
+        };
+        if key.kind != KeyEventKind::Press {
+            continue;
+        }
+        let paused = view.finished.is_none() && view.pause.is_some();
+        let command = match key.code {
+            KeyCode::Char('q') | KeyCode::Esc => return Ok(()),
+            KeyCode::Char('s') | KeyCode::Enter if paused => DebugCommand::Step,
+            KeyCode::Char('c') if paused => DebugCommand::Continue,
+            KeyCode::Char('b') if paused => match view.pause.as_ref().and_then(|pause| pause.bar) {
+                Some(bar) => DebugCommand::ToggleBreakpoint(bar),
+                None => continue,
+            },
+            _ => continue,
+        };
+        if let (DebugCommand::ToggleBreakpoint(bar), Some(pause)) = (command, &mut view.pause) {
+            if !pause.breakpoints.remove(&bar) {
+                pause.breakpoints.insert(bar);
+            }
+        } else {
+            // running again, until the next pause comes in
+            view.pause = None;
+        }
+        if session.commands.send(command).is_err() {
+            return Ok(());
+        }
+    }
+}
+
+fn draw(frame: &mut Frame, view: &DebugView, output: &[u8], source_map: &SourceMap) {
+    let [status_area, tape_area, output_area, help_area] = Layout::vertical([
+        Constraint::Length(3),
+        Constraint::Length(4),
+        Constraint::Min(3),
+        Constraint::Length(3),
+    ])
+    .areas(frame.area());
+
+    let status = match (&view.finished, &view.pause) {
+        (Some(Ok(())), _) => "Finished".to_owned(),
+        (Some(Err(err)), _) => format!("Stopped: {:?}", err),
+        (None, Some(pause)) => {
+            let location = match pause.position {
+                Some(position) => source_map.describe(position),
+                None => "an instruction without a position".to_owned(),
+            };
+            format!("Step {} at {}: {}", pause.steps, location, pause.instruction)
+        }
+        (None, None) => "Running...".to_owned(),
+    };
+    frame.render_widget(Paragraph::new(status).block(Block::bordered().title(view.title.as_str())), status_area);
+
+    if let Some(pause) = &view.pause {
+        frame.render_widget(tape_window(pause, tape_area).block(Block::bordered().title("Tape")), tape_area);
+    } else {
+        frame.render_widget(Block::bordered().title("Tape"), tape_area);
+    }
+
+    let output = Paragraph::new(String::from_utf8_lossy(output).into_owned())
+        .wrap(Wrap { trim: false })
+        .block(Block::bordered().title("Output"));
+    frame.render_widget(output, output_area);
+
+    let breakpoints = match &view.pause {
+        Some(pause) if !pause.breakpoints.is_empty() => {
+            let bars: Vec<_> = pause.breakpoints.iter().map(u64::to_string).collect();
+            format!("breakpoints at bars {}", bars.join(", "))
+        }
+        _ => "no breakpoints".to_owned(),
+    };
+    let help = format!("s step   c continue   b toggle breakpoint on this bar   q quit   ({})", breakpoints);
+    frame.render_widget(Paragraph::new(help).block(Block::bordered()), help_area);
+}
+
+/// The cells around the pointer, with their indexes above them.
+fn tape_window(pause: &Pause, area: Rect) -> Paragraph<'static> {
+    let count = usize::from((area.width.saturating_sub(2) / CELL_COLUMNS).max(1));
+    let first = pause.pointer.saturating_sub(count / 2);
+    let mut indexes = vec![];
+    let mut cells = vec![];
+    for index in first..first + count {
+        let Some(cell) = pause.cells.get(index) else {
+            break;
+        };
+        let style = if index == pause.pointer {
+            Style::default().add_modifier(Modifier::REVERSED)
+        } else {
+            Style::default()
+        };
+        let width = usize::from(CELL_COLUMNS);
+        indexes.push(Span::styled(format!("{:>width$}", index), Style::default().add_modifier(Modifier::DIM)));
+        cells.push(Span::styled(format!("{:>width$}", cell), style));
+    }
+    Paragraph::new(vec![Line::from(indexes), Line::from(cells)])
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+    use crate::parser::{MidiASTBuilder, MidiInstruction};
+    use midly::num::u15;
+    use midly::Timing;
+    use std::num::Wrapping;
+
+    fn build(insts: Vec<MidiInstruction>) -> MidiAST {
+        let mut builder = MidiASTBuilder::new();
+        for inst in insts {
+            builder.push(inst).unwrap();
+        }
+        builder.into_mast().unwrap()
+    }
+
+    fn paused(session: &Session) -> Pause {
+        match session.events.recv().unwrap() {
+            DebugEvent::Paused(pause) => pause,
+            event => panic!("expected a pause, got {:?}", event),
+        }
+    }
+
+    #[test]
+    fn steps_and_stops_at_breakpoints() {
+        let prog = build(vec![
+            MidiInstruction::new_inc(Wrapping(2)),
+            MidiInstruction::new_move(1),
+            MidiInstruction::new_inc(Wrapping(3)),
+            MidiInstruction::new_output(),
+        ]);
+        // one instruction per bar of 4/4 at 480 ticks per quarter
+        let mut source_map = SourceMap::new(Timing::Metrical(u15::from(480)));
+        for bar in 0..4 {
+            source_map.push_instruction(bar * 1920);
+        }
+        let session = Session::start(prog, &source_map, CellWidth::I8, RunOptions::default(), vec![], vec![], BTreeSet::from([4]));
+
+        let first = paused(&session);
+        assert_eq!((first.steps, first.bar, first.instruction.as_str()), (0, Some(1), "add 2"));
+        session.commands.send(DebugCommand::Step).unwrap();
+        let second = paused(&session);
+        assert_eq!((second.steps, second.bar, second.cells), (1, Some(2), vec![Wrapping(2)]));
+
+        session.commands.send(DebugCommand::ToggleBreakpoint(3)).unwrap();
+        session.commands.send(DebugCommand::Continue).unwrap();
+        let third = paused(&session);
+        assert_eq!((third.bar, third.pointer), (Some(3), 1));
+        assert_eq!(third.breakpoints, BTreeSet::from([3, 4]));
+        session.commands.send(DebugCommand::Continue).unwrap();
+        assert_eq!(paused(&session).bar, Some(4));
+        session.commands.send(DebugCommand::Continue).unwrap();
+        assert_eq!(session.events.recv().unwrap(), DebugEvent::Finished(Ok(())));
+        assert_eq!(session.output.contents(), [3]);
+    }
+
+    #[test]
+    fn stops_at_breakpoint_chords() {
+        let prog = build(vec![
+            MidiInstruction::new_inc(Wrapping(2)),
+            MidiInstruction::new_breakpoint(),
+            MidiInstruction::new_inc(Wrapping(3)),
+        ]);
+        // everything is in the first bar
+        let mut source_map = SourceMap::new(Timing::Metrical(u15::from(480)));
+        for beat in 0..3 {
+            source_map.push_instruction(beat * 480);
+        }
+        let session = Session::start(prog, &source_map, CellWidth::I8, RunOptions::default(), vec![], vec![], BTreeSet::new());
+
+        assert_eq!(paused(&session).instruction, "add 2");
+        session.commands.send(DebugCommand::Continue).unwrap();
+        let breakpoint = paused(&session);
+        assert_eq!((breakpoint.instruction.as_str(), breakpoint.cells), ("breakpoint", vec![Wrapping(2)]));
+        session.commands.send(DebugCommand::Continue).unwrap();
+        assert_eq!(session.events.recv().unwrap(), DebugEvent::Finished(Ok(())));
+    }
+}
@@ -0,0 +1,284 @@
+use std::fmt::Write;
+use std::io::IsTerminal;
+
+use crate::check::{Diagnostic, Severity};
+use crate::failure::json_string;
+use crate::parser::{read_chord, Chord, LocatedError, MParseError, MParseResult, MidiInstruction, MidiInstructionKind, Parsed};
+
+/// Chords shown before and after the one an error is about.
+const CONTEXT_BEFORE: usize = 2;
+const CONTEXT_AFTER: usize = 1;
+
+pub(crate) const NOTE_NAMES: [&str; 12] = ["C", "C♯", "D", "D♯", "E", "F", "F♯", "G", "G♯", "A", "A♯", "B"];
+
+/// Whether diagnostics printed to stderr should be colored, which they are on
+/// terminals unless `NO_COLOR` is set.
+pub fn use_color() -> bool {
+    std::io::stderr().is_terminal() && std::env::var_os("NO_COLOR").is_none()
+}
+
+/// Renders every error `parse_all` found in `file_path`, with the notes of the
+/// chords around each one, where in the song they are and a hint at what to play
+/// instead.
+pub fn render(file_path: &str, parsed: &Parsed, color: bool) -> String {
+    let mut out = String::new();
+    for err in &parsed.errors {
+        render_error(&mut out, file_path, err, parsed, color);
+        out.push('\n');
+    }
+    out
+}
+
+fn render_error(out: &mut String, file_path: &str, err: &LocatedError, parsed: &Parsed, color: bool) {
+    let paint = |text: &str, code: &str| match color {
+        true => format!("\x1b[{}m{}\x1b[0m", code, text),
+        false => text.to_owned(),
+    };
+    writeln!(out, "{}: {}", paint("error", "1;31"), paint(&err.error.describe(), "1")).unwrap();
+    let location = match err.tick {
+        Some(tick) => match parsed.source_map.bar_beat_at(tick) {
+            Some(bar_beat) => format!("{}:{}", file_path, bar_beat),
+            None => format!("{} at tick {}", file_path, tick),
+        },
+        None => file_path.to_owned(),
+    };
+    writeln!(out, "  {} {}", paint("-->", "1;34"), location).unwrap();
+
+    let Some(index) = err.chord else {
+        return;
+    };
+    let shown = index.saturating_sub(CONTEXT_BEFORE)..(index + CONTEXT_AFTER + 1).min(parsed.chords.len());
+    let rows: Vec<(String, String, String)> = parsed.chords[shown.clone()]
+        .iter()
+        .map(|chord| {
+            let place = match parsed.source_map.bar_beat_at(chord.tick) {
+                Some(bar_beat) => bar_beat.to_string(),
+                None => chord.tick.to_string(),
+            };
+            let notes: Vec<String> = chord.notes.iter().map(|&key| note_name(key)).collect();
+            (place, notes.join(" "), describe_reading(&chord.reading))
+        })
+        .collect();
+    let place_width = rows.iter().map(|row| row.0.chars().count()).max().unwrap_or(0);
+    let notes_width = rows.iter().map(|row| row.1.chars().count()).max().unwrap_or(0);
+    let gutter = |place: &str| paint(&format!("{:>width$} |", place, width = place_width), "1;34");
+
+    writeln!(out, "{}", gutter("")).unwrap();
+    for (chord, (place, notes, reading)) in shown.zip(&rows) {
+        let padding = " ".repeat(notes_width - notes.chars().count());
+        if chord == index {
+            writeln!(out, "{} {}{}  {}", gutter(place), paint(notes, "1"), padding, reading).unwrap();
+            let carets = "^".repeat(notes.chars().count().max(1));
+            writeln!(out, "{} {}", gutter(""), paint(&carets, "1;31")).unwrap();
+        } else {
+            writeln!(out, "{} {}{}  {}", gutter(place), notes, padding, reading).unwrap();
+        }
+    }
+    if let Some(hint) = hint(&err.error, &parsed.chords[index]) {
+        let equals = paint(&format!("{:>width$} =", "", width = place_width), "1;34");
+        writeln!(out, "{} {}: {}", equals, paint("hint", "1;36"), hint).unwrap();
+    }
+}
+
+/// `diag` in `file_path` as a single line JSON object.
+pub fn to_json(file_path: &str, diag: &Diagnostic) -> String {
+    let optional = |value: Option<u64>| value.map_or_else(|| "null".to_owned(), |value| value.to_string());
+    let notes: Vec<String> = diag.notes.iter().map(u8::to_string).collect();
+    let names: Vec<String> = diag.notes.iter().map(|&key| json_string(&note_name(key))).collect();
+    format!(
+        "{{\"file\":{},\"code\":\"{}\",\"severity\":\"{}\",\"message\":{},\"tick\":{},\"bar\":{},\"beat\":{},\"chord\":{},\"notes\":[{}],\"note_names\":[{}]}}",
+        json_string(file_path),
+        diag.code,
+        diag.severity,
+        json_string(&diag.message),
+        optional(diag.tick),
+        optional(diag.bar_beat.map(|bar_beat| bar_beat.bar)),
+        optional(diag.bar_beat.map(|bar_beat| bar_beat.beat)),
+        optional(diag.chord.map(|chord| chord as u64)),
+        notes.join(","),
+        names.join(","),
+    )
+}
+
+/// A SARIF 2.1.0 log of the diagnostics of every file in `files`, as one run of
+/// midilang. MIDI files have no lines, so results are located by bar and beat
+/// as logical locations, with the tick, chord and notes as properties.
+pub fn to_sarif(files: &[(&str, &[Diagnostic])]) -> String {
+    let mut rules: Vec<&str> = files.iter().flat_map(|(_, diags)| diags.iter().map(|diag| diag.code)).collect();
+    rules.sort_unstable();
+    rules.dedup();
+    let rules: Vec<String> = rules.iter().map(|code| format!("{{\"id\":\"{}\"}}", code)).collect();
+
+    let mut results = vec![];
+    for (file_path, diags) in files {
+        for diag in diags.iter() {
+            let level = match diag.severity {
+                Severity::Warning => "warning",
+                Severity::Error => "error",
+            };
+            let mut location = format!("{{\"physicalLocation\":{{\"artifactLocation\":{{\"uri\":{}}}}}", json_string(file_path));
+            if let Some(bar_beat) = diag.bar_beat {
+                write!(location, ",\"logicalLocations\":[{{\"name\":\"{}\",\"kind\":\"bar:beat\"}}]", bar_beat).unwrap();
+            }
+            location.push('}');
+            let mut properties = vec![];
+            if let Some(tick) = diag.tick {
+                properties.push(format!("\"tick\":{}", tick));
+            }
+            if let Some(chord) = diag.chord {
+                properties.push(format!("\"chord\":{}", chord));
+                let notes: Vec<String> = diag.notes.iter().map(u8::to_string).collect();
+                properties.push(format!("\"notes\":[{}]", notes.join(",")));
+            }
+            results.push(format!(
+                "{{\"ruleId\":\"{}\",\"level\":\"{}\",\"message\":{{\"text\":{}}},\"locations\":[{}],\"properties\":{{{}}}}}",
+                diag.code,
+                level,
+                json_string(&diag.message),
+                location,
+                properties.join(",")
+            ));
+        }
+    }
+    format!(
+        "{{\"$schema\":\"https://json.schemastore.org/sarif-2.1.0.json\",\"version\":\"2.1.0\",\"runs\":[{{\"tool\":{{\"driver\":{{\"name\":\"midilang\",\"version\":\"{}\",\"rules\":[{}]}}}},\"results\":[{}]}}]}}",
+        env!("CARGO_PKG_VERSION"),
+        rules.join(","),
+        results.join(",")
+    )
+}
+
+/// Name of the MIDI note `key`, like `C4` for middle C.
+pub(crate) fn note_name(key: u8) -> String {
+    format!("{}{}", NOTE_NAMES[usize::from(key % 12)], i32::from(key / 12) - 1)
+}
+
+/// What a chord reads as on its own, the way `MidiASTBuilder` takes it.
+pub(crate) fn describe_reading(reading: &MParseResult<MidiInstruction>) -> String {
+    match reading {
+        Ok(MidiInstruction { position: Some(_), instruction: MidiInstructionKind::Loop { .. } }) => "open a loop".to_owned(),
+        Ok(MidiInstruction { position: None, instruction: MidiInstructionKind::Loop { .. } }) => "close a loop".to_owned(),
+        Ok(inst) => inst.instruction.describe(),
+        Err(_) => "not an instruction".to_owned(),
+    }
+}
+
+fn hint(error: &MParseError, chord: &Chord) -> Option<String> {
+    match error {
+        MParseError::NonDiatonic => {
+            let root = *chord.notes.first()?;
+            // every note outside of C major is a semitone away from two inside it
+            let alternatives: Vec<String> = [1, -1]
+                .into_iter()
+                .filter_map(|shift| {
+                    let notes: Vec<u8> = chord
+                        .notes
+                        .iter()
+                        .map(|&key| key.checked_add_signed(shift).filter(|key| *key < 128))
+                        .collect::<Option<_>>()?;
+                    let reading = read_chord(notes.clone());
+                    let name = NOTE_NAMES[usize::from(notes[0] % 12)];
+                    reading.is_ok().then(|| format!("{} ({})", name, describe_reading(&reading)))
+                })
+                .collect();
+            Some(format!(
+                "{} is not diatonic in C major, did you mean {}?",
+                NOTE_NAMES[usize::from(root % 12)],
+                alternatives.join(" or ")
+            ))
+        }
+        MParseError::DanglingLoop(_) => Some("no loop is open here, open one with a G chord before it".to_owned()),
+        MParseError::UnclosedLoop(_) => Some("close the loop with a C chord after its body".to_owned()),
+        MParseError::ShortString(_) => Some("the argument of a D♯ chord is how many notes its string has".to_owned()),
+        MParseError::UnnamedCall => Some("name the function in a text event before the chord".to_owned()),
+        MParseError::NeedsExtensions => Some("read the song with --extensions to take it as one".to_owned()),
+        MParseError::NoTracks | MParseError::Directive(_) => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+    use crate::parser::parse_all;
+    use midly::num::{u15, u28, u4, u7};
+    use midly::{Format, Header, MidiMessage, Smf, Timing, TrackEvent, TrackEventKind};
+
+    fn smf(chords: &[&[u8]]) -> Smf<'static> {
+        let mut track = vec![];
+        for keys in chords {
+            for (on, delta) in [(true, 0), (false, 96)] {
+                for (idx, &key) in keys.iter().enumerate() {
+                    let message = match on {
+                        true => MidiMessage::NoteOn { key: u7::from(key), vel: u7::from(100) },
+                        false => MidiMessage::NoteOff { key: u7::from(key), vel: u7::from(0) },
+                    };
+                    track.push(TrackEvent {
+                        delta: u28::from(if idx == 0 { delta } else { 0 }),
+                        kind: TrackEventKind::Midi { channel: u4::from(0), message },
+                    });
+                }
+            }
+        }
+        let mut midi = Smf::new(Header::new(Format::SingleTrack, Timing::Metrical(u15::from(96))));
+        midi.tracks.push(track);
+        midi
+    }
+
+    #[test]
+    fn shows_the_chord_in_context_with_a_hint() {
+        // +, >, G♯ chord, +
+        let parsed = parse_all(smf(&[&[69], &[64], &[68, 72], &[69]]));
+        assert_eq!(
+            render("song.mid", &parsed, false),
+            "error: chord's root isn't in C major\n\
+             \x20 --> song.mid:1:3\n\
+             \x20   |\n\
+             1:1 | A4      add 1\n\
+             1:2 | E4      move 1\n\
+             1:3 | G♯4 C5  not an instruction\n\
+             \x20   | ^^^^^^\n\
+             1:4 | A4      add 1\n\
+             \x20   = hint: G♯ is not diatonic in C major, did you mean A (add 1) or G (open a loop)?\n\n"
+        );
+    }
+
+    #[test]
+    fn points_unclosed_loops_at_their_opening_chord() {
+        // [ + with a C♯ in between
+        let parsed = parse_all(smf(&[&[67], &[61], &[69]]));
+        let rendered = render("song.mid", &parsed, true);
+        assert!(rendered.contains("\x1b[1;31merror\x1b[0m"));
+        assert!(rendered.contains("song.mid:1:1"));
+        assert!(rendered.contains("song.mid:1:2"));
+        assert!(rendered.contains("close the loop with a C chord"));
+        let plain = render("song.mid", &parsed, false);
+        assert_eq!(plain.matches("error: ").count(), 2);
+        assert!(plain.starts_with("error: chord's root isn't in C major"));
+    }
+
+    #[test]
+    fn writes_structured_diagnostics() {
+        // +, G♯ chord
+        let parsed = parse_all(smf(&[&[69], &[68, 72]]));
+        let report = crate::check::check_parsed(&parsed, true);
+        assert_eq!(
+            to_json("song.mid", &report.diagnostics[0]),
+            r#"{"file":"song.mid","code":"non-diatonic","severity":"error","message":"chord's root isn't in C major","tick":96,"bar":1,"beat":2,"chord":1,"notes":[68,72],"note_names":["G♯4","C5"]}"#
+        );
+        let sarif = to_sarif(&[("song.mid", &report.diagnostics)]);
+        assert!(sarif.starts_with(r#"{"$schema":"https://json.schemastore.org/sarif-2.1.0.json","version":"2.1.0","runs":[{"tool":{"driver":{"name":"midilang""#));
+        assert!(sarif.contains(r#""rules":[{"id":"non-diatonic"}]"#));
+        assert!(sarif.contains(
+            r#"{"ruleId":"non-diatonic","level":"error","message":{"text":"chord's root isn't in C major"},"locations":[{"physicalLocation":{"artifactLocation":{"uri":"song.mid"}},"logicalLocations":[{"name":"1:2","kind":"bar:beat"}]}],"properties":{"tick":96,"chord":1,"notes":[68,72]}}"#
+        ));
+        assert!(to_sarif(&[]).ends_with(r#""rules":[]}},"results":[]}]}"#));
+    }
+
+    #[test]
+    fn names_notes() {
+        assert_eq!(note_name(60), "C4");
+        assert_eq!(note_name(0), "C-1");
+        assert_eq!(note_name(127), "G9");
+    }
+}
@@ -0,0 +1,464 @@
+//! Writing programs out as text other tools can read, and reading them back.
+//!
+//! JSON is the AST as serde sees it, DOT a control flow graph for Graphviz and
+//! LilyPond and MusicXML scores of the chords, none of which can be read back,
+//! and S-expressions are one form per instruction with an optional
+//! `(at START END)` for its position:
+//!
+//! ```text
+//! (add (at 0 7) 8)
+//! (loop (at 8 12)
+//!   (move (at 9 9) 1)
+//!   (output (at 10 10)))
+//! (breakpoint)
+//! ```
+
+use std::error::Error;
+use std::fmt::{Debug, Display, Write};
+use std::num::Wrapping;
+
+use crate::parser::{self, MidiAST, MidiInstruction, MidiInstructionKind::*, Parsed, Position};
+use crate::program::write_bf;
+use crate::timing::SourceMap;
+use crate::{brainf_to_smf, lilypond, musicxml};
+
+/// Text formats a program can be dumped as.
+#[derive(Debug, Default, PartialEq, Eq, Clone, Copy, clap::ValueEnum)]
+pub enum DumpFormat {
+    /// The AST as JSON, for tools in any language
+    #[default]
+    Json,
+    /// One S-expression per instruction, for reading and editing by hand
+    Sexp,
+    /// The control flow graph for Graphviz, with the bars of every block
+    Dot,
+    /// A LilyPond score of the chords, with what each one does written under it
+    Lilypond,
+    /// A MusicXML score of the chords for notation editors, with what each one
+    /// does as its lyric
+    Musicxml,
+}
+
+impl DumpFormat {
+    /// The format `source` was dumped as, `None` when it doesn't look like a
+    /// dump. MIDI files start with `MThd` or `RIFF`, so they're never mistaken for one.
+    pub fn detect(source: &[u8]) -> Option<Self> {
+        match source.iter().find(|byte| !byte.is_ascii_whitespace())? {
+            b'[' => Some(DumpFormat::Json),
+            b'(' | b';' => Some(DumpFormat::Sexp),
+            _ => None,
+        }
+    }
+}
+
+pub enum DumpError {
+    Json(serde_json::Error),
+    /// What's wrong with an S-expression and the line it starts on
+    Sexp(usize, String),
+    /// Graphs and scores leave out too much to get the program back
+    WriteOnly(DumpFormat),
+}
+
+impl Display for DumpError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Json(err) => write!(f, "Invalid JSON program: {}", err),
+            Self::Sexp(line, msg) => write!(f, "Invalid S-expression program at line {}: {}", line, msg),
+            Self::WriteOnly(format) => write!(f, "{:?} dumps can't be read back as programs", format),
+        }
+    }
+}
+
+impl Debug for DumpError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        Display::fmt(self, f)
+    }
+}
+
+impl Error for DumpError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            Self::Json(err) => Some(err),
+            Self::Sexp(..) | Self::WriteOnly(_) => None,
+        }
+    }
+}
+
+/// `program` written out in `format`, ending in a newline. Blocks in graphs
+/// are labelled with bars when there's a source map. Scores are of the chords
+/// `MidiProgram::to_smf` would write, see `dump_file` for one of the song itself.
+pub fn write(program: &MidiAST, format: DumpFormat, source_map: Option<&SourceMap>) -> String {
+    match format {
+        DumpFormat::Json => {
+            let mut json = serde_json::to_string_pretty(program).expect("ASTs always serialize");
+            json.push('\n');
+            json
+        }
+        DumpFormat::Sexp => {
+            let mut sexp = String::new();
+            for inst in program {
+                write_sexp(inst, 0, &mut sexp);
+                sexp.push('\n');
+            }
+            sexp
+        }
+        DumpFormat::Dot => {
+            let mut cfg = Cfg {
+                source_map,
+                dot: "digraph program {\n  node [shape=box, fontname=\"monospace\"];\n".to_owned(),
+                nodes: 0,
+            };
+            let start = cfg.node("start", "circle");
+            let last = cfg.blocks(program, (start, ""));
+            let end = cfg.node("end", "doublecircle");
+            cfg.edge(last, end);
+            cfg.dot.push_str("}\n");
+            cfg.dot
+        }
+        DumpFormat::Lilypond | DumpFormat::Musicxml => {
+            let mut bf = String::new();
+            write_bf(program, &mut bf);
+            write_score(&parser::parse_all(brainf_to_smf(&bf)), format, "")
+        }
+    }
+}
+
+/// The chords of a parsed song as a score in `format`, which has to be
+/// `Lilypond` or `Musicxml`.
+pub(crate) fn write_score(parsed: &Parsed, format: DumpFormat, title: &str) -> String {
+    match format {
+        DumpFormat::Lilypond => lilypond::engrave(parsed, title),
+        DumpFormat::Musicxml => musicxml::write(parsed, title),
+        format => unreachable!("{:?} isn't a score", format),
+    }
+}
+
+/// Reads back a program `write` wrote in `format`.
+pub fn read(source: &str, format: DumpFormat) -> Result<MidiAST, DumpError> {
+    match format {
+        DumpFormat::Json => serde_json::from_str(source).map_err(DumpError::Json),
+        DumpFormat::Sexp => tokenize(source)?.iter().map(to_instruction).collect(),
+        DumpFormat::Dot | DumpFormat::Lilypond | DumpFormat::Musicxml => Err(DumpError::WriteOnly(format)),
+    }
+}
+
+/// A control flow graph being written as DOT. Every loop is a diamond testing
+/// the cell, with the straight runs of instructions around it as blocks.
+struct Cfg<'a> {
+    source_map: Option<&'a SourceMap>,
+    dot: String,
+    /// Number of nodes so far, which names the next one
+    nodes: usize,
+}
+
+impl Cfg<'_> {
+    fn node(&mut self, label: &str, shape: &str) -> usize {
+        writeln!(self.dot, "  n{} [label=\"{}\", shape={}];", self.nodes, label, shape).unwrap();
+        self.nodes += 1;
+        self.nodes - 1
+    }
+
+    /// Edge from the node control is leaving, labelled with when it does.
+    fn edge(&mut self, (from, when): (usize, &str), to: usize) {
+        match when {
+            "" => writeln!(self.dot, "  n{} -> n{};", from, to),
+            when => writeln!(self.dot, "  n{} -> n{} [label=\"{}\"];", from, to, when),
+        }
+        .unwrap();
+    }
+
+    /// Adds `program` after `from`, returning the edge control leaves it by.
+    fn blocks<'p>(&mut self, program: &'p [MidiInstruction], mut from: (usize, &'static str)) -> (usize, &'static str) {
+        let mut block: Vec<&'p MidiInstruction> = vec![];
+        for inst in program {
+            let Loop { body } = &inst.instruction else {
+                block.push(inst);
+                continue;
+            };
+            from = self.block(&block, from);
+            block.clear();
+            let label = match inst.position {
+                Some(position) => format!("loop\\n{}", self.location(position.start())),
+                None => "loop".to_owned(),
+            };
+            let test = self.node(&label, "diamond");
+            self.edge(from, test);
+            let body_end = self.blocks(body, (test, "not 0"));
+            self.edge(body_end, test);
+            from = (test, "0");
+        }
+        self.block(&block, from)
+    }
+
+    /// Adds a node for a straight run of instructions, with runs of adds and
+    /// moves fused, unless there aren't any.
+    fn block(&mut self, block: &[&MidiInstruction], from: (usize, &'static str)) -> (usize, &'static str) {
+        let (Some(first), Some(last)) = (block.first(), block.last()) else {
+            return from;
+        };
+        // ops and their amounts, along with the function of calls
+        let mut fused: Vec<(char, i64, &str)> = vec![];
+        for inst in block {
+            let (op, amount, function) = match &inst.instruction {
+                IncrementCell { amount } => ('+', i64::from(amount.0), ""),
+                MovePointer { amount } => ('>', *amount as i64, ""),
+                OutputCell => ('.', 1, ""),
+                InputCell => (',', 1, ""),
+                Breakpoint => ('#', 1, ""),
+                Call { name } => ('!', 1, name.as_str()),
+                Random => ('?', 1, ""),
+                DumpTape => ('$', 1, ""),
+                Loop { .. } => unreachable!("loops end blocks"),
+            };
+            match fused.last_mut() {
+                Some((last, total, _)) if *last == op && "+>".contains(op) => *total += amount,
+                _ => fused.push((op, amount, function)),
+            }
+        }
+        let ops: Vec<String> = fused
+            .iter()
+            .filter(|&&(_, amount, _)| amount != 0)
+            .map(|&(op, amount, function)| {
+                let op = match op {
+                    '+' if amount < 0 => '-',
+                    '>' if amount < 0 => '<',
+                    '!' => return format!("{}()", function),
+                    op => op,
+                };
+                match amount.unsigned_abs() {
+                    1 => op.to_string(),
+                    times => format!("{}{}", op, times),
+                }
+            })
+            .collect();
+        let mut label = match (first.position, last.position) {
+            (Some(first), Some(last)) => {
+                let (start, end) = (self.location(first.start()), self.location(last.end()));
+                if start == end {
+                    start
+                } else {
+                    format!("{} to {}", start, end)
+                }
+            }
+            _ => String::new(),
+        };
+        // a few per line keeps long blocks from getting too wide to read
+        for line in ops.chunks(8) {
+            if !label.is_empty() {
+                label.push_str("\\n");
+            }
+            label.push_str(&line.join(" "));
+        }
+        let node = self.node(&label, "box");
+        self.edge(from, node);
+        (node, "")
+    }
+
+    /// Bar and beat of the chord of instruction `index`, its timecode in
+    /// timecode based files, or its number.
+    fn location(&self, index: usize) -> String {
+        match (self.source_map.and_then(|map| map.bar_beat(index)), self.source_map.and_then(|map| map.timecode(index))) {
+            (Some(bar_beat), _) => format!("bar {}", bar_beat),
+            (None, Some(timecode)) => format!("at {}", timecode),
+            (None, None) => format!("inst {}", index),
+        }
+    }
+}
+
+fn write_sexp(inst: &MidiInstruction, depth: usize, out: &mut String) {
+    let name = match &inst.instruction {
+        IncrementCell { .. } => "add",
+        MovePointer { .. } => "move",
+        OutputCell => "output",
+        InputCell => "input",
+        Loop { .. } => "loop",
+        Breakpoint => "breakpoint",
+        Call { .. } => "call",
+        Random => "random",
+        DumpTape => "dump",
+    };
+    write!(out, "({}", name).unwrap();
+    if let Some(position) = inst.position {
+        write!(out, " (at {} {})", position.start(), position.end()).unwrap();
+    }
+    match &inst.instruction {
+        IncrementCell { amount } => write!(out, " {}", amount).unwrap(),
+        MovePointer { amount } => write!(out, " {}", amount).unwrap(),
+        Call { name } => write!(out, " {}", name).unwrap(),
+        Loop { body } => {
+            for inst in body {
+                write!(out, "\n{:indent$}", "", indent = 2 * (depth + 1)).unwrap();
+                write_sexp(inst, depth + 1, out);
+            }
+        }
+        _ => {}
+    }
+    out.push(')');
+}
+
+/// A parsed S-expression, along with the line it starts on.
+enum Sexp {
+    Atom(usize, String),
+    List(usize, Vec<Sexp>),
+}
+
+impl Sexp {
+    fn line(&self) -> usize {
+        match self {
+            Sexp::Atom(line, _) | Sexp::List(line, _) => *line,
+        }
+    }
+}
+
+// every S-expression in `source`, skipping `;` comments
+fn tokenize(source: &str) -> Result<Vec<Sexp>, DumpError> {
+    // lists still being read, innermost on top, under the top level
+    let mut lists: Vec<(usize, Vec<Sexp>)> = vec![(1, vec![])];
+    let mut line = 1;
+    let mut chars = source.chars().peekable();
+    while let Some(c) = chars.next() {
+        match c {
+            '\n' => line += 1,
+            ';' => {
+                while chars.next_if(|&c| c != '\n').is_some() {}
+            }
+            '(' => lists.push((line, vec![])),
+            ')' => {
+                if lists.len() == 1 {
+                    return Err(DumpError::Sexp(line, "`)` without a `(`".to_owned()));
+                }
+                let (start, items) = lists.pop().unwrap();
+                lists.last_mut().unwrap().1.push(Sexp::List(start, items));
+            }
+            c if c.is_whitespace() => {}
+            c => {
+                let mut atom = c.to_string();
+                while let Some(c) = chars.next_if(|&c| !c.is_whitespace() && !"();".contains(c)) {
+                    atom.push(c);
+                }
+                lists.last_mut().unwrap().1.push(Sexp::Atom(line, atom));
+            }
+        }
+    }
+    if lists.len() > 1 {
+        let (start, _) = lists.pop().unwrap();
+        return Err(DumpError::Sexp(start, "`(` is never closed".to_owned()));
+    }
+    Ok(lists.pop().unwrap().1)
+}
+
+fn to_instruction(sexp: &Sexp) -> Result<MidiInstruction, DumpError> {
+    let line = sexp.line();
+    let error = |msg: String| DumpError::Sexp(line, msg);
+    let Sexp::List(_, items) = sexp else {
+        return Err(error("expected an instruction like `(add 1)`".to_owned()));
+    };
+    let Some(Sexp::Atom(_, name)) = items.first() else {
+        return Err(error("expected an instruction name".to_owned()));
+    };
+    let mut args = &items[1..];
+    let position = match args.first() {
+        Some(Sexp::List(_, at)) if matches!(at.first(), Some(Sexp::Atom(_, name)) if name == "at") => {
+            args = &args[1..];
+            match &at[1..] {
+                [Sexp::Atom(_, start), Sexp::Atom(_, end)] => match (start.parse(), end.parse()) {
+                    (Ok(start), Ok(end)) => Some(Position::new(start, end)),
+                    _ => return Err(error(format!("`(at {} {})` isn't a position", start, end))),
+                },
+                _ => return Err(error("`at` takes a start and an end".to_owned())),
+            }
+        }
+        _ => None,
+    };
+    let number = |args: &[Sexp]| match args {
+        [Sexp::Atom(_, amount)] => amount.parse::<i64>().map_err(|_| error(format!("`{}` isn't a number", amount))),
+        _ => Err(error(format!("`{}` takes a number", name))),
+    };
+    let no_args = |kind| match args {
+        [] => Ok(kind),
+        _ => Err(error(format!("`{}` takes no arguments", name))),
+    };
+    let instruction = match name.as_str() {
+        // cells wrap around anyway
+        "add" => IncrementCell {
+            amount: Wrapping(number(args)? as i32),
+        },
+        "move" => MovePointer { amount: number(args)? as isize },
+        "output" => no_args(OutputCell)?,
+        "input" => no_args(InputCell)?,
+        "breakpoint" => no_args(Breakpoint)?,
+        "random" => no_args(Random)?,
+        "dump" => no_args(DumpTape)?,
+        "call" => match args {
+            [Sexp::Atom(_, function)] => Call { name: function.clone() },
+            _ => return Err(error("`call` takes the name of a function".to_owned())),
+        },
+        "loop" => Loop {
+            body: args.iter().map(to_instruction).collect::<Result<_, _>>()?,
+        },
+        name => return Err(error(format!("unknown instruction `{}`", name))),
+    };
+    Ok(MidiInstruction { position, instruction })
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+    use crate::MidiProgram;
+
+    #[test]
+    fn round_trips() {
+        let program = MidiProgram::from_bf_str(",[->+>--<<]#>.[[-]>]>.").unwrap();
+        for format in [DumpFormat::Json, DumpFormat::Sexp] {
+            let dump = write(program.ast(), format, None);
+            assert_eq!(DumpFormat::detect(dump.as_bytes()), Some(format));
+            assert_eq!(&read(&dump, format).unwrap(), program.ast());
+        }
+        assert_eq!(DumpFormat::detect(b"MThd"), None);
+    }
+
+    #[test]
+    fn reads_hand_written_sexps() {
+        let source = "; prints a !\n(add 33)\n(loop (at 4 9)\n  (move -1))\n(output (at 2 2))";
+        let program = read(source, DumpFormat::Sexp).unwrap();
+        assert_eq!(
+            write(&program, DumpFormat::Sexp, None),
+            "(add 33)\n(loop (at 4 9)\n  (move -1))\n(output (at 2 2))\n"
+        );
+
+        let err = |source| read(source, DumpFormat::Sexp).unwrap_err().to_string();
+        assert_eq!(err("(add 1)\n(loop (output)"), "Invalid S-expression program at line 2: `(` is never closed");
+        assert_eq!(err("(add one)"), "Invalid S-expression program at line 1: `one` isn't a number");
+        assert_eq!(err("\n\n(jump 3)"), "Invalid S-expression program at line 3: unknown instruction `jump`");
+        assert_eq!(err("(output 1)"), "Invalid S-expression program at line 1: `output` takes no arguments");
+        assert_eq!(err("(call)"), "Invalid S-expression program at line 1: `call` takes the name of a function");
+        assert!(read("[{\"position\": null}]", DumpFormat::Json).is_err());
+        let calls = read("(call (at 0 0) toupper) (output)", DumpFormat::Sexp).unwrap();
+        assert_eq!(write(&calls, DumpFormat::Sexp, None), "(call (at 0 0) toupper)\n(output)\n");
+        let random = read("(random) (loop (random))", DumpFormat::Sexp).unwrap();
+        assert_eq!(write(&random, DumpFormat::Sexp, None), "(random)\n(loop\n  (random))\n");
+    }
+
+    #[test]
+    fn draws_loops_in_graphs() {
+        let program = MidiProgram::from_bf_str("++>-[<+>-]").unwrap();
+        let dot = write(program.ast(), DumpFormat::Dot, None);
+        assert_eq!(
+            dot,
+            "digraph program {\n  node [shape=box, fontname=\"monospace\"];\n\
+             \x20 n0 [label=\"start\", shape=circle];\n\
+             \x20 n1 [label=\"inst 0 to inst 3\\n+2 > -\", shape=box];\n\
+             \x20 n0 -> n1;\n\
+             \x20 n2 [label=\"loop\\ninst 4\", shape=diamond];\n\
+             \x20 n1 -> n2;\n\
+             \x20 n3 [label=\"inst 5 to inst 8\\n< + > -\", shape=box];\n\
+             \x20 n2 -> n3 [label=\"not 0\"];\n\
+             \x20 n3 -> n2;\n\
+             \x20 n4 [label=\"end\", shape=doublecircle];\n\
+             \x20 n2 -> n4 [label=\"0\"];\n\
+             }\n"
+        );
+        assert!(read(&dot, DumpFormat::Dot).is_err());
+    }
+}
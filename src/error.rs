@@ -0,0 +1,197 @@
+use std::error::Error;
+use std::fmt::{Debug, Display};
+use std::io;
+
+use log::error;
+
+use crate::compiler::MCompileError;
+use crate::dump::DumpError;
+use crate::failure::{ErrorFormat, Failure, FailureKind, EXIT_FAILURE};
+use crate::interpreter::InterpError;
+use crate::parser::MParseError;
+#[cfg(feature = "play")]
+use crate::play::PlayError;
+use crate::verify::VerifyError;
+
+pub type MidilangResult<T> = Result<T, MidilangError>;
+
+/// Anything that can go wrong in midilang, returned by everything in `lib.rs`.
+pub enum MidilangError {
+    Io(io::Error),
+    /// The file isn't a standard MIDI file
+    Midi(midly::Error),
+    Parse(MParseError),
+    /// A dumped program that can't be read back
+    Dump(DumpError),
+    Compile(MCompileError),
+    /// The program failed while running, at the bar and beat of the instruction
+    /// when that's known
+    Run(InterpError, Option<String>),
+    Verify(VerifyError),
+    #[cfg(feature = "play")]
+    Play(PlayError),
+    /// A file that couldn't be compiled, with everything found wrong with it
+    Failed(Box<Failure>),
+    /// Something midilang can't do, described for people
+    Other(String),
+}
+
+impl MidilangError {
+    /// Exit code for the error: 2 for files that don't parse, 3 when the
+    /// backend fails, 4 when reading or writing a file does and 1 for anything else.
+    pub fn exit_code(&self) -> i32 {
+        let kind = match self {
+            Self::Io(_) => FailureKind::Io,
+            Self::Midi(_) | Self::Parse(_) | Self::Dump(_) => FailureKind::Parse,
+            Self::Compile(_) | Self::Verify(VerifyError::Compile(_)) => FailureKind::Codegen,
+            Self::Failed(failure) => failure.kind,
+            _ => return EXIT_FAILURE,
+        };
+        kind.exit_code()
+    }
+
+    /// The error as JSON, files that failed like `--error-format json` prints
+    /// them and everything else as just a `message`.
+    pub fn to_json_value(&self) -> serde_json::Value {
+        match self {
+            Self::Failed(failure) => serde_json::from_str(&failure.to_json()).unwrap_or_default(),
+            err => serde_json::json!({ "message": err.to_string() }),
+        }
+    }
+
+    /// Prints the error to stderr, files that failed in `format` and
+    /// everything else as a log line.
+    pub fn report(&self, format: ErrorFormat) {
+        match self {
+            Self::Failed(failure) => failure.report(format),
+            err => error!("Application Error {}", err),
+        }
+    }
+}
+
+impl Display for MidilangError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Io(err) => write!(f, "IO error: {}", err),
+            Self::Midi(err) => write!(f, "Not a MIDI file: {}", err),
+            Self::Parse(err) => write!(f, "Parse error: {}", err),
+            Self::Dump(err) => write!(f, "{}", err),
+            Self::Compile(err) => write!(f, "{}", err),
+            Self::Run(err, Some(location)) => write!(f, "{} at {}", err, location),
+            Self::Run(err, None) => write!(f, "{}", err),
+            Self::Verify(err) => write!(f, "{}", err),
+            #[cfg(feature = "play")]
+            Self::Play(err) => write!(f, "{}", err),
+            Self::Failed(failure) => write!(f, "{}: {}", failure.file, failure.message),
+            Self::Other(msg) => write!(f, "{}", msg),
+        }
+    }
+}
+
+impl Debug for MidilangError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        Display::fmt(self, f)
+    }
+}
+
+impl Error for MidilangError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            Self::Io(err) => Some(err),
+            Self::Midi(err) => Some(err),
+            Self::Parse(err) => Some(err),
+            Self::Dump(err) => Some(err),
+            Self::Compile(err) => Some(err),
+            Self::Run(err, _) => Some(err),
+            Self::Verify(err) => Some(err),
+            #[cfg(feature = "play")]
+            Self::Play(err) => Some(err),
+            Self::Failed(_) | Self::Other(_) => None,
+        }
+    }
+}
+
+impl From<io::Error> for MidilangError {
+    fn from(err: io::Error) -> Self {
+        Self::Io(err)
+    }
+}
+
+impl From<midly::Error> for MidilangError {
+    fn from(err: midly::Error) -> Self {
+        Self::Midi(err)
+    }
+}
+
+impl From<MParseError> for MidilangError {
+    fn from(err: MParseError) -> Self {
+        Self::Parse(err)
+    }
+}
+
+impl From<DumpError> for MidilangError {
+    fn from(err: DumpError) -> Self {
+        Self::Dump(err)
+    }
+}
+
+impl From<MCompileError> for MidilangError {
+    fn from(err: MCompileError) -> Self {
+        Self::Compile(err)
+    }
+}
+
+impl From<InterpError> for MidilangError {
+    fn from(err: InterpError) -> Self {
+        Self::Run(err, None)
+    }
+}
+
+impl From<VerifyError> for MidilangError {
+    fn from(err: VerifyError) -> Self {
+        Self::Verify(err)
+    }
+}
+
+#[cfg(feature = "play")]
+impl From<PlayError> for MidilangError {
+    fn from(err: PlayError) -> Self {
+        Self::Play(err)
+    }
+}
+
+impl From<Failure> for MidilangError {
+    fn from(failure: Failure) -> Self {
+        Self::Failed(Box::new(failure))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+    #[test]
+    fn exit_codes_follow_the_step_that_failed() {
+        let io_err: MidilangError = io::Error::new(io::ErrorKind::NotFound, "gone").into();
+        assert_eq!(io_err.exit_code(), 4);
+        assert_eq!(MidilangError::from(MParseError::NoTracks).exit_code(), 2);
+        assert_eq!(MidilangError::from(MCompileError::Unsupported("wasm".to_owned())).exit_code(), 3);
+        let failure = Failure::new(FailureKind::Parse, "song.mid", "loop is never closed");
+        assert_eq!(MidilangError::from(failure).exit_code(), 2);
+        assert_eq!(MidilangError::from(InterpError::StepLimit(10)).exit_code(), EXIT_FAILURE);
+        assert_eq!(MidilangError::Other("nope".to_owned()).exit_code(), EXIT_FAILURE);
+    }
+
+    #[test]
+    fn chains_sources() {
+        let err = MidilangError::from(VerifyError::Interp(InterpError::StepLimit(10)));
+        assert_eq!(err.to_string(), "Interpreter failed: Step limit of 10 reached");
+        let source = err.source().unwrap();
+        assert_eq!(source.to_string(), "Interpreter failed: Step limit of 10 reached");
+        assert_eq!(source.source().unwrap().to_string(), "Step limit of 10 reached");
+
+        let err = MidilangError::Run(InterpError::PointerUnderflow(None), Some("2:1 (instruction 4)".to_owned()));
+        assert_eq!(err.to_string(), "Pointer moved left of the first cell at 2:1 (instruction 4)");
+    }
+}
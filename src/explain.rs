@@ -0,0 +1,88 @@
+use std::fmt::Write;
+
+use crate::diagnostics::{describe_reading, note_name, NOTE_NAMES};
+use crate::parser::{decode_chord, Parsed};
+
+/// Lists every chord of a parsed song the way the parser reads it, like a
+/// disassembler: where it is, its notes, the root and argument decoded from
+/// them, what it reads as and the number of the instruction it became.
+///
+/// ```text
+/// chord  at   notes       root  arg      reads as  instruction
+///     0  1:1  A0 C♯1 E1   A     1        add 1     0
+///     1  1:2  B0 D♯1 G1   B     5 (1+4)  output    1
+/// ```
+///
+/// Chords that aren't instructions, or that don't fit where they are, say why.
+pub fn explain(parsed: &Parsed) -> String {
+    let mut instructions = vec![None; parsed.chords.len()];
+    for (inst, &chord) in parsed.instructions.iter().enumerate() {
+        instructions[chord] = Some(inst);
+    }
+
+    let mut rows = vec![["chord", "at", "notes", "root", "arg", "reads as", "instruction"].map(str::to_owned)];
+    for (index, chord) in parsed.chords.iter().enumerate() {
+        let place = match parsed.source_map.bar_beat_at(chord.tick) {
+            Some(bar_beat) => bar_beat.to_string(),
+            None => format!("tick {}", chord.tick),
+        };
+        let notes: Vec<String> = chord.notes.iter().map(|&key| note_name(key)).collect();
+        let (root, arg) = match chord.notes.first() {
+            Some(_) => {
+                let decoded = decode_chord(&chord.notes);
+                let bits: Vec<String> = decoded.bits.iter().map(|bit| (1 << bit).to_string()).collect();
+                let arg = match bits.len() {
+                    0 | 1 => decoded.amount.to_string(),
+                    _ => format!("{} ({})", decoded.amount, bits.join("+")),
+                };
+                (NOTE_NAMES[usize::from(decoded.root)].to_owned(), arg)
+            }
+            None => (String::new(), String::new()),
+        };
+        let mut reading = describe_reading(&chord.reading);
+        for err in parsed.errors.iter().filter(|err| err.chord == Some(index)) {
+            write!(reading, ", {}", err.error.describe()).unwrap();
+        }
+        let inst = instructions[index].map_or("-".to_owned(), |inst| inst.to_string());
+        rows.push([index.to_string(), place, notes.join(" "), root, arg, reading, inst]);
+    }
+
+    let mut widths = [0; 7];
+    for row in &rows {
+        for (width, cell) in widths.iter_mut().zip(row) {
+            *width = (*width).max(cell.chars().count());
+        }
+    }
+    let mut out = String::new();
+    for row in &rows {
+        let mut line = format!("{:>width$}", row[0], width = widths[0]);
+        for (cell, width) in row.iter().zip(widths).skip(1) {
+            // `{:width$}` counts bytes, and sharps take more than one
+            write!(line, "  {}{}", cell, " ".repeat(width - cell.chars().count())).unwrap();
+        }
+        out.push_str(line.trim_end());
+        out.push('\n');
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+    use crate::parser::parse_all;
+    use crate::MidiProgram;
+
+    #[test]
+    fn lists_every_chord() {
+        let parsed = parse_all(MidiProgram::from_bf_str("+[.]").unwrap().to_smf());
+        assert_eq!(
+            explain(&parsed),
+            "chord  at   notes        root  arg  reads as      instruction\n\
+             \x20   0  1:1  A-1          A     1    add 1         0\n\
+             \x20   1  1:1  G-1          G     1    open a loop   1\n\
+             \x20   2  1:1  B-1 D♯0 F♯0  B     4    output        2\n\
+             \x20   3  1:1  C-1          C     1    close a loop  3\n"
+        );
+    }
+}
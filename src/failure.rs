@@ -0,0 +1,166 @@
+use std::fmt::Write;
+
+use log::error;
+
+use crate::check::{Diagnostic, Severity};
+use crate::diagnostics;
+
+/// Exit code of a run that failed some other way than the ones below.
+pub const EXIT_FAILURE: i32 = 1;
+
+/// How failures are printed to stderr.
+#[derive(Debug, Default, PartialEq, Eq, Clone, Copy, clap::ValueEnum)]
+pub enum ErrorFormat {
+    /// Log lines for people
+    #[default]
+    Human,
+    /// One JSON object per line, for editors and build tools
+    Json,
+    /// A SARIF log, for CI and code scanning tools
+    Sarif,
+}
+
+/// What step a file failed at, which picks the exit code.
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub enum FailureKind {
+    /// The file isn't MIDI, or its chords aren't a program
+    Parse,
+    /// The backend couldn't compile or write out the program
+    Codegen,
+    /// Reading or writing a file failed
+    Io,
+}
+
+impl FailureKind {
+    pub fn exit_code(self) -> i32 {
+        match self {
+            Self::Parse => 2,
+            Self::Codegen => 3,
+            Self::Io => 4,
+        }
+    }
+
+    fn name(self) -> &'static str {
+        match self {
+            Self::Parse => "parse",
+            Self::Codegen => "codegen",
+            Self::Io => "io",
+        }
+    }
+}
+
+/// Why a file couldn't be compiled.
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub struct Failure {
+    pub kind: FailureKind,
+    pub file: String,
+    pub message: String,
+    /// Diagnostics to print for people instead of the message, see `diagnostics::render`
+    pub rendered: Option<String>,
+    /// Everything wrong with the file when it didn't parse
+    pub diagnostics: Vec<Diagnostic>,
+}
+
+impl Failure {
+    pub fn new(kind: FailureKind, file: &str, message: impl Into<String>) -> Self {
+        Failure {
+            kind,
+            file: file.to_owned(),
+            message: message.into(),
+            rendered: None,
+            diagnostics: vec![],
+        }
+    }
+
+    /// Prints the failure to stderr in `format`.
+    pub fn report(&self, format: ErrorFormat) {
+        match format {
+            ErrorFormat::Human => match &self.rendered {
+                Some(rendered) => eprint!("{}", rendered),
+                None => error!("Error when {} {}: {}", self.doing(), self.file, self.message),
+            },
+            ErrorFormat::Json => eprintln!("{}", self.to_json()),
+            ErrorFormat::Sarif => Failure::report_sarif(std::slice::from_ref(self)),
+        }
+    }
+
+    /// Prints all of `failures` to stderr as a single SARIF log.
+    pub fn report_sarif(failures: &[Failure]) {
+        let diagnostics: Vec<Vec<Diagnostic>> = failures.iter().map(Failure::diagnostics).collect();
+        let files: Vec<(&str, &[Diagnostic])> =
+            failures.iter().zip(&diagnostics).map(|(failure, diags)| (failure.file.as_str(), diags.as_slice())).collect();
+        eprintln!("{}", diagnostics::to_sarif(&files));
+    }
+
+    /// What went wrong as diagnostics, a single one about the whole file
+    /// unless it failed to parse.
+    pub fn diagnostics(&self) -> Vec<Diagnostic> {
+        match self.diagnostics.is_empty() {
+            true => vec![Diagnostic::file(self.kind.name(), Severity::Error, self.message.clone())],
+            false => self.diagnostics.clone(),
+        }
+    }
+
+    fn doing(&self) -> &'static str {
+        match self.kind {
+            FailureKind::Parse => "parsing",
+            FailureKind::Codegen => "compiling",
+            FailureKind::Io => "reading or writing files for",
+        }
+    }
+
+    /// The failure as a single line JSON object, with its diagnostics.
+    pub fn to_json(&self) -> String {
+        let diagnostics: Vec<String> = self.diagnostics().iter().map(|diag| diagnostics::to_json(&self.file, diag)).collect();
+        format!(
+            "{{\"file\":{},\"kind\":\"{}\",\"message\":{},\"exit_code\":{},\"diagnostics\":[{}]}}",
+            json_string(&self.file),
+            self.kind.name(),
+            json_string(&self.message),
+            self.kind.exit_code(),
+            diagnostics.join(",")
+        )
+    }
+}
+
+/// `text` as a JSON string literal.
+pub(crate) fn json_string(text: &str) -> String {
+    let mut out = String::with_capacity(text.len() + 2);
+    out.push('"');
+    for ch in text.chars() {
+        match ch {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            '\n' => out.push_str("\\n"),
+            '\r' => out.push_str("\\r"),
+            '\t' => out.push_str("\\t"),
+            ch if u32::from(ch) < 0x20 => write!(out, "\\u{:04x}", u32::from(ch)).unwrap(),
+            ch => out.push(ch),
+        }
+    }
+    out.push('"');
+    out
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+    #[test]
+    fn kinds_have_distinct_exit_codes() {
+        let codes = [FailureKind::Parse, FailureKind::Codegen, FailureKind::Io].map(FailureKind::exit_code);
+        assert_eq!(codes, [2, 3, 4]);
+        assert!(!codes.contains(&EXIT_FAILURE));
+    }
+
+    #[test]
+    fn writes_json_lines() {
+        let failure = Failure::new(FailureKind::Parse, "songs/\"hit\".mid", "Unclosed loops\n\tat 3");
+        assert_eq!(
+            failure.to_json(),
+            r#"{"file":"songs/\"hit\".mid","kind":"parse","message":"Unclosed loops\n\tat 3","exit_code":2,"diagnostics":[{"file":"songs/\"hit\".mid","code":"parse","severity":"error","message":"Unclosed loops\n\tat 3","tick":null,"bar":null,"beat":null,"chord":null,"notes":[],"note_names":[]}]}"#
+        );
+        assert_eq!(json_string("\u{1}"), r#""\u0001""#);
+    }
+}
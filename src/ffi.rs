@@ -0,0 +1,192 @@
+use std::collections::HashMap;
+use std::num::Wrapping;
+use std::os::raw::c_int;
+use std::path::{Path, PathBuf};
+#[cfg(any(unix, windows))]
+use std::sync::Arc;
+
+use crate::interpreter::{InterpError, InterpResult};
+use crate::parser::Cell;
+
+/// What a call instruction takes the function it names to be, `int name(int)`.
+pub type ExternFn = unsafe extern "C" fn(c_int) -> c_int;
+
+/// Functions every song can call, libc's of the same name in the C locale.
+pub const BUILTINS: &[(&str, ExternFn)] = &[
+    ("abs", abs),
+    ("toupper", toupper),
+    ("tolower", tolower),
+    ("isalnum", isalnum),
+    ("isalpha", isalpha),
+    ("isdigit", isdigit),
+    ("islower", islower),
+    ("isupper", isupper),
+    ("isspace", isspace),
+    ("ispunct", ispunct),
+    ("isxdigit", isxdigit),
+];
+
+/// The external functions a program calls, looked up the first time each one
+/// is called among the `BUILTINS` and then the libraries linked with `link`.
+/// Nothing else midilang runs with can be called, songs don't get to pick any
+/// symbol of the process to run.
+#[derive(Debug, Clone, Default)]
+pub struct Functions {
+    found: HashMap<String, ExternFn>,
+    #[cfg(any(unix, windows))]
+    libraries: Vec<Arc<libloading::Library>>,
+}
+
+impl Functions {
+    pub fn new() -> Self {
+        Functions::default()
+    }
+
+    /// Functions with every library in `paths` linked, see `link`.
+    pub fn linked(paths: &[PathBuf]) -> Result<Self, String> {
+        let mut functions = Functions::new();
+        for path in paths {
+            functions.link(path)?;
+        }
+        Ok(functions)
+    }
+
+    /// Has calls look for functions in the library at `path` too, after the
+    /// builtins and the libraries linked before it.
+    #[cfg(any(unix, windows))]
+    pub fn link(&mut self, path: &Path) -> Result<(), String> {
+        // SAFETY: loading a library runs its initializers, which is on whoever
+        // asked for it to be linked
+        let library = unsafe { libloading::Library::new(path) }.map_err(|err| format!("Could not link {}: {}", path.display(), err))?;
+        self.libraries.push(Arc::new(library));
+        Ok(())
+    }
+
+    // nothing to load libraries with, like in the browser
+    #[cfg(not(any(unix, windows)))]
+    pub fn link(&mut self, path: &Path) -> Result<(), String> {
+        Err(format!("Could not link {}, this system can't load libraries", path.display()))
+    }
+
+    /// The function called `name`, if there's one to call.
+    pub fn find(&mut self, name: &str) -> InterpResult<ExternFn> {
+        if let Some(&function) = self.found.get(name) {
+            return Ok(function);
+        }
+        let builtin = BUILTINS.iter().find(|(builtin, _)| *builtin == name).map(|&(_, function)| function);
+        let function = builtin.or_else(|| self.lookup(name)).ok_or_else(|| InterpError::UnknownFunction(name.to_owned()))?;
+        self.found.insert(name.to_owned(), function);
+        Ok(function)
+    }
+
+    /// Calls `name` with `cell` and returns what it returns, for the caller to
+    /// wrap around to the width of a cell.
+    pub fn call(&mut self, name: &str, cell: Cell) -> InterpResult<Cell> {
+        let function = self.find(name)?;
+        // songs can only call `int name(int)`, whatever a linked symbol really
+        // is is on whoever linked it
+        Ok(Wrapping(unsafe { function(cell.0) }))
+    }
+
+    #[cfg(any(unix, windows))]
+    fn lookup(&self, name: &str) -> Option<ExternFn> {
+        // the libraries are kept as long as the functions found in them
+        let get = |library: &Arc<libloading::Library>| unsafe { library.get::<ExternFn>(name.as_bytes()).ok().map(|symbol| *symbol) };
+        self.libraries.iter().find_map(get)
+    }
+
+    #[cfg(not(any(unix, windows)))]
+    fn lookup(&self, _name: &str) -> Option<ExternFn> {
+        None
+    }
+}
+
+extern "C" fn abs(value: c_int) -> c_int {
+    value.wrapping_abs()
+}
+
+// maps `c` with `map` when it's a byte, leaving EOF and the like alone
+fn map_ascii(c: c_int, map: fn(&u8) -> u8) -> c_int {
+    u8::try_from(c).map_or(c, |byte| c_int::from(map(&byte)))
+}
+
+// 1 when `c` is a byte `class` takes in, 0 otherwise
+fn is_ascii(c: c_int, class: fn(&u8) -> bool) -> c_int {
+    c_int::from(u8::try_from(c).is_ok_and(|byte| class(&byte)))
+}
+
+extern "C" fn toupper(c: c_int) -> c_int {
+    map_ascii(c, u8::to_ascii_uppercase)
+}
+
+extern "C" fn tolower(c: c_int) -> c_int {
+    map_ascii(c, u8::to_ascii_lowercase)
+}
+
+extern "C" fn isalnum(c: c_int) -> c_int {
+    is_ascii(c, u8::is_ascii_alphanumeric)
+}
+
+extern "C" fn isalpha(c: c_int) -> c_int {
+    is_ascii(c, u8::is_ascii_alphabetic)
+}
+
+extern "C" fn isdigit(c: c_int) -> c_int {
+    is_ascii(c, u8::is_ascii_digit)
+}
+
+extern "C" fn islower(c: c_int) -> c_int {
+    is_ascii(c, u8::is_ascii_lowercase)
+}
+
+extern "C" fn isupper(c: c_int) -> c_int {
+    is_ascii(c, u8::is_ascii_uppercase)
+}
+
+extern "C" fn isspace(c: c_int) -> c_int {
+    // C's isspace takes the vertical tab too
+    is_ascii(c, |byte| byte.is_ascii_whitespace() || *byte == 0x0B)
+}
+
+extern "C" fn ispunct(c: c_int) -> c_int {
+    is_ascii(c, u8::is_ascii_punctuation)
+}
+
+extern "C" fn isxdigit(c: c_int) -> c_int {
+    is_ascii(c, u8::is_ascii_hexdigit)
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+    #[test]
+    fn finds_the_builtins() {
+        let mut functions = Functions::new();
+        assert_eq!(functions.call("toupper", Wrapping(i32::from(b'q'))).unwrap(), Wrapping(i32::from(b'Q')));
+        assert_eq!(functions.call("abs", Wrapping(-7)).unwrap(), Wrapping(7));
+        assert_eq!(
+            functions.call("no_such_midilang_function", Wrapping(0)),
+            Err(InterpError::UnknownFunction("no_such_midilang_function".to_owned()))
+        );
+    }
+
+    #[test]
+    fn never_calls_what_the_process_happens_to_have() {
+        let mut functions = Functions::new();
+        for name in ["getpid", "abort", "exit", "system"] {
+            assert!(matches!(functions.find(name), Err(InterpError::UnknownFunction(_))), "{}", name);
+        }
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn finds_functions_in_linked_libraries() {
+        assert!(Functions::linked(&[PathBuf::from("/no/such/libmidilang.so")]).is_err());
+        let mut functions = Functions::new();
+        assert!(functions.find("ilogb").is_err());
+        functions.link(Path::new("libm.so.6")).unwrap();
+        assert!(functions.find("ilogb").is_ok());
+    }
+}
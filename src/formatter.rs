@@ -0,0 +1,252 @@
+use midly::num::{u24, u28, u4, u7};
+use midly::{Format, Header, MetaMessage, MidiMessage, Smf, Timing, Track, TrackEvent, TrackEventKind};
+
+use crate::parser::{c_major_root, MidiInstruction, MidiInstructionKind::*, STRING_ROOT};
+
+/// Ticks per quarter note of formatted songs.
+const TICKS_PER_QUARTER: u16 = 480;
+
+/// Every chord is an eighth note, right after the one before it.
+const CHORD_TICKS: u32 = 240;
+
+/// Key every root is played on the octave of, middle C.
+const ROOT_OCTAVE: u8 = 60;
+
+/// Velocity of every note.
+const VELOCITY: u8 = 80;
+
+/// 120 beats per minute.
+const TEMPO: u32 = 500_000;
+
+/// Largest argument a single chord holds, with a note for each of its 9 bits.
+const MAX_ARGUMENT: u64 = 511;
+
+/// Writes `program` as a canonical MIDI file, the way `gofmt` writes Go: one
+/// eighth note chord per instruction, back to back, every root in the octave
+/// of middle C at the same velocity, arguments as the fewest notes that spell
+/// them, and a meta track with only a 4/4 time signature, a tempo of 120 and
+/// the key of C major.
+///
+/// Parsed songs read back as exactly the same program. Amounts too big for one
+/// chord, which only dumps and the optimizer make, are split over several and
+/// amounts of 0 are left out. Calls get a text event with their function
+/// before them when it isn't the one of the call before.
+pub fn format(program: &[MidiInstruction]) -> Smf<'_> {
+    let mut chords = vec![];
+    let mut names = vec![];
+    push_chords(program, &mut chords, &mut names);
+    named_song(&chords, &names)
+}
+
+/// The notes of the chords `format` writes for `program`, in order, without
+/// the names of calls.
+pub(crate) fn chords(program: &[MidiInstruction]) -> Vec<Vec<u8>> {
+    let mut chords = vec![];
+    push_chords(program, &mut chords, &mut vec![]);
+    chords
+}
+
+/// The chords of string literals setting the cells from the current one on to
+/// `text`, with the pointer back on the first, as many of them as it takes.
+/// Every byte is a note, so `text` has to be ASCII.
+pub(crate) fn string(text: &[u8]) -> Vec<Vec<u8>> {
+    let mut chords = vec![];
+    let parts = text.chunks(MAX_ARGUMENT as usize);
+    let moves = parts.len().saturating_sub(1) as u64 * MAX_ARGUMENT;
+    for (idx, part) in parts.enumerate() {
+        // every part but the last is as long as a chord's argument goes
+        if idx > 0 {
+            push_amount(c_major_root(&MovePointer { amount: 1 }), MAX_ARGUMENT, &mut chords);
+        }
+        chords.push(chord(STRING_ROOT, part.len() as u64));
+        chords.extend(part.iter().map(|&byte| vec![byte]));
+    }
+    push_amount(c_major_root(&MovePointer { amount: -1 }), moves, &mut chords);
+    chords
+}
+
+/// Writes `chords` the way `format` writes the chords of a program, each as
+/// the notes given.
+pub fn song(chords: &[Vec<u8>]) -> Smf<'static> {
+    named_song(chords, &[])
+}
+
+// writes `chords` like `song`, with a text event of `name` before the chord of
+// every index in `names`
+fn named_song<'a>(chords: &[Vec<u8>], names: &[(usize, &'a str)]) -> Smf<'a> {
+    let mut smf = Smf::new(Header::new(Format::Parallel, Timing::Metrical(TICKS_PER_QUARTER.into())));
+    smf.tracks.push(
+        [
+            MetaMessage::TimeSignature(4, 2, 24, 8),
+            MetaMessage::Tempo(u24::from(TEMPO)),
+            MetaMessage::KeySignature(0, false),
+            MetaMessage::EndOfTrack,
+        ]
+        .map(|meta| event(0, TrackEventKind::Meta(meta)))
+        .into(),
+    );
+
+    let mut track = Track::new();
+    track.push(event(0, TrackEventKind::Meta(MetaMessage::TrackName(b"program"))));
+    let mut names = names.iter().copied().peekable();
+    for (idx, notes) in chords.iter().enumerate() {
+        if let Some((_, name)) = names.next_if(|(at, _)| *at == idx) {
+            track.push(event(0, TrackEventKind::Meta(MetaMessage::Text(name.as_bytes()))));
+        }
+        for &key in notes {
+            track.push(note(0, MidiMessage::NoteOn { key: key.into(), vel: VELOCITY.into() }));
+        }
+        for (idx, &key) in notes.iter().enumerate() {
+            // the first release ends the chord
+            let delta = if idx == 0 { CHORD_TICKS } else { 0 };
+            track.push(note(delta, MidiMessage::NoteOff { key: key.into(), vel: u7::from(0) }));
+        }
+    }
+    track.push(event(0, TrackEventKind::Meta(MetaMessage::EndOfTrack)));
+    smf.tracks.push(track);
+    smf
+}
+
+// the notes of the chords of every instruction, in order, and the index of
+// every call chord naming another function than the one before
+fn push_chords<'a>(program: &'a [MidiInstruction], chords: &mut Vec<Vec<u8>>, names: &mut Vec<(usize, &'a str)>) {
+    for inst in program {
+        let root = c_major_root(&inst.instruction);
+        match &inst.instruction {
+            IncrementCell { amount } => push_amount(root, amount.0.unsigned_abs().into(), chords),
+            MovePointer { amount } => push_amount(root, amount.unsigned_abs() as u64, chords),
+            InputCell => chords.push(chord(root, 1)),
+            // the leading tone reads as output with any argument but 1, a
+            // triad is the one to use
+            OutputCell => chords.push(chord(root, 4)),
+            Loop { body } => {
+                chords.push(chord(root, 1));
+                push_chords(body, chords, names);
+                chords.push(chord(0, 1));
+            }
+            Call { name } => {
+                if names.last().is_none_or(|(_, last)| last != name) {
+                    names.push((chords.len(), name));
+                }
+                // a diminished seventh on the tonic
+                chords.push(vec![ROOT_OCTAVE, ROOT_OCTAVE + 3, ROOT_OCTAVE + 6, ROOT_OCTAVE + 9]);
+            }
+            // a diminished triad on the tonic
+            Breakpoint => chords.push(vec![ROOT_OCTAVE, ROOT_OCTAVE + 3, ROOT_OCTAVE + 6]),
+            // an augmented triad on the tonic, read as random in the extensions dialect
+            Random => chords.push(vec![ROOT_OCTAVE, ROOT_OCTAVE + 4, ROOT_OCTAVE + 8]),
+            DumpTape => chords.push(vec![ROOT_OCTAVE, ROOT_OCTAVE + 5, ROOT_OCTAVE + 7]),
+        }
+    }
+}
+
+fn push_amount(root: u8, mut amount: u64, chords: &mut Vec<Vec<u8>>) {
+    while amount > 0 {
+        let part = amount.min(MAX_ARGUMENT);
+        chords.push(chord(root, part));
+        amount -= part;
+    }
+}
+
+/// The notes of a chord on `root` with the argument `amount`: the root alone
+/// for 1, otherwise a base a major third above it and a note above the base
+/// for every bit that's set.
+fn chord(root: u8, amount: u64) -> Vec<u8> {
+    let root = ROOT_OCTAVE + root;
+    if amount == 1 {
+        return vec![root];
+    }
+    let base = root + 4;
+    let mut notes = vec![root, base];
+    notes.extend((0..9).filter(|bit| amount & (1 << bit) != 0).map(|bit| base + 1 + bit));
+    notes
+}
+
+fn note(delta: u32, message: MidiMessage) -> TrackEvent<'static> {
+    event(delta, TrackEventKind::Midi { channel: u4::from(0), message })
+}
+
+fn event<'a>(delta: u32, kind: TrackEventKind<'a>) -> TrackEvent<'a> {
+    TrackEvent { delta: u28::from(delta), kind }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+    use crate::dump::{self, DumpFormat};
+    use crate::parser::{decode_chord, parse, parse_all_bytes_in, ParseOptions};
+    use crate::sysex::Includes;
+    use crate::MidiProgram;
+
+    fn formatted_bytes(program: &[MidiInstruction]) -> Vec<u8> {
+        let mut bytes = vec![];
+        format(program).write_std(&mut bytes).unwrap();
+        bytes
+    }
+
+    #[test]
+    fn keeps_the_parsed_program() {
+        let song = dump::read(
+            "(add (at 0 0) 300) (move (at 1 1) -511) (loop (at 2 5) (input (at 3 3)) (output (at 4 4))) \
+             (breakpoint (at 6 6)) (add (at 7 7) -1) (move (at 8 8) 2)",
+            DumpFormat::Sexp,
+        )
+        .unwrap();
+        let bytes = formatted_bytes(&song);
+        let program = MidiProgram::from_bytes(&bytes).unwrap();
+        assert_eq!(program.ast(), &song);
+        // a chord every eighth note
+        assert_eq!(program.source_map().bar_beat(2).unwrap().to_string(), "1:2");
+
+        // formatting a formatted song changes nothing
+        assert_eq!(formatted_bytes(program.ast()), bytes);
+    }
+
+    #[test]
+    fn spells_arguments_with_the_fewest_notes() {
+        assert_eq!(chord(9, 1), [69]);
+        assert_eq!(chord(9, 5), [69, 73, 74, 76]);
+        assert_eq!(decode_chord(&chord(9, 511)).amount, 511);
+
+        // too big for one chord, and nothing at all
+        let song = dump::read("(add (at 0 0) 600) (move (at 1 1) 0) (add (at 2 2) -1)", DumpFormat::Sexp).unwrap();
+        let amounts: Vec<String> = parse(format(&song)).unwrap().iter().map(|inst| inst.instruction.describe()).collect();
+        assert_eq!(amounts, ["add 511", "add 89", "add -1"]);
+    }
+
+    #[test]
+    fn strings_longer_than_an_argument_take_several_literals() {
+        use crate::interpreter::Interpreter;
+        use std::num::Wrapping;
+
+        let text: Vec<u8> = (0..1200).map(|idx| b'a' + (idx % 26) as u8).collect();
+        let mut chords = chords(&[MidiInstruction::new_move(2)]);
+        chords.extend(string(&text));
+        let mut interp = Interpreter::new();
+        interp.run(&parse(song(&chords)).unwrap(), &mut &[][..], &mut vec![]).unwrap();
+        assert_eq!(interp.pointer(), 2);
+        assert_eq!(interp.tape()[2..], text.iter().map(|&byte| Wrapping(i32::from(byte))).collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn names_functions_before_their_calls() {
+        let song = dump::read(
+            "(call (at 0 0) toupper) (output (at 1 1)) (call (at 2 2) toupper) (loop (at 3 5) (call (at 4 4) abs))",
+            DumpFormat::Sexp,
+        )
+        .unwrap();
+        let smf = format(&song);
+        let texts: Vec<&[u8]> = smf.tracks[1]
+            .iter()
+            .filter_map(|event| match event.kind {
+                TrackEventKind::Meta(MetaMessage::Text(text)) => Some(text),
+                _ => None,
+            })
+            .collect();
+        assert_eq!(texts, [&b"toupper"[..], b"abs"]);
+        let extensions = ParseOptions { extensions: true, ..ParseOptions::default() };
+        let parsed = parse_all_bytes_in(&formatted_bytes(&song), &Includes::default(), &extensions).unwrap();
+        assert_eq!(parsed.ast.unwrap(), song);
+    }
+}
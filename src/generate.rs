@@ -0,0 +1,171 @@
+use std::num::Wrapping;
+
+use arbitrary::{Arbitrary, Unstructured};
+
+use crate::parser::{MidiAST, MidiInstruction, MidiInstructionKind, MidiInstructionKind::*, Position};
+
+/// How big the programs `program` makes can get.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Bounds {
+    /// Instructions in the whole program, counting both ends of loops
+    pub max_instructions: usize,
+    /// How deep loops nest
+    pub max_depth: usize,
+    /// Largest amount to add or move by, amounts up to 511 fit in one chord
+    pub max_amount: u16,
+}
+
+impl Default for Bounds {
+    fn default() -> Self {
+        Bounds {
+            max_instructions: 64,
+            max_depth: 4,
+            max_amount: 511,
+        }
+    }
+}
+
+/// Makes a valid program out of the bytes left in `u`, with balanced loops
+/// and positions numbered the way the parser numbers them, so parsing
+/// `ast_to_smf` of it gives the same program back.
+///
+/// Amounts are never 0 or bigger than `bounds.max_amount`, since songs can't
+/// spell 0 and bigger ones take more than a chord.
+pub fn program(u: &mut Unstructured, bounds: Bounds) -> arbitrary::Result<MidiAST> {
+    let mut generator = Generator { bounds, next: 0 };
+    generator.body(u, 0)
+}
+
+/// A program made by `program` with the default `Bounds`, to take as an
+/// argument in `cargo fuzz` targets or proptest strategies built on
+/// `arbitrary`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ArbitraryAst(pub MidiAST);
+
+impl<'a> Arbitrary<'a> for ArbitraryAst {
+    fn arbitrary(u: &mut Unstructured<'a>) -> arbitrary::Result<Self> {
+        program(u, Bounds::default()).map(ArbitraryAst)
+    }
+
+    fn size_hint(_depth: usize) -> (usize, Option<usize>) {
+        // a byte to go on or not, one for the kind and up to three for the amount
+        (0, Some(Bounds::default().max_instructions * 5))
+    }
+}
+
+struct Generator {
+    bounds: Bounds,
+    /// Position of the next instruction
+    next: usize,
+}
+
+impl Generator {
+    fn body(&mut self, u: &mut Unstructured, depth: usize) -> arbitrary::Result<MidiAST> {
+        let mut body = vec![];
+        // stop once the bytes run out, every byte string makes a program. Bodies
+        // go on for about 8 instructions, so loops nest before the program's full
+        while self.next < self.bounds.max_instructions && !u.is_empty() && u.ratio(7u8, 8)? {
+            let start = self.next;
+            self.next += 1;
+            // a loop needs room to close
+            let can_loop = depth < self.bounds.max_depth && self.next < self.bounds.max_instructions;
+            let instruction = match u.int_in_range(0..=if can_loop { 5 } else { 4 })? {
+                0 => IncrementCell { amount: Wrapping(self.amount(u)?.into()) },
+                1 => MovePointer { amount: self.amount(u)?.into() },
+                2 => OutputCell,
+                3 => InputCell,
+                4 => Breakpoint,
+                _ => self.loop_body(u, depth)?,
+            };
+            // loops end at their close
+            body.push(MidiInstruction { position: Some(Position::new(start, self.next - 1)), instruction });
+        }
+        Ok(body)
+    }
+
+    fn loop_body(&mut self, u: &mut Unstructured, depth: usize) -> arbitrary::Result<MidiInstructionKind> {
+        // keep room for closing the loop
+        self.bounds.max_instructions -= 1;
+        let body = self.body(u, depth + 1);
+        self.bounds.max_instructions += 1;
+        self.next += 1;
+        Ok(Loop { body: body? })
+    }
+
+    fn amount(&self, u: &mut Unstructured) -> arbitrary::Result<i16> {
+        let amount = u.int_in_range(1..=self.bounds.max_amount.clamp(1, i16::MAX as u16))? as i16;
+        Ok(if u.arbitrary()? { amount } else { -amount })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+    use crate::interpreter::RunOptions;
+    use crate::parser::CellWidth;
+    use crate::{ast_to_smf, MidiProgram};
+
+    // bytes that look random enough, the same every run
+    fn bytes(seed: u64, len: usize) -> Vec<u8> {
+        let mut state = seed.wrapping_mul(6364136223846793005).wrapping_add(1442695040888963407);
+        (0..len)
+            .map(|_| {
+                state = state.wrapping_mul(6364136223846793005).wrapping_add(1442695040888963407);
+                (state >> 33) as u8
+            })
+            .collect()
+    }
+
+    // instructions in `body`, how deep its loops nest and its biggest amount
+    fn measure(body: &MidiAST) -> (usize, usize, i32) {
+        let (mut instructions, mut depth, mut largest) = (0, 0, 0);
+        for inst in body {
+            instructions += 1;
+            let amount = match &inst.instruction {
+                Loop { body } => {
+                    let (inner, inner_depth, inner_largest) = measure(body);
+                    instructions += inner + 1;
+                    depth = depth.max(inner_depth + 1);
+                    inner_largest
+                }
+                IncrementCell { amount } => amount.0.abs(),
+                MovePointer { amount } => amount.unsigned_abs() as i32,
+                _ => 0,
+            };
+            largest = largest.max(amount);
+        }
+        (instructions, depth, largest)
+    }
+
+    #[test]
+    fn songs_parse_back_to_the_same_program() {
+        let mut nested = 0;
+        for seed in 0..200 {
+            let bytes = bytes(seed, 256);
+            let ast = program(&mut Unstructured::new(&bytes), Bounds::default()).unwrap();
+            if measure(&ast).1 > 1 {
+                nested += 1;
+            }
+            let parsed = MidiProgram::from_smf(ast_to_smf(&ast)).unwrap();
+            assert_eq!(parsed.ast(), &ast, "seed {}", seed);
+
+            // running it can fail, but not crash
+            let limits = RunOptions { max_steps: Some(1000), ..RunOptions::default() };
+            let _ = parsed.optimize(CellWidth::default()).interpret(CellWidth::default(), limits, &mut &[1, 2, 3][..], &mut vec![]);
+        }
+        assert!(nested > 20, "only {} programs nest loops", nested);
+    }
+
+    #[test]
+    fn stays_in_bounds() {
+        let bounds = Bounds { max_instructions: 10, max_depth: 1, max_amount: 3 };
+        for seed in 0..100 {
+            let bytes = bytes(seed, 64);
+            let (instructions, depth, largest) = measure(&program(&mut Unstructured::new(&bytes), bounds).unwrap());
+            assert!(instructions <= 10 && depth <= 1 && largest <= 3, "seed {}", seed);
+        }
+        // nothing left to make anything from
+        assert_eq!(program(&mut Unstructured::new(&[]), bounds).unwrap(), vec![]);
+    }
+}
@@ -0,0 +1,917 @@
+use std::error::Error;
+use std::fmt::{Debug, Display};
+use std::io::{self, Read, Write};
+use std::num::Wrapping;
+use std::time::{Duration, Instant};
+
+use midilang_core::io::{read_number, read_utf8, write_number};
+use midilang_core::{Encoding, Rng, Tape, TapeDump, Utf8Output};
+
+use crate::compiler::{IoMode, Overflow, PointerOverflow, TapeMode};
+use crate::ffi::Functions;
+use crate::vm::{StdInput, StdOutput};
+use crate::parser::{Cell, CellWidth, MidiInstruction, MidiInstructionKind, MidiInstructionKind::*, Position};
+
+pub type InterpResult<T> = Result<T, InterpError>;
+
+#[derive(PartialEq, Eq)]
+pub enum InterpError {
+    StepLimit(usize),
+    OutputLimit(usize),
+    TimeLimit(Duration),
+    PointerUnderflow(Option<Position>),
+    /// The pointer moved past the last cell of a tape it traps at
+    PointerOverflow(Option<Position>),
+    /// A cell went past its maximum or below 0 with overflow trapping
+    CellOverflow(Option<Position>),
+    Io(io::ErrorKind),
+    /// A call named a function there's none of
+    UnknownFunction(String),
+}
+
+impl Debug for InterpError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::StepLimit(steps) => write!(f, "Step limit of {} reached", steps),
+            Self::OutputLimit(bytes) => write!(f, "Output limit of {} bytes reached", bytes),
+            Self::TimeLimit(limit) => write!(f, "Time limit of {:?} reached", limit),
+            Self::PointerUnderflow(pos) => write!(f, "Pointer moved left of the first cell at: {:?}", pos),
+            Self::PointerOverflow(pos) => write!(f, "Pointer moved past the last cell at: {:?}", pos),
+            Self::CellOverflow(pos) => write!(f, "Cell overflowed at: {:?}", pos),
+            Self::Io(kind) => write!(f, "IO error: {:?}", kind),
+            Self::UnknownFunction(name) => write!(f, "No function called {} to call", name),
+        }
+    }
+}
+
+impl Display for InterpError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            // where is up to whoever has the source map
+            Self::PointerUnderflow(_) => write!(f, "Pointer moved left of the first cell"),
+            Self::PointerOverflow(_) => write!(f, "Pointer moved past the last cell"),
+            Self::CellOverflow(_) => write!(f, "Cell overflowed"),
+            _ => Debug::fmt(self, f),
+        }
+    }
+}
+
+impl Error for InterpError {}
+
+impl From<io::Error> for InterpError {
+    fn from(err: io::Error) -> Self {
+        Self::Io(err.kind())
+    }
+}
+
+/// Steps between looking at the clock when there's a `wall_clock_limit`.
+const CLOCK_INTERVAL: usize = 1 << 16;
+
+/// Sets `tape` up like the tape mode and overflow settings of `options` say.
+pub(crate) fn configure_tape(tape: &mut Tape, options: &RunOptions) {
+    tape.set_two_sided(options.tape_mode == TapeMode::Infinite);
+    tape.set_overflow(options.cell_overflow.into());
+    // only fixed tapes end, the others grow
+    if let (Some((overflow, size)), TapeMode::Fixed) = (options.pointer_overflow, options.tape_mode) {
+        tape.set_size(size as usize, overflow == PointerOverflow::Wrap);
+    }
+}
+
+/// Limits on running a program, for programs that can't be trusted to stop by
+/// themselves, and what it runs with. Nothing is limited by default.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct RunOptions {
+    /// Instructions executed before giving up
+    pub max_steps: Option<usize>,
+    /// Bytes written to the output before giving up
+    pub max_output_bytes: Option<usize>,
+    /// Time spent running before giving up, counted from the first instruction.
+    /// Waiting for input counts too, but can't be cut short.
+    pub wall_clock_limit: Option<Duration>,
+    /// Seed of the random bytes the program picks, from `--seed`
+    pub seed: u64,
+    /// Whether the pointer can move left of the first cell, from `--tape`. The
+    /// tape grows to the right either way
+    pub tape_mode: TapeMode,
+    /// What cells do past their width, from `--cell-overflow`
+    pub cell_overflow: Overflow,
+    /// What the pointer does past the end of a fixed tape and how many cells
+    /// that is, from `--pointer-overflow` and `--tape-size`. The tape grows as
+    /// far as the pointer goes when `None`
+    pub pointer_overflow: Option<(PointerOverflow, u64)>,
+    /// What output and input instructions write and read, from `--io`
+    pub io: IoMode,
+}
+
+impl RunOptions {
+    pub fn is_limited(&self) -> bool {
+        self.max_steps.is_some() || self.max_output_bytes.is_some() || self.wall_clock_limit.is_some()
+    }
+}
+
+/// How a run reads its input when that's a terminal, see `terminal::TerminalInput`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+pub enum InputMode {
+    /// Every key as soon as it's pressed, without echoing it, arrows as ANSI
+    /// escapes. For games
+    Raw,
+    /// A line at a time, edited with the usual readline keys before the
+    /// program gets it
+    Line,
+}
+
+/// Keeps track of a program running up against its `RunOptions`.
+#[derive(Debug, Default, Clone)]
+pub(crate) struct Limits {
+    pub(crate) options: RunOptions,
+    output_bytes: usize,
+    deadline: Option<Instant>,
+}
+
+impl Limits {
+    pub(crate) fn new(options: RunOptions) -> Self {
+        Limits {
+            options,
+            ..Self::default()
+        }
+    }
+
+    /// Checks whether the instruction after the first `steps` may run.
+    #[inline]
+    pub(crate) fn check_step(&mut self, steps: usize) -> InterpResult<()> {
+        if let Some(max) = self.options.max_steps {
+            if steps >= max {
+                return Err(InterpError::StepLimit(max));
+            }
+        }
+        if let Some(limit) = self.options.wall_clock_limit {
+            let deadline = *self.deadline.get_or_insert_with(|| Instant::now() + limit);
+            // reading the clock on every step would slow everything down
+            if steps.is_multiple_of(CLOCK_INTERVAL) && Instant::now() >= deadline {
+                return Err(InterpError::TimeLimit(limit));
+            }
+        }
+        Ok(())
+    }
+
+    /// Counts a byte of output, unless it's one too many.
+    #[inline]
+    pub(crate) fn check_output(&mut self) -> InterpResult<()> {
+        if let Some(max) = self.options.max_output_bytes {
+            if self.output_bytes >= max {
+                return Err(InterpError::OutputLimit(max));
+            }
+        }
+        self.output_bytes += 1;
+        Ok(())
+    }
+}
+
+/// Which instructions a run's cells and output came from, see `Interpreter::track_positions`.
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct Tracking {
+    /// Instruction that last changed each cell, `None` for cells that were never changed
+    pub cells: Vec<Option<Position>>,
+    /// Instruction that wrote each byte of output
+    pub output: Vec<Option<Position>>,
+}
+
+impl Tracking {
+    fn changed_cell(&mut self, index: usize, position: Option<Position>) {
+        if index >= self.cells.len() {
+            self.cells.resize(index + 1, None);
+        }
+        self.cells[index] = position;
+    }
+
+    // keeps up with a tape that grew `cells` to the left
+    fn grew_left(&mut self, cells: usize) {
+        self.cells.splice(0..0, std::iter::repeat_n(None, cells));
+    }
+}
+
+/// Marks the start of `TapeSnapshot::to_bytes`.
+const SNAPSHOT_MAGIC: &[u8] = b"midilang snapshot\0";
+
+/// The state of an `Interpreter` between runs, see `Interpreter::snapshot`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TapeSnapshot {
+    tape: Vec<Cell>,
+    pointer: usize,
+    /// Index of the cell the pointer started on, which `to_bytes` leaves out
+    origin: usize,
+    steps: usize,
+    cell_width: CellWidth,
+}
+
+impl TapeSnapshot {
+    /// Encodes the snapshot to save it to disk, with the cell width as a byte of
+    /// bits and everything else as little endian u64s, and the cells as i32s.
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut bytes = SNAPSHOT_MAGIC.to_vec();
+        bytes.push(self.cell_width.bits() as u8);
+        for number in [self.pointer, self.steps, self.tape.len()] {
+            bytes.extend_from_slice(&(number as u64).to_le_bytes());
+        }
+        for cell in &self.tape {
+            bytes.extend_from_slice(&cell.0.to_le_bytes());
+        }
+        bytes
+    }
+
+    pub fn cells(&self) -> &[Cell] {
+        &self.tape
+    }
+
+    pub fn pointer(&self) -> usize {
+        self.pointer
+    }
+
+    /// Decodes a snapshot written by `to_bytes`, `None` when `bytes` aren't one.
+    pub fn from_bytes(bytes: &[u8]) -> Option<Self> {
+        let rest = bytes.strip_prefix(SNAPSHOT_MAGIC)?;
+        let (&bits, rest) = rest.split_first()?;
+        let cell_width = match bits {
+            8 => CellWidth::I8,
+            16 => CellWidth::I16,
+            32 => CellWidth::I32,
+            _ => return None,
+        };
+        let mut numbers = rest.chunks(8).take(3).map(|chunk| {
+            let number = u64::from_le_bytes(chunk.try_into().ok()?);
+            usize::try_from(number).ok()
+        });
+        let (pointer, steps, len) = (numbers.next()??, numbers.next()??, numbers.next()??);
+        let cells = rest.get(24..)?;
+        if cells.len() / 4 != len || cells.len() % 4 != 0 || pointer >= len {
+            return None;
+        }
+        let tape = cells
+            .chunks_exact(4)
+            .map(|cell| Wrapping(i32::from_le_bytes([cell[0], cell[1], cell[2], cell[3]])))
+            .collect();
+        Some(TapeSnapshot {
+            tape,
+            pointer,
+            origin: 0,
+            steps,
+            cell_width,
+        })
+    }
+}
+
+/// The tape as a `Tracer` sees it.
+#[derive(Debug, Clone, Copy)]
+pub struct TapeView<'a> {
+    cells: &'a [Cell],
+    pointer: usize,
+    steps: usize,
+}
+
+impl TapeView<'_> {
+    pub fn cells(&self) -> &[Cell] {
+        self.cells
+    }
+
+    pub fn pointer(&self) -> usize {
+        self.pointer
+    }
+
+    /// The cell under the pointer.
+    pub fn current(&self) -> Cell {
+        self.cells[self.pointer]
+    }
+
+    /// Steps executed before the instruction being traced.
+    pub fn steps(&self) -> usize {
+        self.steps
+    }
+}
+
+/// Watches an `Interpreter` run, see `Interpreter::run_traced`.
+pub trait Tracer {
+    /// Called right before every step, with the tape as the step finds it. Loops
+    /// are reported once when they're reached and again every time their
+    /// condition is checked after the body, like steps are counted. Breakpoints
+    /// are reported too, even though they aren't steps.
+    fn on_instruction(&mut self, pos: Option<Position>, kind: &MidiInstructionKind, tape: &TapeView);
+}
+
+/// Tracer for untraced runs, which compiles away.
+struct NoTracer;
+
+impl Tracer for NoTracer {
+    #[inline(always)]
+    fn on_instruction(&mut self, _: Option<Position>, _: &MidiInstructionKind, _: &TapeView) {}
+}
+
+/// Tree-walking interpreter over a `MidiAST`.
+///
+/// The tape is the `midilang_core::Tape` the VM runs on too, a single zeroed
+/// cell at first that grows to the right on demand, and to the left with an
+/// infinite `TapeMode`.
+/// Reading past the end of the input stores 0 in the current cell.
+#[derive(Debug, Clone)]
+pub struct Interpreter {
+    tape: Tape,
+    steps: usize,
+    limits: Limits,
+    cell_width: CellWidth,
+    tracking: Option<Tracking>,
+    functions: Functions,
+    rng: Rng,
+    encoding: Encoding,
+    /// Output held back until it's whole UTF-8
+    utf8: Utf8Output,
+}
+
+impl Interpreter {
+    pub fn new() -> Self {
+        Self::with_options(RunOptions::default())
+    }
+
+    /// Creates an interpreter that gives up after executing `max_steps` instructions.
+    pub fn with_step_limit(max_steps: usize) -> Self {
+        Self::with_options(RunOptions {
+            max_steps: Some(max_steps),
+            ..RunOptions::default()
+        })
+    }
+
+    /// Creates an interpreter that gives up once it runs into any of the limits
+    /// in `options`, and runs programs the way the rest of them say.
+    pub fn with_options(options: RunOptions) -> Self {
+        let mut tape = Tape::new();
+        configure_tape(&mut tape, &options);
+        Interpreter {
+            tape,
+            steps: 0,
+            limits: Limits::new(options),
+            cell_width: CellWidth::default(),
+            tracking: None,
+            functions: Functions::new(),
+            rng: Rng::new(options.seed),
+            encoding: options.io.into(),
+            utf8: Utf8Output::new(),
+        }
+    }
+
+    /// Cells wrap around at this width, 8 bits unless set otherwise.
+    pub fn set_cell_width(&mut self, width: CellWidth) {
+        self.cell_width = width;
+        self.tape.set_cell_bits(width.bits());
+    }
+
+    /// Has calls find functions in `functions`, the builtins and the libraries
+    /// linked into them, instead of only the builtins.
+    pub fn set_functions(&mut self, functions: Functions) {
+        self.functions = functions;
+    }
+
+    /// Starts the random bytes over from `seed` instead of the one it was created with.
+    pub fn set_seed(&mut self, seed: u64) {
+        self.rng = Rng::new(seed);
+    }
+
+    /// Lets the pointer move left of the first cell or not, instead of what it
+    /// was created with.
+    pub fn set_tape_mode(&mut self, mode: TapeMode) {
+        self.tape.set_two_sided(mode == TapeMode::Infinite);
+    }
+
+    /// Saturates or stops at cells going past their width, instead of what it
+    /// was created with.
+    pub fn set_cell_overflow(&mut self, overflow: Overflow) {
+        self.tape.set_overflow(overflow.into());
+    }
+
+    /// Keeps the pointer on the first `tape_size` cells, instead of what it was
+    /// created with.
+    pub fn set_pointer_overflow(&mut self, overflow: PointerOverflow, tape_size: u64) {
+        self.tape.set_size(tape_size as usize, overflow == PointerOverflow::Wrap);
+    }
+
+    /// Writes and reads UTF-8 or bytes, instead of what it was created with.
+    pub fn set_io_mode(&mut self, mode: IoMode) {
+        self.encoding = mode.into();
+    }
+
+    pub fn tape(&self) -> &[Cell] {
+        self.tape.cells()
+    }
+
+    /// Index in `tape` of the cell the pointer started on.
+    pub fn origin(&self) -> usize {
+        self.tape.origin()
+    }
+
+    pub fn pointer(&self) -> usize {
+        self.tape.pointer()
+    }
+
+    pub fn steps(&self) -> usize {
+        self.steps
+    }
+
+    /// The cells around the pointer, like the dump tape chord prints them.
+    pub fn dump_tape(&self) -> TapeDump<'_> {
+        self.tape.dump()
+    }
+
+    /// Starts the tape out with the cells of a data track, see `Tape::load`.
+    pub fn load_data(&mut self, data: &[u8]) {
+        self.tape.load(data);
+    }
+
+    /// Copies the tape, pointer, step count and cell width, to `restore` later.
+    pub fn snapshot(&self) -> TapeSnapshot {
+        TapeSnapshot {
+            tape: self.tape.cells().to_vec(),
+            pointer: self.tape.pointer(),
+            origin: self.tape.origin(),
+            steps: self.steps,
+            cell_width: self.cell_width,
+        }
+    }
+
+    /// Puts the interpreter back in the state it was in when `snapshot` was taken.
+    /// Limits and position tracking are left as they are.
+    pub fn restore(&mut self, snapshot: &TapeSnapshot) {
+        self.tape.restore(&snapshot.tape, snapshot.pointer, snapshot.origin);
+        self.steps = snapshot.steps;
+        self.set_cell_width(snapshot.cell_width);
+    }
+
+    /// Starts keeping track of which instruction every change to the tape and
+    /// every byte of output came from, which slows the interpreter down.
+    pub fn track_positions(&mut self) {
+        self.tracking.get_or_insert_with(Tracking::default);
+    }
+
+    pub fn tracking(&self) -> Option<&Tracking> {
+        self.tracking.as_ref()
+    }
+
+    /// Executes `program` against the current tape.
+    ///
+    /// Instructions other than loops are never partially applied: when one fails,
+    /// the tape and pointer are left as they were before it.
+    pub fn run<R: Read, W: Write>(
+        &mut self,
+        program: &[MidiInstruction],
+        input: &mut R,
+        output: &mut W,
+    ) -> InterpResult<()> {
+        self.run_with(program, input, output, &mut NoTracer)?;
+        self.finish_output(output)
+    }
+
+    /// Like `run`, calling `tracer` before every step.
+    pub fn run_traced<R: Read, W: Write>(
+        &mut self,
+        program: &[MidiInstruction],
+        input: &mut R,
+        output: &mut W,
+        tracer: &mut dyn Tracer,
+    ) -> InterpResult<()> {
+        self.run_with(program, input, output, tracer)?;
+        self.finish_output(output)
+    }
+
+    // writes out a UTF-8 sequence the program never finished, replaced
+    fn finish_output<W: Write>(&mut self, output: &mut W) -> InterpResult<()> {
+        Ok(self.utf8.finish(&mut StdOutput(output))?)
+    }
+
+    fn run_with<R: Read, W: Write, T: Tracer + ?Sized>(
+        &mut self,
+        program: &[MidiInstruction],
+        input: &mut R,
+        output: &mut W,
+        tracer: &mut T,
+    ) -> InterpResult<()> {
+        for inst in program {
+            self.step(inst, input, output, tracer)?;
+        }
+        Ok(())
+    }
+
+    fn tick<T: Tracer + ?Sized>(&mut self, inst: &MidiInstruction, tracer: &mut T) -> InterpResult<()> {
+        self.limits.check_step(self.steps)?;
+        self.trace(inst, tracer);
+        self.steps += 1;
+        Ok(())
+    }
+
+    fn trace<T: Tracer + ?Sized>(&self, inst: &MidiInstruction, tracer: &mut T) {
+        let view = TapeView {
+            cells: self.tape.cells(),
+            pointer: self.tape.pointer(),
+            steps: self.steps,
+        };
+        tracer.on_instruction(inst.position, &inst.instruction, &view);
+    }
+
+    fn step<R: Read, W: Write, T: Tracer + ?Sized>(
+        &mut self,
+        inst: &MidiInstruction,
+        input: &mut R,
+        output: &mut W,
+        tracer: &mut T,
+    ) -> InterpResult<()> {
+        if inst.instruction == Breakpoint {
+            // only there for tracers, so it isn't a step
+            self.trace(inst, tracer);
+            return Ok(());
+        }
+        self.tick(inst, tracer)?;
+        match &inst.instruction {
+            IncrementCell { amount } => {
+                if !self.tape.add(*amount) {
+                    return Err(InterpError::CellOverflow(inst.position));
+                }
+                if let Some(tracking) = &mut self.tracking {
+                    tracking.changed_cell(self.tape.pointer(), inst.position);
+                }
+            }
+            MovePointer { amount } => {
+                let origin = self.tape.origin();
+                if !self.tape.move_by(*amount) {
+                    return Err(match *amount < 0 {
+                        true => InterpError::PointerUnderflow(inst.position),
+                        false => InterpError::PointerOverflow(inst.position),
+                    });
+                }
+                if let Some(tracking) = &mut self.tracking {
+                    tracking.grew_left(self.tape.origin() - origin);
+                }
+            }
+            OutputCell => {
+                self.limits.check_output()?;
+                let byte = self.tape.current().0 as u8;
+                match self.encoding {
+                    Encoding::Bytes => output.write_all(&[byte])?,
+                    Encoding::Utf8 => self.utf8.write_byte(byte, &mut StdOutput(output))?,
+                    Encoding::Numeric => write_number(&mut StdOutput(output), self.tape.current().0 as u32)?,
+                }
+                if let Some(tracking) = &mut self.tracking {
+                    tracking.output.push(inst.position);
+                }
+            }
+            InputCell if self.encoding == Encoding::Utf8 => {
+                // EOF reads as 0, a code point takes a cell for every byte
+                let mut bytes = [0; 4];
+                let len = read_utf8(&mut StdInput(input), &mut bytes)?.max(1);
+                self.tape.set_from(&bytes[..len]);
+                if let Some(tracking) = &mut self.tracking {
+                    let pointer = self.tape.pointer();
+                    let end = (pointer + len).min(self.tape.cells().len());
+                    (pointer..end).for_each(|cell| tracking.changed_cell(cell, inst.position));
+                }
+            }
+            InputCell if self.encoding == Encoding::Numeric => {
+                // EOF reads as 0 too
+                let value = read_number(&mut StdInput(input))?;
+                self.tape.set(Wrapping(value as i32));
+                if let Some(tracking) = &mut self.tracking {
+                    tracking.changed_cell(self.tape.pointer(), inst.position);
+                }
+            }
+            InputCell => {
+                // EOF reads as 0
+                let mut buf = [0_u8];
+                let byte = match input.read(&mut buf)? {
+                    0 => 0,
+                    _ => buf[0],
+                };
+                self.tape.set(Wrapping(i32::from(byte)));
+                if let Some(tracking) = &mut self.tracking {
+                    tracking.changed_cell(self.tape.pointer(), inst.position);
+                }
+            }
+            Call { name } => {
+                // C functions printing anything print after what came before
+                output.flush()?;
+                let value = self.functions.call(name, self.tape.current())?;
+                self.tape.set(value);
+                if let Some(tracking) = &mut self.tracking {
+                    tracking.changed_cell(self.tape.pointer(), inst.position);
+                }
+            }
+            Random => {
+                self.tape.set(Wrapping(i32::from(self.rng.byte())));
+                if let Some(tracking) = &mut self.tracking {
+                    tracking.changed_cell(self.tape.pointer(), inst.position);
+                }
+            }
+            DumpTape => {
+                // after what the program printed so far
+                output.flush()?;
+                eprintln!("{}", self.tape.dump());
+            }
+            Loop { body } => {
+                while self.tape.current().0 != 0 {
+                    self.run_with(body, input, output, tracer)?;
+                    // loop condition checks count towards the step limit too
+                    self.tick(inst, tracer)?;
+                }
+            }
+            Breakpoint => {}
+        }
+        Ok(())
+    }
+}
+
+impl Default for Interpreter {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+    use crate::parser::{MidiAST, MidiASTBuilder};
+
+    fn build(insts: Vec<MidiInstruction>) -> MidiAST {
+        let mut builder = MidiASTBuilder::new();
+        for inst in insts {
+            builder.push(inst).unwrap();
+        }
+        builder.into_mast().unwrap()
+    }
+
+    #[test]
+    fn three_plus_five() {
+        let prog = build(vec![
+            MidiInstruction::new_inc(Wrapping(3)),
+            MidiInstruction::new_move(1),
+            MidiInstruction::new_inc(Wrapping(5)),
+            MidiInstruction::new_move(-1),
+            MidiInstruction::new_open_loop(),
+            MidiInstruction::new_inc(Wrapping(-1)),
+            MidiInstruction::new_move(1),
+            MidiInstruction::new_inc(Wrapping(1)),
+            MidiInstruction::new_move(-1),
+            MidiInstruction::new_close_loop(),
+            MidiInstruction::new_move(1),
+            MidiInstruction::new_inc(Wrapping(48)),
+            MidiInstruction::new_output(),
+        ]);
+        let mut out = vec![];
+        let mut interp = Interpreter::new();
+        interp.run(&prog, &mut io::empty(), &mut out).unwrap();
+        assert_eq!(out, b"8");
+        assert_eq!(interp.tape(), &[Wrapping(0), Wrapping(56)]);
+        assert_eq!(interp.pointer(), 1);
+    }
+
+    #[test]
+    fn echoes_input() {
+        let prog = build(vec![MidiInstruction::new_input(), MidiInstruction::new_output()]);
+        let mut out = vec![];
+        Interpreter::new().run(&prog, &mut &b"A"[..], &mut out).unwrap();
+        assert_eq!(out, b"A");
+    }
+
+    #[test]
+    fn step_limit_and_underflow() {
+        let forever = build(vec![
+            MidiInstruction::new_inc(Wrapping(1)),
+            MidiInstruction::new_open_loop(),
+            MidiInstruction::new_output(),
+            MidiInstruction::new_close_loop(),
+        ]);
+        let mut interp = Interpreter::with_step_limit(100);
+        let err = interp.run(&forever, &mut io::empty(), &mut io::sink()).unwrap_err();
+        assert_eq!(err, InterpError::StepLimit(100));
+
+        let underflow = build(vec![MidiInstruction::new_move(-1)]);
+        let err = Interpreter::new().run(&underflow, &mut io::empty(), &mut io::sink()).unwrap_err();
+        assert_eq!(err, InterpError::PointerUnderflow(Some(Position::new(0, 0))));
+    }
+
+    #[test]
+    fn output_and_time_limits() {
+        let forever = build(vec![
+            MidiInstruction::new_inc(Wrapping(1)),
+            MidiInstruction::new_open_loop(),
+            MidiInstruction::new_output(),
+            MidiInstruction::new_close_loop(),
+        ]);
+        let mut interp = Interpreter::with_options(RunOptions {
+            max_output_bytes: Some(3),
+            ..RunOptions::default()
+        });
+        let mut out = vec![];
+        let err = interp.run(&forever, &mut io::empty(), &mut out).unwrap_err();
+        assert_eq!(err, InterpError::OutputLimit(3));
+        assert_eq!(out, [1, 1, 1]);
+
+        let limit = Duration::from_millis(10);
+        let mut interp = Interpreter::with_options(RunOptions {
+            wall_clock_limit: Some(limit),
+            ..RunOptions::default()
+        });
+        let err = interp.run(&forever, &mut io::empty(), &mut io::sink()).unwrap_err();
+        assert_eq!(err, InterpError::TimeLimit(limit));
+    }
+
+    #[test]
+    fn snapshot_and_restore() {
+        let prog = build(vec![
+            MidiInstruction::new_inc(Wrapping(3)),
+            MidiInstruction::new_move(2),
+            MidiInstruction::new_inc(Wrapping(-1)),
+        ]);
+        let mut interp = Interpreter::new();
+        interp.set_cell_width(CellWidth::I16);
+        interp.run(&prog, &mut io::empty(), &mut io::sink()).unwrap();
+        let snapshot = interp.snapshot();
+
+        interp.run(&prog, &mut io::empty(), &mut io::sink()).unwrap();
+        assert_eq!(interp.pointer(), 4);
+        interp.restore(&snapshot);
+        assert_eq!(interp.tape(), &[Wrapping(3), Wrapping(0), Wrapping(65535)]);
+        assert_eq!(interp.pointer(), 2);
+        assert_eq!(interp.steps(), 3);
+
+        let bytes = snapshot.to_bytes();
+        assert_eq!(TapeSnapshot::from_bytes(&bytes), Some(snapshot));
+        assert_eq!(TapeSnapshot::from_bytes(&bytes[..bytes.len() - 1]), None);
+        assert_eq!(TapeSnapshot::from_bytes(b"midilang snapshot\0\x08"), None);
+    }
+
+    #[test]
+    fn traces_every_step() {
+        /// Collects the step, pointer and current cell at every instruction
+        struct Recorder(Vec<(usize, usize, i32)>);
+
+        impl Tracer for Recorder {
+            fn on_instruction(&mut self, _: Option<Position>, kind: &MidiInstructionKind, tape: &TapeView) {
+                if matches!(kind, Loop { .. } | Breakpoint) {
+                    self.0.push((tape.steps(), tape.pointer(), tape.current().0));
+                }
+            }
+        }
+
+        let prog = build(vec![
+            MidiInstruction::new_inc(Wrapping(2)),
+            MidiInstruction::new_breakpoint(),
+            MidiInstruction::new_open_loop(),
+            MidiInstruction::new_move(1),
+            MidiInstruction::new_inc(Wrapping(1)),
+            MidiInstruction::new_move(-1),
+            MidiInstruction::new_inc(Wrapping(-1)),
+            MidiInstruction::new_close_loop(),
+        ]);
+        let mut interp = Interpreter::new();
+        let mut recorder = Recorder(vec![]);
+        interp.run_traced(&prog, &mut io::empty(), &mut io::sink(), &mut recorder).unwrap();
+        // the breakpoint doesn't take a step
+        assert_eq!(recorder.0, [(1, 0, 2), (1, 0, 2), (6, 0, 1), (11, 0, 0)]);
+        assert_eq!(interp.steps(), 12);
+    }
+
+    #[test]
+    fn cells_wrap_at_their_width() {
+        let prog = build(vec![
+            MidiInstruction::new_inc(Wrapping(256)),
+            MidiInstruction::new_move(1),
+            MidiInstruction::new_inc(Wrapping(-1)),
+        ]);
+        let mut narrow = Interpreter::new();
+        narrow.run(&prog, &mut io::empty(), &mut io::sink()).unwrap();
+        assert_eq!(narrow.tape(), &[Wrapping(0), Wrapping(255)]);
+
+        let mut wide = Interpreter::new();
+        wide.set_cell_width(CellWidth::I16);
+        wide.run(&prog, &mut io::empty(), &mut io::sink()).unwrap();
+        assert_eq!(wide.tape(), &[Wrapping(256), Wrapping(65535)]);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn calls_functions_from_libc() {
+        let prog = build(vec![
+            MidiInstruction::new_input(),
+            MidiInstruction::new_call("toupper".to_owned()),
+            MidiInstruction::new_output(),
+        ]);
+        let mut interp = Interpreter::new();
+        let mut output = vec![];
+        interp.run(&prog, &mut &b"m"[..], &mut output).unwrap();
+        assert_eq!((output, interp.steps()), (b"M".to_vec(), 3));
+
+        let missing = build(vec![MidiInstruction::new_call("not_a_midilang_function".to_owned())]);
+        let err = Interpreter::new().run(&missing, &mut io::empty(), &mut io::sink()).unwrap_err();
+        assert_eq!(err, InterpError::UnknownFunction("not_a_midilang_function".to_owned()));
+    }
+
+    #[test]
+    fn seeds_pick_the_same_random_bytes() {
+        let prog = build(vec![
+            MidiInstruction::new_random(),
+            MidiInstruction::new_move(1),
+            MidiInstruction::new_random(),
+        ]);
+        let mut rng = Rng::new(7);
+        let expected = [Wrapping(i32::from(rng.byte())), Wrapping(i32::from(rng.byte()))];
+        let mut interp = Interpreter::with_options(RunOptions { seed: 7, ..RunOptions::default() });
+        interp.run(&prog, &mut io::empty(), &mut io::sink()).unwrap();
+        assert_eq!(interp.tape(), expected);
+        // every interpreter keeps to its own seed
+        let mut other = Interpreter::with_options(RunOptions { seed: 8, ..RunOptions::default() });
+        other.run(&prog, &mut io::empty(), &mut io::sink()).unwrap();
+        assert_ne!(other.tape(), expected);
+    }
+
+    #[test]
+    fn infinite_tapes_let_the_pointer_move_left() {
+        let prog = build(vec![
+            MidiInstruction::new_inc(Wrapping(1)),
+            MidiInstruction::new_move(-2),
+            MidiInstruction::new_inc(Wrapping(2)),
+        ]);
+        assert!(matches!(Interpreter::new().run(&prog, &mut io::empty(), &mut vec![]), Err(InterpError::PointerUnderflow(_))));
+        let mut interp = Interpreter::with_options(RunOptions { tape_mode: TapeMode::Infinite, ..RunOptions::default() });
+        interp.track_positions();
+        interp.run(&prog, &mut io::empty(), &mut vec![]).unwrap();
+        assert_eq!(interp.tape(), [Wrapping(2), Wrapping(0), Wrapping(1)]);
+        assert_eq!((interp.pointer(), interp.origin()), (0, 2));
+        // what changed the cells moved along with them
+        let tracking = interp.tracking().unwrap();
+        assert_eq!(tracking.cells, [Some(Position::new(2, 2)), None, Some(Position::new(0, 0))]);
+    }
+
+    #[test]
+    fn keeps_to_overflow_policies() {
+        let prog = build(vec![
+            MidiInstruction::new_inc(Wrapping(-1)),
+            MidiInstruction::new_move(-1),
+            MidiInstruction::new_inc(Wrapping(300)),
+            MidiInstruction::new_move(2),
+        ]);
+        let mut interp = Interpreter::new();
+        interp.set_cell_overflow(Overflow::Trap);
+        let trapped = interp.clone().run(&prog, &mut io::empty(), &mut vec![]);
+        assert_eq!(trapped, Err(InterpError::CellOverflow(Some(Position::new(0, 0)))));
+
+        interp.set_cell_overflow(Overflow::Saturate);
+        interp.set_pointer_overflow(PointerOverflow::Trap, 2);
+        let trapped = interp.clone().run(&prog, &mut io::empty(), &mut vec![]);
+        assert_eq!(trapped, Err(InterpError::PointerUnderflow(Some(Position::new(1, 1)))));
+
+        interp.set_pointer_overflow(PointerOverflow::Wrap, 3);
+        interp.run(&prog, &mut io::empty(), &mut vec![]).unwrap();
+        assert_eq!(interp.tape(), [Wrapping(0), Wrapping(0), Wrapping(255)]);
+        assert_eq!(interp.pointer(), 1);
+    }
+
+    #[test]
+    fn reads_and_writes_utf8() {
+        // ,.>.>, then a byte no UTF-8 has
+        let prog = build(vec![
+            MidiInstruction::new_input(),
+            MidiInstruction::new_output(),
+            MidiInstruction::new_move(1),
+            MidiInstruction::new_output(),
+            MidiInstruction::new_move(1),
+            MidiInstruction::new_input(),
+            MidiInstruction::new_inc(Wrapping(-0x62)),
+            MidiInstruction::new_output(),
+        ]);
+        let mut interp = Interpreter::new();
+        interp.set_io_mode(IoMode::Utf8);
+        interp.track_positions();
+        let mut output = vec![];
+        interp.run(&prog, &mut "éa".as_bytes(), &mut output).unwrap();
+        assert_eq!(String::from_utf8(output).unwrap(), "é\u{fffd}");
+        assert_eq!(interp.tape(), [Wrapping(0xc3), Wrapping(0xa9), Wrapping(0xff)]);
+        // the second cell is the code point's too
+        let tracking = interp.tracking().unwrap();
+        assert_eq!(tracking.cells[..2], [Some(Position::new(0, 0)), Some(Position::new(0, 0))]);
+    }
+
+    #[test]
+    fn reads_and_prints_numbers() {
+        let prog = build(vec![
+            MidiInstruction::new_input(),
+            MidiInstruction::new_output(),
+            MidiInstruction::new_move(1),
+            MidiInstruction::new_input(),
+            MidiInstruction::new_inc(Wrapping(1)),
+            MidiInstruction::new_output(),
+        ]);
+        let mut interp = Interpreter::with_options(RunOptions { io: IoMode::Numeric, ..RunOptions::default() });
+        let mut output = vec![];
+        interp.run(&prog, &mut "x -7 300".as_bytes(), &mut output).unwrap();
+        // both wrap around the byte cells
+        assert_eq!(String::from_utf8(output).unwrap(), "249\n45\n");
+        // interpreters in the same process keep to their own IO mode
+        let mut output = vec![];
+        Interpreter::new().run(&prog, &mut "AB".as_bytes(), &mut output).unwrap();
+        assert_eq!(output, b"AC");
+    }
+}
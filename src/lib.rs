@@ -1,29 +1,1254 @@
-use log::{debug, error, info};
+use log::{debug, info};
 use midly::num::{u15, u28, u4, u7};
 use midly::{Format, Header, MidiMessage, Smf, Timing, Track, TrackEvent, TrackEventKind};
-use std::error::Error;
 use std::fs::{self, File};
-use std::io::Read;
+use std::io::{self, BufWriter, Read, Write};
+use std::path::{Path, PathBuf};
+use std::sync::OnceLock;
+use std::process;
+use std::time::{Duration, Instant};
 
+use compiler::{CellWidth, CompileOptions, Emit, PointerOverflow, TapeMode};
+use dump::DumpFormat;
+use error::{MidilangError, MidilangResult};
+use cache::Cache;
+use failure::{ErrorFormat, Failure, FailureKind};
+use logging::millis_since;
+use interpreter::{InputMode, InterpError, RunOptions};
+use parser::{MidiAST, ParseOptions};
+use provenance::Provenance;
+use timing::SourceMap;
+
+#[cfg(feature = "llvm14")]
+extern crate llvm_sys_140 as llvm_sys;
+#[cfg(feature = "llvm15")]
+extern crate llvm_sys_150 as llvm_sys;
+#[cfg(feature = "llvm16")]
+extern crate llvm_sys_160 as llvm_sys;
+#[cfg(feature = "llvm17")]
+extern crate llvm_sys_170 as llvm_sys;
+
+#[cfg(not(any(feature = "llvm", feature = "cranelift", feature = "web")))]
+compile_error!("enable the cranelift feature or one of the llvm14, llvm15, llvm16 or llvm17 features");
+
+pub mod bench;
+pub mod bounds;
+pub mod cache;
+pub mod check;
 pub mod compiler;
+pub mod compose;
+#[cfg(unix)]
+pub mod daemon;
+#[cfg(feature = "tui")]
+pub mod debugger;
+pub mod diagnostics;
+pub mod dump;
+pub mod error;
+pub mod explain;
+pub mod failure;
+pub mod ffi;
+pub mod formatter;
+#[cfg(feature = "arbitrary")]
+pub mod generate;
+pub mod interpreter;
+pub mod lilypond;
+pub mod live;
+pub mod logging;
+pub mod lsp;
+pub mod musicxml;
+mod notation;
+pub mod optimizer;
 pub mod parser;
+#[cfg(feature = "play")]
+pub mod play;
+pub mod prelude;
+pub mod pretty;
+pub mod profile;
+pub mod program;
+pub mod progress;
+pub mod provenance;
+pub mod record;
+pub mod reduce;
+pub mod render;
+#[cfg(feature = "serve")]
+pub mod serve;
+pub mod stats;
+pub mod suite;
+pub mod sysex;
+#[cfg(feature = "terminal")]
+pub mod terminal;
+pub mod timing;
 mod utils;
+pub mod verify;
+pub mod vm;
+#[cfg(feature = "watch")]
+pub mod watch;
+#[cfg(feature = "web")]
+pub mod web;
 // use crate::parser::MParseError;
 
-// compiles
-pub fn compile_file(file_path: &str) -> Result<i32, Box<dyn Error>> {
+pub use program::MidiProgram;
+
+/// What `compile_file` writes out, besides the compiled program itself.
+#[derive(Debug, Default, Clone)]
+pub struct OutputOptions {
+    pub emit: Emit,
+    /// Defaults to the input file with an extension matching `emit`
+    pub output: Option<PathBuf>,
+    /// Print the LLVM IR to stderr after optimization
+    pub dump_llvm: bool,
+    /// Print the AST to stderr after optimization
+    pub dump_ast: bool,
+    /// Emit debug info, writing the source map listing its line numbers refer
+    /// to next to the output as `.midimap`
+    pub debug_info: bool,
+    /// Carry the source MIDI file along in the output, see `extract_file`
+    pub embed_source: bool,
+    /// How to print why compiling failed, as logs, JSON lines or a SARIF log
+    pub error_format: ErrorFormat,
+    /// Warn about the pointer certainly leaving the tape instead of failing,
+    /// see `bounds::check`
+    pub lenient: bool,
+    /// Reuse tracks read and programs compiled before, from the `cache::Cache`
+    /// next to the file
+    pub cache: bool,
+}
+
+/// File name that stands for stdin as an input and stdout as an output.
+pub const STDIO: &str = "-";
+
+/// Name programs that didn't come from a file go by in diagnostics and provenance.
+pub(crate) const IN_MEMORY: &str = "<memory>";
+
+/// Files at least this big are mapped into memory instead of read.
+const MAP_FROM: u64 = 1 << 20;
+
+// the bytes of a source file, mapped into memory when it's big so only the
+// parts being parsed need to be in memory
+pub(crate) enum Source {
+    Mapped(memmap2::Mmap),
+    Read(Vec<u8>),
+}
+
+impl std::ops::Deref for Source {
+    type Target = [u8];
+
+    fn deref(&self) -> &[u8] {
+        match self {
+            Source::Mapped(map) => map,
+            Source::Read(bytes) => bytes,
+        }
+    }
+}
+
+// reads a MIDI file, or stdin for `-`. stdin is read once and kept, so every
+// step that needs the source sees the same bytes
+pub(crate) fn read_source(file_path: &str) -> io::Result<Source> {
+    static STDIN: OnceLock<Vec<u8>> = OnceLock::new();
+    if file_path != STDIO {
+        let file = File::open(file_path)?;
+        if file.metadata()?.len() < MAP_FROM {
+            let mut bytes = vec![];
+            (&file).read_to_end(&mut bytes)?;
+            return Ok(Source::Read(bytes));
+        }
+        debug!("Mapping {} into memory", file_path);
+        // SAFETY: the map is only read, and only while the file is open. Another
+        // process truncating the file in the meantime makes reading the map
+        // crash, which is as much as any tool that maps its input can promise
+        return Ok(Source::Mapped(unsafe { memmap2::Mmap::map(&file)? }));
+    }
+    if let Some(bytes) = STDIN.get() {
+        return Ok(Source::Read(bytes.clone()));
+    }
+    let mut bytes = vec![];
+    io::stdin().lock().read_to_end(&mut bytes)?;
+    Ok(Source::Read(STDIN.get_or_init(|| bytes).clone()))
+}
+
+// path outputs are named after by default, `stdin` when reading from there
+fn source_path(file_path: &str) -> &Path {
+    match file_path {
+        STDIO => Path::new("stdin"),
+        path => Path::new(path),
+    }
+}
+
+// a program along with where its instructions came from and the cells its
+// tape starts out with
+type ParsedSource = (MidiAST, SourceMap, Vec<u8>);
+
+// a program along with where its instructions and the file came from, and the
+// cells its tape starts out with
+type LoadedProgram = (MidiAST, SourceMap, Provenance, Vec<u8>);
+
+// reads and parses a MIDI file with `parse`, failing with everything wrong
+// with it when it isn't a valid program
+fn parse_file(file_path: &str, parse: &ParseOptions) -> MidilangResult<LoadedProgram> {
     info!("Reading MIDI file from {}", &file_path);
     // read file
-    let bytes = fs::read(file_path)?;
-    let midi = Smf::parse(&bytes)?;
+    let bytes = read_source(file_path)?;
 
     // parse midi SMF into midi program AST
-    if let Err(mperr) = parser::parse(midi) {
-        error!("Error when parsing file: {:?}", mperr);
+    let (prog, source_map, data) = parse_source(file_path, &bytes, parse)?;
+    Ok((prog, source_map, Provenance::new(source_path(file_path), &bytes), data))
+}
+
+// parses a MIDI file, or a program written out by `dump_file`. Dumps don't
+// know the song they came from, so their source map is empty
+pub(crate) fn parse_source(file_path: &str, bytes: &[u8], parse: &ParseOptions) -> Result<ParsedSource, Failure> {
+    parse_source_in(file_path, bytes, parse, None)
+}
+
+// like `parse_source`, taking the tracks `cache` has read before from it
+fn parse_source_in(file_path: &str, bytes: &[u8], parse: &ParseOptions, cache: Option<&Cache>) -> Result<ParsedSource, Failure> {
+    let parse_failure = |msg: String| Failure::new(FailureKind::Parse, file_path, msg);
+    if let Some(format) = DumpFormat::detect(bytes) {
+        let source = std::str::from_utf8(bytes).map_err(|err| parse_failure(err.to_string()))?;
+        let ast = dump::read(source, format).map_err(|err| parse_failure(err.to_string()))?;
+        // dumps get no more out of calls than songs do
+        if !parse.reads_calls() && has_calls(&ast) {
+            return Err(parse_failure(parser::MParseError::NeedsExtensions.describe()));
+        }
+        return Ok((ast, SourceMap::default(), vec![]));
+    }
+    // events are read as they're parsed, huge files never sit in memory twice
+    let includes = includes_for(file_path);
+    let parsed = match cache {
+        Some(cache) => cache.parse(bytes, &includes, parse),
+        None => parser::parse_all_bytes_in(bytes, &includes, parse),
+    };
+    let parsed = parsed.map_err(|err| parse_failure(err.to_string()))?;
+    parse_program(file_path, parsed)
+}
+
+// included files are found next to the file, songs from stdin or memory
+// only include from the include directories
+fn includes_for(file_path: &str) -> sysex::Includes {
+    match file_path {
+        STDIO | IN_MEMORY => sysex::Includes::default(),
+        _ => sysex::Includes::for_file(Path::new(file_path)),
+    }
+}
+
+// parses every chord of the song in `bytes`, errors and all, for the commands
+// that look at a song without running it
+fn parse_all_source(file_path: &str, bytes: &[u8], parse: &ParseOptions) -> midly::Result<parser::Parsed> {
+    parser::parse_all_bytes_in(bytes, &includes_for(file_path), parse)
+}
+
+// whether `prog` calls a function anywhere
+fn has_calls(prog: &[parser::MidiInstruction]) -> bool {
+    prog.iter().any(|inst| match &inst.instruction {
+        parser::MidiInstructionKind::Call { .. } => true,
+        parser::MidiInstructionKind::Loop { body } => has_calls(body),
+        _ => false,
+    })
+}
+
+// makes a program out of a parsed MIDI file, or describes everything wrong with it
+fn parse_program(file_path: &str, parsed: parser::Parsed) -> Result<ParsedSource, Failure> {
+    let Some(first) = parsed.errors.first() else {
+        // every error is in `errors`, so the program parsed
+        let ast = parsed.ast.map_err(|err| Failure::new(FailureKind::Parse, file_path, err.describe()))?;
+        return Ok((ast, parsed.source_map, parsed.data));
+    };
+    let diagnostics = check::check_parsed(&parsed, false).diagnostics;
+    for diag in &diagnostics {
+        let position = diag.bar_beat.map(|bar_beat| bar_beat.to_string());
+        info!(phase = "parse", file = file_path, code = diag.code, position = position, tick = diag.tick; "{}", diag.message);
+    }
+    Err(Failure {
+        rendered: Some(diagnostics::render(file_path, &parsed, diagnostics::use_color())),
+        diagnostics,
+        ..Failure::new(FailureKind::Parse, file_path, first.error.describe())
+    })
+}
+
+// reads, parses and optimizes a MIDI file for running with the settings of `options`
+fn load_program(file_path: &str, parse: &ParseOptions, cell_width: CellWidth, options: &RunOptions) -> MidilangResult<LoadedProgram> {
+    let (prog, source_map, provenance, data) = parse_file(file_path, parse)?;
+    let midi_program = optimizer::optimize_with_data(prog, cell_width, options, &data);
+    debug!("Optimized program: {:?}", midi_program);
+    Ok((midi_program, source_map, provenance, data))
+}
+
+// parses the MIDI file in `bytes`, failing with everything wrong with it when
+// it isn't a valid program
+pub fn parse_bytes(bytes: &[u8]) -> MidilangResult<MidiProgram> {
+    MidiProgram::from_bytes(bytes)
+}
+
+// parses whatever can be read out of `bytes` without ever failing, like
+// `parser::parse_all`. Tracks and events midly can't read are left out, so a
+// cut off file keeps its chords up to the cut, and bytes that aren't MIDI at
+// all read as a song without tracks. Meant for fuzzing the parser
+pub fn parse_bytes_lossy(bytes: &[u8]) -> parser::Parsed {
+    parser::parse_all_bytes(bytes).unwrap_or_else(|err| {
+        debug!("Not a MIDI file, reading it as a song without tracks: {}", err);
+        parser::parse_all(Smf::new(Header::new(Format::SingleTrack, Timing::Metrical(u15::from(480)))))
+    })
+}
+
+// like `parse_bytes`, reading the MIDI file from `reader`
+pub fn parse_reader(mut reader: impl Read) -> MidilangResult<MidiProgram> {
+    let mut bytes = vec![];
+    reader.read_to_end(&mut bytes)?;
+    parse_bytes(&bytes)
+}
+
+// compiles the MIDI file in `bytes`, read with `parse`, and writes what `emit`
+// asks for to `out`, without touching the filesystem unless it has to link an
+// executable
+pub fn compile_bytes(bytes: &[u8], parse: &ParseOptions, mut options: CompileOptions, emit: Emit, out: &mut impl Write) -> MidilangResult<()> {
+    let (program, source_map) = optimize_source(IN_MEMORY, bytes, parse, &mut options, &OutputOptions::default(), None)?;
+    if emit == Emit::C {
+        let source = compiler::c::transpile(&program, Some(&source_map), options);
+        out.write_all(source.as_bytes())?;
+    } else {
+        out.write_all(&compiler::compile_program(program, Some(source_map), options)?.emit_bytes(emit)?)?;
+    }
+    Ok(())
+}
+
+// like `compile_bytes`, reading the MIDI file from `reader`
+pub fn compile_reader(mut reader: impl Read, parse: &ParseOptions, options: CompileOptions, emit: Emit, out: &mut impl Write) -> MidilangResult<()> {
+    let mut bytes = vec![];
+    reader.read_to_end(&mut bytes)?;
+    compile_bytes(&bytes, parse, options, emit, out)
+}
+
+// an error from running a program, naming where it was when the source map knows
+pub(crate) fn run_error(err: InterpError, source_map: &SourceMap) -> MidilangError {
+    match err {
+        InterpError::PointerUnderflow(Some(position))
+        | InterpError::PointerOverflow(Some(position))
+        | InterpError::CellOverflow(Some(position)) => MidilangError::Run(err, Some(source_map.describe(position))),
+        err => MidilangError::Run(err, None),
+    }
+}
+
+// compiles, failing with what went wrong, see `MidilangError::report` for
+// printing it in `output.error_format`
+pub fn compile_file(file_path: &str, parse: &ParseOptions, options: CompileOptions, output: &OutputOptions) -> MidilangResult<i32> {
+    compile(file_path, parse, options, output)?;
+    Ok(0)
+}
+
+fn compile(file_path: &str, parse: &ParseOptions, mut options: CompileOptions, output: &OutputOptions) -> Result<(), Failure> {
+    let io_failure = |err: io::Error| Failure::new(FailureKind::Io, file_path, err.to_string());
+    let bytes = read_source(file_path).map_err(io_failure)?;
+    let emit = output.emit;
+    let targets_wasm = options.targets_wasm();
+    let default_path = || source_path(file_path).with_extension(emit.extension(targets_wasm));
+    let out_path = output.output.clone().unwrap_or_else(default_path);
+    let to_stdout = out_path == Path::new(STDIO);
+
+    // stdin has nowhere to keep a cache next to it, and side outputs need
+    // every step to run
+    let cache = (output.cache && file_path != STDIO).then(|| Cache::beside(Path::new(file_path)));
+    let settings = format!(
+        "{:?} {:?} {:?} lenient={} embed_source={} {}",
+        options,
+        parse,
+        emit,
+        output.lenient,
+        output.embed_source,
+        source_path(file_path).display()
+    );
+    let output_key = cache
+        .as_ref()
+        .filter(|_| !output.debug_info && !output.dump_ast && !output.dump_llvm)
+        .and_then(|_| Cache::output_key(&bytes, &settings));
+    let output_cache = cache.as_ref().zip(output_key.as_deref());
+    if let Some(cached) = output_cache.and_then(|(cache, key)| cache.output(key)) {
+        if to_stdout {
+            io::stdout().lock().write_all(&fs::read(&cached).map_err(io_failure)?).map_err(io_failure)?;
+        } else {
+            fs::copy(&cached, &out_path).map_err(io_failure)?;
+            info!("Wrote {}, compiled the same before", out_path.display());
+        }
+        return Ok(());
+    }
+
+    let (midi_program, source_map) = optimize_source(file_path, &bytes, parse, &mut options, output, cache.as_ref())?;
+    if output.debug_info {
+        // the listing needs a file of its own, even when the output goes to stdout
+        let listing = if to_stdout { default_path() } else { out_path.clone() }.with_extension("midimap");
+        File::create(&listing).and_then(|mut file| source_map.write_listing(&mut file)).map_err(io_failure)?;
+        info!("Wrote source map listing to {}", listing.display());
+        options.debug_info = Some(std::path::absolute(&listing).map_err(io_failure)?);
+    }
+    if emit == Emit::C {
+        // no backend involved, so this works wherever midilang builds
+        let source = compiler::c::transpile(&midi_program, Some(&source_map), options);
+        if to_stdout {
+            io::stdout().lock().write_all(source.as_bytes()).map_err(io_failure)?;
+        } else {
+            fs::write(&out_path, &source).map_err(io_failure)?;
+            info!("Wrote {}", out_path.display());
+        }
+        if let Some((cache, key)) = output_cache {
+            cache.keep_output_bytes(key, source.as_bytes());
+        }
+        return Ok(());
+    }
+    let codegen_failure = |err| Failure::new(FailureKind::Codegen, file_path, format!("{:?}", err));
+    let started = Instant::now();
+    let backend = compiler::compile_program(midi_program, Some(source_map), options).map_err(codegen_failure)?;
+    if output.dump_llvm {
+        eprintln!("{}", backend.print_ir());
+    }
+    if to_stdout {
+        let bytes = backend.emit_bytes(emit).map_err(codegen_failure)?;
+        info!(phase = "codegen", file = file_path, duration_ms = millis_since(started); "Compiled {}", file_path);
+        io::stdout().lock().write_all(&bytes).map_err(io_failure)?;
+        if let Some((cache, key)) = output_cache {
+            cache.keep_output_bytes(key, &bytes);
+        }
+    } else {
+        backend.emit(emit, &out_path).map_err(codegen_failure)?;
+        info!(phase = "codegen", file = file_path, duration_ms = millis_since(started); "Compiled {}", file_path);
+        info!("Wrote {}", out_path.display());
+        if let Some((cache, key)) = output_cache {
+            cache.keep_output(key, &out_path);
+        }
+    }
+    Ok(())
+}
+
+// parses, checks and optimizes the song in `bytes` the same for every compile,
+// from a file or not, and points `options` at where it came from
+fn optimize_source(
+    file_path: &str,
+    bytes: &[u8],
+    parse: &ParseOptions,
+    options: &mut CompileOptions,
+    output: &OutputOptions,
+    cache: Option<&Cache>,
+) -> Result<(MidiAST, SourceMap), Failure> {
+    let started = Instant::now();
+    let (prog, source_map, data) = parse_source_in(file_path, bytes, parse, cache)?;
+    info!(phase = "parse", file = file_path, instructions = source_map.len(), duration_ms = millis_since(started); "Parsed {}", file_path);
+    check_bounds(file_path, &prog, &source_map, options, output.lenient)?;
+
+    let started = Instant::now();
+    let midi_program = optimizer::optimize_with_data(prog, options.cell_width, &options.run_options(RunOptions::default()), &data);
+    debug!("Optimized program: {:?}", midi_program);
+    info!(phase = "optimize", file = file_path, instructions = midi_program.len(), duration_ms = millis_since(started); "Optimized {}", file_path);
+    options.provenance = Some(Provenance::new(source_path(file_path), bytes));
+    options.data = data;
+    if output.embed_source {
+        options.embedded_source = Some(bytes.to_vec());
+    }
+    if output.dump_ast {
+        eprint!("{}", pretty::PrettyAst::new(&midi_program, Some(&source_map)));
+    }
+    Ok((midi_program, source_map))
+}
+
+// fails compiling when a move certainly takes the pointer off the tape, which
+// has a start unless it's infinite, and only has an end when it can't grow and
+// the pointer traps at it. Fixed tapes without a pointer overflow are fitted
+// to the program later on, so their end is never in the way.
+// Lenient compiles only warn
+fn check_bounds(file_path: &str, prog: &MidiAST, source_map: &SourceMap, options: &CompileOptions, lenient: bool) -> Result<(), Failure> {
+    if options.tape_mode == TapeMode::Infinite || options.pointer_overflow == Some(PointerOverflow::Wrap) {
+        return Ok(());
+    }
+    let tape_size = (options.tape_mode == TapeMode::Fixed)
+        .then_some(options.tape_size)
+        .filter(|_| options.pointer_overflow.is_some());
+    let Some(oob) = bounds::check(prog, tape_size) else {
+        return Ok(());
+    };
+    let message = oob.describe(source_map);
+    if lenient {
+        log::warn!("{}: {}", file_path, message);
+        return Ok(());
+    }
+    let index = oob.position.map(|pos| pos.start());
+    let tick = index.and_then(|index| source_map.tick(index));
+    let diagnostic = check::Diagnostic {
+        code: "pointer-out-of-bounds",
+        severity: check::Severity::Error,
+        tick,
+        bar_beat: index.and_then(|index| source_map.bar_beat(index)),
+        chord: None,
+        notes: vec![],
+        message: message.clone(),
+    };
+    Err(Failure {
+        diagnostics: vec![diagnostic],
+        ..Failure::new(FailureKind::Codegen, file_path, message)
+    })
+}
+
+// compiles every file in `file_paths` on its own, next to where it came from,
+// in parallel with `parallel`. Prints what went wrong once they're all done,
+// along with which ones failed when there's more than one, and exits with the
+// exit code of the first that did
+pub fn compile_files(
+    file_paths: &[String],
+    parse: &ParseOptions,
+    options: CompileOptions,
+    output: &OutputOptions,
+    parallel: bool,
+) -> MidilangResult<i32> {
+    if file_paths.len() > 1 && output.output.is_some() {
+        return Err(MidilangError::Other("-o can't name the output of more than one input file".to_owned()));
+    }
+    let compile = |file_path: &String| compile(file_path, parse, options.clone(), output).err();
+    let failures: Vec<Failure> = if parallel {
+        compile_parallel(file_paths, compile)
+    } else {
+        file_paths.iter().map(compile).collect()
+    }
+    .into_iter()
+    .flatten()
+    .collect();
+
+    match output.error_format {
+        ErrorFormat::Sarif => Failure::report_sarif(&failures),
+        format => failures.iter().for_each(|failure| failure.report(format)),
+    }
+    if file_paths.len() > 1 && output.error_format == ErrorFormat::Human {
+        eprintln!("Compiled {} of {} files", file_paths.len() - failures.len(), file_paths.len());
+        for failure in &failures {
+            eprintln!("  failed: {}", failure.file);
+        }
+    }
+    Ok(failures.first().map_or(0, |failure| failure.kind.exit_code()))
+}
+
+#[cfg(feature = "parallel")]
+fn compile_parallel<F: Fn(&String) -> Option<Failure> + Send + Sync>(file_paths: &[String], compile: F) -> Vec<Option<Failure>> {
+    use rayon::prelude::*;
+    file_paths.par_iter().map(compile).collect()
+}
+
+#[cfg(not(feature = "parallel"))]
+fn compile_parallel<F: Fn(&String) -> Option<Failure>>(file_paths: &[String], compile: F) -> Vec<Option<Failure>> {
+    log::warn!("midilang was built without the parallel feature, compiling one file at a time");
+    file_paths.iter().map(compile).collect()
+}
+
+// writes the parsed program out in `format` for other tools, to stdout unless
+// given an output. Compiling or running a JSON or S-expression dump gets the
+// same program back
+pub fn dump_file(file_path: &str, parse: &ParseOptions, format: DumpFormat, output: Option<&Path>) -> MidilangResult<i32> {
+    let (prog, source_map, ..) = parse_file(file_path, parse)?;
+    let bytes = read_source(file_path)?;
+    let dump = match format {
+        // engraves the song itself, timing and all, when there is one
+        DumpFormat::Lilypond | DumpFormat::Musicxml if DumpFormat::detect(&bytes).is_none() => {
+            let title = source_path(file_path).file_name().unwrap_or_default().to_string_lossy();
+            dump::write_score(&parser::parse_all_bytes(&bytes)?, format, &title)
+        }
+        format => dump::write(&prog, format, Some(&source_map)),
+    };
+    match output {
+        Some(path) if path != Path::new(STDIO) => {
+            fs::write(path, dump)?;
+            info!("Wrote {}", path.display());
+        }
+        _ => io::stdout().lock().write_all(dump.as_bytes())?,
+    }
+    Ok(0)
+}
+
+// rewrites a program as a canonical MIDI file with `formatter::format`, over
+// the file it came from unless given an output. Programs from dumps or stdin go
+// to stdout instead. With `check` nothing is written, it only fails with 1 when
+// the file isn't formatted
+pub fn fmt_file(file_path: &str, parse: &ParseOptions, output: Option<&Path>, check: bool) -> MidilangResult<i32> {
+    let (prog, ..) = parse_file(file_path, parse)?;
+    let bytes = read_source(file_path)?;
+    let mut formatted = vec![];
+    formatter::format(&prog).write_std(&mut formatted)?;
+    if check {
+        if formatted[..] == bytes[..] {
+            return Ok(0);
+        }
+        eprintln!("{} isn't formatted", source_path(file_path).display());
         return Ok(1);
     }
+    let in_place = file_path != STDIO && DumpFormat::detect(&bytes).is_none();
+    match output {
+        Some(path) if path != Path::new(STDIO) => fs::write(path, formatted)?,
+        None if in_place => fs::write(file_path, formatted)?,
+        _ => io::stdout().lock().write_all(&formatted)?,
+    }
+    info!("Formatted {}", source_path(file_path).display());
+    Ok(0)
+}
+
+// makes up a program with `options` and writes it as a formatted MIDI file to
+// `output`, by default `composed-<seed>.mid`
+pub fn compose_file(options: &compose::ComposeOptions, output: Option<&Path>) -> MidilangResult<i32> {
+    let program = compose::compose(options);
+    let output = output.map_or_else(|| PathBuf::from(format!("composed-{}.mid", options.seed)), Path::to_owned);
+    let mut song = vec![];
+    formatter::format(&program).write_std(&mut song)?;
+    if output == Path::new(STDIO) {
+        io::stdout().lock().write_all(&song)?;
+    } else {
+        fs::write(&output, song)?;
+        println!("composed {} with seed {}", output.display(), options.seed);
+    }
+    Ok(0)
+}
+
+// shrinks a MIDI file that shows a bug, taking out loops, chords and notes for
+// as long as the shell command `check` still exits with 0 on what's left, and
+// writes the smallest song it gets to, by default next to the file as
+// `.reduced.mid`. `{}` in `check` is replaced with the path of the song to
+// check, which otherwise goes on the end. Checks taking longer than `timeout`
+// are stopped and taken as not showing the bug, taking chords out of a
+// program can easily make it never end
+pub fn reduce_file(file_path: &str, check: &str, timeout: Duration, output: Option<&Path>) -> MidilangResult<i32> {
+    let bytes = read_source(file_path)?;
+    let chords: Vec<Vec<u8>> = parser::parse_all_bytes(&bytes)?.chords.into_iter().map(|chord| chord.notes).collect();
+    let candidate = compiler::temp_path("mid");
+    let quoted = format!("'{}'", candidate.display().to_string().replace('\'', "'\\''"));
+    let command = match check.contains("{}") {
+        true => check.replace("{}", &quoted),
+        false => format!("{} {}", check, quoted),
+    };
+    let mut checks = 0;
+    let mut holds = |chords: &[Vec<u8>]| -> io::Result<bool> {
+        checks += 1;
+        let mut song = vec![];
+        formatter::song(chords).write_std(&mut song)?;
+        fs::write(&candidate, song)?;
+        run_check(&command, timeout)
+    };
+    // the song is rewritten chord by chord, which can lose the bug
+    let reduced = match holds(&chords) {
+        Ok(true) => reduce::reduce(chords.clone(), &mut holds).map(Some),
+        result => result.map(|_| None),
+    };
+    let _ = fs::remove_file(&candidate);
+    let Some(reduced) = reduced? else {
+        return Err(MidilangError::Other(format!(
+            "`{}` doesn't hold for {} with its chords written back to back, there's nothing to reduce",
+            check, file_path
+        )));
+    };
+
+    let output = output.map_or_else(|| source_path(file_path).with_extension("reduced.mid"), Path::to_owned);
+    let mut song = vec![];
+    formatter::song(&reduced).write_std(&mut song)?;
+    fs::write(&output, song)?;
+    println!(
+        "{}: reduced {} chords to {} in {} checks, written to {}",
+        file_path,
+        chords.len(),
+        reduced.len(),
+        checks,
+        output.display()
+    );
+    Ok(0)
+}
+
+// whether the shell command `command` exits with 0 within `timeout`
+fn run_check(command: &str, timeout: Duration) -> io::Result<bool> {
+    let mut check = process::Command::new("sh");
+    check.arg("-c").arg(command).stdout(process::Stdio::null()).stderr(process::Stdio::null());
+    // so whatever it starts can be stopped with it
+    #[cfg(unix)]
+    std::os::unix::process::CommandExt::process_group(&mut check, 0);
+    let mut child = check.spawn()?;
+    let start = Instant::now();
+    loop {
+        if let Some(status) = child.try_wait()? {
+            return Ok(status.success());
+        }
+        if start.elapsed() >= timeout {
+            debug!("`{}` took longer than {} ms", command, timeout.as_millis());
+            // SAFETY: `kill` takes any pid, the group is the one the check leads
+            #[cfg(unix)]
+            unsafe {
+                libc::kill(-(child.id() as libc::pid_t), libc::SIGKILL);
+            }
+            #[cfg(not(unix))]
+            child.kill()?;
+            child.wait()?;
+            return Ok(false);
+        }
+        std::thread::sleep(Duration::from_millis(5));
+    }
+}
+
+// prints how many instructions of each kind a MIDI file has, how deep its
+// loops go, how much tape it uses and how long the song is, whether or not
+// it's a program
+pub fn stats_file(file_path: &str, parse: &ParseOptions) -> MidilangResult<i32> {
+    let bytes = read_source(file_path)?;
+    let parsed = parse_all_source(file_path, &bytes, parse)?;
+    stats::Stats::new(&parsed).write_report(&mut io::stdout().lock())?;
+    Ok(0)
+}
+
+// prints every chord of a MIDI file with what the parser makes of it, like a
+// disassembler, errors and all
+pub fn explain_file(file_path: &str, parse: &ParseOptions) -> MidilangResult<i32> {
+    let bytes = read_source(file_path)?;
+    let parsed = parse_all_source(file_path, &bytes, parse)?;
+    io::stdout().lock().write_all(explain::explain(&parsed).as_bytes())?;
+    Ok(0)
+}
+
+// lists the riffs of the prelude with what they do
+pub fn lib_list() -> MidilangResult<i32> {
+    let width = prelude::RIFFS.iter().map(|riff| riff.name.len()).max().unwrap_or(0);
+    let mut out = io::stdout().lock();
+    for riff in prelude::RIFFS {
+        writeln!(out, "{:width$}  {}", riff.name, riff.about, width = width)?;
+    }
+    Ok(0)
+}
+
+// writes the prelude riff `name` out as a MIDI file of its own, `<name>.mid`
+// unless given an output, for pasting its chords into a song
+pub fn lib_inline(name: &str, output: Option<&Path>) -> MidilangResult<i32> {
+    let Some(riff) = prelude::Riff::find(name) else {
+        return Err(MidilangError::Other(format!(
+            "the prelude has no riff called {}, `midilang lib list` lists them",
+            name
+        )));
+    };
+    let out_path = output.map_or_else(|| PathBuf::from(format!("{}.mid", riff.name)), Path::to_owned);
+    riff.to_smf().save(&out_path)?;
+    info!("Wrote {}", out_path.display());
+    Ok(0)
+}
+
+// writes a string literal setting the cells to `text` out as a MIDI file of its
+// own, `string.mid` unless given an output, for pasting into a song
+pub fn lib_string(text: &str, output: Option<&Path>) -> MidilangResult<i32> {
+    let out_path = output.map_or_else(|| PathBuf::from("string.mid"), Path::to_owned);
+    string_to_smf(text)?.save(&out_path)?;
+    info!("Wrote {}", out_path.display());
+    Ok(0)
+}
+
+// recovers the source MIDI file of a program compiled with `embed_source`,
+// writing it next to the binary as `<binary>.mid` unless given an output
+pub fn extract_file(binary_path: &Path, output: Option<&Path>) -> MidilangResult<i32> {
+    info!("Extracting the source MIDI file from {}", binary_path.display());
+    let binary = fs::read(binary_path)?;
+    let Some(smf) = provenance::extract_source(&binary) else {
+        return Err(MidilangError::Other(format!(
+            "{} has no embedded MIDI file, compile it with --embed-source",
+            binary_path.display()
+        )));
+    };
+    let out_path = match output {
+        Some(path) => path.to_owned(),
+        None => {
+            let mut path = binary_path.as_os_str().to_owned();
+            path.push(".mid");
+            PathBuf::from(path)
+        }
+    };
+    fs::write(&out_path, smf)?;
+    info!("Wrote {}", out_path.display());
+    Ok(0)
+}
+
+// compiles in memory with Cranelift and runs the program right away, on the
+// files in the options instead of stdin and stdout when there are any
+#[cfg(feature = "cranelift")]
+pub fn jit_file(file_path: &str, parse: &ParseOptions, mut options: CompileOptions) -> MidilangResult<i32> {
+    let (midi_program, _, _, data) = load_program(file_path, parse, options.cell_width, &options.run_options(RunOptions::default()))?;
+    options.data = data;
+    let input = options.stdin.take().map(File::open).transpose()?;
+    let output = options.stdout.take().map(File::create).transpose()?;
+    Ok(compiler::cranelift::run_jit_redirected(&midi_program, options, input, output)?)
+}
+
+// runs the program right away, JIT compiled when Cranelift is built in and
+// on the bytecode VM otherwise, or when there are limits, traps, UTF-8 or an
+// input mode only the VM handles. Reads and writes the files in the options
+// instead of stdin and stdout when there are any
+pub fn run_file(
+    file_path: &str,
+    parse: &ParseOptions,
+    options: CompileOptions,
+    limits: RunOptions,
+    input_mode: Option<InputMode>,
+) -> MidilangResult<i32> {
+    #[cfg(feature = "cranelift")]
+    {
+        let traps = options.overflow == compiler::Overflow::Trap || options.pointer_overflow == Some(PointerOverflow::Trap);
+        if !limits.is_limited() && !traps && options.io == compiler::IoMode::Bytes && input_mode.is_none() {
+            return jit_file(file_path, parse, options);
+        }
+    }
+    let (stdin, stdout): (Box<dyn Read>, Box<dyn Write>) = match input_mode {
+        // a file given as stdin doesn't need the terminal
+        #[cfg(feature = "terminal")]
+        Some(mode) if options.stdin.is_none() => terminal::stdio(mode)?,
+        _ => (Box::new(io::stdin().lock()), Box::new(io::stdout().lock())),
+    };
+    let input: Box<dyn Read> = match &options.stdin {
+        Some(path) => Box::new(io::BufReader::new(File::open(path)?)),
+        None => stdin,
+    };
+    let output: Box<dyn Write> = match &options.stdout {
+        Some(path) => Box::new(File::create(path)?),
+        None => stdout,
+    };
+    interpret_file(file_path, parse, options.cell_width, options.run_options(limits), &options.link, input, output)
+}
+
+// runs the program on the bytecode VM, reading `input` and writing `output`,
+// with the functions of the libraries in `link` for its calls
+pub fn interpret_file(
+    file_path: &str,
+    parse: &ParseOptions,
+    cell_width: CellWidth,
+    limits: RunOptions,
+    link: &[PathBuf],
+    mut input: Box<dyn Read>,
+    output: Box<dyn Write>,
+) -> MidilangResult<i32> {
+    let (midi_program, source_map, _, data) = load_program(file_path, parse, cell_width, &limits)?;
+    let code = vm::Bytecode::new(&midi_program);
+    debug!("Bytecode: {:?}", code.ops());
+    let mut machine = new_vm(cell_width, limits);
+    machine.set_functions(ffi::Functions::linked(link).map_err(MidilangError::Other)?);
+    machine.load_data(&data);
+    let mut stdout = BufWriter::new(output);
+    let result = machine.run(&code, &mut input, &mut stdout);
+    stdout.flush()?;
+    result.map_err(|err| run_error(err, &source_map))?;
+    Ok(0)
+}
+
+// the bytecode VM programs run on when they aren't JIT compiled
+pub(crate) fn new_vm(cell_width: CellWidth, limits: RunOptions) -> vm::Vm {
+    let mut machine = vm::Vm::with_options(limits);
+    machine.set_cell_width(cell_width);
+    // hot loops get compiled when LLVM is around
+    #[cfg(feature = "llvm")]
+    match compiler::llvm::jit::LoopJit::new() {
+        Ok(jit) => machine.set_loop_compiler(Box::new(jit)),
+        Err(err) => log::warn!("Running without the loop JIT: {:?}", err),
+    }
+    machine
+}
+
+// runs every `prog.mid` in `dir` with a `prog.out` next to it or `expect:`
+// meta events in it on the bytecode VM, giving it `prog.in` or its `input:`
+// events as input, or `input` without either, and checks it writes exactly
+// what's expected. Prints how they went as TAP, to `report` when given, and
+// exits with 1 when any of them didn't
+pub fn test_dir(
+    dir: &Path,
+    parse: &ParseOptions,
+    cell_width: CellWidth,
+    limits: RunOptions,
+    input: Option<&Path>,
+    report: Option<&Path>,
+) -> MidilangResult<i32> {
+    let cases = suite::find_cases(dir)?;
+    info!("Running {} tests from {}", cases.len(), dir.display());
+    let default_input = match input {
+        Some(path) => fs::read(path)?,
+        None => vec![],
+    };
+    let passed = match report {
+        Some(path) => suite::run_all(&cases, parse, cell_width, limits, &default_input, &mut BufWriter::new(File::create(path)?))?,
+        None => suite::run_all(&cases, parse, cell_width, limits, &default_input, &mut io::stdout().lock())?,
+    };
+    Ok(if passed { 0 } else { 1 })
+}
+
+// parses, optimizes, compiles and runs a MIDI file `runs` times, reading
+// `input` or nothing, and prints how long each phase took
+pub fn bench_file(
+    file_path: &str,
+    parse: &ParseOptions,
+    options: CompileOptions,
+    input: Option<&Path>,
+    limits: RunOptions,
+    runs: usize,
+) -> MidilangResult<i32> {
+    let bytes = read_source(file_path)?;
+    let input = match input {
+        Some(path) => fs::read(path)?,
+        None => vec![],
+    };
+    let timings = bench::Timings::measure(&bytes, parse, &input, options, limits, runs)?;
+    println!("{}, {} runs", file_path, runs);
+    timings.write_report(&mut io::stdout().lock())?;
+    Ok(0)
+}
+
+// runs the program unoptimized in the interpreter and compiled, reading the
+// same input, and reports where they first disagree
+pub fn verify_file(
+    file_path: &str,
+    parse: &ParseOptions,
+    mut options: CompileOptions,
+    input: Option<&Path>,
+    limits: RunOptions,
+) -> MidilangResult<i32> {
+    let (midi_program, source_map, _, data) = parse_file(file_path, parse)?;
+    options.data = data;
+    let input = match input {
+        Some(path) => fs::read(path)?,
+        None => vec![],
+    };
+    let Some(divergence) = verify::verify(&midi_program, options, &input, limits)? else {
+        println!("{}: compiled output and tape match the interpreter", file_path);
+        return Ok(0);
+    };
+    let location = match divergence.position() {
+        Some(position) => source_map.describe(position),
+        None => "no instruction".to_owned(),
+    };
+    match divergence {
+        verify::Divergence::Output { index, expected, actual, .. } => println!(
+            "{}: output byte {} is {:?} instead of {:?}, written at {}",
+            file_path, index, actual, expected, location
+        ),
+        verify::Divergence::Cell { index, expected, actual, .. } => println!(
+            "{}: cell {} ends up {} instead of {}, last changed at {}",
+            file_path, index, actual, expected, location
+        ),
+    }
+    Ok(1)
+}
+
+// counts how often each chord runs while the song runs on stdin and stdout,
+// writing the counts to `report` and a copy of the file whose velocities follow
+// them to `heatmap`. The song isn't optimized, so merged chords count apart
+pub fn profile_file(
+    file_path: &str,
+    parse: &ParseOptions,
+    cell_width: CellWidth,
+    limits: RunOptions,
+    report: Option<&Path>,
+    heatmap: Option<&Path>,
+) -> MidilangResult<i32> {
+    let (midi_program, source_map, _, data) = parse_file(file_path, parse)?;
+    let mut stdout = BufWriter::new(io::stdout().lock());
+    let result = profile::profile(&midi_program, cell_width, limits, &data, &mut io::stdin().lock(), &mut stdout);
+    stdout.flush()?;
+    let profile = result.map_err(|err| run_error(err, &source_map))?;
+
+    let report_path = match report {
+        Some(path) => path.to_owned(),
+        None => source_path(file_path).with_extension("profile.txt"),
+    };
+    let mut report = BufWriter::new(File::create(&report_path)?);
+    profile.write_report(&midi_program, &source_map, &mut report)?;
+    report.flush()?;
+    info!("Wrote {}", report_path.display());
+
+    let heatmap_path = match heatmap {
+        Some(path) => path.to_owned(),
+        None => source_path(file_path).with_extension("heatmap.mid"),
+    };
+    let bytes = read_source(file_path)?;
+    let mut midi = Smf::parse(&bytes)?;
+    profile.heatmap(&mut midi);
+    midi.save(&heatmap_path)?;
+    info!("Wrote {}", heatmap_path.display());
+    Ok(0)
+}
+
+// parses the file without running it and prints every error in it, and with
+// `lint` likely mistakes as warnings. Exits with 0 when it's clean, 1 when
+// there are only warnings and 2 when there are errors
+pub fn check_file(file_path: &str, parse: &ParseOptions, lint: bool, format: ErrorFormat) -> MidilangResult<i32> {
+    let bytes = read_source(file_path)?;
+    let parsed = parse_all_source(file_path, &bytes, parse)?;
+    print_report(file_path, &check::check_parsed(&parsed, lint), format)
+}
+
+// like `check_file` with lints, at the severities in `levels`. Diagnostics
+// are named after their lint, to allow or deny it by
+pub fn lint_file(file_path: &str, parse: &ParseOptions, levels: &check::LintLevels, format: ErrorFormat) -> MidilangResult<i32> {
+    let bytes = read_source(file_path)?;
+    let parsed = parse_all_source(file_path, &bytes, parse)?;
+    print_report(file_path, &check::lint_parsed(&parsed, levels), format)
+}
+
+fn print_report(file_path: &str, report: &check::Report, format: ErrorFormat) -> MidilangResult<i32> {
+    let mut stdout = io::stdout().lock();
+    match format {
+        ErrorFormat::Human => {
+            for diag in &report.diagnostics {
+                let location = match (diag.bar_beat, diag.tick) {
+                    (Some(bar_beat), _) => format!("{}:{}", file_path, bar_beat),
+                    (None, Some(tick)) => format!("{}: tick {}", file_path, tick),
+                    (None, None) => file_path.to_owned(),
+                };
+                writeln!(stdout, "{}: {}[{}]: {}", location, diag.severity, diag.code, diag.message)?;
+            }
+            let count = |severity| report.diagnostics.iter().filter(|diag| diag.severity == severity).count();
+            let (errors, warnings) = (count(check::Severity::Error), count(check::Severity::Warning));
+            writeln!(stdout, "{}: {} errors, {} warnings", file_path, errors, warnings)?;
+        }
+        ErrorFormat::Json => {
+            for diag in &report.diagnostics {
+                writeln!(stdout, "{}", diagnostics::to_json(file_path, diag))?;
+            }
+        }
+        ErrorFormat::Sarif => writeln!(stdout, "{}", diagnostics::to_sarif(&[(file_path, &report.diagnostics)]))?,
+    }
+    Ok(match report.severity() {
+        None => 0,
+        Some(check::Severity::Warning) => 1,
+        Some(check::Severity::Error) => 2,
+    })
+}
+
+// finds the chords that never run over runs on every input file, or one on
+// stdin without any, writing which chords ran to `report` and a copy of the
+// file with the chords that didn't moved to a muted channel to `annotated`
+pub fn coverage_file(
+    file_path: &str,
+    parse: &ParseOptions,
+    cell_width: CellWidth,
+    inputs: &[PathBuf],
+    limits: RunOptions,
+    report: Option<&Path>,
+    annotated: Option<&Path>,
+) -> MidilangResult<i32> {
+    let (midi_program, source_map, _, data) = parse_file(file_path, parse)?;
+    let mut coverage = profile::Profile { hits: vec![] };
+    let mut stdout = BufWriter::new(io::stdout().lock());
+    let runs = match inputs {
+        [] => vec![None],
+        inputs => inputs.iter().map(Some).collect(),
+    };
+    for input in runs {
+        let result = match input {
+            Some(path) => {
+                let input = fs::read(path)?;
+                profile::profile(&midi_program, cell_width, limits, &data, &mut &input[..], &mut stdout)
+            }
+            None => profile::profile(&midi_program, cell_width, limits, &data, &mut io::stdin().lock(), &mut stdout),
+        };
+        match result {
+            Ok(run) => coverage.merge(&run),
+            Err(err) => {
+                stdout.flush()?;
+                return Err(run_error(err, &source_map));
+            }
+        }
+    }
+    stdout.flush()?;
+
+    let report_path = match report {
+        Some(path) => path.to_owned(),
+        None => source_path(file_path).with_extension("coverage.txt"),
+    };
+    let mut report = BufWriter::new(File::create(&report_path)?);
+    coverage.write_coverage(&midi_program, &source_map, &mut report)?;
+    report.flush()?;
+    info!("Wrote {}", report_path.display());
+
+    let annotated_path = match annotated {
+        Some(path) => path.to_owned(),
+        None => source_path(file_path).with_extension("coverage.mid"),
+    };
+    let bytes = read_source(file_path)?;
+    let mut midi = Smf::parse(&bytes)?;
+    coverage.mute_uncovered(&mut midi);
+    midi.save(&annotated_path)?;
+    info!("Wrote {}", annotated_path.display());
+    Ok(0)
+}
+
+// writes the chords the song plays while it runs on stdin and stdout to
+// `output`, in the order it plays them, loops unrolled
+pub fn record_file(
+    file_path: &str,
+    parse: &ParseOptions,
+    cell_width: CellWidth,
+    limits: RunOptions,
+    output: Option<&Path>,
+) -> MidilangResult<i32> {
+    let (midi_program, _, _, data) = parse_file(file_path, parse)?;
+    let bytes = read_source(file_path)?;
+    let source = Smf::parse(&bytes)?;
+    let mut stdout = BufWriter::new(io::stdout().lock());
+    let (recording, result) =
+        record::record(&midi_program, &source, cell_width, limits, &data, &mut io::stdin().lock(), &mut stdout);
+    stdout.flush()?;
+
+    let out_path = match output {
+        Some(path) => path.to_owned(),
+        None => source_path(file_path).with_extension("performance.mid"),
+    };
+    recording.save(&out_path)?;
+    info!("Wrote {}", out_path.display());
+    match result {
+        Ok(()) => Ok(0),
+        Err(InterpError::StepLimit(steps)) => {
+            // the recording just stops there, which is the point of the limit
+            log::warn!("Stopped recording after {} steps", steps);
+            Ok(0)
+        }
+        Err(err) => {
+            log::warn!("The recording stops where the program failed");
+            Err(err.into())
+        }
+    }
+}
+
+// runs every instruction when its chord comes up in the song instead of as fast
+// as it can, writing to stdout unbuffered so the output keeps time
+pub fn run_in_musical_time(file_path: &str, parse: &ParseOptions, cell_width: CellWidth, limits: RunOptions) -> MidilangResult<i32> {
+    let (midi_program, source_map, _, data) = parse_file(file_path, parse)?;
+    let mut interp = interpreter::Interpreter::with_options(limits);
+    interp.set_cell_width(cell_width);
+    interp.load_data(&data);
+    let mut clock = timing::MusicalClock::new(&source_map);
+    // unbuffered, so output shows up in time with the song
+    let result = interp.run_traced(&midi_program, &mut io::stdin().lock(), &mut io::stdout(), &mut clock);
+    result.map_err(|err| run_error(err, &source_map))?;
+    Ok(0)
+}
+
+// renders the chords the song plays while it runs on stdin and stdout to
+// `output` as a WAV file, `bpm` chords a minute
+pub fn render_file(
+    file_path: &str,
+    parse: &ParseOptions,
+    cell_width: CellWidth,
+    limits: RunOptions,
+    bpm: u32,
+    output: Option<&Path>,
+) -> MidilangResult<i32> {
+    let (midi_program, _, _, data) = parse_file(file_path, parse)?;
+    let bytes = read_source(file_path)?;
+    let source = Smf::parse(&bytes)?;
+    let mut stdout = BufWriter::new(io::stdout().lock());
+    let (played, result) =
+        record::played_chords(&midi_program, cell_width, limits, &data, &mut io::stdin().lock(), &mut stdout);
+    stdout.flush()?;
+
+    let notes = record::chord_notes(&source);
+    let chords: Vec<_> = played.iter().map(|&chord| notes.get(chord).map_or(&[][..], Vec::as_slice)).collect();
+    let out_path = match output {
+        Some(path) => path.to_owned(),
+        None => source_path(file_path).with_extension("wav"),
+    };
+    let mut wav = BufWriter::new(File::create(&out_path)?);
+    render::write_wav(&chords, Duration::from_secs(60) / bpm.max(1), &mut wav)?;
+    wav.flush()?;
+    info!("Wrote {}", out_path.display());
+    match result {
+        Ok(()) => Ok(0),
+        Err(InterpError::StepLimit(steps)) => {
+            log::warn!("Stopped rendering after {} steps", steps);
+            Ok(0)
+        }
+        Err(err) => {
+            log::warn!("The rendering stops where the program failed");
+            Err(err.into())
+        }
+    }
+}
+
+// plays the chord of every step on the MIDI output `port` while the song runs
+// on stdin and stdout, `bpm` chords a minute
+#[cfg(feature = "play")]
+pub fn play_file(
+    file_path: &str,
+    parse: &ParseOptions,
+    cell_width: CellWidth,
+    limits: RunOptions,
+    bpm: u32,
+    port: Option<&str>,
+) -> MidilangResult<i32> {
+    let (midi_program, source_map, _, data) = parse_file(file_path, parse)?;
+    let bytes = read_source(file_path)?;
+    let source = Smf::parse(&bytes)?;
+    let mut connection = play::connect(port)?;
+    let mut interp = interpreter::Interpreter::with_options(limits);
+    interp.set_cell_width(cell_width);
+    interp.load_data(&data);
+    // unbuffered, so output shows up in time with the music
+    let result = play::play(
+        &midi_program,
+        &source,
+        &mut connection,
+        bpm,
+        &mut interp,
+        &mut io::stdin().lock(),
+        &mut io::stdout(),
+    );
+    result.map_err(|err| run_error(err, &source_map))?;
+    Ok(0)
+}
+
+// runs the chords played into midilang's MIDI input, or the input `port`, on
+// one interpreter with stdin and stdout as its IO until it's stopped, taking
+// commands as OSC messages on the UDP address `osc` too. Chords and the files
+// loaded are read with `parse`, and run with the settings of `options`
+#[cfg(feature = "play")]
+pub fn live_midi(parse: &ParseOptions, cell_width: CellWidth, options: RunOptions, port: Option<&str>, osc: Option<&str>) -> MidilangResult<i32> {
+    let mut interp = interpreter::Interpreter::with_options(options);
+    interp.set_cell_width(cell_width);
+    let mut live = live::Live::new(interp);
+    live.set_parse_options(parse.clone());
+    let osc = match osc {
+        Some(address) => {
+            let socket = std::net::UdpSocket::bind(address)?;
+            info!("Taking OSC commands on {}", socket.local_addr()?);
+            Some(socket)
+        }
+        None => None,
+    };
+    play::listen(port, &mut live, io::BufReader::new(io::stdin()), osc, &mut io::stdout())?;
+    Ok(0)
+}
+
+// recompiles the file every time it's saved, or with `then` set to `run` or
+// `check` runs or checks it instead
+#[cfg(feature = "watch")]
+pub fn watch_file(file_path: &str, parse: &ParseOptions, then: &[String], options: CompileOptions, output: &OutputOptions) -> MidilangResult<i32> {
+    if file_path == STDIO {
+        return Err(MidilangError::Other("Can't watch stdin for changes".to_owned()));
+    }
+    let then: Vec<&str> = then.iter().map(String::as_str).collect();
+    // saves often only touch a track or two
+    let output = &OutputOptions { cache: true, ..output.clone() };
+    match then[..] {
+        [] => watch::watch(Path::new(file_path), output.error_format, || compile_file(file_path, parse, options.clone(), output)),
+        ["run"] => watch::watch(Path::new(file_path), output.error_format, || run_file(file_path, parse, options.clone(), RunOptions::default(), None)),
+        ["check"] => watch::watch(Path::new(file_path), output.error_format, || check_file(file_path, parse, true, output.error_format)),
+        _ => Err(MidilangError::Other(format!("Can only run or check a watched file, not {:?}", then.join(" ")))),
+    }
+}
 
-    // compiler::compile(midi_program);
+// steps through the unoptimized program in the terminal debugger, with the
+// contents of `input` as its input and the settings of `options`
+#[cfg(feature = "tui")]
+pub fn debug_file(
+    file_path: &str,
+    parse: &ParseOptions,
+    cell_width: CellWidth,
+    options: RunOptions,
+    input: Option<&Path>,
+    breakpoints: &[u64],
+) -> MidilangResult<i32> {
+    let (midi_program, source_map, _, data) = parse_file(file_path, parse)?;
+    let input = match input {
+        Some(path) => fs::read(path)?,
+        None => vec![],
+    };
+    let breakpoints = breakpoints.iter().copied().collect();
+    let session = debugger::Session::start(midi_program, &source_map, cell_width, options, data, input, breakpoints);
+    debugger::run_tui(session, file_path, &source_map)?;
     Ok(0)
 }
 
@@ -57,21 +1282,47 @@ fn make_off<'a>(key: u7) -> TrackEvent<'a> {
 }
 
 // Converts a brainf program into a MIDIlang program in Smf
-pub fn from_brainf(bf_file_path: &str) -> Result<(), Box<dyn Error>> {
+pub fn from_brainf(bf_file_path: &str) -> MidilangResult<()> {
+    let started = Instant::now();
     info!(
         "Converting BF file {} to Standard Midi Format...",
         &bf_file_path
     );
     let ml_file_path = utils::midi_name(bf_file_path);
-    let mut bf_file = File::open(bf_file_path)?;
-    let mut bf_program = String::new();
-    bf_file.read_to_string(&mut bf_program)?;
-
-    let ml_file = File::options()
+    let bf_file = File::open(bf_file_path)?;
+    let mut ml_file = File::options()
         .append(false)
         .write(true)
         .create(true)
+        .truncate(true)
         .open(&ml_file_path)?;
+    convert_brainf(bf_file, &mut ml_file)?;
+    info!(phase = "convert", file = bf_file_path, duration_ms = millis_since(started); "BF parsing successful!");
+    Ok(())
+}
+
+// Converts the brainf program read from `reader` into a MIDIlang program,
+// writing the MIDI file to `out`
+pub fn convert_brainf(mut reader: impl Read, out: &mut impl Write) -> MidilangResult<()> {
+    let mut bf_program = String::new();
+    reader.read_to_string(&mut bf_program)?;
+    let ml_prog = brainf_to_smf(&bf_program);
+
+    debug!("BF program parsed into:");
+    debug!("{:#?}", ml_prog);
+    ml_prog.write_std::<_>(out)?;
+    Ok(())
+}
+
+// `ast` as the song `midilang fmt` writes for it, which parses back to the same
+// program when its amounts are between 1 and 511
+pub fn ast_to_smf(ast: &MidiAST) -> Smf<'_> {
+    formatter::format(ast)
+}
+
+// a brainf program as a MIDIlang program, one chord per instruction. Anything
+// that isn't an instruction is left out
+pub(crate) fn brainf_to_smf(bf_program: &str) -> Smf<'static> {
     let mut ml_prog = Smf::new(Header::new(
         Format::Parallel,
         Timing::Metrical(u15::from(480)),
@@ -81,7 +1332,33 @@ pub fn from_brainf(bf_file_path: &str) -> Result<(), Box<dyn Error>> {
     ml_prog.tracks.push(Track::new()); // meta track is idx 0
 
     ml_prog.tracks.push(Track::new()); // program track is [1]
+    for notes in brainf_chords(bf_program) {
+        for &key in &notes {
+            ml_prog.tracks[1].push(make_on(u7::from(key)));
+        }
+        for &key in notes.iter().rev() {
+            ml_prog.tracks[1].push(make_off(u7::from(key)));
+        }
+    }
+
+    ml_prog
+}
+
+// a song setting the cells from the first one on to `text` with string
+// literals, like `brainf_to_smf` writes brainf. Only ASCII fits in notes
+pub fn string_to_smf(text: &str) -> MidilangResult<Smf<'static>> {
+    if !text.is_ascii() {
+        return Err(MidilangError::Other("string literals only hold ASCII, every byte is a note".to_owned()));
+    }
+    Ok(formatter::song(&formatter::string(text.as_bytes())))
+}
+
+// the notes of the chord of every instruction in a brainf program, lowest first
+pub(crate) fn brainf_chords(bf_program: &str) -> Vec<Vec<u8>> {
+    let mut chords = vec![];
+    let progress = progress::Progress::new("Converting", bf_program.len() as u64);
     for inst in bf_program.chars() {
+        progress.inc(inst.len_utf8() as u64);
         let key = match inst {
             ']' => 0,
             '<' => 2,
@@ -90,27 +1367,191 @@ pub fn from_brainf(bf_file_path: &str) -> Result<(), Box<dyn Error>> {
             '[' => 7,
             '+' => 9,
             ',' => 11,
+            // need to add simultaneous notes to make parses recognize output char
             '.' => {
-                // need to add simultaneous notes to make parses recognize output char
-                ml_prog.tracks[1].push(make_on(u7::from(11)));
-                ml_prog.tracks[1].push(make_on(u7::from(15)));
-                ml_prog.tracks[1].push(make_on(u7::from(18)));
-                ml_prog.tracks[1].push(make_off(u7::from(18)));
-                ml_prog.tracks[1].push(make_off(u7::from(15)));
-                ml_prog.tracks[1].push(make_off(u7::from(11)));
+                chords.push(vec![11, 15, 18]);
+                continue;
+            }
+            // the breakpoint chord, a diminished triad on the tonic
+            '#' => {
+                chords.push(vec![12, 15, 18]);
                 continue;
             }
             _ => continue,
         };
-        ml_prog.tracks[1].push(make_on(u7::from(key)));
-        ml_prog.tracks[1].push(make_off(u7::from(key)));
+        chords.push(vec![key]);
     }
+    chords
+}
 
-    debug!("BF program parsed into:");
-    debug!("{:#?}", ml_prog);
-    if let Err(e) = ml_prog.write_std::<_>(ml_file) {
-        error!("Error when writing SMF to {}: {}", &ml_file_path, e);
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+    use parser::{LocatedError, MParseError};
+
+    fn midi_bytes(bf_program: &str) -> Vec<u8> {
+        let mut bytes = vec![];
+        convert_brainf(bf_program.as_bytes(), &mut bytes).unwrap();
+        bytes
+    }
+
+    #[test]
+    fn parses_from_memory() {
+        let program = parse_reader(&midi_bytes("+[->+<]")[..]).unwrap();
+        assert_eq!(program.to_bf(), "+[->+<]");
+        assert_eq!(program.source_path(), None);
+
+        let err = parse_bytes(&midi_bytes("+[")).unwrap_err();
+        assert_eq!(err.exit_code(), FailureKind::Parse.exit_code());
+        assert!(err.to_string().starts_with("<memory>: loop is never closed"));
+        let err = parse_bytes(b"not midi").unwrap_err();
+        assert!(matches!(err, MidilangError::Failed(ref failure) if failure.kind == FailureKind::Parse));
+    }
+
+    #[test]
+    fn parses_whatever_it_can() {
+        let bytes = midi_bytes("+++++.");
+        assert_eq!(parse_bytes_lossy(&bytes).chords.len(), 6);
+        // cut off in the middle of the output chord
+        let parsed = parse_bytes_lossy(&bytes[..bytes.len() - 12]);
+        assert_eq!((parsed.chords.len(), parsed.errors.len()), (5, 0));
+        let parsed = parse_bytes_lossy(b"not midi");
+        assert!(matches!(parsed.errors[..], [LocatedError { error: MParseError::NoTracks, .. }]));
+    }
+
+    #[test]
+    fn maps_big_files() {
+        let path = compiler::temp_path("mid");
+        fs::write(&path, midi_bytes(&"+>".repeat(70_000))).unwrap();
+        let file_path = path.to_str().unwrap();
+        let source = read_source(file_path).unwrap();
+        assert!(matches!(source, Source::Mapped(_)));
+        let (prog, source_map, ..) = parse_file(file_path, &ParseOptions::default()).unwrap();
+        assert_eq!((prog.len(), source_map.len()), (140_000, 140_000));
+        drop(source);
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn reuses_cached_outputs() {
+        let dir = compiler::temp_path("dir");
+        fs::create_dir(&dir).unwrap();
+        let song = dir.join("song.mid");
+        fs::write(&song, midi_bytes("+.")).unwrap();
+        let output = OutputOptions { emit: Emit::C, cache: true, ..OutputOptions::default() };
+        compile_file(song.to_str().unwrap(), &ParseOptions::default(), CompileOptions::default(), &output).unwrap();
+        assert!(fs::read_to_string(dir.join("song.c")).unwrap().contains("putchar"));
+
+        // building it the same way again only copies what's in the cache
+        let outputs = dir.join(cache::CACHE_DIR).join("outputs");
+        let entries: Vec<PathBuf> = fs::read_dir(&outputs).unwrap().map(|entry| entry.unwrap().path()).collect();
+        assert_eq!(entries.len(), 1);
+        fs::write(&entries[0], "cached").unwrap();
+        compile_file(song.to_str().unwrap(), &ParseOptions::default(), CompileOptions::default(), &output).unwrap();
+        assert_eq!(fs::read_to_string(dir.join("song.c")).unwrap(), "cached");
+        let optimized = CompileOptions::builder().opt_level(compiler::OptLevel::O2).build().unwrap();
+        compile_file(song.to_str().unwrap(), &ParseOptions::default(), optimized, &output).unwrap();
+        assert_ne!(fs::read_to_string(dir.join("song.c")).unwrap(), "cached");
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    #[cfg(any(feature = "llvm", feature = "cranelift"))]
+    fn compiles_from_memory() {
+        let bytes = midi_bytes("++++++++[>++++++++<-]>+.");
+        let mut c = vec![];
+        compile_reader(&bytes[..], &ParseOptions::default(), CompileOptions::default(), Emit::C, &mut c).unwrap();
+        assert!(String::from_utf8(c).unwrap().contains("int main"));
+
+        let mut object = vec![];
+        compile_bytes(&bytes, &ParseOptions::default(), CompileOptions::default(), Emit::Obj, &mut object).unwrap();
+        assert!(!object.is_empty());
+        // songs in memory are checked like files are
+        let off_the_tape = midi_bytes("<+.");
+        let err = compile_bytes(&off_the_tape, &ParseOptions::default(), CompileOptions::default(), Emit::C, &mut vec![]).unwrap_err();
+        assert!(matches!(err, MidilangError::Failed(failure) if failure.diagnostics[0].code == "pointer-out-of-bounds"));
+    }
+
+    #[test]
+    fn fits_the_tape_before_it_ends() {
+        // six cells on a tape of four, which grows to fit them
+        let bytes = midi_bytes(",>>>>>,.");
+        let options = || CompileOptions::builder().tape_size(4);
+        compile_bytes(&bytes, &ParseOptions::default(), options().build().unwrap(), Emit::C, &mut vec![]).unwrap();
+        // unless the pointer traps at its end
+        let trapping = options().pointer_overflow(Some(PointerOverflow::Trap)).build().unwrap();
+        let err = compile_bytes(&bytes, &ParseOptions::default(), trapping, Emit::C, &mut vec![]).unwrap_err();
+        assert!(matches!(err, MidilangError::Failed(failure) if failure.diagnostics[0].code == "pointer-out-of-bounds"));
+    }
+
+    #[test]
+    fn optimizes_for_the_overflow_it_compiles_with() {
+        // cells saturating at 255 end up at 245, not the 250 of wrapping around
+        let bytes = midi_bytes(&format!("{}{}.", "+".repeat(260), "-".repeat(10)));
+        let saturating = || CompileOptions::builder().overflow(compiler::Overflow::Saturate).build().unwrap();
+        let mut from_memory = vec![];
+        compile_bytes(&bytes, &ParseOptions::default(), saturating(), Emit::C, &mut from_memory).unwrap();
+        let mut wrapping = vec![];
+        compile_bytes(&bytes, &ParseOptions::default(), CompileOptions::default(), Emit::C, &mut wrapping).unwrap();
+        assert_ne!(from_memory, wrapping);
+
+        // the same as what `midilang compile --cell-overflow saturate` writes
+        let dir = compiler::temp_path("dir");
+        fs::create_dir(&dir).unwrap();
+        let song = dir.join("song.mid");
+        fs::write(&song, &bytes).unwrap();
+        let output = OutputOptions { emit: Emit::C, ..OutputOptions::default() };
+        compile_file(song.to_str().unwrap(), &ParseOptions::default(), saturating(), &output).unwrap();
+        assert_eq!(fs::read(dir.join("song.c")).unwrap(), from_memory);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn compiled_traps_exit_like_the_vm() {
+        // prints 1 and takes the trapping cell below 0 on the last `-`
+        let bytes = midi_bytes("+.--");
+        let options = CompileOptions::builder().overflow(compiler::Overflow::Trap).build().unwrap();
+        let program = parse_bytes(&bytes).unwrap();
+        let mut machine = new_vm(CellWidth::I8, options.run_options(RunOptions::default()));
+        let mut output = vec![];
+        let err = machine.run(&vm::Bytecode::new(program.ast()), &mut io::empty(), &mut output).unwrap_err();
+        let expected = (Some(run_error(err, program.source_map()).exit_code()), output);
+        assert_eq!(expected, (Some(1), vec![1]));
+
+        let dir = compiler::temp_path("dir");
+        fs::create_dir(&dir).unwrap();
+        let run = |exe: &Path| {
+            let ran = process::Command::new(exe).stdin(process::Stdio::null()).output().unwrap();
+            (ran.status.code(), ran.stdout)
+        };
+        let mut c = vec![];
+        compile_bytes(&bytes, &ParseOptions::default(), options.clone(), Emit::C, &mut c).unwrap();
+        fs::write(dir.join("song.c"), c).unwrap();
+        let built = process::Command::new("cc").arg(dir.join("song.c")).arg("-o").arg(dir.join("from_c")).status().unwrap();
+        assert!(built.success());
+        assert_eq!(run(&dir.join("from_c")), expected);
+        #[cfg(feature = "llvm")]
+        {
+            let song = dir.join("song.mid");
+            fs::write(&song, &bytes).unwrap();
+            let output = OutputOptions { emit: Emit::Exe, output: Some(dir.join("from_llvm")), ..OutputOptions::default() };
+            let options = CompileOptions { backend: compiler::BackendKind::Llvm, ..options };
+            compile_file(song.to_str().unwrap(), &ParseOptions::default(), options, &output).unwrap();
+            assert_eq!(run(&dir.join("from_llvm")), expected);
+        }
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn checks_with_the_options_it_parses_with() {
+        // the random chord, which plain midilang doesn't read
+        let path = compiler::temp_path("mid");
+        formatter::song(&[vec![60, 64, 68]]).save(&path).unwrap();
+        let file_path = path.to_str().unwrap();
+        let extensions = ParseOptions { extensions: true, ..ParseOptions::default() };
+        assert_eq!(check_file(file_path, &extensions, true, ErrorFormat::Human).unwrap(), 0);
+        assert_eq!(check_file(file_path, &ParseOptions::default(), true, ErrorFormat::Human).unwrap(), 2);
+        fs::remove_file(&path).unwrap();
     }
-    info!("BF parsing successful!");
-    Ok(())
 }
@@ -0,0 +1,104 @@
+use std::fmt::Write;
+
+use crate::notation::{annotation, lengths, needs_bass_clef, notate};
+use crate::parser::Parsed;
+
+/// LilyPond version the scores are written for.
+const LILYPOND_VERSION: &str = "2.24.0";
+
+/// Note names in LilyPond's default (Dutch) names, from C.
+const NOTE_NAMES: [&str; 12] = ["c", "cis", "d", "dis", "e", "f", "fis", "g", "gis", "a", "ais", "b"];
+
+/// Engraves the chords of a parsed song as a LilyPond score, with what each
+/// chord does in the program written under it like lyrics: `+3`, `[`, `.`
+/// and so on, or `?` for chords that aren't instructions. See `notate` for
+/// how chords are rounded to notes. Notes that run over a bar line are tied
+/// by LilyPond.
+pub fn engrave(parsed: &Parsed, title: &str) -> String {
+    let mut music = String::new();
+    let mut words = vec![];
+    let mut chords = 0;
+    for notated in notate(parsed) {
+        if let Some((numerator, denominator)) = notated.time {
+            write!(music, "\\time {}/{} ", numerator, denominator).unwrap();
+        }
+        let Some(chord) = notated.chord else {
+            write_lengths(&mut music, "r", notated.sixteenths, false);
+            continue;
+        };
+        let pitches: Vec<String> = chord.notes.iter().map(|&key| pitch(key)).collect();
+        write_lengths(&mut music, &format!("<{}>", pitches.join(" ")), notated.sixteenths, true);
+        chords += 1;
+        if chords % 8 == 0 {
+            music.push_str("\n   ");
+        }
+        words.push(quoted(&annotation(chord)));
+    }
+
+    let mut score = String::new();
+    writeln!(score, "\\version \"{}\"", LILYPOND_VERSION).unwrap();
+    writeln!(score, "\\header {{\n  title = {}\n  tagline = ##f\n}}", quoted(title)).unwrap();
+    let clef = if needs_bass_clef(parsed) { "bass" } else { "treble" };
+    writeln!(score, "program = {{\n  \\clef {}\n  {}\n}}", clef, music.trim_end()).unwrap();
+    let lines: Vec<String> = words.chunks(8).map(|line| line.join(" ")).collect();
+    writeln!(score, "annotations = \\lyricmode {{\n  {}\n}}", lines.join("\n  ")).unwrap();
+    score.push_str(
+        "\\score {\n  <<\n    \\new Staff \\new Voice = \"program\" \\with {\n      \
+         \\remove \"Note_heads_engraver\"\n      \\consists \"Completion_heads_engraver\"\n      \
+         \\remove \"Rest_engraver\"\n      \\consists \"Completion_rest_engraver\"\n    } \\program\n    \
+         \\new Lyrics \\lyricsto \"program\" \\annotations\n  >>\n  \\layout { }\n}\n",
+    );
+    score
+}
+
+// `note` for `sixteenths` sixteenths, as notes LilyPond has tied together
+fn write_lengths(music: &mut String, note: &str, sixteenths: u64, tied: bool) {
+    let lengths = lengths(sixteenths);
+    for (idx, length) in lengths.iter().enumerate() {
+        let tie = if tied && idx + 1 < lengths.len() { "~" } else { "" };
+        write!(music, "{}{}{} ", note, length.lilypond, tie).unwrap();
+    }
+}
+
+/// `key` in LilyPond's absolute octaves, where `c'` is middle C.
+fn pitch(key: u8) -> String {
+    let octave = i32::from(key / 12) - 4;
+    let marks = match octave {
+        0 => String::new(),
+        up if up > 0 => "'".repeat(up as usize),
+        down => ",".repeat(down.unsigned_abs() as usize),
+    };
+    format!("{}{}", NOTE_NAMES[usize::from(key % 12)], marks)
+}
+
+fn quoted(text: &str) -> String {
+    format!("\"{}\"", text.replace('\\', "\\\\").replace('"', "\\\""))
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+    use crate::parser::parse_all;
+    use crate::MidiProgram;
+
+    #[test]
+    fn engraves_chords_with_their_instructions() {
+        let parsed = parse_all(MidiProgram::from_bf_str("+[->.<]").unwrap().to_smf());
+        let score = engrave(&parsed, "song.mid");
+        assert!(score.contains("title = \"song.mid\""));
+        assert!(score.contains("\\clef bass"));
+        assert!(score.contains("<a,,,,>16 <g,,,,>16 <f,,,,>16 <e,,,,>16 <b,,,, dis,,, fis,,,>16"));
+        assert!(score.contains("annotations = \\lyricmode {\n  \"+\" \"[\" \"-\" \">\" \".\" \"<\" \"]\"\n}"));
+    }
+
+    #[test]
+    fn ties_long_notes() {
+        let mut music = String::new();
+        write_lengths(&mut music, "<c'>", 21, true);
+        assert_eq!(music, "<c'>1~ <c'>4~ <c'>16 ");
+        assert_eq!(pitch(60), "c'");
+        assert_eq!(pitch(47), "b,");
+        assert_eq!(quoted("\"]\""), r#""\"]\"""#);
+    }
+}
@@ -0,0 +1,664 @@
+use std::collections::VecDeque;
+use std::fs;
+use std::io::{self, BufRead, Read, Write};
+use std::net::UdpSocket;
+use std::path::Path;
+use std::sync::mpsc;
+use std::thread;
+
+use log::{debug, info, warn};
+use midly::live::LiveEvent;
+use midly::MidiMessage;
+
+use crate::error::{MidilangError, MidilangResult};
+use crate::interpreter::{InterpResult, Interpreter, TapeSnapshot};
+use crate::parser::{read_chord_in, MidiASTBuilder, MidiInstruction, MidiInstructionKind, ParseOptions};
+use crate::{formatter, parse_source, read_source, run_error};
+
+/// What comes in during a live session.
+#[derive(Debug, PartialEq, Eq)]
+pub enum Event {
+    /// A message from the MIDI input
+    Midi(Vec<u8>),
+    /// A line typed starting with `:`, for `Live::command`
+    Command(String),
+}
+
+/// Input of the program played in a live session, the lines typed that aren't
+/// commands.
+pub struct TypedInput {
+    lines: mpsc::Receiver<Vec<u8>>,
+    /// What's left of the line read last
+    pending: VecDeque<u8>,
+}
+
+impl Read for TypedInput {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if self.pending.is_empty() {
+            // the thread reading lines hangs up at the end of what's typed
+            match self.lines.recv() {
+                Ok(line) => self.pending.extend(line),
+                Err(_) => return Ok(0),
+            }
+        }
+        let count = buf.len().min(self.pending.len());
+        for (slot, byte) in buf.iter_mut().zip(self.pending.drain(..count)) {
+            *slot = byte;
+        }
+        Ok(count)
+    }
+}
+
+/// Reads `typed` a line at a time on a thread of its own, sending the lines
+/// starting with `:` to `events` as commands and leaving the others to the
+/// program, in the input returned.
+pub fn read_typed(mut typed: impl BufRead + Send + 'static, events: mpsc::Sender<Event>) -> TypedInput {
+    let (sender, lines) = mpsc::channel();
+    thread::spawn(move || loop {
+        let mut line = vec![];
+        if !matches!(typed.read_until(b'\n', &mut line), Ok(read) if read > 0) {
+            break;
+        }
+        let sent = match line.starts_with(b":") {
+            true => events.send(Event::Command(String::from_utf8_lossy(&line).trim_end().to_owned())).is_ok(),
+            false => sender.send(line).is_ok(),
+        };
+        if !sent {
+            break;
+        }
+    });
+    TypedInput {
+        lines,
+        pending: VecDeque::new(),
+    }
+}
+
+/// Reads OSC messages sent to `socket` on a thread of its own, sending them to
+/// `events` as the commands they're named after: `/midilang/pause` is `:pause`,
+/// and `/midilang/save` with a string is `:save` with the string as its file.
+/// Messages with 0 as their first argument are left out, controllers send
+/// those when a button is let go.
+pub fn read_osc(socket: UdpSocket, events: mpsc::Sender<Event>) {
+    thread::spawn(move || {
+        let mut packet = [0; 1536];
+        loop {
+            let read = match socket.recv(&mut packet) {
+                Ok(read) => read,
+                Err(err) => {
+                    warn!("Stopped reading OSC messages, {}", err);
+                    break;
+                }
+            };
+            let Some(command) = osc_command(&packet[..read]) else {
+                debug!("Ignoring an OSC message to {:?}", osc_string(&packet[..read]).map(|(address, _)| address));
+                continue;
+            };
+            if events.send(Event::Command(command)).is_err() {
+                break;
+            }
+        }
+    });
+}
+
+// the command an OSC message is for, `None` for messages to other addresses,
+// bundles and buttons let go
+fn osc_command(packet: &[u8]) -> Option<String> {
+    let (address, rest) = osc_string(packet)?;
+    let mut command = format!(":{}", address.strip_prefix("/midilang/")?);
+    // messages from before OSC 1.0 have no type tags
+    let (tags, args) = osc_string(rest).unwrap_or((",", &[]));
+    match tags.strip_prefix(',')?.chars().next() {
+        Some('s') => {
+            command.push(' ');
+            command.push_str(osc_string(args)?.0);
+        }
+        // an int 0 and a float 0.0 are both four zero bytes
+        Some('i' | 'f') if args.get(..4)? == [0; 4] => return None,
+        _ => {}
+    }
+    Some(command)
+}
+
+// the string at the start of `bytes`, and what's after the zeros padding it to
+// four bytes
+fn osc_string(bytes: &[u8]) -> Option<(&str, &[u8])> {
+    let end = bytes.iter().position(|&byte| byte == 0)?;
+    let string = std::str::from_utf8(&bytes[..end]).ok()?;
+    Some((string, bytes.get((end / 4 + 1) * 4..).unwrap_or_default()))
+}
+
+/// Runs chords as they're played on a MIDI input, on an interpreter that keeps
+/// its tape from one chord to the next.
+///
+/// Notes pressed together make a chord, read once all of them are released,
+/// like the chords of a file. Instructions run as soon as their chord is read,
+/// except in loops, which are held until the chord closing the outermost one
+/// since their body has to be known to run them.
+pub struct Live {
+    interp: Interpreter,
+    /// Notes pressed since the last chord was read
+    held: Vec<u8>,
+    /// Notes still down
+    down: usize,
+    /// The loops played so far, `None` outside of loops
+    loops: Option<MidiASTBuilder>,
+    depth: usize,
+    /// Notes of the chords of every instruction run so far, loaded ones too
+    played: Vec<Vec<u8>>,
+    /// Notes of the chords of the loops that aren't closed yet
+    held_chords: Vec<Vec<u8>>,
+    /// What `:undo` takes back, the last step last
+    history: Vec<Step>,
+    /// What `:redo` plays again, the step undone last last
+    undone: Vec<Step>,
+    /// The tape `:reset` goes back to
+    start: TapeSnapshot,
+    paused: bool,
+    /// How chords played and files loaded are read
+    parse: ParseOptions,
+}
+
+// something played, that `:undo` takes back
+enum Step {
+    // instructions that ran with their chords, and the tape to swap back to,
+    // from before they ran or, once undone, after
+    Ran { tape: TapeSnapshot, chords: Vec<Vec<u8>> },
+    // a chord held in a loop that isn't closed yet
+    Held(Vec<u8>),
+}
+
+impl Live {
+    pub fn new(interp: Interpreter) -> Self {
+        Live {
+            start: interp.snapshot(),
+            paused: false,
+            interp,
+            held: vec![],
+            down: 0,
+            loops: None,
+            depth: 0,
+            played: vec![],
+            held_chords: vec![],
+            history: vec![],
+            undone: vec![],
+            parse: ParseOptions::default(),
+        }
+    }
+
+    /// Reads chords and loaded files with `options`, plain midilang otherwise.
+    pub fn set_parse_options(&mut self, options: ParseOptions) {
+        self.parse = options;
+    }
+
+    pub fn interpreter(&self) -> &Interpreter {
+        &self.interp
+    }
+
+    /// Loops that were opened and aren't closed yet.
+    pub fn depth(&self) -> usize {
+        self.depth
+    }
+
+    /// Runs the command `line` typed in the session, `:save FILE`, `:load FILE`,
+    /// `:undo`, `:redo`, `:pause`, `:resume`, `:reset` or `:dump`.
+    pub fn command(&mut self, line: &str, input: &mut impl Read, output: &mut impl Write) -> MidilangResult<()> {
+        let line = line.trim();
+        let (name, arg) = line.split_once(char::is_whitespace).unwrap_or((line, ""));
+        match (name, arg.trim()) {
+            (":save", path) if !path.is_empty() => self.save(Path::new(path)),
+            (":load", path) if !path.is_empty() => self.load(Path::new(path), input, output),
+            (":undo", "") => self.undo(),
+            (":redo", "") => self.redo(),
+            (":pause", "") => self.pause(),
+            (":resume", "") => self.resume(),
+            (":reset", "") => self.reset(),
+            (":dump", "") => self.dump(),
+            _ => Err(MidilangError::Other(format!(
+                "Unknown command {:?}, there's :save FILE, :load FILE, :undo, :redo, :pause, :resume, :reset and :dump",
+                line
+            ))),
+        }
+    }
+
+    /// Takes back the last instruction run, the last loop closed or file loaded
+    /// as a whole, or the last chord held in a loop that's still open. What it
+    /// wrote to the output stays written.
+    pub fn undo(&mut self) -> MidilangResult<()> {
+        let step = self.history.pop().ok_or_else(|| MidilangError::Other("Nothing to undo".to_owned()))?;
+        let step = match step {
+            Step::Ran { tape, chords } => {
+                let ran = self.interp.snapshot();
+                self.interp.restore(&tape);
+                self.played.truncate(self.played.len() - chords.len());
+                info!("Undid {} chords", chords.len());
+                Step::Ran { tape: ran, chords }
+            }
+            Step::Held(notes) => {
+                self.held_chords.pop();
+                self.rehold();
+                info!("Undid a chord held in a loop, {} open", self.depth);
+                Step::Held(notes)
+            }
+        };
+        self.undone.push(step);
+        Ok(())
+    }
+
+    /// Plays the step undone last again, leaving the output alone like `undo`.
+    pub fn redo(&mut self) -> MidilangResult<()> {
+        let step = self.undone.pop().ok_or_else(|| MidilangError::Other("Nothing to redo".to_owned()))?;
+        let step = match step {
+            Step::Ran { tape, chords } => {
+                let undone = self.interp.snapshot();
+                self.interp.restore(&tape);
+                self.played.extend_from_slice(&chords);
+                info!("Redid {} chords", chords.len());
+                Step::Ran { tape: undone, chords }
+            }
+            Step::Held(notes) => {
+                self.hold(read_chord_in(notes.clone(), &self.parse).expect("held a chord that isn't an instruction"), notes.clone());
+                info!("Redid a chord held in a loop, {} open", self.depth);
+                Step::Held(notes)
+            }
+        };
+        self.history.push(step);
+        Ok(())
+    }
+
+    /// Stops taking chords until `resume`, dropping the notes of the one being
+    /// played.
+    pub fn pause(&mut self) -> MidilangResult<()> {
+        self.paused = true;
+        self.held.clear();
+        self.down = 0;
+        info!("Paused, chords are ignored until :resume");
+        Ok(())
+    }
+
+    /// Takes chords again after `pause`.
+    pub fn resume(&mut self) -> MidilangResult<()> {
+        self.paused = false;
+        info!("Resumed");
+        Ok(())
+    }
+
+    /// Starts the session over, on the tape it started with and with nothing
+    /// played, held in loops or to undo.
+    pub fn reset(&mut self) -> MidilangResult<()> {
+        self.interp.restore(&self.start);
+        self.loops = None;
+        self.depth = 0;
+        self.played.clear();
+        self.held_chords.clear();
+        self.history.clear();
+        self.undone.clear();
+        info!("Reset the tape");
+        Ok(())
+    }
+
+    /// Prints the cells around the pointer to stderr like the dump tape chord,
+    /// and logs where the session is at.
+    pub fn dump(&self) -> MidilangResult<()> {
+        eprintln!("{}", self.interp.dump_tape());
+        let paused = if self.paused { ", paused" } else { "" };
+        info!("{} chords run, {} loops open{}", self.played.len(), self.depth, paused);
+        Ok(())
+    }
+
+    /// Writes the chords of every instruction run so far to `path` as a MIDI
+    /// file, which runs the same when it's loaded or compiled. Loops that aren't
+    /// closed yet are left out, they haven't run.
+    pub fn save(&self, path: &Path) -> MidilangResult<()> {
+        let mut bytes = vec![];
+        formatter::song(&self.played).write_std(&mut bytes)?;
+        fs::write(path, bytes)?;
+        info!("Saved {} chords to {}", self.played.len(), path.display());
+        Ok(())
+    }
+
+    /// Runs the program in the MIDI file at `path` on the tape as it is, like
+    /// its chords were played. The cells of its data track are left out, and
+    /// so are files loaded before the loops being played are closed.
+    pub fn load(&mut self, path: &Path, input: &mut impl Read, output: &mut impl Write) -> MidilangResult<()> {
+        if self.depth > 0 {
+            return Err(MidilangError::Other("Can't load a file inside a loop, close it first".to_owned()));
+        }
+        let file_path = path.to_string_lossy();
+        let bytes = read_source(&file_path)?;
+        let (program, source_map, data) = parse_source(&file_path, &bytes, &self.parse)?;
+        if !data.is_empty() {
+            warn!("Leaving out the data track of {}, the tape is already set", path.display());
+        }
+        info!("Running {}", path.display());
+        let chords = formatter::chords(&program);
+        self.run(&program, chords, input, output).map_err(|err| run_error(err, &source_map))
+    }
+
+    /// Takes in the MIDI message `bytes`, running the chord it completes with
+    /// `input` and `output` as the program's IO. Messages other than notes are
+    /// ignored, and so are chords that don't read as instructions, a wrong
+    /// chord played live shouldn't end the session, and every message while
+    /// the session is paused.
+    ///
+    /// Note ons with a velocity of 0 release their note, like most keyboards
+    /// send them.
+    pub fn message(&mut self, bytes: &[u8], input: &mut impl Read, output: &mut impl Write) -> InterpResult<()> {
+        let Ok(LiveEvent::Midi { message, .. }) = LiveEvent::parse(bytes) else {
+            return Ok(());
+        };
+        if self.paused {
+            return Ok(());
+        }
+        match message {
+            MidiMessage::NoteOn { key, vel } if vel.as_int() > 0 => {
+                debug!("{} pressed: {} -> {}", key, self.down, self.down + 1);
+                self.held.push(key.as_int());
+                self.down += 1;
+                Ok(())
+            }
+            // notes held down from before midilang was listening have no chord
+            MidiMessage::NoteOn { key, .. } | MidiMessage::NoteOff { key, .. } if self.down > 0 => {
+                debug!("{} released: {} -> {}", key, self.down, self.down - 1);
+                self.down -= 1;
+                if self.down > 0 {
+                    return Ok(());
+                }
+                let mut notes = std::mem::take(&mut self.held);
+                notes.sort_unstable();
+                self.chord(notes, input, output)
+            }
+            _ => Ok(()),
+        }
+    }
+
+    // reads the sorted `notes` as an instruction and runs it, or holds on to it
+    // in a loop
+    fn chord(&mut self, notes: Vec<u8>, input: &mut impl Read, output: &mut impl Write) -> InterpResult<()> {
+        let inst = match read_chord_in(notes.clone(), &self.parse) {
+            Ok(inst) => inst,
+            Err(err) => {
+                warn!("Skipping chord, {}", err);
+                return Ok(());
+            }
+        };
+        if matches!(inst.instruction, MidiInstructionKind::Call { .. }) {
+            // there are no text events to name the function, only notes
+            warn!("Skipping chord, calls can't be played live");
+            return Ok(());
+        }
+        let is_loop = matches!(inst.instruction, MidiInstructionKind::Loop { .. });
+        match (is_loop, inst.position.is_some()) {
+            (true, false) if self.depth == 0 => {
+                warn!("Skipping chord, there is no loop for it to close");
+                return Ok(());
+            }
+            (false, _) if self.depth == 0 => {
+                debug!("Running {}", inst.instruction.describe());
+                return self.run(&[inst], vec![notes], input, output);
+            }
+            _ => {}
+        }
+        self.undone.clear();
+        self.hold(inst, notes.clone());
+        if self.depth > 0 {
+            info!("Holding on to the loop until it's closed, {} open", self.depth);
+            self.history.push(Step::Held(notes));
+            return Ok(());
+        }
+        // the loop is undone as a whole from now on
+        while let Some(Step::Held(_)) = self.history.last() {
+            self.history.pop();
+        }
+        let program = self.loops.take().unwrap_or_default().into_mast().expect("loops were left open");
+        let chords = std::mem::take(&mut self.held_chords);
+        info!("Running the loop");
+        self.run(&program, chords, input, output)
+    }
+
+    // adds `inst` played as `notes` to the loops held on to
+    fn hold(&mut self, inst: MidiInstruction, notes: Vec<u8>) {
+        match inst.instruction {
+            MidiInstructionKind::Loop { .. } if inst.position.is_some() => self.depth += 1,
+            MidiInstructionKind::Loop { .. } => self.depth -= 1,
+            _ => {}
+        }
+        let loops = self.loops.get_or_insert_with(MidiASTBuilder::new);
+        // the depth keeps closing chords from dangling
+        loops.push(inst).expect("closed a loop that wasn't open");
+        self.held_chords.push(notes);
+    }
+
+    // holds on to the chords of the loops held again from the start, after one
+    // of them was taken back
+    fn rehold(&mut self) {
+        self.loops = None;
+        self.depth = 0;
+        for notes in std::mem::take(&mut self.held_chords) {
+            self.hold(read_chord_in(notes.clone(), &self.parse).expect("held a chord that isn't an instruction"), notes);
+        }
+    }
+
+    // runs `program` played as `chords`, as a step that can be undone
+    fn run(&mut self, program: &[MidiInstruction], chords: Vec<Vec<u8>>, input: &mut impl Read, output: &mut impl Write) -> InterpResult<()> {
+        let tape = self.interp.snapshot();
+        self.played.extend_from_slice(&chords);
+        self.history.push(Step::Ran { tape, chords });
+        self.undone.clear();
+        self.interp.run(program, input, output)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+    use std::io;
+    use std::num::Wrapping;
+
+    // plays `keys` together and releases them, running the chord
+    fn play(live: &mut Live, keys: &[u8], output: &mut Vec<u8>) {
+        for &key in keys {
+            live.message(&[0x90, key, 100], &mut io::empty(), output).unwrap();
+        }
+        for &key in keys {
+            live.message(&[0x80, key, 0], &mut io::empty(), output).unwrap();
+        }
+    }
+
+    #[test]
+    fn runs_chords_as_they_are_played() {
+        let mut live = Live::new(Interpreter::new());
+        let mut output = vec![];
+        // +++
+        for _ in 0..3 {
+            play(&mut live, &[69], &mut output);
+        }
+        assert_eq!(live.interpreter().tape(), [Wrapping(3)]);
+        // [>++<-] is held until it's closed
+        for keys in [&[67][..], &[64], &[69, 72, 74], &[62], &[65]] {
+            play(&mut live, keys, &mut output);
+        }
+        assert_eq!((live.depth(), live.interpreter().tape()), (1, &[Wrapping(3)][..]));
+        play(&mut live, &[60], &mut output);
+        assert_eq!((live.depth(), live.interpreter().tape()), (0, &[Wrapping(0), Wrapping(6)][..]));
+        // >.
+        play(&mut live, &[64], &mut output);
+        play(&mut live, &[71, 72, 74], &mut output);
+        assert_eq!(output, [6]);
+    }
+
+    #[test]
+    fn skips_what_is_not_a_chord() {
+        let mut live = Live::new(Interpreter::new());
+        let mut output = vec![];
+        // released before it was pressed, a control change, C#, a ] and a call
+        live.message(&[0x80, 69, 0], &mut io::empty(), &mut output).unwrap();
+        live.message(&[0xb0, 7, 100], &mut io::empty(), &mut output).unwrap();
+        play(&mut live, &[61], &mut output);
+        play(&mut live, &[60], &mut output);
+        play(&mut live, &[60, 63, 66, 69], &mut output);
+        assert_eq!((live.interpreter().tape(), live.interpreter().steps()), (&[Wrapping(0)][..], 0));
+        // a note on without velocity releases the note
+        live.message(&[0x90, 69, 100], &mut io::empty(), &mut output).unwrap();
+        live.message(&[0x90, 69, 0], &mut io::empty(), &mut output).unwrap();
+        assert_eq!(live.interpreter().tape(), [Wrapping(1)]);
+    }
+
+    #[test]
+    fn saves_and_loads_what_was_played() {
+        let mut live = Live::new(Interpreter::new());
+        let mut output = vec![];
+        // +++[>++<-]>
+        for _ in 0..3 {
+            play(&mut live, &[69], &mut output);
+        }
+        for keys in [&[67][..], &[64], &[69, 72, 74], &[62], &[65], &[60], &[64]] {
+            play(&mut live, keys, &mut output);
+        }
+        // an unclosed [ isn't saved and can't be loaded into
+        play(&mut live, &[67], &mut output);
+        let path = crate::compiler::temp_path("mid");
+        live.command(&format!(":save {}", path.display()), &mut io::empty(), &mut output).unwrap();
+        assert!(live.command(&format!(":load {}", path.display()), &mut io::empty(), &mut output).is_err());
+
+        let mut loaded = Live::new(Interpreter::new());
+        loaded.command(&format!(":load {}", path.display()), &mut io::empty(), &mut output).unwrap();
+        assert_eq!(loaded.interpreter().tape(), [Wrapping(0), Wrapping(6)]);
+        // loading again runs it on from the 6, and saves it twice
+        loaded.load(&path, &mut io::empty(), &mut output).unwrap();
+        assert_eq!(loaded.interpreter().tape(), [Wrapping(0), Wrapping(0), Wrapping(18)]);
+        loaded.save(&path).unwrap();
+        let mut twice = Live::new(Interpreter::new());
+        twice.load(&path, &mut io::empty(), &mut output).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(twice.interpreter().tape(), loaded.interpreter().tape());
+    }
+
+    #[test]
+    fn rejects_unknown_commands() {
+        let mut live = Live::new(Interpreter::new());
+        for line in [":quit", ":save", "save out.mid"] {
+            assert!(live.command(line, &mut io::empty(), &mut vec![]).is_err(), "{}", line);
+        }
+    }
+
+    #[test]
+    fn splits_commands_from_typed_input() {
+        let (sender, events) = mpsc::channel();
+        let typed = io::Cursor::new(b"ab\n:save out.mid\nc".to_vec());
+        let mut input = read_typed(typed, sender);
+        let mut read = vec![];
+        input.read_to_end(&mut read).unwrap();
+        assert_eq!(read, b"ab\nc");
+        assert_eq!(events.iter().collect::<Vec<_>>(), [Event::Command(":save out.mid".to_owned())]);
+    }
+
+    #[test]
+    fn undoes_and_redoes_steps() {
+        let mut live = Live::new(Interpreter::new());
+        let mut output = vec![];
+        let command = |live: &mut Live, line: &str| live.command(line, &mut io::empty(), &mut vec![]);
+        assert!(command(&mut live, ":undo").is_err());
+        // ++[>+ then a wrong chord in the loop, undone and played on as <-]
+        for keys in [&[69][..], &[69], &[67], &[64], &[69], &[65]] {
+            play(&mut live, keys, &mut output);
+        }
+        command(&mut live, ":undo").unwrap();
+        assert_eq!((live.depth(), live.interpreter().tape()), (1, &[Wrapping(2)][..]));
+        for keys in [&[62][..], &[65], &[60]] {
+            play(&mut live, keys, &mut output);
+        }
+        assert_eq!(live.interpreter().tape(), [Wrapping(0), Wrapping(2)]);
+        // the loop is taken back as a whole, and the ++ one at a time
+        command(&mut live, ":undo").unwrap();
+        assert_eq!((live.depth(), live.interpreter().tape()), (0, &[Wrapping(2)][..]));
+        command(&mut live, ":undo").unwrap();
+        assert_eq!(live.interpreter().tape(), [Wrapping(1)]);
+        command(&mut live, ":redo").unwrap();
+        command(&mut live, ":redo").unwrap();
+        assert_eq!(live.interpreter().tape(), [Wrapping(0), Wrapping(2)]);
+        assert!(command(&mut live, ":redo").is_err());
+        // playing after an undo drops what was undone
+        command(&mut live, ":undo").unwrap();
+        play(&mut live, &[65], &mut output);
+        assert!(command(&mut live, ":redo").is_err());
+        assert_eq!(live.interpreter().tape(), [Wrapping(1)]);
+        assert_eq!(live.played.len(), 3);
+    }
+
+    #[test]
+    fn undoes_chords_held_in_open_loops() {
+        let mut live = Live::new(Interpreter::new());
+        let mut output = vec![];
+        // [[ and + undone back to [, then redone and closed as [[+]]
+        for keys in [&[67][..], &[67], &[69]] {
+            play(&mut live, keys, &mut output);
+        }
+        live.undo().unwrap();
+        live.undo().unwrap();
+        assert_eq!(live.depth(), 1);
+        live.redo().unwrap();
+        live.redo().unwrap();
+        assert_eq!((live.depth(), live.held_chords.len()), (2, 3));
+        play(&mut live, &[60], &mut output);
+        play(&mut live, &[60], &mut output);
+        assert_eq!((live.depth(), live.played.len()), (0, 5));
+        live.undo().unwrap();
+        assert!(live.played.is_empty());
+    }
+
+    // an OSC message to `address` with `tags` and `args` already encoded
+    fn osc(address: &str, tags: &str, args: &[u8]) -> Vec<u8> {
+        let mut packet = vec![];
+        for string in [address, tags] {
+            packet.extend(string.bytes());
+            packet.resize((packet.len() / 4 + 1) * 4, 0);
+        }
+        packet.extend(args);
+        packet
+    }
+
+    #[test]
+    fn reads_osc_commands() {
+        assert_eq!(osc_command(&osc("/midilang/pause", ",", &[])).as_deref(), Some(":pause"));
+        assert_eq!(osc_command(&osc("/midilang/save", ",s", b"set.mid\0")).as_deref(), Some(":save set.mid"));
+        // a button pressed and let go
+        assert_eq!(osc_command(&osc("/midilang/reset", ",f", &1f32.to_be_bytes())).as_deref(), Some(":reset"));
+        assert_eq!(osc_command(&osc("/midilang/reset", ",f", &0f32.to_be_bytes())), None);
+        assert_eq!(osc_command(&osc("/midilang/dump", ",i", &[0; 4])), None);
+        // no type tags, another address and a bundle
+        assert_eq!(osc_command(b"/midilang/dump\0\0").as_deref(), Some(":dump"));
+        assert_eq!(osc_command(&osc("/mixer/fader", ",", &[])), None);
+        assert_eq!(osc_command(&osc("#bundle", "", &[0; 8])), None);
+
+        let socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let address = socket.local_addr().unwrap();
+        let (sender, events) = mpsc::channel();
+        read_osc(socket, sender);
+        let controller = UdpSocket::bind("127.0.0.1:0").unwrap();
+        controller.send_to(&osc("/midilang/resume", ",", &[]), address).unwrap();
+        assert_eq!(events.recv().unwrap(), Event::Command(":resume".to_owned()));
+    }
+
+    #[test]
+    fn pauses_and_resets() {
+        let mut live = Live::new(Interpreter::new());
+        let mut output = vec![];
+        let command = |live: &mut Live, line: &str| live.command(line, &mut io::empty(), &mut vec![]).unwrap();
+        // + then [ and a note still down when it's paused
+        play(&mut live, &[69], &mut output);
+        play(&mut live, &[67], &mut output);
+        live.message(&[0x90, 69, 100], &mut io::empty(), &mut output).unwrap();
+        command(&mut live, ":pause");
+        play(&mut live, &[69], &mut output);
+        live.message(&[0x80, 69, 0], &mut io::empty(), &mut output).unwrap();
+        command(&mut live, ":resume");
+        assert_eq!((live.interpreter().tape(), live.depth(), live.held_chords.len()), (&[Wrapping(1)][..], 1, 1));
+        command(&mut live, ":dump");
+        command(&mut live, ":reset");
+        assert_eq!((live.interpreter().tape(), live.depth(), live.played.len()), (&[Wrapping(0)][..], 0, 0));
+        assert!(live.undo().is_err());
+        play(&mut live, &[69], &mut output);
+        assert_eq!(live.interpreter().tape(), [Wrapping(1)]);
+    }
+}
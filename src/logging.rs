@@ -0,0 +1,85 @@
+use std::fmt::Display;
+use std::time::Instant;
+
+use log::kv::{self, Key, Value, VisitSource};
+use log::Record;
+
+use crate::failure::json_string;
+
+/// How log records are written.
+#[derive(Debug, Default, PartialEq, Eq, Clone, Copy, clap::ValueEnum)]
+pub enum LogFormat {
+    /// env_logger's lines for people
+    #[default]
+    Text,
+    /// One JSON object per record, with its key-values as fields
+    Json,
+}
+
+/// Milliseconds since `started`, for the `duration_ms` of a phase's record.
+pub fn millis_since(started: Instant) -> f64 {
+    started.elapsed().as_secs_f64() * 1000.0
+}
+
+/// `record` as a single line JSON object. Key-values like `phase`, `position`
+/// and `duration_ms` become fields next to the message, numbers and booleans
+/// stay numbers and booleans and everything else becomes a string.
+pub fn record_json(record: &Record, timestamp: impl Display) -> String {
+    let mut fields = vec![
+        format!("\"time\":{}", json_string(&timestamp.to_string())),
+        format!("\"level\":\"{}\"", record.level()),
+        format!("\"target\":{}", json_string(record.target())),
+        format!("\"message\":{}", json_string(&record.args().to_string())),
+    ];
+    let mut visitor = JsonFields(&mut fields);
+    // the visitor never fails
+    let _ = record.key_values().visit(&mut visitor);
+    format!("{{{}}}", fields.join(","))
+}
+
+struct JsonFields<'a>(&'a mut Vec<String>);
+
+impl<'kvs> VisitSource<'kvs> for JsonFields<'_> {
+    fn visit_pair(&mut self, key: Key<'kvs>, value: Value<'kvs>) -> Result<(), kv::Error> {
+        let value = if let Some(number) = value.to_i64() {
+            number.to_string()
+        } else if let Some(number) = value.to_u64() {
+            number.to_string()
+        } else if let Some(number) = value.to_f64().filter(|number| number.is_finite()) {
+            number.to_string()
+        } else if let Some(flag) = value.to_bool() {
+            flag.to_string()
+        } else {
+            json_string(&value.to_string())
+        };
+        self.0.push(format!("{}:{}", json_string(key.as_str()), value));
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+    use log::Level;
+
+    #[test]
+    fn writes_key_values_as_fields() {
+        let kvs: [(&str, Value); 4] = [
+            ("phase", Value::from("parse")),
+            ("instructions", Value::from(12_u64)),
+            ("duration_ms", Value::from(1.5_f64)),
+            ("position", Value::from("3:2")),
+        ];
+        let record = Record::builder()
+            .level(Level::Info)
+            .target("midilang")
+            .args(format_args!("Parsed \"song\""))
+            .key_values(&kvs)
+            .build();
+        assert_eq!(
+            record_json(&record, "2024-01-01T00:00:00Z"),
+            r#"{"time":"2024-01-01T00:00:00Z","level":"INFO","target":"midilang","message":"Parsed \"song\"","phase":"parse","instructions":12,"duration_ms":1.5,"position":"3:2"}"#
+        );
+    }
+}
@@ -0,0 +1,408 @@
+use std::collections::HashMap;
+use std::io::{BufRead, Write};
+
+use log::{debug, info, warn};
+use serde_json::{json, Value};
+
+use crate::check::{self, Diagnostic, Severity};
+use crate::error::{MidilangError, MidilangResult};
+use crate::formatter;
+use crate::parser::{self, ParseOptions};
+use crate::sysex::Includes;
+
+/// JSON-RPC error codes the server answers with.
+const PARSE_ERROR: i64 = -32700;
+const METHOD_NOT_FOUND: i64 = -32601;
+
+// how the text of a document is read
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Payload {
+    // a standard MIDI file, base64 encoded
+    Smf,
+    // a chord a line, see `note_diagnostics`
+    Notes,
+}
+
+#[derive(Default)]
+struct Server {
+    /// Open documents by URI
+    documents: HashMap<String, Payload>,
+    /// How documents are read, the options the server was started with
+    parse: ParseOptions,
+    shut_down: bool,
+}
+
+/// Speaks just enough of the language server protocol on `input` and `output`
+/// for editor plugins and DAW scripts to get diagnostics of the programs they
+/// have open: messages framed with `Content-Length` headers, `initialize`,
+/// `textDocument/didOpen`, `didChange` with the whole text and `didClose`,
+/// answered with `textDocument/publishDiagnostics`, and `shutdown` and `exit`.
+///
+/// Documents with the `midi` language or a `.mid` URI are MIDI files encoded as
+/// base64, their diagnostics ranging over the bar as a line and the beat as a
+/// column. Other documents are note text, a chord a line of notes like `C4`,
+/// `F♯3`, `Bb2` or `60` and `;` starting a comment. Every diagnostic has its
+/// tick, bar, beat and notes in its `data`.
+///
+/// Documents are read with `parse`, the dialect and include directories of the
+/// command line.
+pub fn serve(mut input: impl BufRead, mut output: impl Write, parse: &ParseOptions) -> MidilangResult<i32> {
+    let mut server = Server { parse: parse.clone(), ..Server::default() };
+    info!("Listening on stdin");
+    while let Some(body) = read_message(&mut input)? {
+        let message: Value = match serde_json::from_slice(&body) {
+            Ok(message) => message,
+            Err(err) => {
+                write_message(&mut output, &error_response(Value::Null, PARSE_ERROR, &format!("Not JSON: {}", err)))?;
+                continue;
+            }
+        };
+        let method = message["method"].as_str().unwrap_or_default();
+        debug!("Message: {}", method);
+        if method == "exit" {
+            break;
+        }
+        for reply in server.handle(method, &message) {
+            write_message(&mut output, &reply)?;
+        }
+    }
+    // exiting without being shut down first is an error, like for any server
+    Ok(if server.shut_down { 0 } else { 1 })
+}
+
+impl Server {
+    // what to send back for `message`, responses and notifications
+    fn handle(&mut self, method: &str, message: &Value) -> Vec<Value> {
+        let params = &message["params"];
+        let document = &params["textDocument"];
+        let uri = document["uri"].as_str().unwrap_or_default().to_owned();
+        match (method, message.get("id").cloned()) {
+            ("initialize", Some(id)) => {
+                let capabilities = json!({
+                    // every change comes with the whole text
+                    "capabilities": { "textDocumentSync": 1 },
+                    "serverInfo": { "name": "midilang", "version": env!("CARGO_PKG_VERSION") },
+                });
+                vec![response(id, capabilities)]
+            }
+            ("shutdown", Some(id)) => {
+                self.shut_down = true;
+                vec![response(id, Value::Null)]
+            }
+            ("textDocument/didOpen", None) => {
+                let midi = document["languageId"] == "midi" || uri.ends_with(".mid") || uri.ends_with(".midi");
+                let payload = if midi { Payload::Smf } else { Payload::Notes };
+                self.documents.insert(uri.clone(), payload);
+                vec![publish(&uri, payload, &self.parse, document["text"].as_str().unwrap_or_default())]
+            }
+            ("textDocument/didChange", None) => {
+                let Some(&payload) = self.documents.get(&uri) else {
+                    warn!("{} changed without being opened", uri);
+                    return vec![];
+                };
+                match params["contentChanges"].as_array().and_then(|changes| changes.last()) {
+                    Some(change) => vec![publish(&uri, payload, &self.parse, change["text"].as_str().unwrap_or_default())],
+                    None => vec![],
+                }
+            }
+            ("textDocument/didClose", None) => {
+                self.documents.remove(&uri);
+                vec![notification("textDocument/publishDiagnostics", json!({ "uri": uri, "diagnostics": [] }))]
+            }
+            (method, Some(id)) => vec![error_response(id, METHOD_NOT_FOUND, &format!("midilang doesn't answer {}", method))],
+            // notifications there's nothing to do for, like `initialized`
+            _ => vec![],
+        }
+    }
+}
+
+// the diagnostics of the document at `uri`, read as `payload` with `parse`
+fn publish(uri: &str, payload: Payload, parse: &ParseOptions, text: &str) -> Value {
+    let diagnostics = match payload {
+        Payload::Smf => smf_diagnostics(text, parse),
+        Payload::Notes => note_diagnostics(text, parse),
+    };
+    debug!("{} diagnostics for {}", diagnostics.len(), uri);
+    notification("textDocument/publishDiagnostics", json!({ "uri": uri, "diagnostics": diagnostics }))
+}
+
+// diagnostics of a base64 encoded MIDI file, at the bar as a line and the beat
+// as a column
+fn smf_diagnostics(text: &str, parse: &ParseOptions) -> Vec<Value> {
+    let Some(bytes) = decode_base64(text) else {
+        let diag = Diagnostic::file("not-base64", Severity::Error, "The document isn't a MIDI file encoded as base64");
+        return vec![to_lsp(&diag, range(0, 0, 0))];
+    };
+    // documents have no file of their own to include next to
+    let parsed = match parser::parse_all_bytes_in(&bytes, &Includes::default(), parse) {
+        Ok(parsed) => parsed,
+        Err(err) => return vec![to_lsp(&Diagnostic::file("not-midi", Severity::Error, format!("Not a MIDI file: {}", err)), range(0, 0, 0))],
+    };
+    check::check_parsed(&parsed, true)
+        .diagnostics
+        .iter()
+        .map(|diag| match diag.bar_beat {
+            Some(bar_beat) => to_lsp(diag, range(bar_beat.bar - 1, bar_beat.beat - 1, bar_beat.beat)),
+            None => to_lsp(diag, range(0, 0, 0)),
+        })
+        .collect()
+}
+
+// diagnostics of note text, a chord a line, ranging over the words of the
+// chord they're about. The chords are checked as the song `fmt` would write,
+// which is where their bar and beat are from
+fn note_diagnostics(text: &str, parse: &ParseOptions) -> Vec<Value> {
+    let mut found = vec![];
+    let mut chords = vec![];
+    // line, first and last column of every chord
+    let mut spans = vec![];
+    for (line, code) in text.lines().enumerate() {
+        let code = code.split(';').next().unwrap_or_default();
+        let mut notes = vec![];
+        let mut span: Option<(u64, u64)> = None;
+        for word in code.split_whitespace() {
+            let start = code[..word.as_ptr() as usize - code.as_ptr() as usize].encode_utf16().count() as u64;
+            let end = start + word.encode_utf16().count() as u64;
+            let Some(key) = parse_note(word) else {
+                let diag = Diagnostic::file("not-a-note", Severity::Error, format!("`{}` isn't a note like C4, F♯3 or 60", word));
+                found.push(to_lsp(&diag, range(line as u64, start, end)));
+                continue;
+            };
+            notes.push(key);
+            span = Some((span.map_or(start, |span| span.0), end));
+        }
+        if let Some((start, end)) = span {
+            notes.sort_unstable();
+            notes.dedup();
+            chords.push(notes);
+            spans.push(range(line as u64, start, end));
+        }
+    }
+    let parsed = parser::parse_all_in(formatter::song(&chords), &Includes::default(), parse);
+    for diag in check::check_parsed(&parsed, true).diagnostics {
+        let range = diag.chord.and_then(|chord| spans.get(chord)).cloned().unwrap_or_else(|| range(0, 0, 0));
+        found.push(to_lsp(&diag, range));
+    }
+    found
+}
+
+// the key of a note named like `C4`, `F♯3` or `Bb2`, or given as its key
+fn parse_note(word: &str) -> Option<u8> {
+    if let Ok(key) = word.parse::<u8>() {
+        return (key < 128).then_some(key);
+    }
+    let mut chars = word.chars();
+    let pitch = match chars.next()?.to_ascii_uppercase() {
+        'C' => 0,
+        'D' => 2,
+        'E' => 4,
+        'F' => 5,
+        'G' => 7,
+        'A' => 9,
+        'B' => 11,
+        _ => return None,
+    };
+    let rest = chars.as_str();
+    let (shift, octave) = match (rest.strip_prefix(['#', '♯']), rest.strip_prefix(['b', '♭'])) {
+        (Some(octave), _) => (1, octave),
+        (None, Some(octave)) => (-1, octave),
+        (None, None) => (0, rest),
+    };
+    let octave: i32 = octave.parse().ok()?;
+    u8::try_from(pitch + shift + (octave + 1) * 12).ok().filter(|&key| key < 128)
+}
+
+// decodes standard base64, padded or not, skipping whitespace
+fn decode_base64(text: &str) -> Option<Vec<u8>> {
+    let mut bytes = vec![];
+    let (mut bits, mut count) = (0u32, 0);
+    for ch in text.bytes().filter(|ch| !ch.is_ascii_whitespace()) {
+        let value = match ch {
+            b'A'..=b'Z' => ch - b'A',
+            b'a'..=b'z' => ch - b'a' + 26,
+            b'0'..=b'9' => ch - b'0' + 52,
+            b'+' => 62,
+            b'/' => 63,
+            b'=' => break,
+            _ => return None,
+        };
+        bits = bits << 6 | u32::from(value);
+        count += 6;
+        if count >= 8 {
+            count -= 8;
+            bytes.push((bits >> count) as u8);
+        }
+    }
+    Some(bytes)
+}
+
+fn to_lsp(diag: &Diagnostic, range: Value) -> Value {
+    json!({
+        "range": range,
+        "severity": match diag.severity {
+            Severity::Error => 1,
+            Severity::Warning => 2,
+        },
+        "code": diag.code,
+        "source": "midilang",
+        "message": diag.message,
+        "data": {
+            "tick": diag.tick,
+            "bar": diag.bar_beat.map(|bar_beat| bar_beat.bar),
+            "beat": diag.bar_beat.map(|bar_beat| bar_beat.beat),
+            "notes": diag.notes,
+        },
+    })
+}
+
+fn range(line: u64, start: u64, end: u64) -> Value {
+    json!({ "start": { "line": line, "character": start }, "end": { "line": line, "character": end } })
+}
+
+fn response(id: Value, result: Value) -> Value {
+    json!({ "jsonrpc": "2.0", "id": id, "result": result })
+}
+
+fn error_response(id: Value, code: i64, message: &str) -> Value {
+    json!({ "jsonrpc": "2.0", "id": id, "error": { "code": code, "message": message } })
+}
+
+fn notification(method: &str, params: Value) -> Value {
+    json!({ "jsonrpc": "2.0", "method": method, "params": params })
+}
+
+// the body of the next message, `None` once the input ends
+fn read_message(input: &mut impl BufRead) -> MidilangResult<Option<Vec<u8>>> {
+    let mut length = None;
+    loop {
+        let mut header = String::new();
+        if input.read_line(&mut header)? == 0 {
+            return Ok(None);
+        }
+        let header = header.trim_end();
+        if header.is_empty() {
+            break;
+        }
+        match header.split_once(':') {
+            Some((name, value)) if name.eq_ignore_ascii_case("content-length") => length = value.trim().parse().ok(),
+            // like Content-Type, which is always JSON in UTF-8
+            _ => {}
+        }
+    }
+    let length = length.ok_or_else(|| MidilangError::Other("Message without a Content-Length".to_owned()))?;
+    let mut body = vec![0; length];
+    input.read_exact(&mut body)?;
+    Ok(Some(body))
+}
+
+fn write_message(output: &mut impl Write, message: &Value) -> MidilangResult<()> {
+    let body = message.to_string();
+    write!(output, "Content-Length: {}\r\n\r\n{}", body.len(), body)?;
+    output.flush()?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+    use std::io::Cursor;
+
+    fn framed(messages: &[Value]) -> Cursor<Vec<u8>> {
+        let mut bytes = vec![];
+        for message in messages {
+            write_message(&mut bytes, message).unwrap();
+        }
+        Cursor::new(bytes)
+    }
+
+    // every message written to `output`
+    fn replies(output: Vec<u8>) -> Vec<Value> {
+        let mut output = Cursor::new(output);
+        std::iter::from_fn(|| read_message(&mut output).unwrap()).map(|body| serde_json::from_slice(&body).unwrap()).collect()
+    }
+
+    fn encode_base64(bytes: &[u8]) -> String {
+        const ALPHABET: &[u8] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+        let mut text = String::new();
+        for group in bytes.chunks(3) {
+            let bits = group.iter().enumerate().fold(0u32, |bits, (index, &byte)| bits | u32::from(byte) << (16 - 8 * index));
+            for index in 0..=group.len() {
+                text.push(ALPHABET[(bits >> (18 - 6 * index) & 63) as usize] as char);
+            }
+        }
+        text
+    }
+
+    #[test]
+    fn reads_notes() {
+        for (word, key) in [("C4", Some(60)), ("F♯3", Some(54)), ("Bb2", Some(46)), ("c#-1", Some(1)), ("60", Some(60))] {
+            assert_eq!(parse_note(word), key, "{}", word);
+        }
+        for word in ["H2", "128", "G♯9", "C", "C4x"] {
+            assert_eq!(parse_note(word), None, "{}", word);
+        }
+    }
+
+    #[test]
+    fn decodes_base64() {
+        assert_eq!(decode_base64("TVRoZA==").unwrap(), b"MThd");
+        assert_eq!(decode_base64("TVRo\nZA").unwrap(), b"MThd");
+        assert_eq!(decode_base64(&encode_base64(b"midilang")).unwrap(), b"midilang");
+        assert_eq!(decode_base64("TV*o"), None);
+    }
+
+    #[test]
+    fn finds_what_is_wrong_with_note_text() {
+        // [ +, a chord that isn't diatonic and a word that isn't a note
+        let found = note_diagnostics("G4 ; opens a loop\n\n  A4\nC♯4 F4\nA4 X\n", &ParseOptions::default());
+        let found: Vec<_> = found.iter().map(|diag| (diag["code"].as_str().unwrap(), diag["range"]["start"]["line"].as_u64().unwrap())).collect();
+        assert!(found.contains(&("not-a-note", 4)), "{:?}", found);
+        assert!(found.contains(&("non-diatonic", 3)), "{:?}", found);
+        assert!(found.contains(&("unclosed-loop", 0)), "{:?}", found);
+        // lints too, [+] never runs on the cell it starts on
+        assert_eq!(note_diagnostics("G4\nA4\nC4", &ParseOptions::default())[0]["code"], "dead-loop");
+        // the random chord, in the dialect that has it
+        let extensions = ParseOptions { extensions: true, ..ParseOptions::default() };
+        assert_eq!(note_diagnostics("C4 E4 G♯4", &extensions), Vec::<Value>::new());
+        assert_eq!(note_diagnostics("C4 E4 G♯4", &ParseOptions::default())[0]["code"], "dangling-loop");
+    }
+
+    #[test]
+    fn answers_editors() {
+        let song = {
+            let mut bytes = vec![];
+            crate::brainf_to_smf("+[").write_std(&mut bytes).unwrap();
+            encode_base64(&bytes)
+        };
+        let input = framed(&[
+            json!({ "jsonrpc": "2.0", "id": 1, "method": "initialize", "params": {} }),
+            json!({ "jsonrpc": "2.0", "method": "initialized", "params": {} }),
+            json!({ "jsonrpc": "2.0", "method": "textDocument/didOpen", "params": { "textDocument": { "uri": "file:///song.mid", "text": song } } }),
+            json!({ "jsonrpc": "2.0", "method": "textDocument/didOpen", "params": { "textDocument": { "uri": "file:///song.txt", "text": "A4" } } }),
+            json!({ "jsonrpc": "2.0", "method": "textDocument/didChange", "params": { "textDocument": { "uri": "file:///song.txt" }, "contentChanges": [{ "text": "A4\nC♯4" }] } }),
+            json!({ "jsonrpc": "2.0", "id": 2, "method": "textDocument/hover", "params": {} }),
+            json!({ "jsonrpc": "2.0", "method": "textDocument/didClose", "params": { "textDocument": { "uri": "file:///song.txt" } } }),
+            json!({ "jsonrpc": "2.0", "id": 3, "method": "shutdown" }),
+            json!({ "jsonrpc": "2.0", "method": "exit" }),
+        ]);
+        let mut output = vec![];
+        assert_eq!(serve(input, &mut output, &ParseOptions::default()).unwrap(), 0);
+        let answers = replies(output);
+        assert_eq!(answers.len(), 7);
+        assert_eq!(answers[0]["result"]["capabilities"]["textDocumentSync"], 1);
+        // the [ is on the second eighth note of the first bar
+        let unclosed = &answers[1]["params"]["diagnostics"][0];
+        assert_eq!((&unclosed["code"], &unclosed["range"]["start"]), (&json!("unclosed-loop"), &json!({ "line": 0, "character": 0 })));
+        assert_eq!(answers[2]["params"]["diagnostics"], json!([]));
+        assert_eq!(answers[3]["params"]["diagnostics"][0]["range"]["start"]["line"], 1);
+        assert_eq!(answers[4]["error"]["code"], METHOD_NOT_FOUND);
+        assert_eq!((&answers[5]["params"]["uri"], &answers[5]["params"]["diagnostics"]), (&json!("file:///song.txt"), &json!([])));
+        assert_eq!((&answers[6]["id"], &answers[6]["result"]), (&json!(3), &Value::Null));
+
+        // leaving without a shutdown, and a message that isn't JSON
+        let mut output = vec![];
+        assert_eq!(serve(Cursor::new(b"Content-Length: 3\r\n\r\n{{{".to_vec()), &mut output, &ParseOptions::default()).unwrap(), 1);
+        assert_eq!(replies(output)[0]["error"]["code"], PARSE_ERROR);
+    }
+}
@@ -1,6 +1,24 @@
-use clap::Parser;
+use clap::{CommandFactory, Parser, Subcommand};
+use clap_complete::Shell;
 use env_logger::{self, Builder, Target, WriteStyle};
 use log::{self, error, info, LevelFilter};
+use std::fs::File;
+use std::io::Write;
+use std::path::PathBuf;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use midilang::compose::{ComposeOptions, Weights};
+use midilang::compiler::{
+    BackendKind, CellWidth, CompileOptions, Emit, IoMode, OptLevel, Overflow, PointerOverflow, TapeMode, DEFAULT_TAPE_SIZE,
+};
+use midilang::dump::DumpFormat;
+use midilang::interpreter::{InputMode, RunOptions};
+use midilang::parser::ParseOptions;
+use midilang::logging::{self, LogFormat};
+use midilang::check::{self, LintLevels, Severity};
+use midilang::error::{MidilangError, MidilangResult};
+use midilang::failure::{ErrorFormat, FailureKind, EXIT_FAILURE};
+use midilang::OutputOptions;
 
 /// A Program to compile midi into executable code
 #[derive(Parser, Debug)]
@@ -9,49 +27,1054 @@ use log::{self, error, info, LevelFilter};
 #[clap(author = "0.1")]
 #[clap(about = "An assembly compiler for MIDI files", long_about = None)]
 struct MidilangCli {
+    #[clap(subcommand)]
+    command: Option<Command>,
+
+    /// MIDI file to compile, `-` reads it from stdin
     #[clap(short = 'm', long = "midi", value_parser, value_name = "FILE")]
     file_name: Option<String>,
 
+    /// More MIDI files to compile, each to its own output
+    #[clap(value_parser, value_name = "FILES")]
+    files: Vec<String>,
+
+    /// Compile several files at once, needs midilang built with the parallel feature
+    #[clap(long, action)]
+    parallel: bool,
+
     #[clap(long, value_parser, value_name = "BF_FILE")]
     bf: Option<String>,
 
+    /// What to write out
+    #[clap(long, value_enum, default_value_t = Emit::Exe)]
+    emit: Emit,
+
+    /// Output file, defaults to the input file with an extension matching --emit,
+    /// `-` writes to stdout
+    #[clap(short = 'o', long, value_parser, value_name = "FILE")]
+    output: Option<PathBuf>,
+
+    /// Program used to link executables
+    #[clap(long, value_parser, value_name = "CMD", default_value = "cc")]
+    linker: String,
+
+    /// Library whose functions calls can call besides the builtins, linked
+    /// into executables and loaded when running, can be given more than once
+    #[clap(long, value_parser, value_name = "LIB")]
+    link: Vec<PathBuf>,
+
+    /// Optimization level passed to LLVM
+    #[clap(short = 'O', value_parser = clap::value_parser!(u8).range(0..=3), default_value_t = 0)]
+    opt_level: u8,
+
+    /// Number of cells on the tape, or the initial number when it can grow
+    #[clap(long, value_parser, value_name = "N", default_value_t = DEFAULT_TAPE_SIZE)]
+    tape_size: u64,
+
+    /// Whether compiled programs keep a fixed tape or grow it on demand, and
+    /// whether the pointer can move left of where it starts when interpreting
+    /// too
+    #[clap(long, value_enum, default_value_t = TapeMode::Fixed)]
+    tape: TapeMode,
+
+    /// Whether programs write and read bytes, UTF-8 or decimal numbers, when
+    /// interpreting too
+    #[clap(long, value_enum, value_name = "MODE", default_value_t = IoMode::Bytes)]
+    io: IoMode,
+
+    /// File programs read instead of stdin, with `run`, `--jit` and `test`,
+    /// where it's the input of programs without one. Compiled programs open it
+    /// when they start
+    #[clap(long, value_parser, value_name = "FILE")]
+    stdin: Option<PathBuf>,
+
+    /// File programs write instead of stdout, with `run` and `--jit`, or the
+    /// TAP report of `test`. Compiled programs create it when they start
+    #[clap(long, value_parser, value_name = "FILE")]
+    stdout: Option<PathBuf>,
+
+    /// Number of bits in each cell
+    #[clap(long, value_enum, value_name = "BITS", default_value_t = CellWidth::I8)]
+    cell_size: CellWidth,
+
+    /// Look for included files in this directory too, after the one of the
+    /// file including them, can be given more than once
+    #[clap(short = 'I', long, value_parser, value_name = "DIR")]
+    include_dir: Vec<PathBuf>,
+
+    /// Start the tape out with the notes of this track, one cell per note,
+    /// instead of reading chords from it, usually 2
+    #[clap(long, value_parser, value_name = "TRACK")]
+    data_track: Option<usize>,
+
+    /// Read songs in the extensions dialect, which has an augmented triad on
+    /// the tonic set the cell to a random byte and a suspended fourth on the
+    /// tonic print the cells around the pointer to stderr
+    #[clap(long, action)]
+    extensions: bool,
+
+    /// Seed of the random bytes programs pick, the same seed picks the same
+    /// ones in every backend. Runs pick one from the clock when not given,
+    /// compiled programs when they start
+    #[clap(long, value_parser, value_name = "N")]
+    seed: Option<u64>,
+
+    /// Target triple to compile for, defaults to the host
+    #[clap(long, value_parser, value_name = "TRIPLE")]
+    target: Option<String>,
+
+    /// Code generator to compile with
+    #[clap(long, value_enum, default_value_t = BackendKind::default())]
+    backend: BackendKind,
+
+    /// Compile in memory with Cranelift and run the program instead of writing a file
+    #[cfg(feature = "cranelift")]
+    #[clap(long, action)]
+    jit: bool,
+
+    /// Print the LLVM IR (or Cranelift IR) to stderr after optimization
+    #[clap(long, action)]
+    dump_llvm: bool,
+
+    /// Print the parsed program to stderr after optimization
+    #[clap(long, action)]
+    dump_ast: bool,
+
+    /// Emit debug info for gdb and lldb, with lines of a .midimap listing
+    /// written next to the output as line numbers
+    #[clap(short = 'g', long, action)]
+    debug_info: bool,
+
+    /// Carry the source MIDI file along in a section of the output, to get back
+    /// with `midilang extract`
+    #[clap(long, action)]
+    embed_source: bool,
+
+    /// Have the compiled program play its notes as it runs, on the raw MIDI device in
+    /// $MIDILANG_MIDI_OUT (/dev/snd/midiC0D0 by default) or as terminal beeps
+    #[clap(long, action)]
+    perform: bool,
+
+    /// How long each note lasts with --perform
+    #[clap(long, value_parser, value_name = "MS", default_value_t = 150)]
+    note_length: u32,
+
+    /// How to print why compiling failed, and what `check` finds
+    #[clap(long, value_enum, value_name = "FORMAT", default_value_t = ErrorFormat::Human)]
+    error_format: ErrorFormat,
+
+    /// Stop compiled programs when the pointer leaves the tape
+    #[clap(long, action)]
+    checked: bool,
+
+    /// What cells do when they go past their maximum or below 0, when
+    /// interpreting too
+    #[clap(long, value_enum, value_name = "POLICY", default_value_t = Overflow::Wrap)]
+    cell_overflow: Overflow,
+
+    /// What the pointer does when it leaves a fixed tape, when interpreting
+    /// too. Trapping checks bounds like `--checked`, wrapping brings it back in
+    /// at the other end
+    #[clap(long, value_enum, value_name = "POLICY")]
+    pointer_overflow: Option<PointerOverflow>,
+
+    /// Only warn about moves that always take the pointer off the tape,
+    /// instead of failing to compile
+    #[clap(long, action)]
+    lenient: bool,
+
+    /// Keep read tracks and compiled programs in .midilang-cache next to the
+    /// file, and reuse them when building it again. Always on for `watch`
+    #[clap(long, action)]
+    cache: bool,
+
     #[clap(short, long, action)]
     debug: bool,
 
     #[clap(short, long, action)]
     verbose: bool,
+
+    /// How to write log records, json logs what each phase took at info level
+    #[clap(long, value_enum, value_name = "FORMAT", default_value_t = LogFormat::Text)]
+    log_format: LogFormat,
+
+    /// Write log records to this file instead of stderr, at info level
+    #[clap(long, value_parser, value_name = "FILE")]
+    log_file: Option<PathBuf>,
+}
+
+#[derive(Subcommand, Debug)]
+enum Command {
+    /// Run a program right away, JIT compiled when midilang is built with Cranelift
+    /// and on the bytecode VM otherwise, or when it's limited
+    Run {
+        #[clap(value_parser, value_name = "FILE")]
+        file: String,
+
+        /// Stop the program after executing this many instructions
+        #[clap(long, value_parser, value_name = "N")]
+        max_steps: Option<usize>,
+
+        /// Stop the program once it tries to output more than this many bytes
+        #[clap(long, value_parser, value_name = "BYTES")]
+        max_output: Option<usize>,
+
+        /// Stop the program after it ran for this long
+        #[clap(long, value_parser, value_name = "MS")]
+        time_limit: Option<u64>,
+
+        /// Play the chord of every step on a MIDI output port while the program
+        /// runs in the interpreter, needs midilang built with the play feature
+        #[clap(long)]
+        play: bool,
+
+        /// Run every instruction when its chord comes up in the song, following
+        /// its tempo map, so the program takes as long as the song does
+        #[clap(long, conflicts_with = "play")]
+        musical_time: bool,
+
+        /// Chords played per minute with --play
+        #[clap(long, value_parser = clap::value_parser!(u32).range(1..), default_value_t = 120)]
+        bpm: u32,
+
+        /// Play on the first MIDI output port with this in its name, instead of the first port
+        #[clap(long, value_parser, value_name = "NAME")]
+        port: Option<String>,
+
+        /// Read the terminal a key at a time as it's pressed, or a line at a time
+        /// edited like in a shell, on the bytecode VM. Needs midilang built with
+        /// the terminal feature
+        #[clap(long, value_enum, value_name = "MODE")]
+        input_mode: Option<InputMode>,
+    },
+
+    /// Run a program in the interpreter and compiled with the same input, and
+    /// report where their output or final tape first differ
+    Verify {
+        #[clap(value_parser, value_name = "FILE")]
+        file: String,
+
+        /// File the program reads its input from, no input when not given
+        #[clap(long, value_parser, value_name = "FILE")]
+        input: Option<PathBuf>,
+
+        /// Give up when the interpreter executes this many instructions
+        #[clap(long, value_parser, value_name = "N")]
+        max_steps: Option<usize>,
+
+        /// Give up when the interpreter runs for this long
+        #[clap(long, value_parser, value_name = "MS")]
+        time_limit: Option<u64>,
+    },
+
+    /// Run every `prog.mid` in a directory that has a `prog.out` next to it or
+    /// `expect:` text events in it on the bytecode VM, with `prog.in` or its
+    /// `input:` events as input, check it writes exactly what's expected and
+    /// print how they went as TAP
+    Test {
+        #[clap(value_parser, value_name = "DIR")]
+        dir: PathBuf,
+
+        /// Fail a program after it executes this many instructions
+        #[clap(long, value_parser, value_name = "N")]
+        max_steps: Option<usize>,
+
+        /// Fail a program after it runs for this long
+        #[clap(long, value_parser, value_name = "MS", default_value_t = 10_000)]
+        time_limit: u64,
+    },
+
+    /// Time parsing, optimizing, compiling and running a program, several
+    /// times over, for seeing what the optimizer and the VM are worth
+    Bench {
+        #[clap(value_parser, value_name = "FILE")]
+        file: String,
+
+        /// How many times to go through every phase
+        #[clap(long, value_parser = clap::value_parser!(u32).range(1..), default_value_t = 10)]
+        runs: u32,
+
+        /// File the program reads its input from, no input when not given
+        #[clap(long, value_parser, value_name = "FILE")]
+        input: Option<PathBuf>,
+
+        /// Stop the program after executing this many instructions
+        #[clap(long, value_parser, value_name = "N")]
+        max_steps: Option<usize>,
+
+        /// Stop the program after it ran for this long
+        #[clap(long, value_parser, value_name = "MS")]
+        time_limit: Option<u64>,
+    },
+
+    /// Run a program in the interpreter and write out how often each chord ran,
+    /// as a text report and as a copy of the file that plays hot chords louder
+    Profile {
+        #[clap(value_parser, value_name = "FILE")]
+        file: String,
+
+        /// Where to write the report, defaults to the file with .profile.txt
+        #[clap(long, value_parser, value_name = "FILE")]
+        report: Option<PathBuf>,
+
+        /// Where to write the MIDI heatmap, defaults to the file with .heatmap.mid
+        #[clap(long, value_parser, value_name = "FILE")]
+        heatmap: Option<PathBuf>,
+
+        /// Stop the program after it executes this many instructions
+        #[clap(long, value_parser, value_name = "N")]
+        max_steps: Option<usize>,
+
+        /// Stop the program after it runs for this long
+        #[clap(long, value_parser, value_name = "MS")]
+        time_limit: Option<u64>,
+    },
+
+    /// Print a script that completes midilang's arguments in `shell`, to source
+    /// from the shell's startup file
+    Completions {
+        #[clap(value_enum, value_name = "SHELL")]
+        shell: Shell,
+    },
+
+    /// Check a program for mistakes without running it, exits with 1 when
+    /// there are warnings and 2 when there are errors
+    Check {
+        #[clap(value_parser, value_name = "FILE")]
+        file: String,
+
+        /// Only report chords that don't parse, skip looking for likely mistakes
+        #[clap(long, action)]
+        parse_only: bool,
+    },
+
+    /// Look over a program for likely mistakes like `check`, with lints that
+    /// can be allowed or denied by name. Denying a lint wins over allowing it
+    Lint {
+        #[clap(value_parser, value_name = "FILE", required_unless_present = "list")]
+        file: Option<String>,
+
+        /// Don't report what this lint finds, can be given more than once
+        #[clap(short = 'A', long, value_parser, value_name = "LINT")]
+        allow: Vec<String>,
+
+        /// Report what this lint finds as a warning
+        #[clap(short = 'W', long, value_parser, value_name = "LINT")]
+        warn: Vec<String>,
+
+        /// Report what this lint finds as an error
+        #[clap(short = 'D', long, value_parser, value_name = "LINT")]
+        deny: Vec<String>,
+
+        /// List every lint and what it looks for
+        #[clap(long, action)]
+        list: bool,
+    },
+
+    /// Compile a program every time it's saved, or run or check it with
+    /// `-- run` or `-- check`, needs midilang built with the watch feature
+    Watch {
+        #[clap(value_parser, value_name = "FILE")]
+        file: String,
+
+        /// What to do with the file instead of compiling it, `run` or `check`
+        #[clap(last = true, value_parser, value_name = "COMMAND")]
+        then: Vec<String>,
+    },
+
+    /// Keep the backend warmed up and answer compile and run requests, JSON
+    /// objects one per line, on a unix socket, for editors and build tools
+    Daemon {
+        /// Socket to listen on, defaults to midilang.sock in $XDG_RUNTIME_DIR
+        #[clap(long, value_parser, value_name = "PATH")]
+        socket: Option<PathBuf>,
+    },
+
+    /// Speak a minimal language server protocol on stdin and stdout, publishing
+    /// diagnostics of the MIDI files and note text editor plugins and DAW
+    /// scripts open and change, at their bar and beat
+    Lsp,
+
+    /// Run chords as they're played into a MIDI input port called midilang,
+    /// that a DAW track or a keyboard can be patched into, needs midilang built
+    /// with the play feature. Typing `:save FILE` writes what was played to a
+    /// MIDI file, `:load FILE` runs one, `:undo` and `:redo` take back and play
+    /// again the last chord or loop, `:pause` and `:resume` stop and start
+    /// taking chords, `:reset` starts over on a blank tape and `:dump` prints
+    /// it. Other lines typed are the input
+    Live {
+        /// Listen on the first MIDI input port with this in its name instead
+        #[clap(long, value_parser, value_name = "NAME")]
+        port: Option<String>,
+
+        /// Take commands as OSC messages on this UDP address too, like
+        /// `/midilang/pause` for `:pause`, to bind controller buttons to
+        #[clap(long, value_parser, value_name = "ADDR")]
+        osc: Option<String>,
+    },
+
+    /// Answer HTTP requests to compile or run MIDI files posted to it, for
+    /// playgrounds, needs midilang built with the serve feature
+    Serve {
+        /// Address to listen on
+        #[clap(long, value_parser, value_name = "ADDR", default_value = "127.0.0.1:8080")]
+        addr: String,
+
+        /// Largest file taken, in bytes
+        #[clap(long, value_parser, value_name = "N", default_value_t = 1 << 20)]
+        max_body: usize,
+
+        /// Most instructions a run can execute
+        #[clap(long, value_parser, value_name = "N", default_value_t = 100_000_000)]
+        max_steps: usize,
+
+        /// Longest a run can take, in milliseconds
+        #[clap(long, value_parser, value_name = "MS", default_value_t = 5000)]
+        time_limit: u64,
+
+        /// Most bytes a run can write
+        #[clap(long, value_parser, value_name = "N", default_value_t = 1 << 16)]
+        max_output: usize,
+    },
+
+    /// Run a program in the interpreter and write out which chords never ran,
+    /// as a text report and as a copy of the file with those chords muted
+    Coverage {
+        #[clap(value_parser, value_name = "FILE")]
+        file: String,
+
+        /// Run the program once with each of these files as its input and
+        /// combine the runs, reads stdin once when not given
+        #[clap(long, value_parser, value_name = "FILE")]
+        input: Vec<PathBuf>,
+
+        /// Where to write the report, defaults to the file with .coverage.txt
+        #[clap(long, value_parser, value_name = "FILE")]
+        report: Option<PathBuf>,
+
+        /// Where to write the annotated MIDI file, defaults to the file with .coverage.mid
+        #[clap(long, value_parser, value_name = "FILE")]
+        annotated: Option<PathBuf>,
+
+        /// Stop each run after it executes this many instructions
+        #[clap(long, value_parser, value_name = "N")]
+        max_steps: Option<usize>,
+
+        /// Stop each run after it runs for this long
+        #[clap(long, value_parser, value_name = "MS")]
+        time_limit: Option<u64>,
+    },
+
+    /// Run a program in the interpreter and record the chord of every step it
+    /// takes, loops unrolled, as a MIDI file to play back
+    Record {
+        #[clap(value_parser, value_name = "FILE")]
+        file: String,
+
+        /// Where to write the recording, defaults to the file with .performance.mid
+        #[clap(short = 'o', long, value_parser, value_name = "FILE")]
+        output: Option<PathBuf>,
+
+        /// Stop recording after this many steps
+        #[clap(long, value_parser, value_name = "N", default_value_t = 100_000)]
+        max_steps: usize,
+
+        /// Stop recording after the program runs for this long
+        #[clap(long, value_parser, value_name = "MS")]
+        time_limit: Option<u64>,
+    },
+
+    /// Run a program in the interpreter and render the chord of every step it
+    /// takes to a WAV file, with a simple built in synth
+    Render {
+        #[clap(value_parser, value_name = "FILE")]
+        file: String,
+
+        /// Where to write the audio, defaults to the file with .wav
+        #[clap(short = 'o', long, value_parser, value_name = "FILE")]
+        output: Option<PathBuf>,
+
+        /// Chords played per minute
+        #[clap(long, value_parser = clap::value_parser!(u32).range(1..), default_value_t = 240)]
+        bpm: u32,
+
+        /// Stop rendering after this many steps
+        #[clap(long, value_parser, value_name = "N", default_value_t = 2_000)]
+        max_steps: usize,
+
+        /// Stop rendering after the program runs for this long
+        #[clap(long, value_parser, value_name = "MS")]
+        time_limit: Option<u64>,
+    },
+
+    /// Step through a program in a terminal debugger, showing the tape and the
+    /// bar and beat of every instruction
+    #[cfg(feature = "tui")]
+    Debug {
+        #[clap(value_parser, value_name = "FILE")]
+        file: String,
+
+        /// File the program reads its input from, no input when not given
+        #[clap(long, value_parser, value_name = "FILE")]
+        input: Option<PathBuf>,
+
+        /// Pause whenever the program gets to this bar, can be given more than once
+        #[clap(long = "break", value_parser, value_name = "BAR")]
+        breakpoints: Vec<u64>,
+    },
+
+    /// Rewrite a program as a canonical MIDI file: a chord every eighth note,
+    /// roots around middle C and a fresh meta track. It reads back as the same
+    /// program
+    Fmt {
+        #[clap(value_parser, value_name = "FILE")]
+        file: String,
+
+        /// Where to write the formatted file, defaults to the file itself
+        #[clap(short = 'o', long, value_parser, value_name = "FILE")]
+        output: Option<PathBuf>,
+
+        /// Only check whether the file is formatted, failing when it isn't
+        #[clap(long)]
+        check: bool,
+    },
+
+    /// Make up a program and write it as a MIDI file, for stress testing the
+    /// compilers or for listening to
+    Compose {
+        /// Same seed, same song, picked from the clock when not given
+        #[clap(long, value_parser, value_name = "N")]
+        seed: Option<u64>,
+
+        /// Number of instructions to pick, loops left open are closed after them
+        #[clap(long, value_parser, value_name = "M", default_value_t = 64)]
+        length: usize,
+
+        /// Only make programs that end, by counting every loop down
+        #[clap(long)]
+        terminating: bool,
+
+        /// How often to pick each kind of instruction, like `add=3,loop=0`,
+        /// out of add, subtract, right, left, output, input and loop
+        #[clap(long, value_parser, value_name = "WEIGHTS", default_value_t = Weights::default())]
+        weights: Weights,
+
+        /// How deep loops can nest
+        #[clap(long, value_parser, value_name = "N", default_value_t = 3)]
+        max_depth: usize,
+
+        /// Where to write the song, defaults to composed-<seed>.mid
+        #[clap(short = 'o', long, value_parser, value_name = "FILE")]
+        output: Option<PathBuf>,
+    },
+
+    /// Shrink a MIDI file that shows a bug, taking out loops, chords and notes
+    /// while a shell command still exits with 0 on what's left, like
+    /// `--check 'midilang compile {} 2>&1 | grep -q panicked'`
+    Reduce {
+        #[clap(value_parser, value_name = "FILE")]
+        file: String,
+
+        /// Command that exits with 0 while the bug is still there, `{}` is the
+        /// song to check and it goes on the end without one
+        #[clap(long, value_parser, value_name = "COMMAND")]
+        check: String,
+
+        /// Stop checks after this long and take them as not showing the bug
+        #[clap(long, value_parser, value_name = "MS", default_value_t = 10_000)]
+        timeout: u64,
+
+        /// Where to write the smallest song, defaults to the file with .reduced.mid
+        #[clap(short = 'o', long, value_parser, value_name = "FILE")]
+        output: Option<PathBuf>,
+    },
+
+    /// Count the instructions of each kind in a MIDI file, how deep its loops
+    /// nest, the tape it uses, how many notes its chords have and how long it
+    /// lasts, for golfing or telling programs from songs
+    Stats {
+        #[clap(value_parser, value_name = "FILE")]
+        file: String,
+    },
+
+    /// List every chord of a MIDI file with its notes, the root and argument
+    /// decoded from them, the instruction it reads as and where it is
+    Explain {
+        #[clap(value_parser, value_name = "FILE")]
+        file: String,
+    },
+
+    /// Write out a program as JSON or S-expressions for other tools to read or
+    /// change, or its control flow as a Graphviz graph. JSON and S-expression
+    /// dumps compile and run like the MIDI files they came from
+    Dump {
+        #[clap(value_parser, value_name = "FILE")]
+        file: String,
+
+        #[clap(long, value_enum, default_value_t = DumpFormat::Json)]
+        format: DumpFormat,
+
+        /// Where to write the dump, defaults to stdout
+        #[clap(short = 'o', long, value_parser, value_name = "FILE")]
+        output: Option<PathBuf>,
+    },
+
+    /// List the riffs of the prelude, the routines songs can include with
+    /// `include prelude/NAME`, or write one or a string literal out to paste
+    /// into a song
+    Lib {
+        #[clap(subcommand)]
+        command: LibCommand,
+    },
+
+    /// Recover the MIDI file embedded in a program compiled with --embed-source
+    Extract {
+        #[clap(value_parser, value_name = "BINARY")]
+        binary: PathBuf,
+
+        /// Where to write the MIDI file, defaults to the binary with .mid appended
+        #[clap(short = 'o', long, value_parser, value_name = "FILE")]
+        output: Option<PathBuf>,
+    },
+}
+
+#[derive(Subcommand, Debug)]
+enum LibCommand {
+    /// List every riff with what it does
+    List,
+
+    /// Write a riff out as a MIDI file of its own
+    Inline {
+        #[clap(value_parser, value_name = "NAME")]
+        name: String,
+
+        /// Where to write the riff, defaults to NAME.mid
+        #[clap(short = 'o', long, value_parser, value_name = "FILE")]
+        output: Option<PathBuf>,
+    },
+
+    /// Write string literals setting the cells from the pointer on to TEXT
+    /// out as a MIDI file, a chord on D♯ and a note for every byte
+    String {
+        #[clap(value_parser, value_name = "TEXT")]
+        text: String,
+
+        /// Where to write the string, defaults to string.mid
+        #[clap(short = 'o', long, value_parser, value_name = "FILE")]
+        output: Option<PathBuf>,
+    },
+}
+
+impl MidilangCli {
+    /// Options for compiling or running a program, `None` after logging why they're invalid
+    fn compile_options(&self) -> Option<CompileOptions> {
+        let options = CompileOptions::builder()
+            // clap already restricts the range to 0..=3
+            .opt_level(OptLevel::try_from(self.opt_level).unwrap_or_default())
+            .tape_size(self.tape_size)
+            .tape_mode(self.tape)
+            .bounds_check(self.checked)
+            .overflow(self.cell_overflow)
+            .pointer_overflow(self.pointer_overflow)
+            .io(self.io)
+            .stdin(self.stdin.clone())
+            .stdout(self.stdout.clone())
+            .cell_width(self.cell_size)
+            .target_triple(self.target.clone())
+            .linker(self.linker.clone())
+            .link(self.link.clone())
+            .backend(self.backend)
+            .perform(self.perform.then_some(self.note_length))
+            .seed(self.seed)
+            .build();
+        match options {
+            Ok(options) => Some(options),
+            Err(e) => {
+                error!("Invalid compile options: {:?}", e);
+                None
+            }
+        }
+    }
+
+    /// What compiling a program writes out
+    fn output_options(&self) -> OutputOptions {
+        OutputOptions {
+            emit: self.emit,
+            output: self.output.clone(),
+            dump_llvm: self.dump_llvm,
+            dump_ast: self.dump_ast,
+            debug_info: self.debug_info,
+            embed_source: self.embed_source,
+            error_format: self.error_format,
+            lenient: self.lenient,
+            cache: self.cache,
+        }
+    }
+}
+
+// the severities of lints after allowing, warning about and then denying the
+// named ones, failing on names that aren't lints
+fn lint_levels(allow: &[String], warn: &[String], deny: &[String]) -> MidilangResult<LintLevels> {
+    let mut levels = LintLevels::default();
+    for (names, severity) in [(allow, None), (warn, Some(Severity::Warning)), (deny, Some(Severity::Error))] {
+        for name in names {
+            levels.set(name, severity).map_err(MidilangError::Other)?;
+        }
+    }
+    Ok(levels)
+}
+
+// exits with the exit code of a program that was run, or of a failed subcommand
+// after printing what went wrong in `format`, see `MidilangError::exit_code`
+fn exit_with(result: MidilangResult<i32>, format: ErrorFormat) {
+    match result {
+        Err(e) => {
+            e.report(format);
+            std::process::exit(e.exit_code());
+        }
+        Ok(0) => {}
+        Ok(code) => std::process::exit(code),
+    }
+}
+
+// a seed for when none is given, different every run
+fn clock_seed() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).map_or(0, |since| since.as_nanos() as u64)
 }
 
 fn main() {
     let cli_args = MidilangCli::parse();
 
-    Builder::new()
-        .filter(
-            None,
-            if cli_args.debug {
-                LevelFilter::Trace
-            } else {
-                LevelFilter::Warn
-            },
-        )
-        .write_style(WriteStyle::Auto)
-        .target(if cli_args.verbose {
-            Target::Stdout
-        } else {
-            Target::Stderr
-        })
-        .init();
-
-    if let Some(bf) = cli_args.bf {
-        match midilang::from_brainf(&bf) {
+    // structured logs are for watching jobs, so they come with their progress
+    let level = if cli_args.debug {
+        LevelFilter::Trace
+    } else if cli_args.log_format == LogFormat::Json || cli_args.log_file.is_some() {
+        LevelFilter::Info
+    } else {
+        LevelFilter::Warn
+    };
+    let target = match &cli_args.log_file {
+        Some(path) => match File::create(path) {
+            Ok(file) => Target::Pipe(Box::new(file)),
+            Err(e) => {
+                eprintln!("Could not create log file {}: {}", path.display(), e);
+                std::process::exit(FailureKind::Io.exit_code());
+            }
+        },
+        None if cli_args.verbose => Target::Stdout,
+        None => Target::Stderr,
+    };
+    let mut builder = Builder::new();
+    builder.filter(None, level).write_style(WriteStyle::Auto).target(target);
+    if cli_args.log_format == LogFormat::Json {
+        builder.format(|buf, record| {
+            let timestamp = buf.timestamp_millis();
+            writeln!(buf, "{}", logging::record_json(record, timestamp))
+        });
+    }
+    builder.init();
+
+    let parse = ParseOptions {
+        data_track: cli_args.data_track,
+        extensions: cli_args.extensions,
+        include_dirs: cli_args.include_dir.clone(),
+        ..ParseOptions::default()
+    };
+    // what every run starts from, commands add their limits
+    let run = RunOptions {
+        seed: cli_args.seed.unwrap_or_else(clock_seed),
+        tape_mode: cli_args.tape,
+        cell_overflow: cli_args.cell_overflow,
+        pointer_overflow: cli_args.pointer_overflow.map(|overflow| (overflow, cli_args.tape_size)),
+        io: cli_args.io,
+        ..RunOptions::default()
+    };
+
+    if let Some(command) = &cli_args.command {
+        let result = match command {
+            #[cfg(feature = "tui")]
+            Command::Debug {
+                file,
+                input,
+                breakpoints,
+            } => midilang::debug_file(file, &parse, cli_args.cell_size, run, input.as_deref(), breakpoints),
+            Command::Fmt { file, output, check } => midilang::fmt_file(file, &parse, output.as_deref(), *check),
+            Command::Compose {
+                seed,
+                length,
+                terminating,
+                weights,
+                max_depth,
+                output,
+            } => {
+                let seed = seed.unwrap_or_else(clock_seed);
+                let options = ComposeOptions {
+                    seed,
+                    length: *length,
+                    terminating: *terminating,
+                    weights: *weights,
+                    max_depth: *max_depth,
+                };
+                midilang::compose_file(&options, output.as_deref())
+            }
+            Command::Reduce {
+                file,
+                check,
+                timeout,
+                output,
+            } => midilang::reduce_file(file, check, Duration::from_millis(*timeout), output.as_deref()),
+            Command::Stats { file } => midilang::stats_file(file, &parse),
+            Command::Bench {
+                file,
+                runs,
+                input,
+                max_steps,
+                time_limit,
+            } => {
+                let limits = RunOptions {
+                    max_steps: *max_steps,
+                    wall_clock_limit: time_limit.map(Duration::from_millis),
+                    ..run
+                };
+                match cli_args.compile_options() {
+                    Some(options) => midilang::bench_file(file, &parse, options, input.as_deref(), limits, *runs as usize),
+                    None => std::process::exit(EXIT_FAILURE),
+                }
+            }
+            Command::Test { dir, max_steps, time_limit } => {
+                let limits = RunOptions {
+                    max_steps: *max_steps,
+                    wall_clock_limit: Some(Duration::from_millis(*time_limit)),
+                    ..run
+                };
+                midilang::test_dir(dir, &parse, cli_args.cell_size, limits, cli_args.stdin.as_deref(), cli_args.stdout.as_deref())
+            }
+            Command::Explain { file } => midilang::explain_file(file, &parse),
+            Command::Dump { file, format, output } => midilang::dump_file(file, &parse, *format, output.as_deref()),
+            Command::Lib { command: LibCommand::List } => midilang::lib_list(),
+            Command::Lib {
+                command: LibCommand::Inline { name, output },
+            } => midilang::lib_inline(name, output.as_deref()),
+            Command::Lib {
+                command: LibCommand::String { text, output },
+            } => midilang::lib_string(text, output.as_deref()),
+            Command::Extract { binary, output } => midilang::extract_file(binary, output.as_deref()),
+            Command::Run {
+                file,
+                max_steps,
+                max_output,
+                time_limit,
+                play,
+                musical_time,
+                bpm,
+                port,
+                input_mode,
+            } => {
+                let limits = RunOptions {
+                    max_steps: *max_steps,
+                    max_output_bytes: *max_output,
+                    wall_clock_limit: time_limit.map(Duration::from_millis),
+                    ..run
+                };
+                if *musical_time {
+                    exit_with(midilang::run_in_musical_time(file, &parse, cli_args.cell_size, limits), cli_args.error_format);
+                    return;
+                }
+                if *play {
+                    #[cfg(feature = "play")]
+                    let result = midilang::play_file(file, &parse, cli_args.cell_size, limits, *bpm, port.as_deref());
+                    #[cfg(not(feature = "play"))]
+                    let result = {
+                        let _ = (bpm, port);
+                        error!("--play needs midilang built with `--features play`");
+                        Ok(EXIT_FAILURE)
+                    };
+                    exit_with(result, cli_args.error_format);
+                    return;
+                }
+                if input_mode.is_some() && !cfg!(feature = "terminal") {
+                    error!("--input-mode needs midilang built with `--features terminal`");
+                    std::process::exit(EXIT_FAILURE);
+                }
+                match cli_args.compile_options() {
+                    Some(options) => midilang::run_file(file, &parse, options, limits, *input_mode),
+                    None => std::process::exit(EXIT_FAILURE),
+                }
+            }
+            Command::Watch { file, then } => {
+                let Some(options) = cli_args.compile_options() else {
+                    std::process::exit(EXIT_FAILURE);
+                };
+                #[cfg(feature = "watch")]
+                let result = midilang::watch_file(file, &parse, then, options, &cli_args.output_options());
+                #[cfg(not(feature = "watch"))]
+                let result = {
+                    let _ = (file, then, options);
+                    error!("watch needs midilang built with `--features watch`");
+                    Ok(EXIT_FAILURE)
+                };
+                result
+            }
+            Command::Daemon { socket } => {
+                #[cfg(unix)]
+                let result = midilang::daemon::serve(&socket.clone().unwrap_or_else(midilang::daemon::default_socket), &parse);
+                #[cfg(not(unix))]
+                let result = {
+                    let _ = socket;
+                    error!("the daemon listens on a unix socket, which this system doesn't have");
+                    Ok(EXIT_FAILURE)
+                };
+                result
+            }
+            Command::Lsp => midilang::lsp::serve(std::io::stdin().lock(), std::io::stdout().lock(), &parse),
+            Command::Live { port, osc } => {
+                #[cfg(feature = "play")]
+                let result = midilang::live_midi(&parse, cli_args.cell_size, run, port.as_deref(), osc.as_deref());
+                #[cfg(not(feature = "play"))]
+                let result = {
+                    let _ = (port, osc);
+                    error!("live needs midilang built with `--features play`");
+                    Ok(EXIT_FAILURE)
+                };
+                result
+            }
+            Command::Serve { addr, max_body, max_steps, time_limit, max_output } => {
+                #[cfg(feature = "serve")]
+                let result = midilang::serve::serve(
+                    addr,
+                    midilang::serve::Sandbox {
+                        max_body_bytes: *max_body,
+                        max_steps: *max_steps,
+                        time_limit: Duration::from_millis(*time_limit),
+                        max_output_bytes: *max_output,
+                    },
+                );
+                #[cfg(not(feature = "serve"))]
+                let result = {
+                    let _ = (addr, max_body, max_steps, time_limit, max_output);
+                    error!("serve needs midilang built with `--features serve`");
+                    Ok(EXIT_FAILURE)
+                };
+                result
+            }
+            Command::Completions { shell } => {
+                clap_complete::generate(*shell, &mut MidilangCli::command(), "midilang", &mut std::io::stdout());
+                Ok(0)
+            }
+            Command::Check { file, parse_only } => midilang::check_file(file, &parse, !*parse_only, cli_args.error_format),
+            Command::Lint { list: true, .. } => {
+                for lint in check::LINTS {
+                    println!("{:20} {:8} {}", lint.name(), lint.default_severity(), lint.description());
+                }
+                Ok(0)
+            }
+            Command::Lint { file, allow, warn, deny, .. } => lint_levels(allow, warn, deny)
+                .and_then(|levels| midilang::lint_file(file.as_deref().unwrap_or_default(), &parse, &levels, cli_args.error_format)),
+            Command::Coverage {
+                file,
+                input,
+                report,
+                annotated,
+                max_steps,
+                time_limit,
+            } => {
+                let limits = RunOptions {
+                    max_steps: *max_steps,
+                    wall_clock_limit: time_limit.map(Duration::from_millis),
+                    ..run
+                };
+                midilang::coverage_file(
+                    file,
+                    &parse,
+                    cli_args.cell_size,
+                    input,
+                    limits,
+                    report.as_deref(),
+                    annotated.as_deref(),
+                )
+            }
+            Command::Render {
+                file,
+                output,
+                bpm,
+                max_steps,
+                time_limit,
+            } => {
+                let limits = RunOptions {
+                    max_steps: Some(*max_steps),
+                    wall_clock_limit: time_limit.map(Duration::from_millis),
+                    ..run
+                };
+                midilang::render_file(file, &parse, cli_args.cell_size, limits, *bpm, output.as_deref())
+            }
+            Command::Record {
+                file,
+                output,
+                max_steps,
+                time_limit,
+            } => {
+                let limits = RunOptions {
+                    max_steps: Some(*max_steps),
+                    wall_clock_limit: time_limit.map(Duration::from_millis),
+                    ..run
+                };
+                midilang::record_file(file, &parse, cli_args.cell_size, limits, output.as_deref())
+            }
+            Command::Profile {
+                file,
+                report,
+                heatmap,
+                max_steps,
+                time_limit,
+            } => {
+                let limits = RunOptions {
+                    max_steps: *max_steps,
+                    wall_clock_limit: time_limit.map(Duration::from_millis),
+                    ..run
+                };
+                midilang::profile_file(file, &parse, cli_args.cell_size, limits, report.as_deref(), heatmap.as_deref())
+            }
+            Command::Verify {
+                file,
+                input,
+                max_steps,
+                time_limit,
+            } => {
+                let limits = RunOptions {
+                    max_steps: *max_steps,
+                    wall_clock_limit: time_limit.map(Duration::from_millis),
+                    ..run
+                };
+                match cli_args.compile_options() {
+                    Some(options) => midilang::verify_file(file, &parse, options, input.as_deref(), limits),
+                    None => std::process::exit(EXIT_FAILURE),
+                }
+            }
+        };
+        exit_with(result, cli_args.error_format);
+        return;
+    }
+
+    if let Some(bf) = &cli_args.bf {
+        match midilang::from_brainf(bf) {
             Err(e) => error!("Error when parsing BF file: {}", e),
             Ok(_) => info!("BF File parsed successfully!"),
         }
     }
-    if let Some(path) = cli_args.file_name {
-        match midilang::compile_file(&path) {
-            Err(e) => error!("Application Error {}", e),
-            Ok(_) => info!("Ran successfully!"),
+    let files: Vec<String> = cli_args.file_name.iter().chain(&cli_args.files).cloned().collect();
+    if !files.is_empty() {
+        let Some(options) = cli_args.compile_options() else {
+            std::process::exit(EXIT_FAILURE);
+        };
+        #[cfg(feature = "cranelift")]
+        if cli_args.jit {
+            if files.len() > 1 {
+                error!("--jit runs a single program");
+                std::process::exit(EXIT_FAILURE);
+            }
+            exit_with(midilang::jit_file(&files[0], &parse, options), cli_args.error_format);
+            return;
         }
+        exit_with(midilang::compile_files(&files, &parse, options, &cli_args.output_options(), cli_args.parallel), cli_args.error_format);
     }
 }
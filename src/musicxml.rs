@@ -0,0 +1,249 @@
+use std::fmt::Write;
+
+use crate::notation::{annotation, lengths, needs_bass_clef, notate, Length};
+use crate::parser::Parsed;
+
+/// Steps and alterations of the keys of an octave, from C.
+const STEPS: [(char, i8); 12] = [
+    ('C', 0),
+    ('C', 1),
+    ('D', 0),
+    ('D', 1),
+    ('E', 0),
+    ('F', 0),
+    ('F', 1),
+    ('G', 0),
+    ('G', 1),
+    ('A', 0),
+    ('A', 1),
+    ('B', 0),
+];
+
+/// Writes the chords of a parsed song as a MusicXML score for notation
+/// editors like MuseScore, with what each chord does in the program as its
+/// lyric. See `notate` for how chords are rounded to notes.
+///
+/// MusicXML has no octaves below C0, so songs with notes under it are written
+/// octaves higher and marked as sounding where they are.
+pub fn write(parsed: &Parsed, title: &str) -> String {
+    let lowest = parsed.chords.iter().flat_map(|chord| &chord.notes).min().copied().unwrap_or(60);
+    let octaves_up = 12u8.saturating_sub(lowest).div_ceil(12);
+
+    let mut score = String::new();
+    score.push_str("<?xml version=\"1.0\" encoding=\"UTF-8\"?>\n");
+    score.push_str(
+        "<!DOCTYPE score-partwise PUBLIC \"-//Recordare//DTD MusicXML 4.0 Partwise//EN\" \
+         \"http://www.musicxml.org/dtds/partwise.dtd\">\n",
+    );
+    score.push_str("<score-partwise version=\"4.0\">\n");
+    writeln!(score, "  <work><work-title>{}</work-title></work>", escape(title)).unwrap();
+    score.push_str("  <part-list><score-part id=\"P1\"><part-name>Program</part-name></score-part></part-list>\n");
+    score.push_str("  <part id=\"P1\">\n");
+
+    let mut part = Part {
+        xml: score,
+        measure: 1,
+        position: 0,
+        measure_length: 16,
+        pending_time: None,
+    };
+    let clef = if needs_bass_clef(parsed) { ("F", 4) } else { ("G", 2) };
+    write!(
+        part.xml,
+        "    <measure number=\"1\">\n      <attributes><divisions>4</divisions><key><fifths>0</fifths></key>\
+         <time><beats>4</beats><beat-type>4</beat-type></time><clef><sign>{}</sign><line>{}</line></clef>",
+        clef.0, clef.1
+    )
+    .unwrap();
+    if octaves_up > 0 {
+        write!(
+            part.xml,
+            "<transpose><diatonic>0</diatonic><chromatic>0</chromatic><octave-change>-{}</octave-change></transpose>",
+            octaves_up
+        )
+        .unwrap();
+    }
+    part.xml.push_str("</attributes>\n");
+
+    for notated in notate(parsed) {
+        if let Some(time) = notated.time {
+            part.time(time);
+        }
+        match notated.chord {
+            Some(chord) => {
+                let pitches: Vec<String> = chord.notes.iter().map(|&key| pitch(key + 12 * octaves_up)).collect();
+                part.note(&pitches, notated.sixteenths, Some(&annotation(chord)));
+            }
+            None => part.note(&[], notated.sixteenths, None),
+        }
+    }
+    part.xml.push_str("    </measure>\n  </part>\n</score-partwise>\n");
+    part.xml
+}
+
+/// A part being written out, measure by measure.
+struct Part {
+    xml: String,
+    /// Number of the measure being written
+    measure: u64,
+    /// Sixteenths into the measure
+    position: u64,
+    measure_length: u64,
+    /// Time signature to change to at the next measure
+    pending_time: Option<(u8, u64)>,
+}
+
+impl Part {
+    fn time(&mut self, (numerator, denominator): (u8, u64)) {
+        // only the first measure starts before anything is in it
+        if self.position == 0 {
+            self.set_time(numerator, denominator);
+        } else {
+            self.pending_time = Some((numerator, denominator));
+        }
+    }
+
+    fn set_time(&mut self, numerator: u8, denominator: u64) {
+        self.measure_length = (u64::from(numerator) * 16 / denominator).max(1);
+        writeln!(
+            self.xml,
+            "      <attributes><time><beats>{}</beats><beat-type>{}</beat-type></time></attributes>",
+            numerator, denominator
+        )
+        .unwrap();
+    }
+
+    /// A chord of `pitches`, or a rest without any, tied over bar lines and
+    /// into notes of lengths that can be written.
+    fn note(&mut self, pitches: &[String], mut sixteenths: u64, lyric: Option<&str>) {
+        let mut first = true;
+        while sixteenths > 0 {
+            // measures are only started once there's something to go in them
+            if self.position == self.measure_length {
+                self.next_measure();
+            }
+            let fits = sixteenths.min(self.measure_length - self.position);
+            let pieces = lengths(fits);
+            for (idx, length) in pieces.iter().enumerate() {
+                let last = fits == sixteenths && idx + 1 == pieces.len();
+                let tied = !pitches.is_empty();
+                self.piece(pitches, length, tied && !last, tied && !first, lyric.filter(|_| first));
+                first = false;
+            }
+            sixteenths -= fits;
+            self.position += fits;
+        }
+    }
+
+    fn piece(&mut self, pitches: &[String], length: &Length, tie_start: bool, tie_stop: bool, lyric: Option<&str>) {
+        let mut ties = String::new();
+        let mut tied = String::new();
+        for (tie, kind) in [(tie_stop, "stop"), (tie_start, "start")] {
+            if tie {
+                write!(ties, "<tie type=\"{}\"/>", kind).unwrap();
+                write!(tied, "<tied type=\"{}\"/>", kind).unwrap();
+            }
+        }
+        if !tied.is_empty() {
+            tied = format!("<notations>{}</notations>", tied);
+        }
+        let dot = if length.dotted { "<dot/>" } else { "" };
+        let lyric = lyric.map_or(String::new(), |text| format!("<lyric><text>{}</text></lyric>", escape(text)));
+        if pitches.is_empty() {
+            writeln!(
+                self.xml,
+                "      <note><rest/><duration>{}</duration><type>{}</type>{}</note>",
+                length.sixteenths, length.musicxml, dot
+            )
+            .unwrap();
+        }
+        for (idx, pitch) in pitches.iter().enumerate() {
+            let chord = if idx > 0 { "<chord/>" } else { "" };
+            writeln!(
+                self.xml,
+                "      <note>{}{}<duration>{}</duration>{}<type>{}</type>{}{}{}</note>",
+                chord,
+                pitch,
+                length.sixteenths,
+                ties,
+                length.musicxml,
+                dot,
+                tied,
+                // the lyric goes on the chord, not every note in it
+                if idx == 0 { lyric.as_str() } else { "" },
+            )
+            .unwrap();
+        }
+    }
+
+    fn next_measure(&mut self) {
+        self.measure += 1;
+        self.position = 0;
+        write!(self.xml, "    </measure>\n    <measure number=\"{}\">\n", self.measure).unwrap();
+        if let Some((numerator, denominator)) = self.pending_time.take() {
+            self.set_time(numerator, denominator);
+        }
+    }
+}
+
+/// `key` as a MusicXML pitch, where middle C is C4.
+fn pitch(key: u8) -> String {
+    let (step, alter) = STEPS[usize::from(key % 12)];
+    let alter = if alter != 0 { format!("<alter>{}</alter>", alter) } else { String::new() };
+    format!("<pitch><step>{}</step>{}<octave>{}</octave></pitch>", step, alter, i32::from(key / 12) - 1)
+}
+
+fn escape(text: &str) -> String {
+    text.replace('&', "&amp;").replace('<', "&lt;").replace('>', "&gt;").replace('"', "&quot;")
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+    use crate::parser::parse_all;
+    use crate::MidiProgram;
+
+    #[test]
+    fn writes_measures_of_chords_with_lyrics() {
+        let parsed = parse_all(MidiProgram::from_bf_str("+[->.<]").unwrap().to_smf());
+        let score = write(&parsed, "a & b");
+        assert!(score.contains("<work-title>a &amp; b</work-title>"));
+        // converted brainf goes down to key 0, a whole octave under C0
+        assert!(score.contains("<octave-change>-1</octave-change>"));
+        assert!(score.contains(
+            "<note><pitch><step>A</step><octave>0</octave></pitch><duration>1</duration>\
+             <type>16th</type><lyric><text>+</text></lyric></note>"
+        ));
+        assert!(score.contains("<lyric><text>&lt;</text></lyric>"));
+        assert_eq!(score.matches("<measure ").count(), 1);
+    }
+
+    #[test]
+    fn ties_notes_over_bar_lines() {
+        let mut part = Part {
+            xml: String::new(),
+            measure: 1,
+            position: 12,
+            measure_length: 16,
+            pending_time: Some((3, 4)),
+        };
+        part.note(&[pitch(60)], 6, Some("+"));
+        assert_eq!(
+            part.xml,
+            "      <note><pitch><step>C</step><octave>4</octave></pitch><duration>4</duration>\
+             <tie type=\"start\"/><type>quarter</type><notations><tied type=\"start\"/></notations>\
+             <lyric><text>+</text></lyric></note>\n    \
+             </measure>\n    <measure number=\"2\">\n      \
+             <attributes><time><beats>3</beats><beat-type>4</beat-type></time></attributes>\n      \
+             <note><pitch><step>C</step><octave>4</octave></pitch><duration>2</duration>\
+             <tie type=\"stop\"/><type>eighth</type><notations><tied type=\"stop\"/></notations></note>\n"
+        );
+        assert_eq!((part.measure, part.position, part.measure_length), (2, 2, 12));
+
+        // a full measure is only closed once the next note comes
+        part.note(&[], 10, None);
+        assert!(part.xml.ends_with("<note><rest/><duration>2</duration><type>eighth</type></note>\n"));
+        assert_eq!((part.measure, part.position), (2, 12));
+    }
+}
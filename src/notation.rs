@@ -0,0 +1,141 @@
+use crate::parser::{Chord, MidiInstructionKind::*, Parsed};
+
+/// A length that's written as a single note.
+pub(crate) struct Length {
+    pub sixteenths: u64,
+    /// LilyPond duration, like `4.`
+    pub lilypond: &'static str,
+    /// MusicXML note type
+    pub musicxml: &'static str,
+    pub dotted: bool,
+}
+
+/// Lengths written as a single note, longest first.
+const LENGTHS: [Length; 8] = [
+    Length { sixteenths: 16, lilypond: "1", musicxml: "whole", dotted: false },
+    Length { sixteenths: 12, lilypond: "2.", musicxml: "half", dotted: true },
+    Length { sixteenths: 8, lilypond: "2", musicxml: "half", dotted: false },
+    Length { sixteenths: 6, lilypond: "4.", musicxml: "quarter", dotted: true },
+    Length { sixteenths: 4, lilypond: "4", musicxml: "quarter", dotted: false },
+    Length { sixteenths: 3, lilypond: "8.", musicxml: "eighth", dotted: true },
+    Length { sixteenths: 2, lilypond: "8", musicxml: "eighth", dotted: false },
+    Length { sixteenths: 1, lilypond: "16", musicxml: "16th", dotted: false },
+];
+
+/// The notes to tie together for `sixteenths` sixteenths, longest first.
+pub(crate) fn lengths(mut sixteenths: u64) -> Vec<&'static Length> {
+    let mut notes = vec![];
+    while sixteenths > 0 {
+        let length = LENGTHS.iter().find(|length| length.sixteenths <= sixteenths).unwrap();
+        sixteenths -= length.sixteenths;
+        notes.push(length);
+    }
+    notes
+}
+
+/// A chord or a rest on the way to becoming notation.
+pub(crate) struct Notated<'a> {
+    /// `None` for rests
+    pub chord: Option<&'a Chord>,
+    pub sixteenths: u64,
+    /// Time signature starting here, as its numerator and denominator
+    pub time: Option<(u8, u64)>,
+}
+
+/// The chords of a parsed song rounded to sixteenth notes, with rests for the
+/// gaps between them. Chords last at least a sixteenth, so songs with chords
+/// shorter than that (like converted brainf, where they take no time at all)
+/// get stretched. Timecode based files have no bars to speak of, every chord
+/// gets a quarter there.
+pub(crate) fn notate(parsed: &Parsed) -> Vec<Notated<'_>> {
+    let sixteenth = parsed.source_map.ticks_per_quarter().map(|tpq| (tpq / 4).max(1));
+    let sixteenths = |ticks: u64| match sixteenth {
+        Some(sixteenth) => (ticks + sixteenth / 2) / sixteenth,
+        None => 0,
+    };
+    let mut signatures = parsed.source_map.time_signatures().peekable();
+
+    let mut notated = vec![];
+    let mut tick = 0;
+    for chord in &parsed.chords {
+        let mut time = None;
+        while let Some((_, numerator, denominator)) = signatures.next_if(|&(at, _, _)| at <= chord.tick) {
+            time = Some((numerator, denominator));
+        }
+        let rest = sixteenths(chord.tick.saturating_sub(tick));
+        if rest > 0 {
+            notated.push(Notated {
+                chord: None,
+                sixteenths: rest,
+                time: time.take(),
+            });
+        }
+        notated.push(Notated {
+            chord: Some(chord),
+            sixteenths: match sixteenth {
+                Some(_) => sixteenths(chord.end - chord.tick).max(1),
+                None => 4,
+            },
+            time,
+        });
+        tick = chord.tick.max(chord.end);
+    }
+    notated
+}
+
+/// What the chord does in the program, short enough to go under a note, like
+/// `+3`, `[` or `.`, or `?` for chords that aren't instructions.
+pub(crate) fn annotation(chord: &Chord) -> String {
+    let signed = |up: &str, down: &str, amount: i64| {
+        let inst = if amount < 0 { down } else { up };
+        match amount.unsigned_abs() {
+            1 => inst.to_owned(),
+            times => format!("{}{}", inst, times),
+        }
+    };
+    match &chord.reading {
+        Ok(inst) => match &inst.instruction {
+            IncrementCell { amount } => signed("+", "-", i64::from(amount.0)),
+            MovePointer { amount } => signed(">", "<", *amount as i64),
+            OutputCell => ".".to_owned(),
+            InputCell => ",".to_owned(),
+            // the parser opens loops with a position and closes them without
+            Loop { .. } if inst.position.is_some() => "[".to_owned(),
+            Loop { .. } => "]".to_owned(),
+            Breakpoint => "#".to_owned(),
+            Call { name } => format!("{}()", name),
+            Random => "rand".to_owned(),
+            DumpTape => "dump".to_owned(),
+        },
+        Err(_) => "?".to_owned(),
+    }
+}
+
+/// Whether the lowest note of `parsed` is low enough for the bass clef.
+pub(crate) fn needs_bass_clef(parsed: &Parsed) -> bool {
+    parsed.chords.iter().flat_map(|chord| &chord.notes).min().is_some_and(|&lowest| lowest < 55)
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+    use crate::parser::parse_all;
+    use crate::MidiProgram;
+
+    #[test]
+    fn splits_lengths_into_notes() {
+        let sixteenths: Vec<u64> = lengths(21).iter().map(|length| length.sixteenths).collect();
+        assert_eq!(sixteenths, [16, 4, 1]);
+        assert!(lengths(0).is_empty());
+    }
+
+    #[test]
+    fn annotates_chords() {
+        let parsed = parse_all(MidiProgram::from_bf_str("+++[->.<]").unwrap().to_smf());
+        let words: Vec<String> = parsed.chords.iter().map(annotation).collect();
+        assert_eq!(words, ["+", "+", "+", "[", "-", ">", ".", "<", "]"]);
+        // converted brainf takes no time, so every chord gets a sixteenth
+        assert!(notate(&parsed).iter().all(|notated| notated.chord.is_some() && notated.sixteenths == 1));
+    }
+}
@@ -0,0 +1,541 @@
+use std::io;
+use std::num::Wrapping;
+
+use log::debug;
+
+use crate::compiler::{IoMode, Overflow, PointerOverflow, TapeMode};
+use crate::interpreter::{Interpreter, RunOptions};
+use crate::parser::{Cell, CellWidth, MidiAST, MidiInstruction, MidiInstructionKind::*, Position};
+
+/// Number of instructions `fold_constants` may execute at compile time.
+pub const FOLD_STEP_BUDGET: usize = 1_000_000;
+
+/// Runs every optimization pass over `program` until none of them make progress.
+pub fn optimize(program: MidiAST, cell_width: CellWidth) -> MidiAST {
+    optimize_with_data(program, cell_width, &RunOptions::default(), &[])
+}
+
+/// Optimizes `program` like `optimize`, for a program that runs with the tape,
+/// overflow and IO settings of `options` on a tape that starts out with `data`
+/// instead of zeroed cells. The limits of `options` don't matter.
+pub fn optimize_with_data(program: MidiAST, cell_width: CellWidth, options: &RunOptions, data: &[u8]) -> MidiAST {
+    debug!("Optimizing {} instructions...", program.len());
+    let program = remove_breakpoints(program);
+    let mut program = fold_constants(program, cell_width, options, data, FOLD_STEP_BUDGET);
+    loop {
+        let before = program.clone();
+        program = remove_dead_loops(program);
+        program = cancel_pairs(program, options);
+        if program == before {
+            break;
+        }
+    }
+    debug!("Optimized down to {} instructions", program.len());
+    program
+}
+
+/// Applies `pass` to the bodies of all loops in `program`, innermost first.
+fn map_loop_bodies<F: Fn(MidiAST) -> MidiAST>(program: MidiAST, pass: &F) -> MidiAST {
+    program
+        .into_iter()
+        .map(|inst| match inst {
+            MidiInstruction { position, instruction: Loop { body } } => MidiInstruction {
+                position,
+                instruction: Loop { body: pass(map_loop_bodies(body, pass)) },
+            },
+            _ => inst,
+        })
+        .collect()
+}
+
+/// Deletes breakpoints, which only the debugger stops at.
+///
+/// Optimized programs are never debugged, and breakpoints would keep the other
+/// passes from seeing the instructions around them as neighbours.
+pub fn remove_breakpoints(program: MidiAST) -> MidiAST {
+    fn pass(body: MidiAST) -> MidiAST {
+        body.into_iter().filter(|inst| inst.instruction != Breakpoint).collect()
+    }
+    pass(map_loop_bodies(program, &pass))
+}
+
+/// Deletes loops that directly follow another loop.
+///
+/// A loop only exits once the current cell is zero, so a loop right after it
+/// can never be entered. Empty loops anywhere else stay, they either do nothing
+/// or never terminate, which `+[]` does on purpose.
+pub fn remove_dead_loops(program: MidiAST) -> MidiAST {
+    fn pass(body: MidiAST) -> MidiAST {
+        let mut out: MidiAST = Vec::with_capacity(body.len());
+        for inst in body {
+            let after_loop = matches!(out.last(), Some(MidiInstruction { instruction: Loop { .. }, .. }));
+            if after_loop && matches!(inst.instruction, Loop { .. }) {
+                debug!("Removing dead loop at {:?}", inst.position);
+                continue;
+            }
+            out.push(inst);
+        }
+        out
+    }
+    pass(map_loop_bodies(program, &pass))
+}
+
+/// Merges runs of `+`/`-` and `<`/`>` and drops the ones that cancel out entirely.
+///
+/// Cells that saturate or trap instead of wrapping, as the cell overflow of
+/// `options` says, only get runs going the same way merged, `+` then `-` at the
+/// maximum isn't the same as doing nothing. Likewise for moves on a fixed tape
+/// the pointer traps at, `>` then `<` on its last cell stops the program.
+pub fn cancel_pairs(program: MidiAST, options: &RunOptions) -> MidiAST {
+    fn span(first: Option<Position>, second: Option<Position>) -> Option<Position> {
+        match (first, second) {
+            (Some(aa), Some(bb)) => Some(Position::new(aa.start(), bb.end())),
+            (aa, bb) => aa.or(bb),
+        }
+    }
+
+    let wraps = options.cell_overflow == Overflow::Wrap;
+    let pointer_traps = options.tape_mode == TapeMode::Fixed && matches!(options.pointer_overflow, Some((PointerOverflow::Trap, _)));
+    let pass = |body: MidiAST| -> MidiAST {
+        let mut out: MidiAST = Vec::with_capacity(body.len());
+        for inst in body {
+            let merged = match (out.last(), &inst.instruction) {
+                (Some(MidiInstruction { position, instruction: IncrementCell { amount: prev } }), IncrementCell { amount })
+                    if wraps || (prev.0 < 0) == (amount.0 < 0) =>
+                {
+                    Some(MidiInstruction {
+                        position: span(*position, inst.position),
+                        instruction: IncrementCell { amount: prev + amount },
+                    })
+                }
+                (Some(MidiInstruction { position, instruction: MovePointer { amount: prev } }), MovePointer { amount })
+                    if !pointer_traps || (*prev < 0) == (*amount < 0) =>
+                {
+                    Some(MidiInstruction {
+                        position: span(*position, inst.position),
+                        instruction: MovePointer { amount: prev + amount },
+                    })
+                }
+                _ => None,
+            };
+            match merged {
+                Some(merged) => {
+                    out.pop();
+                    if !is_noop(&merged) {
+                        out.push(merged);
+                    }
+                }
+                None if is_noop(&inst) => {}
+                None => out.push(inst),
+            }
+        }
+        out
+    };
+    pass(map_loop_bodies(program, &pass))
+}
+
+/// Evaluates the input-free prefix of `program` at compile time.
+///
+/// Top level instructions are executed until one of them reads input, calls a
+/// function, picks a random byte, dumps the tape, moves the pointer left of
+/// the first cell or off a tape it traps at, overflows a cell that traps or
+/// the interpreter runs out of `max_steps`, running with the overflow settings
+/// and IO mode of `options`. The evaluated prefix is replaced by instructions that print its
+/// precomputed output from the first cell and then rebuild the tape it left
+/// behind out of the one it started with, `data`. Programs without any input
+/// are reduced to just their output.
+pub fn fold_constants(program: MidiAST, cell_width: CellWidth, options: &RunOptions, data: &[u8], max_steps: usize) -> MidiAST {
+    let numeric = options.io == IoMode::Numeric;
+    let mut interp = Interpreter::with_options(RunOptions {
+        max_steps: Some(max_steps),
+        max_output_bytes: None,
+        wall_clock_limit: None,
+        // the tape is rebuilt from the first cell, moves left of it stay for
+        // runtime, and only fixed tapes end
+        tape_mode: TapeMode::Fixed,
+        pointer_overflow: options.pointer_overflow.filter(|_| options.tape_mode == TapeMode::Fixed),
+        // the output is printed again by the residual, byte for byte, UTF-8 or
+        // not, or number for number
+        io: if numeric { IoMode::Numeric } else { IoMode::Bytes },
+        ..*options
+    });
+    interp.set_cell_width(cell_width);
+    interp.load_data(data);
+    let mut output = Vec::<u8>::new();
+    let mut folded = 0;
+    for inst in program.iter() {
+        if needs_runtime(inst) {
+            break;
+        }
+        // only loops can fail halfway through, so only they need a checkpoint
+        let checkpoint = matches!(inst.instruction, Loop { .. }).then(|| (interp.clone(), output.len()));
+        if let Err(err) = interp.run(std::slice::from_ref(inst), &mut io::empty(), &mut output) {
+            debug!("Stopped constant folding at {:?}: {:?}", inst.position, err);
+            if let Some((saved, len)) = checkpoint {
+                interp = saved;
+                output.truncate(len);
+            }
+            break;
+        }
+        folded += 1;
+    }
+    if folded == 0 {
+        return program;
+    }
+
+    let position = match (program[0].position, program[folded - 1].position) {
+        (Some(first), Some(last)) => Some(Position::new(first.start(), last.end())),
+        (first, last) => first.or(last),
+    };
+    let inc = |amount| MidiInstruction { position, instruction: IncrementCell { amount } };
+    let shift = |amount| MidiInstruction { position, instruction: MovePointer { amount } };
+    let mut residual: MidiAST = vec![];
+
+    let start = |idx: usize| Wrapping(data.get(idx).copied().map_or(0, i32::from));
+    let mut current = start(0);
+    let printed: Vec<Cell> = match numeric {
+        // a number a line
+        true => output
+            .split(|&byte| byte == b'\n')
+            .filter(|line| !line.is_empty())
+            .map(|line| Wrapping(String::from_utf8_lossy(line).parse::<u32>().unwrap_or_default() as i32))
+            .collect(),
+        false => output.into_iter().map(|byte| Wrapping(i32::from(byte))).collect(),
+    };
+    for byte in printed {
+        if byte != current {
+            residual.push(inc(byte - current));
+        }
+        residual.push(MidiInstruction { position, instruction: OutputCell });
+        current = byte;
+    }
+
+    if folded < program.len() {
+        // the rest of the program still needs the tape the prefix left behind
+        let tape = interp.tape();
+        if tape[0] != current {
+            residual.push(inc(tape[0] - current));
+        }
+        let mut at = 0;
+        let cells = tape.len().max(data.len());
+        let changes = (1..cells).map(|idx| (idx, tape.get(idx).copied().unwrap_or_default() - start(idx)));
+        for (idx, change) in changes.filter(|(_, change)| change.0 != 0) {
+            residual.push(shift((idx - at) as isize));
+            residual.push(inc(change));
+            at = idx;
+        }
+        if interp.pointer() != at {
+            residual.push(shift(interp.pointer() as isize - at as isize));
+        }
+    }
+
+    debug!("Folded {} instructions into {}", folded, residual.len());
+    residual.extend(program.into_iter().skip(folded));
+    residual
+}
+
+/// Returns true if executing `inst` could read from the input, call a function,
+/// pick a random byte or dump the tape, which only running the program can do.
+fn needs_runtime(inst: &MidiInstruction) -> bool {
+    match &inst.instruction {
+        InputCell | Call { .. } | Random | DumpTape => true,
+        Loop { body } => body.iter().any(needs_runtime),
+        _ => false,
+    }
+}
+
+/// Static bounds on how far a program moves the pointer.
+pub trait TapeUsage {
+    /// Returns the index of the rightmost cell the program can reach, or `None`
+    /// when a loop moves the pointer so the bound isn't known statically.
+    fn highest_cell(&self) -> Option<usize>;
+}
+
+impl TapeUsage for [MidiInstruction] {
+    fn highest_cell(&self) -> Option<usize> {
+        let (highest, _) = pointer_movement(self)?;
+        Some(highest.max(0) as usize)
+    }
+}
+
+/// Returns the rightmost offset reached by `body` and the offset it ends on,
+/// both relative to where it starts. Loops have to end where they began.
+fn pointer_movement(body: &[MidiInstruction]) -> Option<(isize, isize)> {
+    let mut offset: isize = 0;
+    let mut highest: isize = 0;
+    for inst in body {
+        match &inst.instruction {
+            MovePointer { amount } => {
+                offset = offset.checked_add(*amount)?;
+                highest = highest.max(offset);
+            }
+            Loop { body } => match pointer_movement(body)? {
+                (loop_highest, 0) => highest = highest.max(offset.checked_add(loop_highest)?),
+                _ => return None,
+            },
+            _ => {}
+        }
+    }
+    Some((highest, offset))
+}
+
+/// Returns true for instructions that have no effect when executed.
+fn is_noop(inst: &MidiInstruction) -> bool {
+    match inst.instruction {
+        IncrementCell { amount } => amount.0 == 0,
+        MovePointer { amount } => amount == 0,
+        _ => false,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+    use crate::parser::MidiASTBuilder;
+
+    fn build(insts: Vec<MidiInstruction>) -> MidiAST {
+        let mut builder = MidiASTBuilder::new();
+        for inst in insts {
+            builder.push(inst).unwrap();
+        }
+        builder.into_mast().unwrap()
+    }
+
+    #[test]
+    fn removes_empty_loops_after_loops() {
+        let prog = build(vec![
+            MidiInstruction::new_open_loop(),
+            MidiInstruction::new_inc(Wrapping(-1)),
+            MidiInstruction::new_close_loop(),
+            MidiInstruction::new_open_loop(),
+            MidiInstruction::new_close_loop(),
+            MidiInstruction::new_output(),
+        ]);
+        let opt = remove_dead_loops(prog);
+        assert_eq!(opt.len(), 2);
+        assert_eq!(opt[1].instruction, OutputCell);
+    }
+
+    #[test]
+    fn keeps_empty_loops_that_never_end() {
+        // + [ ] hangs on purpose
+        let prog = build(vec![
+            MidiInstruction::new_inc(Wrapping(1)),
+            MidiInstruction::new_open_loop(),
+            MidiInstruction::new_close_loop(),
+            MidiInstruction::new_output(),
+        ]);
+        assert_eq!(remove_dead_loops(prog.clone()), prog);
+        let opt = optimize(prog, CellWidth::I8);
+        assert_eq!(opt[1].instruction, Loop { body: vec![] });
+    }
+
+    #[test]
+    fn removes_breakpoints() {
+        let prog = build(vec![
+            MidiInstruction::new_inc(Wrapping(1)),
+            MidiInstruction::new_breakpoint(),
+            MidiInstruction::new_open_loop(),
+            MidiInstruction::new_breakpoint(),
+            MidiInstruction::new_inc(Wrapping(-1)),
+            MidiInstruction::new_close_loop(),
+            MidiInstruction::new_breakpoint(),
+            MidiInstruction::new_inc(Wrapping(1)),
+        ]);
+        let opt = remove_breakpoints(prog);
+        assert_eq!(opt.len(), 3);
+        assert_eq!(opt[1].instruction, Loop { body: vec![MidiInstruction {
+            position: Some(Position::new(4, 4)),
+            instruction: IncrementCell { amount: Wrapping(-1) },
+        }] });
+    }
+
+    #[test]
+    fn removes_loops_after_loops() {
+        let prog = build(vec![
+            MidiInstruction::new_open_loop(),
+            MidiInstruction::new_inc(Wrapping(-1)),
+            MidiInstruction::new_close_loop(),
+            MidiInstruction::new_open_loop(),
+            MidiInstruction::new_output(),
+            MidiInstruction::new_close_loop(),
+        ]);
+        let opt = remove_dead_loops(prog);
+        assert_eq!(opt.len(), 1);
+        assert_eq!(opt[0].position, Some(Position::new(0, 2)));
+    }
+
+    #[test]
+    fn cancels_pairs() {
+        let prog = build(vec![
+            MidiInstruction::new_inc(Wrapping(3)),
+            MidiInstruction::new_inc(Wrapping(-3)),
+            MidiInstruction::new_move(2),
+            MidiInstruction::new_move(-1),
+            MidiInstruction::new_output(),
+        ]);
+        let opt = cancel_pairs(prog.clone(), &RunOptions::default());
+        assert_eq!(opt.len(), 2);
+        assert_eq!(opt[0], MidiInstruction {
+            position: Some(Position::new(2, 3)),
+            instruction: MovePointer { amount: 1 },
+        });
+        // cells that saturate keep runs going different ways apart
+        let saturating = RunOptions { cell_overflow: Overflow::Saturate, ..RunOptions::default() };
+        assert_eq!(cancel_pairs(prog, &saturating).len(), 4);
+    }
+
+    #[test]
+    fn keeps_moves_off_a_trapping_tape() {
+        // > off the only cell traps before < can bring it back
+        let prog = build(vec![MidiInstruction::new_move(1), MidiInstruction::new_move(-1)]);
+        let trapping = RunOptions { pointer_overflow: Some((PointerOverflow::Trap, 1)), ..RunOptions::default() };
+        assert_eq!(cancel_pairs(prog.clone(), &trapping), prog);
+        assert_eq!(cancel_pairs(prog, &RunOptions::default()), vec![]);
+    }
+
+    #[test]
+    fn optimize_until_fixpoint() {
+        // , [ - ] + - [ ] -> , [ - ]
+        let prog = build(vec![
+            MidiInstruction::new_input(),
+            MidiInstruction::new_open_loop(),
+            MidiInstruction::new_inc(Wrapping(-1)),
+            MidiInstruction::new_close_loop(),
+            MidiInstruction::new_inc(Wrapping(1)),
+            MidiInstruction::new_inc(Wrapping(-1)),
+            MidiInstruction::new_open_loop(),
+            MidiInstruction::new_close_loop(),
+        ]);
+        let opt = optimize(prog, CellWidth::I8);
+        assert_eq!(opt.len(), 2);
+        assert_eq!(opt[0].instruction, InputCell);
+    }
+
+    #[test]
+    fn folds_input_free_programs() {
+        // 3 + 5 and print, from examples/3plus5.bf
+        let prog = build(vec![
+            MidiInstruction::new_inc(Wrapping(3)),
+            MidiInstruction::new_move(1),
+            MidiInstruction::new_inc(Wrapping(5)),
+            MidiInstruction::new_move(-1),
+            MidiInstruction::new_open_loop(),
+            MidiInstruction::new_inc(Wrapping(-1)),
+            MidiInstruction::new_move(1),
+            MidiInstruction::new_inc(Wrapping(1)),
+            MidiInstruction::new_move(-1),
+            MidiInstruction::new_close_loop(),
+            MidiInstruction::new_move(1),
+            MidiInstruction::new_inc(Wrapping(48)),
+            MidiInstruction::new_output(),
+        ]);
+        let folded = fold_constants(prog, CellWidth::I8, &RunOptions::default(), &[], FOLD_STEP_BUDGET);
+        let position = Some(Position::new(0, 12));
+        assert_eq!(folded, vec![
+            MidiInstruction { position, instruction: IncrementCell { amount: Wrapping(56) } },
+            MidiInstruction { position, instruction: OutputCell },
+        ]);
+    }
+
+    #[test]
+    fn folds_prefix_before_input() {
+        let prog = build(vec![
+            MidiInstruction::new_inc(Wrapping(2)),
+            MidiInstruction::new_output(),
+            MidiInstruction::new_move(2),
+            MidiInstruction::new_inc(Wrapping(7)),
+            MidiInstruction::new_move(-1),
+            MidiInstruction::new_input(),
+            MidiInstruction::new_output(),
+        ]);
+        let folded = fold_constants(prog, CellWidth::I8, &RunOptions::default(), &[], FOLD_STEP_BUDGET);
+        let kinds: Vec<_> = folded.into_iter().map(|inst| inst.instruction).collect();
+        assert_eq!(kinds, vec![
+            IncrementCell { amount: Wrapping(2) },
+            OutputCell,
+            MovePointer { amount: 2 },
+            IncrementCell { amount: Wrapping(7) },
+            MovePointer { amount: -1 },
+            InputCell,
+            OutputCell,
+        ]);
+    }
+
+    #[test]
+    fn leaves_tape_dumps_to_the_running_program() {
+        let prog = build(vec![
+            MidiInstruction::new_inc(Wrapping(2)),
+            MidiInstruction::new_dump_tape(),
+            MidiInstruction::new_output(),
+        ]);
+        let folded = fold_constants(prog, CellWidth::I8, &RunOptions::default(), &[], FOLD_STEP_BUDGET);
+        let kinds: Vec<_> = folded.into_iter().map(|inst| inst.instruction).collect();
+        assert_eq!(kinds, vec![IncrementCell { amount: Wrapping(2) }, DumpTape, OutputCell]);
+    }
+
+    #[test]
+    fn folds_from_the_data_the_tape_starts_with() {
+        // [.>], after the data, and ,.
+        let prog = build(vec![
+            MidiInstruction::new_open_loop(),
+            MidiInstruction::new_output(),
+            MidiInstruction::new_move(1),
+            MidiInstruction::new_close_loop(),
+            MidiInstruction::new_input(),
+            MidiInstruction::new_output(),
+        ]);
+        let data = [104, 105];
+        let folded = fold_constants(prog.clone(), CellWidth::I8, &RunOptions::default(), &data, FOLD_STEP_BUDGET);
+        assert!(!folded.iter().any(|inst| matches!(inst.instruction, Loop { .. })));
+        let run = |program: &[MidiInstruction]| {
+            let mut interp = Interpreter::new();
+            interp.load_data(&data);
+            let mut output = vec![];
+            interp.run(program, &mut &b"!"[..], &mut output).unwrap();
+            (output, interp.tape().to_vec(), interp.pointer())
+        };
+        assert_eq!(run(&folded), run(&prog));
+        assert_eq!(run(&folded).0, b"hi!");
+    }
+
+    #[test]
+    fn leaves_non_terminating_loops_alone() {
+        let prog = build(vec![
+            MidiInstruction::new_inc(Wrapping(1)),
+            MidiInstruction::new_open_loop(),
+            MidiInstruction::new_move(1),
+            MidiInstruction::new_inc(Wrapping(1)),
+            MidiInstruction::new_close_loop(),
+        ]);
+        let folded = fold_constants(prog, CellWidth::I8, &RunOptions::default(), &[], 1_000);
+        assert_eq!(folded.len(), 2);
+        assert_eq!(folded[1].position, Some(Position::new(1, 4)));
+    }
+
+    #[test]
+    fn highest_cell_bounds() {
+        let balanced = build(vec![
+            MidiInstruction::new_move(2),
+            MidiInstruction::new_open_loop(),
+            MidiInstruction::new_move(3),
+            MidiInstruction::new_inc(Wrapping(1)),
+            MidiInstruction::new_move(-3),
+            MidiInstruction::new_inc(Wrapping(-1)),
+            MidiInstruction::new_close_loop(),
+            MidiInstruction::new_move(-2),
+        ]);
+        assert_eq!(balanced.highest_cell(), Some(5));
+
+        let left_only = build(vec![MidiInstruction::new_move(-4)]);
+        assert_eq!(left_only.highest_cell(), Some(0));
+
+        let scanning = build(vec![
+            MidiInstruction::new_open_loop(),
+            MidiInstruction::new_move(1),
+            MidiInstruction::new_close_loop(),
+        ]);
+        assert_eq!(scanning.highest_cell(), None);
+    }
+}
@@ -1,41 +1,94 @@
+//! Defines the Abstract Syntax Tree (AST) for midilang.
+//!
+//! The Syntax corresponding to these instructions is as follows:
+//! - `+` -> IncrementCell(...)
+//! - `-` -> IncrementCell(...) (constructed with a negated argument)
+//! - `>` -> MovePointer { right: true, amount: ... }
+//! - `<` -> MovePointer { right false, amount: ... }
+//! - `.` -> OutputCell
+//! - `,` -> InputCell
+//! - `[` ... `]` -> Loop { body } (the closing chord ends the body)
+//! - `#` -> Breakpoint (a diminished triad on the tonic)
+//! - Call { name } (a diminished seventh on the tonic), Random (an augmented
+//!   triad on the tonic) and DumpTape (a suspended fourth on the tonic), only in
+//!   the extensions dialect, see `ParseOptions::extensions`
+//!
+//! A chord on D♯ opens a string literal, see `STRING_ROOT`, which plays as the
+//! instructions setting the cells to its bytes.
+//!
+//! A midilang Program is defined by a vector of MASTs. `vm::Bytecode` flattens
+//! it into a list with jumps for the loops.
 
 use std::collections::BinaryHeap;
-use std::fmt::Debug;
+use std::error::Error;
+use std::fmt::{Debug, Display};
 use std::num::Wrapping;
+use std::path::PathBuf;
 
-use log::{debug, info};
-use midly::MidiMessage;
-
-/// Defines the Abstract Syntax Tree (AST) for midilang.
-/// 
-/// The Syntax corresponding to these instructions is as follows:
-/// - `+` -> IncrementCell(...)
-/// - `-` -> IncrementCell(...) (constructed with a negated argument)
-/// - `>` -> MovePointer { right: true, amount: ... }
-/// - `<` -> MovePointer { right false, amount: ... }
-/// - `.` -> OutputCell
-/// - `,` -> InputCell
-/// - `[` -> Loop {}
-/// - `]` -> JumpNotZero
-/// 
-/// A midilang Program is defined by a vector of MASTs.
+use log::{debug, info, warn};
+use midly::{EventIter, Format, Header, MetaMessage, MidiMessage, TrackEvent, TrackEventKind};
+use serde::{Deserialize, Serialize};
+
+use crate::prelude::{Riff, PRELUDE_DIR};
+use crate::progress::Progress;
+use crate::sysex::{Directive, Includes};
+use crate::timing::SourceMap;
 
 use MidiInstructionKind::*;
 
-/// BF cells are exactly one byte
-pub type Cell = Wrapping<i8>;
+/// Cell values and increments, wide enough for every `CellWidth`
+pub use midilang_core::Cell;
+
+/// Width of a single tape cell, shared by the interpreter and the compiler.
+#[derive(Debug, Default, PartialEq, Eq, Clone, Copy, clap::ValueEnum)]
+pub enum CellWidth {
+    #[default]
+    #[clap(name = "8")]
+    I8,
+    #[clap(name = "16")]
+    I16,
+    #[clap(name = "32")]
+    I32,
+}
+
+impl CellWidth {
+    pub fn bits(&self) -> u32 {
+        match self {
+            CellWidth::I8 => 8,
+            CellWidth::I16 => 16,
+            CellWidth::I32 => 32,
+        }
+    }
+
+    /// Wraps `value` around to the range of an unsigned cell of this width.
+    pub fn truncate(&self, value: Cell) -> Cell {
+        match self {
+            CellWidth::I8 => Wrapping(value.0 & 0xff),
+            CellWidth::I16 => Wrapping(value.0 & 0xffff),
+            CellWidth::I32 => value,
+        }
+    }
+}
 
 /// Range for keeping track of positions in code
-#[derive(PartialEq, Eq, Clone, Copy)]
+#[derive(PartialEq, Eq, Clone, Copy, Serialize, Deserialize)]
 pub struct Position {
     start: usize,
     end: usize
 }
 
 impl Position {
-    fn new(start: usize, end: usize) -> Self {
+    pub(crate) fn new(start: usize, end: usize) -> Self {
         Position{ start, end }
     }
+
+    pub fn start(&self) -> usize {
+        self.start
+    }
+
+    pub fn end(&self) -> usize {
+        self.end
+    }
 }
 
 impl Debug for Position {
@@ -52,13 +105,13 @@ impl Debug for Position {
 /// Our instruction datatype
         // Loops with position: `None` are used to represent closed loops
         // Loops with position: `Some(_)` are used for open loops
-#[derive(Debug, PartialEq, Eq, Clone)]
+#[derive(Debug, PartialEq, Eq, Clone, Serialize, Deserialize)]
 pub struct MidiInstruction {
     pub position: Option<Position>,
     pub instruction: MidiInstructionKind
 }
 
-#[derive(Debug, PartialEq, Eq, Clone)]
+#[derive(Debug, PartialEq, Eq, Clone, Serialize, Deserialize)]
 pub enum MidiInstructionKind {
     IncrementCell {
         amount: Cell,
@@ -70,53 +123,110 @@ pub enum MidiInstructionKind {
     InputCell,
     Loop {
         body: MidiAST
+    },
+    /// Pauses the debugger, everything else skips over it
+    Breakpoint,
+    /// Calls the external C function `int name(int)` with the cell and puts
+    /// what it returns in the cell. The name is the last text event before the
+    /// chord that is one
+    Call {
+        name: String,
+    },
+    /// Sets the cell to a random byte
+    Random,
+    /// Prints the cells around the pointer to stderr, see `midilang_core::TapeDump`
+    DumpTape,
+}
+
+impl MidiInstructionKind {
+    /// Short description of the instruction, like `add 3` or `output`.
+    pub fn describe(&self) -> String {
+        match self {
+            IncrementCell { amount } => format!("add {}", amount),
+            MovePointer { amount } => format!("move {}", amount),
+            OutputCell => "output".to_owned(),
+            InputCell => "input".to_owned(),
+            Loop { body } => format!("loop over {} instructions", body.len()),
+            Breakpoint => "breakpoint".to_owned(),
+            Call { name } => format!("call {}", name),
+            Random => "random".to_owned(),
+            DumpTape => "dump tape".to_owned(),
+        }
     }
 }
 
 impl MidiInstruction {
 
-    fn new_inc(amount: Cell) -> Self {
+    pub(crate) fn new_inc(amount: Cell) -> Self {
         MidiInstruction {
             position: None,
             instruction: IncrementCell { amount }
         }
     }
 
-    fn new_move(amount: isize) -> Self {
+    pub(crate) fn new_move(amount: isize) -> Self {
         MidiInstruction {
             position: None,
             instruction: MovePointer { amount }
         }
     }
 
-    fn new_close_loop() -> Self {
+    pub(crate) fn new_close_loop() -> Self {
         MidiInstruction {
             position: None,
             instruction: Loop { body: vec![] }
         }
     }
 
-    fn new_open_loop() -> Self {
+    pub(crate) fn new_open_loop() -> Self {
         MidiInstruction {
             position: Some(Position::new(0, 0)),
             instruction: Loop { body: vec![] }
         }
     }
 
-    fn new_output() -> Self {
+    pub(crate) fn new_output() -> Self {
         MidiInstruction {
             position: None,
             instruction: OutputCell
         }
     }
 
-    fn new_input() -> Self {
+    pub(crate) fn new_input() -> Self {
         MidiInstruction {
             position: None,
             instruction: InputCell
         }
     }
 
+    pub(crate) fn new_breakpoint() -> Self {
+        MidiInstruction {
+            position: None,
+            instruction: Breakpoint
+        }
+    }
+
+    pub(crate) fn new_call(name: String) -> Self {
+        MidiInstruction {
+            position: None,
+            instruction: Call { name }
+        }
+    }
+
+    pub(crate) fn new_random() -> Self {
+        MidiInstruction {
+            position: None,
+            instruction: Random
+        }
+    }
+
+    pub(crate) fn new_dump_tape() -> Self {
+        MidiInstruction {
+            position: None,
+            instruction: DumpTape
+        }
+    }
+
     fn set_position(&mut self, new_pos: Position) {
         self.position = Some(new_pos);
     }
@@ -124,10 +234,16 @@ impl MidiInstruction {
 
 
 // pub type MidiAST = Vec<MidiInstruction>;
+/// Builds an AST out of instructions in the order their chords come, loops
+/// opening with a `Some` position and closing with `None`.
+///
+/// The bodies of open loops sit at the end of one flat `body`, so closing a
+/// loop only moves its own instructions into it.
 pub struct MidiASTBuilder {
     body: MidiAST,
     size: usize,
-    loop_stack: Vec<(MidiAST, usize)>
+    /// Where the body of every open loop starts in `body`, and its position
+    loop_stack: Vec<(usize, usize)>
 }
 
 impl MidiASTBuilder {
@@ -143,19 +259,16 @@ impl MidiASTBuilder {
         match inst {
             MidiInstruction { position: Some(_), instruction: Loop {..}} => {
                 // open loop 
-                self.loop_stack.push((self.body.drain(..).collect(), self.size));
-                self.body = vec![];
+                self.loop_stack.push((self.body.len(), self.size));
             },
             MidiInstruction { position: None, instruction: Loop {..}} => {
                 // close loop
-                if let Some((mut before_loop, loop_start)) = self.loop_stack.pop() {
-                    before_loop.push(MidiInstruction {
+                if let Some((body_start, loop_start)) = self.loop_stack.pop() {
+                    let body = self.body.split_off(body_start);
+                    self.body.push(MidiInstruction {
                         position: Some(Position::new(loop_start, self.size)),
-                        instruction: Loop {
-                            body: self.body.to_owned()
-                        }
+                        instruction: Loop { body }
                     });
-                    self.body = before_loop;
                 }
                 else {
                     return Err(MParseError::DanglingLoop(Position::new(self.size, self.size)));
@@ -170,12 +283,12 @@ impl MidiASTBuilder {
         Ok(())
     }
 
-    pub fn into_mast(&self) -> MParseResult<MidiAST> {
+    pub fn into_mast(self) -> MParseResult<MidiAST> {
         if self.loop_stack.is_empty() {
-            Ok(self.body.to_owned())
+            Ok(self.body)
         } else {
             let loops = self.loop_stack.iter()
-                                       .map(|(_b, start)| Position::new(*start, *start))
+                                       .map(|(_body_start, start)| Position::new(*start, *start))
                                        .collect();
             Err(MParseError::UnclosedLoop(loops))
         }
@@ -192,12 +305,124 @@ pub type MidiAST = Vec<MidiInstruction>;
 
 pub type MParseResult<T> = Result<T, MParseError>;
 
-#[derive(PartialEq, Eq)]
+/// A parse error and where its chord is, if it is about a chord.
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub struct LocatedError {
+    pub error: MParseError,
+    pub tick: Option<u64>,
+    /// Index of the chord in `Parsed::chords`
+    pub chord: Option<usize>,
+}
+
+/// A chord as the parser read it, instruction or not.
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub struct Chord {
+    pub tick: u64,
+    /// Tick where the last of its notes is released
+    pub end: u64,
+    /// Keys of the notes, lowest first
+    pub notes: Vec<u8>,
+    /// The instruction the chord reads as on its own, loops opening with a
+    /// `Some` position and closing with `None` like the builder takes them
+    pub reading: MParseResult<MidiInstruction>,
+}
+
+/// Everything `parse_all` found in a file.
+#[derive(Debug, Clone)]
+pub struct Parsed {
+    pub ast: MParseResult<MidiAST>,
+    pub source_map: SourceMap,
+    pub errors: Vec<LocatedError>,
+    /// Every chord in the file, in the order they were read
+    pub chords: Vec<Chord>,
+    /// Index in `chords` of the chord of every instruction, indexed like `Position`s
+    pub instructions: Vec<usize>,
+    /// Cells the tape starts out with, read from the data track
+    pub data: Vec<u8>,
+}
+
+/// Track the data segment is on by convention, the one after the program track.
+pub const DATA_TRACK: usize = 2;
+
+/// How to read a file besides its chords.
+#[derive(Debug, Default, PartialEq, Eq, Clone)]
+pub struct ParseOptions {
+    /// Track holding the cells the tape starts out with instead of chords,
+    /// usually `DATA_TRACK`. Every note is a cell, its key the value, in the
+    /// order they're played and lowest first when played together, so data
+    /// is 7 bit, like ASCII strings
+    pub data_track: Option<usize>,
+    /// Read the chords of the extensions dialect, which plain midilang reads
+    /// as something else or not at all, like the augmented triad on the tonic
+    /// setting the cell to a random byte and the suspended fourth dumping the tape
+    pub extensions: bool,
+    /// Directories included files are looked for in after the one of the file
+    /// including them, like a standard library of riffs, from `--include-dir`
+    pub include_dirs: Vec<PathBuf>,
+    /// Read songs that can't be trusted with the machine reading them, like
+    /// the ones posted to `serve`. They can't include anything but riffs of
+    /// the prelude, and calls aren't read even with `extensions`
+    pub sandboxed: bool,
+}
+
+impl ParseOptions {
+    /// Options for songs that can't be trusted, see `sandboxed`.
+    pub fn sandboxed() -> Self {
+        ParseOptions { sandboxed: true, ..ParseOptions::default() }
+    }
+
+    /// Whether call chords are read as calls, which run whatever function
+    /// the song names.
+    pub fn reads_calls(&self) -> bool {
+        self.extensions && !self.sandboxed
+    }
+}
+
+#[derive(PartialEq, Eq, Clone)]
 pub enum MParseError {
     NoTracks,
     UnclosedLoop(Vec<Position>),
     DanglingLoop(Position),
     NonDiatonic,
+    /// A midilang SysEx message that can't be played, and why
+    Directive(String),
+    /// A string literal the track ends in the middle of, with how many of its
+    /// notes are missing
+    ShortString(usize),
+    /// A call chord with no function named before it
+    UnnamedCall,
+    /// A chord only the extensions dialect reads, read without it
+    NeedsExtensions,
+}
+
+impl MParseError {
+    /// Short name of the kind of error, for tools to match on.
+    pub fn code(&self) -> &'static str {
+        match self {
+            Self::NoTracks => "no-tracks",
+            Self::UnclosedLoop(_) => "unclosed-loop",
+            Self::DanglingLoop(_) => "dangling-loop",
+            Self::NonDiatonic => "non-diatonic",
+            Self::Directive(_) => "bad-directive",
+            Self::ShortString(_) => "short-string",
+            Self::UnnamedCall => "unnamed-call",
+            Self::NeedsExtensions => "needs-extensions",
+        }
+    }
+
+    /// What went wrong, in a few words for people.
+    pub fn describe(&self) -> String {
+        match self {
+            Self::NoTracks => "file has no tracks".to_owned(),
+            Self::UnclosedLoop(_) => "loop is never closed".to_owned(),
+            Self::DanglingLoop(_) => "closing chord without a loop to close".to_owned(),
+            Self::NonDiatonic => "chord's root isn't in C major".to_owned(),
+            Self::Directive(why) => format!("directive can't be played, {}", why),
+            Self::ShortString(missing) => format!("string literal is {} notes short", missing),
+            Self::UnnamedCall => "call has no function named before it".to_owned(),
+            Self::NeedsExtensions => "chord is only read in the extensions dialect".to_owned(),
+        }
+    }
 }
 
 impl Debug for MParseError {
@@ -207,43 +432,135 @@ impl Debug for MParseError {
             Self::NoTracks => write!(f, "File has no tracks to parse!"),
             Self::UnclosedLoop(poss) => write!(f, "Unclosed loops starting at: {:?}", poss),
             Self::DanglingLoop(pos) => write!(f, "Dangling loops starting at: {:?}", pos),
-            Self::NonDiatonic => write!(f, "Non Diatonic note found")
+            Self::NonDiatonic => write!(f, "Non Diatonic note found"),
+            Self::Directive(why) => write!(f, "Bad directive: {}", why),
+            Self::ShortString(missing) => write!(f, "String literal {} notes short", missing),
+            Self::UnnamedCall => write!(f, "Call without a function name"),
+            Self::NeedsExtensions => write!(f, "Extensions chord read without extensions"),
+        }
+    }
+}
+
+impl Display for MParseError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::UnclosedLoop(poss) => write!(f, "{} at instructions {:?}", self.describe(), poss),
+            Self::DanglingLoop(pos) => write!(f, "{} at instruction {:?}", self.describe(), pos),
+            _ => write!(f, "{}", self.describe()),
         }
     }
 }
 
-fn parse_chord<F: Fn(u8, i8) -> MParseResult<MidiInstruction>>(vals: Vec<u8>, key: &F) -> MParseResult<MidiInstruction> {
-    // unwrap is safe, we will never deal with an empty vector
-    let root = vals.first().unwrap() % 12;
-    let mut arg = None;
+impl Error for MParseError {}
+
+/// Intervals above the lowest note of the breakpoint chord, a diminished triad.
+const BREAKPOINT_CHORD: [u8; 3] = [0, 3, 6];
+
+/// Intervals above the lowest note of the call chord, a diminished seventh.
+const CALL_CHORD: [u8; 4] = [0, 3, 6, 9];
+
+/// Intervals above the lowest note of the random chord, an augmented triad.
+/// Only the extensions dialect has it.
+const RANDOM_CHORD: [u8; 3] = [0, 4, 8];
+
+/// Intervals above the lowest note of the tape dump chord, a suspended fourth.
+/// Only the extensions dialect has it.
+const DUMP_CHORD: [u8; 3] = [0, 5, 7];
+
+/// Whether the sorted notes `vals` are `chord`, intervals above its lowest
+/// note, on the tonic in any voicing that keeps the tonic at the bottom.
+fn is_on_tonic(vals: &[u8], chord: &[u8]) -> bool {
+    let Some(&lowest) = vals.first() else {
+        return false;
+    };
+    // unsorted notes below the lowest one can't be part of it
+    let intervals: Option<Vec<u8>> = vals.iter().map(|vv| vv.checked_sub(lowest).map(|interval| interval % 12)).collect();
+    let Some(mut intervals) = intervals else {
+        return false;
+    };
+    intervals.sort_unstable();
+    intervals.dedup();
+    lowest % 12 == 0 && intervals == chord
+}
+
+/// How a chord's notes read before the key turns them into an instruction.
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub struct DecodedChord {
+    /// Pitch class of the lowest note, which picks the instruction
+    pub root: u8,
+    /// Note the argument is counted up from
+    pub base: Option<u8>,
+    /// Bit of the argument each note above the base sets, in order
+    pub bits: Vec<u8>,
+    /// The argument, 1 when no note sets a bit
+    pub amount: i32,
+    pub breakpoint: bool,
+    pub call: bool,
+}
+
+/// Decodes the sorted notes `vals` step by step, the way `parse_chord` reads them.
+///
+/// Any notes decode to something without panicking: no notes at all read as
+/// a lone C, and notes below the base, which sorted notes never have, end the
+/// argument like notes too far above it.
+pub fn decode_chord(vals: &[u8]) -> DecodedChord {
+    let root = vals.first().map_or(0, |lowest| lowest % 12);
+    let mut arg: Option<i32> = None;
     let mut base = None;
+    let mut bits = vec![];
     let mut prev = root;
-    for vv in vals[1..].iter() {
+    for vv in vals.iter().skip(1) {
         if prev != *vv {
             if let Some(bb) = base {
-                let tmp = vv - bb - 1;
                 // Need to protect against overflow
-                if tmp > 8 {
+                let Some(tmp) = vv.checked_sub(bb).and_then(|above| above.checked_sub(1)).filter(|&tmp| tmp <= 8) else {
                     break;
-                }
-                let to_add = 2_i8.pow(u32::from(vv - bb - 1));
-                arg = arg.map_or(Some(to_add), |xx| Some(xx + to_add));
+                };
+                let to_add = 2_i32.pow(u32::from(tmp));
+                bits.push(tmp);
+                // every note above the base counts, even played more than once
+                arg = Some(arg.map_or(to_add, |xx| xx.saturating_add(to_add)));
             } else {
-                base = Some(vv);
+                base = Some(*vv);
                 prev = *vv
             }
         }
     };
-    let amount = arg.unwrap_or(1);
-    key(root, amount)
+    DecodedChord {
+        root,
+        base,
+        bits,
+        amount: arg.unwrap_or(1),
+        breakpoint: is_on_tonic(vals, &BREAKPOINT_CHORD),
+        call: is_on_tonic(vals, &CALL_CHORD),
+    }
+}
+
+fn parse_chord<F: Fn(u8, i32) -> MParseResult<MidiInstruction>>(vals: Vec<u8>, key: &F) -> MParseResult<MidiInstruction> {
+    let decoded = decode_chord(&vals);
+    // checked before the key, the chords would otherwise read as a `]`
+    if decoded.breakpoint {
+        return Ok(MidiInstruction::new_breakpoint());
+    }
+    // named by the parser, which knows the text events before it
+    if decoded.call {
+        return Ok(MidiInstruction::new_call(String::new()));
+    }
+    key(decoded.root, decoded.amount)
 }
 
 
-fn c_major(root: u8, arg: i8) -> MParseResult<MidiInstruction> {
+/// Key programs are read in, until the parser can tell what key a song is in.
+pub const PROGRAM_KEY: &str = "C major";
+
+/// Instruction set the parser reads chords as.
+pub const DIALECT: &str = "core";
+
+fn c_major(root: u8, arg: i32) -> MParseResult<MidiInstruction> {
     match root {
         0 => Ok(MidiInstruction::new_close_loop()),
-        2 => Ok(MidiInstruction::new_move(-isize::from(arg))),
-        4 => Ok(MidiInstruction::new_move(isize::from(arg))),
+        2 => Ok(MidiInstruction::new_move(-(arg as isize))),
+        4 => Ok(MidiInstruction::new_move(arg as isize)),
         5 => Ok(MidiInstruction::new_inc(Wrapping(-arg))),
         7 => Ok(MidiInstruction::new_open_loop()),
         9 => Ok(MidiInstruction::new_inc(Wrapping(arg))),
@@ -253,29 +570,422 @@ fn c_major(root: u8, arg: i8) -> MParseResult<MidiInstruction> {
     }
 }
 
-pub fn parse(midi: midly::Smf) -> MParseResult<MidiAST> { 
+/// Pitch class of the chord opening a string literal, D♯, which no instruction
+/// in C major is on. Its argument is how many notes after it are the bytes of
+/// the string, lowest first in every chord. The string sets the cells from the
+/// current one on to its bytes, leaving the pointer where it was, like a tape
+/// directive would.
+pub const STRING_ROOT: u8 = 3;
+
+/// Reads the sorted notes `notes` as a single instruction in C major, in plain
+/// midilang.
+pub(crate) fn read_chord(notes: Vec<u8>) -> MParseResult<MidiInstruction> {
+    read_chord_in(notes, &ParseOptions::default())
+}
+
+/// Reads the sorted `notes` like `read_chord`, in the dialect of `options`.
+pub(crate) fn read_chord_in(notes: Vec<u8>, options: &ParseOptions) -> MParseResult<MidiInstruction> {
+    if options.extensions && is_on_tonic(&notes, &RANDOM_CHORD) {
+        return Ok(MidiInstruction::new_random());
+    }
+    if options.extensions && is_on_tonic(&notes, &DUMP_CHORD) {
+        return Ok(MidiInstruction::new_dump_tape());
+    }
+    if !options.reads_calls() && is_on_tonic(&notes, &CALL_CHORD) {
+        return Err(MParseError::NeedsExtensions);
+    }
+    parse_chord(notes, &c_major)
+}
+
+/// Scale degree in C major of the chord root `c_major` reads as `kind`, taking
+/// loops as their opening chord.
+pub(crate) fn c_major_root(kind: &MidiInstructionKind) -> u8 {
+    match kind {
+        IncrementCell { amount } if amount.0 < 0 => 5,
+        IncrementCell { .. } => 9,
+        MovePointer { amount } if *amount < 0 => 2,
+        MovePointer { .. } => 4,
+        OutputCell | InputCell => 11,
+        Loop { .. } => 7,
+        Breakpoint | Call { .. } | Random | DumpTape => 0,
+    }
+}
+
+pub fn parse(midi: midly::Smf) -> MParseResult<MidiAST> {
+    parse_with_source_map(midi).map(|(ast, _)| ast)
+}
+
+/// Parses `midi` like `parse`, also recording where each instruction's chord starts.
+pub fn parse_with_source_map(midi: midly::Smf) -> MParseResult<(MidiAST, SourceMap)> {
+    let (tracks, total) = parsed_tracks(&midi);
+    let (ast, source_map, _) = parse_chords(midi.header, tracks, total, &Includes::default(), &ParseOptions::default(), &mut |_, pushed| {
+        pushed.is_ok()
+    });
+    Ok((ast?, source_map))
+}
+
+/// Parses `midi` like `parse_with_source_map`, but carries on past chords that
+/// aren't instructions to find every error, and keeps every chord it read.
+pub fn parse_all(midi: midly::Smf) -> Parsed {
+    parse_all_in(midi, &Includes::default(), &ParseOptions::default())
+}
+
+/// Parses `midi` like `parse_all`, with the files it includes found by
+/// `includes` and read with `options`.
+pub fn parse_all_in(midi: midly::Smf, includes: &Includes, options: &ParseOptions) -> Parsed {
+    let (tracks, total) = parsed_tracks(&midi);
+    collect_parsed(midi.header, tracks, total, includes, options)
+}
+
+/// Parses the MIDI file in `bytes` like `parse_all`, reading events straight
+/// out of the file as it goes instead of reading every event of it first.
+/// Only fails when the file has no MIDI header, events midly can't read end
+/// their track like they do in `Smf::parse`.
+pub fn parse_all_bytes(bytes: &[u8]) -> midly::Result<Parsed> {
+    parse_all_bytes_in(bytes, &Includes::default(), &ParseOptions::default())
+}
+
+/// Parses the MIDI file in `bytes` like `parse_all_bytes`, with the files it
+/// includes found by `includes` and read with `options`.
+pub fn parse_all_bytes_in(bytes: &[u8], includes: &Includes, options: &ParseOptions) -> midly::Result<Parsed> {
+    let (header, tracks) = midly::parse(bytes)?;
+    let tracks = tracks.collect::<midly::Result<Vec<_>>>()?;
+    // bytes stand in for events in the progress bar
+    let total = tracks.iter().map(|events| events.unread().len() as u64).sum();
+    let tracks = tracks.into_iter().map(|events| move |progress: &Progress| read_track(raw_events(events), progress));
+    Ok(collect_parsed(header, tracks.collect(), total, includes, options))
+}
+
+// how to read every track of `midi`, each event worth one event of progress
+fn parsed_tracks<'a>(midi: &'a midly::Smf) -> (Vec<impl FnOnce(&Progress) -> TrackReading + Send + 'a>, u64) {
+    let tracks = midi
+        .tracks
+        .iter()
+        .map(|track| |progress: &Progress| read_track(track.iter().map(|event| (*event, 1)), progress))
+        .collect();
+    (tracks, midi.tracks.iter().map(|track| track.len() as u64).sum())
+}
+
+/// The events midly reads out of a track as it goes, each worth the bytes it
+/// took of progress.
+pub(crate) fn raw_events(mut events: EventIter) -> impl Iterator<Item = (TrackEvent, u64)> + Send {
+    std::iter::from_fn(move || {
+        let before = events.unread().len();
+        let event = events.next()?.ok()?;
+        Some((event, (before - events.unread().len()) as u64))
+    })
+}
+
+/// Parses the tracks that `tracks` read like `parse_all`, `total` being what
+/// their progress adds up to.
+pub(crate) fn collect_parsed<T>(header: Header, tracks: Vec<T>, total: u64, includes: &Includes, options: &ParseOptions) -> Parsed
+where
+    T: FnOnce(&Progress) -> TrackReading + Send,
+{
+    let mut errors = vec![];
+    let mut chords = vec![];
+    let mut instructions = vec![];
+    let (ast, source_map, data) = parse_chords(header, tracks, total, includes, options, &mut |chord, result| {
+        match result {
+            Ok(()) => instructions.push(chords.len()),
+            Err(error) => errors.push(LocatedError {
+                error: error.clone(),
+                tick: Some(chord.tick),
+                chord: Some(chords.len()),
+            }),
+        }
+        chords.push(chord);
+        true
+    });
+    match &ast {
+        Err(MParseError::NoTracks) => errors.push(LocatedError {
+            error: MParseError::NoTracks,
+            tick: None,
+            chord: None,
+        }),
+        Err(MParseError::UnclosedLoop(loops)) => {
+            for pos in loops {
+                errors.push(LocatedError {
+                    error: MParseError::UnclosedLoop(vec![*pos]),
+                    tick: source_map.tick(pos.start()),
+                    chord: instructions.get(pos.start()).copied(),
+                });
+            }
+        }
+        _ => {}
+    }
+    Parsed {
+        ast,
+        source_map,
+        errors,
+        chords,
+        instructions,
+        data,
+    }
+}
+
+/// Reads the chords of every track, as `tracks` read them, into an AST,
+/// calling `on_chord` with every chord and whether it went into the AST.
+/// Chords that don't are skipped when `on_chord` returns true, otherwise
+/// parsing stops at the first one. `total` is what the progress of every track
+/// adds up to, and the files directives include are found by `includes`.
+/// Also returns the cells of the data track when `options` has one.
+fn parse_chords<T>(
+    header: Header,
+    tracks: Vec<T>,
+    total: u64,
+    includes: &Includes,
+    options: &ParseOptions,
+    on_chord: &mut dyn FnMut(Chord, &MParseResult<()>) -> bool,
+) -> (MParseResult<MidiAST>, SourceMap, Vec<u8>)
+where
+    T: FnOnce(&Progress) -> TrackReading + Send,
+{
 
     info!("Starting to parse MIDI file...");
 
     let mut ast_builder = MidiASTBuilder::new();
+    let mut source_map = SourceMap::new(header.timing);
+
+    if tracks.is_empty() {
+        return (Err(MParseError::NoTracks), source_map, vec![])
+    }
+
+    debug!("MIDI File Header: {:?}", header);
+    let progress = Progress::new("Parsing", total);
+    // the tracks of parallel files play at once, so they can be read at once
+    // too. Either way they go into the AST one after another
+    let tracks: Box<dyn Iterator<Item = TrackReading> + '_> =
+        if cfg!(feature = "parallel") && header.format == Format::Parallel && tracks.len() > 1 {
+            Box::new(read_tracks(tracks, &progress).into_iter())
+        } else {
+            Box::new(tracks.into_iter().map(|read| read(&progress)))
+        };
+
+    // notes still held at the end of a track go in the next chord
+    let mut held: Vec<u8> = vec![];
+    // the function calls call, named by the last text event before them
+    let mut function = None;
+    let mut data = vec![];
+    for (idx, track) in tracks.enumerate() {
+        if options.data_track == Some(idx) {
+            data = track_data(track);
+            info!("Read {} cells of data from track {}", data.len(), idx);
+            continue;
+        }
+        let mut items = track.items;
+        // held notes go in the first chord played, before it can open a string
+        let first = items.iter_mut().find(|item| !matches!(item, TrackItem::Tempo(..) | TrackItem::TimeSignature(..) | TrackItem::Function(..)));
+        if let Some(TrackItem::Chord { notes, .. }) = first {
+            notes.append(&mut held);
+            notes.sort_unstable();
+        }
+        for item in read_strings(items) {
+            // directives are played as their chords, all of them at their tick
+            let chords = match item {
+                TrackItem::Chord { tick, end, notes } => vec![(tick, end, Ok(notes))],
+                TrackItem::Directive(tick, directive) => match directive_chords(directive, includes, options) {
+                    Ok(chords) => chords.into_iter().map(|notes| (tick, tick, Ok(notes))).collect(),
+                    Err(why) => vec![(tick, tick, Err(MParseError::Directive(why)))],
+                },
+                // and so are strings
+                TrackItem::String(tick, Ok(bytes)) => crate::sysex::set_cells(&bytes).into_iter().map(|notes| (tick, tick, Ok(notes))).collect(),
+                TrackItem::String(tick, Err(missing)) => vec![(tick, tick, Err(MParseError::ShortString(missing)))],
+                TrackItem::Tempo(tick, tempo) => {
+                    source_map.push_tempo(tick, tempo);
+                    continue;
+                }
+                TrackItem::TimeSignature(tick, numerator, denominator) => {
+                    source_map.push_time_signature(tick, numerator, denominator);
+                    continue;
+                }
+                TrackItem::Function(_, name) => {
+                    function = Some(name);
+                    continue;
+                }
+            };
+            for (tick, end, notes) in chords {
+                let chord = match notes {
+                    Ok(mut notes) => {
+                        if !held.is_empty() {
+                            notes.append(&mut held);
+                            notes.sort_unstable();
+                        }
+                        // TODO: Figure out what song the key is in, for now everything is in C major
+                        let reading = read_chord_in(notes.clone(), options).and_then(|inst| match inst.instruction {
+                            Call { .. } => function.clone().map(MidiInstruction::new_call).ok_or(MParseError::UnnamedCall),
+                            _ => Ok(inst),
+                        });
+                        Chord { tick, end, reading, notes }
+                    }
+                    Err(err) => Chord { tick, end, reading: Err(err), notes: vec![] },
+                };
+                let pushed = chord.reading.clone().and_then(|node| {
+                    debug!("Parsing successful: {:?}", node);
+                    ast_builder.push(node)
+                });
+                if pushed.is_ok() {
+                    source_map.push_instruction(chord.tick);
+                }
+                if !on_chord(chord, &pushed) {
+                    if let Err(err) = pushed {
+                        return (Err(err), source_map, data)
+                    }
+                }
+            }
+        }
+        held.extend(track.held);
+    }
+
+    (ast_builder.into_mast(), source_map, data)
+}
+
+// the notes of the data track, lowest first in every chord. Anything else on
+// it isn't data
+fn track_data(track: TrackReading) -> Vec<u8> {
+    let chords = track.items.into_iter().filter_map(|item| match item {
+        TrackItem::Chord { notes, .. } => Some(notes),
+        _ => None,
+    });
+    chords.flatten().chain(track.held).collect()
+}
+
+// the chords `directive` stands for, read or not, with includes found by
+// `includes` or in the include directories of `options`
+fn directive_chords(directive: Result<Directive, String>, includes: &Includes, options: &ParseOptions) -> Result<Vec<Vec<u8>>, String> {
+    directive?.chords(|name| included_chords(name, includes, options))
+}
+
+// the chords of the prelude riff `name`, or of every track of the file `name`
+// one after another with directives played as theirs
+fn included_chords(name: &str, includes: &Includes, options: &ParseOptions) -> Result<Vec<Vec<u8>>, String> {
+    if let Some(riff) = name.strip_prefix(PRELUDE_DIR).and_then(Riff::find) {
+        info!("Including {} from the prelude", riff.name);
+        return Ok(riff.chords());
+    }
+    if options.sandboxed {
+        return Err(format!("can't include {}, only riffs of the prelude can be included here", name));
+    }
+    let (path, includes) = includes.enter(name, &options.include_dirs)?;
+    info!("Including {}", path.display());
+    let bytes = std::fs::read(&path).map_err(|err| format!("can't include {}, {}", name, err))?;
+    let midi = midly::Smf::parse(&bytes).map_err(|err| format!("can't include {}, {}", name, err))?;
+    let mut chords = vec![];
+    for track in &midi.tracks {
+        for item in read_strings(read_track(track.iter().map(|event| (*event, 1)), &Progress::default()).items) {
+            match item {
+                TrackItem::Chord { notes, .. } => chords.push(notes),
+                TrackItem::Directive(_, directive) => chords.extend(directive_chords(directive, &includes, options)?),
+                TrackItem::String(_, Ok(bytes)) => chords.extend(crate::sysex::set_cells(&bytes)),
+                TrackItem::String(_, Err(missing)) => return Err(format!("{} ends in a string literal {} notes short", name, missing)),
+                TrackItem::Tempo(..) | TrackItem::TimeSignature(..) | TrackItem::Function(..) => {}
+            }
+        }
+    }
+    Ok(chords)
+}
+
+/// What a track says, read apart from the other tracks.
+#[derive(Debug, PartialEq, Eq, Clone, Serialize, Deserialize)]
+pub(crate) struct TrackReading {
+    items: Vec<TrackItem>,
+    /// Notes still held when the track ends
+    held: Vec<u8>,
+}
+
+#[derive(Debug, PartialEq, Eq, Clone, Serialize, Deserialize)]
+enum TrackItem {
+    /// Start tick, tick of the last release and sorted notes
+    Chord { tick: u64, end: u64, notes: Vec<u8> },
+    /// Tick and microseconds per quarter
+    Tempo(u64, u32),
+    /// Tick, numerator and power of two of the denominator
+    TimeSignature(u64, u8, u8),
+    /// Tick and the directive of a midilang SysEx message, or why it can't be read
+    Directive(u64, Result<Directive, String>),
+    /// Tick of the chord opening a string literal and its bytes, or how many
+    /// of them are missing. Only `read_strings` makes them
+    String(u64, Result<Vec<u8>, usize>),
+    /// Tick and the name of a text event, the external function calls after
+    /// it call
+    Function(u64, String),
+}
 
-    // TODO: Figure out what song the key is in, for now everything is in C major
-    let program_key = |xx| parse_chord(xx, &c_major);
+// whether `text` can name a C function
+fn is_function_name(text: &[u8]) -> bool {
+    let starts_right = text.first().is_some_and(|byte| byte.is_ascii_alphabetic() || *byte == b'_');
+    starts_right && text.iter().all(|byte| byte.is_ascii_alphanumeric() || *byte == b'_')
+}
 
-    if midi.tracks.is_empty() {
-        return Err(MParseError::NoTracks)
+// the items of a track with every chord opening a string literal, and the
+// notes after it that are its bytes, read as the string
+fn read_strings(items: Vec<TrackItem>) -> Vec<TrackItem> {
+    let mut read = Vec::with_capacity(items.len());
+    // tick of the opening chord, bytes so far and how many are still missing
+    let mut string: Option<(u64, Vec<u8>, usize)> = None;
+    for item in items {
+        let TrackItem::Chord { tick, notes, .. } = &item else {
+            read.push(item);
+            continue;
+        };
+        if let Some((start, mut bytes, missing)) = string.take() {
+            let taken = notes.len().min(missing);
+            if taken < notes.len() {
+                warn!("Leaving out the {} notes of the chord at tick {} after the end of its string", notes.len() - taken, tick);
+            }
+            bytes.extend_from_slice(&notes[..taken]);
+            match missing - taken {
+                0 => read.push(TrackItem::String(start, Ok(bytes))),
+                missing => string = Some((start, bytes, missing)),
+            }
+            continue;
+        }
+        let decoded = decode_chord(notes);
+        if decoded.root == STRING_ROOT && !notes.is_empty() {
+            debug!("String literal of {} notes at tick {}", decoded.amount, tick);
+            string = Some((*tick, vec![], decoded.amount.max(1) as usize));
+        } else {
+            read.push(item);
+        }
+    }
+    if let Some((start, _, missing)) = string {
+        read.push(TrackItem::String(start, Err(missing)));
     }
+    read
+}
+
+#[cfg(feature = "parallel")]
+fn read_tracks<T: FnOnce(&Progress) -> TrackReading + Send>(tracks: Vec<T>, progress: &Progress) -> Vec<TrackReading> {
+    use rayon::prelude::*;
+    // collecting keeps the tracks in order
+    tracks.into_par_iter().map(|read| read(progress)).collect()
+}
+
+#[cfg(not(feature = "parallel"))]
+fn read_tracks<T: FnOnce(&Progress) -> TrackReading>(tracks: Vec<T>, progress: &Progress) -> Vec<TrackReading> {
+    tracks.into_iter().map(|read| read(progress)).collect()
+}
 
+/// Reads the chords and meta events of a track, counting the progress of its
+/// events in `progress`.
+pub(crate) fn read_track<'a>(track: impl Iterator<Item = (TrackEvent<'a>, u64)>, progress: &Progress) -> TrackReading {
+    let mut items = vec![];
     let mut current_node = BinaryHeap::<u8>::new();
-    debug!("MIDI File Header: {:?}", midi.header);
-    for track in midi.tracks {
-        let mut notes_on: i32 = 0;
-        for (_, te) in track.iter().enumerate() {
-            if let midly::TrackEventKind::Midi{channel: _, message} = te.kind {
+    let mut notes_on: i32 = 0;
+    let mut tick: u64 = 0;
+    let mut chord_start: u64 = 0;
+    for (te, done) in track {
+        progress.inc(done);
+        tick += u64::from(u32::from(te.delta));
+        match te.kind {
+            TrackEventKind::Midi{channel: _, message} => {
                 debug!("Processing {:?}", message);
                 match message {
                     MidiMessage::NoteOn{key, vel: _} => {
                         debug!("{} pressed: {} -> {}", key, notes_on, notes_on + 1);
+                        if notes_on == 0 {
+                            chord_start = tick;
+                        }
                         current_node.push(u8::from(key));
                         notes_on += 1;
                     },
@@ -286,26 +996,46 @@ pub fn parse(midi: midly::Smf) -> MParseResult<MidiAST> {
                         if notes_on == 0 {
                             debug!("All notes are off, parsing instruction...");
                             debug!("parsing {:?}", current_node);
-                            match program_key(current_node.into_sorted_vec()) {
-                                Ok(node) => {
-                                    debug!("Parsing successful: {:?}", node);
-                                    ast_builder.push(node)?;
-
-                                },
-                                Err(err) => return Err(err) 
-                            }
-                            current_node = BinaryHeap::<u8>::new();
+                            let notes = std::mem::take(&mut current_node).into_sorted_vec();
+                            items.push(TrackItem::Chord { tick: chord_start, end: tick, notes });
                         }
                     },
                     _ => {
                         debug!("Ignoring non-midi message...");
                     }
                 }
-            }
+            },
+            TrackEventKind::SysEx(data) => {
+                if let Some(directive) = Directive::from_sysex(data) {
+                    debug!("Directive {:?} at tick {}", directive, tick);
+                    items.push(TrackItem::Directive(tick, directive));
+                }
+            },
+            TrackEventKind::Meta(MetaMessage::Marker(text)) => {
+                if let Some(directive) = Directive::from_marker(text) {
+                    debug!("Directive {:?} at tick {}", directive, tick);
+                    items.push(TrackItem::Directive(tick, Ok(directive)));
+                }
+            },
+            // text events that aren't names, like the `expect:` of tests,
+            // are left to whatever reads them
+            TrackEventKind::Meta(MetaMessage::Text(text)) if is_function_name(text) => {
+                let name = String::from_utf8_lossy(text).into_owned();
+                debug!("Calls name {} from tick {}", name, tick);
+                items.push(TrackItem::Function(tick, name));
+            },
+            TrackEventKind::Meta(MetaMessage::Tempo(tempo)) => {
+                debug!("Tempo of {} microseconds per quarter at tick {}", tempo, tick);
+                items.push(TrackItem::Tempo(tick, tempo.as_int()));
+            },
+            TrackEventKind::Meta(MetaMessage::TimeSignature(numerator, denominator, ..)) => {
+                debug!("Time signature {}/{} at tick {}", numerator, 1 << denominator.min(7), tick);
+                items.push(TrackItem::TimeSignature(tick, numerator, denominator));
+            },
+            _ => {}
         }
     }
-
-    ast_builder.into_mast()
+    TrackReading { items, held: current_node.into_sorted_vec() }
 }
 
 #[cfg(test)]
@@ -362,6 +1092,37 @@ mod tests {
         assert_eq!(key(non_diatonic).unwrap_err(), MParseError::NonDiatonic);
     }
 
+    #[test]
+    fn parse_chord_args_wider_than_a_byte() {
+        let key = |xx| parse_chord(xx, &c_major);
+        let submediant_chord = Vec::from([9, 20, 21, 29]); // 100000001b = 257
+        assert_eq!(key(submediant_chord).unwrap(), MidiInstruction::new_inc(Wrapping(257)));
+    }
+
+    #[test]
+    fn parse_chord_breakpoint() {
+        let key = |xx| parse_chord(xx, &c_major);
+        assert_eq!(key(Vec::from([0, 3, 6])).unwrap(), MidiInstruction::new_breakpoint());
+        assert_eq!(key(Vec::from([48, 51, 54, 60])).unwrap(), MidiInstruction::new_breakpoint());
+        // the tonic has to be at the bottom, and nothing else can be in there
+        assert_eq!(key(Vec::from([3, 6, 12])).unwrap_err(), MParseError::NonDiatonic);
+        assert_eq!(key(Vec::from([0, 3, 6, 10])).unwrap(), MidiInstruction::new_close_loop());
+        // a diminished seventh is a call, named later
+        assert_eq!(key(Vec::from([0, 3, 6, 9])).unwrap(), MidiInstruction::new_call(String::new()));
+    }
+
+    #[test]
+    fn decode_chord_takes_any_notes() {
+        assert_eq!(decode_chord(&[]).amount, 1);
+        // out of order, below the base and the lowest note
+        let unsorted = decode_chord(&[9, 13, 14, 12, 2]);
+        assert_eq!((unsorted.root, unsorted.base, unsorted.bits, unsorted.breakpoint), (9, Some(13), vec![0], false));
+        // the highest bit played more times than an argument can count
+        let mut held = vec![9, 13];
+        held.resize(9_000_000, 22);
+        assert_eq!(decode_chord(&held).amount, i32::MAX);
+    }
+
     #[test]
     fn build_no_loops() {
         let mut mast_builder = MidiASTBuilder::new();
@@ -376,11 +1137,11 @@ mod tests {
         assert!(mast_builder.push(MidiInstruction::new_inc(Wrapping(3))).is_ok());
         assert!(mast_builder.push(MidiInstruction::new_move(1)).is_ok());
         assert!(mast_builder.push(MidiInstruction::new_inc(Wrapping(4))).is_ok());
+        assert_eq!(mast_builder.size, 11);
         match mast_builder.into_mast() {
             Err(_) => panic!(),
             Ok(prog) => {
                 assert_eq!(prog.len(), 11);
-                assert_eq!(mast_builder.size, 11);
             }
         }
         // mast_builder.push
@@ -395,11 +1156,11 @@ mod tests {
         assert!(mast_builder.push(MidiInstruction::new_move(12)).is_ok());
         assert!(mast_builder.push(MidiInstruction::new_inc(Wrapping(-1))).is_ok());
         assert!(mast_builder.push(MidiInstruction::new_close_loop()).is_ok());
+        assert_eq!(mast_builder.size, 6);
         match mast_builder.into_mast() {
             Err(_) => panic!(),
             Ok(mut prog) => {
                 assert_eq!(prog.len(), 1);
-                assert_eq!(mast_builder.size, 6);
                 assert_eq!(prog.pop().unwrap().position.unwrap(), Position::new(0, 5));
             }
         }
@@ -424,11 +1185,11 @@ mod tests {
         assert!(mast_builder.push(MidiInstruction::new_move(-1)).is_ok());
         assert!(mast_builder.push(MidiInstruction::new_inc(Wrapping(-1))).is_ok());
         assert!(mast_builder.push(MidiInstruction::new_close_loop()).is_ok());
+        assert_eq!(mast_builder.size, 13);
         match mast_builder.into_mast() {
             Err(e) => panic!("{:?}", e),
             Ok(mut prog) => {
                 assert_eq!(prog.len(), 2);
-                assert_eq!(mast_builder.size, 13);
                 if let MidiInstruction { 
                     position: pos,
                     instruction: Loop {
@@ -446,4 +1207,253 @@ mod tests {
         }
 
     }
+
+    #[test]
+    fn build_a_million_instructions() {
+        let mut mast_builder = MidiASTBuilder::new();
+        for _ in 0..1000 {
+            assert!(mast_builder.push(MidiInstruction::new_open_loop()).is_ok());
+            for _ in 0..998 {
+                assert!(mast_builder.push(MidiInstruction::new_inc(Wrapping(1))).is_ok());
+            }
+            assert!(mast_builder.push(MidiInstruction::new_close_loop()).is_ok());
+        }
+        assert!(mast_builder.push(MidiInstruction::new_open_loop()).is_ok());
+        assert_eq!(mast_builder.size, 1_000_001);
+        assert_eq!(mast_builder.into_mast().unwrap_err(), MParseError::UnclosedLoop(vec![Position::new(1_000_000, 1_000_000)]));
+
+        let mut mast_builder = MidiASTBuilder::new();
+        for _ in 0..1_000_000 {
+            assert!(mast_builder.push(MidiInstruction::new_open_loop()).is_ok());
+            assert!(mast_builder.push(MidiInstruction::new_close_loop()).is_ok());
+        }
+        let prog = mast_builder.into_mast().unwrap();
+        assert_eq!(prog.len(), 1_000_000);
+        assert_eq!(prog[999_999].position, Some(Position::new(1_999_998, 1_999_999)));
+    }
+
+    #[test]
+    fn parses_events_as_it_reads_them() {
+        let mut bytes = vec![];
+        crate::brainf_to_smf("+[>,.<]]#").write_std(&mut bytes).unwrap();
+        let read = parse_all(midly::Smf::parse(&bytes).unwrap());
+        let streamed = parse_all_bytes(&bytes).unwrap();
+        assert_eq!((streamed.ast, streamed.chords, streamed.instructions), (read.ast, read.chords, read.instructions));
+        assert_eq!(streamed.source_map.tick(3), read.source_map.tick(3));
+        assert!(parse_all_bytes(b"MThd").is_err());
+    }
+
+    #[test]
+    fn timecode_files_are_located_by_frame() {
+        let mut song = crate::brainf_to_smf("+>.");
+        song.header.timing = midly::Timing::Timecode(midly::Fps::Fps25, 10);
+        let parsed = parse_all(song);
+        assert_eq!(parsed.source_map.bar_beat(1), None);
+        assert_eq!(parsed.source_map.describe(Position::new(1, 1)), "00:00:00:03 (instruction 1)");
+    }
+
+    #[test]
+    fn plays_directives_as_their_chords() {
+        use crate::sysex::{Directive, Includes};
+        use midly::{Smf, TrackEvent};
+
+        let sysex = |directive: &Directive| TrackEvent {
+            delta: 0.into(),
+            kind: TrackEventKind::SysEx(Box::leak(directive.to_sysex().into_boxed_slice())),
+        };
+        let marker = |text: &'static str| TrackEvent {
+            delta: 0.into(),
+            kind: TrackEventKind::Meta(MetaMessage::Marker(text.as_bytes())),
+        };
+        let dir = std::env::temp_dir().join(format!("midilang-include-{}", std::process::id()));
+        std::fs::create_dir_all(dir.join("riffs")).unwrap();
+        crate::brainf_to_smf(".").save(dir.join("riffs/out.mid")).unwrap();
+        // includes are found next to the file including them
+        let mut riff = crate::brainf_to_smf("-");
+        riff.tracks[1].push(marker("include out.mid"));
+        riff.save(dir.join("riffs/minus-out.mid")).unwrap();
+        // two files including each other
+        let mut ping = Smf::new(Header::new(Format::SingleTrack, midly::Timing::Metrical(480.into())));
+        ping.tracks.push(vec![sysex(&Directive::Include("pong.mid".to_owned()))]);
+        ping.save(dir.join("ping.mid")).unwrap();
+        ping.tracks[0] = vec![sysex(&Directive::Include("ping.mid".to_owned()))];
+        ping.save(dir.join("pong.mid")).unwrap();
+
+        let mut song = crate::brainf_to_smf("+");
+        song.tracks[1].push(sysex(&Directive::Brainf(">++".to_owned())));
+        song.tracks[1].push(marker("include riffs/minus-out.mid"));
+        // other manufacturers' messages and other markers are skipped
+        song.tracks[1].push(TrackEvent { delta: 0.into(), kind: TrackEventKind::SysEx(&[0x43, 0x10, 0xF7]) });
+        song.tracks[1].push(marker("chorus"));
+        let read = |song: &Smf, includes: &Includes, options: &ParseOptions| {
+            let mut bytes = vec![];
+            song.write_std(&mut bytes).unwrap();
+            parse_all_bytes_in(&bytes, includes, options).unwrap()
+        };
+        let includes = Includes::for_file(&dir.join("song.mid"));
+        let parse_in = |song: &Smf| read(song, &includes, &ParseOptions::default());
+        let parsed = parse_in(&song);
+        assert_eq!(parsed.ast, parse(crate::brainf_to_smf("+>++-.")));
+        assert_eq!(parsed.source_map.tick(5), parsed.source_map.tick(1));
+        // in memory, includes are only looked for in the include directories
+        assert!(!parse_all(song.clone()).errors.is_empty());
+        let options = ParseOptions { include_dirs: vec![dir.clone()], ..ParseOptions::default() };
+        assert_eq!(read(&song, &Includes::default(), &options).ast, parsed.ast);
+        // and never outside of them
+        let mut escaping = crate::brainf_to_smf("+");
+        escaping.tracks[1].push(marker("include ../riffs/out.mid"));
+        escaping.tracks[1].push(marker(Box::leak(format!("include {}", dir.join("riffs/out.mid").display()).into_boxed_str())));
+        let errors = read(&escaping, &Includes::for_file(&dir.join("riffs/song.mid")), &options).errors;
+        assert_eq!(errors.len(), 2);
+        // sandboxed songs don't even get the files next to them
+        assert!(!read(&song, &includes, &ParseOptions::sandboxed()).errors.is_empty());
+
+        song.tracks[1].push(marker("include ping.mid"));
+        song.tracks[1].push(sysex(&Directive::Include("missing.mid".to_owned())));
+        let errors: Vec<String> = parse_in(&song).errors.iter().map(|err| err.error.to_string()).collect();
+        assert_eq!(errors.len(), 2);
+        assert!(errors[0].ends_with("ping.mid ends up including itself"), "{}", errors[0]);
+        assert!(errors[1].contains("missing.mid isn't next to the song"), "{}", errors[1]);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn tracks_go_in_one_after_another() {
+        use crate::formatter::song;
+        use midly::{Track, TrackEvent};
+
+        let whole = parse_all(crate::brainf_to_smf("++[>+++<-]>."));
+        let chords: Vec<Vec<u8>> = whole.chords.iter().map(|chord| chord.notes.clone()).collect();
+        let idx = chords.iter().position(|notes| notes.len() > 1).unwrap();
+
+        // the same chords over three program tracks, the first note of one of
+        // them held over from a track of its own
+        let mut split = song(&chords[..idx]);
+        let held: Track = vec![TrackEvent {
+            delta: 0.into(),
+            kind: TrackEventKind::Midi {
+                channel: 0.into(),
+                message: MidiMessage::NoteOn { key: chords[idx][0].into(), vel: 64.into() },
+            },
+        }];
+        split.tracks.push(held);
+        let mut rest = chords[idx..].to_vec();
+        rest[0].remove(0);
+        split.tracks.extend(song(&rest).tracks.pop());
+
+        let parsed = parse_all(split);
+        assert_eq!(parsed.ast, whole.ast);
+        assert_eq!(parsed.instructions, whole.instructions);
+        assert!(parsed.errors.is_empty());
+    }
+
+    #[test]
+    fn data_tracks_start_the_tape() {
+        use crate::formatter::song;
+        use crate::sysex::Includes;
+
+        let mut smf = crate::brainf_to_smf("[.>]");
+        // "hi" and a chord, whose notes are cells too
+        smf.tracks.extend(song(&[vec![104], vec![105], vec![33, 60]]).tracks.pop());
+        let mut bytes = vec![];
+        smf.write_std(&mut bytes).unwrap();
+        let options = ParseOptions { data_track: Some(DATA_TRACK), ..ParseOptions::default() };
+        let parsed = parse_all_bytes_in(&bytes, &Includes::default(), &options).unwrap();
+        assert_eq!(parsed.ast, parse(crate::brainf_to_smf("[.>]")));
+        assert_eq!(parsed.data, [104, 105, 33, 60]);
+        assert!(parsed.errors.is_empty());
+        // without the option the track is more chords
+        let parsed = parse_all_bytes_in(&bytes, &Includes::default(), &ParseOptions::default()).unwrap();
+        assert!(parsed.data.is_empty());
+        assert!(parsed.chords.len() > 4);
+    }
+
+    #[test]
+    fn strings_set_cells_from_the_pointer() {
+        use crate::formatter::{song, string};
+        use crate::interpreter::Interpreter;
+
+        // +++ and "Hi\n" played over it, then printed
+        let mut chords = crate::brainf_chords("+++");
+        chords.extend(string(b"Hi\n"));
+        chords.extend(crate::brainf_chords("[.>]"));
+        let parsed = parse_all(song(&chords));
+        assert!(parsed.errors.is_empty());
+        let mut output = vec![];
+        Interpreter::new().run(&parsed.ast.unwrap(), &mut &[][..], &mut output).unwrap();
+        assert_eq!(output, b"Hi\n");
+
+        // the bytes of a string can be played together, and D♯ is one of them
+        let together = parse_all(song(&[vec![63, 67, 68, 69], vec![51, 72], vec![33]]));
+        let tape = crate::sysex::set_cells(&[51, 72, 33]);
+        assert_eq!(together.ast, parse(song(&tape)));
+
+        // a D♯ chord for 3 notes followed by 1
+        let short = parse_all(song(&[vec![63, 67, 68, 69], vec![72]]));
+        let errors: Vec<&MParseError> = short.errors.iter().map(|err| &err.error).collect();
+        assert_eq!(errors, [&MParseError::ShortString(2)]);
+    }
+
+    #[test]
+    fn calls_call_the_function_named_before_them() {
+        use crate::formatter::song;
+
+        let chords = [vec![60, 63, 66, 69], vec![71, 74, 77], vec![72, 75, 78, 81]];
+        let mut smf = song(&chords);
+        let text = |text| TrackEvent { delta: 0.into(), kind: TrackEventKind::Meta(MetaMessage::Text(text)) };
+        // before the first chord, after its name, and the text of a test that isn't one
+        smf.tracks[1].insert(1, text(b"toupper"));
+        smf.tracks[1].insert(2, text(b"expect: A"));
+        let extensions = ParseOptions { extensions: true, ..ParseOptions::default() };
+        let read = |smf: &midly::Smf, options: &ParseOptions| {
+            let mut bytes = vec![];
+            smf.write_std(&mut bytes).unwrap();
+            parse_all_bytes_in(&bytes, &Includes::default(), options).unwrap()
+        };
+        let names: Vec<String> = read(&smf, &extensions).ast.unwrap().iter().map(|inst| inst.instruction.describe()).collect();
+        assert_eq!(names, ["call toupper", "output", "call toupper"]);
+
+        let unnamed = read(&song(&chords), &extensions);
+        let errors: Vec<&MParseError> = unnamed.errors.iter().map(|err| &err.error).collect();
+        assert_eq!(errors, [&MParseError::UnnamedCall, &MParseError::UnnamedCall]);
+
+        // plain midilang doesn't read them at all
+        let plain = read(&smf, &ParseOptions::default());
+        let errors: Vec<&MParseError> = plain.errors.iter().map(|err| &err.error).collect();
+        assert_eq!(errors, [&MParseError::NeedsExtensions, &MParseError::NeedsExtensions]);
+        assert!(parse(smf).is_err());
+    }
+
+    #[test]
+    fn augmented_triads_are_random_in_the_extensions_dialect() {
+        use crate::formatter::song;
+
+        let mut bytes = vec![];
+        song(&[vec![60, 64, 68], vec![62, 66, 70]]).write_std(&mut bytes).unwrap();
+        let options = ParseOptions { extensions: true, ..ParseOptions::default() };
+        let parsed = parse_all_bytes_in(&bytes, &Includes::default(), &options).unwrap();
+        let names: Vec<String> = parsed.ast.unwrap().iter().map(|inst| inst.instruction.describe()).collect();
+        // only on the tonic
+        assert_eq!(names.first().map(String::as_str), Some("random"));
+        assert_ne!(names.get(1).map(String::as_str), Some("random"));
+
+        let plain = parse_all_bytes_in(&bytes, &Includes::default(), &ParseOptions::default()).unwrap();
+        let plain = plain.ast.unwrap_or_default();
+        assert!(plain.iter().all(|inst| inst.instruction != MidiInstructionKind::Random));
+    }
+
+    #[test]
+    fn suspended_fourths_dump_the_tape_in_the_extensions_dialect() {
+        use crate::formatter::song;
+
+        let mut bytes = vec![];
+        song(&[vec![48, 53, 55], vec![60, 67, 65, 72]]).write_std(&mut bytes).unwrap();
+        let options = ParseOptions { extensions: true, ..ParseOptions::default() };
+        let parsed = parse_all_bytes_in(&bytes, &Includes::default(), &options).unwrap();
+        let kinds: Vec<MidiInstructionKind> = parsed.ast.unwrap().into_iter().map(|inst| inst.instruction).collect();
+        assert_eq!(kinds, [DumpTape, DumpTape]);
+
+        let plain = parse_all_bytes_in(&bytes, &Includes::default(), &ParseOptions::default()).unwrap();
+        assert!(plain.chords.iter().all(|chord| !matches!(&chord.reading, Ok(inst) if inst.instruction == DumpTape)));
+    }
 }
@@ -0,0 +1,309 @@
+use std::error::Error;
+use std::fmt::{Debug, Display};
+use std::io::{BufRead, Write};
+use std::net::UdpSocket;
+use std::sync::mpsc;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use log::{info, warn};
+#[cfg(unix)]
+use midir::os::unix::VirtualInput;
+use midir::{Ignore, MidiInput, MidiOutput, MidiOutputConnection};
+use midly::Smf;
+
+use crate::error::MidilangError;
+use crate::interpreter::{InterpResult, Interpreter, TapeView, Tracer};
+use crate::live::{read_osc, read_typed, Event, Live};
+use crate::parser::{MidiAST, MidiInstructionKind, Position};
+use crate::profile::ChordTracker;
+use crate::record::{chord_notes, Note};
+
+/// Name midilang shows up as to other MIDI software.
+const CLIENT_NAME: &str = "midilang";
+
+pub type PlayResult<T> = Result<T, PlayError>;
+
+#[derive(PartialEq, Eq)]
+pub enum PlayError {
+    /// The system's MIDI API couldn't be opened
+    Init(String),
+    NoPorts,
+    /// No port has a name containing `wanted`
+    NoSuchPort { wanted: String, available: Vec<String> },
+    Connect(String),
+}
+
+impl Display for PlayError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Init(msg) => write!(f, "Could not open MIDI: {}", msg),
+            Self::NoPorts => write!(f, "There are no MIDI output ports to play on"),
+            Self::NoSuchPort { wanted, available } => {
+                write!(f, "No MIDI port matches {:?}, there are {:?}", wanted, available)
+            }
+            Self::Connect(msg) => write!(f, "Could not connect to the MIDI port: {}", msg),
+        }
+    }
+}
+
+impl Debug for PlayError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        Display::fmt(self, f)
+    }
+}
+
+impl Error for PlayError {}
+
+/// Connects to the first MIDI output port with `port` in its name, or the first
+/// port there is.
+pub fn connect(port: Option<&str>) -> PlayResult<MidiOutputConnection> {
+    let output = MidiOutput::new(CLIENT_NAME).map_err(|err| PlayError::Init(err.to_string()))?;
+    let ports = output.ports();
+    let names: Vec<String> = ports.iter().map(|port| output.port_name(port).unwrap_or_default()).collect();
+    let index = match port {
+        Some(wanted) => find_port(&names, wanted)?,
+        None if ports.is_empty() => return Err(PlayError::NoPorts),
+        None => 0,
+    };
+    info!("Playing on {}", names[index]);
+    output
+        .connect(&ports[index], CLIENT_NAME)
+        .map_err(|err| PlayError::Connect(err.to_string()))
+}
+
+// index of the first of the port `names` with `wanted` in it
+fn find_port(names: &[String], wanted: &str) -> PlayResult<usize> {
+    names.iter().position(|name| name.contains(wanted)).ok_or_else(|| PlayError::NoSuchPort {
+        wanted: wanted.to_owned(),
+        available: names.to_vec(),
+    })
+}
+
+/// Runs the chords played on a MIDI input in `live` as they come, until
+/// midilang is stopped.
+///
+/// midilang registers as a MIDI client other software can be patched into,
+/// a DAW track or a keyboard: an ALSA sequencer client on Linux, a JACK client
+/// when built with the jack feature. It listens on a port of its own called
+/// midilang, or with `port` on the first input port with `port` in its name.
+/// Programs going wrong are only warned about, the session goes on. Commands
+/// come from the lines `typed` and as OSC messages to `osc`, see `read_osc`.
+pub fn listen(
+    port: Option<&str>,
+    live: &mut Live,
+    typed: impl BufRead + Send + 'static,
+    osc: Option<UdpSocket>,
+    output: &mut impl Write,
+) -> PlayResult<()> {
+    let mut midi_input = MidiInput::new(CLIENT_NAME).map_err(|err| PlayError::Init(err.to_string()))?;
+    // clock, active sensing and sysex would only wake the session up
+    midi_input.ignore(Ignore::All);
+    let (sender, events) = mpsc::channel();
+    let mut input = read_typed(typed, sender.clone());
+    if let Some(socket) = osc {
+        read_osc(socket, sender.clone());
+    }
+    let forward = move |_: u64, message: &[u8], _: &mut ()| {
+        // fails only once midilang stopped listening
+        let _ = sender.send(Event::Midi(message.to_vec()));
+    };
+    let connection = match port {
+        Some(wanted) => {
+            let ports = midi_input.ports();
+            let names: Vec<String> = ports.iter().map(|port| midi_input.port_name(port).unwrap_or_default()).collect();
+            let index = find_port(&names, wanted)?;
+            info!("Listening on {}", names[index]);
+            midi_input.connect(&ports[index], CLIENT_NAME, forward, ())
+        }
+        #[cfg(unix)]
+        None => {
+            info!("Listening on {}:{}, patch a MIDI output into it", CLIENT_NAME, CLIENT_NAME);
+            midi_input.create_virtual(CLIENT_NAME, forward, ())
+        }
+        // there are no virtual ports on Windows
+        #[cfg(not(unix))]
+        None => {
+            let ports = midi_input.ports();
+            let first = ports.first().ok_or(PlayError::NoPorts)?;
+            info!("Listening on {}", midi_input.port_name(first).unwrap_or_default());
+            midi_input.connect(first, CLIENT_NAME, forward, ())
+        }
+    };
+    // closes the port when dropped
+    let _connection = connection.map_err(|err| PlayError::Connect(err.to_string()))?;
+    for event in events {
+        let result = match event {
+            Event::Midi(message) => live.message(&message, &mut input, output).map_err(MidilangError::from),
+            Event::Command(line) => live.command(&line, &mut input, output),
+        };
+        if let Err(err) = result {
+            warn!("{}", err);
+        }
+        if let Err(err) = output.flush() {
+            warn!("Could not write the output: {}", err);
+        }
+    }
+    Ok(())
+}
+
+/// Sends the chord of every step to a synth as the interpreter gets to it, one
+/// chord per beat. Each chord is held until the next one starts.
+struct Player<S: FnMut(&[u8]) -> Result<(), String>> {
+    send: S,
+    chords: Vec<Vec<Note>>,
+    tracker: ChordTracker,
+    beat: Duration,
+    /// When the next chord is due, `None` before the first one
+    next: Option<Instant>,
+    sounding: Vec<Note>,
+    /// Set once sending fails, so that the run goes on quietly
+    failed: bool,
+}
+
+impl<S: FnMut(&[u8]) -> Result<(), String>> Player<S> {
+    fn new(send: S, chords: Vec<Vec<Note>>, bpm: u32) -> Self {
+        Player {
+            send,
+            chords,
+            tracker: ChordTracker::default(),
+            beat: Duration::from_secs(60) / bpm.max(1),
+            next: None,
+            sounding: vec![],
+            failed: false,
+        }
+    }
+
+    fn send(&mut self, message: [u8; 3]) {
+        if self.failed {
+            return;
+        }
+        if let Err(err) = (self.send)(&message) {
+            warn!("Stopped playing, could not send to the synth: {}", err);
+            self.failed = true;
+        }
+    }
+
+    /// Waits for the current chord's beat to be over.
+    fn wait(&self) {
+        if let Some(next) = self.next {
+            thread::sleep(next.saturating_duration_since(Instant::now()));
+        }
+    }
+
+    fn release(&mut self) {
+        for note in std::mem::take(&mut self.sounding) {
+            self.send([0x80 | note.channel.as_int(), note.key.as_int(), 0]);
+        }
+    }
+}
+
+impl<S: FnMut(&[u8]) -> Result<(), String>> Tracer for Player<S> {
+    fn on_instruction(&mut self, pos: Option<Position>, kind: &MidiInstructionKind, _: &TapeView) {
+        let Some(chord) = self.tracker.chord(pos, kind) else {
+            return;
+        };
+        self.wait();
+        self.release();
+        let notes = self.chords.get(chord).cloned().unwrap_or_default();
+        for note in &notes {
+            self.send([0x90 | note.channel.as_int(), note.key.as_int(), note.vel.as_int()]);
+        }
+        self.sounding = notes;
+        // keeps to the beat, unless a step took longer than one
+        let due = self.next.map_or_else(Instant::now, |next| next.max(Instant::now()));
+        self.next = Some(due + self.beat);
+    }
+}
+
+impl<S: FnMut(&[u8]) -> Result<(), String>> Drop for Player<S> {
+    fn drop(&mut self) {
+        self.wait();
+        self.release();
+    }
+}
+
+/// Runs `program`, parsed from `source`, in `interp` while playing the chord of
+/// every step it takes on `connection` at `bpm` chords per minute.
+pub fn play(
+    program: &MidiAST,
+    source: &Smf,
+    connection: &mut MidiOutputConnection,
+    bpm: u32,
+    interp: &mut Interpreter,
+    input: &mut impl Read,
+    output: &mut impl Write,
+) -> InterpResult<()> {
+    let send = |message: &[u8]| connection.send(message).map_err(|err| err.to_string());
+    let mut player = Player::new(send, chord_notes(source), bpm);
+    interp.run_traced(program, input, output, &mut player)
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+    use crate::parser::{MidiASTBuilder, MidiInstruction};
+    use midly::num::{u4, u7};
+    use std::io;
+    use std::num::Wrapping;
+
+    #[test]
+    fn plays_a_chord_per_beat() {
+        // +[-]
+        let mut builder = MidiASTBuilder::new();
+        builder.push(MidiInstruction::new_inc(Wrapping(1))).unwrap();
+        builder.push(MidiInstruction::new_open_loop()).unwrap();
+        builder.push(MidiInstruction::new_inc(Wrapping(-1))).unwrap();
+        builder.push(MidiInstruction::new_close_loop()).unwrap();
+        let prog = builder.into_mast().unwrap();
+        let note = |key: u8| vec![Note { channel: u4::from(2), key: u7::from(key), vel: u7::from(90) }];
+        let chords = vec![note(9), note(7), note(5), note(0)];
+
+        let mut sent = vec![];
+        let started = Instant::now();
+        {
+            // 10ms a beat
+            let mut player = Player::new(|message: &[u8]| {
+                sent.push(message.to_vec());
+                Ok(())
+            }, chords, 6000);
+            Interpreter::new().run_traced(&prog, &mut io::empty(), &mut io::sink(), &mut player).unwrap();
+        }
+        assert!(started.elapsed() >= Duration::from_millis(40));
+        assert_eq!(
+            sent,
+            [
+                [0x92, 9, 90],
+                [0x82, 9, 0],
+                [0x92, 7, 90],
+                [0x82, 7, 0],
+                [0x92, 5, 90],
+                [0x82, 5, 0],
+                [0x92, 0, 90],
+                [0x82, 0, 0],
+            ]
+        );
+    }
+
+    #[test]
+    fn goes_on_quietly_when_sending_fails() {
+        let prog = vec![MidiInstruction { position: Some(Position::new(0, 0)), instruction: MidiInstructionKind::OutputCell }];
+        let chords = vec![vec![Note { channel: u4::from(0), key: u7::from(11), vel: u7::from(90) }]];
+        let mut attempts = 0;
+        let mut output = vec![];
+        {
+            let mut player = Player::new(
+                |_: &[u8]| {
+                    attempts += 1;
+                    Err("gone".to_owned())
+                },
+                chords,
+                6000,
+            );
+            Interpreter::new().run_traced(&prog, &mut io::empty(), &mut output, &mut player).unwrap();
+        }
+        assert_eq!(attempts, 1);
+        assert_eq!(output, [0]);
+    }
+}
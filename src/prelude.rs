@@ -0,0 +1,139 @@
+use midly::Smf;
+
+/// Starts the names songs include prelude riffs by, like
+/// `include prelude/print-decimal`. The prelude comes before any file of the
+/// same name.
+pub const PRELUDE_DIR: &str = "prelude/";
+
+/// A routine shipped with midilang, for songs to include instead of playing
+/// its chords by hand.
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub struct Riff {
+    pub name: &'static str,
+    /// What it does to the tape, and what it needs from it
+    pub about: &'static str,
+    brainf: &'static str,
+}
+
+/// Every riff of the prelude, sorted by name.
+pub const RIFFS: &[Riff] = &[
+    Riff {
+        name: "clear-range",
+        about: "Clears the cells from the pointer up to the first one that's already 0, and stops on it",
+        brainf: "[[-]>]",
+    },
+    Riff {
+        name: "copy-cell",
+        about: "Adds the cell to the next one, using the one after that, which has to be 0",
+        brainf: "[->+>+<<]>>[-<<+>>]<<",
+    },
+    Riff {
+        name: "print-decimal",
+        about: "Prints the cell, below 256, as a decimal number, using the 9 cells after it, which have to be 0",
+        brainf: ">>++++++++++<<[->+>-[>+>>]>[+[-<+>]>+>>]<<<<<<]>>[-]>>>++++++++++<[->-[>+>>]>[+[-<+>]>+>>]<<<<<]>[-]>>\
+                 [>++++++[-<++++++++>]<.<<+>+>[-]]<[<[->-<]++++++[->++++++++<]>.[-]]<<++++++[-<++++++++>]<.[-]<<[-<+>]<",
+    },
+    Riff {
+        name: "print-string",
+        about: "Prints the cells from the pointer up to the first 0, and stops on it",
+        brainf: "[.>]",
+    },
+    Riff {
+        name: "read-line",
+        about: "Reads a line into the cells from the pointer on, without its newline, and stops on the 0 after it",
+        brainf: ",----------[++++++++++>,----------]",
+    },
+];
+
+impl Riff {
+    /// The riff called `name`, with or without `PRELUDE_DIR` in front.
+    pub fn find(name: &str) -> Option<&'static Riff> {
+        let name = name.strip_prefix(PRELUDE_DIR).unwrap_or(name);
+        RIFFS.iter().find(|riff| riff.name == name)
+    }
+
+    /// The notes of its chords, lowest first.
+    pub fn chords(&self) -> Vec<Vec<u8>> {
+        crate::brainf_chords(self.brainf)
+    }
+
+    /// The riff as a song of its own, to paste into other songs.
+    pub fn to_smf(&self) -> Smf<'static> {
+        crate::brainf_to_smf(self.brainf)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+    use crate::interpreter::Interpreter;
+    use crate::parser::parse;
+    use std::num::Wrapping;
+
+    // runs the riff `name` on `tape` with `input`, returning the interpreter and
+    // what it printed
+    fn run(name: &str, tape: &[u8], input: &[u8]) -> (Interpreter, Vec<u8>) {
+        let mut interp = Interpreter::new();
+        let mut setup = String::new();
+        for &cell in tape {
+            setup.push_str(&"+".repeat(cell.into()));
+            setup.push('>');
+        }
+        setup.push_str(&"<".repeat(tape.len()));
+        interp.run(&parse(crate::brainf_to_smf(&setup)).unwrap(), &mut &[][..], &mut vec![]).unwrap();
+        let mut output = vec![];
+        let riff = Riff::find(name).unwrap();
+        interp.run(&parse(riff.to_smf()).unwrap(), &mut &input[..], &mut output).unwrap();
+        (interp, output)
+    }
+
+    #[test]
+    fn riffs_are_found_by_name() {
+        assert!(RIFFS.windows(2).all(|pair| pair[0].name < pair[1].name));
+        assert_eq!(Riff::find("prelude/copy-cell"), Riff::find("copy-cell"));
+        assert_eq!(Riff::find("print-number"), None);
+        for riff in RIFFS {
+            assert!(parse(riff.to_smf()).is_ok(), "{} doesn't parse", riff.name);
+        }
+    }
+
+    #[test]
+    fn songs_include_riffs_from_the_prelude() {
+        let mut song = crate::brainf_to_smf("++++++++++++");
+        song.tracks[1].push(midly::TrackEvent {
+            delta: 0.into(),
+            kind: midly::TrackEventKind::Meta(midly::MetaMessage::Marker(b"include prelude/print-decimal")),
+        });
+        let mut bytes = vec![];
+        song.write_std(&mut bytes).unwrap();
+        let mut output = vec![];
+        let ast = crate::parser::parse_all_bytes(&bytes).unwrap().ast.unwrap();
+        Interpreter::new().run(&ast, &mut &[][..], &mut output).unwrap();
+        assert_eq!(output, b"12");
+    }
+
+    #[test]
+    fn print_decimal_keeps_the_cell() {
+        for value in [0, 7, 10, 42, 100, 255] {
+            let (interp, output) = run("print-decimal", &[value], &[]);
+            assert_eq!(output, value.to_string().as_bytes());
+            assert_eq!(interp.pointer(), 0);
+            assert!(interp.tape().len() <= 10);
+            assert!(interp.tape()[1..].iter().all(|&cell| cell == Wrapping(0)));
+            assert_eq!(interp.tape()[0], Wrapping(value.into()));
+        }
+    }
+
+    #[test]
+    fn riffs_do_what_they_say() {
+        let (interp, _) = run("copy-cell", &[5, 2], &[]);
+        assert_eq!((interp.tape(), interp.pointer()), (&[Wrapping(5), Wrapping(7), Wrapping(0)][..], 0));
+        let (interp, _) = run("clear-range", &[1, 2, 3, 0, 4], &[]);
+        assert_eq!((&interp.tape()[..5], interp.pointer()), (&[Wrapping(0), Wrapping(0), Wrapping(0), Wrapping(0), Wrapping(4)][..], 3));
+        let (_, output) = run("print-string", &[104, 105, 0, 33], &[]);
+        assert_eq!(output, b"hi");
+        let (interp, _) = run("read-line", &[], b"ok\nnot this");
+        assert_eq!((&interp.tape()[..3], interp.pointer()), (&[Wrapping(111), Wrapping(107), Wrapping(0)][..], 2));
+    }
+}
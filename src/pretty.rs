@@ -0,0 +1,125 @@
+use std::fmt::Display;
+
+use crate::parser::{MidiInstruction, MidiInstructionKind::*};
+use crate::timing::SourceMap;
+
+/// A program printed as brainf with one instruction per line, loops indented
+/// and each line labelled with the bar and beat of its chord:
+///
+/// ```text
+/// [ bar 1:1 ] + (x8)
+/// [ bar 1:3 ] loop {
+/// [ bar 1:4 ]   >
+/// [ bar 2:1 ]   + (x9)
+///             }
+/// ```
+///
+/// Labels are instruction numbers without a source map, and blank for
+/// instructions that don't know where they came from.
+pub struct PrettyAst<'a> {
+    ast: &'a [MidiInstruction],
+    source_map: Option<&'a SourceMap>,
+}
+
+impl<'a> PrettyAst<'a> {
+    pub fn new(ast: &'a [MidiInstruction], source_map: Option<&'a SourceMap>) -> Self {
+        PrettyAst { ast, source_map }
+    }
+
+    fn label(&self, inst: &MidiInstruction) -> String {
+        let Some(position) = inst.position else {
+            return String::new();
+        };
+        let index = position.start();
+        match (self.source_map.and_then(|map| map.bar_beat(index)), self.source_map.and_then(|map| map.timecode(index))) {
+            (Some(bar_beat), _) => format!("bar {}", bar_beat),
+            (None, Some(timecode)) => format!("at {}", timecode),
+            (None, None) => format!("inst {}", index),
+        }
+    }
+
+    // (label, depth, text) of every line, in order
+    fn lines(&self, program: &[MidiInstruction], depth: usize, lines: &mut Vec<(String, usize, String)>) {
+        let repeat = |up: &str, down: &str, amount: i64| {
+            let inst = if amount < 0 { down } else { up };
+            match amount.unsigned_abs() {
+                1 => inst.to_owned(),
+                times => format!("{} (x{})", inst, times),
+            }
+        };
+        for inst in program {
+            let text = match &inst.instruction {
+                IncrementCell { amount } => repeat("+", "-", i64::from(amount.0)),
+                MovePointer { amount } => repeat(">", "<", *amount as i64),
+                OutputCell => ".".to_owned(),
+                InputCell => ",".to_owned(),
+                Breakpoint => "#".to_owned(),
+                Call { name } => format!("call {}", name),
+                Random => "random".to_owned(),
+                DumpTape => "dump tape".to_owned(),
+                Loop { body } if body.is_empty() => "loop {}".to_owned(),
+                Loop { body } => {
+                    lines.push((self.label(inst), depth, "loop {".to_owned()));
+                    self.lines(body, depth + 1, lines);
+                    lines.push((String::new(), depth, "}".to_owned()));
+                    continue;
+                }
+            };
+            lines.push((self.label(inst), depth, text));
+        }
+    }
+}
+
+impl Display for PrettyAst<'_> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let mut lines = vec![];
+        self.lines(self.ast, 0, &mut lines);
+        let width = lines.iter().map(|(label, _, _)| label.len()).max().unwrap_or(0);
+        for (label, depth, text) in lines {
+            if label.is_empty() {
+                write!(f, "{:gutter$}", "", gutter = width + 4)?;
+            } else {
+                write!(f, "[ {:width$} ]", label, width = width)?;
+            }
+            writeln!(f, " {:indent$}{}", "", text, indent = 2 * depth)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+    use crate::compiler::CellWidth;
+    use crate::MidiProgram;
+
+    #[test]
+    fn prints_indented_brainf() {
+        let bf = ",+++[->>+<<]#.";
+        let program = MidiProgram::from_bf_str(bf).unwrap().optimize(CellWidth::I8);
+        // a chord every quarter note
+        let mut source_map = SourceMap::default();
+        for index in 0..bf.len() as u64 {
+            source_map.push_instruction(index * 480);
+        }
+        assert_eq!(
+            PrettyAst::new(program.ast(), Some(&source_map)).to_string(),
+            "[ bar 1:1 ] ,\n\
+             [ bar 1:2 ] + (x3)\n\
+             [ bar 2:1 ] loop {\n\
+             [ bar 2:2 ]   -\n\
+             [ bar 2:3 ]   > (x2)\n\
+             [ bar 3:1 ]   +\n\
+             [ bar 3:2 ]   < (x2)\n\
+             \x20           }\n\
+             [ bar 4:2 ] .\n"
+        );
+
+        let program = MidiProgram::from_bf_str("+[]#").unwrap();
+        assert_eq!(
+            PrettyAst::new(program.ast(), None).to_string(),
+            "[ inst 0 ] +\n[ inst 1 ] loop {}\n[ inst 3 ] #\n"
+        );
+    }
+}
@@ -0,0 +1,401 @@
+use std::io::{self, Read, Write};
+
+use midly::num::{u28, u4, u7};
+use midly::{MidiMessage, Smf, TrackEvent, TrackEventKind};
+
+use crate::interpreter::{InterpResult, Interpreter, RunOptions, TapeView, Tracer};
+use crate::parser::{CellWidth, MidiAST, MidiInstructionKind, MidiInstructionKind::*, Position};
+use crate::timing::SourceMap;
+
+/// Channel `Profile::mute_uncovered` moves chords that never ran to, the last one.
+pub const MUTED_CHANNEL: u8 = 15;
+
+/// Channel volume, as a MIDI control change number.
+const VOLUME_CONTROLLER: u8 = 7;
+
+/// Number of times every chord of a program was executed, indexed like
+/// `Position`s, with loops counted like `ChordTracker` plays them.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Profile {
+    pub hits: Vec<u64>,
+}
+
+/// Works out which chord a traced instruction was played with.
+///
+/// Loops are played with their opening chord when they're reached, and with
+/// their closing chord every time their condition is checked after the body.
+#[derive(Debug, Default)]
+pub(crate) struct ChordTracker {
+    /// Loops the program is currently inside of, innermost last
+    open_loops: Vec<Position>,
+}
+
+impl ChordTracker {
+    /// Index of the chord for the step `Tracer::on_instruction` was called with,
+    /// `None` for breakpoints, which aren't steps, and unpositioned instructions.
+    pub(crate) fn chord(&mut self, pos: Option<Position>, kind: &MidiInstructionKind) -> Option<usize> {
+        let pos = pos?;
+        // the program left every loop that doesn't contain this chord
+        while self.open_loops.last().is_some_and(|open| pos.start() < open.start() || pos.start() > open.end()) {
+            self.open_loops.pop();
+        }
+        match kind {
+            Loop { .. } if self.open_loops.last() == Some(&pos) => Some(pos.end()),
+            Loop { .. } => {
+                self.open_loops.push(pos);
+                Some(pos.start())
+            }
+            Breakpoint => None,
+            _ => Some(pos.start()),
+        }
+    }
+}
+
+/// Counts executions per chord while the interpreter runs.
+struct Profiler {
+    hits: Vec<u64>,
+    chords: ChordTracker,
+}
+
+impl Tracer for Profiler {
+    fn on_instruction(&mut self, pos: Option<Position>, kind: &MidiInstructionKind, _: &TapeView) {
+        let Some(index) = self.chords.chord(pos, kind) else {
+            return;
+        };
+        if index >= self.hits.len() {
+            self.hits.resize(index + 1, 0);
+        }
+        self.hits[index] += 1;
+    }
+}
+
+/// Runs `program` in the interpreter, with `data` on the tape, and counts how
+/// often each of its chords is executed.
+pub fn profile(
+    program: &MidiAST,
+    cell_width: CellWidth,
+    limits: RunOptions,
+    data: &[u8],
+    input: &mut impl Read,
+    output: &mut impl Write,
+) -> InterpResult<Profile> {
+    let mut interp = Interpreter::with_options(limits);
+    interp.set_cell_width(cell_width);
+    interp.load_data(data);
+    let mut profiler = Profiler { hits: vec![], chords: ChordTracker::default() };
+    interp.run_traced(program, input, output, &mut profiler)?;
+    Ok(Profile { hits: profiler.hits })
+}
+
+impl Profile {
+    fn hits(&self, index: usize) -> u64 {
+        self.hits.get(index).copied().unwrap_or(0)
+    }
+
+    /// Writes one line per chord, hottest first, with its share of all the
+    /// executions, where it is in the song and what it does.
+    ///
+    /// ```text
+    ///     hits   share  bar:beat  instruction
+    ///     2000   41.2%  3:1       add 1
+    /// ```
+    pub fn write_report(&self, program: &MidiAST, source_map: &SourceMap, out: &mut impl Write) -> io::Result<()> {
+        let mut chords = vec![String::new(); source_map.len()];
+        describe_chords(program, &mut chords);
+        let total: u64 = self.hits.iter().sum();
+        let mut order: Vec<usize> = (0..chords.len()).collect();
+        // stable, so chords that ran equally often stay in song order
+        order.sort_by_key(|&index| std::cmp::Reverse(self.hits(index)));
+
+        writeln!(out, "{:>10}  {:>6}  {:<8}  instruction", "hits", "share", "bar:beat")?;
+        for index in order {
+            let hits = self.hits(index);
+            let share = if total == 0 { 0.0 } else { hits as f64 * 100.0 / total as f64 };
+            let location = match source_map.bar_beat(index) {
+                Some(bar_beat) => bar_beat.to_string(),
+                None => format!("#{}", index),
+            };
+            writeln!(out, "{:>10}  {:>5.1}%  {:<8}  {}", hits, share, location, chords[index])?;
+        }
+        Ok(())
+    }
+
+    /// Turns the velocity of every note into how hot its chord is, so the
+    /// hottest chords are played loudest.
+    ///
+    /// Velocities grow with the logarithm of the hits, since hot loops run many
+    /// orders of magnitude more often than the rest. Chords that never ran get
+    /// the quietest velocity that is still a note.
+    pub fn heatmap(&self, midi: &mut Smf) {
+        let hottest = self.hits.iter().copied().max().unwrap_or(0);
+        let velocity = |hits: u64| {
+            if hottest == 0 {
+                return u7::from(1);
+            }
+            let heat = (hits as f64).ln_1p() / (hottest as f64).ln_1p();
+            u7::from(1 + (heat * 126.0).round() as u8)
+        };
+
+        visit_chords(midi, |chord, notes| {
+            let heat = velocity(self.hits(chord));
+            for note in notes {
+                if let TrackEventKind::Midi { message: MidiMessage::NoteOn { vel, .. }, .. } = note {
+                    *vel = heat;
+                }
+            }
+        });
+    }
+
+    /// Number of chords that ran at least once.
+    pub fn covered(&self) -> usize {
+        self.hits.iter().filter(|&&hits| hits > 0).count()
+    }
+
+    /// Adds up the hits of `other`, to cover a program over several runs.
+    pub fn merge(&mut self, other: &Profile) {
+        if other.hits.len() > self.hits.len() {
+            self.hits.resize(other.hits.len(), 0);
+        }
+        for (hits, other_hits) in self.hits.iter_mut().zip(&other.hits) {
+            *hits += other_hits;
+        }
+    }
+
+    /// Writes how many of the chords ran, followed by every chord that didn't.
+    ///
+    /// ```text
+    /// 12 of 15 chords executed (80.0%)
+    /// not executed:
+    ///   3:2       #9    output
+    /// ```
+    pub fn write_coverage(&self, program: &MidiAST, source_map: &SourceMap, out: &mut impl Write) -> io::Result<()> {
+        let mut chords = vec![String::new(); source_map.len()];
+        describe_chords(program, &mut chords);
+        let covered = (0..chords.len()).filter(|&index| self.hits(index) > 0).count();
+        let share = if chords.is_empty() { 100.0 } else { covered as f64 * 100.0 / chords.len() as f64 };
+        writeln!(out, "{} of {} chords executed ({:.1}%)", covered, chords.len(), share)?;
+        if covered == chords.len() {
+            return Ok(());
+        }
+        writeln!(out, "not executed:")?;
+        for (index, chord) in chords.iter().enumerate().filter(|&(index, _)| self.hits(index) == 0) {
+            let location = match source_map.bar_beat(index) {
+                Some(bar_beat) => bar_beat.to_string(),
+                None => String::new(),
+            };
+            writeln!(out, "  {:<8}  #{:<4} {}", location, index, chord)?;
+        }
+        Ok(())
+    }
+
+    /// Moves the notes of every chord that never ran to `MUTED_CHANNEL`, and
+    /// turns that channel's volume all the way down.
+    pub fn mute_uncovered(&self, midi: &mut Smf) {
+        let mut muted_any = false;
+        visit_chords(midi, |chord, notes| {
+            if self.hits(chord) > 0 {
+                return;
+            }
+            muted_any = true;
+            for note in notes {
+                if let TrackEventKind::Midi { channel, .. } = note {
+                    *channel = u4::from(MUTED_CHANNEL);
+                }
+            }
+        });
+        if let (true, Some(track)) = (muted_any, midi.tracks.first_mut()) {
+            let silence = MidiMessage::Controller { controller: u7::from(VOLUME_CONTROLLER), value: u7::from(0) };
+            track.insert(0, TrackEvent {
+                delta: u28::from(0),
+                kind: TrackEventKind::Midi { channel: u4::from(MUTED_CHANNEL), message: silence },
+            });
+        }
+    }
+}
+
+/// Calls `visit` with the index of every chord in `midi` and its note on and off
+/// events, reading chords the same way as the parser does, across all tracks.
+pub(crate) fn visit_chords<'a, F: FnMut(usize, &mut [&mut TrackEventKind<'a>])>(midi: &mut Smf<'a>, mut visit: F) {
+    let mut chord = 0;
+    for track in midi.tracks.iter_mut() {
+        let mut notes_on: i32 = 0;
+        let mut notes = vec![];
+        for event in track.iter_mut() {
+            match event.kind {
+                TrackEventKind::Midi { message: MidiMessage::NoteOn { .. }, .. } => {
+                    notes.push(&mut event.kind);
+                    notes_on += 1;
+                }
+                TrackEventKind::Midi { message: MidiMessage::NoteOff { .. }, .. } => {
+                    notes.push(&mut event.kind);
+                    notes_on -= 1;
+                    if notes_on == 0 {
+                        visit(chord, &mut notes);
+                        notes.clear();
+                        chord += 1;
+                    }
+                }
+                _ => {}
+            }
+        }
+    }
+}
+
+/// Describes every chord of `program`, loops both by their opening and closing chord.
+fn describe_chords(program: &MidiAST, chords: &mut [String]) {
+    for inst in program {
+        let Some(pos) = inst.position else {
+            continue;
+        };
+        if let Some(chord) = chords.get_mut(pos.start()) {
+            *chord = inst.instruction.describe();
+        }
+        if let Loop { body } = &inst.instruction {
+            if let Some(chord) = chords.get_mut(pos.end()) {
+                *chord = format!("end of the loop at {}", pos.start());
+            }
+            describe_chords(body, chords);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+    use crate::parser::{MidiASTBuilder, MidiInstruction};
+    use midly::num::u15;
+    use midly::{Format, Header, Timing};
+    use std::num::Wrapping;
+
+    fn build(insts: Vec<MidiInstruction>) -> MidiAST {
+        let mut builder = MidiASTBuilder::new();
+        for inst in insts {
+            builder.push(inst).unwrap();
+        }
+        builder.into_mast().unwrap()
+    }
+
+    // +++[>++[-]<-]
+    fn nested_loops() -> MidiAST {
+        build(vec![
+            MidiInstruction::new_inc(Wrapping(3)),
+            MidiInstruction::new_open_loop(),
+            MidiInstruction::new_move(1),
+            MidiInstruction::new_inc(Wrapping(2)),
+            MidiInstruction::new_open_loop(),
+            MidiInstruction::new_inc(Wrapping(-1)),
+            MidiInstruction::new_close_loop(),
+            MidiInstruction::new_move(-1),
+            MidiInstruction::new_inc(Wrapping(-1)),
+            MidiInstruction::new_close_loop(),
+        ])
+    }
+
+    #[test]
+    fn counts_every_chord() {
+        let prog = nested_loops();
+        let profile = profile(&prog, CellWidth::I8, RunOptions::default(), &[], &mut io::empty(), &mut io::sink()).unwrap();
+        assert_eq!(profile.hits, [1, 1, 3, 3, 3, 6, 6, 3, 3, 3]);
+
+        let mut source_map = SourceMap::new(Timing::Metrical(u15::from(480)));
+        for index in 0..10 {
+            source_map.push_instruction(index * 480);
+        }
+        let mut report = vec![];
+        profile.write_report(&prog, &source_map, &mut report).unwrap();
+        let report = String::from_utf8(report).unwrap();
+        let lines: Vec<_> = report.lines().collect();
+        assert_eq!(lines.len(), 11);
+        assert_eq!(lines[1], "         6   18.8%  2:2       add -1");
+        assert_eq!(lines[2], "         6   18.8%  2:3       end of the loop at 4");
+        assert_eq!(lines[10], "         1    3.1%  1:2       loop over 5 instructions");
+    }
+
+    // a two note chord and then a single note
+    fn two_chords() -> Smf<'static> {
+        let note = |key: u8, on: bool| TrackEvent {
+            delta: u28::from(10),
+            kind: TrackEventKind::Midi {
+                channel: u4::from(0),
+                message: match on {
+                    true => MidiMessage::NoteOn { key: u7::from(key), vel: u7::from(64) },
+                    false => MidiMessage::NoteOff { key: u7::from(key), vel: u7::from(64) },
+                },
+            },
+        };
+        let mut midi = Smf::new(Header::new(Format::SingleTrack, Timing::Metrical(u15::from(480))));
+        midi.tracks.push(vec![note(9, true), note(21, true), note(21, false), note(9, false), note(11, true), note(11, false)]);
+        midi
+    }
+
+    #[test]
+    fn heatmap_sets_velocities_per_chord() {
+        let mut midi = two_chords();
+        Profile { hits: vec![100, 0] }.heatmap(&mut midi);
+        let velocities: Vec<_> = midi.tracks[0]
+            .iter()
+            .filter_map(|event| match event.kind {
+                TrackEventKind::Midi { message: MidiMessage::NoteOn { vel, .. }, .. } => Some(vel.as_int()),
+                _ => None,
+            })
+            .collect();
+        assert_eq!(velocities, [127, 127, 1]);
+    }
+
+    #[test]
+    fn covers_chords_over_several_runs() {
+        // ,[.]
+        let prog = build(vec![
+            MidiInstruction::new_input(),
+            MidiInstruction::new_open_loop(),
+            MidiInstruction::new_output(),
+            MidiInstruction::new_inc(Wrapping(-1)),
+            MidiInstruction::new_close_loop(),
+        ]);
+        let mut source_map = SourceMap::new(Timing::Metrical(u15::from(480)));
+        for index in 0..5 {
+            source_map.push_instruction(index * 480);
+        }
+        let run = |input: &[u8]| profile(&prog, CellWidth::I8, RunOptions::default(), &[], &mut &input[..], &mut io::sink()).unwrap();
+
+        let mut coverage = run(b"");
+        assert_eq!(coverage.covered(), 2);
+        let mut report = vec![];
+        coverage.write_coverage(&prog, &source_map, &mut report).unwrap();
+        assert_eq!(
+            String::from_utf8(report).unwrap(),
+            "2 of 5 chords executed (40.0%)\nnot executed:\n  \
+             1:3       #2    output\n  1:4       #3    add -1\n  2:1       #4    end of the loop at 1\n"
+        );
+
+        coverage.merge(&run(b"\x02"));
+        assert_eq!(coverage.hits, [2, 2, 2, 2, 2]);
+        let mut report = vec![];
+        coverage.write_coverage(&prog, &source_map, &mut report).unwrap();
+        assert_eq!(report, b"5 of 5 chords executed (100.0%)\n");
+    }
+
+    #[test]
+    fn mutes_chords_that_never_ran() {
+        let mut midi = two_chords();
+        Profile { hits: vec![1] }.mute_uncovered(&mut midi);
+        let channels: Vec<_> = midi.tracks[0]
+            .iter()
+            .filter_map(|event| match event.kind {
+                TrackEventKind::Midi { channel, .. } => Some(channel.as_int()),
+                _ => None,
+            })
+            .collect();
+        // the volume change goes first
+        assert_eq!(channels, [15, 0, 0, 0, 0, 15, 15]);
+        assert!(matches!(
+            midi.tracks[0][0].kind,
+            TrackEventKind::Midi { message: MidiMessage::Controller { value, .. }, .. } if value == 0
+        ));
+
+        let mut covered = two_chords();
+        Profile { hits: vec![1, 1] }.mute_uncovered(&mut covered);
+        assert_eq!(covered, two_chords());
+    }
+}
@@ -0,0 +1,260 @@
+use std::fmt::Display;
+use std::io::{Read, Write};
+use std::path::{Path, PathBuf};
+
+use midly::Smf;
+
+use crate::compiler::{self, Backend, CellWidth, CompileOptions};
+use crate::dump::{self, DumpFormat};
+use crate::error::MidilangResult;
+use crate::interpreter::{Interpreter, RunOptions};
+use crate::parser::{self, MidiAST, MidiInstruction, MidiInstructionKind::*, ParseOptions, PROGRAM_KEY};
+use crate::pretty::PrettyAst;
+use crate::timing::SourceMap;
+use crate::{brainf_to_smf, optimizer, parse_source, read_source, run_error, IN_MEMORY};
+
+/// A parsed midilang program along with what's known about the song it came
+/// from, for using midilang as a library.
+#[derive(Debug, Clone)]
+pub struct MidiProgram {
+    ast: MidiAST,
+    /// Tempo, time signatures and where the chord of every instruction starts
+    source_map: SourceMap,
+    /// Key the chords were read in
+    key: &'static str,
+    source_path: Option<PathBuf>,
+    /// Cells the tape starts out with, from the data track
+    data: Vec<u8>,
+}
+
+impl MidiProgram {
+    /// Parses `midi`, failing at the first chord that isn't an instruction.
+    pub fn from_smf(midi: Smf) -> MidilangResult<Self> {
+        let (ast, source_map) = parser::parse_with_source_map(midi)?;
+        Ok(MidiProgram {
+            ast,
+            source_map,
+            key: PROGRAM_KEY,
+            source_path: None,
+            data: vec![],
+        })
+    }
+
+    /// Reads and parses the MIDI file or `dump` at `path`, or stdin for `-`.
+    /// Fails with everything wrong with the file when it isn't a valid program.
+    pub fn from_file(path: &Path) -> MidilangResult<Self> {
+        let file_path = path.to_string_lossy();
+        let bytes = read_source(&file_path)?;
+        let (ast, source_map, data) = parse_source(&file_path, &bytes, &ParseOptions::default())?;
+        Ok(MidiProgram {
+            ast,
+            source_map,
+            key: PROGRAM_KEY,
+            source_path: Some(path.to_owned()),
+            data,
+        })
+    }
+
+    /// Parses the MIDI file or `dump` in `bytes`, failing like `from_file`.
+    pub fn from_bytes(bytes: &[u8]) -> MidilangResult<Self> {
+        Self::from_bytes_in(bytes, &ParseOptions::default())
+    }
+
+    /// Parses `bytes` like `from_bytes`, read with `options`.
+    pub fn from_bytes_in(bytes: &[u8], options: &ParseOptions) -> MidilangResult<Self> {
+        let (ast, source_map, data) = parse_source(IN_MEMORY, bytes, options)?;
+        Ok(MidiProgram {
+            ast,
+            source_map,
+            key: PROGRAM_KEY,
+            source_path: None,
+            data,
+        })
+    }
+
+    /// Reads a brainf program, one chord per instruction like `from_brainf`.
+    /// Fails on unbalanced brackets.
+    pub fn from_bf_str(bf_program: &str) -> MidilangResult<Self> {
+        MidiProgram::from_smf(brainf_to_smf(bf_program))
+    }
+
+    /// The program as a MIDI file `from_smf` reads back as the same program,
+    /// one chord per brainf instruction of `to_bf`.
+    pub fn to_smf(&self) -> Smf<'static> {
+        brainf_to_smf(&self.to_bf())
+    }
+
+    /// Reads back a program written by `dump`. It doesn't know the song it
+    /// came from, so the source map is empty.
+    pub fn from_dump(source: &str, format: DumpFormat) -> MidilangResult<Self> {
+        Ok(MidiProgram {
+            ast: dump::read(source, format)?,
+            source_map: SourceMap::default(),
+            key: PROGRAM_KEY,
+            source_path: None,
+            data: vec![],
+        })
+    }
+
+    /// The program as JSON or S-expressions, with the positions of its
+    /// instructions, or as a DOT graph.
+    pub fn dump(&self, format: DumpFormat) -> String {
+        dump::write(&self.ast, format, Some(&self.source_map))
+    }
+
+    /// The program as brainf, with `#` for breakpoints.
+    pub fn to_bf(&self) -> String {
+        let mut bf = String::new();
+        write_bf(&self.ast, &mut bf);
+        bf
+    }
+
+    /// Optimizes the program for cells of `cell_width`. Instructions keep the
+    /// positions of the chords they came from.
+    pub fn optimize(self, cell_width: CellWidth) -> Self {
+        MidiProgram {
+            ast: optimizer::optimize_with_data(self.ast, cell_width, &RunOptions::default(), &self.data),
+            ..self
+        }
+    }
+
+    /// Runs the program in the interpreter, reading `input` and writing `output`.
+    pub fn interpret<R: Read, W: Write>(
+        &self,
+        cell_width: CellWidth,
+        limits: RunOptions,
+        input: &mut R,
+        output: &mut W,
+    ) -> MidilangResult<()> {
+        let mut interp = Interpreter::with_options(limits);
+        interp.set_cell_width(cell_width);
+        interp.load_data(&self.data);
+        interp.run(&self.ast, input, output).map_err(|err| run_error(err, &self.source_map))
+    }
+
+    /// Compiles the program with the backend picked in `options`, write it out
+    /// with `Backend::emit`.
+    pub fn compile(&self, options: CompileOptions) -> MidilangResult<Box<dyn Backend>> {
+        let options = CompileOptions { data: self.data.clone(), ..options };
+        Ok(compiler::compile_program(self.ast.clone(), Some(self.source_map.clone()), options)?)
+    }
+
+    pub fn ast(&self) -> &MidiAST {
+        &self.ast
+    }
+
+    pub fn source_map(&self) -> &SourceMap {
+        &self.source_map
+    }
+
+    pub fn key(&self) -> &str {
+        self.key
+    }
+
+    /// Cells the tape starts out with, read from the data track when the file
+    /// was parsed with one, see `ParseOptions::data_track`.
+    pub fn data(&self) -> &[u8] {
+        &self.data
+    }
+
+    /// File the program was read from, `None` when it didn't come from one.
+    pub fn source_path(&self) -> Option<&Path> {
+        self.source_path.as_deref()
+    }
+}
+
+/// The program as indented brainf, see `PrettyAst`.
+impl Display for MidiProgram {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        PrettyAst::new(&self.ast, Some(&self.source_map)).fmt(f)
+    }
+}
+
+/// Writes `program` as brainf to `bf`.
+pub(crate) fn write_bf(program: &[MidiInstruction], bf: &mut String) {
+    let repeat = |bf: &mut String, up: char, down: char, amount: i64| {
+        let inst = if amount < 0 { down } else { up };
+        bf.extend(std::iter::repeat_n(inst, amount.unsigned_abs() as usize));
+    };
+    for inst in program {
+        match &inst.instruction {
+            IncrementCell { amount } => repeat(bf, '+', '-', i64::from(amount.0)),
+            MovePointer { amount } => repeat(bf, '>', '<', *amount as i64),
+            OutputCell => bf.push('.'),
+            InputCell => bf.push(','),
+            Loop { body } => {
+                bf.push('[');
+                write_bf(body, bf);
+                bf.push(']');
+            }
+            Breakpoint => bf.push('#'),
+            // brainf has nothing to call functions with or pick random bytes
+            Call { .. } | Random | DumpTape => {}
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+    const HELLO: &str = "++++++++[>+++++++++<-]>.<+++[>++++++++++<-]>-.#";
+
+    #[test]
+    fn reads_and_writes_bf() {
+        let program = MidiProgram::from_bf_str(HELLO).unwrap();
+        assert_eq!(program.to_bf(), HELLO);
+        assert_eq!(program.key(), "C major");
+        assert_eq!(program.source_path(), None);
+        assert_eq!(program.source_map().len(), HELLO.len());
+        assert!(MidiProgram::from_bf_str("+[").is_err());
+    }
+
+    #[test]
+    fn round_trips_through_smf() {
+        let program = MidiProgram::from_bf_str(HELLO).unwrap();
+        let again = MidiProgram::from_smf(program.to_smf()).unwrap();
+        assert_eq!(again.ast(), program.ast());
+    }
+
+    #[test]
+    fn optimizes_and_interprets() {
+        let program = MidiProgram::from_bf_str(HELLO).unwrap();
+        let optimized = program.clone().optimize(CellWidth::I8);
+        assert!(optimized.ast().len() < program.ast().len());
+        for program in [program, optimized] {
+            let mut output = vec![];
+            program.interpret(CellWidth::I8, RunOptions::default(), &mut &b""[..], &mut output).unwrap();
+            assert_eq!(output, b"He");
+        }
+    }
+
+    #[test]
+    fn names_where_running_failed() {
+        let program = MidiProgram::from_bf_str("+<").unwrap();
+        let err = program.interpret(CellWidth::I8, RunOptions::default(), &mut &b""[..], &mut vec![]).unwrap_err();
+        assert_eq!(err.to_string(), "Pointer moved left of the first cell at 1:1 (instruction 1)");
+    }
+
+    #[test]
+    fn round_trips_through_dumps() {
+        let program = MidiProgram::from_bf_str(HELLO).unwrap();
+        let json = program.dump(DumpFormat::Json);
+        assert_eq!(MidiProgram::from_dump(&json, DumpFormat::Json).unwrap().ast(), program.ast());
+        let again = MidiProgram::from_bytes(program.dump(DumpFormat::Sexp).as_bytes()).unwrap();
+        assert_eq!(again.ast(), program.ast());
+        assert!(again.source_map().is_empty());
+    }
+
+    #[test]
+    #[cfg(any(feature = "llvm", feature = "cranelift"))]
+    fn compiles() {
+        let program = MidiProgram::from_bf_str(HELLO).unwrap();
+        let backend = program.compile(CompileOptions::default()).unwrap();
+        let object = compiler::temp_path("o");
+        backend.emit(compiler::Emit::Obj, &object).unwrap();
+        assert!(std::fs::metadata(&object).unwrap().len() > 0);
+        std::fs::remove_file(object).unwrap();
+    }
+}
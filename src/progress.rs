@@ -0,0 +1,109 @@
+use std::io::{self, IsTerminal};
+use std::sync::OnceLock;
+
+use indicatif::{MultiProgress, ProgressBar, ProgressDrawTarget, ProgressStyle};
+use log::{log_enabled, Level};
+
+use crate::parser::{MidiInstruction, MidiInstructionKind::Loop};
+
+/// Jobs smaller than this are over before a bar would be worth looking at.
+const MIN_TOTAL: u64 = 50_000;
+
+/// A progress bar on stderr for a long running step, counting events,
+/// characters or instructions.
+///
+/// Only drawn when stderr is a terminal, the job is big enough to take a while
+/// and no log lines at info level or below would be drawn over it. Bars of
+/// files compiled in parallel are stacked instead of fighting over a line.
+/// Dropping it clears the bar.
+pub struct Progress {
+    bar: Option<ProgressBar>,
+}
+
+impl Progress {
+    pub fn new(doing: &str, total: u64) -> Self {
+        let shown = total >= MIN_TOTAL && io::stderr().is_terminal() && !log_enabled!(Level::Info);
+        if !shown {
+            return Progress::default();
+        }
+        let style = ProgressStyle::with_template("{msg} [{bar:40}] {human_pos}/{human_len} ({eta} left)")
+            .expect("progress template is valid")
+            .progress_chars("=> ");
+        let bar = bars().add(ProgressBar::new(total).with_style(style).with_message(doing.to_owned()));
+        Progress { bar: Some(bar) }
+    }
+
+    /// Counts `done` more of the total as finished.
+    pub fn inc(&self, done: u64) {
+        if let Some(bar) = &self.bar {
+            bar.inc(done);
+        }
+    }
+}
+
+/// A bar that's never drawn, for before the total is known.
+impl Default for Progress {
+    fn default() -> Self {
+        Progress { bar: None }
+    }
+}
+
+impl Drop for Progress {
+    fn drop(&mut self) {
+        if let Some(bar) = self.bar.take() {
+            bar.finish_and_clear();
+            bars().remove(&bar);
+        }
+    }
+}
+
+// every bar goes through the same `MultiProgress`, so several at once each get a line
+fn bars() -> &'static MultiProgress {
+    static BARS: OnceLock<MultiProgress> = OnceLock::new();
+    BARS.get_or_init(|| MultiProgress::with_draw_target(ProgressDrawTarget::stderr()))
+}
+
+/// Number of instructions in `program`, counting the ones in loops.
+pub fn instruction_count(program: &[MidiInstruction]) -> u64 {
+    program
+        .iter()
+        .map(|inst| match &inst.instruction {
+            Loop { body } => 1 + instruction_count(body),
+            _ => 1,
+        })
+        .sum()
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+    use crate::parser::MidiASTBuilder;
+    use std::num::Wrapping;
+
+    #[test]
+    fn counts_instructions_in_loops() {
+        let mut builder = MidiASTBuilder::new();
+        for inst in [
+            MidiInstruction::new_inc(Wrapping(1)),
+            MidiInstruction::new_open_loop(),
+            MidiInstruction::new_move(1),
+            MidiInstruction::new_open_loop(),
+            MidiInstruction::new_output(),
+            MidiInstruction::new_close_loop(),
+            MidiInstruction::new_close_loop(),
+        ] {
+            builder.push(inst).unwrap();
+        }
+        let program = builder.into_mast().unwrap();
+        assert_eq!(instruction_count(&program), 5);
+        assert_eq!(instruction_count(&[]), 0);
+    }
+
+    #[test]
+    fn small_jobs_have_no_bar() {
+        let progress = Progress::new("Parsing", MIN_TOTAL - 1);
+        assert!(progress.bar.is_none());
+        progress.inc(1);
+    }
+}
@@ -0,0 +1,130 @@
+use std::fmt::Write;
+use std::path::Path;
+
+use sha2::{Digest, Sha256};
+
+use crate::parser::{DIALECT, PROGRAM_KEY};
+
+/// Where a compiled program came from, embedded in its output so a binary can be
+/// traced back to the song that produced it.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Provenance {
+    /// File name of the source MIDI, without its directory
+    pub source: String,
+    /// Key the chords were read in
+    pub key: String,
+    pub dialect: String,
+    /// Version of midilang that compiled the program
+    pub compiler_version: String,
+    /// Hex SHA-256 of the source MIDI file
+    pub sha256: String,
+}
+
+impl Provenance {
+    /// Provenance of a program parsed from `bytes`, read from `path`.
+    pub fn new(path: &Path, bytes: &[u8]) -> Self {
+        let mut sha256 = String::with_capacity(64);
+        for byte in Sha256::digest(bytes) {
+            write!(sha256, "{:02x}", byte).unwrap();
+        }
+        Provenance {
+            source: path.file_name().map_or_else(
+                || path.display().to_string(),
+                |name| name.to_string_lossy().into_owned(),
+            ),
+            key: PROGRAM_KEY.to_owned(),
+            dialect: DIALECT.to_owned(),
+            compiler_version: env!("CARGO_PKG_VERSION").to_owned(),
+            sha256,
+        }
+    }
+
+    /// Names and values of every field, in the order they're embedded.
+    pub fn fields(&self) -> [(&'static str, &str); 5] {
+        [
+            ("source", &self.source),
+            ("key", &self.key),
+            ("dialect", &self.dialect),
+            ("compiler_version", &self.compiler_version),
+            ("sha256", &self.sha256),
+        ]
+    }
+
+    /// Contents of the provenance section, NUL terminated `name=value` strings so
+    /// `strings` prints one field per line.
+    ///
+    /// ```text
+    /// midilang provenance\0source=song.mid\0key=C major\0...
+    /// ```
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut bytes = b"midilang provenance\0".to_vec();
+        for (name, value) in self.fields() {
+            // a NUL in a file name would split the field
+            let value = value.replace('\0', "");
+            bytes.extend_from_slice(format!("{}={}\0", name, value).as_bytes());
+        }
+        bytes
+    }
+}
+
+/// Marks the start of a source MIDI file embedded in a compiled program, which
+/// is followed by the length of the file as a little endian `u64`, then the file.
+const SOURCE_MAGIC: &[u8] = b"midilang source\0";
+
+/// Contents of the `.midilang.src` section embedding the MIDI file `smf`.
+pub fn embedded_source(smf: &[u8]) -> Vec<u8> {
+    let mut bytes = SOURCE_MAGIC.to_vec();
+    bytes.extend_from_slice(&(smf.len() as u64).to_le_bytes());
+    bytes.extend_from_slice(smf);
+    bytes
+}
+
+/// Finds the MIDI file embedded by `embedded_source` in a compiled program.
+///
+/// This looks for the contents of the section rather than the section itself, so
+/// it works the same for every object file format, even on stripped binaries.
+pub fn extract_source(binary: &[u8]) -> Option<&[u8]> {
+    let header_len = SOURCE_MAGIC.len() + 8;
+    (0..binary.len().saturating_sub(header_len)).find_map(|start| {
+        let rest = binary[start..].strip_prefix(SOURCE_MAGIC)?;
+        let len = u64::from_le_bytes(rest[..8].try_into().ok()?);
+        let smf = rest[8..].get(..usize::try_from(len).ok()?)?;
+        // the magic could show up by chance, but not followed by a MIDI file
+        smf.starts_with(b"MThd").then_some(smf)
+    })
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+    #[test]
+    fn provenance_of_file() {
+        let provenance = Provenance::new(Path::new("songs/cat.mid"), b"abc");
+        assert_eq!(provenance.source, "cat.mid");
+        assert_eq!(provenance.key, "C major");
+        assert_eq!(
+            provenance.sha256,
+            "ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad"
+        );
+        let bytes = String::from_utf8(provenance.to_bytes()).unwrap();
+        assert!(
+            bytes.starts_with("midilang provenance\0source=cat.mid\0key=C major\0dialect=core\0")
+        );
+        assert!(bytes.ends_with(
+            "\0sha256=ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad\0"
+        ));
+    }
+
+    #[test]
+    fn extracts_embedded_source() {
+        let smf = b"MThd\0\0\0\x06rest of the file";
+        let mut binary = b"\x7fELF...midilang source\0 not really...".to_vec();
+        binary.extend(embedded_source(smf));
+        binary.extend_from_slice(b"...more sections");
+        assert_eq!(extract_source(&binary), Some(&smf[..]));
+        assert_eq!(extract_source(&binary[..binary.len() - 20]), None);
+        assert_eq!(extract_source(b"no source here"), None);
+    }
+}
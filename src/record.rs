@@ -0,0 +1,187 @@
+use std::io::{Read, Write};
+
+use midly::num::{u15, u28, u4, u7};
+use midly::{Format, Header, MetaMessage, MidiMessage, Smf, Timing, TrackEvent, TrackEventKind};
+
+use crate::interpreter::{InterpResult, Interpreter, RunOptions, TapeView, Tracer};
+use crate::parser::{CellWidth, MidiAST, MidiInstructionKind, Position};
+use crate::profile::{visit_chords, ChordTracker};
+
+/// Ticks per quarter note of recordings of timecode based files.
+const DEFAULT_TICKS_PER_QUARTER: u16 = 480;
+
+/// A note of the source file, as it gets played back.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Note {
+    pub channel: u4,
+    pub key: u7,
+    pub vel: u7,
+}
+
+/// Remembers the chords a program plays, in the order it plays them.
+struct Recorder {
+    chords: ChordTracker,
+    played: Vec<usize>,
+}
+
+impl Tracer for Recorder {
+    fn on_instruction(&mut self, pos: Option<Position>, kind: &MidiInstructionKind, _: &TapeView) {
+        if let Some(chord) = self.chords.chord(pos, kind) {
+            self.played.push(chord);
+        }
+    }
+}
+
+/// Runs `program`, parsed from `source`, in the interpreter with `data` on the
+/// tape and records the
+/// chord of every step it takes as a new single track file. Loops are unrolled
+/// the way the run took them and every chord lasts an eighth note.
+///
+/// The run stops at the first error like it would otherwise, the recording
+/// still has every chord played up to there.
+pub fn record(
+    program: &MidiAST,
+    source: &Smf,
+    cell_width: CellWidth,
+    limits: RunOptions,
+    data: &[u8],
+    input: &mut impl Read,
+    output: &mut impl Write,
+) -> (Smf<'static>, InterpResult<()>) {
+    let (played, result) = played_chords(program, cell_width, limits, data, input, output);
+    let ticks_per_quarter = match source.header.timing {
+        Timing::Metrical(ticks) => ticks,
+        Timing::Timecode(..) => u15::from(DEFAULT_TICKS_PER_QUARTER),
+    };
+    let chord_ticks = u28::from(u32::from(ticks_per_quarter.as_int() / 2).max(1));
+    let chords = chord_notes(source);
+
+    let mut track = vec![];
+    for chord in played {
+        let notes = chords.get(chord).map_or(&[][..], Vec::as_slice);
+        for note in notes {
+            track.push(TrackEvent {
+                delta: u28::from(0),
+                kind: TrackEventKind::Midi {
+                    channel: note.channel,
+                    message: MidiMessage::NoteOn { key: note.key, vel: note.vel },
+                },
+            });
+        }
+        for (idx, note) in notes.iter().enumerate() {
+            track.push(TrackEvent {
+                // the first release ends the chord
+                delta: if idx == 0 { chord_ticks } else { u28::from(0) },
+                kind: TrackEventKind::Midi {
+                    channel: note.channel,
+                    message: MidiMessage::NoteOff { key: note.key, vel: u7::from(0) },
+                },
+            });
+        }
+    }
+    track.push(TrackEvent {
+        delta: u28::from(0),
+        kind: TrackEventKind::Meta(MetaMessage::EndOfTrack),
+    });
+
+    let mut recording = Smf::new(Header::new(Format::SingleTrack, Timing::Metrical(ticks_per_quarter)));
+    recording.tracks.push(track);
+    (recording, result)
+}
+
+/// Runs `program` in the interpreter and returns the index of the chord of
+/// every step it took, in order, along with how the run ended.
+pub(crate) fn played_chords(
+    program: &MidiAST,
+    cell_width: CellWidth,
+    limits: RunOptions,
+    data: &[u8],
+    input: &mut impl Read,
+    output: &mut impl Write,
+) -> (Vec<usize>, InterpResult<()>) {
+    let mut interp = Interpreter::with_options(limits);
+    interp.set_cell_width(cell_width);
+    interp.load_data(data);
+    let mut recorder = Recorder {
+        chords: ChordTracker::default(),
+        played: vec![],
+    };
+    let result = interp.run_traced(program, input, output, &mut recorder);
+    (recorder.played, result)
+}
+
+/// Notes of every chord in `source`, indexed like `Position`s.
+pub(crate) fn chord_notes(source: &Smf) -> Vec<Vec<Note>> {
+    let mut source = source.clone();
+    let mut chords = vec![];
+    visit_chords(&mut source, |_, events| {
+        let notes = events.iter().filter_map(|event| match **event {
+            TrackEventKind::Midi { channel, message: MidiMessage::NoteOn { key, vel } } => Some(Note { channel, key, vel }),
+            _ => None,
+        });
+        chords.push(notes.collect());
+    });
+    chords
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+    use crate::parser::parse;
+    use std::io;
+
+    fn chord(keys: &[u8]) -> Vec<TrackEvent<'static>> {
+        let note = |key: u8, on: bool| TrackEvent {
+            delta: u28::from(10),
+            kind: TrackEventKind::Midi {
+                channel: u4::from(0),
+                message: match on {
+                    true => MidiMessage::NoteOn { key: u7::from(key), vel: u7::from(100) },
+                    false => MidiMessage::NoteOff { key: u7::from(key), vel: u7::from(100) },
+                },
+            },
+        };
+        let mut events: Vec<_> = keys.iter().map(|&key| note(key, true)).collect();
+        events.extend(keys.iter().map(|&key| note(key, false)));
+        events
+    }
+
+    #[test]
+    fn unrolls_loops_as_they_ran() {
+        // ++[-]
+        let mut source = Smf::new(Header::new(Format::SingleTrack, Timing::Metrical(u15::from(96))));
+        let track = [chord(&[9, 21, 23]), chord(&[7]), chord(&[5]), chord(&[0])].concat();
+        source.tracks.push(track);
+        let program = parse(source.clone()).unwrap();
+
+        let (recording, result) = record(&program, &source, CellWidth::I8, RunOptions::default(), &[], &mut io::empty(), &mut io::sink());
+        assert_eq!(result, Ok(()));
+        assert_eq!(recording.header.timing, Timing::Metrical(u15::from(96)));
+        let played: Vec<_> = recording.tracks[0]
+            .iter()
+            .filter_map(|event| match event.kind {
+                TrackEventKind::Midi { message: MidiMessage::NoteOn { key, .. }, .. } => Some(key.as_int()),
+                _ => None,
+            })
+            .collect();
+        // the loop is entered, runs twice and is checked after each time around
+        assert_eq!(played, [9, 21, 23, 7, 5, 0, 5, 0]);
+        let ends: Vec<_> = recording.tracks[0].iter().filter(|event| event.delta == 48).collect();
+        assert_eq!(ends.len(), 6);
+    }
+
+    #[test]
+    fn keeps_what_ran_before_an_error() {
+        // +[]
+        let mut source = Smf::new(Header::new(Format::SingleTrack, Timing::Metrical(u15::from(96))));
+        source.tracks.push([chord(&[9]), chord(&[7]), chord(&[0])].concat());
+        let program = parse(source.clone()).unwrap();
+
+        let limits = RunOptions { max_steps: Some(5), ..RunOptions::default() };
+        let (recording, result) = record(&program, &source, CellWidth::I8, limits, &[], &mut io::empty(), &mut io::sink());
+        assert!(result.is_err());
+        // a note on and off per step, and the end of the track
+        assert_eq!(recording.tracks[0].len(), 5 * 2 + 1);
+    }
+}
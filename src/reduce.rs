@@ -0,0 +1,169 @@
+use std::io;
+
+use log::debug;
+
+use crate::parser::{read_chord, MidiInstructionKind::Loop};
+
+/// Shrinks a song, given as the notes of its chords, for as long as `holds`
+/// says the bug it shows is still there, the way delta debugging does. Goes
+/// over it again and again until nothing more can go:
+///
+/// - loops, whole and then only their opening and closing chords
+/// - runs of chords, halving in length down to single chords
+/// - the notes of chords above the lowest one
+///
+/// `holds` has to hold for `chords`. Loops are told by the chords that open
+/// and close them, the rest can come out unbalanced if `holds` lets it.
+pub fn reduce<F>(mut chords: Vec<Vec<u8>>, mut holds: F) -> io::Result<Vec<Vec<u8>>>
+where
+    F: FnMut(&[Vec<u8>]) -> io::Result<bool>,
+{
+    loop {
+        let mut progress = remove_loops(&mut chords, &mut holds)?;
+        progress |= remove_runs(&mut chords, &mut holds)?;
+        progress |= thin_chords(&mut chords, &mut holds)?;
+        if !progress {
+            return Ok(chords);
+        }
+    }
+}
+
+// keeps `candidate` when the bug's still in it
+fn try_keep<F>(chords: &mut Vec<Vec<u8>>, candidate: Vec<Vec<u8>>, holds: &mut F) -> io::Result<bool>
+where
+    F: FnMut(&[Vec<u8>]) -> io::Result<bool>,
+{
+    if !holds(&candidate)? {
+        return Ok(false);
+    }
+    debug!("Still there with {} chords", candidate.len());
+    *chords = candidate;
+    Ok(true)
+}
+
+fn remove_loops<F>(chords: &mut Vec<Vec<u8>>, holds: &mut F) -> io::Result<bool>
+where
+    F: FnMut(&[Vec<u8>]) -> io::Result<bool>,
+{
+    let mut progress = false;
+    // loops before `idx` stay, the ones after it move down when one goes
+    let mut idx = 0;
+    while let Some(&(open, close)) = loops(chords).get(idx) {
+        let mut without = chords.clone();
+        without.drain(open..=close);
+        let mut unwrapped = chords.clone();
+        unwrapped.remove(close);
+        unwrapped.remove(open);
+        if try_keep(chords, without, holds)? || try_keep(chords, unwrapped, holds)? {
+            progress = true;
+        } else {
+            idx += 1;
+        }
+    }
+    Ok(progress)
+}
+
+fn remove_runs<F>(chords: &mut Vec<Vec<u8>>, holds: &mut F) -> io::Result<bool>
+where
+    F: FnMut(&[Vec<u8>]) -> io::Result<bool>,
+{
+    let mut progress = false;
+    let mut length = chords.len() / 2;
+    while length > 0 {
+        let mut start = 0;
+        while start < chords.len() {
+            let mut without = chords.clone();
+            without.drain(start..(start + length).min(chords.len()));
+            if try_keep(chords, without, holds)? {
+                progress = true;
+            } else {
+                start += length;
+            }
+        }
+        length /= 2;
+    }
+    Ok(progress)
+}
+
+fn thin_chords<F>(chords: &mut Vec<Vec<u8>>, holds: &mut F) -> io::Result<bool>
+where
+    F: FnMut(&[Vec<u8>]) -> io::Result<bool>,
+{
+    let mut progress = false;
+    for idx in 0..chords.len() {
+        if chords[idx].len() < 2 {
+            continue;
+        }
+        let mut thinned = chords.clone();
+        thinned[idx].truncate(1);
+        progress |= try_keep(chords, thinned, holds)?;
+    }
+    Ok(progress)
+}
+
+/// Opening and closing chord of every loop, in the order they open. Chords
+/// left unmatched aren't loops.
+fn loops(chords: &[Vec<u8>]) -> Vec<(usize, usize)> {
+    let mut open = vec![];
+    let mut loops = vec![];
+    for (idx, notes) in chords.iter().enumerate() {
+        let mut sorted = notes.clone();
+        sorted.sort_unstable();
+        match read_chord(sorted).map(|inst| (inst.instruction, inst.position)) {
+            // the parser opens loops with a position and closes them without
+            Ok((Loop { .. }, Some(_))) => open.push(idx),
+            Ok((Loop { .. }, None)) => {
+                if let Some(start) = open.pop() {
+                    loops.push((start, idx));
+                }
+            }
+            _ => {}
+        }
+    }
+    loops.sort_unstable();
+    loops
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+    use crate::diagnostics::describe_reading;
+    use crate::parser::{parse_all, MidiInstructionKind::OutputCell};
+
+    fn chords(bf: &str) -> Vec<Vec<u8>> {
+        parse_all(crate::brainf_to_smf(bf)).chords.into_iter().map(|chord| chord.notes).collect()
+    }
+
+    fn reads(chords: &[Vec<u8>]) -> Vec<String> {
+        chords.iter().map(|notes| describe_reading(&read_chord(notes.clone()))).collect()
+    }
+
+    #[test]
+    fn finds_loops() {
+        assert_eq!(loops(&chords("+[>[-]<]]-[")), [(1, 7), (3, 5)]);
+    }
+
+    #[test]
+    fn shrinks_to_what_shows_the_bug() {
+        // the "bug" is outputting inside a loop
+        let mut checks = 0;
+        let reduced = reduce(chords("+++[>++[-]<.-]>>.,"), |chords| {
+            checks += 1;
+            let mut depth = 0;
+            let mut found = false;
+            for notes in chords {
+                match read_chord(notes.clone()).map(|inst| (inst.instruction, inst.position)) {
+                    Ok((Loop { .. }, Some(_))) => depth += 1,
+                    Ok((Loop { .. }, None)) => depth -= 1,
+                    Ok((OutputCell, _)) => found |= depth > 0,
+                    _ => {}
+                }
+            }
+            Ok(found)
+        })
+        .unwrap();
+        assert_eq!(reads(&reduced), ["open a loop", "output"]);
+        assert!(checks < 60, "took {} checks", checks);
+    }
+}
@@ -0,0 +1,120 @@
+use std::f64::consts::TAU;
+use std::io::{self, Write};
+use std::time::Duration;
+
+use crate::record::Note;
+
+/// Samples per second of rendered audio.
+pub const SAMPLE_RATE: u32 = 44_100;
+
+/// Fade in at the start of every chord, long enough to not click.
+const ATTACK_SECS: f64 = 0.005;
+
+/// Fade out at the end of every chord, long enough to not click.
+const RELEASE_SECS: f64 = 0.01;
+
+/// How fast notes die away, in 1/seconds.
+const DECAY_RATE: f64 = 3.0;
+
+/// Loudness of a note at full velocity, leaving room for a few of them at once.
+const NOTE_GAIN: f64 = 0.3;
+
+/// Renders `chords` one after the other, each lasting `chord_length`, as a 16 bit
+/// mono WAV file. Notes are sines with a little of their octave mixed in, loud
+/// as their velocity and fading out over the chord like a plucked string.
+pub fn write_wav(chords: &[&[Note]], chord_length: Duration, out: &mut impl Write) -> io::Result<()> {
+    let chord_samples = (chord_length.as_secs_f64() * f64::from(SAMPLE_RATE)).round() as usize;
+    let data_bytes = chords.len() * chord_samples * 2;
+    // the sizes in the header are 32 bits
+    let data_bytes = u32::try_from(data_bytes)
+        .ok()
+        .filter(|bytes| *bytes <= u32::MAX - 36)
+        .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "too much audio for a WAV file"))?;
+    write_header(data_bytes, out)?;
+
+    let mut samples = Vec::with_capacity(chord_samples * 2);
+    for notes in chords {
+        samples.clear();
+        for idx in 0..chord_samples {
+            let time = idx as f64 / f64::from(SAMPLE_RATE);
+            let left = (chord_samples - 1 - idx) as f64 / f64::from(SAMPLE_RATE);
+            let envelope = (time / ATTACK_SECS).min(1.0) * (left / RELEASE_SECS).min(1.0) * (-DECAY_RATE * time).exp();
+            let value: f64 = notes
+                .iter()
+                .map(|note| {
+                    let freq = 440.0 * 2_f64.powf((f64::from(note.key.as_int()) - 69.0) / 12.0);
+                    let gain = NOTE_GAIN * f64::from(note.vel.as_int()) / 127.0;
+                    gain * (0.8 * (TAU * freq * time).sin() + 0.2 * (2.0 * TAU * freq * time).sin())
+                })
+                .sum();
+            let sample = (envelope * value).clamp(-1.0, 1.0) * f64::from(i16::MAX);
+            samples.extend_from_slice(&(sample as i16).to_le_bytes());
+        }
+        out.write_all(&samples)?;
+    }
+    Ok(())
+}
+
+/// Writes a canonical 44 byte WAV header for `data_bytes` of 16 bit mono PCM.
+fn write_header(data_bytes: u32, out: &mut impl Write) -> io::Result<()> {
+    let channels: u16 = 1;
+    let bits: u16 = 16;
+    let block_align = channels * bits / 8;
+    out.write_all(b"RIFF")?;
+    out.write_all(&(36 + data_bytes).to_le_bytes())?;
+    out.write_all(b"WAVEfmt ")?;
+    out.write_all(&16_u32.to_le_bytes())?;
+    // PCM
+    out.write_all(&1_u16.to_le_bytes())?;
+    out.write_all(&channels.to_le_bytes())?;
+    out.write_all(&SAMPLE_RATE.to_le_bytes())?;
+    out.write_all(&(SAMPLE_RATE * u32::from(block_align)).to_le_bytes())?;
+    out.write_all(&block_align.to_le_bytes())?;
+    out.write_all(&bits.to_le_bytes())?;
+    out.write_all(b"data")?;
+    out.write_all(&data_bytes.to_le_bytes())
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+    use midly::num::{u4, u7};
+
+    fn samples(wav: &[u8]) -> Vec<i16> {
+        wav[44..].chunks(2).map(|pair| i16::from_le_bytes([pair[0], pair[1]])).collect()
+    }
+
+    #[test]
+    fn writes_a_wav_header() {
+        let mut wav = vec![];
+        write_wav(&[&[], &[]], Duration::from_millis(10), &mut wav).unwrap();
+        // 441 samples per chord
+        assert_eq!(wav.len(), 44 + 2 * 441 * 2);
+        assert_eq!(&wav[..4], b"RIFF");
+        assert_eq!(u32::from_le_bytes(wav[4..8].try_into().unwrap()), 36 + 1764);
+        assert_eq!(&wav[8..16], b"WAVEfmt ");
+        assert_eq!(u32::from_le_bytes(wav[24..28].try_into().unwrap()), SAMPLE_RATE);
+        assert_eq!(&wav[36..40], b"data");
+        assert_eq!(u32::from_le_bytes(wav[40..44].try_into().unwrap()), 1764);
+        assert!(samples(&wav).iter().all(|&sample| sample == 0));
+    }
+
+    #[test]
+    fn renders_notes_between_silence() {
+        let a4 = [Note { channel: u4::from(0), key: u7::from(69), vel: u7::from(127) }];
+        let mut wav = vec![];
+        write_wav(&[&[], &a4, &[]], Duration::from_millis(100), &mut wav).unwrap();
+        let samples = samples(&wav);
+        let (silence, rest) = samples.split_at(4410);
+        let (note, after) = rest.split_at(4410);
+        assert!(silence.iter().chain(after).all(|&sample| sample == 0));
+        let loudest = note.iter().map(|sample| sample.unsigned_abs()).max().unwrap();
+        assert!(loudest > i16::MAX as u16 / 5);
+        // fades in and out, so chords don't click into each other
+        assert_eq!((note[0], note[4409]), (0, 0));
+        // 440Hz crosses zero going up 44 times in 100ms
+        let rising = note.windows(2).filter(|pair| pair[0] < 0 && pair[1] >= 0).count();
+        assert!((43..=45).contains(&rising), "{} rising zero crossings", rising);
+    }
+}
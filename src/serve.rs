@@ -0,0 +1,399 @@
+use std::collections::HashMap;
+use std::io::{self, Read};
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use clap::ValueEnum;
+use log::{debug, info, warn};
+use tiny_http::{Header, Method, Request, Response, Server};
+
+use crate::compiler::{self, CellWidth, CompileOptions, Emit, OptLevel};
+use crate::error::{MidilangError, MidilangResult};
+use crate::interpreter::RunOptions;
+use crate::logging::millis_since;
+use crate::parser::ParseOptions;
+use crate::{compile_bytes, new_vm, run_error, vm, MidiProgram};
+
+/// Target programs asked for with `emit=wasm` are compiled for.
+pub const WASM_TARGET: &str = "wasm32-wasi";
+
+/// How much a request can ask of the service. Runs can ask for less with
+/// `max_steps` and `time_limit`, never for more.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Sandbox {
+    /// Largest MIDI file taken
+    pub max_body_bytes: usize,
+    /// Instructions a run executes before it's stopped
+    pub max_steps: usize,
+    /// Time a run takes before it's stopped
+    pub time_limit: Duration,
+    /// Bytes a run writes before it's stopped
+    pub max_output_bytes: usize,
+}
+
+impl Default for Sandbox {
+    fn default() -> Self {
+        Sandbox {
+            max_body_bytes: 1 << 20,
+            max_steps: 100_000_000,
+            time_limit: Duration::from_secs(5),
+            max_output_bytes: 1 << 16,
+        }
+    }
+}
+
+/// A small HTTP API compiling and running MIDI files posted to it, enough
+/// for a playground to be built on:
+///
+/// ```text
+/// POST /compile?emit=llvm-ir&opt_level=2   the output, like `--emit`
+/// POST /compile?emit=wasm                  a module for wasm32-wasi
+/// POST /run?input=hi&max_steps=1000        {"exit_code":0,"output":"hi",...}
+/// GET  /health                             ok
+/// ```
+///
+/// The MIDI file, or a `dump` of a program, is the body of the request.
+/// Compiling takes the options `CompileRequest` of the daemon does, named the
+/// same, but never touches a file other than the temporary ones linking
+/// needs. Songs are read sandboxed, see `ParseOptions::sandboxed`, and
+/// programs run on the VM under the `Sandbox` limits. Failures come back as
+/// JSON with a 4xx or 5xx status, like `--error-format json` prints them.
+pub struct Service {
+    server: Arc<Server>,
+    sandbox: Sandbox,
+    stopping: AtomicBool,
+}
+
+impl Service {
+    /// Listens on `addr`, like `127.0.0.1:8080`. Port 0 picks a free one.
+    pub fn bind(addr: &str, sandbox: Sandbox) -> MidilangResult<Self> {
+        let server = Server::http(addr).map_err(|err| MidilangError::Other(format!("Could not listen on {}: {}", addr, err)))?;
+        Ok(Service { server: Arc::new(server), sandbox, stopping: AtomicBool::new(false) })
+    }
+
+    /// Where the service listens.
+    pub fn addr(&self) -> Option<SocketAddr> {
+        self.server.server_addr().to_ip()
+    }
+
+    /// Answers requests with `workers` threads, so no more than that many
+    /// programs compile or run at once, until `stop` is called.
+    pub fn run(&self, workers: usize) -> MidilangResult<i32> {
+        if let Some(addr) = self.addr() {
+            info!("Listening on http://{}", addr);
+        }
+        thread::scope(|scope| {
+            for _ in 0..workers.max(1) {
+                scope.spawn(|| self.work());
+            }
+        });
+        info!("Shutting down");
+        Ok(0)
+    }
+
+    /// Has `run` return once the requests being answered are.
+    pub fn stop(&self) {
+        self.stopping.store(true, Ordering::SeqCst);
+        self.server.unblock();
+    }
+
+    fn work(&self) {
+        loop {
+            let request = self.server.recv();
+            if self.stopping.load(Ordering::SeqCst) {
+                // unblocking only wakes one worker, which wakes the next
+                self.server.unblock();
+                return;
+            }
+            match request {
+                Ok(request) => self.answer(request),
+                Err(err) => warn!("Could not take a request: {}", err),
+            }
+        }
+    }
+
+    fn answer(&self, mut request: Request) {
+        let started = Instant::now();
+        let (path, query) = request.url().split_once('?').unwrap_or((request.url(), ""));
+        let (path, query) = (path.to_owned(), params(query));
+        debug!("{} {} {:?}", request.method(), path, query);
+        let response = match (request.method(), path.as_str()) {
+            (Method::Get, "/health") => Ok(text(200, "ok")),
+            (Method::Post, "/compile" | "/run") => match self.body(&mut request) {
+                Ok(body) if path == "/compile" => self.compile(&body, &query),
+                Ok(body) => self.run_program(&body, &query, started),
+                Err(response) => Ok(response),
+            },
+            (_, "/health" | "/compile" | "/run") => Ok(text(405, "method not allowed")),
+            _ => Ok(text(404, "not found")),
+        };
+        let response = response.unwrap_or_else(|err| failed(&err, started));
+        debug!("Answered {} in {:.1}ms", path, millis_since(started));
+        if let Err(err) = request.respond(response) {
+            warn!("Could not answer {}: {}", path, err);
+        }
+    }
+
+    // the MIDI file in the request, as long as it isn't too large
+    fn body(&self, request: &mut Request) -> Result<Vec<u8>, Response<io::Cursor<Vec<u8>>>> {
+        let too_large = || text(413, &format!("files can be {} bytes at most", self.sandbox.max_body_bytes));
+        if request.body_length().is_some_and(|length| length > self.sandbox.max_body_bytes) {
+            return Err(too_large());
+        }
+        let mut body = vec![];
+        let read = request.as_reader().take(self.sandbox.max_body_bytes as u64 + 1).read_to_end(&mut body);
+        match read {
+            Ok(_) if body.len() > self.sandbox.max_body_bytes => Err(too_large()),
+            Ok(_) => Ok(body),
+            Err(err) => Err(text(400, &format!("could not read the file: {}", err))),
+        }
+    }
+
+    fn compile(&self, body: &[u8], query: &HashMap<String, String>) -> MidilangResult<Response<io::Cursor<Vec<u8>>>> {
+        let wasm = query.get("emit").is_some_and(|emit| emit == "wasm");
+        let emit: Emit = if wasm { Emit::Exe } else { value("emit", query)?.unwrap_or(Emit::Obj) };
+        let target = if wasm { Some(WASM_TARGET.to_owned()) } else { query.get("target").cloned() };
+        let opt_level = query.get("opt_level").map_or(Ok(0), |level| number::<u8>("opt_level", level))?;
+        let tape_size = query.get("tape_size").map(|size| number("tape_size", size)).transpose()?;
+        let options = CompileOptions::builder()
+            .opt_level(OptLevel::try_from(opt_level)?)
+            .tape_size(tape_size.unwrap_or(compiler::DEFAULT_TAPE_SIZE))
+            .tape_mode(value("tape", query)?.unwrap_or_default())
+            .bounds_check(flag("checked", query))
+            .cell_width(value("cell_size", query)?.unwrap_or_default())
+            .target_triple(target)
+            .backend(value("backend", query)?.unwrap_or_default())
+            .build()?;
+        let mut output = vec![];
+        compile_bytes(body, &ParseOptions::sandboxed(), options, emit, &mut output)?;
+        let content_type = match emit {
+            Emit::LlvmIr | Emit::Asm | Emit::C => "text/plain; charset=utf-8",
+            _ if wasm => "application/wasm",
+            _ => "application/octet-stream",
+        };
+        Ok(Response::from_data(output).with_header(header("Content-Type", content_type)))
+    }
+
+    fn run_program(&self, body: &[u8], query: &HashMap<String, String>, started: Instant) -> MidilangResult<Response<io::Cursor<Vec<u8>>>> {
+        let cell_width: CellWidth = value("cell_size", query)?.unwrap_or_default();
+        let max_steps = query.get("max_steps").map(|steps| number("max_steps", steps)).transpose()?;
+        let time_limit = query.get("time_limit").map(|millis| number("time_limit", millis)).transpose()?;
+        let limits = RunOptions {
+            max_steps: Some(max_steps.map_or(self.sandbox.max_steps, |steps: usize| steps.min(self.sandbox.max_steps))),
+            wall_clock_limit: Some(time_limit.map_or(self.sandbox.time_limit, |millis| Duration::from_millis(millis).min(self.sandbox.time_limit))),
+            max_output_bytes: Some(self.sandbox.max_output_bytes),
+            ..RunOptions::default()
+        };
+        let program = MidiProgram::from_bytes_in(body, &ParseOptions::sandboxed())?.optimize(cell_width);
+        let input = query.get("input").map_or("", String::as_str);
+        let mut written = vec![];
+        let result = new_vm(cell_width, limits).run(&vm::Bytecode::new(program.ast()), &mut input.as_bytes(), &mut written);
+        // a program that fails running still ran, the request went fine
+        let mut json = serde_json::json!({
+            "exit_code": 0,
+            "output": String::from_utf8_lossy(&written),
+        });
+        if let Err(err) = result.map_err(|err| run_error(err, program.source_map())) {
+            json["exit_code"] = err.exit_code().into();
+            json["error"] = err.to_json_value();
+        }
+        json["duration_ms"] = millis_since(started).into();
+        Ok(Response::from_data(json.to_string()).with_header(header("Content-Type", "application/json")))
+    }
+}
+
+/// Answers requests on `addr` with a worker for every core, until the
+/// process is stopped.
+pub fn serve(addr: &str, sandbox: Sandbox) -> MidilangResult<i32> {
+    let workers = thread::available_parallelism().map_or(1, |cores| cores.get());
+    Service::bind(addr, sandbox)?.run(workers)
+}
+
+// the parameters of a query string, decoded
+fn params(query: &str) -> HashMap<String, String> {
+    query
+        .split('&')
+        .filter(|pair| !pair.is_empty())
+        .map(|pair| {
+            let (name, value) = pair.split_once('=').unwrap_or((pair, ""));
+            (decode(name), decode(value))
+        })
+        .collect()
+}
+
+// undoes percent-encoding, and `+` standing for a space
+fn decode(encoded: &str) -> String {
+    let mut bytes = Vec::with_capacity(encoded.len());
+    let mut rest = encoded.as_bytes();
+    while let Some((&byte, after)) = rest.split_first() {
+        rest = after;
+        match byte {
+            b'+' => bytes.push(b' '),
+            b'%' => match rest.get(..2).and_then(|hex| u8::from_str_radix(std::str::from_utf8(hex).ok()?, 16).ok()) {
+                Some(decoded) => {
+                    bytes.push(decoded);
+                    rest = &rest[2..];
+                }
+                None => bytes.push(b'%'),
+            },
+            byte => bytes.push(byte),
+        }
+    }
+    String::from_utf8_lossy(&bytes).into_owned()
+}
+
+// the value of an option the command line takes as one of a few names
+fn value<T: ValueEnum>(option: &str, query: &HashMap<String, String>) -> MidilangResult<Option<T>> {
+    query
+        .get(option)
+        .map(|name| T::from_str(name, true).map_err(|_| MidilangError::Other(format!("`{}` isn't a value of {}", name, option))))
+        .transpose()
+}
+
+fn number<T: std::str::FromStr>(option: &str, value: &str) -> MidilangResult<T> {
+    value.parse().map_err(|_| MidilangError::Other(format!("`{}` isn't a number of {}", value, option)))
+}
+
+// `checked`, `checked=true` and `checked=1` all turn an option on
+fn flag(option: &str, query: &HashMap<String, String>) -> bool {
+    query.get(option).is_some_and(|value| matches!(value.as_str(), "" | "true" | "1"))
+}
+
+// options that make no sense are the client's fault, programs that don't
+// compile are too, the rest is ours
+fn failed(err: &MidilangError, started: Instant) -> Response<io::Cursor<Vec<u8>>> {
+    let status = match err {
+        MidilangError::Io(_) => 500,
+        MidilangError::Other(_) => 400,
+        _ => 422,
+    };
+    let json = serde_json::json!({
+        "exit_code": err.exit_code(),
+        "error": err.to_json_value(),
+        "duration_ms": millis_since(started),
+    });
+    Response::from_data(json.to_string()).with_status_code(status).with_header(header("Content-Type", "application/json"))
+}
+
+fn text(status: u16, body: &str) -> Response<io::Cursor<Vec<u8>>> {
+    Response::from_string(body).with_status_code(status).with_header(header("Content-Type", "text/plain; charset=utf-8"))
+}
+
+fn header(name: &str, value: &str) -> Header {
+    Header::from_bytes(name.as_bytes(), value.as_bytes()).expect("header names and values are ASCII")
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+    use std::io::Write;
+    use std::net::TcpStream;
+
+    // the status and body of a response to posting `body`
+    fn post(addr: SocketAddr, path: &str, body: &[u8]) -> (u16, Vec<u8>) {
+        let mut stream = TcpStream::connect(addr).unwrap();
+        write!(stream, "POST {} HTTP/1.1\r\nHost: localhost\r\nContent-Length: {}\r\nConnection: close\r\n\r\n", path, body.len()).unwrap();
+        stream.write_all(body).unwrap();
+        let mut response = vec![];
+        stream.read_to_end(&mut response).unwrap();
+        let split = response.windows(4).position(|window| window == b"\r\n\r\n").unwrap();
+        let status = std::str::from_utf8(&response[9..12]).unwrap().parse().unwrap();
+        (status, response[split + 4..].to_vec())
+    }
+
+    fn json(body: &[u8]) -> serde_json::Value {
+        serde_json::from_slice(body).unwrap()
+    }
+
+    #[test]
+    fn decodes_queries() {
+        let query = params("input=a+b%21&checked&emit=llvm-ir&bad=%zz");
+        assert_eq!(query["input"], "a b!");
+        assert_eq!(query["emit"], "llvm-ir");
+        assert_eq!(query["bad"], "%zz");
+        assert!(flag("checked", &query));
+        assert!(!flag("lenient", &query));
+    }
+
+    #[test]
+    fn compiles_and_runs_posted_files() {
+        let mut song = vec![];
+        crate::brainf_to_smf(",[.,]").write_std(&mut song).unwrap();
+        let sandbox = Sandbox { max_body_bytes: 1 << 12, max_steps: 1000, ..Sandbox::default() };
+        let service = Arc::new(Service::bind("127.0.0.1:0", sandbox).unwrap());
+        let addr = service.addr().unwrap();
+        let server = {
+            let service = Arc::clone(&service);
+            thread::spawn(move || service.run(2).unwrap())
+        };
+
+        let (status, c) = post(addr, "/compile?emit=c", &song);
+        assert_eq!(status, 200);
+        assert!(String::from_utf8(c).unwrap().contains("int main"));
+        let (status, object) = post(addr, "/compile", &song);
+        assert_eq!(status, 200);
+        assert!(!object.is_empty());
+
+        let (status, run) = post(addr, "/run?input=hi%21", &song);
+        assert_eq!(status, 200);
+        assert_eq!((&json(&run)["exit_code"], &json(&run)["output"]), (&0.into(), &"hi!".into()));
+        // asking for more steps than the sandbox allows gets its steps
+        let mut forever = vec![];
+        crate::brainf_to_smf("+[>+<]").write_std(&mut forever).unwrap();
+        let (_, run) = post(addr, "/run?max_steps=1000000", &forever);
+        assert_eq!(json(&run)["exit_code"], 1);
+        assert!(json(&run)["error"]["message"].as_str().unwrap().contains("1000"));
+
+        let (status, failure) = post(addr, "/run", b"not a song");
+        assert_eq!((status, &json(&failure)["exit_code"]), (422, &2.into()));
+        let (status, failure) = post(addr, "/compile?emit=sound", &song);
+        assert_eq!(status, 400);
+        assert_eq!(json(&failure)["error"]["message"], "`sound` isn't a value of emit");
+        assert_eq!(post(addr, "/run", &[0; 1 << 13]).0, 413);
+        assert_eq!(post(addr, "/health", b"").0, 405);
+        assert_eq!(post(addr, "/elsewhere", b"").0, 404);
+        service.stop();
+        assert_eq!(server.join().unwrap(), 0);
+    }
+
+    #[test]
+    fn turns_away_calls_and_includes() {
+        use crate::sysex::Directive;
+        use midly::{MetaMessage, TrackEvent, TrackEventKind};
+
+        let service = Arc::new(Service::bind("127.0.0.1:0", Sandbox::default()).unwrap());
+        let addr = service.addr().unwrap();
+        let server = {
+            let service = Arc::clone(&service);
+            thread::spawn(move || service.run(2).unwrap())
+        };
+        let event = |kind| TrackEvent { delta: 0.into(), kind };
+        // a diminished seventh calling `abort`
+        let mut call = crate::formatter::song(&[vec![60, 63, 66, 69]]);
+        call.tracks[1].insert(0, event(TrackEventKind::Meta(MetaMessage::Text(b"abort"))));
+        let mut include = crate::brainf_to_smf("+");
+        let directive = Directive::Include("/etc/passwd".to_owned()).to_sysex();
+        include.tracks[1].insert(0, event(TrackEventKind::SysEx(&directive)));
+        let dump = b"(call (at 0 0) abort)";
+
+        for song in [call, include] {
+            let mut bytes = vec![];
+            song.write_std(&mut bytes).unwrap();
+            for path in ["/run", "/compile?emit=c"] {
+                let (status, failure) = post(addr, path, &bytes);
+                assert_eq!((status, &json(&failure)["exit_code"]), (422, &2.into()), "{}", path);
+            }
+        }
+        assert_eq!(post(addr, "/run", dump).0, 422);
+        // and goes on answering
+        let mut fine = vec![];
+        crate::brainf_to_smf("+.").write_std(&mut fine).unwrap();
+        assert_eq!(post(addr, "/run", &fine).0, 200);
+        service.stop();
+        assert_eq!(server.join().unwrap(), 0);
+    }
+}
@@ -0,0 +1,174 @@
+use std::collections::BTreeMap;
+use std::io::{self, Write};
+use std::time::Duration;
+
+use crate::optimizer::TapeUsage;
+use crate::parser::{MidiInstructionKind::*, Parsed};
+
+/// Kinds of instructions, in the order they're reported.
+const KINDS: [&str; 12] = [
+    "add",
+    "subtract",
+    "move right",
+    "move left",
+    "output",
+    "input",
+    "open loop",
+    "close loop",
+    "breakpoint",
+    "call",
+    "random",
+    "dump tape",
+];
+
+/// Longest bar of the notes per chord histogram.
+const BAR_WIDTH: usize = 40;
+
+/// Numbers about a song and the program in it, for golfing and for telling
+/// programs from songs that merely parse.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Stats {
+    pub chords: usize,
+    /// Number of chords that read as each of `KINDS`
+    pub kinds: [usize; 12],
+    /// How deep loops nest inside each other, 0 without any
+    pub max_depth: usize,
+    /// Rightmost cell the program can reach, `None` when loops move the
+    /// pointer too freely to tell or it doesn't parse
+    pub highest_cell: Option<usize>,
+    /// How many chords have each number of notes
+    pub notes_per_chord: BTreeMap<usize, usize>,
+    /// From the start of the song to the end of its last chord
+    pub length: Duration,
+    /// Why the song isn't a program, if it isn't
+    pub parse_error: Option<String>,
+}
+
+impl Stats {
+    pub fn new(parsed: &Parsed) -> Self {
+        let mut kinds = [0; 12];
+        let mut depth: usize = 0;
+        let mut max_depth = 0;
+        let mut notes_per_chord = BTreeMap::new();
+        for chord in &parsed.chords {
+            *notes_per_chord.entry(chord.notes.len()).or_insert(0) += 1;
+            let Ok(inst) = &chord.reading else {
+                continue;
+            };
+            let kind = match &inst.instruction {
+                IncrementCell { amount } if amount.0 < 0 => 1,
+                IncrementCell { .. } => 0,
+                MovePointer { amount } if *amount < 0 => 3,
+                MovePointer { .. } => 2,
+                OutputCell => 4,
+                InputCell => 5,
+                // the parser opens loops with a position and closes them without
+                Loop { .. } if inst.position.is_some() => {
+                    depth += 1;
+                    max_depth = max_depth.max(depth);
+                    6
+                }
+                Loop { .. } => {
+                    depth = depth.saturating_sub(1);
+                    7
+                }
+                Breakpoint => 8,
+                Call { .. } => 9,
+                Random => 10,
+                DumpTape => 11,
+            };
+            kinds[kind] += 1;
+        }
+        let end = parsed.chords.iter().map(|chord| chord.end).max().unwrap_or(0);
+        Stats {
+            chords: parsed.chords.len(),
+            kinds,
+            max_depth,
+            highest_cell: parsed.ast.as_ref().ok().and_then(|ast| ast.highest_cell()),
+            notes_per_chord,
+            length: parsed.source_map.time_at(end),
+            parse_error: parsed.errors.first().map(|err| err.error.describe()),
+        }
+    }
+
+    /// Number of chords that read as instructions.
+    pub fn instructions(&self) -> usize {
+        self.kinds.iter().sum()
+    }
+
+    /// Writes the stats for people.
+    ///
+    /// ```text
+    /// 45 of 48 chords are instructions, doesn't parse: chord's root isn't in C major
+    ///   add            12
+    ///   ...
+    /// loops nest 2 deep
+    /// tape used up to cell 4
+    /// song lasts 0:24, 112.5 instructions a minute
+    /// notes per chord
+    ///    1  ████████████████████████████████████████ 40
+    ///    3  ████ 5
+    /// ```
+    pub fn write_report(&self, out: &mut impl Write) -> io::Result<()> {
+        write!(out, "{} of {} chords are instructions", self.instructions(), self.chords)?;
+        match &self.parse_error {
+            Some(error) => writeln!(out, ", doesn't parse: {}", error)?,
+            None => writeln!(out)?,
+        }
+        for (kind, count) in KINDS.iter().zip(self.kinds) {
+            writeln!(out, "  {:<12} {:>5}", kind, count)?;
+        }
+        writeln!(out, "loops nest {} deep", self.max_depth)?;
+        match (&self.parse_error, self.highest_cell) {
+            (Some(_), _) => {}
+            (None, Some(cell)) => writeln!(out, "tape used up to cell {}", cell)?,
+            (None, None) => writeln!(out, "tape used isn't known, loops move the pointer too freely")?,
+        }
+        let seconds = self.length.as_secs();
+        write!(out, "song lasts {}:{:02}", seconds / 60, seconds % 60)?;
+        match self.length.as_secs_f64() / 60.0 {
+            minutes if minutes > 0.0 => writeln!(out, ", {:.1} instructions a minute", self.instructions() as f64 / minutes)?,
+            _ => writeln!(out)?,
+        }
+        writeln!(out, "notes per chord")?;
+        let most = self.notes_per_chord.values().copied().max().unwrap_or(0);
+        for (notes, &count) in &self.notes_per_chord {
+            let bar = (count * BAR_WIDTH).div_ceil(most.max(1));
+            writeln!(out, "  {:>3}  {} {}", notes, "█".repeat(bar), count)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+    use crate::parser::parse_all;
+    use crate::MidiProgram;
+
+    #[test]
+    fn counts_a_program() {
+        let parsed = parse_all(MidiProgram::from_bf_str("++[>[-]<.-]>>,").unwrap().to_smf());
+        let stats = Stats::new(&parsed);
+        assert_eq!(stats.kinds, [2, 2, 3, 1, 1, 1, 2, 2, 0, 0, 0, 0]);
+        assert_eq!((stats.max_depth, stats.highest_cell, stats.parse_error.as_deref()), (2, Some(2), None));
+        // outputs are triads
+        assert_eq!(stats.notes_per_chord, BTreeMap::from([(1, 13), (3, 1)]));
+
+        let mut report = vec![];
+        stats.write_report(&mut report).unwrap();
+        let report = String::from_utf8(report).unwrap();
+        assert!(report.starts_with("14 of 14 chords are instructions\n  add              2\n"));
+        assert!(report.contains("tape used up to cell 2\nsong lasts 0:00, "));
+        assert!(report.ends_with("    1  ████████████████████████████████████████ 13\n    3  ████ 1\n"));
+    }
+
+    #[test]
+    fn tells_songs_that_arent_programs() {
+        let parsed = parse_all(crate::brainf_to_smf("[["));
+        let stats = Stats::new(&parsed);
+        assert_eq!(stats.parse_error.as_deref(), Some("loop is never closed"));
+        assert_eq!((stats.max_depth, stats.highest_cell), (2, None));
+    }
+}
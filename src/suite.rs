@@ -0,0 +1,335 @@
+use std::fs;
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+
+use midly::{MetaMessage, Smf, TrackEventKind};
+
+use crate::interpreter::RunOptions;
+use crate::parser::{CellWidth, ParseOptions};
+use crate::{optimizer, vm};
+
+/// Output bytes shown around where a program's output first differs.
+const SNIPPET_BYTES: usize = 40;
+
+/// A program with the output it should write, and the input to give it.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Case {
+    /// File name of the program
+    pub name: String,
+    pub program: PathBuf,
+    /// The `.in` file next to the program, it gets the input in the song or
+    /// the default input of the run without one
+    pub input: Option<PathBuf>,
+    /// The `.out` file next to the program, `None` when only the song says
+    /// what it writes
+    pub expected: Option<PathBuf>,
+}
+
+/// Input and output a song carries for its program, in text or cue point
+/// meta events starting with `input:` or `expect:`. Each kind of event can be
+/// split over several, which are put together in the order of the tracks.
+/// `\n`, `\t`, `\r`, `\0`, `\\` and `\xNN` write bytes that are hard to put in
+/// a meta event by hand.
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct Inline {
+    pub input: Option<Vec<u8>>,
+    pub expected: Option<Vec<u8>>,
+}
+
+impl Inline {
+    pub fn read(smf: &Smf) -> Result<Self, String> {
+        let mut inline = Inline::default();
+        let events = smf.tracks.iter().flatten().filter_map(|event| match event.kind {
+            TrackEventKind::Meta(MetaMessage::Text(text) | MetaMessage::CuePoint(text)) => Some(text),
+            _ => None,
+        });
+        for text in events {
+            let (part, rest) = if let Some(rest) = text.strip_prefix(b"input:") {
+                (&mut inline.input, rest)
+            } else if let Some(rest) = text.strip_prefix(b"expect:") {
+                (&mut inline.expected, rest)
+            } else {
+                continue;
+            };
+            // `expect: Hello` reads better than `expect:Hello`
+            let rest = rest.strip_prefix(b" ").unwrap_or(rest);
+            part.get_or_insert_with(Vec::new).extend(unescape(rest)?);
+        }
+        Ok(inline)
+    }
+}
+
+/// How a case went, with what went wrong when it failed.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Outcome {
+    Passed,
+    Failed(Vec<String>),
+}
+
+/// Finds every `prog.mid` in `dir` with a `prog.out` next to it or an
+/// `expect:` meta event in it, by name. Programs with neither aren't tests.
+pub fn find_cases(dir: &Path) -> io::Result<Vec<Case>> {
+    let mut cases = vec![];
+    for entry in fs::read_dir(dir)? {
+        let program = entry?.path();
+        if !matches!(program.extension().and_then(|ext| ext.to_str()), Some("mid" | "midi")) {
+            continue;
+        }
+        let expected = Some(program.with_extension("out")).filter(|expected| expected.is_file());
+        if expected.is_none() && !expects_output(&program) {
+            log::info!("{} has no .out file or expect event, it isn't a test", program.display());
+            continue;
+        }
+        let input = Some(program.with_extension("in")).filter(|input| input.is_file());
+        let name = program.file_name().map_or_else(String::new, |name| name.to_string_lossy().into_owned());
+        cases.push(Case { name, program, input, expected });
+    }
+    cases.sort_by(|a, b| a.name.cmp(&b.name));
+    Ok(cases)
+}
+
+/// Runs the program of `case`, read with `parse`, on the bytecode VM with its
+/// input, or `default_input` when it has none, and compares what it writes to
+/// its `.out` file and the output the song expects, when it has either.
+pub fn run_case(case: &Case, parse: &ParseOptions, cell_width: CellWidth, limits: RunOptions, default_input: &[u8]) -> Outcome {
+    match try_case(case, parse, cell_width, limits, default_input) {
+        Ok(outcome) => outcome,
+        Err(message) => Outcome::Failed(vec![message]),
+    }
+}
+
+fn try_case(case: &Case, parse: &ParseOptions, cell_width: CellWidth, limits: RunOptions, default_input: &[u8]) -> Result<Outcome, String> {
+    let read = |path: &Path| fs::read(path).map_err(|err| format!("can't read {}: {}", path.display(), err));
+    let program = read(&case.program)?;
+    // anything that isn't a MIDI file fails to parse in `run`
+    let inline = match Smf::parse(&program) {
+        Ok(smf) => Inline::read(&smf).map_err(|err| format!("bad meta event: {}", err))?,
+        Err(_) => Inline::default(),
+    };
+    let input = match &case.input {
+        Some(path) => read(path)?,
+        None => inline.input.unwrap_or_else(|| default_input.to_vec()),
+    };
+    let mut expected = vec![];
+    if let Some(path) = &case.expected {
+        let name = path.file_name().map_or_else(String::new, |name| name.to_string_lossy().into_owned());
+        expected.push((name, read(path)?));
+    }
+    if let Some(inline) = inline.expected {
+        expected.push(("the expect events".to_owned(), inline));
+    }
+    let output = run(&case.name, &program, parse, &input, cell_width, limits)?;
+    let messages: Vec<String> = expected.iter().flat_map(|(what, expected)| compare(what, expected, &output)).collect();
+    Ok(if messages.is_empty() { Outcome::Passed } else { Outcome::Failed(messages) })
+}
+
+/// Runs every case, writing how each went as TAP to `out` as it's done.
+/// Returns whether they all passed.
+pub fn run_all(
+    cases: &[Case],
+    parse: &ParseOptions,
+    cell_width: CellWidth,
+    limits: RunOptions,
+    default_input: &[u8],
+    out: &mut impl Write,
+) -> io::Result<bool> {
+    writeln!(out, "TAP version 13")?;
+    if cases.is_empty() {
+        writeln!(out, "1..0 # SKIP no programs with .out files")?;
+        return Ok(true);
+    }
+    writeln!(out, "1..{}", cases.len())?;
+    let mut failed = 0;
+    for (number, case) in cases.iter().enumerate() {
+        match run_case(case, parse, cell_width, limits, default_input) {
+            Outcome::Passed => writeln!(out, "ok {} - {}", number + 1, case.name)?,
+            Outcome::Failed(messages) => {
+                failed += 1;
+                writeln!(out, "not ok {} - {}", number + 1, case.name)?;
+                for message in messages {
+                    writeln!(out, "# {}", message)?;
+                }
+            }
+        }
+        out.flush()?;
+    }
+    writeln!(out, "# {} passed, {} failed", cases.len() - failed, failed)?;
+    Ok(failed == 0)
+}
+
+// parses, optimizes and runs a program the way `interpret_file` does, on
+// `input` instead of stdin, returning what it wrote
+fn run(name: &str, bytes: &[u8], parse: &ParseOptions, input: &[u8], cell_width: CellWidth, limits: RunOptions) -> Result<Vec<u8>, String> {
+    let (program, source_map, data) = crate::parse_source(name, bytes, parse).map_err(|failure| failure.message)?;
+    let code = vm::Bytecode::new(&optimizer::optimize_with_data(program, cell_width, &limits, &data));
+    let mut output = vec![];
+    let mut machine = crate::new_vm(cell_width, limits);
+    machine.load_data(&data);
+    machine
+        .run(&code, &mut &input[..], &mut output)
+        .map_err(|err| format!("program failed: {}", crate::run_error(err, &source_map)))?;
+    Ok(output)
+}
+
+// whether the song in `path` has an `expect:` event, songs that can't be
+// read don't
+fn expects_output(path: &Path) -> bool {
+    let Ok(bytes) = fs::read(path) else {
+        return false;
+    };
+    Smf::parse(&bytes).is_ok_and(|smf| {
+        smf.tracks.iter().flatten().any(|event| {
+            matches!(event.kind, TrackEventKind::Meta(MetaMessage::Text(text) | MetaMessage::CuePoint(text))
+                if text.starts_with(b"expect:"))
+        })
+    })
+}
+
+// what's wrong with `output`, nothing when it's `expected` from `what`
+fn compare(what: &str, expected: &[u8], output: &[u8]) -> Vec<String> {
+    let Some(index) = (0..=expected.len().max(output.len())).find(|&idx| expected.get(idx) != output.get(idx)) else {
+        return vec![];
+    };
+    let from = index.saturating_sub(SNIPPET_BYTES / 2);
+    vec![
+        format!(
+            "output differs from {} at byte {}, {} bytes expected and {} written",
+            what,
+            index,
+            expected.len(),
+            output.len()
+        ),
+        format!("  expected: {}", snippet(expected, from)),
+        format!("       got: {}", snippet(output, from)),
+    ]
+}
+
+fn unescape(text: &[u8]) -> Result<Vec<u8>, String> {
+    let mut bytes = vec![];
+    let mut rest = text.iter();
+    while let Some(&byte) = rest.next() {
+        if byte != b'\\' {
+            bytes.push(byte);
+            continue;
+        }
+        let escaped = match rest.next() {
+            Some(b'n') => b'\n',
+            Some(b't') => b'\t',
+            Some(b'r') => b'\r',
+            Some(b'0') => 0,
+            Some(b'\\') => b'\\',
+            Some(b'x') => {
+                let digits = [rest.next(), rest.next()].map(|digit| digit.map(|&digit| char::from(digit)));
+                let hex: Option<String> = digits.into_iter().collect();
+                hex.and_then(|hex| u8::from_str_radix(&hex, 16).ok()).ok_or("`\\x` needs two hex digits")?
+            }
+            Some(&other) => return Err(format!("unknown escape `\\{}`", char::from(other))),
+            None => return Err("`\\` at the end".to_owned()),
+        };
+        bytes.push(escaped);
+    }
+    Ok(bytes)
+}
+
+// up to `SNIPPET_BYTES` of `bytes` from `from`, escaped
+fn snippet(bytes: &[u8], from: usize) -> String {
+    let to = bytes.len().min(from + SNIPPET_BYTES);
+    let part = String::from_utf8_lossy(bytes.get(from..to).unwrap_or_default());
+    let before = if from > 0 { "…" } else { "" };
+    let after = if to < bytes.len() { "…" } else { "" };
+    format!("{}\"{}\"{}", before, part.escape_debug(), after)
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+    use crate::MidiProgram;
+    use midly::num::u28;
+    use midly::TrackEvent;
+
+    fn write_song(path: &Path, bf: &str, texts: &[&'static [u8]]) {
+        let mut smf = MidiProgram::from_bf_str(bf).unwrap().to_smf();
+        for &text in texts {
+            let kind = TrackEventKind::Meta(MetaMessage::Text(text));
+            smf.tracks[0].insert(0, TrackEvent { delta: u28::from(0), kind });
+        }
+        let mut bytes = vec![];
+        smf.write_std(&mut bytes).unwrap();
+        fs::write(path, bytes).unwrap();
+    }
+
+    fn test_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("midilang-suite-{}-{}", name, std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    #[test]
+    fn runs_programs_with_their_input() {
+        let dir = test_dir("files");
+        // echoes two bytes
+        write_song(&dir.join("echo.mid"), ",.,.", &[]);
+        fs::write(dir.join("echo.in"), "hi").unwrap();
+        fs::write(dir.join("echo.out"), "hi").unwrap();
+        write_song(&dir.join("wrong.mid"), "+++.", &[]);
+        fs::write(dir.join("wrong.out"), "\x02").unwrap();
+        // no .out, so not a test
+        write_song(&dir.join("other.mid"), ".", &[]);
+
+        let cases = find_cases(&dir).unwrap();
+        let names: Vec<&str> = cases.iter().map(|case| case.name.as_str()).collect();
+        assert_eq!(names, ["echo.mid", "wrong.mid"]);
+        assert_eq!(cases[0].input, Some(dir.join("echo.in")));
+        assert_eq!(cases[1].input, None);
+
+        let mut tap = vec![];
+        let passed = run_all(&cases, &ParseOptions::default(), CellWidth::default(), RunOptions::default(), &[], &mut tap).unwrap();
+        fs::remove_dir_all(&dir).unwrap();
+        assert!(!passed);
+        assert_eq!(
+            String::from_utf8(tap).unwrap(),
+            "TAP version 13\n1..2\nok 1 - echo.mid\nnot ok 2 - wrong.mid\n\
+             # output differs from wrong.out at byte 0, 1 bytes expected and 1 written\n\
+             #   expected: \"\\u{2}\"\n\
+             #        got: \"\\u{3}\"\n\
+             # 1 passed, 1 failed\n"
+        );
+    }
+
+    #[test]
+    fn runs_songs_that_carry_their_own_test() {
+        let dir = test_dir("inline");
+        // inserted at the start, so the last one comes first
+        write_song(&dir.join("echo.mid"), ",.,.,.", &[b"expect: \\x41\\n", b"expect:h", b"input: hA\\n"]);
+        write_song(&dir.join("wrong.mid"), "+++.", &[b"expect: \\x02"]);
+        fs::write(dir.join("wrong.out"), "\x03").unwrap();
+
+        let cases = find_cases(&dir).unwrap();
+        assert_eq!((cases[0].input.as_ref(), cases[0].expected.as_ref()), (None, None));
+        assert_eq!(run_case(&cases[0], &ParseOptions::default(), CellWidth::default(), RunOptions::default(), &[]), Outcome::Passed);
+        // the .out file passes but the song doesn't
+        let Outcome::Failed(messages) = run_case(&cases[1], &ParseOptions::default(), CellWidth::default(), RunOptions::default(), &[]) else {
+            panic!("wrong expect event passed");
+        };
+        fs::remove_dir_all(&dir).unwrap();
+        assert_eq!(messages[0], "output differs from the expect events at byte 0, 1 bytes expected and 1 written");
+        assert_eq!(messages.len(), 3);
+    }
+
+    #[test]
+    fn reads_escapes() {
+        assert_eq!(unescape(b"a\\n\\t\\\\\\x7f\\0").unwrap(), b"a\n\t\\\x7f\0");
+        assert_eq!(unescape(b"\\q").unwrap_err(), "unknown escape `\\q`");
+        assert_eq!(unescape(b"\\x4").unwrap_err(), "`\\x` needs two hex digits");
+    }
+
+    #[test]
+    fn shows_where_output_differs() {
+        assert!(compare("out", b"abc", b"abc").is_empty());
+        let long = [b'a'; 100];
+        let messages = compare("out", &long, &long[..60]);
+        assert_eq!(messages[0], "output differs from out at byte 60, 100 bytes expected and 60 written");
+        assert_eq!(messages[2], format!("       got: …\"{}\"", "a".repeat(20)));
+    }
+}
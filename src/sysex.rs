@@ -0,0 +1,286 @@
+use std::fs;
+use std::num::Wrapping;
+use std::path::{Component, Path, PathBuf};
+
+use midly::{MetaMessage, TrackEventKind};
+use serde::{Deserialize, Serialize};
+
+use crate::formatter;
+use crate::interpreter::TapeSnapshot;
+use crate::parser::{MidiASTBuilder, MidiInstruction};
+
+/// Manufacturer ID of midilang's SysEx messages. 0x7D is set aside for
+/// non-commercial use, so no synth takes them for its own and players skip
+/// them like any SysEx they don't know.
+pub const MANUFACTURER_ID: u8 = 0x7D;
+
+/// Follows the manufacturer ID, to tell midilang's messages apart from other
+/// non-commercial ones.
+pub const TAG: &[u8] = b"ml";
+
+/// End of exclusive, the byte every SysEx message ends on.
+const EOX: u8 = 0xF7;
+
+/// Starts the text of a marker including another file, like `include riffs/print-number.mid`.
+pub const INCLUDE_MARKER: &str = "include ";
+
+const INCLUDE: u8 = 0x01;
+const TAPE: u8 = 0x02;
+const BRAINF: u8 = 0x03;
+
+/// An extended instruction carried by a SysEx message, for what chords can't
+/// say or would take too many of to say.
+///
+/// Messages are `F0 7D 'm' 'l'`, a byte for the kind of directive, its payload
+/// 7 bits to a byte, and `F7`. The payload is packed like most SysEx dumps,
+/// every 7 bytes after a byte with their high bits.
+///
+/// The parser plays a directive as the chords it stands for, at the tick of its
+/// message. Includes can be markers too, see `INCLUDE_MARKER`, which DAWs
+/// show on their timeline.
+#[derive(Debug, PartialEq, Eq, Clone, Serialize, Deserialize)]
+pub enum Directive {
+    /// The chords of every track of another MIDI file, found like
+    /// `Includes::find` finds it
+    Include(String),
+    /// A `TapeSnapshot` as `to_bytes` writes it, setting the cells from the
+    /// current one on to its cells and leaving the pointer on its pointer
+    Tape(Vec<u8>),
+    /// Brainf, one chord per instruction like `from_brainf`
+    Brainf(String),
+}
+
+impl Directive {
+    /// Reads the data of a SysEx event as midly hands it over, after the `F0`.
+    /// `None` for messages that aren't midilang's, and an error for ones that
+    /// are but can't be read.
+    pub fn from_sysex(data: &[u8]) -> Option<Result<Self, String>> {
+        let data = data.strip_suffix(&[EOX]).unwrap_or(data);
+        let rest = data.strip_prefix(&[MANUFACTURER_ID])?.strip_prefix(TAG)?;
+        let Some((&kind, packed)) = rest.split_first() else {
+            return Some(Err("directive has no kind".to_owned()));
+        };
+        let payload = unpack(packed);
+        let text = |payload: Vec<u8>| String::from_utf8(payload).map_err(|_| "directive's text isn't UTF-8".to_owned());
+        Some(match kind {
+            INCLUDE => text(payload).map(Directive::Include),
+            TAPE if TapeSnapshot::from_bytes(&payload).is_none() => Err("directive's tape isn't a snapshot".to_owned()),
+            TAPE => Ok(Directive::Tape(payload)),
+            BRAINF => text(payload).map(Directive::Brainf),
+            _ => Err(format!("no directive is kind {:#04x}", kind)),
+        })
+    }
+
+    /// Reads the text of a marker, `None` unless it includes a file.
+    pub fn from_marker(text: &[u8]) -> Option<Self> {
+        let name = std::str::from_utf8(text).ok()?.strip_prefix(INCLUDE_MARKER)?.trim();
+        Some(Directive::Include(name.to_owned()))
+    }
+
+    /// Sets the tape to `snapshot` from the current cell on.
+    pub fn tape(snapshot: &TapeSnapshot) -> Self {
+        Directive::Tape(snapshot.to_bytes())
+    }
+
+    /// The data of a SysEx event carrying the directive, after the `F0`, to
+    /// write with `TrackEventKind::SysEx`.
+    pub fn to_sysex(&self) -> Vec<u8> {
+        let (kind, payload) = match self {
+            Directive::Include(path) => (INCLUDE, path.as_bytes()),
+            Directive::Tape(snapshot) => (TAPE, &snapshot[..]),
+            Directive::Brainf(bf) => (BRAINF, bf.as_bytes()),
+        };
+        let mut data = vec![MANUFACTURER_ID];
+        data.extend_from_slice(TAG);
+        data.push(kind);
+        data.extend(pack(payload));
+        data.push(EOX);
+        data
+    }
+
+    /// The chords the directive stands for, lowest note first, those of the
+    /// file an include names as `include` reads them.
+    pub(crate) fn chords<F>(&self, include: F) -> Result<Vec<Vec<u8>>, String>
+    where
+        F: FnOnce(&str) -> Result<Vec<Vec<u8>>, String>,
+    {
+        match self {
+            Directive::Include(path) => include(path),
+            Directive::Tape(snapshot) => {
+                let snapshot = TapeSnapshot::from_bytes(snapshot).ok_or("directive's tape isn't a snapshot")?;
+                Ok(formatter::chords(&set_tape(snapshot.cells(), snapshot.pointer())))
+            }
+            Directive::Brainf(bf) => Ok(crate::brainf_chords(bf)),
+        }
+    }
+}
+
+/// Whether the event includes another file, so what the song compiles to
+/// can change without the song changing.
+pub(crate) fn is_include(kind: &TrackEventKind) -> bool {
+    let directive = match kind {
+        TrackEventKind::SysEx(data) => Directive::from_sysex(data).and_then(Result::ok),
+        TrackEventKind::Meta(MetaMessage::Marker(text)) => Directive::from_marker(text),
+        _ => None,
+    };
+    matches!(directive, Some(Directive::Include(_)))
+}
+
+/// Where the file being parsed is, for includes to find the files they name,
+/// and the files including it, so includes going round in a circle fail instead
+/// of going on forever.
+#[derive(Debug, Clone, Default)]
+pub struct Includes {
+    /// Directory of the file, `None` when it isn't on disk
+    dir: Option<PathBuf>,
+    /// Files including the file and the file itself, outermost first
+    stack: Vec<PathBuf>,
+}
+
+impl Includes {
+    /// Includes of the file at `path`.
+    pub fn for_file(path: &Path) -> Self {
+        Includes {
+            dir: path.parent().map(Path::to_owned),
+            stack: fs::canonicalize(path).into_iter().collect(),
+        }
+    }
+
+    /// Where the file `name` is: next to the file including it or in the first
+    /// of `include_dirs` that has it. Names are relative and stay inside those
+    /// directories, files anywhere else can't be included.
+    pub fn find(&self, name: &str, include_dirs: &[PathBuf]) -> Result<PathBuf, String> {
+        let inside = Path::new(name).components().all(|part| matches!(part, Component::Normal(_) | Component::CurDir));
+        if !inside {
+            return Err(format!("can't include {}, includes are named relative to the song and can't go up", name));
+        }
+        self.dir
+            .iter()
+            .chain(include_dirs)
+            .map(|dir| dir.join(name))
+            .find(|path| path.is_file())
+            .ok_or_else(|| format!("{} isn't next to the song or in an include directory", name))
+    }
+
+    /// Finds the file `name` and the includes it's read with, failing when
+    /// it's already being included.
+    pub(crate) fn enter(&self, name: &str, include_dirs: &[PathBuf]) -> Result<(PathBuf, Includes), String> {
+        let path = self.find(name, include_dirs)?;
+        let canonical = fs::canonicalize(&path).map_err(|err| format!("can't include {}, {}", name, err))?;
+        if self.stack.contains(&canonical) {
+            return Err(format!("{} ends up including itself", path.display()));
+        }
+        let mut stack = self.stack.clone();
+        stack.push(canonical);
+        let dir = path.parent().map(Path::to_owned);
+        Ok((path, Includes { dir, stack }))
+    }
+}
+
+/// The chords a string literal plays as, setting the cells from the current
+/// one on to `bytes` and going back to it.
+pub(crate) fn set_cells(bytes: &[u8]) -> Vec<Vec<u8>> {
+    let cells: Vec<_> = bytes.iter().map(|&byte| Wrapping(i32::from(byte))).collect();
+    formatter::chords(&set_tape(&cells, 0))
+}
+
+// clears every cell from the pointer on and adds its value in, then goes back
+// to `pointer` cells past where it started
+fn set_tape(cells: &[crate::parser::Cell], pointer: usize) -> Vec<MidiInstruction> {
+    let mut builder = MidiASTBuilder::new();
+    for (idx, &cell) in cells.iter().enumerate() {
+        if idx > 0 {
+            builder.push(MidiInstruction::new_move(1)).expect("moves always push");
+        }
+        for inst in [
+            MidiInstruction::new_open_loop(),
+            MidiInstruction::new_inc(Wrapping(-1)),
+            MidiInstruction::new_close_loop(),
+            MidiInstruction::new_inc(cell),
+        ] {
+            builder.push(inst).expect("the clearing loop is closed");
+        }
+    }
+    let back = pointer as isize - cells.len().saturating_sub(1) as isize;
+    builder.push(MidiInstruction::new_move(back)).expect("moves always push");
+    builder.into_mast().expect("the clearing loops are closed")
+}
+
+// `bytes` 7 bits to a byte, every 7 of them after a byte with their high bits
+fn pack(bytes: &[u8]) -> Vec<u8> {
+    let mut packed = vec![];
+    for group in bytes.chunks(7) {
+        let high = group.iter().enumerate().fold(0, |high, (idx, byte)| high | (byte >> 7) << idx);
+        packed.push(high);
+        packed.extend(group.iter().map(|byte| byte & 0x7F));
+    }
+    packed
+}
+
+fn unpack(packed: &[u8]) -> Vec<u8> {
+    let mut bytes = vec![];
+    for group in packed.chunks(8) {
+        let Some((high, low)) = group.split_first() else {
+            continue;
+        };
+        bytes.extend(low.iter().enumerate().map(|(idx, byte)| byte | (high >> idx & 1) << 7));
+    }
+    bytes
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+    use crate::interpreter::Interpreter;
+    use crate::parser::parse;
+    use midly::Smf;
+    use std::io;
+
+    fn program(bf: &str) -> Vec<MidiInstruction> {
+        parse(crate::brainf_to_smf(bf)).unwrap()
+    }
+
+    #[test]
+    fn directives_go_through_sysex() {
+        let mut interp = Interpreter::new();
+        interp.run(&program("+++>-"), &mut io::empty(), &mut io::sink()).unwrap();
+        for directive in [
+            Directive::Include("lib/prelude.mid".to_owned()),
+            Directive::tape(&interp.snapshot()),
+            Directive::Brainf("+[>,.<]".to_owned()),
+        ] {
+            let data = directive.to_sysex();
+            assert!(data.iter().all(|&byte| byte < 0x80 || byte == EOX));
+            assert_eq!(Directive::from_sysex(&data), Some(Ok(directive)));
+        }
+        assert_eq!(Directive::from_sysex(&[0x43, 0x10, EOX]), None);
+        assert!(matches!(Directive::from_sysex(&[MANUFACTURER_ID, b'm', b'l', 0x7F, EOX]), Some(Err(_))));
+        assert!(matches!(Directive::from_sysex(&[MANUFACTURER_ID, b'm', b'l', TAPE, 0, 1, EOX]), Some(Err(_))));
+    }
+
+    #[test]
+    fn packs_high_bits() {
+        let bytes: Vec<u8> = (0..=255).collect();
+        let packed = pack(&bytes);
+        assert!(packed.iter().all(|&byte| byte < 0x80));
+        assert_eq!(unpack(&packed), bytes);
+    }
+
+    #[test]
+    fn tape_directives_set_cells_from_the_pointer() {
+        let mut from = Interpreter::new();
+        let setup = program("+++>-->+<");
+        from.run(&setup, &mut io::empty(), &mut io::sink()).unwrap();
+        let chords = Directive::tape(&from.snapshot()).chords(|_| unreachable!()).unwrap();
+        let song = formatter::song(&chords);
+        let mut bytes = vec![];
+        song.write_std(&mut bytes).unwrap();
+
+        let mut to = Interpreter::new();
+        to.run(&program(">+++++>>>+<<<"), &mut io::empty(), &mut io::sink()).unwrap();
+        to.run(&parse(Smf::parse(&bytes).unwrap()).unwrap(), &mut io::empty(), &mut io::sink()).unwrap();
+        assert_eq!(to.tape(), [0, 3, 254, 1, 1].map(Wrapping));
+        assert_eq!(to.pointer(), 1 + from.pointer());
+    }
+}
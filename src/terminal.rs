@@ -0,0 +1,377 @@
+//! Input straight from the terminal for `run --input-mode`, a key at a time
+//! for games or a line at a time with readline style editing.
+
+use std::collections::VecDeque;
+use std::io::{self, IsTerminal, Read, Write};
+
+use crossterm::cursor::MoveLeft;
+use crossterm::event::{self, Event, KeyCode, KeyEvent, KeyEventKind, KeyModifiers};
+use crossterm::style::Print;
+use crossterm::terminal::{disable_raw_mode, enable_raw_mode, Clear, ClearType};
+use crossterm::queue;
+
+use crate::interpreter::InputMode;
+
+/// Stdin read in `mode` and stdout to go with it when stdin is a terminal,
+/// and the plain ones when it isn't, since there's nothing to edit then.
+pub fn stdio(mode: InputMode) -> io::Result<(Box<dyn Read>, Box<dyn Write>)> {
+    if !io::stdin().is_terminal() {
+        return Ok((Box::new(io::stdin().lock()), Box::new(io::stdout().lock())));
+    }
+    let input = TerminalInput::new(mode)?;
+    let output: Box<dyn Write> = match mode {
+        InputMode::Raw if io::stdout().is_terminal() => Box::new(RawOutput(io::stdout().lock())),
+        _ => Box::new(io::stdout().lock()),
+    };
+    Ok((Box::new(input), output))
+}
+
+/// Stdin of a terminal, read in raw mode for as long as this is around or only
+/// while a line is being edited.
+pub struct TerminalInput {
+    mode: InputMode,
+    /// Bytes of the keys or the line read, the program hasn't taken yet
+    pending: VecDeque<u8>,
+    /// Lines entered so far, oldest first
+    history: Vec<String>,
+}
+
+impl TerminalInput {
+    /// Starts reading the terminal in `mode`. Raw mode puts it in raw mode right
+    /// away, and it goes back to how it was when this is dropped.
+    pub fn new(mode: InputMode) -> io::Result<Self> {
+        if mode == InputMode::Raw {
+            enable_raw_mode()?;
+        }
+        Ok(TerminalInput {
+            mode,
+            pending: VecDeque::new(),
+            history: vec![],
+        })
+    }
+
+    // waits for keys until one of them has bytes for the program, false at EOF
+    fn read_key(&mut self) -> io::Result<bool> {
+        loop {
+            match raw_key(next_key()?) {
+                Key::Bytes(bytes) => {
+                    self.pending.extend(bytes);
+                    return Ok(true);
+                }
+                Key::Eof => return Ok(false),
+                Key::Interrupt => return Err(io::ErrorKind::Interrupted.into()),
+                Key::Ignored => {}
+            }
+        }
+    }
+
+    // reads a line with the terminal in raw mode only while it's edited, false
+    // at EOF
+    fn read_line(&mut self) -> io::Result<bool> {
+        enable_raw_mode()?;
+        let line = self.edit_line();
+        disable_raw_mode()?;
+        let Some(line) = line? else {
+            return Ok(false);
+        };
+        self.pending.extend(line.as_bytes());
+        self.pending.push_back(b'\n');
+        if !line.is_empty() {
+            self.history.push(line);
+        }
+        Ok(true)
+    }
+
+    fn edit_line(&self) -> io::Result<Option<String>> {
+        let mut editor = LineEditor::new(self.history.len());
+        let mut stdout = io::stdout();
+        loop {
+            let shown = editor.at;
+            match editor.press(next_key()?, &self.history) {
+                Edit::Changed => editor.redraw(&mut stdout, shown)?,
+                Edit::Done => {
+                    stdout.write_all(b"\r\n")?;
+                    stdout.flush()?;
+                    return Ok(Some(editor.line.into_iter().collect()));
+                }
+                Edit::Eof => return Ok(None),
+                Edit::Interrupt => return Err(io::ErrorKind::Interrupted.into()),
+                Edit::Ignored => {}
+            }
+        }
+    }
+}
+
+impl Read for TerminalInput {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if self.pending.is_empty() {
+            let more = match self.mode {
+                InputMode::Raw => self.read_key()?,
+                InputMode::Line => self.read_line()?,
+            };
+            if !more {
+                return Ok(0);
+            }
+        }
+        let count = buf.len().min(self.pending.len());
+        for (slot, byte) in buf.iter_mut().zip(self.pending.drain(..count)) {
+            *slot = byte;
+        }
+        Ok(count)
+    }
+}
+
+impl Drop for TerminalInput {
+    fn drop(&mut self) {
+        if self.mode == InputMode::Raw {
+            let _ = disable_raw_mode();
+        }
+    }
+}
+
+/// Output going to a terminal in raw mode, which doesn't go back to the start
+/// of the line on `\n` by itself.
+pub struct RawOutput<W>(pub W);
+
+impl<W: Write> Write for RawOutput<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        for line in buf.split_inclusive(|&byte| byte == b'\n') {
+            match line.strip_suffix(b"\n") {
+                Some(text) => {
+                    self.0.write_all(text)?;
+                    self.0.write_all(b"\r\n")?;
+                }
+                None => self.0.write_all(line)?,
+            }
+        }
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.0.flush()
+    }
+}
+
+// the next key pressed, or repeated while it's held
+fn next_key() -> io::Result<KeyEvent> {
+    loop {
+        if let Event::Key(key) = event::read()? {
+            if key.kind != KeyEventKind::Release {
+                return Ok(key);
+            }
+        }
+    }
+}
+
+/// What a key pressed in raw mode gives the program.
+#[derive(Debug, PartialEq, Eq)]
+enum Key {
+    Bytes(Vec<u8>),
+    Eof,
+    Interrupt,
+    /// Keys without bytes, like shift on its own
+    Ignored,
+}
+
+// the bytes a terminal sends for `key`, control characters for ctrl and
+// ANSI escapes for arrows
+fn raw_key(key: KeyEvent) -> Key {
+    let ctrl = key.modifiers.contains(KeyModifiers::CONTROL);
+    let bytes: &[u8] = match key.code {
+        KeyCode::Char('c') if ctrl => return Key::Interrupt,
+        KeyCode::Char('d') if ctrl => return Key::Eof,
+        KeyCode::Char(c) if ctrl && c.is_ascii_alphabetic() => return Key::Bytes(vec![c.to_ascii_lowercase() as u8 & 0x1f]),
+        KeyCode::Char(c) => return Key::Bytes(c.to_string().into_bytes()),
+        KeyCode::Enter => b"\n",
+        KeyCode::Tab => b"\t",
+        KeyCode::Backspace => b"\x7f",
+        KeyCode::Esc => b"\x1b",
+        KeyCode::Up => b"\x1b[A",
+        KeyCode::Down => b"\x1b[B",
+        KeyCode::Right => b"\x1b[C",
+        KeyCode::Left => b"\x1b[D",
+        KeyCode::Home => b"\x1b[H",
+        KeyCode::End => b"\x1b[F",
+        KeyCode::Delete => b"\x1b[3~",
+        _ => return Key::Ignored,
+    };
+    Key::Bytes(bytes.to_vec())
+}
+
+/// What a key did to the line being edited.
+#[derive(Debug, PartialEq, Eq)]
+enum Edit {
+    Changed,
+    /// Enter, the line is finished
+    Done,
+    Eof,
+    Interrupt,
+    Ignored,
+}
+
+/// A line being edited, with emacs keys like readline's.
+struct LineEditor {
+    line: Vec<char>,
+    /// Cursor, in chars
+    at: usize,
+    /// Index in the history of the line shown, its length for a new one
+    recalled: usize,
+}
+
+impl LineEditor {
+    fn new(history_len: usize) -> Self {
+        LineEditor {
+            line: vec![],
+            at: 0,
+            recalled: history_len,
+        }
+    }
+
+    fn press(&mut self, key: KeyEvent, history: &[String]) -> Edit {
+        let ctrl = key.modifiers.contains(KeyModifiers::CONTROL);
+        match key.code {
+            KeyCode::Char('c') if ctrl => return Edit::Interrupt,
+            KeyCode::Char('d') if ctrl && self.line.is_empty() => return Edit::Eof,
+            KeyCode::Enter => return Edit::Done,
+            KeyCode::Char('a') if ctrl => self.at = 0,
+            KeyCode::Home => self.at = 0,
+            KeyCode::Char('e') if ctrl => self.at = self.line.len(),
+            KeyCode::End => self.at = self.line.len(),
+            KeyCode::Char('b') if ctrl => self.at = self.at.saturating_sub(1),
+            KeyCode::Left => self.at = self.at.saturating_sub(1),
+            KeyCode::Char('f') if ctrl => self.at = (self.at + 1).min(self.line.len()),
+            KeyCode::Right => self.at = (self.at + 1).min(self.line.len()),
+            KeyCode::Char('u') if ctrl => {
+                self.line.drain(..self.at);
+                self.at = 0;
+            }
+            KeyCode::Char('k') if ctrl => self.line.truncate(self.at),
+            KeyCode::Char('w') if ctrl => {
+                // the word before the cursor and the spaces after it
+                let mut start = self.at;
+                while start > 0 && self.line[start - 1] == ' ' {
+                    start -= 1;
+                }
+                while start > 0 && self.line[start - 1] != ' ' {
+                    start -= 1;
+                }
+                self.line.drain(start..self.at);
+                self.at = start;
+            }
+            KeyCode::Char('d') if ctrl => return self.delete(),
+            KeyCode::Delete => return self.delete(),
+            KeyCode::Backspace if self.at > 0 => {
+                self.at -= 1;
+                self.line.remove(self.at);
+            }
+            KeyCode::Up if self.recalled > 0 => self.recall(self.recalled - 1, history),
+            KeyCode::Down if self.recalled < history.len() => self.recall(self.recalled + 1, history),
+            KeyCode::Char(c) if !ctrl => {
+                self.line.insert(self.at, c);
+                self.at += 1;
+            }
+            _ => return Edit::Ignored,
+        }
+        Edit::Changed
+    }
+
+    fn delete(&mut self) -> Edit {
+        if self.at == self.line.len() {
+            return Edit::Ignored;
+        }
+        self.line.remove(self.at);
+        Edit::Changed
+    }
+
+    // shows line `index` of the history, or a new one past its end
+    fn recall(&mut self, index: usize, history: &[String]) {
+        self.recalled = index;
+        self.line = history.get(index).map_or_else(Vec::new, |line| line.chars().collect());
+        self.at = self.line.len();
+    }
+
+    // writes the line again over what's on screen, where the cursor was at
+    // `shown`
+    fn redraw(&self, out: &mut impl Write, shown: usize) -> io::Result<()> {
+        // moving by 0 moves by 1
+        if shown > 0 {
+            queue!(out, MoveLeft(shown as u16))?;
+        }
+        let line: String = self.line.iter().collect();
+        queue!(out, Clear(ClearType::UntilNewLine), Print(line))?;
+        if self.line.len() > self.at {
+            queue!(out, MoveLeft((self.line.len() - self.at) as u16))?;
+        }
+        out.flush()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+    fn press(editor: &mut LineEditor, code: KeyCode, modifiers: KeyModifiers) -> Edit {
+        editor.press(KeyEvent::new(code, modifiers), &["first".to_owned(), "second".to_owned()])
+    }
+
+    fn typed(text: &str) -> LineEditor {
+        let mut editor = LineEditor::new(2);
+        for c in text.chars() {
+            press(&mut editor, KeyCode::Char(c), KeyModifiers::NONE);
+        }
+        editor
+    }
+
+    #[test]
+    fn sends_keys_like_a_terminal() {
+        assert_eq!(raw_key(KeyEvent::new(KeyCode::Char('é'), KeyModifiers::NONE)), Key::Bytes("é".into()));
+        assert_eq!(raw_key(KeyEvent::new(KeyCode::Char('L'), KeyModifiers::CONTROL)), Key::Bytes(vec![0x0c]));
+        assert_eq!(raw_key(KeyEvent::new(KeyCode::Up, KeyModifiers::NONE)), Key::Bytes(b"\x1b[A".to_vec()));
+        assert_eq!(raw_key(KeyEvent::new(KeyCode::Enter, KeyModifiers::NONE)), Key::Bytes(b"\n".to_vec()));
+        assert_eq!(raw_key(KeyEvent::new(KeyCode::Char('d'), KeyModifiers::CONTROL)), Key::Eof);
+        assert_eq!(raw_key(KeyEvent::new(KeyCode::Char('c'), KeyModifiers::CONTROL)), Key::Interrupt);
+        assert_eq!(raw_key(KeyEvent::new(KeyCode::F(1), KeyModifiers::NONE)), Key::Ignored);
+    }
+
+    #[test]
+    fn edits_lines_with_readline_keys() {
+        let mut editor = typed("hello world");
+        press(&mut editor, KeyCode::Char('w'), KeyModifiers::CONTROL);
+        assert_eq!(editor.line.iter().collect::<String>(), "hello ");
+        press(&mut editor, KeyCode::Char('a'), KeyModifiers::CONTROL);
+        press(&mut editor, KeyCode::Right, KeyModifiers::NONE);
+        press(&mut editor, KeyCode::Backspace, KeyModifiers::NONE);
+        press(&mut editor, KeyCode::Char('j'), KeyModifiers::NONE);
+        assert_eq!(editor.line.iter().collect::<String>(), "jello ");
+        assert_eq!(editor.at, 1);
+        press(&mut editor, KeyCode::Char('k'), KeyModifiers::CONTROL);
+        assert_eq!(editor.line, ['j']);
+        assert_eq!(press(&mut editor, KeyCode::Delete, KeyModifiers::NONE), Edit::Ignored);
+        assert_eq!(press(&mut editor, KeyCode::Enter, KeyModifiers::NONE), Edit::Done);
+        // ctrl-d only ends the input on an empty line
+        assert_eq!(press(&mut editor, KeyCode::Char('d'), KeyModifiers::CONTROL), Edit::Ignored);
+        press(&mut editor, KeyCode::Char('u'), KeyModifiers::CONTROL);
+        assert_eq!(press(&mut editor, KeyCode::Char('d'), KeyModifiers::CONTROL), Edit::Eof);
+    }
+
+    #[test]
+    fn goes_through_the_history() {
+        let mut editor = typed("new");
+        press(&mut editor, KeyCode::Up, KeyModifiers::NONE);
+        press(&mut editor, KeyCode::Up, KeyModifiers::NONE);
+        assert_eq!(editor.line.iter().collect::<String>(), "first");
+        assert_eq!(press(&mut editor, KeyCode::Up, KeyModifiers::NONE), Edit::Ignored);
+        press(&mut editor, KeyCode::Down, KeyModifiers::NONE);
+        press(&mut editor, KeyCode::Down, KeyModifiers::NONE);
+        assert!(editor.line.is_empty());
+        assert_eq!(editor.at, 0);
+    }
+
+    #[test]
+    fn returns_to_the_start_of_lines_in_raw_mode() {
+        let mut out = RawOutput(vec![]);
+        out.write_all(b"a\nb\n\nc").unwrap();
+        assert_eq!(out.0, b"a\r\nb\r\n\r\nc");
+    }
+}
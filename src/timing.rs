@@ -0,0 +1,480 @@
+use std::fmt::Display;
+use std::io::{self, Write};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use midly::{Fps, Timing};
+
+use crate::interpreter::{TapeView, Tracer};
+use crate::parser::{MidiInstructionKind, Position};
+use crate::profile::ChordTracker;
+
+/// Musical location of a chord, counted from 1 like bars in a score.
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub struct BarBeat {
+    pub bar: u64,
+    pub beat: u64,
+}
+
+impl Display for BarBeat {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}:{}", self.bar, self.beat)
+    }
+}
+
+/// SMPTE location of a chord in a timecode based file, like a cue scored to
+/// picture.
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub struct Timecode {
+    pub hours: u64,
+    pub minutes: u8,
+    pub seconds: u8,
+    pub frames: u8,
+    /// Ticks into the frame
+    pub subframes: u8,
+    /// 29.97 fps timecode, which skips frame numbers to keep up with the clock
+    pub drop_frame: bool,
+}
+
+impl Timecode {
+    /// Timecode `tick` ticks into a file at `fps` with `subframes` ticks a frame.
+    pub fn at(tick: u64, fps: Fps, subframes: u8) -> Self {
+        let subframes = u64::from(subframes.max(1));
+        let mut frame = tick / subframes;
+        let drop_frame = fps == Fps::Fps29;
+        if drop_frame {
+            // frames 0 and 1 are skipped every minute but every tenth one
+            let (tens, rest) = (frame / 17_982, frame % 17_982);
+            frame += 18 * tens + 2 * ((rest.max(2) - 2) / 1798);
+        }
+        let nominal = frames_per_second(fps).0.div_ceil(frames_per_second(fps).1);
+        let seconds = frame / nominal;
+        Timecode {
+            hours: seconds / 3600,
+            minutes: (seconds / 60 % 60) as u8,
+            seconds: (seconds % 60) as u8,
+            frames: (frame % nominal) as u8,
+            subframes: (tick % subframes) as u8,
+            drop_frame,
+        }
+    }
+}
+
+impl Display for Timecode {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let separator = if self.drop_frame { ';' } else { ':' };
+        write!(f, "{:02}:{:02}:{:02}{}{:02}", self.hours, self.minutes, self.seconds, separator, self.frames)?;
+        if self.subframes > 0 {
+            write!(f, ".{:02}", self.subframes)?;
+        }
+        Ok(())
+    }
+}
+
+// frames per second as a fraction, 29.97 being 30 / 1.001
+fn frames_per_second(fps: Fps) -> (u64, u64) {
+    match fps {
+        Fps::Fps24 => (24, 1),
+        Fps::Fps25 => (25, 1),
+        Fps::Fps29 => (30_000, 1001),
+        Fps::Fps30 => (30, 1),
+    }
+}
+
+/// A time signature change, with the denominator stored as a power of two like in SMF.
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+struct TimeSignature {
+    tick: u64,
+    numerator: u8,
+    denominator: u8,
+}
+
+const COMMON_TIME: TimeSignature = TimeSignature {
+    tick: 0,
+    numerator: 4,
+    denominator: 2,
+};
+
+/// Microseconds per quarter note until a file sets its tempo, 120 bpm.
+const DEFAULT_TEMPO: u64 = 500_000;
+
+/// A tempo change, in microseconds per quarter note like in SMF.
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+struct Tempo {
+    tick: u64,
+    micros_per_quarter: u64,
+}
+
+/// Maps instruction indexes back to the ticks where their chords start in the source MIDI.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SourceMap {
+    timing: Timing,
+    /// Ticks per quarter note, `None` for timecode based files
+    ticks_per_quarter: Option<u64>,
+    /// Start tick of every instruction, indexed like `Position`s
+    ticks: Vec<u64>,
+    time_signatures: Vec<TimeSignature>,
+    tempos: Vec<Tempo>,
+}
+
+/// Source map of a program that didn't come from a song, like a dumped one,
+/// which knows where none of its instructions are.
+impl Default for SourceMap {
+    fn default() -> Self {
+        SourceMap::new(Timing::Metrical(480.into()))
+    }
+}
+
+impl SourceMap {
+    pub fn new(timing: Timing) -> Self {
+        let ticks_per_quarter = match timing {
+            Timing::Metrical(tpq) => Some(u64::from(u16::from(tpq))),
+            Timing::Timecode(..) => None,
+        };
+        SourceMap {
+            timing,
+            ticks_per_quarter,
+            ticks: vec![],
+            time_signatures: vec![],
+            tempos: vec![],
+        }
+    }
+
+    pub(crate) fn push_instruction(&mut self, tick: u64) {
+        self.ticks.push(tick);
+    }
+
+    pub(crate) fn push_time_signature(&mut self, tick: u64, numerator: u8, denominator: u8) {
+        // a zero numerator would make bars empty
+        if numerator == 0 {
+            return;
+        }
+        let sig = TimeSignature {
+            tick,
+            numerator,
+            denominator,
+        };
+        let idx = self.time_signatures.partition_point(|other| other.tick <= tick);
+        self.time_signatures.insert(idx, sig);
+    }
+
+    pub(crate) fn push_tempo(&mut self, tick: u64, micros_per_quarter: u32) {
+        let tempo = Tempo {
+            tick,
+            micros_per_quarter: u64::from(micros_per_quarter),
+        };
+        let idx = self.tempos.partition_point(|other| other.tick <= tick);
+        self.tempos.insert(idx, tempo);
+    }
+
+    /// Ticks per quarter note, `None` for timecode based files.
+    pub fn ticks_per_quarter(&self) -> Option<u64> {
+        self.ticks_per_quarter
+    }
+
+    /// Time signature changes as their tick, numerator and denominator, like
+    /// `(0, 3, 4)` for 3/4 from the start.
+    pub fn time_signatures(&self) -> impl Iterator<Item = (u64, u8, u64)> + '_ {
+        self.time_signatures
+            .iter()
+            .map(|sig| (sig.tick, sig.numerator, 1 << sig.denominator.min(6)))
+    }
+
+    /// Number of instructions mapped.
+    pub fn len(&self) -> usize {
+        self.ticks.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.ticks.is_empty()
+    }
+
+    /// Tick at which the chord for instruction `index` starts.
+    pub fn tick(&self, index: usize) -> Option<u64> {
+        self.ticks.get(index).copied()
+    }
+
+    /// Bar and beat where the chord for instruction `index` starts.
+    pub fn bar_beat(&self, index: usize) -> Option<BarBeat> {
+        self.bar_beat_at(self.tick(index)?)
+    }
+
+    /// Timecode where the chord for instruction `index` starts, `None` unless
+    /// the file is timecode based.
+    pub fn timecode(&self, index: usize) -> Option<Timecode> {
+        self.timecode_at(self.tick(index)?)
+    }
+
+    /// Timecode `tick` falls on, `None` unless the file is timecode based.
+    pub fn timecode_at(&self, tick: u64) -> Option<Timecode> {
+        match self.timing {
+            Timing::Metrical(_) => None,
+            Timing::Timecode(fps, subframes) => Some(Timecode::at(tick, fps, subframes)),
+        }
+    }
+
+    /// Bar and beat `tick` falls on, `None` for timecode based files.
+    pub fn bar_beat_at(&self, tick: u64) -> Option<BarBeat> {
+        let ticks_per_quarter = self.ticks_per_quarter?;
+
+        let mut signatures = vec![COMMON_TIME];
+        signatures.extend(self.time_signatures.iter().copied());
+        let mut bar = 0;
+        for (idx, sig) in signatures.iter().enumerate() {
+            let beat_ticks = ((ticks_per_quarter * 4) >> sig.denominator.min(6)).max(1);
+            let bar_ticks = beat_ticks * u64::from(sig.numerator);
+            let end = signatures.get(idx + 1).map_or(u64::MAX, |next| next.tick);
+            if tick < end {
+                let offset = tick - sig.tick;
+                return Some(BarBeat {
+                    bar: bar + offset / bar_ticks + 1,
+                    beat: offset % bar_ticks / beat_ticks + 1,
+                });
+            }
+            // time signatures usually change on a bar line, but round up just in case
+            bar += (end - sig.tick).div_ceil(bar_ticks);
+        }
+        None
+    }
+
+    /// How far into the song `tick` is, following its tempo changes. Timecode
+    /// based files keep to their frame rate whatever their tempo.
+    pub fn time_at(&self, tick: u64) -> Duration {
+        let ticks_per_quarter = match self.timing {
+            Timing::Metrical(tpq) => u128::from(u16::from(tpq).max(1)),
+            Timing::Timecode(fps, subframes) => {
+                let (frames, per_seconds) = frames_per_second(fps);
+                let ticks_per_second = u128::from(frames) * u128::from(subframes.max(1));
+                let nanos = u128::from(tick) * u128::from(per_seconds) * 1_000_000_000 / ticks_per_second;
+                return Duration::from_nanos(u64::try_from(nanos).unwrap_or(u64::MAX));
+            }
+        };
+        let mut tempos = vec![Tempo {
+            tick: 0,
+            micros_per_quarter: DEFAULT_TEMPO,
+        }];
+        tempos.extend(self.tempos.iter().copied());
+        let mut nanos: u128 = 0;
+        for (idx, tempo) in tempos.iter().enumerate() {
+            let end = tempos.get(idx + 1).map_or(u64::MAX, |next| next.tick).min(tick);
+            if end <= tempo.tick {
+                continue;
+            }
+            let ticks = u128::from(end - tempo.tick);
+            nanos += ticks * u128::from(tempo.micros_per_quarter) * 1000 / ticks_per_quarter;
+        }
+        Duration::from_nanos(u64::try_from(nanos).unwrap_or(u64::MAX))
+    }
+
+    /// How far into the song the chord for instruction `index` starts.
+    pub fn chord_time(&self, index: usize) -> Option<Duration> {
+        self.tick(index).map(|tick| self.time_at(tick))
+    }
+
+    /// Time from the chord for instruction `index` to the next one, zero for
+    /// the last chord and ones the next chord doesn't come after.
+    pub fn chord_length(&self, index: usize) -> Duration {
+        match (self.chord_time(index), self.chord_time(index + 1)) {
+            (Some(start), Some(next)) => next.saturating_sub(start),
+            _ => Duration::ZERO,
+        }
+    }
+
+    /// Bar and beat of the chord for instruction `index`, or its timecode in
+    /// timecode based files.
+    pub fn location(&self, index: usize) -> Option<String> {
+        match self.timing {
+            Timing::Metrical(_) => self.bar_beat(index).map(|bar_beat| bar_beat.to_string()),
+            Timing::Timecode(..) => self.timecode(index).map(|timecode| timecode.to_string()),
+        }
+    }
+
+    /// Human readable location of `position` for diagnostics.
+    pub fn describe(&self, position: Position) -> String {
+        match self.location(position.start()) {
+            Some(location) => format!("{} (instruction {})", location, position.start()),
+            None => format!("instruction {}", position.start()),
+        }
+    }
+
+    /// Writes the source map as text, one line per instruction, so that line `n`
+    /// describes instruction `n - 1`. Debug info uses these as its line numbers.
+    ///
+    /// ```text
+    /// instruction 0 at 1:1, tick 0
+    /// instruction 1 at 1:2, tick 480
+    /// ```
+    ///
+    /// with timecodes like `00:00:01:12` instead in timecode based files.
+    pub fn write_listing(&self, out: &mut impl Write) -> io::Result<()> {
+        for (index, tick) in self.ticks.iter().enumerate() {
+            match self.location(index) {
+                Some(location) => writeln!(out, "instruction {} at {}, tick {}", index, location, tick)?,
+                None => writeln!(out, "instruction {}, tick {}", index, tick)?,
+            }
+        }
+        Ok(())
+    }
+
+    /// Short location of `position` that can go in identifiers, like
+    /// `bar12_beat3`, or `tc00_01_30_12` in timecode based files.
+    pub fn label(&self, position: Position) -> String {
+        if let Some(Timecode { hours, minutes, seconds, frames, .. }) = self.timecode(position.start()) {
+            return format!("tc{:02}_{:02}_{:02}_{:02}", hours, minutes, seconds, frames);
+        }
+        match self.bar_beat(position.start()) {
+            Some(BarBeat { bar, beat }) => format!("bar{}_beat{}", bar, beat),
+            None => format!("inst{}", position.start()),
+        }
+    }
+}
+
+/// Paces an interpreter to the song: every step waits for as long as its chord
+/// lasts in the source, so straight line programs take as long as the song and
+/// loops take as long as their part of it every time around.
+pub struct MusicalClock<'a> {
+    source_map: &'a SourceMap,
+    chords: ChordTracker,
+    /// When the next step is due, `None` before the first one
+    next: Option<Instant>,
+}
+
+impl<'a> MusicalClock<'a> {
+    pub fn new(source_map: &'a SourceMap) -> Self {
+        MusicalClock {
+            source_map,
+            chords: ChordTracker::default(),
+            next: None,
+        }
+    }
+}
+
+impl Tracer for MusicalClock<'_> {
+    fn on_instruction(&mut self, pos: Option<Position>, kind: &MidiInstructionKind, _: &TapeView) {
+        let Some(chord) = self.chords.chord(pos, kind) else {
+            return;
+        };
+        // the song might not start right away
+        let due = self
+            .next
+            .unwrap_or_else(|| Instant::now() + self.source_map.chord_time(chord).unwrap_or_default());
+        thread::sleep(due.saturating_duration_since(Instant::now()));
+        // a slow step pushes the rest back instead of rushing to catch up
+        self.next = Some(due.max(Instant::now()) + self.source_map.chord_length(chord));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+    use midly::num::u15;
+
+    #[test]
+    fn bar_beat_in_common_time() {
+        let mut map = SourceMap::new(Timing::Metrical(u15::from(480)));
+        map.push_instruction(0);
+        map.push_instruction(480);
+        map.push_instruction(480 * 4 + 240);
+        assert_eq!(map.bar_beat(0), Some(BarBeat { bar: 1, beat: 1 }));
+        assert_eq!(map.bar_beat(1), Some(BarBeat { bar: 1, beat: 2 }));
+        assert_eq!(map.bar_beat(2), Some(BarBeat { bar: 2, beat: 1 }));
+        assert_eq!(map.bar_beat(3), None);
+        assert_eq!(map.describe(Position::new(1, 1)), "1:2 (instruction 1)");
+        assert_eq!(map.label(Position::new(2, 2)), "bar2_beat1");
+        assert_eq!(map.label(Position::new(3, 3)), "inst3");
+    }
+
+    #[test]
+    fn time_follows_tempo_changes() {
+        let mut map = SourceMap::new(Timing::Metrical(u15::from(480)));
+        // a bar at 120 bpm, then 60 bpm from the second bar
+        map.push_tempo(1920, 1_000_000);
+        map.push_instruction(0);
+        map.push_instruction(960);
+        map.push_instruction(1920 + 480);
+        assert_eq!(map.time_at(1920), Duration::from_secs(2));
+        assert_eq!(map.chord_time(1), Some(Duration::from_secs(1)));
+        assert_eq!(map.chord_time(2), Some(Duration::from_secs(3)));
+        assert_eq!(map.chord_length(1), Duration::from_secs(2));
+        assert_eq!(map.chord_length(2), Duration::ZERO);
+
+        let timecode = SourceMap::new(Timing::Timecode(midly::Fps::Fps25, 40));
+        assert_eq!(timecode.time_at(1500), Duration::from_millis(1500));
+        // 30 frames at 29.97 fps take a little over a second
+        let timecode = SourceMap::new(Timing::Timecode(midly::Fps::Fps29, 4));
+        assert_eq!(timecode.time_at(120), Duration::from_millis(1001));
+    }
+
+    #[test]
+    fn timecode_locates_chords_by_frame() {
+        let mut map = SourceMap::new(Timing::Timecode(midly::Fps::Fps25, 40));
+        // tempo changes don't move anything in a timecode file
+        map.push_tempo(0, 1_000_000);
+        map.push_instruction(0);
+        map.push_instruction(40 * (25 * 90 + 12) + 5);
+        assert_eq!(map.bar_beat(1), None);
+        assert_eq!(map.chord_time(1), Some(Duration::from_millis(90_485)));
+        assert_eq!(map.describe(Position::new(1, 1)), "00:01:30:12.05 (instruction 1)");
+        assert_eq!(map.label(Position::new(1, 1)), "tc00_01_30_12");
+        let mut listing = vec![];
+        map.write_listing(&mut listing).unwrap();
+        assert_eq!(
+            String::from_utf8(listing).unwrap(),
+            "instruction 0 at 00:00:00:00, tick 0\ninstruction 1 at 00:01:30:12.05, tick 90485\n"
+        );
+
+        // drop frame skips frames 0 and 1 of every minute but every tenth
+        let at = |frame| Timecode::at(frame, midly::Fps::Fps29, 1).to_string();
+        assert_eq!(at(1799), "00:00:59;29");
+        assert_eq!(at(1800), "00:01:00;02");
+        assert_eq!(at(2700), "00:01:30;02");
+        assert_eq!(at(17_982), "00:10:00;00");
+        assert_eq!(at(17_982 + 1800), "00:11:00;02");
+    }
+
+    #[test]
+    fn clock_waits_for_every_chord() {
+        use crate::interpreter::Interpreter;
+        use crate::parser::MidiInstruction;
+        use std::num::Wrapping;
+
+        // 96 ticks a quarter at 20ms a quarter, so a chord every 10ms
+        let mut map = SourceMap::new(Timing::Metrical(u15::from(96)));
+        map.push_tempo(0, 20_000);
+        let mut prog = vec![];
+        for index in 0..4 {
+            map.push_instruction(index as u64 * 48);
+            let mut inst = MidiInstruction::new_inc(Wrapping(1));
+            inst.position = Some(Position::new(index, index));
+            prog.push(inst);
+        }
+        let started = Instant::now();
+        let mut clock = MusicalClock::new(&map);
+        Interpreter::new().run_traced(&prog, &mut io::empty(), &mut io::sink(), &mut clock).unwrap();
+        // the last chord plays right when it starts
+        assert!(started.elapsed() >= Duration::from_millis(30));
+    }
+
+    #[test]
+    fn bar_beat_after_time_signature_change() {
+        let mut map = SourceMap::new(Timing::Metrical(u15::from(100)));
+        // two bars of 4/4, then 6/8
+        map.push_time_signature(800, 6, 3);
+        map.push_instruction(800 + 50 * 7);
+        assert_eq!(map.bar_beat(0), Some(BarBeat { bar: 4, beat: 2 }));
+    }
+
+    #[test]
+    fn listing_has_a_line_per_instruction() {
+        let mut map = SourceMap::new(Timing::Metrical(u15::from(480)));
+        map.push_instruction(0);
+        map.push_instruction(480 * 5);
+        let mut listing = vec![];
+        map.write_listing(&mut listing).unwrap();
+        assert_eq!(
+            String::from_utf8(listing).unwrap(),
+            "instruction 0 at 1:1, tick 0\ninstruction 1 at 2:2, tick 2400\n"
+        );
+    }
+}
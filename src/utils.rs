@@ -8,8 +8,3 @@ pub fn midi_name(src_str: &str) -> String {
     let bn = binary_name(src_str);
     bn + ".mid"
 }
-
-pub fn bf_name(src_str: &str) -> String {
-    let bn = binary_name(src_str);
-    bn + ".bf"
-}
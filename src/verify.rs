@@ -0,0 +1,302 @@
+use std::error::Error;
+use std::fmt::{Debug, Display};
+
+use crate::compiler::{CompileOptions, MCompileError};
+use crate::interpreter::{InterpError, Interpreter, RunOptions};
+use crate::optimizer;
+use crate::parser::{Cell, MidiAST, Position};
+
+pub type VerifyResult<T> = Result<T, VerifyError>;
+
+#[derive(PartialEq)]
+pub enum VerifyError {
+    /// The reference run failed, so there's nothing to compare against
+    Interp(InterpError),
+    Compile(MCompileError),
+}
+
+impl Display for VerifyError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Interp(err) => write!(f, "Interpreter failed: {:?}", err),
+            Self::Compile(err) => write!(f, "Compiler failed: {}", err),
+        }
+    }
+}
+
+impl Debug for VerifyError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        Display::fmt(self, f)
+    }
+}
+
+impl Error for VerifyError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            Self::Interp(err) => Some(err),
+            Self::Compile(err) => Some(err),
+        }
+    }
+}
+
+impl From<InterpError> for VerifyError {
+    fn from(err: InterpError) -> Self {
+        Self::Interp(err)
+    }
+}
+
+impl From<MCompileError> for VerifyError {
+    fn from(err: MCompileError) -> Self {
+        Self::Compile(err)
+    }
+}
+
+/// The first difference between the interpreter and the compiled program.
+///
+/// Positions point at the instruction the interpreter got the expected value
+/// from, `None` when there isn't one.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Divergence {
+    /// Byte `index` of the output differs, a missing byte means that output ended early
+    Output {
+        index: usize,
+        expected: Option<u8>,
+        actual: Option<u8>,
+        position: Option<Position>,
+    },
+    /// Cell `index`, counted from the one the pointer started on, was left
+    /// with a different value
+    Cell {
+        index: isize,
+        expected: Cell,
+        actual: Cell,
+        position: Option<Position>,
+    },
+}
+
+impl Divergence {
+    pub fn position(&self) -> Option<Position> {
+        match self {
+            Self::Output { position, .. } | Self::Cell { position, .. } => *position,
+        }
+    }
+}
+
+/// Runs `program` as is in the interpreter and optimized in the JIT (the VM
+/// without Cranelift), both reading `input`, and compares their output and
+/// final tape. Returns `None` when they agree.
+///
+/// `limits` only apply to the interpreter, a compiled program that doesn't stop
+/// when the interpreted one did hangs.
+pub fn verify(
+    program: &MidiAST,
+    mut options: CompileOptions,
+    input: &[u8],
+    limits: RunOptions,
+) -> VerifyResult<Option<Divergence>> {
+    // both sides have to pick the same random bytes
+    let runs_with = options.run_options(limits);
+    options.seed = Some(runs_with.seed);
+    let mut interp = Interpreter::with_options(runs_with);
+    interp.set_cell_width(options.cell_width);
+    interp.load_data(&options.data);
+    interp.track_positions();
+    let mut expected_output = vec![];
+    interp.run(program, &mut &input[..], &mut expected_output)?;
+
+    let optimized = optimizer::optimize_with_data(program.clone(), options.cell_width, &runs_with, &options.data);
+    let (output, tape, origin) = run_compiled(&optimized, options, input)?;
+
+    Ok(first_divergence(&interp, &expected_output, &output, &tape, origin))
+}
+
+/// Compares a run against the one `interp` made, which wrote `expected_output`
+/// while keeping track of positions. The pointer of the run started on cell
+/// `origin` of its `tape`.
+fn first_divergence(interp: &Interpreter, expected_output: &[u8], output: &[u8], tape: &[Cell], origin: usize) -> Option<Divergence> {
+    let tracking = interp.tracking().cloned().unwrap_or_default();
+    let output_len = expected_output.len().max(output.len());
+    if let Some(index) = (0..output_len).find(|&i| expected_output.get(i) != output.get(i)) {
+        return Some(Divergence::Output {
+            index,
+            expected: expected_output.get(index).copied(),
+            actual: output.get(index).copied(),
+            position: tracking.output.get(index).copied().flatten(),
+        });
+    }
+    // the tapes line up at their origins, cells off either one are still 0
+    let cell = |tape: &[Cell], origin: usize, i: isize| {
+        let index = usize::try_from(i + origin as isize).ok();
+        index.and_then(|index| tape.get(index)).copied().unwrap_or_default()
+    };
+    let expected_origin = interp.origin();
+    let first = -(expected_origin.max(origin) as isize);
+    let end = (interp.tape().len() - expected_origin).max(tape.len() - origin) as isize;
+    let diverged = (first..end).find(|&i| cell(interp.tape(), expected_origin, i) != cell(tape, origin, i));
+    diverged.map(|index| Divergence::Cell {
+        index,
+        expected: cell(interp.tape(), expected_origin, index),
+        actual: cell(tape, origin, index),
+        position: usize::try_from(index + expected_origin as isize)
+            .ok()
+            .and_then(|index| tracking.cells.get(index).copied().flatten()),
+    })
+}
+
+#[cfg(feature = "cranelift")]
+fn run_compiled(program: &MidiAST, options: CompileOptions, input: &[u8]) -> VerifyResult<(Vec<u8>, Vec<Cell>, usize)> {
+    let run = crate::compiler::cranelift::run_jit_captured(program, options, input)?;
+    Ok((run.output, run.tape, run.origin))
+}
+
+#[cfg(not(feature = "cranelift"))]
+fn run_compiled(program: &MidiAST, options: CompileOptions, input: &[u8]) -> VerifyResult<(Vec<u8>, Vec<Cell>, usize)> {
+    use crate::vm::{Bytecode, Vm};
+
+    let mut vm = Vm::with_options(options.run_options(RunOptions::default()));
+    vm.set_cell_width(options.cell_width);
+    vm.load_data(&options.data);
+    #[cfg(feature = "llvm")]
+    if let Ok(jit) = crate::compiler::llvm::jit::LoopJit::new() {
+        vm.set_loop_compiler(Box::new(jit));
+    }
+    let mut output = vec![];
+    vm.run(&Bytecode::new(program), &mut &input[..], &mut output)?;
+    Ok((output, vm.tape().to_vec(), vm.origin()))
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+    use crate::compiler::{Overflow, PointerOverflow, TapeMode};
+    use crate::parser::{MidiASTBuilder, MidiInstruction};
+    use std::io;
+    use std::num::Wrapping;
+
+    fn build(insts: Vec<MidiInstruction>) -> MidiAST {
+        let mut builder = MidiASTBuilder::new();
+        for inst in insts {
+            builder.push(inst).unwrap();
+        }
+        builder.into_mast().unwrap()
+    }
+
+    #[test]
+    fn agrees_with_itself() {
+        let prog = build(vec![
+            MidiInstruction::new_input(),
+            MidiInstruction::new_open_loop(),
+            MidiInstruction::new_output(),
+            MidiInstruction::new_inc(Wrapping(-1)),
+            MidiInstruction::new_close_loop(),
+            MidiInstruction::new_move(1),
+            MidiInstruction::new_inc(Wrapping(7)),
+            MidiInstruction::new_move(-1),
+            MidiInstruction::new_inc(Wrapping(1)),
+            MidiInstruction::new_inc(Wrapping(-1)),
+        ]);
+        assert_eq!(verify(&prog, CompileOptions::default(), b"\x03", RunOptions::default()), Ok(None));
+    }
+
+    #[test]
+    fn finds_the_first_divergence() {
+        let prog = build(vec![
+            MidiInstruction::new_inc(Wrapping(65)),
+            MidiInstruction::new_output(),
+            MidiInstruction::new_move(1),
+            MidiInstruction::new_inc(Wrapping(2)),
+            MidiInstruction::new_output(),
+        ]);
+        let mut interp = Interpreter::new();
+        interp.track_positions();
+        let mut expected = vec![];
+        interp.run(&prog, &mut io::empty(), &mut expected).unwrap();
+
+        let tape = [Wrapping(65), Wrapping(2)];
+        assert_eq!(first_divergence(&interp, &expected, &expected, &tape, 0), None);
+        assert_eq!(
+            first_divergence(&interp, &expected, b"A\x03", &tape, 0),
+            Some(Divergence::Output {
+                index: 1,
+                expected: Some(2),
+                actual: Some(3),
+                position: Some(Position::new(4, 4)),
+            })
+        );
+        assert_eq!(
+            first_divergence(&interp, &expected, b"A", &tape, 0).and_then(|d| d.position()),
+            Some(Position::new(4, 4))
+        );
+        // extra output didn't come from anywhere in the interpreter
+        assert_eq!(
+            first_divergence(&interp, &expected, b"A\x02!", &tape, 0).and_then(|d| d.position()),
+            None
+        );
+        assert_eq!(
+            first_divergence(&interp, &expected, &expected, &[Wrapping(65), Wrapping(1), Wrapping(0)], 0),
+            Some(Divergence::Cell {
+                index: 1,
+                expected: Wrapping(2),
+                actual: Wrapping(1),
+                position: Some(Position::new(3, 3)),
+            })
+        );
+    }
+
+    #[test]
+    fn reports_the_reference_failing() {
+        let prog = build(vec![MidiInstruction::new_move(-1)]);
+        let err = verify(&prog, CompileOptions::default(), b"", RunOptions::default()).unwrap_err();
+        assert!(matches!(err, VerifyError::Interp(InterpError::PointerUnderflow(_))));
+    }
+
+    #[test]
+    fn lines_infinite_tapes_up_at_their_origins() {
+        let prog = build(vec![
+            MidiInstruction::new_move(-2),
+            MidiInstruction::new_inc(Wrapping(3)),
+            MidiInstruction::new_move(3),
+            MidiInstruction::new_inc(Wrapping(1)),
+        ]);
+        let mut interp = Interpreter::new();
+        interp.set_tape_mode(TapeMode::Infinite);
+        interp.track_positions();
+        interp.run(&prog, &mut io::empty(), &mut vec![]).unwrap();
+
+        // a compiled program that grew its tape by more, or by less
+        let tape = [Wrapping(0), Wrapping(3), Wrapping(0), Wrapping(0), Wrapping(1)];
+        assert_eq!(first_divergence(&interp, b"", b"", &tape, 3), None);
+        assert_eq!(first_divergence(&interp, b"", b"", &tape[1..], 2), None);
+        assert_eq!(
+            first_divergence(&interp, b"", b"", &tape[1..4], 2),
+            Some(Divergence::Cell {
+                index: 1,
+                expected: Wrapping(1),
+                actual: Wrapping(0),
+                position: Some(Position::new(3, 3)),
+            })
+        );
+        let options = CompileOptions::builder().tape_mode(TapeMode::Infinite).build().unwrap();
+        assert_eq!(verify(&prog, options, b"", RunOptions::default()), Ok(None));
+    }
+
+    #[test]
+    fn agrees_on_saturating_cells_and_a_wrapping_pointer() {
+        let prog = build(vec![
+            MidiInstruction::new_input(),
+            MidiInstruction::new_inc(Wrapping(-100)),
+            MidiInstruction::new_move(-1),
+            MidiInstruction::new_inc(Wrapping(200)),
+            MidiInstruction::new_inc(Wrapping(100)),
+            MidiInstruction::new_output(),
+        ]);
+        let options = CompileOptions::builder()
+            .tape_size(4)
+            .overflow(Overflow::Saturate)
+            .pointer_overflow(Some(PointerOverflow::Wrap))
+            .build()
+            .unwrap();
+        assert_eq!(verify(&prog, options, b"a", RunOptions::default()).unwrap(), None);
+    }
+}
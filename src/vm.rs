@@ -0,0 +1,759 @@
+use std::fmt::Debug;
+use std::io::{self, Read, Write};
+use std::num::Wrapping;
+use std::time::{Duration, Instant};
+
+use midilang_core::{CellOverflow, Host, Input, Limits, Machine, Output, Stop, Tape};
+
+use crate::ffi::Functions;
+use crate::compiler::{IoMode, Overflow, PointerOverflow, TapeMode};
+use crate::interpreter::{configure_tape, InterpError, InterpResult, RunOptions};
+use crate::parser::{Cell, CellWidth, MidiAST, MidiInstruction, MidiInstructionKind::*, Position};
+
+/// A single bytecode instruction, from `midilang_core` where the VM's
+/// dispatch loop lives.
+pub use midilang_core::Op;
+
+/// Start of bytecode written by `Bytecode::to_bytes`.
+const BYTECODE_MAGIC: &[u8] = b"midilang bytecode\0";
+
+/// A `MidiAST` flattened into a list of `Op`s, with the position of every
+/// instruction kept so it can be turned back into the same AST with `to_ast`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Bytecode {
+    ops: Vec<Op>,
+    /// Position of the instruction each op came from
+    positions: Vec<Option<Position>>,
+    /// Names of the functions `Op::Call`s call, by index
+    functions: Vec<String>,
+}
+
+impl Bytecode {
+    pub fn new(program: &MidiAST) -> Self {
+        let mut code = Bytecode {
+            ops: vec![],
+            positions: vec![],
+            functions: vec![],
+        };
+        code.flatten(program);
+        code
+    }
+
+    fn flatten(&mut self, program: &MidiAST) {
+        for inst in program {
+            let op = match &inst.instruction {
+                IncrementCell { amount } => Op::Add(*amount),
+                MovePointer { amount } => Op::Move(*amount),
+                OutputCell => Op::Output,
+                InputCell => Op::Input,
+                Breakpoint => Op::Breakpoint,
+                Call { name } => Op::Call(self.function(name)),
+                Random => Op::Random,
+                DumpTape => Op::DumpTape,
+                Loop { body } => {
+                    let start = self.ops.len();
+                    // patched once we know where the loop ends
+                    self.push(Op::JumpIfZero(0), inst.position);
+                    self.flatten(body);
+                    self.push(Op::JumpUnlessZero(start + 1), inst.position);
+                    self.ops[start] = Op::JumpIfZero(self.ops.len());
+                    continue;
+                }
+            };
+            self.push(op, inst.position);
+        }
+    }
+
+    fn push(&mut self, op: Op, position: Option<Position>) {
+        self.ops.push(op);
+        self.positions.push(position);
+    }
+
+    // index of the function called `name`, added if it's new
+    fn function(&mut self, name: &str) -> usize {
+        match self.functions.iter().position(|function| function == name) {
+            Some(index) => index,
+            None => {
+                self.functions.push(name.to_owned());
+                self.functions.len() - 1
+            }
+        }
+    }
+
+    pub fn ops(&self) -> &[Op] {
+        &self.ops
+    }
+
+    /// Names of the functions called, `Op::Call` holds an index into them.
+    pub fn functions(&self) -> &[String] {
+        &self.functions
+    }
+
+    /// The AST the bytecode was flattened from, `Bytecode::new` the other way.
+    pub fn to_ast(&self) -> MidiAST {
+        // the innermost loop being read is on top
+        let mut bodies: Vec<MidiAST> = vec![vec![]];
+        for (op, &position) in self.ops.iter().zip(&self.positions) {
+            let instruction = match *op {
+                Op::Add(amount) => IncrementCell { amount },
+                Op::Move(amount) => MovePointer { amount },
+                Op::Output => OutputCell,
+                Op::Input => InputCell,
+                Op::Breakpoint => Breakpoint,
+                Op::Call(function) => Call {
+                    name: self.functions[function].clone(),
+                },
+                Op::Random => Random,
+                Op::DumpTape => DumpTape,
+                Op::JumpIfZero(_) => {
+                    bodies.push(vec![]);
+                    continue;
+                }
+                // jumps are checked to pair up when the bytecode is made
+                Op::JumpUnlessZero(_) => Loop { body: bodies.pop().unwrap() },
+            };
+            bodies.last_mut().unwrap().push(MidiInstruction { position, instruction });
+        }
+        bodies.pop().unwrap()
+    }
+
+    /// Encodes the bytecode to save it to disk. Every op is a tag byte and its
+    /// operand as a little endian i32, i64 or u64, followed by its position as
+    /// a 0 byte when it has none or a 1 and its start and end as u64s. Calls
+    /// have the name of their function for an operand, its length as a u64
+    /// and then its bytes.
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut bytes = BYTECODE_MAGIC.to_vec();
+        bytes.extend_from_slice(&(self.ops.len() as u64).to_le_bytes());
+        for (op, position) in self.ops.iter().zip(&self.positions) {
+            match *op {
+                Op::Add(amount) => {
+                    bytes.push(0);
+                    bytes.extend_from_slice(&amount.0.to_le_bytes());
+                }
+                Op::Move(amount) => {
+                    bytes.push(1);
+                    bytes.extend_from_slice(&(amount as i64).to_le_bytes());
+                }
+                Op::Output => bytes.push(2),
+                Op::Input => bytes.push(3),
+                Op::JumpIfZero(target) => {
+                    bytes.push(4);
+                    bytes.extend_from_slice(&(target as u64).to_le_bytes());
+                }
+                Op::JumpUnlessZero(target) => {
+                    bytes.push(5);
+                    bytes.extend_from_slice(&(target as u64).to_le_bytes());
+                }
+                Op::Breakpoint => bytes.push(6),
+                Op::Call(function) => {
+                    let name = self.functions[function].as_bytes();
+                    bytes.push(7);
+                    bytes.extend_from_slice(&(name.len() as u64).to_le_bytes());
+                    bytes.extend_from_slice(name);
+                }
+                Op::Random => bytes.push(8),
+                Op::DumpTape => bytes.push(9),
+            }
+            match position {
+                Some(position) => {
+                    bytes.push(1);
+                    bytes.extend_from_slice(&(position.start() as u64).to_le_bytes());
+                    bytes.extend_from_slice(&(position.end() as u64).to_le_bytes());
+                }
+                None => bytes.push(0),
+            }
+        }
+        bytes
+    }
+
+    /// Decodes bytecode written by `to_bytes`, `None` when `bytes` aren't any or
+    /// the jumps in them don't pair up into loops.
+    pub fn from_bytes(bytes: &[u8]) -> Option<Self> {
+        let mut reader = ByteReader(bytes.strip_prefix(BYTECODE_MAGIC)?);
+        let len = usize::try_from(reader.u64()?).ok()?;
+        let mut code = Bytecode {
+            ops: vec![],
+            positions: vec![],
+            functions: vec![],
+        };
+        for _ in 0..len {
+            let op = match reader.byte()? {
+                0 => Op::Add(Wrapping(i32::from_le_bytes(reader.take()?))),
+                1 => Op::Move(isize::try_from(i64::from_le_bytes(reader.take()?)).ok()?),
+                2 => Op::Output,
+                3 => Op::Input,
+                4 => Op::JumpIfZero(usize::try_from(reader.u64()?).ok()?),
+                5 => Op::JumpUnlessZero(usize::try_from(reader.u64()?).ok()?),
+                6 => Op::Breakpoint,
+                7 => {
+                    let len = usize::try_from(reader.u64()?).ok()?;
+                    let name = std::str::from_utf8(reader.slice(len)?).ok()?;
+                    Op::Call(code.function(name))
+                }
+                8 => Op::Random,
+                9 => Op::DumpTape,
+                _ => return None,
+            };
+            let position = match reader.byte()? {
+                0 => None,
+                1 => {
+                    let start = usize::try_from(reader.u64()?).ok()?;
+                    Some(Position::new(start, usize::try_from(reader.u64()?).ok()?))
+                }
+                _ => return None,
+            };
+            code.push(op, position);
+        }
+        (reader.0.is_empty() && code.jumps_pair_up()).then_some(code)
+    }
+
+    /// Whether every `JumpIfZero` goes to right after a `JumpUnlessZero` that
+    /// jumps back to right after it, like `new` makes them.
+    fn jumps_pair_up(&self) -> bool {
+        let mut starts = vec![];
+        for (index, op) in self.ops.iter().enumerate() {
+            match *op {
+                Op::JumpIfZero(_) => starts.push(index),
+                Op::JumpUnlessZero(target) => {
+                    let Some(start) = starts.pop() else {
+                        return false;
+                    };
+                    if target != start + 1 || self.ops[start] != Op::JumpIfZero(index + 1) {
+                        return false;
+                    }
+                }
+                _ => {}
+            }
+        }
+        starts.is_empty()
+    }
+}
+
+/// Reads little endian numbers off the front of a byte slice.
+struct ByteReader<'a>(&'a [u8]);
+
+impl ByteReader<'_> {
+    fn take<const N: usize>(&mut self) -> Option<[u8; N]> {
+        let (bytes, rest) = self.0.split_first_chunk()?;
+        self.0 = rest;
+        Some(*bytes)
+    }
+
+    fn byte(&mut self) -> Option<u8> {
+        self.take::<1>().map(|[byte]| byte)
+    }
+
+    fn u64(&mut self) -> Option<u64> {
+        self.take().map(u64::from_le_bytes)
+    }
+
+    fn slice(&mut self, len: usize) -> Option<&[u8]> {
+        let (bytes, rest) = self.0.split_at_checked(len)?;
+        self.0 = rest;
+        Some(bytes)
+    }
+}
+
+/// Times the body of a loop runs in the VM before it gets compiled.
+pub const TIER_UP_THRESHOLD: u32 = 1000;
+
+/// A loop compiled by a `LoopCompiler`, called with the tape and the pointer when
+/// the VM is about to run the body of the loop.
+///
+/// Runs the body until the loop's cell is 0 and returns the steps that took,
+/// counting the `JumpUnlessZero` at the end of each iteration. It never resizes
+/// the tape, so every cell the loop can reach from the pointer has to be on it.
+pub type NativeLoop = unsafe extern "C" fn(*mut Cell, usize) -> u64;
+
+/// Compiles hot loops to native code for `Vm::set_loop_compiler`.
+pub trait LoopCompiler: Debug {
+    /// Compiles the loop whose body is `body`, which starts at op `start` of the
+    /// program. Jumps in `body` are still relative to the whole program.
+    ///
+    /// Loops handed over never do IO, call functions, pick random bytes or dump
+    /// the tape, and always end on the cell they started on.
+    /// The code has to stay around for as long as the compiler does.
+    fn compile_loop(&mut self, body: &[Op], start: usize, cell_width: CellWidth) -> Option<NativeLoop>;
+}
+
+/// What the VM knows about a loop, kept at the index of its `JumpIfZero`.
+#[derive(Clone, Copy)]
+enum Tier {
+    /// Ran the body this many times so far
+    Cold(u32),
+    /// Compiled, reaching this many cells to the left and right of its own
+    Native(NativeLoop, usize, usize),
+    /// Can't or couldn't be compiled
+    Interpreted,
+}
+
+/// Runs `Bytecode` in a dispatch loop, which is a lot faster than walking the AST
+/// like `Interpreter` does. The loop itself is `midilang_core::Machine`, the VM
+/// adds reading and writing through `std::io`, the time limit and compiling
+/// hot loops.
+///
+/// Programs behave exactly like they do in `Interpreter`, down to the step count,
+/// but only the interpreter can be watched with a `Tracer`.
+#[derive(Debug)]
+pub struct Vm {
+    machine: Machine,
+    time_limit: Option<Duration>,
+    /// When the time limit runs out, counted from the first run
+    deadline: Option<Instant>,
+    cell_width: CellWidth,
+    loop_compiler: Option<Box<dyn LoopCompiler>>,
+    functions: Functions,
+}
+
+impl Vm {
+    pub fn new() -> Self {
+        Self::with_options(RunOptions::default())
+    }
+
+    /// Creates a VM that gives up after executing `max_steps` instructions.
+    pub fn with_step_limit(max_steps: usize) -> Self {
+        Self::with_options(RunOptions {
+            max_steps: Some(max_steps),
+            ..RunOptions::default()
+        })
+    }
+
+    /// Creates a VM that gives up once it runs into any of the limits in
+    /// `options`, and runs programs the way the rest of them say.
+    pub fn with_options(options: RunOptions) -> Self {
+        Vm {
+            machine: configured_machine(options),
+            time_limit: options.wall_clock_limit,
+            deadline: None,
+            cell_width: CellWidth::default(),
+            loop_compiler: None,
+            functions: Functions::new(),
+        }
+    }
+
+    /// Cells wrap around at this width, 8 bits unless set otherwise.
+    pub fn set_cell_width(&mut self, width: CellWidth) {
+        self.cell_width = width;
+        self.machine.tape_mut().set_cell_bits(width.bits());
+    }
+
+    /// Has calls find functions in `functions`, the builtins and the libraries
+    /// linked into them, instead of only the builtins.
+    pub fn set_functions(&mut self, functions: Functions) {
+        self.functions = functions;
+    }
+
+    /// Starts the random bytes over from `seed` instead of the one it was
+    /// created with.
+    pub fn set_seed(&mut self, seed: u64) {
+        self.machine.set_seed(seed);
+    }
+
+    /// Lets the pointer move left of the first cell or not, instead of what it
+    /// was created with.
+    pub fn set_tape_mode(&mut self, mode: TapeMode) {
+        self.machine.tape_mut().set_two_sided(mode == TapeMode::Infinite);
+    }
+
+    /// Saturates or stops at cells going past their width, instead of what it
+    /// was created with.
+    pub fn set_cell_overflow(&mut self, overflow: Overflow) {
+        self.machine.tape_mut().set_overflow(overflow.into());
+    }
+
+    /// Keeps the pointer on the first `tape_size` cells, instead of what it was
+    /// created with.
+    pub fn set_pointer_overflow(&mut self, overflow: PointerOverflow, tape_size: u64) {
+        self.machine.tape_mut().set_size(tape_size as usize, overflow == PointerOverflow::Wrap);
+    }
+
+    /// Writes and reads UTF-8 or bytes, instead of what it was created with.
+    pub fn set_io_mode(&mut self, mode: IoMode) {
+        self.machine.set_encoding(mode.into());
+    }
+
+    /// Hands loops that ran more than `TIER_UP_THRESHOLD` times to `compiler`.
+    ///
+    /// Step and time limits are checked between ops, so VMs with either never compile loops.
+    pub fn set_loop_compiler(&mut self, compiler: Box<dyn LoopCompiler>) {
+        self.loop_compiler = Some(compiler);
+    }
+
+    /// Starts the tape out with the cells of a data track, see `Tape::load`.
+    pub fn load_data(&mut self, data: &[u8]) {
+        self.machine.tape_mut().load(data);
+    }
+
+    pub fn tape(&self) -> &[Cell] {
+        self.machine.tape().cells()
+    }
+
+    pub fn pointer(&self) -> usize {
+        self.machine.tape().pointer()
+    }
+
+    /// Index in `tape` of the cell the pointer started on.
+    pub fn origin(&self) -> usize {
+        self.machine.tape().origin()
+    }
+
+    pub fn steps(&self) -> usize {
+        self.machine.steps()
+    }
+
+    /// Executes `code` against the current tape.
+    ///
+    /// `output` is flushed before reading input, so prompts show up in time.
+    pub fn run<R: Read, W: Write>(&mut self, code: &Bytecode, input: &mut R, output: &mut W) -> InterpResult<()> {
+        let tiered = self.loop_compiler.is_some() && self.machine.limits().max_steps.is_none() && self.time_limit.is_none();
+        if let Some(limit) = self.time_limit {
+            self.deadline.get_or_insert_with(|| Instant::now() + limit);
+        }
+        let mut host = VmHost {
+            tiers: if tiered { vec![Tier::Cold(0); code.ops.len()] } else { vec![] },
+            loop_compiler: &mut self.loop_compiler,
+            cell_width: self.cell_width,
+            deadline: self.deadline,
+            names: &code.functions,
+            functions: &mut self.functions,
+        };
+        let result = self.machine.run(&code.ops, &mut StdInput(input), &mut StdOutput(output), &mut host);
+        result.map_err(|stop| match stop {
+            Stop::StepLimit(max) => InterpError::StepLimit(max),
+            Stop::OutputLimit(max) => InterpError::OutputLimit(max),
+            Stop::Interrupted => InterpError::TimeLimit(self.time_limit.unwrap_or_default()),
+            Stop::PointerUnderflow(op) => InterpError::PointerUnderflow(code.positions[op]),
+            Stop::PointerOverflow(op) => InterpError::PointerOverflow(code.positions[op]),
+            Stop::CellOverflow(op) => InterpError::CellOverflow(code.positions[op]),
+            Stop::NoFunction(op) => match code.ops[op] {
+                Op::Call(function) => InterpError::UnknownFunction(code.functions[function].clone()),
+                _ => unreachable!("only calls call functions"),
+            },
+            Stop::Io(err) => err.into(),
+        })
+    }
+}
+
+// a machine keeping to the limits of `options` that picks random bytes from
+// its seed, on its tape with the overflow settings, in its IO mode
+fn configured_machine(options: RunOptions) -> Machine {
+    let limits = Limits {
+        max_steps: options.max_steps,
+        max_output_bytes: options.max_output_bytes,
+    };
+    let mut machine = Machine::with_limits(limits);
+    machine.set_seed(options.seed);
+    configure_tape(machine.tape_mut(), &options);
+    machine.set_encoding(options.io.into());
+    machine
+}
+
+// what the machine asks the VM for while it runs a program
+struct VmHost<'a> {
+    tiers: Vec<Tier>,
+    loop_compiler: &'a mut Option<Box<dyn LoopCompiler>>,
+    cell_width: CellWidth,
+    deadline: Option<Instant>,
+    names: &'a [String],
+    functions: &'a mut Functions,
+}
+
+impl Host for VmHost<'_> {
+    fn interrupted(&mut self, _steps: usize) -> bool {
+        // reading the clock on every step would slow everything down
+        self.deadline.is_some_and(|deadline| Instant::now() >= deadline)
+    }
+
+    /// Runs the loop natively if it's hot enough.
+    fn run_loop(&mut self, ops: &[Op], tape: &mut Tape, start: usize, end: usize) -> Option<usize> {
+        // native loops only know how to wrap cells around
+        if tape.overflow() != CellOverflow::Wrap {
+            return None;
+        }
+        let tier = self.tiers.get_mut(start)?;
+        if let Tier::Cold(runs) = tier {
+            if *runs < TIER_UP_THRESHOLD {
+                *runs += 1;
+                return None;
+            }
+            let body = &ops[start + 1..end - 1];
+            *tier = match (reach(body), &mut *self.loop_compiler) {
+                (Some((left, right)), Some(compiler)) => match compiler.compile_loop(body, start + 1, self.cell_width) {
+                    Some(native) => Tier::Native(native, left, right),
+                    None => Tier::Interpreted,
+                },
+                _ => Tier::Interpreted,
+            };
+        }
+        let pointer = tape.pointer();
+        match *tier {
+            Tier::Native(native, left, right) if pointer >= left && pointer + right < tape.cells().len() => {
+                // the reach check keeps the loop on the tape
+                let steps = unsafe { native(tape.cells_mut().as_mut_ptr(), pointer) };
+                Some(steps as usize)
+            }
+            _ => None,
+        }
+    }
+
+    fn call(&mut self, function: usize, cell: Cell) -> Option<Cell> {
+        self.functions.call(&self.names[function], cell).ok()
+    }
+
+    fn dump_tape(&mut self, tape: &Tape) {
+        eprintln!("{}", tape.dump());
+    }
+}
+
+// `std::io` readers and writers as the machine's input and output
+pub(crate) struct StdInput<'a, R>(pub(crate) &'a mut R);
+
+impl<R: Read> Input for StdInput<'_, R> {
+    type Error = io::Error;
+
+    fn read_byte(&mut self) -> io::Result<Option<u8>> {
+        let mut buf = [0_u8];
+        Ok(match self.0.read(&mut buf)? {
+            0 => None,
+            _ => Some(buf[0]),
+        })
+    }
+}
+
+pub(crate) struct StdOutput<'a, W>(pub(crate) &'a mut W);
+
+impl<W: Write> Output for StdOutput<'_, W> {
+    type Error = io::Error;
+
+    fn write_byte(&mut self, byte: u8) -> io::Result<()> {
+        self.0.write_all(&[byte])
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.0.flush()
+    }
+}
+
+/// How far left and right of its own cell a loop with `body` can move, `None`
+/// when that depends on the cells or the loop does IO.
+fn reach(body: &[Op]) -> Option<(usize, usize)> {
+    let (mut offset, mut left, mut right) = (0_isize, 0_isize, 0_isize);
+    let mut loop_offsets = vec![];
+    for op in body {
+        match *op {
+            Op::Add(_) => {}
+            Op::Move(amount) => {
+                offset += amount;
+                left = left.min(offset);
+                right = right.max(offset);
+            }
+            // breakpoints are gone from optimized programs, the only hot ones
+            Op::Output | Op::Input | Op::Breakpoint | Op::Call(_) | Op::Random | Op::DumpTape => return None,
+            Op::JumpIfZero(_) => loop_offsets.push(offset),
+            // an unbalanced inner loop could wander off anywhere
+            Op::JumpUnlessZero(_) => {
+                if loop_offsets.pop() != Some(offset) {
+                    return None;
+                }
+            }
+        }
+    }
+    (offset == 0).then_some((left.unsigned_abs(), right.unsigned_abs()))
+}
+
+impl Default for Vm {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+    use crate::interpreter::Interpreter;
+    use crate::parser::{MidiASTBuilder, MidiInstruction};
+    use std::io;
+
+    fn build(insts: Vec<MidiInstruction>) -> MidiAST {
+        let mut builder = MidiASTBuilder::new();
+        for inst in insts {
+            builder.push(inst).unwrap();
+        }
+        builder.into_mast().unwrap()
+    }
+
+    #[test]
+    fn flattens_nested_loops() {
+        let prog = build(vec![
+            MidiInstruction::new_open_loop(),
+            MidiInstruction::new_open_loop(),
+            MidiInstruction::new_inc(Wrapping(-1)),
+            MidiInstruction::new_close_loop(),
+            MidiInstruction::new_move(1),
+            MidiInstruction::new_close_loop(),
+            MidiInstruction::new_output(),
+        ]);
+        assert_eq!(
+            Bytecode::new(&prog).ops(),
+            &[
+                Op::JumpIfZero(6),
+                Op::JumpIfZero(4),
+                Op::Add(Wrapping(-1)),
+                Op::JumpUnlessZero(2),
+                Op::Move(1),
+                Op::JumpUnlessZero(1),
+                Op::Output,
+            ]
+        );
+    }
+
+    #[test]
+    fn matches_the_interpreter() {
+        // copies the input to cell 1 and then prints it twice
+        let prog = build(vec![
+            MidiInstruction::new_input(),
+            MidiInstruction::new_open_loop(),
+            MidiInstruction::new_inc(Wrapping(-1)),
+            MidiInstruction::new_move(1),
+            MidiInstruction::new_inc(Wrapping(1)),
+            MidiInstruction::new_move(1),
+            MidiInstruction::new_inc(Wrapping(2)),
+            MidiInstruction::new_move(-2),
+            MidiInstruction::new_close_loop(),
+            MidiInstruction::new_move(1),
+            MidiInstruction::new_output(),
+            MidiInstruction::new_move(1),
+            MidiInstruction::new_output(),
+        ]);
+        let mut interp = Interpreter::new();
+        let mut interp_out = vec![];
+        interp.run(&prog, &mut &b"!"[..], &mut interp_out).unwrap();
+
+        let mut vm = Vm::new();
+        let mut vm_out = vec![];
+        vm.run(&Bytecode::new(&prog), &mut &b"!"[..], &mut vm_out).unwrap();
+        assert_eq!(vm_out, b"!B");
+        assert_eq!(vm_out, interp_out);
+        assert_eq!(vm.tape(), interp.tape());
+        assert_eq!(vm.pointer(), interp.pointer());
+        assert_eq!(vm.steps(), interp.steps());
+    }
+
+    #[test]
+    fn limits_and_underflow() {
+        let forever = build(vec![
+            MidiInstruction::new_inc(Wrapping(1)),
+            MidiInstruction::new_open_loop(),
+            MidiInstruction::new_output(),
+            MidiInstruction::new_close_loop(),
+        ]);
+        let mut vm = Vm::with_step_limit(100);
+        let err = vm.run(&Bytecode::new(&forever), &mut io::empty(), &mut io::sink()).unwrap_err();
+        assert_eq!(err, InterpError::StepLimit(100));
+        assert_eq!(vm.steps(), 100);
+
+        let mut vm = Vm::with_options(RunOptions {
+            max_output_bytes: Some(5),
+            wall_clock_limit: Some(std::time::Duration::from_secs(60)),
+            ..RunOptions::default()
+        });
+        let err = vm.run(&Bytecode::new(&forever), &mut io::empty(), &mut io::sink()).unwrap_err();
+        assert_eq!(err, InterpError::OutputLimit(5));
+
+        let underflow = build(vec![MidiInstruction::new_output(), MidiInstruction::new_move(-1)]);
+        let err = Vm::new().run(&Bytecode::new(&underflow), &mut io::empty(), &mut io::sink()).unwrap_err();
+        assert_eq!(err, InterpError::PointerUnderflow(Some(Position::new(1, 1))));
+    }
+
+    #[test]
+    fn round_trips_to_the_ast_and_bytes() {
+        let program = crate::MidiProgram::from_bf_str(",[->+>++<<]#>.[[-]>]>.").unwrap();
+        let code = Bytecode::new(program.ast());
+        assert!(code.ops().contains(&Op::Breakpoint));
+        assert_eq!(&code.to_ast(), program.ast());
+
+        let bytes = code.to_bytes();
+        assert_eq!(Bytecode::from_bytes(&bytes), Some(code.clone()));
+        assert_eq!(Bytecode::from_bytes(&bytes[..bytes.len() - 1]), None);
+        assert_eq!(Bytecode::from_bytes(b"midilang snapshot\0"), None);
+
+        // a loop that jumps back to the wrong place
+        let mut bad = code.clone();
+        let end = bad.ops.iter().position(|op| matches!(op, Op::JumpUnlessZero(_))).unwrap();
+        bad.ops[end] = Op::JumpUnlessZero(0);
+        assert_eq!(Bytecode::from_bytes(&bad.to_bytes()), None);
+    }
+
+    #[test]
+    fn breakpoints_are_not_steps() {
+        let program = crate::MidiProgram::from_bf_str("++#[->+#<]").unwrap();
+        let mut interp = Interpreter::new();
+        interp.run(program.ast(), &mut io::empty(), &mut io::sink()).unwrap();
+        let mut vm = Vm::new();
+        vm.run(&Bytecode::new(program.ast()), &mut io::empty(), &mut io::sink()).unwrap();
+        assert_eq!(vm.tape(), interp.tape());
+        assert_eq!(vm.steps(), interp.steps());
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn calls_the_same_functions_as_the_interpreter() {
+        let prog = build(vec![
+            MidiInstruction::new_input(),
+            MidiInstruction::new_call("toupper".to_owned()),
+            MidiInstruction::new_output(),
+            MidiInstruction::new_call("abs".to_owned()),
+            MidiInstruction::new_call("toupper".to_owned()),
+        ]);
+        let code = Bytecode::new(&prog);
+        assert_eq!(code.functions(), ["toupper", "abs"]);
+        assert_eq!(Bytecode::from_bytes(&code.to_bytes()), Some(code.clone()));
+        assert_eq!(code.to_ast(), prog);
+
+        let mut vm = Vm::new();
+        let mut output = vec![];
+        vm.run(&code, &mut &b"q"[..], &mut output).unwrap();
+        assert_eq!((output, vm.tape(), vm.steps()), (b"Q".to_vec(), &[Wrapping(81)][..], 5));
+
+        let missing = Bytecode::new(&build(vec![MidiInstruction::new_call("not_a_midilang_function".to_owned())]));
+        let err = Vm::new().run(&missing, &mut io::empty(), &mut io::sink()).unwrap_err();
+        assert_eq!(err, InterpError::UnknownFunction("not_a_midilang_function".to_owned()));
+    }
+
+    #[test]
+    fn picks_the_same_random_bytes_as_the_interpreter() {
+        let prog = build(vec![
+            MidiInstruction::new_random(),
+            MidiInstruction::new_move(1),
+            MidiInstruction::new_random(),
+        ]);
+        let code = Bytecode::new(&prog);
+        assert_eq!(Bytecode::from_bytes(&code.to_bytes()), Some(code.clone()));
+        assert_eq!(code.to_ast(), prog);
+
+        let mut interp = Interpreter::with_options(RunOptions { seed: 99, ..RunOptions::default() });
+        interp.run(&prog, &mut io::empty(), &mut io::sink()).unwrap();
+        let mut vm = Vm::with_options(RunOptions { seed: 99, ..RunOptions::default() });
+        vm.run(&code, &mut io::empty(), &mut io::sink()).unwrap();
+        assert_eq!(vm.tape(), interp.tape());
+    }
+
+    #[test]
+    fn dumps_the_tape_and_carries_on() {
+        let prog = build(vec![
+            MidiInstruction::new_inc(Wrapping(5)),
+            MidiInstruction::new_dump_tape(),
+            MidiInstruction::new_output(),
+        ]);
+        let code = Bytecode::new(&prog);
+        assert_eq!(Bytecode::from_bytes(&code.to_bytes()), Some(code.clone()));
+        assert_eq!(code.to_ast(), prog);
+
+        let mut vm = Vm::new();
+        let mut output = vec![];
+        vm.run(&code, &mut io::empty(), &mut output).unwrap();
+        assert_eq!((output, vm.steps()), (vec![5], 3));
+    }
+}
@@ -0,0 +1,63 @@
+use std::path::Path;
+use std::sync::mpsc;
+use std::time::Duration;
+
+use log::{debug, error, info};
+use notify::{Event, EventKind, RecursiveMode, Watcher};
+
+use crate::error::{MidilangError, MidilangResult};
+use crate::failure::ErrorFormat;
+
+/// How long a file has to stay untouched after a change before it's picked up,
+/// DAWs tend to write a save in several goes.
+const SETTLE_TIME: Duration = Duration::from_millis(200);
+
+/// Calls `on_change` right away and again every time `file_path` is saved,
+/// until the watcher stops. Errors from `on_change` are reported in `format`
+/// and watching goes on, so a broken save doesn't end the session.
+pub fn watch(
+    file_path: &Path,
+    format: ErrorFormat,
+    mut on_change: impl FnMut() -> MidilangResult<i32>,
+) -> MidilangResult<i32> {
+    let watch_error = |err: notify::Error| MidilangError::Other(format!("Could not watch {}: {}", file_path.display(), err));
+    let (sender, changes) = mpsc::channel();
+    let mut watcher = notify::recommended_watcher(sender).map_err(watch_error)?;
+    // the file itself can't be watched, saving often replaces it with a new one
+    let dir = match file_path.parent() {
+        Some(dir) if !dir.as_os_str().is_empty() => dir,
+        _ => Path::new("."),
+    };
+    watcher.watch(dir, RecursiveMode::NonRecursive).map_err(watch_error)?;
+    let name = file_path.file_name();
+
+    loop {
+        match on_change() {
+            Ok(0) => info!("Done, waiting for {} to change", file_path.display()),
+            Ok(code) => info!("Finished with exit code {}, waiting for {} to change", code, file_path.display()),
+            Err(err) => err.report(format),
+        }
+        eprintln!("[watching {}]", file_path.display());
+
+        // block until the file changes, then until it settles
+        let touches_file = |event: &notify::Result<Event>| match event {
+            Ok(event) => {
+                !matches!(event.kind, EventKind::Access(_)) && event.paths.iter().any(|path| path.file_name() == name)
+            }
+            Err(err) => {
+                error!("Error when watching {}: {}", file_path.display(), err);
+                false
+            }
+        };
+        loop {
+            let Ok(event) = changes.recv() else {
+                return Ok(0);
+            };
+            if touches_file(&event) {
+                debug!("{} changed: {:?}", file_path.display(), event);
+                break;
+            }
+        }
+        while changes.recv_timeout(SETTLE_TIME).is_ok() {}
+    }
+}
@@ -0,0 +1,92 @@
+use wasm_bindgen::prelude::*;
+
+use crate::compiler::CellWidth;
+use crate::dump::DumpFormat;
+use crate::error::MidilangResult;
+use crate::interpreter::RunOptions;
+use crate::{new_vm, run_error, vm, MidiProgram};
+
+/// Instructions a run executes before it's stopped when not told otherwise,
+/// a browser tab can't be interrupted like a process.
+pub const DEFAULT_MAX_STEPS: usize = 100_000_000;
+
+/// Parses the MIDI file in `bytes`, like one put together from what was
+/// played on a WebMIDI input, into JSON with the program as `dump` writes it
+/// and as brainf:
+///
+/// ```text
+/// {"program":[...],"brainf":"+[>,.<]"}
+/// ```
+///
+/// Files that aren't programs throw what `--error-format json` prints for them.
+#[wasm_bindgen]
+pub fn parse_smf_bytes(bytes: &[u8]) -> Result<String, JsValue> {
+    parse_json(bytes).map_err(|err| JsValue::from_str(&err.to_json_value().to_string()))
+}
+
+/// Runs the MIDI file in `bytes` on the VM with `input`, into JSON with what
+/// it wrote, like `midilang serve` answers runs:
+///
+/// ```text
+/// {"exit_code":0,"output":"hi"}
+/// ```
+///
+/// Stops after `max_steps` instructions, `DEFAULT_MAX_STEPS` when not given.
+/// Files that aren't programs throw like with `parse_smf_bytes`, programs that
+/// fail running don't, their `exit_code` and `error` say how.
+#[wasm_bindgen]
+pub fn run(bytes: &[u8], input: &[u8], max_steps: Option<usize>) -> Result<String, JsValue> {
+    run_json(bytes, input, max_steps).map_err(|err| JsValue::from_str(&err.to_json_value().to_string()))
+}
+
+// the wasm-bindgen exports without JS in them, so they can be tested anywhere
+fn parse_json(bytes: &[u8]) -> MidilangResult<String> {
+    let program = MidiProgram::from_bytes(bytes)?;
+    let dumped: serde_json::Value = serde_json::from_str(&program.dump(DumpFormat::Json)).unwrap_or_default();
+    Ok(serde_json::json!({ "program": dumped, "brainf": program.to_bf() }).to_string())
+}
+
+fn run_json(bytes: &[u8], mut input: &[u8], max_steps: Option<usize>) -> MidilangResult<String> {
+    let program = MidiProgram::from_bytes(bytes)?.optimize(CellWidth::default());
+    // no wall clock limit, wasm32-unknown-unknown has no clock to read
+    let limits = RunOptions { max_steps: Some(max_steps.unwrap_or(DEFAULT_MAX_STEPS)), ..RunOptions::default() };
+    let mut written = vec![];
+    let result = new_vm(CellWidth::default(), limits).run(&vm::Bytecode::new(program.ast()), &mut input, &mut written);
+    let mut json = serde_json::json!({
+        "exit_code": 0,
+        "output": String::from_utf8_lossy(&written),
+    });
+    if let Err(err) = result.map_err(|err| run_error(err, program.source_map())) {
+        json["exit_code"] = err.exit_code().into();
+        json["error"] = err.to_json_value();
+    }
+    Ok(json.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+    fn song(bf: &str) -> Vec<u8> {
+        let mut bytes = vec![];
+        crate::brainf_to_smf(bf).write_std(&mut bytes).unwrap();
+        bytes
+    }
+
+    #[test]
+    fn parses_and_runs_songs() {
+        let parsed: serde_json::Value = serde_json::from_str(&parse_json(&song(",[.,]")).unwrap()).unwrap();
+        assert_eq!(parsed["brainf"], ",[.,]");
+        assert!(parsed["program"].is_array());
+
+        let ran: serde_json::Value = serde_json::from_str(&run_json(&song(",[.,]"), b"hi", None).unwrap()).unwrap();
+        assert_eq!((&ran["exit_code"], &ran["output"]), (&0.into(), &"hi".into()));
+        let ran: serde_json::Value = serde_json::from_str(&run_json(&song("+[>+<]"), b"", Some(100)).unwrap()).unwrap();
+        assert_eq!(ran["exit_code"], 1);
+        assert!(ran["error"]["message"].is_string());
+
+        let err = parse_json(b"not a song").unwrap_err();
+        assert_eq!((err.exit_code(), &err.to_json_value()["kind"]), (2, &"parse".into()));
+    }
+}